@@ -0,0 +1,321 @@
+use crate::KernelInterface;
+use crate::KernelInterfaceError;
+use std::net::Ipv4Addr;
+use std::net::SocketAddrV4;
+
+impl dyn KernelInterface {
+    /// Redirects http traffic from client arriving on iface to the captive portal, used by exits
+    /// to show clients that are behind on payments why their connection is limited
+    pub fn set_captive_portal(
+        &self,
+        iface: &str,
+        client: Ipv4Addr,
+        portal: SocketAddrV4,
+    ) -> Result<(), KernelInterfaceError> {
+        let client = client.to_string();
+        let portal = portal.to_string();
+        if self.does_nftables_exist() {
+            if self
+                .get_captive_portal_handles(iface, &client, Some(&portal))?
+                .is_empty()
+            {
+                self.init_nft_prerouting_chain()?;
+                self.run_command(
+                    "nft",
+                    &[
+                        "add",
+                        "rule",
+                        "ip",
+                        "nat",
+                        "prerouting",
+                        "iifname",
+                        iface,
+                        "ip",
+                        "saddr",
+                        &client,
+                        "tcp",
+                        "dport",
+                        "80",
+                        "dnat",
+                        "to",
+                        &portal,
+                    ],
+                )?;
+            }
+            Ok(())
+        } else {
+            self.add_iptables_rule(
+                "iptables",
+                &[
+                    "-w",
+                    "-t",
+                    "nat",
+                    "-I",
+                    "PREROUTING",
+                    "-i",
+                    iface,
+                    "-s",
+                    &client,
+                    "-p",
+                    "tcp",
+                    "--dport",
+                    "80",
+                    "-j",
+                    "DNAT",
+                    "--to-destination",
+                    &portal,
+                ],
+            )
+        }
+    }
+
+    /// Removes every redirect added by set_captive_portal for this client, whatever portal it
+    /// points to, so a redirect doesn't outlive a change of the portal or the policy. Does nothing
+    /// if there are none
+    pub fn remove_captive_portal(
+        &self,
+        iface: &str,
+        client: Ipv4Addr,
+    ) -> Result<(), KernelInterfaceError> {
+        let client = client.to_string();
+        if self.does_nftables_exist() {
+            for handle in self.get_captive_portal_handles(iface, &client, None)? {
+                self.run_command(
+                    "nft",
+                    &[
+                        "delete",
+                        "rule",
+                        "ip",
+                        "nat",
+                        "prerouting",
+                        "handle",
+                        &handle.to_string(),
+                    ],
+                )?;
+            }
+        } else {
+            let out = self.run_command("iptables", &["-w", "-t", "nat", "-S", "PREROUTING"])?;
+            let out = String::from_utf8(out.stdout)?;
+            let source = format!("{client}/32");
+            for line in out.lines() {
+                let args: Vec<&str> = line.split_whitespace().collect();
+                let has = |flag: &str, value: &str| args.windows(2).any(|w| w == [flag, value]);
+                if !(has("-i", iface) && has("-s", &source) && has("--dport", "80")) {
+                    continue;
+                }
+                let portal = args
+                    .windows(2)
+                    .find(|w| w[0] == "--to-destination")
+                    .map(|w| w[1]);
+                if let Some(portal) = portal {
+                    self.run_command(
+                        "iptables",
+                        &[
+                            "-w",
+                            "-t",
+                            "nat",
+                            "-D",
+                            "PREROUTING",
+                            "-i",
+                            iface,
+                            "-s",
+                            &client,
+                            "-p",
+                            "tcp",
+                            "--dport",
+                            "80",
+                            "-j",
+                            "DNAT",
+                            "--to-destination",
+                            portal,
+                        ],
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates the nat prerouting chain if it does not already exist
+    fn init_nft_prerouting_chain(&self) -> Result<(), KernelInterfaceError> {
+        self.run_command("nft", &["add", "table", "ip", "nat"])?;
+        self.run_command(
+            "nft",
+            &[
+                "add",
+                "chain",
+                "ip",
+                "nat",
+                "prerouting",
+                "{",
+                "type",
+                "nat",
+                "hook",
+                "prerouting",
+                "priority",
+                "-100",
+                ";",
+                "policy",
+                "accept",
+                ";",
+                "}",
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The handles of the redirects for this client, to portal or to any portal if it is None.
+    /// Empty if the chain does not exist
+    fn get_captive_portal_handles(
+        &self,
+        iface: &str,
+        client: &str,
+        portal: Option<&str>,
+    ) -> Result<Vec<u32>, KernelInterfaceError> {
+        let out = self.run_command("nft", &["-a", "list", "chain", "ip", "nat", "prerouting"])?;
+        if !out.status.success() {
+            return Ok(Vec::new());
+        }
+        let out = String::from_utf8(out.stdout)?;
+        let rule = format!(
+            "iifname \"{iface}\" ip saddr {client} tcp dport 80 dnat to {}",
+            portal.unwrap_or_default()
+        );
+        Ok(out
+            .lines()
+            .filter(|line| line.contains(&rule))
+            .filter_map(|line| line.split(' ').next_back())
+            .filter_map(|handle| handle.parse().ok())
+            .collect())
+    }
+}
+
+#[test]
+fn test_set_captive_portal_iptables() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    let mut counter = 0;
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        match counter {
+            1 => {
+                assert_eq!(program, "nft");
+                assert_eq!(args, vec!["-v"]);
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(1),
+                })
+            }
+            2 | 3 => {
+                assert_eq!(program, "iptables");
+                let op = if counter == 2 { "-C" } else { "-I" };
+                assert_eq!(
+                    args,
+                    vec![
+                        "-w",
+                        "-t",
+                        "nat",
+                        op,
+                        "PREROUTING",
+                        "-i",
+                        "wg_exit_v2",
+                        "-s",
+                        "172.168.1.5",
+                        "-p",
+                        "tcp",
+                        "--dport",
+                        "80",
+                        "-j",
+                        "DNAT",
+                        "--to-destination",
+                        "172.168.0.1:8080",
+                    ]
+                );
+                Ok(Output {
+                    stdout: b"".to_vec(),
+                    stderr: b"".to_vec(),
+                    status: ExitStatus::from_raw(if counter == 2 { 1 } else { 0 }),
+                })
+            }
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        }
+    }));
+
+    KI.set_captive_portal(
+        "wg_exit_v2",
+        "172.168.1.5".parse().unwrap(),
+        "172.168.0.1:8080".parse().unwrap(),
+    )
+    .unwrap();
+}
+
+#[test]
+fn test_remove_captive_portal_iptables() {
+    use crate::KI;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+    use std::process::Output;
+
+    let mut counter = 0;
+    KI.set_mock(Box::new(move |program, args| {
+        counter += 1;
+        let output = |stdout: &str, status| {
+            Ok(Output {
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: b"".to_vec(),
+                status: ExitStatus::from_raw(status),
+            })
+        };
+        match counter {
+            1 => {
+                assert_eq!(program, "nft");
+                output("", 1)
+            }
+            2 => {
+                assert_eq!(program, "iptables");
+                assert_eq!(args, vec!["-w", "-t", "nat", "-S", "PREROUTING"]);
+                output(
+                    "-P PREROUTING ACCEPT\n\
+                     -A PREROUTING -s 172.168.1.6/32 -i wg_exit_v2 -p tcp -m tcp --dport 80 -j DNAT --to-destination 172.168.0.1:8080\n\
+                     -A PREROUTING -s 172.168.1.5/32 -i wg_exit_v2 -p tcp -m tcp --dport 80 -j DNAT --to-destination 172.168.0.1:8080\n",
+                    0,
+                )
+            }
+            3 => {
+                // the redirect is found without knowing the portal it points to
+                assert_eq!(program, "iptables");
+                assert_eq!(
+                    args,
+                    vec![
+                        "-w",
+                        "-t",
+                        "nat",
+                        "-D",
+                        "PREROUTING",
+                        "-i",
+                        "wg_exit_v2",
+                        "-s",
+                        "172.168.1.5",
+                        "-p",
+                        "tcp",
+                        "--dport",
+                        "80",
+                        "-j",
+                        "DNAT",
+                        "--to-destination",
+                        "172.168.0.1:8080",
+                    ]
+                );
+                output("", 0)
+            }
+            _ => panic!("Unexpected call {} {:?} {:?}", counter, program, args),
+        }
+    }));
+
+    KI.remove_captive_portal("wg_exit_v2", "172.168.1.5".parse().unwrap())
+        .unwrap();
+}
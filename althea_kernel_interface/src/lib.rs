@@ -24,6 +24,7 @@ use std::str;
 
 mod babel;
 pub mod bridge_tools;
+mod captive_portal;
 mod check_cron;
 mod counter;
 mod create_wg_key;
@@ -16,6 +16,33 @@ pub fn to_wg_local(ip: &IpAddr) -> IpAddr {
     }
 }
 
+/// to_wg_local for an address that hasn't been checked yet, such as a mesh ip from a neighbor's
+/// hello. None if it isn't in fd00::/8
+pub fn try_to_wg_local(ip: &IpAddr) -> Option<Ipv6Addr> {
+    match *ip {
+        IpAddr::V6(ip) if ip.segments()[0] & 0xff00 == 0xfd00 => {
+            let seg = ip.segments();
+            Some(Ipv6Addr::new(
+                0xfe80, 0x0, 0x0, 0x0, seg[4], seg[5], seg[6], seg[7],
+            ))
+        }
+        _ => None,
+    }
+}
+
+#[test]
+fn test_try_to_wg_local() {
+    assert_eq!(
+        try_to_wg_local(&"fd00::1:2".parse().unwrap()),
+        Some("fe80::1:2".parse().unwrap())
+    );
+    // none of these may panic
+    assert_eq!(try_to_wg_local(&"2001:db8::1".parse().unwrap()), None);
+    assert_eq!(try_to_wg_local(&"fe80::1".parse().unwrap()), None);
+    assert_eq!(try_to_wg_local(&"ff02::1".parse().unwrap()), None);
+    assert_eq!(try_to_wg_local(&"10.0.0.1".parse().unwrap()), None);
+}
+
 #[test]
 fn test_to_wg_local() {
     assert_eq!(
@@ -8,7 +8,7 @@ use althea_types::WgKey;
 use regex::Regex;
 use std::collections::HashMap;
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, Serialize, Deserialize)]
 pub struct WgUsage {
     pub upload: u64,
     pub download: u64,
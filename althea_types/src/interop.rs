@@ -10,6 +10,7 @@ use num256::Uint256;
 use serde::de::Error;
 use serde::{Deserialize, Deserializer, Serializer};
 use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::fmt;
 use std::fmt::Display;
 use std::hash::{Hash, Hasher};
@@ -567,6 +568,10 @@ pub enum OperatorAction {
         add_list: Vec<String>,
         drop_list: Vec<String>,
     },
+    /// Replaces the policy that decides which neighbors this router will open tunnels with
+    SetPeerPolicy {
+        policy: PeerPolicy,
+    },
 }
 
 /// Operator update that we get from the operator server during our checkin
@@ -680,6 +685,69 @@ pub struct ShaperSettings {
     /// max_shaper_speed and heads downward from there. Set this value based on what you think the
     /// worst realistic performance of any link in the network may be.
     pub min_speed: usize,
+    /// How the shaper picks a new speed for an interface
+    #[serde(default)]
+    pub algorithm: ShaperAlgorithmKind,
+}
+
+/// The algorithms the bandwidth shaper can use, see tunnel_manager::shaping in rita_common
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, Eq, PartialEq, Default)]
+pub enum ShaperAlgorithmKind {
+    /// Cut the speed 20% when the link looks bloated and raise it 5% once it has looked good
+    /// for a while
+    #[default]
+    Step,
+    /// Follow the queueing delay and its trend to settle on the link capacity
+    DelayGradient,
+}
+
+/// Matches a neighbor by one part of its identity
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, Eq, PartialEq)]
+pub enum PeerMatch {
+    WgKey(WgKey),
+    EthAddress(Address),
+    MeshIp(IpAddr),
+}
+
+impl PeerMatch {
+    pub fn matches(&self, id: &Identity) -> bool {
+        match self {
+            PeerMatch::WgKey(key) => id.wg_public_key == *key,
+            PeerMatch::EthAddress(address) => id.eth_address == *address,
+            PeerMatch::MeshIp(ip) => id.mesh_ip == *ip,
+        }
+    }
+}
+
+impl Display for PeerMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerMatch::WgKey(key) => write!(f, "wg key {key}"),
+            PeerMatch::EthAddress(address) => write!(f, "eth address {address}"),
+            PeerMatch::MeshIp(ip) => write!(f, "mesh ip {ip}"),
+        }
+    }
+}
+
+/// Decides which neighbors we are willing to open tunnels with, by default anyone who says hello
+/// on a peer interface gets a tunnel. Eth addresses, mesh ips and operator addresses are taken from
+/// the neighbor's hello and can be spoofed unless require_signed_hellos is on
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Hash, Eq, PartialEq)]
+pub struct PeerPolicy {
+    /// If not empty only neighbors matching one of these get tunnels
+    #[serde(default)]
+    pub allow: Vec<PeerMatch>,
+    /// Neighbors matching any of these never get tunnels, even if they are also allowed
+    #[serde(default)]
+    pub deny: Vec<PeerMatch>,
+    /// The most neighbors we will open tunnels with on a given peer interface, by interface
+    /// name. Interfaces not listed have no limit
+    #[serde(default)]
+    pub max_peers_per_interface: BTreeMap<String, usize>,
+    /// Only open tunnels with neighbors that advertise the same operator address as us, this
+    /// rejects neighbors too old to advertise one
+    #[serde(default)]
+    pub require_operator_match: bool,
 }
 
 /// This struct is sent up to op to display info related to a routers connect exit there
@@ -703,6 +771,7 @@ fn default_shaper_settings() -> ShaperSettings {
         max_speed: 1000,
         min_speed: 50,
         enabled: true,
+        algorithm: ShaperAlgorithmKind::default(),
     }
 }
 
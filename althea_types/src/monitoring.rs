@@ -174,6 +174,17 @@ impl RunningLatencyStats {
         history.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
         history[history.len() / 2]
     }
+    /// gets up to the last n samples oldest first, slots of the history that have never been
+    /// filled are skipped
+    pub fn recent(&self, n: usize) -> Vec<f32> {
+        let len = self.history.len();
+        let mut out: Vec<f32> = (1..=n.min(len))
+            .map(|i| self.history[(self.front + len - i) % len])
+            .filter(|s| *s != 0.0)
+            .collect();
+        out.reverse();
+        out
+    }
 }
 
 /// Due to the way babel communicates packet loss the functions here require slightly
@@ -345,6 +356,18 @@ mod tests {
         assert!(stats.get_median() != 0.0);
     }
 
+    #[test]
+    fn test_rtt_recent() {
+        let mut stats = RunningLatencyStats::new();
+        assert!(stats.recent(10).is_empty());
+        for i in 1..=LATENCY_HISTORY + 5 {
+            stats.add_sample(i as f32);
+        }
+        assert_eq!(stats.recent(3), vec![103.0, 104.0, 105.0]);
+        assert_eq!(stats.recent(1000).len(), LATENCY_HISTORY);
+        assert_eq!(stats.recent(1000)[0], 6.0);
+    }
+
     #[test]
     fn test_packet_loss_increment() {
         let mut stats = RunningPacketLossStats::new();
@@ -0,0 +1,247 @@
+//! Async versions of the babel_monitor functions, built on tokio. These share the parsers in
+//! parsing.rs with the blocking api but never block the thread while waiting on babel, so they
+//! are safe to use from actix handlers and other code running on an async runtime.
+
+use crate::parsing::{
+    get_local_fee_sync, parse_interfaces_sync, parse_neighs_sync, parse_routes_sync,
+    read_babel_sync, validate_preamble,
+};
+use crate::structs::{BabelMonitorError, Interface, Neighbor, Route};
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::time::timeout as tokio_timeout;
+
+/// An open connection to the babel management socket, every read and connect
+/// is limited to the timeout the stream was opened with
+pub struct BabelStream {
+    stream: TcpStream,
+    timeout: Duration,
+}
+
+/// Opens a connection to the babel management socket, consuming and validating the preamble
+pub async fn open_babel_stream(
+    babel_port: u16,
+    timeout: Duration,
+) -> Result<BabelStream, BabelMonitorError> {
+    let socket_string = format!("[::1]:{babel_port}");
+    trace!("About to open Babel socket using {}", socket_string);
+    let socket: SocketAddr = socket_string.parse().unwrap();
+    let stream = match tokio_timeout(timeout, TcpStream::connect(socket)).await {
+        Ok(stream) => stream?,
+        Err(_) => {
+            return Err(BabelMonitorError::TcpError(
+                "Timed out connecting to Babel".to_string(),
+            ))
+        }
+    };
+    let mut stream = BabelStream { stream, timeout };
+
+    // Consumes the automated Preamble and validates configuration api version
+    info!("Starting babel connection");
+    let preamble = read_babel(&mut stream).await?;
+    validate_preamble(preamble)?;
+    Ok(stream)
+}
+
+/// Reads from babel until a terminator is found or the stream timeout runs out
+async fn read_babel(stream: &mut BabelStream) -> Result<String, BabelMonitorError> {
+    match tokio_timeout(
+        stream.timeout,
+        read_babel_until_terminator(&mut stream.stream),
+    )
+    .await
+    {
+        Ok(res) => res,
+        Err(_) => {
+            warn!("Babel read timed out!");
+            Err(BabelMonitorError::ReadFailed(
+                "Babel read timed out!".to_string(),
+            ))
+        }
+    }
+}
+
+async fn read_babel_until_terminator(stream: &mut TcpStream) -> Result<String, BabelMonitorError> {
+    const BUFFER_SIZE: usize = 64_000;
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut full_message = String::new();
+    loop {
+        let bytes = stream.read(&mut buffer).await?;
+        if bytes == 0 {
+            return Err(BabelMonitorError::ReadFailed(format!(
+                "Babel closed the connection before finishing:\n{full_message}"
+            )));
+        }
+        full_message += &String::from_utf8(buffer[..bytes].to_vec())?;
+        trace!("Babel monitor got {} bytes", bytes);
+
+        // babel writes large dumps over several reads, keep reading until we see a terminator
+        match read_babel_sync(&full_message) {
+            Ok(babel_data) => return Ok(babel_data),
+            Err(BabelMonitorError::NoTerminator(_)) => continue,
+            Err(e) => {
+                warn!("Babel read failed! {} {:?}", full_message, e);
+                return Err(BabelMonitorError::ReadFailed(format!("{e:?}")));
+            }
+        }
+    }
+}
+
+pub async fn run_command(stream: &mut BabelStream, cmd: &str) -> Result<String, BabelMonitorError> {
+    info!("Running babel command {}", cmd);
+    let cmd = format!("{cmd}\n");
+    match tokio_timeout(stream.timeout, stream.stream.write_all(cmd.as_bytes())).await {
+        Ok(Ok(_)) => {
+            info!("Command write succeeded, returning output");
+            read_babel(stream).await
+        }
+        Ok(Err(e)) => Err(BabelMonitorError::CommandFailed(cmd, format!("{e:?}"))),
+        Err(e) => Err(BabelMonitorError::CommandFailed(cmd, format!("{e:?}"))),
+    }
+}
+
+pub async fn parse_interfaces(
+    stream: &mut BabelStream,
+) -> Result<Vec<Interface>, BabelMonitorError> {
+    let output = run_command(stream, "dump").await?;
+    parse_interfaces_sync(output)
+}
+
+pub async fn get_local_fee(stream: &mut BabelStream) -> Result<u32, BabelMonitorError> {
+    let output = run_command(stream, "dump").await?;
+    get_local_fee_sync(output)
+}
+
+pub async fn set_local_fee(
+    stream: &mut BabelStream,
+    new_fee: u32,
+) -> Result<(), BabelMonitorError> {
+    run_command(stream, &format!("fee {new_fee}")).await?;
+    Ok(())
+}
+
+pub async fn set_metric_factor(
+    stream: &mut BabelStream,
+    new_factor: u32,
+) -> Result<(), BabelMonitorError> {
+    run_command(stream, &format!("metric-factor {new_factor}")).await?;
+    Ok(())
+}
+
+pub async fn monitor(stream: &mut BabelStream, iface: &str) -> Result<(), BabelMonitorError> {
+    let command = format!("interface {iface} max-rtt-penalty 2000 enable-timestamps true");
+    run_command(stream, &command).await?;
+    trace!("Babel started monitoring: {}", iface);
+    Ok(())
+}
+
+pub async fn redistribute_ip(
+    stream: &mut BabelStream,
+    ip: &IpAddr,
+    allow: bool,
+) -> Result<String, BabelMonitorError> {
+    let command = format!(
+        "redistribute ip {}/128 {}",
+        ip,
+        if allow { "allow" } else { "deny" }
+    );
+    run_command(stream, &command).await?;
+    read_babel(stream).await
+}
+
+pub async fn unmonitor(stream: &mut BabelStream, iface: &str) -> Result<(), BabelMonitorError> {
+    let command = format!("flush interface {iface}");
+    run_command(stream, &command).await?;
+    trace!("Babel stopped monitoring: {}", iface);
+    Ok(())
+}
+
+pub async fn parse_neighs(stream: &mut BabelStream) -> Result<Vec<Neighbor>, BabelMonitorError> {
+    let output = run_command(stream, "dump").await?;
+    parse_neighs_sync(output)
+}
+
+pub async fn parse_routes(stream: &mut BabelStream) -> Result<Vec<Route>, BabelMonitorError> {
+    let output = run_command(stream, "dump").await?;
+    parse_routes_sync(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    static PREAMBLE: &str =
+        "ALTHEA 0.1\nversion babeld-1.8.0-24-g6335378\nhost raspberrypi\nmy-id \
+         ba:27:eb:ff:fe:09:06:dd\nok\n";
+
+    static DUMP: &str = "local fee 1024\n\
+add neighbour 14f05f0 address fe80::e9d0:498f:6c61:be29 if wlan0 reach feff rxcost 258 txcost 341 \
+rtt 18.674 rttcost 473 cost 817\n\
+add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:c1:2d:d5 \
+metric 817 price 4008 fee 4008 refmetric 0 full-path-rtt 18.674 via fe80::e9d0:498f:6c61:be29 if wlan0\n";
+
+    #[test]
+    fn async_dump() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("[::1]:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                stream.write_all(PREAMBLE.as_bytes()).await.unwrap();
+                let mut buf = [0u8; 5];
+                loop {
+                    stream.read_exact(&mut buf).await.unwrap();
+                    assert_eq!(&buf, b"dump\n");
+                    // split the dump across writes to make sure we read until the terminator
+                    stream.write_all(DUMP.as_bytes()).await.unwrap();
+                    stream.flush().await.unwrap();
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    stream.write_all(b"ok\n").await.unwrap();
+                }
+            });
+
+            let mut stream = open_babel_stream(port, Duration::from_secs(1))
+                .await
+                .unwrap();
+            let routes = parse_routes(&mut stream).await.unwrap();
+            assert_eq!(routes.len(), 1);
+            assert_eq!(routes[0].price, 4008);
+            let neighs = parse_neighs(&mut stream).await.unwrap();
+            assert_eq!(neighs.len(), 1);
+            assert_eq!(get_local_fee(&mut stream).await.unwrap(), 1024);
+        });
+    }
+
+    #[test]
+    fn async_read_timeout() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("[::1]:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                // a preamble with no terminator
+                stream.write_all(b"ALTHEA 0.1\n").await.unwrap();
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            });
+
+            match open_babel_stream(port, Duration::from_millis(200)).await {
+                Err(BabelMonitorError::ReadFailed(_)) => {}
+                Err(e) => panic!("Unexpected error {}", e),
+                Ok(_) => panic!("Opened a stream without a full preamble"),
+            }
+        });
+    }
+}
@@ -11,8 +11,10 @@ extern crate serde_derive;
 #[macro_use]
 extern crate log;
 
+pub mod async_monitor;
 pub mod parsing;
 pub mod structs;
+pub mod subscription;
 
 use crate::parsing::{read_babel_sync, validate_preamble};
 use crate::structs::{BabelMonitorError, Route};
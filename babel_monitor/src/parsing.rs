@@ -51,31 +51,26 @@ pub fn validate_preamble(preamble: String) -> Result<(), BabelMonitorError> {
     }
 }
 
+/// Parses a single babel interface line, the verb (add, change or flush) is ignored
+pub fn parse_interface_line(entry: &str) -> Result<Interface, BabelMonitorError> {
+    Ok(Interface {
+        name: find_babel_val("interface", entry)?,
+        up: find_and_parse_babel_val("up", entry)?,
+        ipv4: find_and_parse_babel_val("ipv4", entry).ok(),
+        ipv6: find_and_parse_babel_val("ipv6", entry).ok(),
+    })
+}
+
 pub fn parse_interfaces_sync(output: String) -> Result<Vec<Interface>, BabelMonitorError> {
     let mut vector: Vec<Interface> = Vec::new();
     let mut found_interface = false;
     for entry in output.split('\n') {
         if entry.contains("add interface") {
             found_interface = true;
-            let interface = Interface {
-                name: match find_babel_val("interface", entry) {
-                    Ok(val) => val,
-                    Err(_) => continue,
-                },
-                up: match find_and_parse_babel_val("up", entry) {
-                    Ok(val) => val,
-                    Err(_) => continue,
-                },
-                ipv4: match find_and_parse_babel_val("ipv4", entry) {
-                    Ok(val) => Some(val),
-                    Err(_) => None,
-                },
-                ipv6: match find_and_parse_babel_val("ipv6", entry) {
-                    Ok(val) => Some(val),
-                    Err(_) => None,
-                },
-            };
-            vector.push(interface);
+            match parse_interface_line(entry) {
+                Ok(interface) => vector.push(interface),
+                Err(_) => continue,
+            }
         }
     }
     if vector.is_empty() && found_interface {
@@ -106,52 +101,38 @@ pub fn get_local_fee_sync(babel_output: String) -> Result<u32, BabelMonitorError
     Err(BabelMonitorError::LocalFeeNotFound(String::from(fee_entry)))
 }
 
+/// Parses a single babel neighbour line, the verb (add, change or flush) is ignored
+pub fn parse_neigh_line(entry: &str) -> Result<Neighbor, BabelMonitorError> {
+    Ok(Neighbor {
+        id: find_babel_val("neighbour", entry)?,
+        address: find_and_parse_babel_val("address", entry)?,
+        iface: find_babel_val("if", entry)?,
+        reach: match u16::from_str_radix(&find_babel_val("reach", entry)?, 16) {
+            Ok(val) => val,
+            Err(e) => {
+                warn!("Failed to convert reach {:?} {}", e, entry);
+                return Err(e.into());
+            }
+        },
+        txcost: find_and_parse_babel_val("txcost", entry)?,
+        rxcost: find_and_parse_babel_val("rxcost", entry)?,
+        // it's possible that the neighbor does not have rtt enabled
+        rtt: find_and_parse_babel_val("rtt", entry).unwrap_or(0.0),
+        rttcost: find_and_parse_babel_val("rttcost", entry).unwrap_or(0),
+        cost: find_and_parse_babel_val("cost", entry)?,
+    })
+}
+
 pub fn parse_neighs_sync(output: String) -> Result<Vec<Neighbor>, BabelMonitorError> {
     let mut vector: Vec<Neighbor> = Vec::with_capacity(5);
     let mut found_neigh = false;
     for entry in output.split('\n') {
         if entry.contains("add neighbour") {
             found_neigh = true;
-            let neigh = Neighbor {
-                id: match find_babel_val("neighbour", entry) {
-                    Ok(val) => val,
-                    Err(_) => continue,
-                },
-                address: match find_and_parse_babel_val("address", entry) {
-                    Ok(entry) => entry,
-                    Err(_) => continue,
-                },
-                iface: match find_babel_val("if", entry) {
-                    Ok(val) => val,
-                    Err(_) => continue,
-                },
-                reach: match find_babel_val("reach", entry) {
-                    Ok(val) => match u16::from_str_radix(&val, 16) {
-                        Ok(val) => val,
-                        Err(e) => {
-                            warn!("Failed to convert reach {:?} {}", e, entry);
-                            continue;
-                        }
-                    },
-                    Err(_) => continue,
-                },
-                txcost: match find_and_parse_babel_val("txcost", entry) {
-                    Ok(entry) => entry,
-                    Err(_) => continue,
-                },
-                rxcost: match find_and_parse_babel_val("rxcost", entry) {
-                    Ok(entry) => entry,
-                    Err(_) => continue,
-                },
-                // it's possible that the neighbor does not have rtt enabled
-                rtt: find_and_parse_babel_val("rtt", entry).unwrap_or(0.0),
-                rttcost: find_and_parse_babel_val("rttcost", entry).unwrap_or(0),
-                cost: match find_and_parse_babel_val("cost", entry) {
-                    Ok(entry) => entry,
-                    Err(_) => continue,
-                },
-            };
-            vector.push(neigh);
+            match parse_neigh_line(entry) {
+                Ok(neigh) => vector.push(neigh),
+                Err(_) => continue,
+            }
         }
     }
     if vector.is_empty() && found_neigh {
@@ -162,6 +143,23 @@ pub fn parse_neighs_sync(output: String) -> Result<Vec<Neighbor>, BabelMonitorEr
     Ok(vector)
 }
 
+/// Parses a single babel route line, the verb (add, change or flush) is ignored
+pub fn parse_route_line(entry: &str) -> Result<Route, BabelMonitorError> {
+    Ok(Route {
+        id: find_babel_val("route", entry)?,
+        iface: find_babel_val("if", entry)?,
+        xroute: false,
+        installed: find_babel_val("installed", entry)?.contains("yes"),
+        neigh_ip: find_and_parse_babel_val("via", entry)?,
+        prefix: find_and_parse_babel_val("prefix", entry)?,
+        metric: find_and_parse_babel_val("metric", entry)?,
+        refmetric: find_and_parse_babel_val("refmetric", entry)?,
+        full_path_rtt: find_and_parse_babel_val("full-path-rtt", entry)?,
+        price: find_and_parse_babel_val("price", entry)?,
+        fee: find_and_parse_babel_val("fee", entry)?,
+    })
+}
+
 pub fn parse_routes_sync(babel_out: String) -> Result<Vec<Route>, BabelMonitorError> {
     let mut vector: Vec<Route> = Vec::with_capacity(20);
     let mut found_route = false;
@@ -171,51 +169,10 @@ pub fn parse_routes_sync(babel_out: String) -> Result<Vec<Route>, BabelMonitorEr
         if entry.contains("add route") {
             trace!("Parsing 'add route' entry: {}", entry);
             found_route = true;
-            let route = Route {
-                id: match find_babel_val("route", entry) {
-                    Ok(value) => value,
-                    Err(_) => continue,
-                },
-                iface: match find_babel_val("if", entry) {
-                    Ok(value) => value,
-                    Err(_) => continue,
-                },
-                xroute: false,
-                installed: match find_babel_val("installed", entry) {
-                    Ok(value) => value.contains("yes"),
-                    Err(_) => continue,
-                },
-                neigh_ip: match find_and_parse_babel_val("via", entry) {
-                    Ok(value) => value,
-                    Err(_) => continue,
-                },
-                prefix: match find_and_parse_babel_val("prefix", entry) {
-                    Ok(value) => value,
-                    Err(_) => continue,
-                },
-                metric: match find_and_parse_babel_val("metric", entry) {
-                    Ok(value) => value,
-                    Err(_) => continue,
-                },
-                refmetric: match find_and_parse_babel_val("refmetric", entry) {
-                    Ok(value) => value,
-                    Err(_) => continue,
-                },
-                full_path_rtt: match find_and_parse_babel_val("full-path-rtt", entry) {
-                    Ok(value) => value,
-                    Err(_) => continue,
-                },
-                price: match find_and_parse_babel_val("price", entry) {
-                    Ok(value) => value,
-                    Err(_) => continue,
-                },
-                fee: match find_and_parse_babel_val("fee", entry) {
-                    Ok(value) => value,
-                    Err(_) => continue,
-                },
-            };
-
-            vector.push(route);
+            match parse_route_line(entry) {
+                Ok(route) => vector.push(route),
+                Err(_) => continue,
+            }
         }
     }
     if vector.is_empty() && found_route {
@@ -28,6 +28,8 @@ pub enum BabelMonitorError {
     NoRoute(String),
     MiscStringError(String),
     FromUtf8Error(FromUtf8Error),
+    /// The monitor connection to babel has not yet received a full table
+    NotSynced,
 }
 
 impl From<std::io::Error> for BabelMonitorError {
@@ -100,11 +102,14 @@ impl Display for BabelMonitorError {
             }
             BabelMonitorError::MiscStringError(a) => write!(f, "{a}",),
             BabelMonitorError::FromUtf8Error(a) => write!(f, "{a}",),
+            BabelMonitorError::NotSynced => {
+                write!(f, "Babel monitor has not yet received the routing table")
+            }
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Interface {
     pub name: String,
     pub up: bool,
@@ -112,7 +117,7 @@ pub struct Interface {
     pub ipv4: Option<IpAddr>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Route {
     pub id: String,
     pub iface: String,
@@ -127,7 +132,7 @@ pub struct Route {
     pub fee: u32,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Neighbor {
     pub id: String,
     pub address: IpAddr,
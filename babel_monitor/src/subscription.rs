@@ -0,0 +1,573 @@
+//! A long lived connection to babel's monitor mode. Rather than running a full dump every time
+//! something needs the routing table, BabelMonitorClient keeps a table in memory that is updated
+//! as babel reports changes, and passes those changes on to any subsystem that subscribes to them.
+
+use crate::find_and_parse_babel_val;
+use crate::find_babel_val;
+use crate::open_babel_stream;
+use crate::parsing::{parse_interface_line, parse_neigh_line, parse_route_line};
+use crate::run_command;
+use crate::structs::{BabelMonitorError, Interface, Neighbor, Route};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Write;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+/// How long to wait before reconnecting after the monitor connection fails
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// Babel reports changes to route metrics constantly on any real network, if we hear nothing
+/// for this long we assume the connection is dead and reconnect
+const MONITOR_READ_TIMEOUT: Duration = Duration::from_secs(300);
+/// How many events may queue up for a subscriber before it is considered stuck and dropped
+const SUBSCRIBER_QUEUE_LEN: usize = 1024;
+
+/// A change to the babel routing table
+#[derive(Debug, Clone, PartialEq)]
+pub enum BabelEvent {
+    RouteAdded(Route),
+    RouteChanged(Route),
+    RouteRetracted(Route),
+    NeighborUp(Neighbor),
+    NeighborChanged(Neighbor),
+    NeighborDown(Neighbor),
+    InterfaceAdded(Interface),
+    InterfaceChanged(Interface),
+    InterfaceRemoved(Interface),
+}
+
+/// Ids of the entries seen since a resync began, anything not seen by the time babel
+/// finishes the dump has gone away while we were not connected
+#[derive(Debug, Default, Clone)]
+struct Seen {
+    routes: HashSet<String>,
+    neighbors: HashSet<String>,
+    interfaces: HashSet<String>,
+}
+
+/// Babel's routing table as built from monitor output
+#[derive(Debug, Default, Clone)]
+pub struct BabelTable {
+    routes: HashMap<String, Route>,
+    neighbors: HashMap<String, Neighbor>,
+    interfaces: HashMap<String, Interface>,
+    local_fee: Option<u32>,
+    synced: bool,
+    syncing: Option<Seen>,
+}
+
+/// Inserts or updates an entry, returning the event describing the change if there was one
+fn upsert<T: Clone + PartialEq>(
+    map: &mut HashMap<String, T>,
+    seen: Option<&mut HashSet<String>>,
+    id: String,
+    value: T,
+    added: fn(T) -> BabelEvent,
+    changed: fn(T) -> BabelEvent,
+) -> Vec<BabelEvent> {
+    if let Some(seen) = seen {
+        seen.insert(id.clone());
+    }
+    match map.insert(id, value.clone()) {
+        None => vec![added(value)],
+        Some(old) if old != value => vec![changed(value)],
+        Some(_) => Vec::new(),
+    }
+}
+
+/// Removes every entry not in seen, returning an event for each
+fn remove_unseen<K: Eq + Hash + Clone, T>(
+    map: &mut HashMap<K, T>,
+    seen: &HashSet<K>,
+    removed: fn(T) -> BabelEvent,
+) -> Vec<BabelEvent> {
+    let unseen: Vec<K> = map
+        .keys()
+        .filter(|id| !seen.contains(*id))
+        .cloned()
+        .collect();
+    unseen
+        .into_iter()
+        .filter_map(|id| map.remove(&id))
+        .map(removed)
+        .collect()
+}
+
+impl BabelTable {
+    pub fn new() -> BabelTable {
+        BabelTable::default()
+    }
+
+    /// True once a full dump has been received
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// Starts a resync, babel dumps the whole table when monitoring starts and ends it with ok
+    pub fn begin_sync(&mut self) {
+        self.synced = false;
+        self.syncing = Some(Seen::default());
+    }
+
+    /// Marks the table as not synced, used when the monitor connection is lost
+    pub fn desync(&mut self) {
+        self.synced = false;
+        self.syncing = None;
+    }
+
+    pub fn get_routes(&self) -> Vec<Route> {
+        self.routes.values().cloned().collect()
+    }
+
+    pub fn get_neighs(&self) -> Vec<Neighbor> {
+        self.neighbors.values().cloned().collect()
+    }
+
+    pub fn get_interfaces(&self) -> Vec<Interface> {
+        self.interfaces.values().cloned().collect()
+    }
+
+    pub fn get_local_fee(&self) -> Option<u32> {
+        self.local_fee
+    }
+
+    /// Applies a single line of monitor output and returns the resulting changes
+    pub fn apply_line(&mut self, line: &str) -> Result<Vec<BabelEvent>, BabelMonitorError> {
+        let line = line.trim();
+        let mut words = line.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("ok"), None) => Ok(self.end_sync()),
+            (Some("no"), None) | (Some("bad"), None) => {
+                Err(BabelMonitorError::ReadFailed(line.to_string()))
+            }
+            (Some("local"), Some("fee")) => {
+                self.local_fee = Some(find_and_parse_babel_val("fee", line)?);
+                Ok(Vec::new())
+            }
+            (Some(verb), Some("route")) => {
+                let id = find_babel_val("route", line)?;
+                if verb == "flush" {
+                    return Ok(self
+                        .routes
+                        .remove(&id)
+                        .map(BabelEvent::RouteRetracted)
+                        .into_iter()
+                        .collect());
+                }
+                let route = parse_route_line(line)?;
+                Ok(upsert(
+                    &mut self.routes,
+                    self.syncing.as_mut().map(|s| &mut s.routes),
+                    id,
+                    route,
+                    BabelEvent::RouteAdded,
+                    BabelEvent::RouteChanged,
+                ))
+            }
+            (Some(verb), Some("neighbour")) => {
+                let id = find_babel_val("neighbour", line)?;
+                if verb == "flush" {
+                    return Ok(self
+                        .neighbors
+                        .remove(&id)
+                        .map(BabelEvent::NeighborDown)
+                        .into_iter()
+                        .collect());
+                }
+                let neigh = parse_neigh_line(line)?;
+                Ok(upsert(
+                    &mut self.neighbors,
+                    self.syncing.as_mut().map(|s| &mut s.neighbors),
+                    id,
+                    neigh,
+                    BabelEvent::NeighborUp,
+                    BabelEvent::NeighborChanged,
+                ))
+            }
+            (Some(verb), Some("interface")) => {
+                let id = find_babel_val("interface", line)?;
+                if verb == "flush" {
+                    return Ok(self
+                        .interfaces
+                        .remove(&id)
+                        .map(BabelEvent::InterfaceRemoved)
+                        .into_iter()
+                        .collect());
+                }
+                let iface = parse_interface_line(line)?;
+                Ok(upsert(
+                    &mut self.interfaces,
+                    self.syncing.as_mut().map(|s| &mut s.interfaces),
+                    id,
+                    iface,
+                    BabelEvent::InterfaceAdded,
+                    BabelEvent::InterfaceChanged,
+                ))
+            }
+            // xroutes, the preamble and anything else we don't keep track of
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Finishes a resync, anything babel did not mention in the dump is removed
+    fn end_sync(&mut self) -> Vec<BabelEvent> {
+        let seen = match self.syncing.take() {
+            Some(seen) => seen,
+            None => return Vec::new(),
+        };
+        self.synced = true;
+        let mut events = remove_unseen(&mut self.routes, &seen.routes, BabelEvent::RouteRetracted);
+        events.extend(remove_unseen(
+            &mut self.neighbors,
+            &seen.neighbors,
+            BabelEvent::NeighborDown,
+        ));
+        events.extend(remove_unseen(
+            &mut self.interfaces,
+            &seen.interfaces,
+            BabelEvent::InterfaceRemoved,
+        ));
+        events
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    table: RwLock<BabelTable>,
+    subscribers: Mutex<Vec<SyncSender<BabelEvent>>>,
+}
+
+impl Shared {
+    fn broadcast(&self, events: Vec<BabelEvent>) {
+        if events.is_empty() {
+            return;
+        }
+        let mut subscribers = self.subscribers.lock().unwrap();
+        // subscribers that have dropped their receiver are removed, as are subscribers that
+        // have fallen too far behind, the monitor thread must never block on a slow reader
+        subscribers.retain(|sub| {
+            events
+                .iter()
+                .all(|event| match sub.try_send(event.clone()) {
+                    Ok(()) => true,
+                    Err(TrySendError::Full(_)) => {
+                        warn!("Babel event subscriber fell behind, dropping it");
+                        false
+                    }
+                    Err(TrySendError::Disconnected(_)) => false,
+                })
+        });
+    }
+}
+
+/// A handle to a babel monitor connection running in its own thread, cloning the handle
+/// shares the connection and the thread exits once every handle is dropped
+#[derive(Clone)]
+pub struct BabelMonitorClient {
+    shared: Arc<Shared>,
+    babel_port: u16,
+    timeout: Duration,
+}
+
+impl BabelMonitorClient {
+    /// Connects to babel on babel_port and starts monitoring, reconnecting whenever the connection fails
+    pub fn start(babel_port: u16, timeout: Duration) -> BabelMonitorClient {
+        let shared = Arc::new(Shared::default());
+        let weak = Arc::downgrade(&shared);
+        thread::spawn(move || {
+            while weak.strong_count() > 0 {
+                if let Err(e) = run_monitor(babel_port, timeout, &weak) {
+                    warn!("Babel monitor connection failed with {}, reconnecting", e);
+                }
+                if let Some(shared) = weak.upgrade() {
+                    shared.table.write().unwrap().desync();
+                }
+                thread::sleep(RECONNECT_DELAY);
+            }
+        });
+        BabelMonitorClient {
+            shared,
+            babel_port,
+            timeout,
+        }
+    }
+
+    /// Returns a channel that receives every change to the table from now on. A subscriber that
+    /// lets SUBSCRIBER_QUEUE_LEN events pile up is disconnected, it must subscribe again and
+    /// check the table for anything it missed
+    pub fn subscribe(&self) -> Receiver<BabelEvent> {
+        let (tx, rx) = sync_channel(SUBSCRIBER_QUEUE_LEN);
+        self.shared.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Runs a command on its own connection, babel only reports the result of a command
+    /// through the monitor connection as changes to the table
+    fn command(&self, cmd: &str) -> Result<(), BabelMonitorError> {
+        let mut stream = open_babel_stream(self.babel_port, self.timeout)?;
+        run_command(&mut stream, cmd)?;
+        Ok(())
+    }
+
+    /// Asks babel to start routing over iface
+    pub fn monitor(&self, iface: &str) -> Result<(), BabelMonitorError> {
+        self.command(&format!(
+            "interface {iface} max-rtt-penalty 2000 enable-timestamps true"
+        ))?;
+        trace!("Babel started monitoring: {}", iface);
+        Ok(())
+    }
+
+    /// Asks babel to stop routing over iface
+    pub fn unmonitor(&self, iface: &str) -> Result<(), BabelMonitorError> {
+        self.command(&format!("flush interface {iface}"))?;
+        trace!("Babel stopped monitoring: {}", iface);
+        Ok(())
+    }
+
+    pub fn set_local_fee(&self, new_fee: u32) -> Result<(), BabelMonitorError> {
+        self.command(&format!("fee {new_fee}"))
+    }
+
+    pub fn set_metric_factor(&self, new_factor: u32) -> Result<(), BabelMonitorError> {
+        self.command(&format!("metric-factor {new_factor}"))
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.shared.table.read().unwrap().is_synced()
+    }
+
+    /// A copy of the current table, check is_synced before relying on it
+    pub fn get_table(&self) -> BabelTable {
+        self.shared.table.read().unwrap().clone()
+    }
+
+    pub fn get_routes(&self) -> Result<Vec<Route>, BabelMonitorError> {
+        let table = self.shared.table.read().unwrap();
+        if !table.is_synced() {
+            return Err(BabelMonitorError::NotSynced);
+        }
+        Ok(table.get_routes())
+    }
+
+    pub fn get_neighs(&self) -> Result<Vec<Neighbor>, BabelMonitorError> {
+        let table = self.shared.table.read().unwrap();
+        if !table.is_synced() {
+            return Err(BabelMonitorError::NotSynced);
+        }
+        Ok(table.get_neighs())
+    }
+
+    pub fn get_interfaces(&self) -> Result<Vec<Interface>, BabelMonitorError> {
+        let table = self.shared.table.read().unwrap();
+        if !table.is_synced() {
+            return Err(BabelMonitorError::NotSynced);
+        }
+        Ok(table.get_interfaces())
+    }
+
+    pub fn get_local_fee(&self) -> Result<u32, BabelMonitorError> {
+        let table = self.shared.table.read().unwrap();
+        match (table.is_synced(), table.get_local_fee()) {
+            (true, Some(fee)) => Ok(fee),
+            (true, None) => Err(BabelMonitorError::LocalFeeNotFound(
+                "<No local fee in monitor output>".to_string(),
+            )),
+            (false, _) => Err(BabelMonitorError::NotSynced),
+        }
+    }
+}
+
+/// Runs a single monitor connection until it fails or every client handle is dropped
+fn run_monitor(
+    babel_port: u16,
+    timeout: Duration,
+    shared: &Weak<Shared>,
+) -> Result<(), BabelMonitorError> {
+    let mut stream = open_babel_stream(babel_port, timeout)?;
+    stream.write_all(b"monitor\n")?;
+    stream.set_read_timeout(Some(MONITOR_READ_TIMEOUT))?;
+    match shared.upgrade() {
+        Some(shared) => shared.table.write().unwrap().begin_sync(),
+        None => return Ok(()),
+    }
+    info!("Babel monitor connected, waiting for table");
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let shared = match shared.upgrade() {
+            Some(shared) => shared,
+            None => return Ok(()),
+        };
+        let events = shared.table.write().unwrap().apply_line(&line);
+        match events {
+            Ok(events) => shared.broadcast(events),
+            Err(BabelMonitorError::ReadFailed(e)) => return Err(BabelMonitorError::ReadFailed(e)),
+            // a line we can't parse is skipped, like it would be in a dump
+            Err(e) => trace!("Failed to parse babel monitor line {} with {}", line, e),
+        }
+    }
+    Err(BabelMonitorError::ReadFailed(
+        "Babel closed the monitor connection".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    static DUMP: &str = "local fee 1024\n\
+add interface wlan0 up true ipv6 fe80::1a8b:ec1:8542:1bd8 ipv4 10.28.119.131\n\
+add neighbour 14f05f0 address fe80::e9d0:498f:6c61:be29 if wlan0 reach feff rxcost 258 txcost 341 \
+rtt 18.674 rttcost 473 cost 817\n\
+add xroute 10.28.119.131/32-::/0 prefix 10.28.119.131/32 from ::/0 metric 0\n\
+add route 14f0820 prefix 10.28.7.7/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:5b:fe:c7 \
+metric 1596 price 3072 fee 3072 refmetric 638 full-path-rtt 22.805 via fe80::e9d0:498f:6c61:be29 if wlan0\n\
+add route 14f06d8 prefix 10.28.20.151/32 from 0.0.0.0/0 installed yes id ba:27:eb:ff:fe:c1:2d:d5 \
+metric 817 price 4008 fee 4008 refmetric 0 full-path-rtt 18.674 via fe80::e9d0:498f:6c61:be29 if wlan0\n\
+ok\n";
+
+    static CHANGE_LINE: &str =
+        "change route 14f0820 prefix 10.28.7.7/32 from 0.0.0.0/0 installed yes \
+id ba:27:eb:ff:fe:5b:fe:c7 metric 1200 price 3072 fee 3072 refmetric 638 full-path-rtt 22.805 \
+via fe80::e9d0:498f:6c61:be29 if wlan0";
+
+    static FLUSH_NEIGH_LINE: &str =
+        "flush neighbour 14f05f0 address fe80::e9d0:498f:6c61:be29 if wlan0 \
+reach 0000 rxcost 65535 txcost 341 rtt 18.674 rttcost 473 cost 65535";
+
+    fn apply(table: &mut BabelTable, lines: &str) -> Vec<BabelEvent> {
+        let mut events = Vec::new();
+        for line in lines.lines() {
+            events.extend(table.apply_line(line).unwrap());
+        }
+        events
+    }
+
+    #[test]
+    fn table_updates() {
+        let mut table = BabelTable::new();
+        table.begin_sync();
+        let events = apply(&mut table, DUMP);
+        assert!(table.is_synced());
+        assert_eq!(events.len(), 4);
+        assert_eq!(table.get_routes().len(), 2);
+        assert_eq!(table.get_neighs().len(), 1);
+        assert_eq!(table.get_interfaces().len(), 1);
+        assert_eq!(table.get_local_fee(), Some(1024));
+
+        let events = apply(&mut table, CHANGE_LINE);
+        match events.as_slice() {
+            [BabelEvent::RouteChanged(route)] => assert_eq!(route.metric, 1200),
+            _ => panic!("Unexpected events {:?}", events),
+        }
+        // repeating a line is not a change
+        assert!(apply(&mut table, CHANGE_LINE).is_empty());
+
+        let events = apply(&mut table, FLUSH_NEIGH_LINE);
+        match events.as_slice() {
+            [BabelEvent::NeighborDown(neigh)] => assert_eq!(neigh.id, "14f05f0"),
+            _ => panic!("Unexpected events {:?}", events),
+        }
+        assert!(table.get_neighs().is_empty());
+
+        assert!(table.apply_line("no").is_err());
+    }
+
+    #[test]
+    fn resync_removes_missing_entries() {
+        let mut table = BabelTable::new();
+        table.begin_sync();
+        apply(&mut table, DUMP);
+
+        // the connection dropped and when we came back one route was gone
+        table.desync();
+        assert!(!table.is_synced());
+        table.begin_sync();
+        let dump: String = DUMP
+            .lines()
+            .filter(|line| !line.contains("14f06d8"))
+            .map(|line| format!("{line}\n"))
+            .collect();
+        let events = apply(&mut table, &dump);
+        match events.as_slice() {
+            [BabelEvent::RouteRetracted(route)] => assert_eq!(route.id, "14f06d8"),
+            _ => panic!("Unexpected events {:?}", events),
+        }
+        assert!(table.is_synced());
+        assert_eq!(table.get_routes().len(), 1);
+    }
+
+    #[test]
+    fn monitor_client() {
+        let listener = TcpListener::bind("[::1]:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream
+                .write_all(b"ALTHEA 0.1\nversion babeld-1.8.0\nhost test\nmy-id ba:27:eb:ff:fe:09:06:dd\nok\n")
+                .unwrap();
+            let mut buf = [0u8; 8];
+            stream.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, b"monitor\n");
+            // give the test time to subscribe
+            thread::sleep(Duration::from_millis(100));
+            stream.write_all(DUMP.as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            stream
+                .write_all(format!("{CHANGE_LINE}\n").as_bytes())
+                .unwrap();
+            // hold the connection open until the test is done
+            thread::sleep(Duration::from_secs(5));
+        });
+
+        let client = BabelMonitorClient::start(port, Duration::from_secs(1));
+        let events = client.subscribe();
+        let mut routes_added = 0;
+        loop {
+            match events.recv_timeout(Duration::from_secs(4)).unwrap() {
+                BabelEvent::RouteAdded(_) => routes_added += 1,
+                BabelEvent::RouteChanged(route) => {
+                    assert_eq!(route.metric, 1200);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(routes_added, 2);
+        assert!(client.is_synced());
+        assert_eq!(client.get_routes().unwrap().len(), 2);
+        assert_eq!(client.get_local_fee().unwrap(), 1024);
+    }
+
+    #[test]
+    fn slow_subscriber_dropped() {
+        let shared = Shared::default();
+        let (tx, slow) = sync_channel(1);
+        shared.subscribers.lock().unwrap().push(tx);
+        let fast = {
+            let (tx, rx) = sync_channel(SUBSCRIBER_QUEUE_LEN);
+            shared.subscribers.lock().unwrap().push(tx);
+            rx
+        };
+
+        let mut table = BabelTable::new();
+        table.begin_sync();
+        let events = apply(&mut table, DUMP);
+        let count = events.len();
+        shared.broadcast(events);
+
+        // the slow subscriber gets what fit in its queue and is then disconnected
+        assert!(slow.recv().is_ok());
+        assert!(slow.recv().is_err());
+        assert_eq!(fast.try_iter().count(), count);
+        assert_eq!(shared.subscribers.lock().unwrap().len(), 1);
+    }
+}
@@ -1,13 +1,10 @@
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate lazy_static;
 
 use althea_kernel_interface::KI;
 use clarity::PrivateKey;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
-use regex::Regex;
 use settings::client::RitaClientSettings;
 use settings::exit::RitaExitSettingsStruct;
 use std::fmt::{Display, Formatter, Result as FmtResult};
@@ -62,23 +59,12 @@ pub fn validate_mesh_ip(ip: &IpAddr) -> bool {
     ip.is_ipv6() && !ip.is_unspecified()
 }
 
-/// Called before anything is started to delete existing wireguard per hop tunnels
+/// Called before anything is started to delete existing wireguard exit tunnels. Per hop tunnels
+/// are left alone, TunnelManager adopts the ones it saved on startup and deletes the rest
 pub fn cleanup() -> Result<(), NewCluError> {
     debug!("Cleaning up WireGuard tunnels");
 
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"^wg[0-9]+$").unwrap();
-    }
-
-    let mut interfaces: Vec<String> = Vec::new();
-
-    for i in KI.get_interfaces()? {
-        if RE.is_match(&i) {
-            interfaces.push(i);
-        }
-    }
-    interfaces.push("wg_exit".to_string());
-    interfaces.push("wg_exit_v2".to_string());
+    let interfaces = vec!["wg_exit".to_string(), "wg_exit_v2".to_string()];
 
     del_multiple_interfaces(interfaces);
     Ok(())
@@ -0,0 +1,38 @@
+//! Embeds the migrations in migrations/ so backends the exit sets up itself, like SQLite, are
+//! created from the same schema postgres gets from diesel_cli
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+    let mut dirs: Vec<_> = fs::read_dir("migrations")
+        .expect("Unable to read the migrations directory")
+        .map(|entry| entry.expect("Unable to read a migration").path())
+        .filter(|path| path.join("up.sql").exists())
+        .collect();
+    // the directories start with their timestamp so this is the order diesel runs them in
+    dirs.sort();
+
+    let mut out = String::from("pub const MIGRATIONS: &[Migration] = &[\n");
+    for dir in dirs {
+        let name = dir.file_name().unwrap().to_str().unwrap().to_string();
+        // diesel records a migration by the digits of the timestamp before the first _
+        let version: String = name
+            .split('_')
+            .next()
+            .unwrap()
+            .chars()
+            .filter(|c| c.is_ascii_digit())
+            .collect();
+        let up_sql = fs::canonicalize(dir.join("up.sql")).unwrap();
+        out.push_str(&format!(
+            "    Migration {{ version: {version:?}, name: {name:?}, up_sql: include_str!({up_sql:?}) }},\n"
+        ));
+    }
+    out.push_str("];\n");
+
+    let dest = Path::new(&env::var("OUT_DIR").unwrap()).join("migrations.rs");
+    fs::write(dest, out).expect("Unable to write the embedded migrations");
+}
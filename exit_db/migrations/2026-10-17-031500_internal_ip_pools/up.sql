@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE internal_ip_pools
+(
+    pool varchar(64) CONSTRAINT thirdkey PRIMARY KEY,
+    available_offsets varchar NOT NULL,
+    next_offset bigint DEFAULT 0 NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+CREATE TABLE banned_clients
+(
+    wg_pubkey varchar(64) CONSTRAINT fourthkey PRIMARY KEY,
+    reason varchar NOT NULL,
+    banned_at bigint DEFAULT 0 NOT NULL
+);
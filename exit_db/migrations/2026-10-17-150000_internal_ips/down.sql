@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE internal_ips;
+CREATE TABLE internal_ip_pools
+(
+    pool varchar(64) CONSTRAINT thirdkey PRIMARY KEY,
+    available_offsets varchar NOT NULL,
+    next_offset bigint DEFAULT 0 NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+DROP TABLE internal_ip_pools;
+CREATE TABLE internal_ips
+(
+    pool varchar(64) NOT NULL,
+    ip_offset bigint NOT NULL,
+    reserved_at bigint DEFAULT 0 NOT NULL,
+    released_at bigint DEFAULT 0 NOT NULL,
+    CONSTRAINT fifthkey PRIMARY KEY (pool, ip_offset)
+);
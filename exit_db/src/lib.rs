@@ -3,5 +3,6 @@ extern crate diesel;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod migrations;
 pub mod models;
 pub mod schema;
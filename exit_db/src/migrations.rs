@@ -0,0 +1,14 @@
+//! The migrations in exit_db/migrations, embedded at build time. Postgres exits are migrated with
+//! diesel_cli, these are for the backends rita creates and upgrades itself.
+
+/// A migration directory, in the order diesel would run it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Migration {
+    /// What diesel records the migration as having run under, the digits of its timestamp
+    pub version: &'static str,
+    /// The name of the migration's directory
+    pub name: &'static str,
+    pub up_sql: &'static str,
+}
+
+include!(concat!(env!("OUT_DIR"), "/migrations.rs"));
@@ -1,6 +1,8 @@
 #![allow(clippy::extra_unused_lifetimes)]
 use crate::schema::assigned_ips;
+use crate::schema::banned_clients;
 use crate::schema::clients;
+use crate::schema::internal_ips;
 
 #[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, Default)]
 #[table_name = "clients"]
@@ -29,10 +31,33 @@ pub struct Client {
 /// The iterative index stores the index at which we assign a subnet to a client
 /// For example, if our exit subnet is fd00::1000/120 and our client subnets are /124, index 0 represents
 /// fd00::1000/124 index 1 represents fd00::1010/124, 2 is fd00::1120/124 etc...
-#[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, Default)]
+#[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, Default, PartialEq, Eq)]
 #[table_name = "assigned_ips"]
 pub struct AssignedIps {
     pub subnet: String,
     pub available_subnets: String,
     pub iterative_index: i64,
 }
+
+/// An internal ipv4 address the allocator has handed out to a client. Addresses are tracked as offsets
+/// from the exit start ip, the pool is named by the start ip and netmask it covers for example
+/// "172.16.0.0/12". Times are in unix seconds, released_at is 0 while the address is held and otherwise
+/// when it was let go, it is only handed out again once it has been released for a grace period
+#[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, Default, PartialEq, Eq)]
+#[table_name = "internal_ips"]
+pub struct InternalIp {
+    pub pool: String,
+    pub ip_offset: i64,
+    pub reserved_at: i64,
+    pub released_at: i64,
+}
+
+/// A wireguard key the operator has banned from the exit. Banned keys are refused at signup and
+/// any client record they had is removed, banned_at is in unix seconds
+#[derive(Queryable, Serialize, Deserialize, Debug, Insertable, Clone, Default, PartialEq, Eq)]
+#[table_name = "banned_clients"]
+pub struct BannedClient {
+    pub wg_pubkey: String,
+    pub reason: String,
+    pub banned_at: i64,
+}
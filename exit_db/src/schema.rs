@@ -26,3 +26,20 @@ table! {
         iterative_index -> Int8,
     }
 }
+
+table! {
+    internal_ips (pool, ip_offset) {
+        pool -> Varchar,
+        ip_offset -> Int8,
+        reserved_at -> Int8,
+        released_at -> Int8,
+    }
+}
+
+table! {
+    banned_clients (wg_pubkey) {
+        wg_pubkey -> Varchar,
+        reason -> Varchar,
+        banned_at -> Int8,
+    }
+}
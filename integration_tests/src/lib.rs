@@ -11,6 +11,7 @@ pub mod payments_althea;
 pub mod payments_eth;
 pub mod setup_utils;
 pub mod utils;
+pub mod verification;
 
 /// The amount of time we wait for a network to stabalize before testing
 pub const SETUP_WAIT: Duration = Duration::from_secs(60);
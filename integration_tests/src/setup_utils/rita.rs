@@ -18,7 +18,7 @@ use rita_common::rita_loop::{
     start_core_rita_endpoints, start_rita_common_loops,
     write_to_disk::{save_to_disk_loop, SettingsOnDisk},
 };
-use rita_exit::initialize_db_pool;
+use rita_exit::initialize_client_store;
 use rita_exit::{
     operator_update::update_loop::start_operator_update_loop,
     rita_loop::{start_rita_exit_endpoints, start_rita_exit_loop},
@@ -254,7 +254,7 @@ pub fn spawn_rita_exit(
 
         let system = actix_async::System::new();
 
-        initialize_db_pool();
+        initialize_client_store();
 
         start_rita_common_loops();
         start_rita_exit_loop();
@@ -14,7 +14,7 @@ use althea_proto::{
         query_client::QueryClient, Metadata, QueryDenomMetadataRequest,
     },
 };
-use althea_types::{ContactType, Denom, Identity, SystemChain, WgKey};
+use althea_types::{ContactType, Denom, ExitState, Identity, SystemChain, WgKey};
 use awc::http::StatusCode;
 use babel_monitor::{open_babel_stream, parse_routes, structs::Route};
 use clarity::{Transaction, Uint256};
@@ -407,11 +407,37 @@ pub fn althea_system_chain_exit(settings: RitaExitSettingsStruct) -> RitaExitSet
 
 // Calls the register to exit rpc function within the provided namespace
 pub async fn register_to_exit(namespace_name: String, exit_name: String) -> StatusCode {
-    // thread safe lock that allows us to pass data between the router thread and this thread
-    // one copy of the reference is sent into the closure and the other is kept in this scope.
     let exit_network = TEST_EXIT_DETAILS
         .get(&exit_name)
         .expect("Please provide a valid exit");
+    post_to_dashboard(
+        namespace_name,
+        format!("/exits/{}/register", exit_network.exit_name),
+    )
+    .await
+}
+
+// Calls the verify rpc function within the provided namespace, submitting the code the user
+// was sent by the exit
+pub async fn verify_exit_code(
+    namespace_name: String,
+    exit_name: String,
+    code: String,
+) -> StatusCode {
+    let exit_network = TEST_EXIT_DETAILS
+        .get(&exit_name)
+        .expect("Please provide a valid exit");
+    post_to_dashboard(
+        namespace_name,
+        format!("/exits/{}/verify/{}", exit_network.exit_name, code),
+    )
+    .await
+}
+
+/// Posts to a path on the dashboard of the rita running in the provided namespace
+async fn post_to_dashboard(namespace_name: String, path: String) -> StatusCode {
+    // thread safe lock that allows us to pass data between the router thread and this thread
+    // one copy of the reference is sent into the closure and the other is kept in this scope.
     let response: Arc<RwLock<Option<StatusCode>>> = Arc::new(RwLock::new(None));
     let response_local = response.clone();
     let namespace_local = namespace_name.clone();
@@ -424,10 +450,7 @@ pub async fn register_to_exit(namespace_name: String, exit_name: String) -> Stat
         runner.block_on(async move {
             let client = awc::Client::default();
             let req = client
-                .post(format!(
-                    "http://localhost:4877/exits/{}/register",
-                    exit_network.exit_name
-                ))
+                .post(format!("http://localhost:4877{path}"))
                 .send()
                 .await
                 .expect("Failed to make request to rita RPC");
@@ -444,6 +467,24 @@ pub async fn register_to_exit(namespace_name: String, exit_name: String) -> Stat
     code
 }
 
+/// Reads the state the rita client in the provided namespace has for the exit. Settings are keyed
+/// by network namespace so this has to be done from a thread inside it
+pub fn get_exit_state(namespace_name: String, exit_name: String) -> ExitState {
+    thread::spawn(move || {
+        let nsfd = get_nsfd(namespace_name);
+        setns(nsfd, CloneFlags::CLONE_NEWNET).expect("Couldn't set network namespace");
+        settings::get_rita_client()
+            .exit_client
+            .exits
+            .get(&exit_name)
+            .expect("Please provide a valid exit")
+            .info
+            .clone()
+    })
+    .join()
+    .expect("Failed to read client settings")
+}
+
 /// This allows the tester to exit cleanly then it gets a ctrl-c message
 /// allowing you to reuse a test env and save a lot of setup
 pub fn set_sigterm() {
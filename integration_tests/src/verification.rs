@@ -0,0 +1,147 @@
+use crate::five_nodes::five_node_config;
+use crate::setup_utils::database::start_postgres;
+use crate::setup_utils::namespaces::*;
+use crate::setup_utils::rita::thread_spawner;
+use crate::utils::{
+    get_default_settings, get_exit_state, register_to_exit, test_all_internet_connectivity,
+    test_reach_all, test_routes, verify_exit_code, TEST_EXIT_NAME,
+};
+use actix_rt::time::sleep;
+use althea_types::{ContactType, ExitState, ExitVerifMode};
+use log::info;
+use mock_verification::{start_sms_server, start_smtp_server, MockVerification};
+use settings::exit::{EmailVerifSettings, ExitVerifSettings, PhoneVerifSettings};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+
+/// The address of the native namespace on the bridge every exit routes through, the mock
+/// services listen here so the exits can reach them
+const MOCK_SERVICES_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+const TEST_PHONE: &str = "+15555555555";
+const TEST_EMAIL: &str = "client@example.com";
+const VERIFICATION_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Runs the five node network with the exit verifying clients by phone or email against stand
+/// ins for Twilio Verify and an smtp server. Each client signs up, which sends it a code, then
+/// submits the code through its dashboard and waits to be registered
+pub async fn run_verification_test(verif_mode: ExitVerifMode) {
+    info!("Starting {:?} verification test", verif_mode);
+    let node_config = five_node_config();
+    let namespaces = node_config.0;
+    let expected_routes = node_config.1;
+
+    let (mut client_settings, mut exit_settings) =
+        get_default_settings(TEST_EXIT_NAME.to_string(), namespaces.clone());
+
+    namespaces.validate();
+
+    start_postgres();
+    let res = setup_ns(namespaces.clone());
+    info!("Namespaces setup: {res:?}");
+
+    // the bridge address only exists once the namespaces are setup. The sms server runs on this
+    // runtime, which is free to serve it while the test awaits the dashboard requests that
+    // cause the exit to call it, so nothing here may block the runtime while waiting
+    let mock = MockVerification::new();
+    exit_settings.verif_settings = Some(match verif_mode {
+        ExitVerifMode::Phone => {
+            let url = start_sms_server(mock.clone(), MOCK_SERVICES_IP)
+                .expect("Could not start mock sms server");
+            ExitVerifSettings::Phone(PhoneVerifSettings {
+                auth_api_key: String::new(),
+                verify_service_id: "VAtest".to_string(),
+                notification_number: "+15555555500".to_string(),
+                twillio_account_id: "ACtest".to_string(),
+                twillio_auth_token: "test_auth_token".to_string(),
+                operator_notification_number: Vec::new(),
+                verify_url: url.clone(),
+                twilio_url: url,
+            })
+        }
+        ExitVerifMode::Email => {
+            let url = start_smtp_server(mock.clone(), MOCK_SERVICES_IP)
+                .expect("Could not start mock smtp server");
+            ExitVerifSettings::Email(EmailVerifSettings {
+                from_address: "email-verif@example.com".to_string(),
+                email_cooldown: 60,
+                signup_subject: "Althea Exit verification code".to_string(),
+                // the code is bracketed so it can be picked back out of the mail
+                signup_body: "Your althea verification code is [{{email_code}}]".to_string(),
+                smtp_domain: "exit.example.com".to_string(),
+                smtp_connection_url: Some(url),
+                balance_notification_interval: 600,
+                ..Default::default()
+            })
+        }
+        ExitVerifMode::Off => panic!("The verification test needs a verification mode"),
+    });
+    client_settings.exit_client.contact_info = Some(
+        ContactType::Both {
+            number: TEST_PHONE.parse().unwrap(),
+            email: TEST_EMAIL.parse().unwrap(),
+            sequence_number: Some(0),
+        }
+        .into(),
+    );
+
+    let _ = thread_spawner(namespaces.clone(), client_settings, exit_settings)
+        .expect("Could not spawn Rita threads");
+
+    test_reach_all(namespaces.clone());
+
+    test_routes(namespaces.clone(), expected_routes);
+
+    // every client shares the same contact details, so they are verified one at a time to keep
+    // the latest code sent belonging to the client being verified
+    for ns in namespaces.names.clone() {
+        if let NodeType::Client { cluster_name } = ns.node_type.clone() {
+            signup(ns.get_name(), cluster_name.clone()).await;
+            let code = match verif_mode {
+                ExitVerifMode::Phone => mock.code_for(TEST_PHONE),
+                _ => mock.last_mail_to(TEST_EMAIL).and_then(|mail| {
+                    let start = mail.data.find('[')? + 1;
+                    let end = mail.data[start..].find(']')? + start;
+                    Some(mail.data[start..end].to_string())
+                }),
+            }
+            .unwrap_or_else(|| panic!("{} was never sent a code", ns.get_name()));
+
+            info!("Verifying {} with code {}", ns.get_name(), code);
+            let res = verify_exit_code(ns.get_name(), cluster_name.clone(), code).await;
+            assert!(res.is_success(), "Verification of {} failed", ns.get_name());
+            match get_exit_state(ns.get_name(), cluster_name.clone()) {
+                ExitState::Registered { .. } => {}
+                state => panic!("{} is {:?} after verifying", ns.get_name(), state),
+            }
+            info!("{} registered to exit {}", ns.get_name(), cluster_name);
+        }
+    }
+    if let ExitVerifMode::Phone = verif_mode {
+        assert!(mock.checks().iter().all(|c| c.valid));
+    }
+
+    info!("Checking for wg_exit tunnel setup");
+    sleep(Duration::from_secs(5)).await;
+    test_all_internet_connectivity(namespaces.clone());
+}
+
+/// Asks the exit to register the client, retrying until it has heard from the exit, and waits
+/// for the exit to put it in Pending while it verifies
+async fn signup(namespace: String, cluster_name: String) {
+    let start = Instant::now();
+    loop {
+        let res = register_to_exit(namespace.clone(), cluster_name.clone()).await;
+        if res.is_success() {
+            if let ExitState::Pending { .. } =
+                get_exit_state(namespace.clone(), cluster_name.clone())
+            {
+                return;
+            }
+        }
+        if Instant::now() - start > VERIFICATION_TIMEOUT {
+            panic!("Failed to sign {namespace} up to exit");
+        }
+        info!("{namespace} is not pending verification yet, trying again");
+        sleep(Duration::from_secs(1)).await;
+    }
+}
@@ -0,0 +1,401 @@
+//! A stand in for babeld's local control socket. MockBabel holds the interface, neighbor and
+//! route tables that tests script directly, and start_babel_server serves them over the same
+//! line based protocol babeld uses, replies terminated by ok, no or bad. Monitor connections
+//! receive the full dump and then every change made to the tables afterwards, so code built
+//! on babel_monitor can be exercised without running a real babeld.
+
+#![warn(clippy::all)]
+#![allow(clippy::pedantic)]
+#![forbid(unsafe_code)]
+
+#[macro_use]
+extern crate log;
+
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, RwLock};
+
+pub mod server;
+
+pub use server::start_babel_server;
+
+/// The local fee babeld starts with
+pub const DEFAULT_LOCAL_FEE: u32 = 0;
+/// The metric factor babeld starts with
+pub const DEFAULT_METRIC_FACTOR: u32 = 1900;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockInterface {
+    pub name: String,
+    pub up: bool,
+    pub ipv4: Option<IpAddr>,
+    pub ipv6: Option<IpAddr>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockNeighbor {
+    /// babeld uses the address of its internal struct as an id, any unique string will do
+    pub id: String,
+    pub address: IpAddr,
+    pub iface: String,
+    pub reach: u16,
+    pub rxcost: u16,
+    pub txcost: u16,
+    pub rtt: f32,
+    pub rttcost: u16,
+    pub cost: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockRoute {
+    pub id: String,
+    /// The destination in cidr notation, for example fd00::1/128
+    pub prefix: String,
+    pub installed: bool,
+    /// The babel router id of the node that originated this route
+    pub router_id: String,
+    pub metric: u16,
+    pub refmetric: u16,
+    pub price: u32,
+    pub fee: u32,
+    pub full_path_rtt: f32,
+    pub via: IpAddr,
+    pub iface: String,
+}
+
+impl MockInterface {
+    pub fn new(name: &str) -> MockInterface {
+        MockInterface {
+            name: name.to_string(),
+            up: true,
+            ipv4: None,
+            ipv6: None,
+        }
+    }
+
+    fn to_line(&self, verb: &str) -> String {
+        let mut line = format!("{} interface {} up {}", verb, self.name, self.up);
+        if let Some(ip) = self.ipv6 {
+            line += &format!(" ipv6 {ip}");
+        }
+        if let Some(ip) = self.ipv4 {
+            line += &format!(" ipv4 {ip}");
+        }
+        line
+    }
+}
+
+impl MockNeighbor {
+    /// A neighbor with a perfect link, tests change the fields they care about
+    pub fn new(id: &str, address: IpAddr, iface: &str) -> MockNeighbor {
+        MockNeighbor {
+            id: id.to_string(),
+            address,
+            iface: iface.to_string(),
+            reach: 0xffff,
+            rxcost: 256,
+            txcost: 256,
+            rtt: 0.0,
+            rttcost: 0,
+            cost: 256,
+        }
+    }
+
+    fn to_line(&self, verb: &str) -> String {
+        format!(
+            "{} neighbour {} address {} if {} reach {:04x} rxcost {} txcost {} rtt {:.3} \
+             rttcost {} cost {}",
+            verb,
+            self.id,
+            self.address,
+            self.iface,
+            self.reach,
+            self.rxcost,
+            self.txcost,
+            self.rtt,
+            self.rttcost,
+            self.cost
+        )
+    }
+}
+
+impl MockRoute {
+    /// An installed route to prefix through the neighbor at via, tests change the fields they
+    /// care about
+    pub fn new(id: &str, prefix: &str, via: IpAddr, iface: &str) -> MockRoute {
+        MockRoute {
+            id: id.to_string(),
+            prefix: prefix.to_string(),
+            installed: true,
+            router_id: "ba:27:eb:ff:fe:00:00:02".to_string(),
+            metric: 256,
+            refmetric: 0,
+            price: 0,
+            fee: 0,
+            full_path_rtt: 0.0,
+            via,
+            iface: iface.to_string(),
+        }
+    }
+
+    fn to_line(&self, verb: &str) -> String {
+        let from = if self.prefix.contains(':') {
+            "::/0"
+        } else {
+            "0.0.0.0/0"
+        };
+        format!(
+            "{} route {} prefix {} from {} installed {} id {} metric {} price {} fee {} \
+             refmetric {} full-path-rtt {:.3} via {} if {}",
+            verb,
+            self.id,
+            self.prefix,
+            from,
+            if self.installed { "yes" } else { "no" },
+            self.router_id,
+            self.metric,
+            self.price,
+            self.fee,
+            self.refmetric,
+            self.full_path_rtt,
+            self.via,
+            self.iface
+        )
+    }
+}
+
+/// A babeld whose tables are scripted by the test, clones share the same state so a test can
+/// keep one handle and give another to start_babel_server
+#[derive(Debug, Clone, Default)]
+pub struct MockBabel {
+    state: Arc<RwLock<MockBabelState>>,
+}
+
+#[derive(Debug)]
+struct MockBabelState {
+    local_fee: u32,
+    metric_factor: u32,
+    interfaces: BTreeMap<String, MockInterface>,
+    neighbors: BTreeMap<String, MockNeighbor>,
+    routes: BTreeMap<String, MockRoute>,
+    /// Every command received, in order
+    commands: Vec<String>,
+    /// Commands (by their first word) that get a no in place of their next reply
+    fail_next: HashSet<String>,
+    /// Change lines are pushed to every connection in monitor mode through these
+    monitors: Vec<Sender<String>>,
+}
+
+impl Default for MockBabelState {
+    fn default() -> Self {
+        MockBabelState {
+            local_fee: DEFAULT_LOCAL_FEE,
+            metric_factor: DEFAULT_METRIC_FACTOR,
+            interfaces: BTreeMap::new(),
+            neighbors: BTreeMap::new(),
+            routes: BTreeMap::new(),
+            commands: Vec::new(),
+            fail_next: HashSet::new(),
+            monitors: Vec::new(),
+        }
+    }
+}
+
+impl MockBabelState {
+    /// Sends a change line to every monitor, dropping those whose connection has gone away
+    fn notify(&mut self, line: String) {
+        self.monitors.retain(|m| m.send(line.clone()).is_ok());
+    }
+
+    /// The full table as babeld prints it for dump and at the start of monitor mode, without
+    /// the terminating ok
+    fn dump(&self) -> String {
+        let mut out = format!("local fee {}\n", self.local_fee);
+        for iface in self.interfaces.values() {
+            out += &iface.to_line("add");
+            out.push('\n');
+        }
+        for neigh in self.neighbors.values() {
+            out += &neigh.to_line("add");
+            out.push('\n');
+        }
+        for route in self.routes.values() {
+            out += &route.to_line("add");
+            out.push('\n');
+        }
+        out
+    }
+
+    fn set_interface(&mut self, iface: MockInterface) {
+        let verb = if self.interfaces.contains_key(&iface.name) {
+            "change"
+        } else {
+            "add"
+        };
+        self.notify(iface.to_line(verb));
+        self.interfaces.insert(iface.name.clone(), iface);
+    }
+
+    fn remove_interface(&mut self, name: &str) -> bool {
+        match self.interfaces.remove(name) {
+            Some(iface) => {
+                self.notify(iface.to_line("flush"));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl MockBabel {
+    pub fn new() -> MockBabel {
+        MockBabel::default()
+    }
+
+    pub fn local_fee(&self) -> u32 {
+        self.state.read().unwrap().local_fee
+    }
+
+    /// Sets the local fee as if it were changed with a fee command, monitors are not notified
+    /// since babeld only reports the fee in a full dump
+    pub fn set_local_fee(&self, fee: u32) {
+        self.state.write().unwrap().local_fee = fee;
+    }
+
+    pub fn metric_factor(&self) -> u32 {
+        self.state.read().unwrap().metric_factor
+    }
+
+    pub fn set_metric_factor(&self, factor: u32) {
+        self.state.write().unwrap().metric_factor = factor;
+    }
+
+    pub fn get_interfaces(&self) -> Vec<MockInterface> {
+        self.state
+            .read()
+            .unwrap()
+            .interfaces
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Adds or replaces an interface, keyed by name
+    pub fn set_interface(&self, iface: MockInterface) {
+        self.state.write().unwrap().set_interface(iface)
+    }
+
+    pub fn remove_interface(&self, name: &str) {
+        self.state.write().unwrap().remove_interface(name);
+    }
+
+    pub fn get_neighbors(&self) -> Vec<MockNeighbor> {
+        self.state
+            .read()
+            .unwrap()
+            .neighbors
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Adds or replaces a neighbor, keyed by id
+    pub fn set_neighbor(&self, neigh: MockNeighbor) {
+        let mut state = self.state.write().unwrap();
+        let verb = if state.neighbors.contains_key(&neigh.id) {
+            "change"
+        } else {
+            "add"
+        };
+        state.notify(neigh.to_line(verb));
+        state.neighbors.insert(neigh.id.clone(), neigh);
+    }
+
+    pub fn remove_neighbor(&self, id: &str) {
+        let mut state = self.state.write().unwrap();
+        if let Some(neigh) = state.neighbors.remove(id) {
+            state.notify(neigh.to_line("flush"));
+        }
+    }
+
+    pub fn get_routes(&self) -> Vec<MockRoute> {
+        self.state
+            .read()
+            .unwrap()
+            .routes
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Adds or replaces a route, keyed by id
+    pub fn set_route(&self, route: MockRoute) {
+        let mut state = self.state.write().unwrap();
+        let verb = if state.routes.contains_key(&route.id) {
+            "change"
+        } else {
+            "add"
+        };
+        state.notify(route.to_line(verb));
+        state.routes.insert(route.id.clone(), route);
+    }
+
+    pub fn remove_route(&self, id: &str) {
+        let mut state = self.state.write().unwrap();
+        if let Some(route) = state.routes.remove(id) {
+            state.notify(route.to_line("flush"));
+        }
+    }
+
+    /// Every command received by the server so far, without the trailing newline
+    pub fn commands(&self) -> Vec<String> {
+        self.state.read().unwrap().commands.clone()
+    }
+
+    /// Makes the next command starting with this word (for example fee or dump) fail with a
+    /// no reply without changing any state
+    pub fn fail_next(&self, command: &str) {
+        self.state
+            .write()
+            .unwrap()
+            .fail_next
+            .insert(command.to_string());
+    }
+
+    /// Closes every connection in monitor mode, as if babeld had restarted
+    pub fn disconnect_monitors(&self) {
+        self.state.write().unwrap().monitors.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use babel_monitor::parsing::{parse_interface_line, parse_neigh_line, parse_route_line};
+
+    #[test]
+    fn test_lines_parse() {
+        let via: IpAddr = "fe80::1".parse().unwrap();
+        let mut route = MockRoute::new("1", "fd00::2/128", via, "wg0");
+        route.price = 42;
+        let parsed = parse_route_line(&route.to_line("add")).unwrap();
+        assert_eq!(parsed.prefix, "fd00::2/128".parse().unwrap());
+        assert_eq!(parsed.neigh_ip, via);
+        assert_eq!(parsed.price, 42);
+        assert!(parsed.installed);
+
+        let mut neigh = MockNeighbor::new("2", via, "wg0");
+        neigh.reach = 0xff00;
+        let parsed = parse_neigh_line(&neigh.to_line("change")).unwrap();
+        assert_eq!(parsed.reach, 0xff00);
+        assert_eq!(parsed.cost, 256);
+
+        let mut iface = MockInterface::new("wg0");
+        iface.ipv6 = Some(via);
+        let parsed = parse_interface_line(&iface.to_line("add")).unwrap();
+        assert_eq!(parsed.name, "wg0");
+        assert_eq!(parsed.ipv6, Some(via));
+        assert_eq!(parsed.ipv4, None);
+    }
+}
@@ -0,0 +1,227 @@
+//! Serves a MockBabel over TCP, one thread per connection. Replies follow babeld's framing, any
+//! output for a command is followed by a line containing only ok, no (the command was understood
+//! but failed) or bad (the command was not understood).
+
+use crate::{MockBabel, MockInterface};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const PREAMBLE: &str =
+    "ALTHEA 0.1\nversion babeld-mock\nhost mock\nmy-id ba:27:eb:ff:fe:00:00:01\nok\n";
+
+/// Starts a control socket server for this babel on a random port on [::1] and returns the
+/// port, so it can be used anywhere a babel port is expected. The server runs until the test
+/// process exits
+pub fn start_babel_server(babel: MockBabel) -> io::Result<u16> {
+    let listener = TcpListener::bind("[::1]:0")?;
+    let port = listener.local_addr()?.port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let babel = babel.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(babel, stream) {
+                            trace!("Mock babel connection closed with {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Mock babel failed to accept with {:?}", e),
+            }
+        }
+    });
+    Ok(port)
+}
+
+fn handle_connection(babel: MockBabel, stream: TcpStream) -> io::Result<()> {
+    let reader = BufReader::new(stream.try_clone()?);
+    // monitor mode writes from a second thread, the lock keeps replies and change lines whole
+    let writer = Arc::new(Mutex::new(stream));
+    writer.lock().unwrap().write_all(PREAMBLE.as_bytes())?;
+
+    for line in reader.lines() {
+        let line = line?;
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+        trace!("Mock babel got command {}", command);
+
+        if command == "monitor" {
+            start_monitor(&babel, &writer)?;
+            continue;
+        }
+        let reply = run_command(&babel, command);
+        writer.lock().unwrap().write_all(reply.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Sends the full table followed by ok and then forwards every change until the connection
+/// closes or the test calls disconnect_monitors
+fn start_monitor(babel: &MockBabel, writer: &Arc<Mutex<TcpStream>>) -> io::Result<()> {
+    let (tx, rx) = channel::<String>();
+    let dump = {
+        let mut state = babel.state.write().unwrap();
+        state.commands.push("monitor".to_string());
+        // registered under the same lock the dump is taken with so no change is missed
+        state.monitors.push(tx);
+        state.dump()
+    };
+    writer
+        .lock()
+        .unwrap()
+        .write_all(format!("{dump}ok\n").as_bytes())?;
+
+    let writer = writer.clone();
+    thread::spawn(move || {
+        for line in rx {
+            let mut stream = writer.lock().unwrap();
+            if stream.write_all(format!("{line}\n").as_bytes()).is_err() {
+                return;
+            }
+        }
+        // every sender is gone, babel has 'restarted'
+        let _ = writer.lock().unwrap().shutdown(Shutdown::Both);
+    });
+    Ok(())
+}
+
+/// Runs a single command against the babel state and returns the full reply
+fn run_command(babel: &MockBabel, command: &str) -> String {
+    let mut state = babel.state.write().unwrap();
+    state.commands.push(command.to_string());
+    let words: Vec<&str> = command.split_whitespace().collect();
+    if state.fail_next.remove(words[0]) {
+        return "no\n".to_string();
+    }
+
+    match words.as_slice() {
+        ["dump"] => format!("{}ok\n", state.dump()),
+        ["fee", fee] => match fee.parse() {
+            Ok(fee) => {
+                state.local_fee = fee;
+                "ok\n".to_string()
+            }
+            Err(_) => "bad\n".to_string(),
+        },
+        ["metric-factor", factor] => match factor.parse() {
+            Ok(factor) => {
+                state.metric_factor = factor;
+                "ok\n".to_string()
+            }
+            Err(_) => "bad\n".to_string(),
+        },
+        // options such as max-rtt-penalty are accepted and ignored
+        ["interface", name, ..] => {
+            if !state.interfaces.contains_key(*name) {
+                state.set_interface(MockInterface::new(name));
+            }
+            "ok\n".to_string()
+        }
+        ["flush", "interface", name] => {
+            if state.remove_interface(name) {
+                "ok\n".to_string()
+            } else {
+                "no\n".to_string()
+            }
+        }
+        ["redistribute", ..] => "ok\n".to_string(),
+        _ => {
+            warn!("Mock babel got unknown command {}", command);
+            "bad\n".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MockNeighbor, MockRoute};
+    use babel_monitor::structs::BabelMonitorError;
+    use babel_monitor::subscription::{BabelEvent, BabelMonitorClient};
+    use babel_monitor::{
+        get_local_fee, open_babel_stream, parse_interfaces, parse_neighs, parse_routes,
+        set_local_fee, set_metric_factor,
+    };
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    fn scripted_babel() -> MockBabel {
+        let babel = MockBabel::new();
+        let via: IpAddr = "fe80::1".parse().unwrap();
+        babel.set_interface(MockInterface::new("wg0"));
+        babel.set_neighbor(MockNeighbor::new("1", via, "wg0"));
+        let mut route = MockRoute::new("2", "fd00::2/128", via, "wg0");
+        route.price = 10;
+        babel.set_route(route);
+        babel
+    }
+
+    #[test]
+    fn test_commands() {
+        let babel = scripted_babel();
+        let port = start_babel_server(babel.clone()).unwrap();
+        let mut stream = open_babel_stream(port, TIMEOUT).unwrap();
+
+        assert_eq!(parse_routes(&mut stream).unwrap()[0].price, 10);
+        assert_eq!(parse_neighs(&mut stream).unwrap().len(), 1);
+        assert_eq!(parse_interfaces(&mut stream).unwrap()[0].name, "wg0");
+
+        set_local_fee(&mut stream, 25).unwrap();
+        assert_eq!(babel.local_fee(), 25);
+        assert_eq!(get_local_fee(&mut stream).unwrap(), 25);
+        set_metric_factor(&mut stream, 0).unwrap();
+        assert_eq!(babel.metric_factor(), 0);
+
+        babel_monitor::monitor(&mut stream, "wg1").unwrap();
+        assert_eq!(babel.get_interfaces().len(), 2);
+        babel_monitor::unmonitor(&mut stream, "wg1").unwrap();
+        assert_eq!(babel.get_interfaces().len(), 1);
+
+        babel.fail_next("fee");
+        match set_local_fee(&mut stream, 30) {
+            Err(BabelMonitorError::ReadFailed(_)) => {}
+            r => panic!("Expected a failed fee change, got {:?}", r),
+        }
+        assert_eq!(babel.local_fee(), 25);
+        assert!(babel_monitor::run_command(&mut stream, "not-a-command").is_err());
+        assert_eq!(babel.commands().last().unwrap(), "not-a-command");
+    }
+
+    #[test]
+    fn test_monitor() {
+        let babel = scripted_babel();
+        let port = start_babel_server(babel.clone()).unwrap();
+        let client = BabelMonitorClient::start(port, TIMEOUT);
+        let start = Instant::now();
+        while !client.is_synced() {
+            assert!(start.elapsed() < TIMEOUT, "Monitor never synced");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(client.get_routes().unwrap().len(), 1);
+        assert_eq!(client.get_local_fee().unwrap(), 0);
+
+        let events = client.subscribe();
+        let mut route = babel.get_routes()[0].clone();
+        route.price = 20;
+        babel.set_route(route);
+        babel.remove_neighbor("1");
+        match events.recv_timeout(TIMEOUT).unwrap() {
+            BabelEvent::RouteChanged(r) => assert_eq!(r.price, 20),
+            e => panic!("Unexpected event {:?}", e),
+        }
+        match events.recv_timeout(TIMEOUT).unwrap() {
+            BabelEvent::NeighborDown(n) => assert_eq!(n.id, "1"),
+            e => panic!("Unexpected event {:?}", e),
+        }
+        assert_eq!(client.get_routes().unwrap()[0].price, 20);
+        assert!(client.get_neighs().unwrap().is_empty());
+    }
+}
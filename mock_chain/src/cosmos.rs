@@ -0,0 +1,259 @@
+//! Serves a MockChain over the Cosmos gRPC queries deep_space uses to look up bank transfers, balances,
+//! account sequences and chain status. Transactions can't be broadcast to this server, tests script
+//! them with MockChain::submit_cosmos_transfers instead. A transaction is only returned once it is
+//! in a block, until then it is reported as not found just like a real node.
+
+// tonic handlers return its Status as their error, which is large but not ours to shrink
+#![allow(clippy::result_large_err)]
+
+use crate::{Account, ChainState, MockChain, TxKind};
+use cosmos_sdk_proto_althea::cosmos::auth::v1beta1::{
+    BaseAccount, QueryAccountRequest, QueryAccountResponse,
+};
+use cosmos_sdk_proto_althea::cosmos::bank::v1beta1::{
+    MsgSend, QueryBalanceRequest, QueryBalanceResponse,
+};
+use cosmos_sdk_proto_althea::cosmos::base::abci::v1beta1::TxResponse;
+use cosmos_sdk_proto_althea::cosmos::base::tendermint::v1beta1::{
+    GetLatestBlockRequest, GetLatestBlockResponse, GetSyncingRequest, GetSyncingResponse,
+};
+use cosmos_sdk_proto_althea::cosmos::base::v1beta1::Coin;
+use cosmos_sdk_proto_althea::cosmos::tx::v1beta1::{GetTxRequest, GetTxResponse, TxBody, TxRaw};
+use cosmos_sdk_proto_althea::tendermint::types::{Block, Commit, Header};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Server};
+use num256::Uint256;
+use num_traits::Num;
+use prost::Message;
+use prost_types::Any;
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+use std::io;
+use std::net::TcpListener;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::codec::ProstCodec;
+use tonic::codegen::Service;
+use tonic::server::Grpc;
+use tonic::Status;
+
+/// Chain id reported in block headers
+pub const CHAIN_ID: &str = "mock-chain";
+
+/// Starts a gRPC server for this chain on a random local port and returns its url. Must be called
+/// from within a tokio runtime, such as an actix System, the server runs until that runtime stops
+pub fn start_cosmos_server(chain: MockChain) -> io::Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+
+    let make_service = make_service_fn(move |_| {
+        let chain = chain.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let chain = chain.clone();
+                async move { Ok::<_, Infallible>(handle_request(chain, req).await) }
+            }))
+        }
+    });
+    let server = Server::from_tcp(listener)
+        .map_err(io::Error::other)?
+        .http2_only(true)
+        .serve(make_service);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Mock cosmos server failed with {:?}", e);
+        }
+    });
+    Ok(format!("http://{addr}"))
+}
+
+async fn handle_request(chain: MockChain, req: Request<Body>) -> http::Response<BoxBody> {
+    trace!("Mock cosmos request {}", req.uri().path());
+    match req.uri().path() {
+        "/cosmos.bank.v1beta1.Query/Balance" => {
+            unary(req, move |r: QueryBalanceRequest| {
+                chain.read(|state| balance(state, r))
+            })
+            .await
+        }
+        "/cosmos.auth.v1beta1.Query/Account" => {
+            unary(req, move |r: QueryAccountRequest| {
+                chain.read(|state| account(state, r))
+            })
+            .await
+        }
+        "/cosmos.tx.v1beta1.Service/GetTx" => {
+            unary(req, move |r: GetTxRequest| {
+                chain.read(|state| get_tx(state, r))
+            })
+            .await
+        }
+        "/cosmos.base.tendermint.v1beta1.Service/GetSyncing" => {
+            unary(req, move |_: GetSyncingRequest| {
+                chain.read(|state| {
+                    Ok(GetSyncingResponse {
+                        syncing: state.syncing,
+                    })
+                })
+            })
+            .await
+        }
+        "/cosmos.base.tendermint.v1beta1.Service/GetLatestBlock" => {
+            unary(req, move |_: GetLatestBlockRequest| {
+                chain.read(latest_block)
+            })
+            .await
+        }
+        path => Status::unimplemented(format!("{path} not supported by mock chain")).to_http(),
+    }
+}
+
+fn balance(state: &ChainState, req: QueryBalanceRequest) -> Result<QueryBalanceResponse, Status> {
+    let amount = state.balance(&Account::Cosmos(req.address), &req.denom);
+    Ok(QueryBalanceResponse {
+        balance: Some(Coin {
+            denom: req.denom,
+            amount: amount.to_string(),
+        }),
+    })
+}
+
+fn account(state: &ChainState, req: QueryAccountRequest) -> Result<QueryAccountResponse, Status> {
+    let account = Account::Cosmos(req.address.clone());
+    let known = state
+        .balances
+        .iter()
+        .any(|((a, _), amount)| *a == account && *amount > 0u8.into());
+    let sequence = state
+        .transactions
+        .values()
+        .filter(|tx| {
+            tx.kind == TxKind::Cosmos
+                && tx.block.is_some()
+                && tx.transfers.first().map(|t| &t.from) == Some(&account)
+        })
+        .count() as u64;
+    if !known && sequence == 0 {
+        return Err(Status::not_found(format!(
+            "account {} not found",
+            req.address
+        )));
+    }
+    let base_account = BaseAccount {
+        address: req.address,
+        pub_key: None,
+        account_number: 0,
+        sequence,
+    };
+    Ok(QueryAccountResponse {
+        account: Some(Any {
+            type_url: "/cosmos.auth.v1beta1.BaseAccount".to_string(),
+            value: base_account.encode_to_vec(),
+        }),
+    })
+}
+
+fn get_tx(state: &ChainState, req: GetTxRequest) -> Result<GetTxResponse, Status> {
+    let not_found = || Status::not_found(format!("tx not found: {}", req.hash));
+    let hash = Uint256::from_str_radix(&req.hash, 16).map_err(|_| not_found())?;
+    let (tx, height) = match state.transactions.get(&hash) {
+        Some(tx) if tx.kind == TxKind::Cosmos => match tx.block {
+            Some(height) => (tx, height),
+            None => return Err(not_found()),
+        },
+        _ => return Err(not_found()),
+    };
+
+    let mut messages = Vec::new();
+    for transfer in tx.transfers.iter() {
+        let (from, to) = match (&transfer.from, &transfer.to) {
+            (Account::Cosmos(from), Account::Cosmos(to)) => (from.clone(), to.clone()),
+            _ => return Err(Status::internal("Not a cosmos transfer")),
+        };
+        let send = MsgSend {
+            from_address: from,
+            to_address: to,
+            amount: vec![Coin {
+                denom: transfer.denom.clone(),
+                amount: transfer.amount.to_string(),
+            }],
+        };
+        messages.push(Any {
+            type_url: "/cosmos.bank.v1beta1.MsgSend".to_string(),
+            value: send.encode_to_vec(),
+        });
+    }
+    let body = TxBody {
+        messages,
+        ..Default::default()
+    };
+    // TxRaw shares its wire format with Tx, so it can stand in for the signed transaction
+    let raw = TxRaw {
+        body_bytes: body.encode_to_vec(),
+        auth_info_bytes: Vec::new(),
+        signatures: Vec::new(),
+    };
+    Ok(GetTxResponse {
+        tx: None,
+        tx_response: Some(TxResponse {
+            height: height as i64,
+            txhash: format!("{:X}", hash),
+            tx: Some(Any {
+                type_url: "/cosmos.tx.v1beta1.Tx".to_string(),
+                value: raw.encode_to_vec(),
+            }),
+            ..Default::default()
+        }),
+    })
+}
+
+fn latest_block(state: &ChainState) -> Result<GetLatestBlockResponse, Status> {
+    let height = state.height as i64;
+    Ok(GetLatestBlockResponse {
+        block_id: None,
+        block: Some(Block {
+            header: Some(Header {
+                chain_id: CHAIN_ID.to_string(),
+                height,
+                ..Default::default()
+            }),
+            last_commit: Some(Commit {
+                height,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+    })
+}
+
+/// Decodes a unary gRPC request, answers it with handler and encodes the result
+async fn unary<Req, Resp, F>(req: Request<Body>, handler: F) -> http::Response<BoxBody>
+where
+    Req: Message + Default + Send + 'static,
+    Resp: Message + Send + 'static,
+    F: FnMut(Req) -> Result<Resp, Status>,
+{
+    Grpc::new(ProstCodec::<Resp, Req>::default())
+        .unary(UnaryHandler(handler), req)
+        .await
+}
+
+struct UnaryHandler<F>(F);
+
+impl<Req, Resp, F> Service<tonic::Request<Req>> for UnaryHandler<F>
+where
+    F: FnMut(Req) -> Result<Resp, Status>,
+{
+    type Response = tonic::Response<Resp>;
+    type Error = Status;
+    type Future = Ready<Result<Self::Response, Status>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Status>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: tonic::Request<Req>) -> Self::Future {
+        ready((self.0)(req.into_inner()).map(tonic::Response::new))
+    }
+}
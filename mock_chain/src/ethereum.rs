@@ -0,0 +1,401 @@
+//! Serves a MockChain over the Ethereum json rpc methods web30 uses to send and look up native token
+//! transfers. Gas is priced but never charged, only transferred value moves between balances. Every
+//! block has a nonzero base fee so that web30 will build EIP1559 transactions against it. Contracts
+//! are not executed, eth_call only answers ERC20 balanceOf from the balances held under erc20_denom
+//! and no contract ever emits an event.
+
+use crate::{Account, ChainState, MockChain, Transfer, TxKind, ETH_DENOM};
+use clarity::rlp::{unpack_rlp, RlpToken};
+use clarity::utils::hex_str_to_bytes;
+use clarity::{Address, Signature, Transaction};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use num256::Uint256;
+use num_traits::ToPrimitive;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::io;
+use std::net::TcpListener;
+use std::str::FromStr;
+use web30::types::{ConciseBlock, Data, SyncingStatus, TransactionResponse};
+
+/// Gas used by a plain value transfer
+const TRANSFER_GAS: u64 = 21_000;
+/// Base fee reported in every block
+const BASE_FEE: u64 = 1;
+/// Selector of the ERC20 balanceOf(address) call
+const BALANCE_OF_SELECTOR: [u8; 4] = [0x70, 0xa0, 0x82, 0x31];
+
+/// The denom balances of the ERC20 token at this contract address are tracked in
+pub fn erc20_denom(token: Address) -> String {
+    token.to_string()
+}
+
+/// Starts a json rpc server for this chain on a random local port and returns its url. Must be
+/// called from within a tokio runtime, such as an actix System, the server runs until that runtime
+/// stops
+pub fn start_ethereum_server(chain: MockChain) -> io::Result<String> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+
+    let make_service = make_service_fn(move |_| {
+        let chain = chain.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle_request(chain.clone(), req))) }
+    });
+    let server = Server::from_tcp(listener)
+        .map_err(io::Error::other)?
+        .serve(make_service);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Mock ethereum server failed with {:?}", e);
+        }
+    });
+    Ok(format!("http://{addr}"))
+}
+
+async fn handle_request(
+    chain: MockChain,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let response = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => match serde_json::from_slice::<Value>(&body) {
+            Ok(request) => {
+                let id = request["id"].clone();
+                let method = request["method"].as_str().unwrap_or_default();
+                let params = request["params"].as_array().cloned().unwrap_or_default();
+                trace!("Mock ethereum request {} {:?}", method, params);
+                match handle_method(&chain, method, &params) {
+                    Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                    Err(message) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": {"code": -32000, "message": message}
+                    }),
+                }
+            }
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": Value::Null,
+                "error": {"code": -32700, "message": e.to_string()}
+            }),
+        },
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "id": Value::Null,
+            "error": {"code": -32700, "message": e.to_string()}
+        }),
+    };
+    Ok(Response::builder()
+        .header("content-type", "application/json")
+        .body(Body::from(response.to_string()))
+        .unwrap())
+}
+
+fn handle_method(chain: &MockChain, method: &str, params: &[Value]) -> Result<Value, String> {
+    match method {
+        "eth_syncing" => chain.read(|state| {
+            let status = if state.syncing {
+                SyncingStatus::Syncing {
+                    starting_block: 0u8.into(),
+                    current_block: state.height.into(),
+                    highest_block: (state.height + 1).into(),
+                }
+            } else {
+                SyncingStatus::NotSyncing(false)
+            };
+            to_value(status)
+        }),
+        "eth_blockNumber" => chain.read(|state| to_value(Uint256::from(state.height))),
+        "eth_gasPrice" => chain.read(|state| to_value(state.gas_price)),
+        "net_version" => chain.read(|state| to_value(state.net_version.to_string())),
+        "eth_chainId" => chain.read(|state| to_value(Uint256::from(state.net_version))),
+        "eth_estimateGas" => {
+            // contracts are not executed, so calls only cost their intrinsic gas
+            let (to, data) = call_param(params)?;
+            let tx = Transaction::Legacy {
+                nonce: 0u8.into(),
+                gas_price: 0u8.into(),
+                gas_limit: 0u8.into(),
+                to,
+                value: 0u8.into(),
+                data,
+                signature: None,
+            };
+            to_value(tx.intrinsic_gas_used())
+        }
+        "eth_getBalance" => {
+            let address = address_param(params)?;
+            chain.read(|state| to_value(state.balance(&Account::Eth(address), ETH_DENOM)))
+        }
+        "eth_getTransactionCount" => {
+            let address = address_param(params)?;
+            chain.read(|state| to_value(state.eth_nonce(address)))
+        }
+        "eth_getBlockByNumber" => {
+            let number = params.first().and_then(|v| v.as_str()).unwrap_or("latest");
+            chain.read(|state| {
+                let height = match number {
+                    "latest" | "pending" | "finalized" | "safe" => state.height,
+                    number => parse_uint(number)?
+                        .to_u64()
+                        .ok_or_else(|| "Invalid block number".to_string())?,
+                };
+                if height > state.height {
+                    return Ok(Value::Null);
+                }
+                to_value(get_block(state, height))
+            })
+        }
+        "eth_sendRawTransaction" => {
+            let raw = params
+                .first()
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| "Missing transaction".to_string())?;
+            let bytes = hex_str_to_bytes(raw).map_err(|e| e.to_string())?;
+            let tx = decode_transaction(&bytes).map_err(|e| e.to_string())?;
+            let from = tx.sender().map_err(|e| e.to_string())?;
+            let hash = Uint256::from_be_bytes(&tx.hash());
+            chain.write(|state| {
+                if state.balance(&Account::Eth(from), ETH_DENOM) < tx.get_value() {
+                    return Err("insufficient funds for transfer".to_string());
+                }
+                if state.transactions.contains_key(&hash) {
+                    return Err("already known".to_string());
+                }
+                state.submit(
+                    hash,
+                    TxKind::Ethereum,
+                    vec![Transfer {
+                        from: Account::Eth(from),
+                        to: Account::Eth(tx.get_to()),
+                        amount: tx.get_value(),
+                        denom: ETH_DENOM.to_string(),
+                    }],
+                    tx.get_nonce(),
+                );
+                to_value(hash)
+            })
+        }
+        "eth_call" => {
+            let (token, data) = call_param(params)?;
+            if data.len() != 36 || data[..4] != BALANCE_OF_SELECTOR {
+                return Err("Only ERC20 balanceOf calls are supported by mock chain".to_string());
+            }
+            let owner = Address::from_slice(&data[16..]).map_err(|e| e.to_string())?;
+            chain.read(|state| {
+                let balance = state.balance(&Account::Eth(owner), &erc20_denom(token));
+                to_value(Data(balance.to_be_bytes().to_vec()))
+            })
+        }
+        "eth_getLogs" => Ok(Value::Array(Vec::new())),
+        "eth_getTransactionByHash" => {
+            let hash = params
+                .first()
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| "Missing hash".to_string())?;
+            let hash = parse_uint(hash)?;
+            chain.read(|state| match state.transactions.get(&hash) {
+                Some(tx) if tx.kind == TxKind::Ethereum => {
+                    let transfer = &tx.transfers[0];
+                    let (from, to) = match (&transfer.from, &transfer.to) {
+                        (Account::Eth(from), Account::Eth(to)) => (*from, *to),
+                        _ => return Err("Not an ethereum transfer".to_string()),
+                    };
+                    to_value(TransactionResponse::Legacy {
+                        block_hash: tx.block.map(|b| Data(block_hash(b).to_be_bytes().to_vec())),
+                        block_number: tx.block.map(Uint256::from),
+                        from,
+                        gas: TRANSFER_GAS.into(),
+                        gas_price: state.gas_price,
+                        hash: Data(hash.to_be_bytes().to_vec()),
+                        input: Data(Vec::new()),
+                        nonce: tx.nonce,
+                        to: Some(to),
+                        transaction_index: tx.block.map(|_| 0u8.into()),
+                        value: transfer.amount,
+                        v: 0u8.into(),
+                        r: 0u8.into(),
+                        s: 0u8.into(),
+                    })
+                }
+                _ => Ok(Value::Null),
+            })
+        }
+        _ => Err(format!("Method {method} not supported by mock chain")),
+    }
+}
+
+/// clarity refuses EIP1559 transactions whose signature v is 1, since rlp packs it as a single byte
+/// rather than a string, so those are decoded here instead
+fn decode_transaction(bytes: &[u8]) -> Result<Transaction, clarity::Error> {
+    let err = match Transaction::decode_from_rlp(bytes) {
+        Ok(tx) => return Ok(tx),
+        Err(e) => e,
+    };
+    if bytes.first() != Some(&2) {
+        return Err(err);
+    }
+    let fields = match unpack_rlp(&bytes[1..])?.first() {
+        Some(RlpToken::List(fields)) if fields.len() == 12 => fields.clone(),
+        _ => return Err(err),
+    };
+    // only transactions without an access list, which is all Rita sends
+    if fields[9] != RlpToken::SingleByte(1) || !fields[8].get_list_content()?.is_empty() {
+        return Err(err);
+    }
+    let uint = |i: usize| -> Result<Uint256, clarity::Error> {
+        Ok(Uint256::from_be_bytes(&fields[i].get_byte_content()?))
+    };
+    Ok(Transaction::Eip1559 {
+        chain_id: uint(0)?,
+        nonce: uint(1)?,
+        max_priority_fee_per_gas: uint(2)?,
+        max_fee_per_gas: uint(3)?,
+        gas_limit: uint(4)?,
+        to: Address::from_rlp_data(fields[5].clone())?,
+        value: uint(6)?,
+        data: fields[7].get_byte_content()?,
+        access_list: Vec::new(),
+        signature: Some(Signature::new(true, uint(10)?, uint(11)?)),
+    })
+}
+
+fn get_block(state: &ChainState, height: u64) -> ConciseBlock {
+    let transactions = if height == 0 {
+        Vec::new()
+    } else {
+        state.blocks[height as usize - 1].clone()
+    };
+    ConciseBlock {
+        author: None,
+        difficulty: 0u8.into(),
+        extra_data: 0u8.into(),
+        gas_limit: 30_000_000u64.into(),
+        gas_used: (TRANSFER_GAS * transactions.len() as u64).into(),
+        base_fee_per_gas: Some(BASE_FEE.into()),
+        hash: block_hash(height),
+        logs_bloom: Data(vec![0; 256]),
+        miner: Address::default(),
+        number: height.into(),
+        parent_hash: block_hash(height.saturating_sub(1)),
+        receipts_root: 0u8.into(),
+        sha3_uncles: 0u8.into(),
+        size: 0u8.into(),
+        state_root: 0u8.into(),
+        timestamp: height.into(),
+        total_difficulty: 0u8.into(),
+        transactions,
+        transactions_root: 0u8.into(),
+        uncles: Vec::new(),
+    }
+}
+
+/// Block hashes only need to be unique per height, the mock chain does not track forks
+fn block_hash(height: u64) -> Uint256 {
+    Uint256::from(height) + Uint256::from(u64::MAX)
+}
+
+fn address_param(params: &[Value]) -> Result<Address, String> {
+    params
+        .first()
+        .and_then(|v| v.as_str())
+        .ok_or_else(|| "Missing address".to_string())
+        .and_then(|a| Address::from_str(a).map_err(|e| e.to_string()))
+}
+
+/// The destination and data of the transaction passed to eth_call and eth_estimateGas
+fn call_param(params: &[Value]) -> Result<(Address, Vec<u8>), String> {
+    let call = params
+        .first()
+        .ok_or_else(|| "Missing transaction".to_string())?;
+    let to = call["to"]
+        .as_str()
+        .ok_or_else(|| "Missing to".to_string())
+        .and_then(|a| Address::from_str(a).map_err(|e| e.to_string()))?;
+    let data = match call["data"].as_str().or_else(|| call["input"].as_str()) {
+        Some(data) => hex_str_to_bytes(data).map_err(|e| e.to_string())?,
+        None => Vec::new(),
+    };
+    Ok((to, data))
+}
+
+fn parse_uint(value: &str) -> Result<Uint256, String> {
+    Uint256::from_str(value).map_err(|e| format!("Invalid number {value} {e:?}"))
+}
+
+fn to_value<T: serde::Serialize>(value: T) -> Result<Value, String> {
+    serde_json::to_value(value).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_async::System;
+    use clarity::PrivateKey;
+    use std::time::Duration;
+    use web30::client::Web3;
+
+    #[test]
+    fn test_send_transaction() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let chain = MockChain::new();
+            let key: PrivateKey =
+                "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f1e"
+                    .parse()
+                    .unwrap();
+            let from = key.to_address();
+            let to = Address::from_slice(&[2; 20]).unwrap();
+            chain.set_balance(Account::Eth(from), ETH_DENOM, 1_000_000_000u64.into());
+            chain.set_inclusion_delay(1);
+
+            let url = start_ethereum_server(chain.clone()).unwrap();
+            let web3 = Web3::new(&url, Duration::from_secs(5));
+            let txid = web3
+                .send_transaction(to, Vec::new(), 1000u32.into(), key, Vec::new())
+                .await
+                .unwrap();
+            let tx = web3.eth_get_transaction_by_hash(txid).await.unwrap();
+            assert_eq!(tx.unwrap().get_block_number(), None);
+
+            chain.mine_blocks(2);
+            let tx = web3.eth_get_transaction_by_hash(txid).await.unwrap();
+            assert_eq!(tx.unwrap().get_block_number(), Some(2u8.into()));
+            assert_eq!(web3.eth_block_number().await.unwrap(), 2u8.into());
+            assert_eq!(web3.eth_get_balance(to).await.unwrap(), 1000u32.into());
+            assert_eq!(
+                web3.eth_get_transaction_count(from).await.unwrap(),
+                1u8.into()
+            );
+            assert_eq!(
+                web3.eth_get_transaction_by_hash(1u8.into()).await.unwrap(),
+                None
+            );
+        });
+    }
+
+    #[test]
+    fn test_erc20_balance() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let chain = MockChain::new();
+            let owner = Address::from_slice(&[1; 20]).unwrap();
+            let token = Address::from_slice(&[3; 20]).unwrap();
+            chain.set_balance(Account::Eth(owner), ETH_DENOM, 1_000_000_000u64.into());
+            chain.set_balance(Account::Eth(owner), &erc20_denom(token), 500u32.into());
+
+            let url = start_ethereum_server(chain.clone()).unwrap();
+            let web3 = Web3::new(&url, Duration::from_secs(5));
+            assert_eq!(
+                web3.get_erc20_balance(token, owner).await.unwrap(),
+                500u32.into()
+            );
+            let other = Address::from_slice(&[4; 20]).unwrap();
+            assert_eq!(
+                web3.get_erc20_balance(other, owner).await.unwrap(),
+                0u8.into()
+            );
+        });
+    }
+}
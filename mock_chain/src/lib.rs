@@ -0,0 +1,393 @@
+//! A scriptable in memory blockchain for tests. MockChain holds balances, blocks and a mempool
+//! that tests drive directly, mining blocks, delaying the inclusion of transactions and rolling
+//! back blocks to simulate reorgs. The same chain can then be served to Rita over the subset of
+//! Ethereum json rpc (see ethereum) and Cosmos gRPC (see cosmos) that it uses, so that payment
+//! code can be exercised against real clients without a full node.
+
+#![warn(clippy::all)]
+#![allow(clippy::pedantic)]
+#![forbid(unsafe_code)]
+
+#[macro_use]
+extern crate log;
+
+use clarity::Address;
+use num256::Uint256;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+pub mod cosmos;
+pub mod ethereum;
+
+pub use cosmos::start_cosmos_server;
+pub use ethereum::{erc20_denom, start_ethereum_server};
+
+/// The denom Ethereum native token balances are tracked in
+pub const ETH_DENOM: &str = "wei";
+/// Default gas price, web30 divides by this so it can never be zero
+pub const DEFAULT_GAS_PRICE: u64 = 1_000_000_000;
+/// Default net_version, the same as xDai
+pub const DEFAULT_NET_VERSION: u64 = 100;
+
+/// An account on the mock chain, Ethereum accounts are identified by address and Cosmos
+/// accounts by their bech32 string
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Account {
+    Eth(Address),
+    Cosmos(String),
+}
+
+/// A single movement of funds within a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transfer {
+    pub from: Account,
+    pub to: Account,
+    pub amount: Uint256,
+    pub denom: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxKind {
+    Ethereum,
+    Cosmos,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockTransaction {
+    pub hash: Uint256,
+    pub kind: TxKind,
+    /// Ethereum transactions always contain exactly one transfer
+    pub transfers: Vec<Transfer>,
+    pub nonce: Uint256,
+    /// The block this transaction was included in, None while it is in the mempool
+    pub block: Option<u64>,
+    /// The first block height this transaction may be included at
+    include_at: u64,
+    /// Submission order, transactions are included in the order they where submitted
+    sequence: u64,
+}
+
+#[derive(Debug)]
+struct ChainState {
+    height: u64,
+    /// The hashes of the transactions in each block, index 0 is block 1
+    blocks: Vec<Vec<Uint256>>,
+    transactions: HashMap<Uint256, MockTransaction>,
+    balances: HashMap<(Account, String), Uint256>,
+    /// How many blocks a newly submitted transaction waits in the mempool
+    inclusion_delay: u64,
+    gas_price: Uint256,
+    net_version: u64,
+    syncing: bool,
+    next_sequence: u64,
+}
+
+impl Default for ChainState {
+    fn default() -> Self {
+        ChainState {
+            height: 0,
+            blocks: Vec::new(),
+            transactions: HashMap::new(),
+            balances: HashMap::new(),
+            inclusion_delay: 0,
+            gas_price: DEFAULT_GAS_PRICE.into(),
+            net_version: DEFAULT_NET_VERSION,
+            syncing: false,
+            next_sequence: 0,
+        }
+    }
+}
+
+impl ChainState {
+    fn balance(&self, account: &Account, denom: &str) -> Uint256 {
+        self.balances
+            .get(&(account.clone(), denom.to_string()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Moves funds for every transfer in the transaction, or for none of them if any sender
+    /// can't cover the total it is sending
+    fn apply(&mut self, tx: &MockTransaction) -> bool {
+        let mut totals: HashMap<(Account, String), Uint256> = HashMap::new();
+        for t in tx.transfers.iter() {
+            *totals.entry((t.from.clone(), t.denom.clone())).or_default() += t.amount;
+        }
+        if totals
+            .iter()
+            .any(|((account, denom), total)| self.balance(account, denom) < *total)
+        {
+            return false;
+        }
+        for t in tx.transfers.iter() {
+            *self
+                .balances
+                .entry((t.from.clone(), t.denom.clone()))
+                .or_default() -= t.amount;
+            *self
+                .balances
+                .entry((t.to.clone(), t.denom.clone()))
+                .or_default() += t.amount;
+        }
+        true
+    }
+
+    fn revert(&mut self, tx: &MockTransaction) {
+        for t in tx.transfers.iter() {
+            *self
+                .balances
+                .entry((t.to.clone(), t.denom.clone()))
+                .or_default() -= t.amount;
+            *self
+                .balances
+                .entry((t.from.clone(), t.denom.clone()))
+                .or_default() += t.amount;
+        }
+    }
+
+    fn submit(&mut self, hash: Uint256, kind: TxKind, transfers: Vec<Transfer>, nonce: Uint256) {
+        let tx = MockTransaction {
+            hash,
+            kind,
+            transfers,
+            nonce,
+            block: None,
+            include_at: self.height + 1 + self.inclusion_delay,
+            sequence: self.next_sequence,
+        };
+        self.next_sequence += 1;
+        self.transactions.insert(hash, tx);
+    }
+
+    fn mine_block(&mut self) {
+        self.height += 1;
+        let height = self.height;
+        let mut ready: Vec<MockTransaction> = self
+            .transactions
+            .values()
+            .filter(|tx| tx.block.is_none() && tx.include_at <= height)
+            .cloned()
+            .collect();
+        ready.sort_by_key(|tx| tx.sequence);
+
+        let mut block = Vec::new();
+        for tx in ready {
+            if self.apply(&tx) {
+                self.transactions.get_mut(&tx.hash).unwrap().block = Some(height);
+                block.push(tx.hash);
+            } else {
+                warn!("Dropping tx {:#066x} with insufficient funds", tx.hash);
+                self.transactions.remove(&tx.hash);
+            }
+        }
+        self.blocks.push(block);
+    }
+
+    /// Number of included Ethereum transactions sent from this address
+    fn eth_nonce(&self, address: Address) -> Uint256 {
+        let from = Account::Eth(address);
+        let count = self
+            .transactions
+            .values()
+            .filter(|tx| {
+                tx.kind == TxKind::Ethereum
+                    && tx.block.is_some()
+                    && tx.transfers.iter().any(|t| t.from == from)
+            })
+            .count();
+        (count as u64).into()
+    }
+}
+
+/// A handle to a mock chain, clones share the same chain so a test can keep one to script
+/// while the servers hold another
+#[derive(Debug, Clone, Default)]
+pub struct MockChain {
+    state: Arc<RwLock<ChainState>>,
+}
+
+impl MockChain {
+    pub fn new() -> MockChain {
+        MockChain::default()
+    }
+
+    pub fn height(&self) -> u64 {
+        self.state.read().unwrap().height
+    }
+
+    pub fn set_balance(&self, account: Account, denom: &str, amount: Uint256) {
+        self.state
+            .write()
+            .unwrap()
+            .balances
+            .insert((account, denom.to_string()), amount);
+    }
+
+    pub fn get_balance(&self, account: &Account, denom: &str) -> Uint256 {
+        self.state.read().unwrap().balance(account, denom)
+    }
+
+    /// Sets how many blocks newly submitted transactions wait before they are included,
+    /// with zero they are included in the next block
+    pub fn set_inclusion_delay(&self, blocks: u64) {
+        self.state.write().unwrap().inclusion_delay = blocks;
+    }
+
+    pub fn set_gas_price(&self, gas_price: Uint256) {
+        self.state.write().unwrap().gas_price = gas_price;
+    }
+
+    pub fn set_net_version(&self, net_version: u64) {
+        self.state.write().unwrap().net_version = net_version;
+    }
+
+    /// While syncing both servers report so, and Rita should refuse to trust their data
+    pub fn set_syncing(&self, syncing: bool) {
+        self.state.write().unwrap().syncing = syncing;
+    }
+
+    /// Submits a native token transfer to the mempool as if someone else had sent it
+    pub fn submit_eth_transfer(&self, from: Address, to: Address, amount: Uint256) -> Uint256 {
+        let state = &mut *self.state.write().unwrap();
+        let hash = Uint256::from(state.next_sequence + 1);
+        let nonce = state.eth_nonce(from);
+        state.submit(
+            hash,
+            TxKind::Ethereum,
+            vec![Transfer {
+                from: Account::Eth(from),
+                to: Account::Eth(to),
+                amount,
+                denom: ETH_DENOM.to_string(),
+            }],
+            nonce,
+        );
+        hash
+    }
+
+    /// Submits a Cosmos transaction containing one bank MsgSend per transfer to the mempool
+    pub fn submit_cosmos_transfers(&self, transfers: Vec<Transfer>) -> Uint256 {
+        let state = &mut *self.state.write().unwrap();
+        let hash = Uint256::from(state.next_sequence + 1);
+        state.submit(hash, TxKind::Cosmos, transfers, 0u8.into());
+        hash
+    }
+
+    /// Removes a transaction from the mempool, as if it had been replaced or expired. Returns
+    /// false if the transaction is unknown or already in a block
+    pub fn drop_transaction(&self, hash: Uint256) -> bool {
+        let state = &mut *self.state.write().unwrap();
+        match state.transactions.get(&hash) {
+            Some(tx) if tx.block.is_none() => {
+                state.transactions.remove(&hash);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn get_transaction(&self, hash: Uint256) -> Option<MockTransaction> {
+        self.state.read().unwrap().transactions.get(&hash).cloned()
+    }
+
+    /// Mines a block including every mempool transaction whose delay has passed
+    pub fn mine_block(&self) {
+        self.state.write().unwrap().mine_block()
+    }
+
+    pub fn mine_blocks(&self, count: u64) {
+        let state = &mut *self.state.write().unwrap();
+        for _ in 0..count {
+            state.mine_block()
+        }
+    }
+
+    /// Rolls back the last `depth` blocks, reverting their transfers and returning their
+    /// transactions to the mempool where they will be included again by the next block
+    /// unless dropped
+    pub fn reorg(&self, depth: u64) {
+        let state = &mut *self.state.write().unwrap();
+        for _ in 0..depth.min(state.height) {
+            let block = state.blocks.pop().unwrap();
+            for hash in block.iter().rev() {
+                let tx = state.transactions.get(hash).cloned().unwrap();
+                state.revert(&tx);
+                let tx = state.transactions.get_mut(hash).unwrap();
+                tx.block = None;
+                tx.include_at = 0;
+            }
+            state.height -= 1;
+        }
+    }
+
+    fn read<T, F: FnOnce(&ChainState) -> T>(&self, f: F) -> T {
+        f(&self.state.read().unwrap())
+    }
+
+    fn write<T, F: FnOnce(&mut ChainState) -> T>(&self, f: F) -> T {
+        f(&mut self.state.write().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eth_account(n: u8) -> Address {
+        Address::from_slice(&[n; 20]).unwrap()
+    }
+
+    #[test]
+    fn test_delayed_inclusion() {
+        let chain = MockChain::new();
+        let (a, b) = (eth_account(1), eth_account(2));
+        chain.set_balance(Account::Eth(a), ETH_DENOM, 100u8.into());
+        chain.set_inclusion_delay(2);
+
+        let hash = chain.submit_eth_transfer(a, b, 40u8.into());
+        chain.mine_blocks(2);
+        assert_eq!(chain.get_transaction(hash).unwrap().block, None);
+        chain.mine_block();
+        assert_eq!(chain.get_transaction(hash).unwrap().block, Some(3));
+        assert_eq!(chain.get_balance(&Account::Eth(a), ETH_DENOM), 60u8.into());
+        assert_eq!(chain.get_balance(&Account::Eth(b), ETH_DENOM), 40u8.into());
+    }
+
+    #[test]
+    fn test_reorg() {
+        let chain = MockChain::new();
+        let (a, b) = (eth_account(1), eth_account(2));
+        chain.set_balance(Account::Eth(a), ETH_DENOM, 100u8.into());
+
+        chain.mine_block();
+        let hash = chain.submit_eth_transfer(a, b, 40u8.into());
+        chain.mine_blocks(3);
+        assert_eq!(chain.get_transaction(hash).unwrap().block, Some(2));
+
+        chain.reorg(3);
+        assert_eq!(chain.height(), 1);
+        assert_eq!(chain.get_transaction(hash).unwrap().block, None);
+        assert_eq!(chain.get_balance(&Account::Eth(a), ETH_DENOM), 100u8.into());
+
+        // re-included by the next block on the new fork
+        chain.mine_block();
+        assert_eq!(chain.get_transaction(hash).unwrap().block, Some(2));
+
+        chain.reorg(1);
+        assert!(chain.drop_transaction(hash));
+        chain.mine_block();
+        assert_eq!(chain.get_transaction(hash), None);
+        assert_eq!(chain.get_balance(&Account::Eth(b), ETH_DENOM), 0u8.into());
+    }
+
+    #[test]
+    fn test_insufficient_funds_dropped() {
+        let chain = MockChain::new();
+        let (a, b) = (eth_account(1), eth_account(2));
+        chain.set_balance(Account::Eth(a), ETH_DENOM, 10u8.into());
+        let hash = chain.submit_eth_transfer(a, b, 40u8.into());
+        chain.mine_block();
+        assert_eq!(chain.get_transaction(hash), None);
+        assert_eq!(chain.get_balance(&Account::Eth(a), ETH_DENOM), 10u8.into());
+    }
+}
@@ -0,0 +1,189 @@
+//! Stand ins for the services rita_exit verifies clients through. MockVerification records every
+//! verification text, operator notification and email the exit sends, start_sms_server serves
+//! it over the subset of the Twilio Verify and messaging apis the exit uses (see sms) and
+//! start_smtp_server accepts mail over plain smtp (see smtp). Tests point the exit's verify_url,
+//! twilio_url and smtp_connection_url at these servers and read the codes back out to finish
+//! signing up.
+
+#![warn(clippy::all)]
+#![allow(clippy::pedantic)]
+#![forbid(unsafe_code)]
+
+#[macro_use]
+extern crate log;
+
+use std::sync::{Arc, RwLock};
+
+pub mod sms;
+pub mod smtp;
+
+pub use sms::start_sms_server;
+pub use smtp::start_smtp_server;
+
+/// The first code handed out, later codes count up from here so every code is six digits
+const FIRST_CODE: u32 = 100_000;
+
+/// A verification text the exit asked Twilio Verify to send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentCode {
+    /// The number in international format, for example +15555555555
+    pub number: String,
+    pub code: String,
+    /// The Verify service the code was sent through
+    pub service_sid: String,
+}
+
+/// An attempt by the exit to check a code a user entered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodeCheck {
+    pub number: String,
+    pub code: String,
+    pub valid: bool,
+}
+
+/// A message sent through the Twilio messaging api
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextMessage {
+    pub account_id: String,
+    pub to: String,
+    pub from: String,
+    pub body: String,
+}
+
+/// A mail accepted by the smtp server, data is the message exactly as it was sent with headers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+    pub from: String,
+    pub to: Vec<String>,
+    pub data: String,
+}
+
+#[derive(Debug, Default)]
+struct VerificationState {
+    sent_codes: Vec<SentCode>,
+    checks: Vec<CodeCheck>,
+    texts: Vec<TextMessage>,
+    mails: Vec<Mail>,
+}
+
+impl VerificationState {
+    fn latest_code(&self, number: &str) -> Option<&SentCode> {
+        self.sent_codes.iter().rev().find(|c| c.number == number)
+    }
+}
+
+/// Shared handle to everything the mock services have received, clones see the same state so
+/// one can be given to the servers and another kept by the test
+#[derive(Debug, Clone, Default)]
+pub struct MockVerification {
+    state: Arc<RwLock<VerificationState>>,
+}
+
+impl MockVerification {
+    pub fn new() -> MockVerification {
+        MockVerification::default()
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&VerificationState) -> T) -> T {
+        f(&self.state.read().unwrap())
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&mut VerificationState) -> T) -> T {
+        f(&mut self.state.write().unwrap())
+    }
+
+    /// Records a verification text to number and returns the code it contains
+    fn send_code(&self, number: String, service_sid: String) -> String {
+        self.write(|state| {
+            let code = (FIRST_CODE + state.sent_codes.len() as u32).to_string();
+            info!("Mock sms sending code {} to {}", code, number);
+            state.sent_codes.push(SentCode {
+                number,
+                code: code.clone(),
+                service_sid,
+            });
+            code
+        })
+    }
+
+    /// A code is valid if it is the last one sent to the number, like Twilio Verify resending
+    /// replaces the previous code
+    fn check_code(&self, number: String, code: String) -> bool {
+        self.write(|state| {
+            let valid = state.latest_code(&number).map(|c| c.code == code) == Some(true);
+            state.checks.push(CodeCheck {
+                number,
+                code,
+                valid,
+            });
+            valid
+        })
+    }
+
+    fn send_text(&self, text: TextMessage) {
+        self.write(|state| state.texts.push(text))
+    }
+
+    fn receive_mail(&self, mail: Mail) {
+        info!("Mock smtp received mail for {:?}", mail.to);
+        self.write(|state| state.mails.push(mail))
+    }
+
+    /// The code that will currently pass a check for this number, numbers are in international
+    /// format, for example +15555555555
+    pub fn code_for(&self, number: &str) -> Option<String> {
+        self.read(|state| state.latest_code(number).map(|c| c.code.clone()))
+    }
+
+    pub fn sent_codes(&self) -> Vec<SentCode> {
+        self.read(|state| state.sent_codes.clone())
+    }
+
+    pub fn checks(&self) -> Vec<CodeCheck> {
+        self.read(|state| state.checks.clone())
+    }
+
+    pub fn texts(&self) -> Vec<TextMessage> {
+        self.read(|state| state.texts.clone())
+    }
+
+    pub fn mails(&self) -> Vec<Mail> {
+        self.read(|state| state.mails.clone())
+    }
+
+    /// The most recent mail with address among its recipients
+    pub fn last_mail_to(&self, address: &str) -> Option<Mail> {
+        self.read(|state| {
+            state
+                .mails
+                .iter()
+                .rev()
+                .find(|m| m.to.iter().any(|to| to == address))
+                .cloned()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_codes() {
+        let mock = MockVerification::new();
+        let first = mock.send_code("+15555555555".to_string(), "VA123".to_string());
+        assert_eq!(mock.code_for("+15555555555"), Some(first.clone()));
+        assert_eq!(mock.code_for("+15555555556"), None);
+
+        let second = mock.send_code("+15555555555".to_string(), "VA123".to_string());
+        assert_ne!(first, second);
+        assert_eq!(second.len(), 6);
+        assert!(!mock.check_code("+15555555555".to_string(), first));
+        assert!(mock.check_code("+15555555555".to_string(), second.clone()));
+        assert!(!mock.check_code("+15555555556".to_string(), second));
+        assert_eq!(
+            mock.checks().iter().map(|c| c.valid).collect::<Vec<_>>(),
+            vec![false, true, false]
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! Serves a MockVerification over the parts of the Twilio Verify and Twilio messaging apis
+//! rita_exit calls. Requests are form encoded like the real apis, codes are checked against the
+//! last one sent to the number and credentials are never checked.
+
+use crate::{MockVerification, TextMessage};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::io;
+use std::net::{IpAddr, TcpListener};
+
+#[derive(Debug, Deserialize)]
+struct VerificationForm {
+    #[serde(rename = "To")]
+    to: String,
+    #[serde(rename = "Channel")]
+    channel: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct VerificationCheckForm {
+    #[serde(rename = "To")]
+    to: String,
+    #[serde(rename = "Code")]
+    code: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TwilioForm {
+    #[serde(rename = "To")]
+    to: String,
+    #[serde(rename = "From")]
+    from: String,
+    #[serde(rename = "Body")]
+    body: String,
+}
+
+/// Starts the sms api server on a random port at ip and returns its base url, which is what
+/// verify_url and twilio_url expect. Must be called from within a tokio runtime, such as an
+/// actix System, the server runs until that runtime stops
+pub fn start_sms_server(mock: MockVerification, ip: IpAddr) -> io::Result<String> {
+    let listener = TcpListener::bind((ip, 0))?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+
+    let make_service = make_service_fn(move |_| {
+        let mock = mock.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle_request(mock.clone(), req))) }
+    });
+    let server = Server::from_tcp(listener)
+        .map_err(io::Error::other)?
+        .serve(make_service);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Mock sms server failed with {:?}", e);
+        }
+    });
+    Ok(format!("http://{addr}"))
+}
+
+async fn handle_request(
+    mock: MockVerification,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    trace!("Mock sms request {} {}", method, path);
+    let (status, response) = match hyper::body::to_bytes(req.into_body()).await {
+        Ok(body) => route(&mock, &method, &path, &body),
+        Err(e) => (StatusCode::BAD_REQUEST, json!({"message": e.to_string()})),
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(response.to_string()))
+        .unwrap())
+}
+
+fn route(mock: &MockVerification, method: &Method, path: &str, body: &[u8]) -> (StatusCode, Value) {
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match (method, segments.as_slice()) {
+        (&Method::POST, ["v2", "Services", service_sid, "Verifications"]) => {
+            match serde_urlencoded::from_bytes::<VerificationForm>(body) {
+                Ok(form) => {
+                    mock.send_code(form.to.clone(), service_sid.to_string());
+                    (
+                        StatusCode::CREATED,
+                        json!({
+                            "sid": format!("VE{:032}", mock.sent_codes().len()),
+                            "service_sid": service_sid,
+                            "to": form.to,
+                            "channel": form.channel,
+                            "status": "pending",
+                        }),
+                    )
+                }
+                Err(e) => bad_form(e),
+            }
+        }
+        (&Method::POST, ["v2", "Services", service_sid, "VerificationCheck"]) => {
+            match serde_urlencoded::from_bytes::<VerificationCheckForm>(body) {
+                // like Twilio a number that was never sent a code has no verification to check
+                Ok(form) if mock.code_for(&form.to).is_none() => (
+                    StatusCode::NOT_FOUND,
+                    json!({
+                        "code": 20404,
+                        "message": format!("The requested resource /Services/{service_sid}/VerificationCheck was not found"),
+                        "status": 404,
+                    }),
+                ),
+                Ok(form) => {
+                    let valid = mock.check_code(form.to.clone(), form.code);
+                    (
+                        StatusCode::OK,
+                        json!({
+                            "service_sid": service_sid,
+                            "to": form.to,
+                            "status": if valid { "approved" } else { "pending" },
+                            "valid": valid,
+                        }),
+                    )
+                }
+                Err(e) => bad_form(e),
+            }
+        }
+        (&Method::POST, ["2010-04-01", "Accounts", account_id, "Messages.json"]) => {
+            match serde_urlencoded::from_bytes::<TwilioForm>(body) {
+                Ok(form) => {
+                    let response = json!({
+                        "sid": format!("SM{:032}", mock.texts().len()),
+                        "status": "queued",
+                        "to": form.to,
+                        "from": form.from,
+                        "body": form.body,
+                    });
+                    mock.send_text(TextMessage {
+                        account_id: account_id.to_string(),
+                        to: form.to,
+                        from: form.from,
+                        body: form.body,
+                    });
+                    (StatusCode::CREATED, response)
+                }
+                Err(e) => bad_form(e),
+            }
+        }
+        _ => (
+            StatusCode::NOT_FOUND,
+            json!({"message": format!("No route for {method} {path}")}),
+        ),
+    }
+}
+
+fn bad_form(e: serde_urlencoded::de::Error) -> (StatusCode, Value) {
+    (StatusCode::BAD_REQUEST, json!({"message": e.to_string()}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_async::System;
+    use std::collections::HashMap;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_verification_flow() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let mock = MockVerification::new();
+            let url = start_sms_server(mock.clone(), Ipv4Addr::LOCALHOST.into()).unwrap();
+            let client = awc::Client::default();
+            let verifications = format!("{url}/v2/Services/VA123/Verifications");
+            let check = format!("{url}/v2/Services/VA123/VerificationCheck");
+            let code_form = |code: &str| {
+                let mut form = HashMap::new();
+                form.insert("To", "+15555555555".to_string());
+                form.insert("Code", code.to_string());
+                form
+            };
+
+            let response = client
+                .post(&check)
+                .send_form(&code_form("000000"))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+            let mut form = HashMap::new();
+            form.insert("To", "+15555555555");
+            form.insert("Channel", "sms");
+            let response = client.post(&verifications).send_form(&form).await.unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+            let code = mock.code_for("+15555555555").unwrap();
+            assert_eq!(mock.sent_codes()[0].service_sid, "VA123");
+
+            let mut response = client
+                .post(&check)
+                .send_form(&code_form("000000"))
+                .await
+                .unwrap();
+            assert!(response.status().is_success());
+            let body: Value = response.json().await.unwrap();
+            assert_eq!(body["status"], "pending");
+            let mut response = client
+                .post(&check)
+                .send_form(&code_form(&code))
+                .await
+                .unwrap();
+            let body: Value = response.json().await.unwrap();
+            assert_eq!(body["status"], "approved");
+
+            let mut text = HashMap::new();
+            text.insert("To", "+15555555555");
+            text.insert("From", "+15555555556");
+            text.insert("Body", "Exit restarted");
+            let response = client
+                .post(format!("{url}/2010-04-01/Accounts/AC123/Messages.json"))
+                .send_form(&text)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::CREATED);
+            assert_eq!(
+                mock.texts(),
+                vec![TextMessage {
+                    account_id: "AC123".to_string(),
+                    to: "+15555555555".to_string(),
+                    from: "+15555555556".to_string(),
+                    body: "Exit restarted".to_string(),
+                }]
+            );
+
+            let response = client.get(format!("{url}/nothing")).send().await.unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        });
+    }
+}
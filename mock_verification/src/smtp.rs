@@ -0,0 +1,179 @@
+//! Accepts mail for a MockVerification over plain smtp, one thread per connection. Only the
+//! commands lettre sends to a server without tls are understood, any AUTH PLAIN credentials are
+//! accepted and every mail is recorded rather than relayed.
+
+use crate::{Mail, MockVerification};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::thread;
+
+const GREETING: &str = "220 mock ESMTP ready\r\n";
+const EHLO_REPLY: &str = "250-mock\r\n250-AUTH PLAIN\r\n250 8BITMIME\r\n";
+const OK: &str = "250 OK\r\n";
+
+/// Starts an smtp server on a random port at ip and returns its connection url, which is what
+/// smtp_connection_url expects. The server runs until the test process exits
+pub fn start_smtp_server(mock: MockVerification, ip: IpAddr) -> io::Result<String> {
+    let listener = TcpListener::bind((ip, 0))?;
+    let addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let mock = mock.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_connection(mock, stream) {
+                            trace!("Mock smtp connection closed with {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("Mock smtp failed to accept with {:?}", e),
+            }
+        }
+    });
+    Ok(format!("smtp://{addr}"))
+}
+
+/// The address between the angle brackets of a MAIL FROM or RCPT TO argument
+fn parse_path(argument: &str) -> String {
+    match (argument.find('<'), argument.find('>')) {
+        (Some(start), Some(end)) if start < end => argument[start + 1..end].to_string(),
+        _ => argument.trim().to_string(),
+    }
+}
+
+fn handle_connection(mock: MockVerification, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    writer.write_all(GREETING.as_bytes())?;
+
+    let mut from = None;
+    let mut to = Vec::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let command = line.trim_end();
+        trace!("Mock smtp got command {}", command);
+        let (verb, argument) = match command.find([' ', ':']) {
+            Some(i) => (&command[..i], &command[i + 1..]),
+            None => (command, ""),
+        };
+
+        let reply = match verb.to_ascii_uppercase().as_str() {
+            "EHLO" => EHLO_REPLY,
+            "HELO" | "NOOP" => OK,
+            "AUTH" => "235 2.7.0 Authentication successful\r\n",
+            "MAIL" => {
+                from = Some(parse_path(argument));
+                to.clear();
+                OK
+            }
+            "RCPT" => {
+                if from.is_none() {
+                    "503 5.5.1 MAIL first\r\n"
+                } else {
+                    to.push(parse_path(argument));
+                    OK
+                }
+            }
+            "DATA" => match from.take() {
+                Some(from) if !to.is_empty() => {
+                    writer.write_all(b"354 End data with <CR><LF>.<CR><LF>\r\n")?;
+                    let data = read_data(&mut reader)?;
+                    mock.receive_mail(Mail {
+                        from,
+                        to: std::mem::take(&mut to),
+                        data,
+                    });
+                    OK
+                }
+                _ => "503 5.5.1 RCPT first\r\n",
+            },
+            "RSET" => {
+                from = None;
+                to.clear();
+                OK
+            }
+            "QUIT" => {
+                writer.write_all(b"221 Bye\r\n")?;
+                return Ok(());
+            }
+            _ => "502 5.5.2 Command not recognized\r\n",
+        };
+        writer.write_all(reply.as_bytes())?;
+    }
+}
+
+/// Reads a message up to the line containing only a dot, undoing dot stuffing
+fn read_data(reader: &mut impl BufRead) -> io::Result<String> {
+    let mut data = String::new();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let content = line.trim_end_matches(&['\r', '\n'][..]);
+        if content == "." {
+            return Ok(data);
+        }
+        data += content.strip_prefix('.').unwrap_or(content);
+        data += "\r\n";
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// Sends a command and returns the reply, reading every line of multiline replies
+    fn command(reader: &mut impl BufRead, writer: &mut TcpStream, command: &str) -> String {
+        writer.write_all(command.as_bytes()).unwrap();
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            reply += &line;
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return reply;
+            }
+        }
+    }
+
+    #[test]
+    fn test_receive_mail() {
+        let mock = MockVerification::new();
+        let url = start_smtp_server(mock.clone(), Ipv4Addr::LOCALHOST.into()).unwrap();
+        let mut writer = TcpStream::connect(url.trim_start_matches("smtp://")).unwrap();
+        let mut reader = BufReader::new(writer.try_clone().unwrap());
+        let mut greeting = String::new();
+        reader.read_line(&mut greeting).unwrap();
+        assert!(greeting.starts_with("220"));
+
+        let mut send = |c: &str| command(&mut reader, &mut writer, c);
+        assert!(send("EHLO exit.example.com\r\n").contains("AUTH PLAIN"));
+        assert!(send("AUTH PLAIN AHVzZXIAcGFzcw==\r\n").starts_with("235"));
+        assert!(send("DATA\r\n").starts_with("503"));
+        assert!(send("MAIL FROM:<verif@example.com> SIZE=100\r\n").starts_with("250"));
+        assert!(send("RCPT TO:<user@example.com>\r\n").starts_with("250"));
+        assert!(send("DATA\r\n").starts_with("354"));
+        let body = "Subject: Code\r\n\r\nYour code is [123456]\r\n..hidden\r\n.\r\n";
+        assert!(send(body).starts_with("250"));
+        assert!(send("QUIT\r\n").starts_with("221"));
+
+        assert_eq!(
+            mock.last_mail_to("user@example.com"),
+            Some(Mail {
+                from: "verif@example.com".to_string(),
+                to: vec!["user@example.com".to_string()],
+                data: "Subject: Code\r\n\r\nYour code is [123456]\r\n.hidden\r\n".to_string(),
+            })
+        );
+        assert_eq!(mock.last_mail_to("verif@example.com"), None);
+    }
+}
@@ -38,6 +38,7 @@ use rita_common::rita_loop::start_core_rita_endpoints;
 use rita_common::rita_loop::start_rita_common_loops;
 use rita_common::rita_loop::write_to_disk::save_to_disk_loop;
 use rita_common::rita_loop::write_to_disk::SettingsOnDisk;
+use rita_common::tunnel_manager::persist::save_tunnels_on_shutdown;
 use rita_common::usage_tracker::save_usage_on_shutdown;
 use rita_common::utils::env_vars_contains;
 use settings::client::RitaClientSettings;
@@ -54,16 +55,17 @@ fn main() {
         info!("received Ctrl+C!");
         save_debt_on_shutdown();
         save_usage_on_shutdown();
+        save_tunnels_on_shutdown();
         save_settings_on_shutdown();
 
         std::process::exit(0);
     })
     .expect("Error setting Ctrl-C handler");
 
-    // Because Rita clears and sets up new Wireguard Tunnels on every restart Babel, which was attached and listening to
-    // the old tunnels is now in an incorrect state. We must either restart babel or empty it's interfaces list so that the newly
-    // created wireguard tunnels can be re-added by this instance of Rita. Due to errors in babel (see git history there)
-    // restarting is the way to go as removing dead interfaces often does not work
+    // Because Rita clears any Wireguard Tunnels it can not adopt on every restart Babel, which was attached and listening to
+    // the old tunnels is now in an incorrect state. We must either restart babel or empty it's interfaces list so that the
+    // adopted and newly created wireguard tunnels can be re-added by this instance of Rita. Due to errors in babel (see git
+    // history there) restarting is the way to go as removing dead interfaces often does not work
     KI.restart_babel();
 
     let args: Args = Docopt::new(get_client_usage(
@@ -32,10 +32,11 @@ use rita_common::rita_loop::start_core_rita_endpoints;
 use rita_common::rita_loop::start_rita_common_loops;
 use rita_common::rita_loop::write_to_disk::save_to_disk_loop;
 use rita_common::rita_loop::write_to_disk::SettingsOnDisk;
+use rita_common::tunnel_manager::persist::save_tunnels_on_shutdown;
 use rita_common::usage_tracker::save_usage_on_shutdown;
 use rita_common::utils::env_vars_contains;
 use rita_exit::database::sms::send_admin_notification_sms;
-use rita_exit::initialize_db_pool;
+use rita_exit::initialize_client_store;
 use rita_exit::operator_update::update_loop::start_operator_update_loop;
 use rita_exit::rita_loop::start_rita_exit_endpoints;
 use rita_exit::rita_loop::start_rita_exit_loop;
@@ -86,6 +87,7 @@ fn main() {
         info!("received Ctrl+C!");
         save_debt_on_shutdown();
         save_usage_on_shutdown();
+        save_tunnels_on_shutdown();
         save_settings_on_shutdown();
 
         std::process::exit(0);
@@ -151,7 +153,7 @@ fn main() {
     send_admin_notification_sms("Exit restarted");
 
     // Initialize db pool
-    initialize_db_pool();
+    initialize_client_store();
 
     let system = actix_async::System::new();
 
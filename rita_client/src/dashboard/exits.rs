@@ -5,8 +5,8 @@ use crate::RitaClientError;
 use actix_web_async::http::StatusCode;
 use actix_web_async::{web::Json, web::Path, HttpRequest, HttpResponse};
 use althea_types::ExitState;
-use babel_monitor::open_babel_stream;
-use babel_monitor::parse_routes;
+use babel_monitor::async_monitor::open_babel_stream;
+use babel_monitor::async_monitor::parse_routes;
 use babel_monitor::parsing::do_we_have_route;
 
 use rita_common::RitaCommonError;
@@ -56,11 +56,11 @@ fn is_tunnel_working(exit: &ExitServer, current_exit: Option<&ExitServer>) -> bo
     }
 }
 
-pub fn dashboard_get_exit_info() -> Result<Vec<ExitInfo>, RitaClientError> {
+pub async fn dashboard_get_exit_info() -> Result<Vec<ExitInfo>, RitaClientError> {
     let babel_port = settings::get_rita_client().network.babel_port;
-    match open_babel_stream(babel_port, Duration::from_secs(5)) {
+    match open_babel_stream(babel_port, Duration::from_secs(5)).await {
         Ok(mut stream) => {
-            match parse_routes(&mut stream) {
+            match parse_routes(&mut stream).await {
                 Ok(routes) => {
                     let route_table_sample = routes;
                     let mut output = Vec::new();
@@ -143,7 +143,7 @@ pub async fn add_exits(new_exits: Json<HashMap<String, ExitServer>>) -> HttpResp
 
 pub async fn get_exit_info(_req: HttpRequest) -> HttpResponse {
     debug!("Exit endpoint hit!");
-    match dashboard_get_exit_info() {
+    match dashboard_get_exit_info().await {
         Ok(a) => HttpResponse::Ok().json(a),
         Err(e) => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!("{e:?}")),
     }
@@ -57,6 +57,7 @@ use rita_common::dashboard::debts::*;
 use rita_common::dashboard::development::*;
 use rita_common::dashboard::nickname::*;
 use rita_common::dashboard::own_info::*;
+use rita_common::dashboard::peer_policy::*;
 use rita_common::dashboard::settings::*;
 use rita_common::dashboard::token_bridge::*;
 use rita_common::dashboard::usage::*;
@@ -89,6 +90,7 @@ pub fn start_client_dashboard(rita_dashboard_port: u16) {
                     .route("/operator_debt", web::get().to(get_operator_debt))
                     .route("/debts", web::get().to(get_debts))
                     .route("/debts/reset", web::post().to(reset_debt))
+                    .route("/debts/ledger", web::post().to(get_debts_ledger))
                     .route("/exits", web::get().to(get_exit_info))
                     .route("/exits", web::post().to(add_exits))
                     .route("/exits/{name}/register", web::post().to(register_to_exit))
@@ -166,6 +168,9 @@ pub fn start_client_dashboard(rita_dashboard_port: u16) {
                     .route("/blockchain/get", web::get().to(get_system_blockchain))
                     .route("/nickname/get", web::get().to(get_nickname))
                     .route("/nickname/set", web::post().to(set_nickname))
+                    .route("/peer_policy", web::get().to(get_peer_policy))
+                    .route("/peer_policy", web::post().to(set_peer_policy))
+                    .route("/peer_policy/rejected", web::get().to(get_rejected_peers))
                     .route(
                         "/low_balance_notification",
                         web::get().to(get_low_balance_notification),
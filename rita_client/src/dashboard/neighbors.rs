@@ -2,15 +2,16 @@ use actix_web_async::http::StatusCode;
 use actix_web_async::{HttpRequest, HttpResponse};
 use althea_types::Identity;
 use arrayvec::ArrayString;
+use babel_monitor::async_monitor::{open_babel_stream, parse_routes};
 use babel_monitor::parsing::get_installed_route;
 use babel_monitor::parsing::get_route_via_neigh;
 use babel_monitor::structs::Route;
-use babel_monitor::{open_babel_stream, parse_routes};
 
 use num256::{Int256, Uint256};
 use rita_common::debt_keeper::{dump, NodeDebtData};
 use rita_common::network_monitor::{get_stats, IfaceStats, Stats};
-use rita_common::tunnel_manager::{tm_get_neighbors, Neighbor};
+use rita_common::tunnel_manager::lifecycle::{ClosedTunnel, TunnelState, TunnelTransition};
+use rita_common::tunnel_manager::{tm_get_closed_tunnels, tm_get_neighbors, Neighbor};
 use std::collections::HashMap;
 use std::time::Duration;
 
@@ -33,12 +34,17 @@ pub struct NodeInfo {
     pub price_to_exit: u32,
     pub speed_limit: Option<usize>,
     pub stats: IfaceStats,
+    pub tunnel_state: TunnelState,
+    /// Recent lifecycle transitions of the tunnel to this neighbor
+    pub tunnel_history: Vec<TunnelTransition>,
+    /// Tunnels to this neighbor that were recently torn down and why
+    pub closed_tunnels: Vec<ClosedTunnel>,
 }
 
 pub async fn get_routes(_req: HttpRequest) -> HttpResponse {
     let babel_port = settings::get_rita_client().network.babel_port;
-    match open_babel_stream(babel_port, Duration::from_secs(5)) {
-        Ok(mut stream) => match parse_routes(&mut stream) {
+    match open_babel_stream(babel_port, BABEL_TIMEOUT).await {
+        Ok(mut stream) => match parse_routes(&mut stream).await {
             Ok(routes) => HttpResponse::Ok().json(routes),
             Err(e) => HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
                 .json(format!("Unable to parse babel routes: {e}")),
@@ -59,13 +65,18 @@ pub async fn get_neighbor_info(_req: HttpRequest) -> HttpResponse {
     let combined_list = merge_debts_and_neighbors(neighbors, debts);
     let babel_port = settings::get_rita_client().network.babel_port;
 
-    match open_babel_stream(babel_port, BABEL_TIMEOUT) {
+    match open_babel_stream(babel_port, BABEL_TIMEOUT).await {
         Ok(mut stream) => {
-            let routes = parse_routes(&mut stream);
+            let routes = parse_routes(&mut stream).await;
             if let Ok(routes) = routes {
                 let route_table_sample = routes;
                 let stats = get_stats();
-                let output = generate_neighbors_list(stats, route_table_sample, combined_list);
+                let output = generate_neighbors_list(
+                    stats,
+                    route_table_sample,
+                    combined_list,
+                    tm_get_closed_tunnels(),
+                );
                 HttpResponse::Ok().json(output)
             } else {
                 HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!(
@@ -86,6 +97,7 @@ fn generate_neighbors_list(
     stats: Stats,
     route_table_sample: Vec<Route>,
     debts: HashMap<Identity, (NodeDebtData, Neighbor)>,
+    closed_tunnels: Vec<ClosedTunnel>,
 ) -> Vec<NodeInfo> {
     let mut output = Vec::new();
 
@@ -94,6 +106,11 @@ fn generate_neighbors_list(
             Some(val) => val,
             None => ArrayString::<32>::from("No Nickname").unwrap(),
         };
+        let closed: Vec<ClosedTunnel> = closed_tunnels
+            .iter()
+            .filter(|t| t.identity == *identity)
+            .cloned()
+            .collect();
         let maybe_route = get_installed_route(&identity.mesh_ip, &route_table_sample);
         if maybe_route.is_err() {
             output.push(nonviable_node_info(
@@ -101,7 +118,8 @@ fn generate_neighbors_list(
                 u16::max_value(),
                 identity.mesh_ip.to_string(),
                 *identity,
-                neigh.speed_limit,
+                neigh,
+                closed,
             ));
             continue;
         }
@@ -128,7 +146,8 @@ fn generate_neighbors_list(
                     neigh_route.metric,
                     identity.mesh_ip.to_string(),
                     *identity,
-                    neigh.speed_limit,
+                    neigh,
+                    closed,
                 ));
                 continue;
             }
@@ -147,6 +166,9 @@ fn generate_neighbors_list(
                 link_cost: exit_route.refmetric,
                 price_to_exit: exit_route.price,
                 stats: *stats_entry,
+                tunnel_state: neigh.state,
+                tunnel_history: neigh.history.clone(),
+                closed_tunnels: closed,
             })
         } else {
             output.push(nonviable_node_info(
@@ -154,7 +176,8 @@ fn generate_neighbors_list(
                 neigh_route.metric,
                 identity.mesh_ip.to_string(),
                 *identity,
-                neigh.speed_limit,
+                neigh,
+                closed,
             ));
         }
     }
@@ -185,7 +208,8 @@ fn nonviable_node_info(
     neigh_metric: u16,
     ip: String,
     id: Identity,
-    speed_limit: Option<usize>,
+    neigh: &Neighbor,
+    closed_tunnels: Vec<ClosedTunnel>,
 ) -> NodeInfo {
     NodeInfo {
         nickname: nickname.to_string(),
@@ -197,7 +221,10 @@ fn nonviable_node_info(
         price_to_exit: 0,
         route_metric_to_exit: u16::max_value(),
         route_metric: neigh_metric,
-        speed_limit,
+        speed_limit: neigh.speed_limit,
         stats: IfaceStats::default(),
+        tunnel_state: neigh.state,
+        tunnel_history: neigh.history.clone(),
+        closed_tunnels,
     }
 }
@@ -69,8 +69,7 @@ pub fn start_exit_manager_loop() {
                             if let Some(general_details) = exit.clone().info.general_details() {
                                 info!("We have details for the selected exit!");
                                 // Logic to determnine what the best exit is and if we should switch
-                                let babel_port = settings::get_rita_client().network.babel_port;
-                                let routes = match get_babel_routes(babel_port) {
+                                let routes = match get_babel_routes() {
                                     Ok(a) => a,
                                     Err(_) => {
                                         warn!("No babel routes present to setup an exit");
@@ -228,9 +227,8 @@ pub fn start_exit_manager_loop() {
                                         exit.wg_public_key,
                                         None,
                                     );
-                                    let babel_port = settings::get_rita_client().network.babel_port;
                                     info!("We are signed up for the selected exit!");
-                                    let routes = match get_babel_routes(babel_port) {
+                                    let routes = match get_babel_routes() {
                                         Ok(a) => a,
                                         Err(_) => {
                                             error!("No babel routes present to query exit debts");
@@ -14,10 +14,10 @@
 use crate::exit_manager::{
     get_full_selected_exit, get_selected_exit_ip, reset_exit_blacklist, set_selected_exit,
 };
-use crate::rita_loop::CLIENT_LOOP_TIMEOUT;
 use crate::RitaClientError;
 use althea_types::ExitList;
-use babel_monitor::{open_babel_stream, parse_routes, structs::Route};
+use babel_monitor::structs::Route;
+use rita_common::rita_loop::get_babel_monitor;
 use rita_common::FAST_LOOP_SPEED;
 use settings::client::ExitSwitchingCode;
 use settings::client::SelectedExit;
@@ -732,27 +732,15 @@ fn calculate_average(vals: Vec<u16>) -> u16 {
     (sum / vals.len() as u64) as u16
 }
 
-/// Simple helper function that opens a babel stream to get all routes related to us. We can use these routes to
-/// check which ips are exits and thereby register or setup exits
-pub fn get_babel_routes(babel_port: u16) -> Result<Vec<Route>, RitaClientError> {
-    let mut stream = match open_babel_stream(babel_port, CLIENT_LOOP_TIMEOUT) {
-        Ok(a) => a,
-        Err(_) => {
-            return Err(RitaClientError::MiscStringError(
-                "open babel stream error in exit manager tick".to_string(),
-            ))
-        }
-    };
-    let routes = match parse_routes(&mut stream) {
-        Ok(a) => a,
-        Err(_) => {
-            return Err(RitaClientError::MiscStringError(
-                "Parse routes error in exit manager tick".to_string(),
-            ))
-        }
-    };
-
-    Ok(routes)
+/// Simple helper function that reads the babel routing table to get all routes related to us. We can use these
+/// routes to check which ips are exits and thereby register or setup exits
+pub fn get_babel_routes() -> Result<Vec<Route>, RitaClientError> {
+    match get_babel_monitor().get_routes() {
+        Ok(routes) => Ok(routes),
+        Err(e) => Err(RitaClientError::MiscStringError(format!(
+            "Babel routes error in exit manager tick {e}"
+        ))),
+    }
 }
 
 #[cfg(test)]
@@ -764,9 +752,14 @@ mod tests {
 
     use super::*;
     use crate::exit_manager::{
-        reset_blacklist_warnings, ExitBlacklist, MAX_BLACKLIST_STRIKES, SELECTED_EXIT_LIST,
+        get_routes_hashmap, reset_blacklist_warnings, ExitBlacklist, MAX_BLACKLIST_STRIKES,
+        SELECTED_EXIT_LIST,
     };
+    use babel_monitor::subscription::BabelMonitorClient;
+    use mock_babeld::{start_babel_server, MockBabel, MockRoute};
     use std::net::{IpAddr, Ipv4Addr};
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn test_calculate_average() {
@@ -1252,4 +1245,81 @@ mod tests {
         let ip_network: IpNetwork = "fd00::1340/116".parse().unwrap();
         assert_eq!(ip_network.ip(), "fd00::1340".parse::<IpAddr>().unwrap())
     }
+
+    /// Waits for the monitor's routes to match
+    fn wait_for_routes(
+        client: &BabelMonitorClient,
+        matches: impl Fn(&HashMap<IpAddr, Route>) -> bool,
+    ) -> HashMap<IpAddr, Route> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if let Ok(routes) = client.get_routes() {
+                let routes = get_routes_hashmap(routes);
+                if matches(&routes) {
+                    return routes;
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("babel monitor never saw the expected routes");
+    }
+
+    #[test]
+    fn test_set_best_exit_with_mock_babel() {
+        let via: IpAddr = "fe80::2".parse().unwrap();
+        let ip1: IpAddr = "fd00::101".parse().unwrap();
+        let ip2: IpAddr = "fd00::102".parse().unwrap();
+        let ip3: IpAddr = "fd00::103".parse().unwrap();
+        let babel = MockBabel::new();
+        for (id, prefix, metric) in [
+            ("1", "fd00::101/128", 400),
+            ("2", "fd00::102/128", 200),
+            ("3", "fd00::103/128", 500),
+            // cheaper than any exit, but not one of them
+            ("4", "fd00::104/128", 100),
+        ] {
+            let mut route = MockRoute::new(id, prefix, via, "wg0");
+            route.metric = metric;
+            babel.set_route(route);
+        }
+        let port = start_babel_server(babel.clone()).unwrap();
+        let client = BabelMonitorClient::start(port, Duration::from_secs(2));
+        let exit_list = ExitList {
+            exit_list: vec![test_identity(ip1), test_identity(ip2), test_identity(ip3)],
+            wg_exit_listen_port: 0,
+        };
+        let exit_name = "mock_babel_exit".to_string();
+
+        // with no exit selected the lowest metric exit is picked
+        let routes = wait_for_routes(&client, |r| r.len() == 4);
+        assert_eq!(
+            set_best_exit(exit_name.clone(), &exit_list, routes).unwrap(),
+            ip2
+        );
+        let selected = get_full_selected_exit(exit_name.clone()).unwrap();
+        assert_eq!(selected.selected_id, Some(ip2));
+        assert_eq!(selected.selected_id_metric, Some(200));
+
+        // a worse metric on another exit doesn't move us
+        let mut route = MockRoute::new("1", "fd00::101/128", via, "wg0");
+        route.metric = 450;
+        babel.set_route(route);
+        let routes = wait_for_routes(&client, |r| r[&ip1].metric == 450);
+        assert_eq!(
+            set_best_exit(exit_name.clone(), &exit_list, routes).unwrap(),
+            ip2
+        );
+
+        // once babel loses the route to our exit we fail over right away
+        babel.remove_route("2");
+        let routes = wait_for_routes(&client, |r| !r.contains_key(&ip2));
+        assert_eq!(
+            set_best_exit(exit_name.clone(), &exit_list, routes).unwrap(),
+            ip1
+        );
+        assert_eq!(
+            get_full_selected_exit(exit_name).unwrap().selected_id,
+            Some(ip1)
+        );
+    }
 }
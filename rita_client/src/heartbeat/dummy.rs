@@ -9,6 +9,7 @@ use babel_monitor::structs::Neighbor;
 use babel_monitor::structs::Route;
 use clarity::Address;
 use ipnetwork::IpNetwork;
+use rita_common::tunnel_manager::lifecycle::TunnelState;
 use rita_common::tunnel_manager::Neighbor as RitaNeighbor;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
@@ -68,6 +69,9 @@ pub fn dummy_neigh_tunnel() -> RitaNeighbor {
         iface_name: "dummy_iface".to_string(),
         tunnel_ip: IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)),
         speed_limit: None,
+        state: TunnelState::Up,
+        history: Vec::new(),
+        capabilities: None,
     }
 }
 
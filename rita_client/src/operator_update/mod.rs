@@ -336,6 +336,10 @@ fn perform_operator_update(
             let res = update_authorized_keys(add_list, drop_list, key_file);
             info!("Update auth_keys result is  {:?}", res);
         }
+        Some(OperatorAction::SetPeerPolicy { policy }) => {
+            info!("Received a new peer policy from op tools {:?}", policy);
+            network.peer_policy = policy;
+        }
         None => {}
     }
     network.shaper_settings = new_settings.shaper_settings;
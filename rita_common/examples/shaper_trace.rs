@@ -0,0 +1,118 @@
+//! Records babel rtt traces on a router and replays them through the shaper algorithms offline.
+//!
+//! Recording polls babel once per fast loop tick and prints the rtt to the neighbor on the given
+//! interface, one sample per line, which is the trace format replay reads
+//!
+//!     shaper_trace record <babel port> <interface> <samples> > trace.txt
+//!
+//! Replaying runs the trace through every algorithm and prints how each did. With a capacity in
+//! mbps the link is modeled so the effect of each limit on the rtt and throughput is estimated,
+//! without one the trace is replayed as recorded and only the decisions are meaningful
+//!
+//!     shaper_trace replay <trace file> [capacity mbps] [min speed] [max speed]
+
+use babel_monitor::open_babel_stream;
+use babel_monitor::parse_neighs;
+use rita_common::rita_loop::fast_loop::FAST_LOOP_SPEED;
+use rita_common::tunnel_manager::shaping::algorithm::ShapingBounds;
+use rita_common::tunnel_manager::shaping::simulation::{compare, parse_trace, LinkModel};
+use std::env;
+use std::fs;
+use std::process::exit;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+const USAGE: &str = "usage: shaper_trace record <babel port> <interface> <samples>
+       shaper_trace replay <trace file> [capacity mbps] [min speed] [max speed]";
+
+const BABEL_TIMEOUT: Duration = Duration::from_secs(4);
+
+fn arg<T: std::str::FromStr>(args: &[String], idx: usize, name: &str) -> T {
+    match args.get(idx).map(|a| a.parse()) {
+        Some(Ok(val)) => val,
+        _ => {
+            eprintln!("missing or invalid {name}\n{USAGE}");
+            exit(1)
+        }
+    }
+}
+
+fn record(babel_port: u16, iface: &str, samples: usize) {
+    println!("# babel rtt to the neighbor on {iface} in ms, one sample every {FAST_LOOP_SPEED:?}");
+    for _ in 0..samples {
+        let start = Instant::now();
+        let neighs = open_babel_stream(babel_port, BABEL_TIMEOUT)
+            .and_then(|mut stream| parse_neighs(&mut stream));
+        match neighs {
+            Ok(neighs) => match neighs.iter().find(|n| n.iface == iface) {
+                Some(neigh) => println!("{}", neigh.rtt),
+                None => eprintln!("no neighbor on {iface}, skipping sample"),
+            },
+            Err(e) => eprintln!("failed to get neighbors from babel {e:?}"),
+        }
+        if let Some(remaining) = FAST_LOOP_SPEED.checked_sub(start.elapsed()) {
+            thread::sleep(remaining);
+        }
+    }
+}
+
+fn replay(path: &str, capacity: Option<f32>, bounds: ShapingBounds) {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("failed to read {path} {e}");
+            exit(1)
+        }
+    };
+    let trace = match parse_trace(&text) {
+        Ok(trace) => trace,
+        Err(e) => {
+            eprintln!("failed to parse {path} {e}");
+            exit(1)
+        }
+    };
+    println!(
+        "{:<16} {:>10} {:>10} {:>16} {:>8} {:>12}",
+        "algorithm", "mean rtt", "p95 rtt", "mean throughput", "changes", "final limit"
+    );
+    for result in compare(&trace, LinkModel { capacity }, bounds) {
+        let throughput = match result.mean_throughput {
+            Some(t) => format!("{t:.1}"),
+            None => "-".to_string(),
+        };
+        let last = match result.limits.last().copied().flatten() {
+            Some(limit) => limit.to_string(),
+            None => "none".to_string(),
+        };
+        println!(
+            "{:<16} {:>10.1} {:>10.1} {:>16} {:>8} {:>12}",
+            result.algorithm, result.mean_rtt, result.p95_rtt, throughput, result.changes, last
+        );
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(|a| a.as_str()) {
+        Some("record") => record(
+            arg(&args, 2, "babel port"),
+            &arg::<String>(&args, 3, "interface"),
+            arg(&args, 4, "samples"),
+        ),
+        Some("replay") => {
+            let capacity = args.get(3).map(|_| arg(&args, 3, "capacity"));
+            let min = args.get(4).map_or(50, |_| arg(&args, 4, "min speed"));
+            let max = args.get(5).map_or(10000, |_| arg(&args, 5, "max speed"));
+            replay(
+                &arg::<String>(&args, 2, "trace file"),
+                capacity,
+                ShapingBounds { max, min },
+            )
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            exit(1)
+        }
+    }
+}
@@ -382,6 +382,19 @@ pub fn low_balance() -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use actix_async::System;
+    use clarity::PrivateKey;
+    use mock_chain::{start_ethereum_server, Account, MockChain, ETH_DENOM};
+    use settings::client::RitaClientSettings;
+    use std::sync::Mutex;
+    use web30::client::Web3;
+
+    const WEI_PER_ETH: u64 = 1_000_000_000_000_000_000;
+
+    lazy_static! {
+        /// Every test here shares the oracle, so they take turns
+        static ref ORACLE_TEST_LOCK: Mutex<()> = Mutex::new(());
+    }
 
     /// Helper function to prevent race conditions when running these test due to parallel test environment
     fn clear_gas_oracle() {
@@ -390,6 +403,7 @@ mod tests {
 
     #[test]
     fn test_oracle_get_set() {
+        let _lock = ORACLE_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         clear_gas_oracle();
 
         let or = ORACLE.read().unwrap();
@@ -406,6 +420,7 @@ mod tests {
 
     #[test]
     fn test_set_network_and_nonce() {
+        let _lock = ORACLE_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
         clear_gas_oracle();
 
         let or = ORACLE.read().unwrap();
@@ -435,4 +450,73 @@ mod tests {
 
         clear_gas_oracle();
     }
+
+    fn set_oracle_settings(full_node: &str, our_address: Address, min_gas: Uint256) {
+        settings::set_rita_client(RitaClientSettings::default());
+        let mut common = settings::get_rita_common();
+        common.payment.eth_address = Some(our_address);
+        common.payment.eth_node_list = vec![full_node.to_string()];
+        common.payment.min_gas = min_gas;
+        settings::set_rita_common(common);
+    }
+
+    #[test]
+    /// update() takes the balance, nonce and gas price from the full node and refuses data from
+    /// a node that is syncing or behind the last block we saw
+    fn test_update_against_mock_chain() {
+        let _lock = ORACLE_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let runner = System::new();
+        runner.block_on(async move {
+            let chain = MockChain::new();
+            let full_node = start_ethereum_server(chain.clone()).unwrap();
+            let key: PrivateKey =
+                "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f1e"
+                    .parse()
+                    .unwrap();
+            let us = key.to_address();
+            let them = Address::from_slice(&[2; 20]).unwrap();
+            chain.set_balance(Account::Eth(us), ETH_DENOM, WEI_PER_ETH.into());
+            chain.set_gas_price(2_000_000_000u64.into());
+            Web3::new(&full_node, ORACLE_TIMEOUT)
+                .send_transaction(them, Vec::new(), 1_000_000u64.into(), key, Vec::new())
+                .await
+                .unwrap();
+            chain.mine_blocks(5);
+
+            set_oracle_settings(&full_node, us, 1_000_000_000u64.into());
+            update().await;
+            assert_eq!(get_oracle_balance(), Some((WEI_PER_ETH - 1_000_000).into()));
+            assert_eq!(get_oracle_nonce(), 1u8.into());
+            assert_eq!(get_oracle_latest_gas_price(), 2_000_000_000u64.into());
+            assert_eq!(get_oracle_last_seen_block(), Some(5u8.into()));
+            assert!(get_oracle_last_updated().is_some());
+            assert!(!potential_payment_issues_detected());
+
+            // gas prices below the configured minimum are raised to it
+            set_oracle_settings(&full_node, us, 3_000_000_000u64.into());
+            update().await;
+            assert_eq!(get_oracle_latest_gas_price(), 3_000_000_000u64.into());
+
+            // a node that is behind the last block we saw is ignored
+            chain.reorg(2);
+            chain.set_balance(Account::Eth(us), ETH_DENOM, 1u8.into());
+            update().await;
+            assert_eq!(get_oracle_balance(), Some((WEI_PER_ETH - 1_000_000).into()));
+            assert_eq!(get_oracle_last_seen_block(), Some(5u8.into()));
+
+            // as is one that is syncing, even once it has caught up
+            chain.mine_blocks(3);
+            chain.set_syncing(true);
+            update().await;
+            assert_eq!(get_oracle_balance(), Some((WEI_PER_ETH - 1_000_000).into()));
+            assert_eq!(get_oracle_last_seen_block(), Some(5u8.into()));
+
+            chain.set_syncing(false);
+            update().await;
+            assert_eq!(get_oracle_balance(), Some(1u8.into()));
+            assert_eq!(get_oracle_last_seen_block(), Some(6u8.into()));
+        });
+        set_oracle_nonce(0u8.into());
+        clear_gas_oracle();
+    }
 }
@@ -1,9 +1,9 @@
 use actix_web_async::http::StatusCode;
 use actix_web_async::web::Path;
 use actix_web_async::{HttpRequest, HttpResponse};
-use babel_monitor::open_babel_stream;
-use babel_monitor::set_local_fee as babel_set_local_fee;
-use babel_monitor::set_metric_factor as babel_set_metric_factor;
+use babel_monitor::async_monitor::open_babel_stream;
+use babel_monitor::async_monitor::set_local_fee as babel_set_local_fee;
+use babel_monitor::async_monitor::set_metric_factor as babel_set_metric_factor;
 use std::collections::HashMap;
 use std::time::Duration;
 
@@ -35,9 +35,9 @@ pub async fn set_local_fee(path: Path<u32>) -> HttpResponse {
     // themselves
     let new_fee = if new_fee > max_fee { max_fee } else { new_fee };
 
-    match open_babel_stream(babel_port, Duration::from_secs(5)) {
+    match open_babel_stream(babel_port, Duration::from_secs(5)).await {
         Ok(mut stream) => {
-            match babel_set_local_fee(&mut stream, new_fee) {
+            match babel_set_local_fee(&mut stream, new_fee).await {
                 Ok(_) => {
                     let mut common = settings::get_rita_common();
                     common.payment.local_fee = new_fee;
@@ -71,9 +71,9 @@ pub async fn set_metric_factor(path: Path<u32>) -> HttpResponse {
     debug!("/metric_factor/{} POST hit", new_factor);
     let babel_port = settings::get_rita_common().network.babel_port;
 
-    match open_babel_stream(babel_port, Duration::from_secs(5)) {
+    match open_babel_stream(babel_port, Duration::from_secs(5)).await {
         Ok(mut stream) => {
-            match babel_set_metric_factor(&mut stream, new_factor) {
+            match babel_set_metric_factor(&mut stream, new_factor).await {
                 Ok(_) => {
                     let mut common = settings::get_rita_common();
                     common.network.metric_factor = new_factor;
@@ -1,7 +1,9 @@
+use crate::debt_keeper;
 use crate::debt_keeper::get_debts_list;
-use crate::debt_keeper::traffic_replace;
-use crate::debt_keeper::Traffic;
-use actix_web_async::{web::Json, HttpRequest, HttpResponse};
+use crate::debt_keeper::get_ledger;
+use crate::debt_keeper::ledger::LedgerQuery;
+use actix_web_async::web::{self, Json};
+use actix_web_async::{HttpRequest, HttpResponse};
 use althea_types::Identity;
 
 pub async fn get_debts(_req: HttpRequest) -> HttpResponse {
@@ -10,9 +12,21 @@ pub async fn get_debts(_req: HttpRequest) -> HttpResponse {
 }
 
 pub async fn reset_debt(user_to_forgive: Json<Identity>) -> HttpResponse {
-    traffic_replace(Traffic {
-        from: user_to_forgive.into_inner(),
-        amount: 0.into(),
-    });
+    debt_keeper::reset_debt(user_to_forgive.into_inner());
     HttpResponse::Ok().json(())
 }
+
+/// Returns the debt ledger history, optionally filtered by identity and a time range
+/// in unix seconds
+pub async fn get_debts_ledger(query: Json<LedgerQuery>) -> HttpResponse {
+    trace!("get_debts_ledger: Hit");
+    let query = query.into_inner();
+    // the ledger files can be large, they are read on a blocking thread
+    match web::block(move || get_ledger(query)).await {
+        Ok(entries) => HttpResponse::Ok().json(entries),
+        Err(e) => {
+            error!("Failed to read the debt ledger {:?}", e);
+            HttpResponse::InternalServerError().json(format!("{e}"))
+        }
+    }
+}
@@ -7,6 +7,7 @@ pub mod debts;
 pub mod development;
 pub mod nickname;
 pub mod own_info;
+pub mod peer_policy;
 pub mod settings;
 pub mod token_bridge;
 pub mod usage;
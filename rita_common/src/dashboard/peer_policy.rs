@@ -0,0 +1,29 @@
+use actix_web_async::{http::StatusCode, web::Json, HttpRequest, HttpResponse};
+use althea_types::PeerPolicy;
+
+use crate::tunnel_manager::tm_get_rejected_peers;
+use crate::RitaCommonError;
+
+pub async fn get_peer_policy(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(settings::get_rita_common().network.peer_policy)
+}
+
+/// Replaces the peer policy, it applies to the next hello from each neighbor
+pub async fn set_peer_policy(policy: Json<PeerPolicy>) -> HttpResponse {
+    let mut common = settings::get_rita_common();
+    common.network.peer_policy = policy.into_inner();
+    settings::set_rita_common(common);
+
+    // try and save the config and fail if we can't
+    if let Err(e) = settings::write_config() {
+        return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
+            .json(format!("{}", RitaCommonError::SettingsError(e)));
+    }
+
+    HttpResponse::Ok().json(())
+}
+
+/// Neighbors that were recently refused a tunnel by the peer policy and why
+pub async fn get_rejected_peers(_req: HttpRequest) -> HttpResponse {
+    HttpResponse::Ok().json(tm_get_rejected_peers())
+}
@@ -0,0 +1,657 @@
+//! Neighbors bill each other from their own traffic counters, so the debt each side records for the
+//! other is never exactly the same and until now the only way a disagreement surfaced was one side
+//! enforcing on the other. Every slow loop tick we send each neighbor our view of the debt between us
+//! over the rita contact port and they respond with theirs. Since debt keeper records what we owe as
+//! positive debt the two debts should cancel out, and what one side has sent should match what the
+//! other has received.
+//!
+//! Some drift is expected, traffic is billed at slightly different times on each side and payments
+//! are validated by each side separately, so a field only counts as disputed once its drift has been
+//! past debt_dispute_threshold for two exchanges in a row. If debt_reconcile_threshold is set, drift in
+//! the debt smaller than it is reconciled by moving our debt half way towards our neighbor's view.
+//! Our neighbor does the same when it starts an exchange, so the two views converge. We only
+//! reconcile using responses to exchanges we started, views sent to us are only used for warnings
+//! and the dashboard. Reconciliation with any one neighbor is capped at debt_reconcile_limit in total
+//! so that a neighbor reporting a slightly wrong view every exchange can't walk our debt away.
+//!
+//! Views are signed with the eth private key from the payment settings and checked against the eth
+//! address of the node they claim to be from, and carry a timestamp so an old view can't be replayed.
+//! We only accept a view from a neighbor we have a tunnel to, sent from its mesh ip.
+
+use crate::debt_keeper::get_node_debt_data;
+use crate::debt_keeper::reconcile_debt;
+use crate::tunnel_manager::tm_get_neighbors;
+use crate::KI;
+use althea_types::Identity;
+use clarity::utils::get_ethereum_msg_hash;
+use clarity::PrivateKey;
+use clarity::Signature;
+use futures::future::join_all;
+use num256::{Int256, Uint256};
+use num_traits::{Signed, Zero};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::Result as DisplayResult;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+pub const DEBT_VIEW_TIMEOUT: Duration = Duration::from_secs(5);
+/// Views older (or further in the future) than this are refused, allowing for clock drift
+pub const DEBT_VIEW_MAX_AGE: Duration = Duration::from_secs(300);
+
+const DEBT_VIEW_DOMAIN: &[u8] = b"althea debt view";
+
+lazy_static! {
+    static ref COMPARISONS: Arc<RwLock<HashMap<u32, HashMap<Identity, DebtComparison>>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+    /// The total amount auto reconcile has moved the debt with each neighbor, in either direction
+    static ref RECONCILED: Arc<RwLock<HashMap<u32, HashMap<Identity, Uint256>>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DebtDisputeError {
+    /// The view was sent to us but is about some other node
+    WrongRecipient,
+    /// We have no debt data for the node that sent the view
+    UnknownNeighbor,
+    /// The view was not sent by a neighbor we have a tunnel to
+    NotNeighbor,
+    InvalidSignature,
+    /// The view is too old, or no newer than the last one we accepted from this neighbor
+    StaleView,
+    NoPrivateKey,
+    SendFailed(String),
+}
+
+impl Display for DebtDisputeError {
+    fn fmt(&self, f: &mut Formatter) -> DisplayResult {
+        match self {
+            Self::WrongRecipient => write!(f, "Debt view is not addressed to us"),
+            Self::UnknownNeighbor => write!(f, "No debt data for this neighbor"),
+            Self::NotNeighbor => write!(f, "Debt view was not sent by a neighbor"),
+            Self::InvalidSignature => write!(f, "Invalid debt view signature"),
+            Self::StaleView => write!(f, "Stale debt view"),
+            Self::NoPrivateKey => write!(f, "No private key to sign debt views with"),
+            Self::SendFailed(e) => write!(f, "Failed to exchange debt views {e}"),
+        }
+    }
+}
+
+impl Error for DebtDisputeError {}
+
+/// One node's record of the debt between it and a neighbor, taken from its debt keeper
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DebtView {
+    /// The node this view belongs to
+    pub from: Identity,
+    /// The neighbor the view is about
+    pub to: Identity,
+    /// What `from` owes `to` (positive) or `to` owes `from` (negative)
+    pub debt: Int256,
+    pub total_payment_sent: Uint256,
+    pub total_payment_received: Uint256,
+    /// Unix timestamp in seconds of when the view was taken
+    pub timestamp: u64,
+    pub signature: Signature,
+}
+
+impl DebtView {
+    fn signed_bytes(
+        from: &Identity,
+        to: &Identity,
+        debt: Int256,
+        total_payment_sent: Uint256,
+        total_payment_received: Uint256,
+        timestamp: u64,
+    ) -> Vec<u8> {
+        let mut bytes = DEBT_VIEW_DOMAIN.to_vec();
+        bytes.extend_from_slice(from.eth_address.as_bytes());
+        bytes.extend_from_slice(to.eth_address.as_bytes());
+        bytes.push(u8::from(debt < Int256::zero()));
+        // unwrap is safe because the abs of a signed 256 bit int can't overflow a unsigned 256 bit int
+        bytes.extend_from_slice(&debt.abs().to_uint256().unwrap().to_be_bytes());
+        bytes.extend_from_slice(&total_payment_sent.to_be_bytes());
+        bytes.extend_from_slice(&total_payment_received.to_be_bytes());
+        bytes.extend_from_slice(&timestamp.to_be_bytes());
+        bytes
+    }
+
+    pub fn new(
+        from: Identity,
+        to: Identity,
+        debt: Int256,
+        total_payment_sent: Uint256,
+        total_payment_received: Uint256,
+        timestamp: u64,
+        key: PrivateKey,
+    ) -> DebtView {
+        let signature = key.sign_ethereum_msg(&DebtView::signed_bytes(
+            &from,
+            &to,
+            debt,
+            total_payment_sent,
+            total_payment_received,
+            timestamp,
+        ));
+        DebtView {
+            from,
+            to,
+            debt,
+            total_payment_sent,
+            total_payment_received,
+            timestamp,
+            signature,
+        }
+    }
+
+    /// Checks that this view was signed by the eth address of the node it is from
+    pub fn verify(&self) -> bool {
+        let hash = get_ethereum_msg_hash(&DebtView::signed_bytes(
+            &self.from,
+            &self.to,
+            self.debt,
+            self.total_payment_sent,
+            self.total_payment_received,
+            self.timestamp,
+        ));
+        match self.signature.recover(&hash) {
+            Ok(address) => address == self.from.eth_address,
+            Err(_) => false,
+        }
+    }
+
+    /// True if the view was taken within DEBT_VIEW_MAX_AGE of now
+    fn is_fresh(&self, now: u64) -> bool {
+        self.timestamp.abs_diff(now) <= DEBT_VIEW_MAX_AGE.as_secs()
+    }
+}
+
+/// A field of the debt views that the two sides disagree on
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum DebtDivergence {
+    Debt,
+    PaymentsSent,
+    PaymentsReceived,
+}
+
+/// The result of comparing our view of the debt with a neighbor against theirs
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DebtComparison {
+    /// Unix timestamp in seconds of when the views were compared
+    pub timestamp: u64,
+    pub ours: DebtView,
+    pub theirs: DebtView,
+    /// Our debt plus theirs, zero when we agree
+    pub debt_drift: Int256,
+    /// What we have sent minus what they have received
+    pub sent_drift: Int256,
+    /// What we have received minus what they have sent
+    pub received_drift: Int256,
+    /// Every field whose drift is past the dispute threshold in this comparison
+    pub divergences: Vec<DebtDivergence>,
+    /// The fields that have been past the dispute threshold for two comparisons in a row
+    pub disputed: Vec<DebtDivergence>,
+    /// The amount auto reconcile added to our debt as a result of this comparison
+    pub reconciled: Int256,
+}
+
+/// Returns the last comparison made with this neighbor, if any
+pub fn get_debt_comparison(neighbor: &Identity) -> Option<DebtComparison> {
+    let netns = KI.check_integration_test_netns();
+    COMPARISONS
+        .read()
+        .unwrap()
+        .get(&netns)
+        .and_then(|c| c.get(neighbor).cloned())
+}
+
+fn set_debt_comparison(comparison: DebtComparison) {
+    let netns = KI.check_integration_test_netns();
+    COMPARISONS
+        .write()
+        .unwrap()
+        .entry(netns)
+        .or_default()
+        .insert(comparison.theirs.from, comparison);
+}
+
+/// Our signed view of the debt with the given neighbor, None if we have never had any debt with
+/// them
+pub fn get_debt_view(our_id: Identity, neighbor: Identity, key: PrivateKey) -> Option<DebtView> {
+    get_node_debt_data(&neighbor).map(|d| {
+        DebtView::new(
+            our_id,
+            neighbor,
+            d.debt,
+            d.total_payment_sent,
+            d.total_payment_received,
+            get_timestamp(),
+            key,
+        )
+    })
+}
+
+fn get_reconciled(neighbor: &Identity) -> Uint256 {
+    let netns = KI.check_integration_test_netns();
+    RECONCILED
+        .read()
+        .unwrap()
+        .get(&netns)
+        .and_then(|r| r.get(neighbor).cloned())
+        .unwrap_or_else(Uint256::zero)
+}
+
+fn add_reconciled(neighbor: Identity, amount: Int256) {
+    let netns = KI.check_integration_test_netns();
+    let reconciled = &mut *RECONCILED.write().unwrap();
+    let total = reconciled
+        .entry(netns)
+        .or_default()
+        .entry(neighbor)
+        .or_insert_with(Uint256::zero);
+    *total += amount.abs().to_uint256().unwrap();
+}
+
+/// The difference between two unsigned totals
+fn drift(a: Uint256, b: Uint256) -> Int256 {
+    if a > b {
+        (a - b).to_int256().unwrap_or_else(Int256::zero)
+    } else {
+        Int256::zero() - (b - a).to_int256().unwrap_or_else(Int256::zero)
+    }
+}
+
+fn past_threshold(drift: Int256, threshold: Uint256) -> bool {
+    // unwrap is safe because the abs of a signed 256 bit int can't overflow a unsigned 256 bit int
+    drift.abs().to_uint256().unwrap() > threshold
+}
+
+/// Compares our view against our neighbors, previous is the last comparison with this neighbor
+/// and is used to tell a brief divergence from a persistent one
+pub fn compare_debt_views(
+    ours: DebtView,
+    theirs: DebtView,
+    previous: Option<&DebtComparison>,
+    dispute_threshold: Uint256,
+) -> DebtComparison {
+    let debt_drift = ours.debt + theirs.debt;
+    let sent_drift = drift(ours.total_payment_sent, theirs.total_payment_received);
+    let received_drift = drift(ours.total_payment_received, theirs.total_payment_sent);
+
+    let mut divergences = Vec::new();
+    for (divergence, drift) in [
+        (DebtDivergence::Debt, debt_drift),
+        (DebtDivergence::PaymentsSent, sent_drift),
+        (DebtDivergence::PaymentsReceived, received_drift),
+    ] {
+        if past_threshold(drift, dispute_threshold) {
+            divergences.push(divergence);
+        }
+    }
+    let disputed = match previous {
+        Some(previous) => divergences
+            .iter()
+            .filter(|d| previous.divergences.contains(d))
+            .cloned()
+            .collect(),
+        None => Vec::new(),
+    };
+
+    DebtComparison {
+        timestamp: get_timestamp(),
+        ours,
+        theirs,
+        debt_drift,
+        sent_drift,
+        received_drift,
+        divergences,
+        disputed,
+        reconciled: Int256::zero(),
+    }
+}
+
+/// The amount to add to our debt to move half way towards our neighbor's view, None if the drift
+/// in the debt is too large to reconcile automatically or payments are still being disputed, in
+/// which case the debt drift may just be a payment one side has not validated yet
+pub fn reconcile_amount(
+    comparison: &DebtComparison,
+    reconcile_threshold: Uint256,
+) -> Option<Int256> {
+    if comparison.debt_drift == Int256::zero()
+        || past_threshold(comparison.debt_drift, reconcile_threshold)
+        || past_threshold(comparison.sent_drift, reconcile_threshold)
+        || past_threshold(comparison.received_drift, reconcile_threshold)
+    {
+        return None;
+    }
+    let half = comparison.debt_drift.abs().to_uint256().unwrap() / 2u8.into();
+    let half = half.to_int256()?;
+    if comparison.debt_drift > Int256::zero() {
+        Some(Int256::zero() - half)
+    } else {
+        Some(half)
+    }
+}
+
+/// Limits a reconcile amount so that the total reconciled with a neighbor, already being what has
+/// been reconciled so far, stays within limit. None if the limit has been reached
+pub fn cap_reconcile_amount(amount: Int256, already: Uint256, limit: Uint256) -> Option<Int256> {
+    if already >= limit {
+        return None;
+    }
+    let remaining = limit - already;
+    let abs = amount.abs().to_uint256().unwrap();
+    if abs <= remaining {
+        return Some(amount);
+    }
+    let remaining = remaining.to_int256()?;
+    if amount > Int256::zero() {
+        Some(remaining)
+    } else {
+        Some(Int256::zero() - remaining)
+    }
+}
+
+/// Compares our current view with the neighbor's, logs any dispute and stores the result
+fn record_comparison(ours: DebtView, theirs: DebtView, reconcile: bool) -> DebtComparison {
+    let payment = settings::get_rita_common().payment;
+    let previous = get_debt_comparison(&theirs.from);
+    let mut comparison = compare_debt_views(
+        ours,
+        theirs,
+        previous.as_ref(),
+        payment.debt_dispute_threshold,
+    );
+
+    if !comparison.disputed.is_empty() {
+        warn!(
+            "Debt dispute with {}: disputed={:?} our_debt={} their_debt={} debt_drift={} \
+             sent_drift={} received_drift={}",
+            comparison.theirs.from.wg_public_key,
+            comparison.disputed,
+            comparison.ours.debt,
+            comparison.theirs.debt,
+            comparison.debt_drift,
+            comparison.sent_drift,
+            comparison.received_drift
+        );
+    }
+
+    if reconcile && payment.debt_reconcile_threshold > Uint256::zero() {
+        let neighbor = comparison.theirs.from;
+        if let Some(amount) = reconcile_amount(&comparison, payment.debt_reconcile_threshold) {
+            match cap_reconcile_amount(
+                amount,
+                get_reconciled(&neighbor),
+                payment.debt_reconcile_limit,
+            ) {
+                Some(amount) => {
+                    info!(
+                        "Reconciling debt with {} by {}, drift was {}",
+                        neighbor.wg_public_key, amount, comparison.debt_drift
+                    );
+                    reconcile_debt(neighbor, amount);
+                    add_reconciled(neighbor, amount);
+                    comparison.reconciled = amount;
+                }
+                None => warn!(
+                    "Not reconciling debt with {}, reconcile limit reached, drift is {}",
+                    neighbor.wg_public_key, comparison.debt_drift
+                ),
+            }
+        }
+    }
+
+    set_debt_comparison(comparison.clone());
+    comparison
+}
+
+/// Checks the signature and age of a view from a neighbor, and that it is newer than the last
+/// one we accepted from them
+fn check_view(theirs: &DebtView) -> Result<(), DebtDisputeError> {
+    if !theirs.verify() {
+        return Err(DebtDisputeError::InvalidSignature);
+    }
+    let newer = match get_debt_comparison(&theirs.from) {
+        Some(previous) => theirs.timestamp > previous.theirs.timestamp,
+        None => true,
+    };
+    if !newer || !theirs.is_fresh(get_timestamp()) {
+        return Err(DebtDisputeError::StaleView);
+    }
+    Ok(())
+}
+
+/// Handles a view sent to us by a neighbor from the given ip, responding with our own
+pub fn debt_view_received(
+    theirs: DebtView,
+    sender: Option<IpAddr>,
+) -> Result<DebtView, DebtDisputeError> {
+    let common = settings::get_rita_common();
+    let our_id = match common.get_identity() {
+        Some(id) if id == theirs.to => id,
+        _ => return Err(DebtDisputeError::WrongRecipient),
+    };
+    let key = match common.payment.eth_private_key {
+        Some(key) => key,
+        None => return Err(DebtDisputeError::NoPrivateKey),
+    };
+    let is_neighbor = tm_get_neighbors()
+        .iter()
+        .any(|n| n.identity.global == theirs.from);
+    if !is_neighbor || sender != Some(theirs.from.mesh_ip) {
+        return Err(DebtDisputeError::NotNeighbor);
+    }
+    check_view(&theirs)?;
+    let ours = match get_debt_view(our_id, theirs.from, key) {
+        Some(v) => v,
+        None => return Err(DebtDisputeError::UnknownNeighbor),
+    };
+    record_comparison(ours.clone(), theirs, false);
+    Ok(ours)
+}
+
+/// Sends our view to a single neighbor and compares it with the one they respond with
+async fn exchange_debt_view(ours: DebtView) -> Result<DebtComparison, DebtDisputeError> {
+    let neighbor_url = format!(
+        "http://[{}]:{}/debt_view",
+        ours.to.mesh_ip,
+        settings::get_rita_common().network.rita_contact_port,
+    );
+
+    let client = awc::Client::default();
+    let mut res = client
+        .post(&neighbor_url)
+        .timeout(DEBT_VIEW_TIMEOUT)
+        .send_json(&ours)
+        .await
+        .map_err(|e| DebtDisputeError::SendFailed(e.to_string()))?;
+    if !res.status().is_success() {
+        return Err(DebtDisputeError::SendFailed(res.status().to_string()));
+    }
+    let theirs: DebtView = res
+        .json()
+        .await
+        .map_err(|e| DebtDisputeError::SendFailed(e.to_string()))?;
+    if theirs.from != ours.to || theirs.to != ours.from {
+        return Err(DebtDisputeError::WrongRecipient);
+    }
+    check_view(&theirs)?;
+    Ok(record_comparison(ours, theirs, true))
+}
+
+/// Exchanges debt views with every neighbor we have a tunnel to
+pub async fn exchange_debt_views() {
+    let common = settings::get_rita_common();
+    let (our_id, key) = match (common.get_identity(), common.payment.eth_private_key) {
+        (Some(id), Some(key)) => (id, key),
+        _ => return,
+    };
+    let neighbors: HashSet<Identity> = tm_get_neighbors()
+        .into_iter()
+        .map(|n| n.identity.global)
+        .collect();
+
+    let mut futs = Vec::new();
+    for neighbor in neighbors {
+        if let Some(ours) = get_debt_view(our_id, neighbor, key) {
+            futs.push(exchange_debt_view(ours));
+        }
+    }
+    for res in join_all(futs).await {
+        // older neighbors don't have the endpoint, so failures are expected
+        if let Err(e) = res {
+            trace!("{}", e);
+        }
+    }
+}
+
+fn get_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage_tracker::tests::test::random_identity;
+
+    fn get_test_key() -> PrivateKey {
+        "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f1e"
+            .parse()
+            .unwrap()
+    }
+
+    fn views(our_debt: i64, their_debt: i64) -> (DebtView, DebtView) {
+        let key = get_test_key();
+        let a = random_identity();
+        let b = random_identity();
+        (
+            DebtView::new(a, b, our_debt.into(), 100u32.into(), 0u32.into(), 0, key),
+            DebtView::new(b, a, their_debt.into(), 0u32.into(), 100u32.into(), 0, key),
+        )
+    }
+
+    #[test]
+    fn test_debt_view_signature() {
+        let key = get_test_key();
+        let mut from = random_identity();
+        from.eth_address = key.to_address();
+        let now = get_timestamp();
+        let view = DebtView::new(
+            from,
+            random_identity(),
+            (-50).into(),
+            100u32.into(),
+            0u32.into(),
+            now,
+            key,
+        );
+        assert!(view.verify());
+        assert!(view.is_fresh(now));
+        assert!(!view.is_fresh(now + DEBT_VIEW_MAX_AGE.as_secs() + 1));
+
+        // flipping the sign of the debt must break the signature
+        let mut tampered = view.clone();
+        tampered.debt = 50.into();
+        assert!(!tampered.verify());
+
+        let mut tampered = view.clone();
+        tampered.timestamp = now + 1;
+        assert!(!tampered.verify());
+
+        // signed by someone other than the node it claims to be from
+        let mut forged = view;
+        forged.from = random_identity();
+        assert!(!forged.verify());
+    }
+
+    #[test]
+    fn test_cap_reconcile_amount() {
+        let limit: Uint256 = 10u32.into();
+        assert_eq!(
+            cap_reconcile_amount(3.into(), 0u32.into(), limit),
+            Some(3.into())
+        );
+        assert_eq!(
+            cap_reconcile_amount((-3).into(), 8u32.into(), limit),
+            Some((-2).into())
+        );
+        assert_eq!(
+            cap_reconcile_amount(3.into(), 8u32.into(), limit),
+            Some(2.into())
+        );
+        assert_eq!(cap_reconcile_amount(3.into(), 10u32.into(), limit), None);
+        assert_eq!(
+            cap_reconcile_amount(3.into(), 0u32.into(), 0u32.into()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_compare_debt_views() {
+        let threshold: Uint256 = 10u32.into();
+
+        // we owe them 50, they agree
+        let (ours, theirs) = views(50, -50);
+        let comparison = compare_debt_views(ours, theirs, None, threshold);
+        assert_eq!(comparison.debt_drift, 0.into());
+        assert!(comparison.divergences.is_empty());
+
+        // within the threshold
+        let (ours, theirs) = views(50, -45);
+        let comparison = compare_debt_views(ours, theirs, None, threshold);
+        assert_eq!(comparison.debt_drift, 5.into());
+        assert!(comparison.divergences.is_empty());
+
+        // a divergence only becomes a dispute if it persists
+        let (ours, mut theirs) = views(50, -20);
+        theirs.total_payment_received = 50u32.into();
+        let first = compare_debt_views(ours.clone(), theirs.clone(), None, threshold);
+        assert_eq!(first.debt_drift, 30.into());
+        assert_eq!(first.sent_drift, 50.into());
+        assert_eq!(
+            first.divergences,
+            vec![DebtDivergence::Debt, DebtDivergence::PaymentsSent]
+        );
+        assert!(first.disputed.is_empty());
+
+        // the payment has been validated on their side, only the debt is still disputed
+        theirs.total_payment_received = 100u32.into();
+        let second = compare_debt_views(ours, theirs, Some(&first), threshold);
+        assert_eq!(second.divergences, vec![DebtDivergence::Debt]);
+        assert_eq!(second.disputed, vec![DebtDivergence::Debt]);
+    }
+
+    #[test]
+    fn test_reconcile_amount() {
+        let threshold: Uint256 = 10u32.into();
+
+        let (ours, theirs) = views(50, -45);
+        let comparison = compare_debt_views(ours, theirs, None, threshold);
+        assert_eq!(reconcile_amount(&comparison, threshold), Some((-2).into()));
+
+        let (ours, theirs) = views(-50, 44);
+        let comparison = compare_debt_views(ours, theirs, None, threshold);
+        assert_eq!(reconcile_amount(&comparison, threshold), Some(3.into()));
+
+        // too large to reconcile
+        let (ours, theirs) = views(50, -20);
+        let comparison = compare_debt_views(ours, theirs, None, threshold);
+        assert_eq!(reconcile_amount(&comparison, threshold), None);
+
+        // a payment one side has not validated yet
+        let (ours, mut theirs) = views(50, -45);
+        theirs.total_payment_received = 0u32.into();
+        let comparison = compare_debt_views(ours, theirs, None, threshold);
+        assert_eq!(reconcile_amount(&comparison, threshold), None);
+    }
+}
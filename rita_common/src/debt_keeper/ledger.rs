@@ -0,0 +1,401 @@
+//! The debt ledger is an append only history of every change DebtKeeper makes to a neighbor's debt.
+//! DebtKeeper itself only stores the running total for each neighbor, which is enough to decide
+//! when to pay or enforce but not enough to explain to a neighbor (or an operator) how a disputed
+//! bill came to be. Every traffic delta, payment, debt limit forgiveness and manual reset is
+//! recorded here along with the debt value that resulted from it.
+//!
+//! Entries are buffered in memory and appended to a file next to the debts file whenever debt keeper
+//! is saved. Once that file grows past MAX_LEDGER_FILE_SIZE it is rotated, keeping at most
+//! LEDGER_ROTATIONS old files so that the history can't fill up the disk of a small router.
+
+use althea_types::Identity;
+use bincode::ErrorKind as BincodeErrorKind;
+use num256::{Int256, Uint256};
+use std::collections::VecDeque;
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Error as IOError;
+use std::io::ErrorKind;
+use std::io::Write;
+use std::path::Path;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// The maximum number of entries we will hold in memory waiting to be written to disk, if we
+/// exceed this the oldest entries are dropped
+pub const MAX_PENDING_LEDGER_ENTRIES: usize = 10_000;
+/// Once the active ledger file grows past this size in bytes it is rotated out
+pub const MAX_LEDGER_FILE_SIZE: u64 = 1_000_000;
+/// The number of rotated ledger files we keep around, the oldest is deleted on rotation
+pub const LEDGER_ROTATIONS: usize = 3;
+
+/// A single change to the debt of a neighbor
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LedgerEvent {
+    /// Traffic was billed, positive amounts mean we owe them more, negative that they owe us more
+    Traffic { amount: Int256 },
+    /// A payment from this neighbor was validated
+    PaymentReceived { amount: Uint256 },
+    /// A payment we sent to this neighbor was validated
+    PaymentSent { amount: Uint256 },
+    /// The debt limit clamped the debt, this is the amount that was dropped, negative values
+    /// are debt we forgave them, positive values are debt we will not pay
+    DebtLimitForgiven { amount: Int256 },
+    /// The debt was manually reset to zero, usually from the dashboard
+    DebtReset { previous_debt: Int256 },
+    /// A validated payment from this neighbor was removed from the chain by a reorg
+    PaymentReceivedReversed { amount: Uint256 },
+    /// A validated payment we sent to this neighbor was removed from the chain by a reorg
+    PaymentSentReversed { amount: Uint256 },
+    /// Drift between our debt and this neighbor's view of it was reconciled automatically, this
+    /// is the amount that was added to the debt
+    Reconciled { amount: Int256 },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct LedgerEntry {
+    /// Unix timestamp in seconds of when this change was made
+    pub timestamp: u64,
+    pub identity: Identity,
+    pub event: LedgerEvent,
+    /// The debt of this neighbor after the event was applied
+    pub debt: Int256,
+}
+
+/// Filters entries when reading back the ledger, all fields are optional and an empty
+/// query returns the entire history
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LedgerQuery {
+    pub identity: Option<Identity>,
+    /// Unix timestamp in seconds, inclusive
+    pub start: Option<u64>,
+    /// Unix timestamp in seconds, inclusive
+    pub end: Option<u64>,
+}
+
+impl LedgerQuery {
+    pub fn matches(&self, entry: &LedgerEntry) -> bool {
+        if let Some(identity) = self.identity {
+            if identity != entry.identity {
+                return false;
+            }
+        }
+        if let Some(start) = self.start {
+            if entry.timestamp < start {
+                return false;
+            }
+        }
+        if let Some(end) = self.end {
+            if entry.timestamp > end {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The in memory half of the ledger, holds entries that have not yet been written to disk
+#[derive(Clone, Debug, Default)]
+pub struct DebtLedger {
+    pending: VecDeque<LedgerEntry>,
+}
+
+impl DebtLedger {
+    pub fn record(&mut self, identity: Identity, event: LedgerEvent, debt: Int256) {
+        if self.pending.len() >= MAX_PENDING_LEDGER_ENTRIES {
+            warn!("Debt ledger is full, dropping oldest unsaved entry");
+            self.pending.pop_front();
+        }
+        self.pending.push_back(LedgerEntry {
+            timestamp: get_timestamp(),
+            identity,
+            event,
+            debt,
+        });
+    }
+
+    pub fn pending(&self) -> &VecDeque<LedgerEntry> {
+        &self.pending
+    }
+
+    /// Appends all pending entries to the ledger file at file_path, rotating it first if required.
+    /// Pending entries are only cleared once they have been successfully written.
+    pub fn flush(&mut self, file_path: &str) -> Result<(), IOError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        rotate_if_needed(file_path)?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_path)?;
+        let mut writer = BufWriter::new(file);
+        for entry in self.pending.iter() {
+            if let Err(e) = bincode::serialize_into(&mut writer, entry) {
+                return Err(IOError::other(e));
+            }
+        }
+        writer.flush()?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+fn get_timestamp() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(val) => val.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+/// The ledger is stored next to the debts file, with the same name but a different extension
+pub fn get_ledger_file_path(debts_file: &str) -> String {
+    let path = Path::new(debts_file);
+    path.with_extension("ledger").to_string_lossy().to_string()
+}
+
+fn get_rotated_file_path(file_path: &str, index: usize) -> String {
+    format!("{file_path}.{index}")
+}
+
+/// Moves the active ledger file to file_path.1, shifting all older files up by one
+/// and deleting the oldest, if the active file has grown too large
+fn rotate_if_needed(file_path: &str) -> Result<(), IOError> {
+    match fs::metadata(file_path) {
+        Ok(metadata) => {
+            if metadata.len() < MAX_LEDGER_FILE_SIZE {
+                return Ok(());
+            }
+        }
+        Err(_) => return Ok(()),
+    }
+    info!("Rotating debt ledger file {}", file_path);
+
+    let oldest = get_rotated_file_path(file_path, LEDGER_ROTATIONS);
+    if Path::new(&oldest).exists() {
+        fs::remove_file(oldest)?;
+    }
+    for i in (1..LEDGER_ROTATIONS).rev() {
+        let from = get_rotated_file_path(file_path, i);
+        if Path::new(&from).exists() {
+            fs::rename(from, get_rotated_file_path(file_path, i + 1))?;
+        }
+    }
+    fs::rename(file_path, get_rotated_file_path(file_path, 1))
+}
+
+/// Reads every entry in a single ledger file, stopping at the first entry that can't be
+/// decoded, this may happen if we lost power part way through a write
+fn read_ledger_file(file_path: &str) -> Vec<LedgerEntry> {
+    let mut ret = Vec::new();
+    let file = match File::open(file_path) {
+        Ok(f) => f,
+        Err(_) => return ret,
+    };
+    let mut reader = BufReader::new(file);
+    loop {
+        match bincode::deserialize_from(&mut reader) {
+            Ok(entry) => ret.push(entry),
+            Err(e) => {
+                match *e {
+                    BincodeErrorKind::Io(ref e) if e.kind() == ErrorKind::UnexpectedEof => {}
+                    _ => error!("Failed to read debt ledger entry in {} {:?}", file_path, e),
+                }
+                break;
+            }
+        }
+    }
+    ret
+}
+
+/// Reads the full ledger history, rotated files first then the active file and finally any
+/// pending entries that have not been written out yet. The result is in chronological order.
+/// pending is a copy taken before the files are read, if some of it was flushed in between those
+/// entries end the files and are only returned once
+pub fn read_ledger(
+    file_path: &str,
+    pending: &VecDeque<LedgerEntry>,
+    query: &LedgerQuery,
+) -> Vec<LedgerEntry> {
+    let mut ret = Vec::new();
+    // the last entries on disk, as many as could have been flushed from pending
+    let mut tail: VecDeque<LedgerEntry> = VecDeque::with_capacity(pending.len());
+    let files = (1..=LEDGER_ROTATIONS)
+        .rev()
+        .map(|i| get_rotated_file_path(file_path, i))
+        .chain(std::iter::once(file_path.to_string()));
+    for file in files {
+        for entry in read_ledger_file(&file) {
+            if query.matches(&entry) {
+                ret.push(entry.clone());
+            }
+            if !pending.is_empty() {
+                if tail.len() == pending.len() {
+                    tail.pop_front();
+                }
+                tail.push_back(entry);
+            }
+        }
+    }
+    let flushed = (0..=tail.len())
+        .rev()
+        .find(|n| tail.iter().skip(tail.len() - n).eq(pending.iter().take(*n)))
+        .unwrap_or(0);
+    for entry in pending.iter().skip(flushed) {
+        if query.matches(entry) {
+            ret.push(entry.clone());
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_test_identity(n: u8) -> Identity {
+        Identity::new(
+            format!("2001::{n}").parse().unwrap(),
+            "0x0000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+            "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+                .parse()
+                .unwrap(),
+            None,
+        )
+    }
+
+    fn get_test_ledger_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(name);
+        let path = path.to_string_lossy().to_string();
+        let _ = fs::remove_file(&path);
+        for i in 1..=LEDGER_ROTATIONS {
+            let _ = fs::remove_file(get_rotated_file_path(&path, i));
+        }
+        path
+    }
+
+    #[test]
+    fn test_ledger_file_path() {
+        assert_eq!(
+            get_ledger_file_path("/etc/rita-debts.bincode"),
+            "/etc/rita-debts.ledger"
+        );
+        assert_eq!(
+            get_ledger_file_path("/etc/rita-debts.json"),
+            "/etc/rita-debts.ledger"
+        );
+    }
+
+    /// The pending entries are copied before the files are read, so they may have been flushed by
+    /// the time the files are read
+    #[test]
+    fn test_ledger_read_after_flush() {
+        let path = get_test_ledger_path("test_ledger_read_after_flush.ledger");
+        let a = get_test_identity(1);
+
+        let mut ledger = DebtLedger::default();
+        ledger.record(a, LedgerEvent::Traffic { amount: 10.into() }, 10.into());
+        ledger.flush(&path).unwrap();
+        ledger.record(a, LedgerEvent::Traffic { amount: 10.into() }, 20.into());
+        ledger.record(a, LedgerEvent::Traffic { amount: 10.into() }, 30.into());
+        let snapshot = ledger.pending().clone();
+        ledger.flush(&path).unwrap();
+        ledger.record(a, LedgerEvent::Traffic { amount: 10.into() }, 40.into());
+
+        let debts: Vec<Int256> = read_ledger(&path, &snapshot, &LedgerQuery::default())
+            .into_iter()
+            .map(|e| e.debt)
+            .collect();
+        assert_eq!(debts, vec![10.into(), 20.into(), 30.into()]);
+
+        // nothing was flushed since the copy
+        let debts: Vec<Int256> = read_ledger(&path, ledger.pending(), &LedgerQuery::default())
+            .into_iter()
+            .map(|e| e.debt)
+            .collect();
+        assert_eq!(debts, vec![10.into(), 20.into(), 30.into(), 40.into()]);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_ledger_flush_and_query() {
+        let path = get_test_ledger_path("test_ledger_flush_and_query.ledger");
+        let a = get_test_identity(1);
+        let b = get_test_identity(2);
+
+        let mut ledger = DebtLedger::default();
+        ledger.record(a, LedgerEvent::Traffic { amount: 100.into() }, 100.into());
+        ledger.record(
+            b,
+            LedgerEvent::Traffic {
+                amount: (-50).into(),
+            },
+            (-50).into(),
+        );
+        ledger.flush(&path).unwrap();
+        assert!(ledger.pending().is_empty());
+
+        ledger.record(
+            a,
+            LedgerEvent::PaymentSent {
+                amount: 100u32.into(),
+            },
+            0.into(),
+        );
+
+        let all = read_ledger(&path, ledger.pending(), &LedgerQuery::default());
+        assert_eq!(all.len(), 3);
+
+        let query = LedgerQuery {
+            identity: Some(a),
+            start: None,
+            end: None,
+        };
+        let only_a = read_ledger(&path, ledger.pending(), &query);
+        assert_eq!(only_a.len(), 2);
+        assert_eq!(only_a[1].debt, 0.into());
+
+        let query = LedgerQuery {
+            identity: None,
+            start: Some(get_timestamp() + 1000),
+            end: None,
+        };
+        assert!(read_ledger(&path, ledger.pending(), &query).is_empty());
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_ledger_rotation() {
+        let path = get_test_ledger_path("test_ledger_rotation.ledger");
+        let a = get_test_identity(1);
+
+        // write enough entries to force several rotations
+        let mut ledger = DebtLedger::default();
+        ledger.record(a, LedgerEvent::Traffic { amount: 1.into() }, 1.into());
+        let entry_size = bincode::serialize(&ledger.pending()[0]).unwrap().len() as u64;
+        let flushes = MAX_LEDGER_FILE_SIZE * (LEDGER_ROTATIONS as u64 + 2) / (entry_size * 1000);
+        for _ in 0..=flushes {
+            for _ in 0..1000 {
+                ledger.record(a, LedgerEvent::Traffic { amount: 1.into() }, 1.into());
+            }
+            ledger.flush(&path).unwrap();
+        }
+
+        assert!(Path::new(&get_rotated_file_path(&path, LEDGER_ROTATIONS)).exists());
+        assert!(!Path::new(&get_rotated_file_path(&path, LEDGER_ROTATIONS + 1)).exists());
+        for i in 1..=LEDGER_ROTATIONS {
+            let size = fs::metadata(get_rotated_file_path(&path, i)).unwrap().len();
+            assert!(size >= MAX_LEDGER_FILE_SIZE);
+            let _ = fs::remove_file(get_rotated_file_path(&path, i));
+        }
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -8,9 +8,19 @@
 //! increase the amount we owe Bob? That's probably a vulnerability rabbit hole at the very least.
 //! Hence we need an incoming payments parameter to take money out of. This of course implies half
 //! of the excess complexity you see, managing an incoming payments pool versus a incoming debts pool
+use self::ledger::get_ledger_file_path;
+use self::ledger::read_ledger;
+use self::ledger::DebtLedger;
+use self::ledger::LedgerEntry;
+use self::ledger::LedgerEvent;
+use self::ledger::LedgerQuery;
 use crate::blockchain_oracle::calculate_close_thresh;
 use crate::blockchain_oracle::get_pay_thresh;
 use crate::blockchain_oracle::potential_payment_issues_detected;
+use crate::debt_dispute::get_debt_comparison;
+use crate::debt_dispute::DebtComparison;
+use crate::payment_channel::apply_settlement;
+use crate::payment_channel::has_open_channel;
 use crate::payment_controller::queue_payment;
 use crate::payment_validator::PAYMENT_SEND_TIMEOUT;
 use crate::simulated_txfee_manager::add_tx_to_total;
@@ -30,6 +40,9 @@ use num_traits::identities::Zero;
 use num_traits::CheckedMul;
 use num_traits::Signed;
 use settings::get_rita_common;
+use settings::payment::EnforcementPolicy;
+use settings::payment::SettlementDenomPolicy;
+use settings::payment::ThrottleStep;
 use settings::DEBT_KEEPER_DENOM;
 use settings::DEBT_KEEPER_DENOM_DECIMAL;
 
@@ -43,6 +56,8 @@ use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use std::time::Instant;
 
+pub mod ledger;
+
 lazy_static! {
     /// A locked global ref containing the state for this module. Note that the default implementation
     /// loads saved data from teh disk if it exists.
@@ -97,6 +112,24 @@ pub struct NodeDebtData {
     /// case, where when we get payments from the exit there is a race condition where the
     /// exit may not update that we have paid it fast enough
     pub last_successful_payment: Option<Instant>,
+    #[serde(skip_serializing, skip_deserializing)]
+    /// When this node's debt first went past the close threshold, used to give them a grace
+    /// period before the enforcement policy applies
+    pub overdue_since: Option<Instant>,
+    /// The amount this node has paid us in each denom, not normalized, keyed by the denom string.
+    /// The totals above are normalized to DEBT_KEEPER_DENOM, this lets operators accepting more than
+    /// one denom reconcile what was actually received in each asset
+    #[serde(default)]
+    pub payments_received_by_denom: HashMap<String, Uint256>,
+    /// The amount we have sent this node in each denom, not normalized, keyed by the denom string
+    #[serde(default)]
+    pub payments_sent_by_denom: HashMap<String, Uint256>,
+    /// The debt broken down by the denom it is held in, in that denom's own units. Payments move
+    /// the debt held in the denom they were made in while usage, payment channels and forgiveness
+    /// move the debt held in DEBT_KEEPER_DENOM, so normalized these add up to debt plus
+    /// incoming_payments, less rounding
+    #[serde(default)]
+    pub debt_by_denom: HashMap<String, Int256>,
 }
 
 impl Default for NodeDebtData {
@@ -110,6 +143,10 @@ impl Default for NodeDebtData {
             payment_in_flight: false,
             payment_in_flight_start: None,
             last_successful_payment: None,
+            overdue_since: None,
+            payments_received_by_denom: HashMap::new(),
+            payments_sent_by_denom: HashMap::new(),
+            debt_by_denom: HashMap::new(),
         }
     }
 }
@@ -124,7 +161,78 @@ impl NodeDebtData {
             payment_in_flight: false,
             payment_in_flight_start: None,
             last_successful_payment: None,
+            overdue_since: None,
+            payments_received_by_denom: HashMap::new(),
+            payments_sent_by_denom: HashMap::new(),
+            debt_by_denom: HashMap::new(),
+        }
+    }
+
+    /// Moves the debt held in the given denom, positive amounts are owed by us
+    fn add_denom_debt(&mut self, denom: &str, amount: Int256) {
+        if amount == Int256::zero() {
+            return;
+        }
+        let debt = self
+            .debt_by_denom
+            .entry(denom.to_string())
+            .or_insert_with(Int256::zero);
+        *debt += amount;
+    }
+}
+
+/// Converts a payment amount so that it can be applied to a debt
+fn to_signed(amount: Uint256) -> Result<Int256, RitaCommonError> {
+    amount.to_int256().ok_or_else(|| {
+        RitaCommonError::ConversionError("Failed to convert payment amount to Int256!".to_string())
+    })
+}
+
+/// Adds an amount to the running total for a denom in one of the by denom maps
+fn add_to_denom_total(totals: &mut HashMap<String, Uint256>, denom: &Denom, amount: Uint256) {
+    let total = totals
+        .entry(denom.denom.clone())
+        .or_insert_with(Uint256::zero);
+    *total += amount;
+}
+
+/// Removes an amount from the running total for a denom, used when a payment is reversed
+fn sub_from_denom_total(totals: &mut HashMap<String, Uint256>, denom: &Denom, amount: Uint256) {
+    if let Some(total) = totals.get_mut(&denom.denom) {
+        if *total > amount {
+            *total -= amount;
+        } else {
+            *total = Uint256::zero();
+        }
+    }
+}
+
+/// NodeDebtData as it was saved before debts were tracked per denom, only used to
+/// load old debts files
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct NodeDebtDataOld {
+    total_payment_received: Uint256,
+    total_payment_sent: Uint256,
+    debt: Int256,
+    incoming_payments: Uint256,
+    action: DebtAction,
+}
+
+impl From<NodeDebtDataOld> for NodeDebtData {
+    fn from(input: NodeDebtDataOld) -> Self {
+        let mut data = NodeDebtData {
+            total_payment_received: input.total_payment_received,
+            total_payment_sent: input.total_payment_sent,
+            debt: input.debt,
+            incoming_payments: input.incoming_payments,
+            action: input.action,
+            ..NodeDebtData::new()
+        };
+        // everything owed before per denom tracking is held in the debt keeper denom
+        if let Some(incoming) = input.incoming_payments.to_int256() {
+            data.add_denom_debt(DEBT_KEEPER_DENOM, input.debt + incoming);
         }
+        data
     }
 }
 
@@ -132,6 +240,7 @@ pub type DebtData = HashMap<Identity, NodeDebtData>;
 /// a datatype used only for the serializing of DebtData since
 /// serde does not support structs as keys in maps
 type DebtDataSer = Vec<(Identity, NodeDebtData)>;
+type DebtDataSerOld = Vec<(Identity, NodeDebtDataOld)>;
 
 fn debt_data_to_ser(input: DebtData) -> DebtDataSer {
     let mut ret = DebtDataSer::new();
@@ -158,6 +267,8 @@ fn ser_to_debt_data(input: DebtDataSer) -> DebtData {
             if d.debt <= Int256::zero() && d.incoming_payments == Uint256::zero() {
                 continue;
             } else if d.debt <= Int256::zero() {
+                let forgiven = -d.debt;
+                d.add_denom_debt(DEBT_KEEPER_DENOM, forgiven);
                 d.debt = Int256::from(0);
             }
         }
@@ -167,15 +278,15 @@ fn ser_to_debt_data(input: DebtDataSer) -> DebtData {
 }
 
 /// used to prevent debts from growing higher than the enforcement limit in either direction
-/// if the debt is more negative or more positive than the ABS of close_threshold we set it to
-/// one more than that value
-fn debt_limit(debt: Int256, close_threshold: Int256) -> Int256 {
-    if debt < close_threshold {
+/// if the debt is more negative than enforcement_limit or more positive than the ABS of
+/// close_threshold we set it to one more than that value
+fn debt_limit(debt: Int256, close_threshold: Int256, enforcement_limit: Int256) -> Int256 {
+    if debt < enforcement_limit {
         info!(
             "Forgiving {} wei to enforce debt limit",
-            debt - close_threshold
+            debt - enforcement_limit
         );
-        close_threshold - 1u8.into()
+        enforcement_limit - 1u8.into()
     } else if debt > close_threshold.abs() {
         info!(
             "Not paying {} wei to enforce debt limit",
@@ -192,6 +303,8 @@ pub struct DebtKeeper {
     #[serde(skip_serializing, skip_deserializing)]
     last_save: Option<Instant>,
     debt_data: DebtData,
+    #[serde(skip_serializing, skip_deserializing)]
+    ledger: DebtLedger,
 }
 
 #[allow(dead_code)]
@@ -207,6 +320,9 @@ pub fn payment_received(
 ) -> Result<(), RitaCommonError> {
     let dk_pin = &mut *DEBT_DATA.write().unwrap();
     let dk = get_debt_keeper_write_ref(dk_pin);
+    let peer = dk.get_debt_data_mut(&from);
+    add_to_denom_total(&mut peer.payments_received_by_denom, &denom, amount);
+    peer.add_denom_debt(&denom.denom, to_signed(amount)?);
 
     // Debt keeper currently bookeeps in dai, we convert whatever amount we recive to the debt keeper using
     let amount = normalize_payment_amount(
@@ -217,6 +333,21 @@ pub fn payment_received(
             decimal: DEBT_KEEPER_DENOM_DECIMAL,
         },
     );
+    // payments that settle a payment channel balance have already been credited, in the debt
+    // keeper denom, so that part of the debt now rests on this payment's denom instead
+    let credit = apply_settlement(&from, amount, false);
+    dk.get_debt_data_mut(&from)
+        .add_denom_debt(DEBT_KEEPER_DENOM, -to_signed(amount - credit)?);
+    dk.payment_received(&from, credit)
+}
+
+/// Credits a payment made to us over a payment channel, these are not on chain yet so they
+/// are not counted in the per denom totals. Amount is in the debt keeper denom
+pub fn channel_payment_received(from: Identity, amount: Uint256) -> Result<(), RitaCommonError> {
+    let dk_pin = &mut *DEBT_DATA.write().unwrap();
+    let dk = get_debt_keeper_write_ref(dk_pin);
+    dk.get_debt_data_mut(&from)
+        .add_denom_debt(DEBT_KEEPER_DENOM, to_signed(amount)?);
     dk.payment_received(&from, amount)
 }
 
@@ -237,6 +368,64 @@ pub fn normalize_payment_amount(amount: Uint256, from_denom: Denom, to_denom: De
     amount
 }
 
+/// Returns the accepted denom we should use to pay the given node, according to the configured
+/// settlement_denom_policy
+pub fn get_settlement_denom(to: &Identity) -> Option<Denom> {
+    let payment = settings::get_rita_common().payment;
+    let received_by_denom = get_debt_keeper()
+        .debt_data
+        .get(to)
+        .map(|d| d.payments_received_by_denom.clone())
+        .unwrap_or_default();
+    select_settlement_denom(
+        &payment.settlement_denom_policy,
+        &payment.accepted_denoms.unwrap_or_default(),
+        &received_by_denom,
+    )
+}
+
+fn select_settlement_denom(
+    policy: &SettlementDenomPolicy,
+    accepted_denoms: &HashMap<String, Denom>,
+    received_by_denom: &HashMap<String, Uint256>,
+) -> Option<Denom> {
+    match policy {
+        SettlementDenomPolicy::Fixed(key) => accepted_denoms.get(key).cloned(),
+        SettlementDenomPolicy::MatchNeighbor(default) => {
+            let debt_keeper_denom = Denom {
+                denom: DEBT_KEEPER_DENOM.to_string(),
+                decimal: DEBT_KEEPER_DENOM_DECIMAL,
+            };
+            // compare the amounts received in each denom by their value, not their raw amount
+            let mut best: Option<(Denom, Uint256)> = None;
+            for denom in accepted_denoms.values() {
+                if let Some(amount) = received_by_denom.get(&denom.denom) {
+                    let value =
+                        normalize_payment_amount(*amount, denom.clone(), debt_keeper_denom.clone());
+                    match best {
+                        Some((_, best_value)) if best_value >= value => {}
+                        _ => best = Some((denom.clone(), value)),
+                    }
+                }
+            }
+            match best {
+                Some((denom, _)) => Some(denom),
+                None => accepted_denoms.get(default).cloned(),
+            }
+        }
+    }
+}
+
+/// Marks a payment to the given node as in flight, used when replaying the payment journal
+/// on startup so that we don't pay the same debt twice
+pub fn set_payment_in_flight(to: Identity) {
+    let dk_pin = &mut *DEBT_DATA.write().unwrap();
+    let dk = get_debt_keeper_write_ref(dk_pin);
+    let peer = dk.get_debt_data_mut(&to);
+    peer.payment_in_flight = true;
+    peer.payment_in_flight_start = Some(Instant::now());
+}
+
 pub fn payment_failed(to: Identity) {
     let dk_pin = &mut *DEBT_DATA.write().unwrap();
     let dk = get_debt_keeper_write_ref(dk_pin);
@@ -250,6 +439,9 @@ pub fn payment_succeeded(
 ) -> Result<(), RitaCommonError> {
     let dk_pin = &mut *DEBT_DATA.write().unwrap();
     let dk = get_debt_keeper_write_ref(dk_pin);
+    let peer = dk.get_debt_data_mut(&to);
+    add_to_denom_total(&mut peer.payments_sent_by_denom, &denom, amount);
+    peer.add_denom_debt(&denom.denom, -to_signed(amount)?);
     // Debt keeper currently bookeeps in dai, we convert whatever amount we recive to the debt keeper using
     let amount = normalize_payment_amount(
         amount,
@@ -260,9 +452,74 @@ pub fn payment_succeeded(
         },
     );
     add_tx_to_total(amount);
+    // payments that settle a payment channel balance have already been credited, a pure settlement
+    // was not made by debt keeper so it must not clear the payment in flight
+    let credit = apply_settlement(&to, amount, true);
+    dk.get_debt_data_mut(&to)
+        .add_denom_debt(DEBT_KEEPER_DENOM, to_signed(amount - credit)?);
+    if credit == Uint256::zero() && amount > Uint256::zero() {
+        return Ok(());
+    }
+    dk.payment_succeeded(&to, credit)
+}
+
+/// Credits a payment we made over a payment channel, amount is in the debt keeper denom
+pub fn channel_payment_succeeded(to: Identity, amount: Uint256) -> Result<(), RitaCommonError> {
+    let dk_pin = &mut *DEBT_DATA.write().unwrap();
+    let dk = get_debt_keeper_write_ref(dk_pin);
+    dk.get_debt_data_mut(&to)
+        .add_denom_debt(DEBT_KEEPER_DENOM, -to_signed(amount)?);
     dk.payment_succeeded(&to, amount)
 }
 
+/// Reverses a payment from this node that was previously passed to payment_received, used when
+/// the transaction is removed from the chain by a reorg after being validated. The full amount is
+/// owed again, so a payment that settled a payment channel is put back onto the debt rather than
+/// reopening the channel balance
+pub fn payment_received_reversed(
+    from: Identity,
+    amount: Uint256,
+    denom: Denom,
+) -> Result<(), RitaCommonError> {
+    let dk_pin = &mut *DEBT_DATA.write().unwrap();
+    let dk = get_debt_keeper_write_ref(dk_pin);
+    let peer = dk.get_debt_data_mut(&from);
+    sub_from_denom_total(&mut peer.payments_received_by_denom, &denom, amount);
+    peer.add_denom_debt(&denom.denom, -to_signed(amount)?);
+    let amount = normalize_payment_amount(
+        amount,
+        denom,
+        Denom {
+            denom: DEBT_KEEPER_DENOM.to_string(),
+            decimal: DEBT_KEEPER_DENOM_DECIMAL,
+        },
+    );
+    dk.payment_received_reversed(&from, amount)
+}
+
+/// Reverses a payment we made to this node that was previously passed to payment_succeeded, used
+/// when the transaction is removed from the chain by a reorg after being validated
+pub fn payment_succeeded_reversed(
+    to: Identity,
+    amount: Uint256,
+    denom: Denom,
+) -> Result<(), RitaCommonError> {
+    let dk_pin = &mut *DEBT_DATA.write().unwrap();
+    let dk = get_debt_keeper_write_ref(dk_pin);
+    let peer = dk.get_debt_data_mut(&to);
+    sub_from_denom_total(&mut peer.payments_sent_by_denom, &denom, amount);
+    peer.add_denom_debt(&denom.denom, to_signed(amount)?);
+    let amount = normalize_payment_amount(
+        amount,
+        denom,
+        Denom {
+            denom: DEBT_KEEPER_DENOM.to_string(),
+            decimal: DEBT_KEEPER_DENOM_DECIMAL,
+        },
+    );
+    dk.payment_succeeded_reversed(&to, amount)
+}
+
 pub struct Traffic {
     pub from: Identity,
     pub amount: Int256,
@@ -295,6 +552,47 @@ pub fn wgkey_insensitive_traffic_update(traffic: Traffic) {
     error!("Wg key insensitive billing has not found a target! Gateway billing incorrect!");
 }
 
+/// Resets the debt of the given node to zero, unlike traffic_replace this is recorded in the
+/// ledger as a manual reset rather than a traffic change
+pub fn reset_debt(ident: Identity) {
+    let dk_pin = &mut *DEBT_DATA.write().unwrap();
+    let dk = get_debt_keeper_write_ref(dk_pin);
+    dk.reset_debt(&ident)
+}
+
+/// Applies an adjustment to the debt of the given node after comparing it with their view of the
+/// debt, see debt_dispute
+pub fn reconcile_debt(ident: Identity, amount: Int256) {
+    let dk_pin = &mut *DEBT_DATA.write().unwrap();
+    let dk = get_debt_keeper_write_ref(dk_pin);
+    dk.reconcile_debt(&ident, amount)
+}
+
+/// Returns a copy of the debt data for a single node, if we have any
+pub fn get_node_debt_data(ident: &Identity) -> Option<NodeDebtData> {
+    let dk_pin = &*DEBT_DATA.read().unwrap();
+    let netns = KI.check_integration_test_netns();
+    dk_pin
+        .get(&netns)
+        .and_then(|dk| dk.debt_data.get(ident).cloned())
+}
+
+/// Returns the ledger history matching the provided query, in chronological order. Only the
+/// unsaved entries are copied under the debt keeper lock, the ledger files are read after it is
+/// released but this still blocks on disk io
+pub fn get_ledger(query: LedgerQuery) -> Vec<LedgerEntry> {
+    let pending = {
+        let dk_pin = &*DEBT_DATA.read().unwrap();
+        let netns = KI.check_integration_test_netns();
+        match dk_pin.get(&netns) {
+            Some(dk) => dk.ledger.pending().clone(),
+            None => return Vec::new(),
+        }
+    };
+    let file_path = get_ledger_file_path(&settings::get_rita_common().payment.debts_file);
+    read_ledger(&file_path, &pending, &query)
+}
+
 /// A variant of traffic update that replaces one debts entry wholesale
 /// only used by the client to update it's own debt to the exit
 pub fn traffic_replace(traffic: Traffic) {
@@ -309,7 +607,84 @@ pub fn traffic_replace(traffic: Traffic) {
 pub enum DebtAction {
     SuspendTunnel,
     OpenTunnel,
-    MakePayment { to: Box<Identity>, amount: Uint256 },
+    MakePayment {
+        to: Box<Identity>,
+        amount: Uint256,
+    },
+    /// Limit the tunnel to this throughput in kbit/s
+    ThrottleTunnel {
+        throughput: u32,
+    },
+    /// Limit the tunnel to the free tier and redirect the client to a captive portal
+    CaptivePortal,
+}
+
+/// The action the enforcement policy calls for against a node with the given debt, which must be
+/// past the close threshold
+fn enforcement_action(
+    policy: &EnforcementPolicy,
+    debt: Int256,
+    close_threshold: Int256,
+) -> DebtAction {
+    match policy {
+        EnforcementPolicy::WarnOnly => DebtAction::OpenTunnel,
+        EnforcementPolicy::FreeTier => DebtAction::SuspendTunnel,
+        EnforcementPolicy::CaptivePortal(_) => DebtAction::CaptivePortal,
+        EnforcementPolicy::Progressive(steps) => {
+            let debt = debt.abs();
+            let close_threshold = close_threshold.abs();
+            let mut reached = None;
+            let mut smallest = None;
+            for step in steps {
+                let step_debt = close_threshold
+                    .checked_mul(&Int256::from(u64::from(step.close_threshold_multiple)));
+                if let Some(step_debt) = step_debt {
+                    if debt >= step_debt
+                        && reached.is_none_or(|r: &ThrottleStep| {
+                            r.close_threshold_multiple < step.close_threshold_multiple
+                        })
+                    {
+                        reached = Some(step);
+                    }
+                }
+                if smallest.is_none_or(|s: &ThrottleStep| {
+                    s.close_threshold_multiple > step.close_threshold_multiple
+                }) {
+                    smallest = Some(step);
+                }
+            }
+            match reached.or(smallest) {
+                Some(step) => DebtAction::ThrottleTunnel {
+                    throughput: step.throughput,
+                },
+                None => DebtAction::SuspendTunnel,
+            }
+        }
+    }
+}
+
+/// How negative a debt may get before debt_limit forgives the rest. This is the close threshold
+/// unless the policy throttles in steps, then the debt has to be able to reach the largest step
+fn enforcement_limit(policy: &EnforcementPolicy, close_threshold: Int256) -> Int256 {
+    match policy {
+        EnforcementPolicy::Progressive(steps) => {
+            let multiple = steps
+                .iter()
+                .map(|step| step.close_threshold_multiple)
+                .max()
+                .unwrap_or(1)
+                .max(1);
+            close_threshold
+                .checked_mul(&Int256::from(u64::from(multiple)))
+                .unwrap_or(close_threshold)
+        }
+        _ => close_threshold,
+    }
+}
+
+/// True if a node that went past the close threshold at overdue_since is still within its grace period
+fn in_grace_period(overdue_since: Instant, grace_period: Duration, now: Instant) -> bool {
+    now.saturating_duration_since(overdue_since) < grace_period
 }
 
 pub fn send_debt_update() -> Result<(), RitaCommonError> {
@@ -322,12 +697,20 @@ pub fn send_debt_update() -> Result<(), RitaCommonError> {
 
     for (k, _) in dk.debt_data.clone() {
         match dk.send_update(&k)? {
-            DebtAction::SuspendTunnel => {
+            // tunnels carry encrypted exit traffic so there is no captive portal to
+            // redirect to, only exits act on it, we just apply the free tier
+            DebtAction::SuspendTunnel | DebtAction::CaptivePortal => {
                 debts_message.push(TunnelChange {
                     identity: k,
                     action: TunnelAction::PaymentOverdue,
                 });
             }
+            DebtAction::ThrottleTunnel { throughput } => {
+                debts_message.push(TunnelChange {
+                    identity: k,
+                    action: TunnelAction::PaymentThrottled { throughput },
+                });
+            }
             DebtAction::OpenTunnel => {
                 debts_message.push(TunnelChange {
                     identity: k,
@@ -375,10 +758,15 @@ fn deserialize_from_binary(file_path: String) -> Option<DebtDataSer> {
         Ok(file) => {
             let deserialized_binary: DebtDataSer = match bincode::deserialize(&file) {
                 Ok(value) => value,
-                Err(val) => {
-                    error!("Failed to deserialize debts file via bincode {:?}", val);
-                    Vec::new()
-                }
+                // debts files saved before per denom tracking was added, serde defaults
+                // don't apply to bincode so the missing fields can't just be filled in
+                Err(val) => match bincode::deserialize::<DebtDataSerOld>(&file) {
+                    Ok(value) => value.into_iter().map(|(i, d)| (i, d.into())).collect(),
+                    Err(_) => {
+                        error!("Failed to deserialize debts file via bincode {:?}", val);
+                        Vec::new()
+                    }
+                },
             };
             Some(deserialized_binary)
         }
@@ -427,6 +815,7 @@ impl Default for DebtKeeper {
         let blank_debt_keeper = DebtKeeper {
             last_save: None,
             debt_data: HashMap::new(),
+            ledger: DebtLedger::default(),
         };
 
         let deserialized_binary =
@@ -441,16 +830,19 @@ impl Default for DebtKeeper {
             (None, Some(val)) => DebtKeeper {
                 last_save: None,
                 debt_data: ser_to_debt_data(val),
+                ledger: DebtLedger::default(),
             },
             (Some(val), None) => DebtKeeper {
                 last_save: None,
                 debt_data: ser_to_debt_data(val),
+                ledger: DebtLedger::default(),
             },
             (Some(val), Some(_)) => {
                 log::info!("File is both binary and json");
                 DebtKeeper {
                     last_save: None,
                     debt_data: ser_to_debt_data(val),
+                    ledger: DebtLedger::default(),
                 }
             }
         }
@@ -466,6 +858,7 @@ impl DebtKeeper {
         DebtKeeper {
             last_save: None,
             debt_data: DebtData::new(),
+            ledger: DebtLedger::default(),
         }
     }
 
@@ -507,6 +900,15 @@ impl DebtKeeper {
         file.write_all(&serialized)
     }
 
+    /// Writes out any pending ledger entries, unlike the debts themselves the ledger is append only
+    /// so this is cheap enough to do every time we consider saving
+    fn save_ledger(&mut self) {
+        let file_path = get_ledger_file_path(&settings::get_rita_common().payment.debts_file);
+        if let Err(e) = self.ledger.flush(&file_path) {
+            error!("Failed to save debt ledger {:?}", e);
+        }
+    }
+
     fn get_debts(&self) -> DebtData {
         self.debt_data.clone()
     }
@@ -538,6 +940,9 @@ impl DebtKeeper {
                 ))
             }
         };
+        let debt = peer.debt;
+        self.ledger
+            .record(*to, LedgerEvent::PaymentSent { amount }, debt);
         Ok(())
     }
 
@@ -550,6 +955,7 @@ impl DebtKeeper {
         let unsigned_zero = Uint256::zero();
 
         let debt_data = self.get_debt_data_mut(ident);
+        let old_debt = debt_data.debt;
         info!(
             "payment received: old incoming payments for {:?}: {:?}",
             ident.mesh_ip, debt_data.incoming_payments
@@ -598,6 +1004,77 @@ impl DebtKeeper {
             "new incoming payments for {:?}: {:?}",
             ident.mesh_ip, debt_data.incoming_payments
         );
+
+        // zero payments are used to apply existing credit, only worth a ledger entry if they did something
+        let debt = debt_data.debt;
+        if amount > unsigned_zero || debt != old_debt {
+            self.ledger
+                .record(*ident, LedgerEvent::PaymentReceived { amount }, debt);
+        }
+        Ok(())
+    }
+
+    /// Takes a reversed payment back out of the incoming payments pool first, since that is
+    /// where any excess went, and applies the rest to the debt
+    fn payment_received_reversed(
+        &mut self,
+        ident: &Identity,
+        amount: Uint256,
+    ) -> Result<(), RitaCommonError> {
+        let debt_data = self.get_debt_data_mut(ident);
+        if debt_data.total_payment_received > amount {
+            debt_data.total_payment_received -= amount;
+        } else {
+            debt_data.total_payment_received = Uint256::zero();
+        }
+
+        let from_incoming = if debt_data.incoming_payments > amount {
+            amount
+        } else {
+            debt_data.incoming_payments
+        };
+        debt_data.incoming_payments -= from_incoming;
+        debt_data.debt -= match (amount - from_incoming).to_int256() {
+            Some(val) => val,
+            None => {
+                return Err(RitaCommonError::ConversionError(
+                    "Failed to convert reversed amount to Int256!".to_string(),
+                ))
+            }
+        };
+
+        let debt = debt_data.debt;
+        self.ledger.record(
+            *ident,
+            LedgerEvent::PaymentReceivedReversed { amount },
+            debt,
+        );
+        Ok(())
+    }
+
+    fn payment_succeeded_reversed(
+        &mut self,
+        to: &Identity,
+        amount: Uint256,
+    ) -> Result<(), RitaCommonError> {
+        let peer = self.get_debt_data_mut(to);
+        if peer.total_payment_sent > amount {
+            peer.total_payment_sent -= amount;
+        } else {
+            peer.total_payment_sent = Uint256::zero();
+        }
+        peer.debt += match amount.to_int256() {
+            Some(val) => val,
+            None => {
+                return Err(RitaCommonError::ConversionError(
+                    "Failed to convert reversed amount to Int256!".to_string(),
+                ))
+            }
+        };
+
+        let debt = peer.debt;
+        self.ledger
+            .record(*to, LedgerEvent::PaymentSentReversed { amount }, debt);
         Ok(())
     }
 
@@ -608,13 +1085,57 @@ impl DebtKeeper {
         // we handle the incoming debit or credit versus our existing debit or credit
         // very simple
         debt_data.debt += amount;
+        debt_data.add_denom_debt(DEBT_KEEPER_DENOM, amount);
 
         trace!("debt data for {} is {:?}", ident.mesh_ip, debt_data);
+        let debt = debt_data.debt;
+        if amount != Int256::zero() {
+            self.ledger
+                .record(*ident, LedgerEvent::Traffic { amount }, debt);
+        }
     }
 
     fn traffic_replace(&mut self, ident: &Identity, amount: Int256) {
         trace!("traffic replace for {} is {}", ident.mesh_ip, amount);
+        if let Some(previous_debt) = self.replace_debt(ident, amount) {
+            if previous_debt != amount {
+                self.ledger.record(
+                    *ident,
+                    LedgerEvent::Traffic {
+                        amount: amount - previous_debt,
+                    },
+                    amount,
+                );
+            }
+        }
+    }
+
+    fn reconcile_debt(&mut self, ident: &Identity, amount: Int256) {
+        let debt_data = self.get_debt_data_mut(ident);
+        debt_data.debt += amount;
+        debt_data.add_denom_debt(DEBT_KEEPER_DENOM, amount);
+        let debt = debt_data.debt;
+        self.ledger
+            .record(*ident, LedgerEvent::Reconciled { amount }, debt);
+    }
+
+    fn reset_debt(&mut self, ident: &Identity) {
+        info!("Resetting debt for {}", ident.mesh_ip);
+        if let Some(previous_debt) = self.replace_debt(ident, Int256::zero()) {
+            self.ledger.record(
+                *ident,
+                LedgerEvent::DebtReset { previous_debt },
+                Int256::zero(),
+            );
+        }
+    }
+
+    /// Replaces the debt for a node with the given amount, returns the previous debt if the
+    /// replacement was made
+    fn replace_debt(&mut self, ident: &Identity, amount: Int256) -> Option<Int256> {
         let debt_data = self.get_debt_data_mut(ident);
+        let previous_debt = debt_data.debt;
+        let mut replaced = false;
 
         // if we have a payment in flight we shouldn't reset the debt as
         // we may end up double paying we also should wait 60 seconds after
@@ -628,18 +1149,30 @@ impl DebtKeeper {
             (false, Some(val)) => {
                 if Instant::now() - val > Duration::from_secs(15) {
                     debt_data.debt = amount;
+                    replaced = true;
                 }
             }
-            (false, None) => debt_data.debt = amount,
+            (false, None) => {
+                debt_data.debt = amount;
+                replaced = true;
+            }
         }
 
         trace!("debt data for {} is {:?}", ident.mesh_ip, debt_data);
+        if replaced {
+            debt_data.add_denom_debt(DEBT_KEEPER_DENOM, amount - previous_debt);
+            Some(previous_debt)
+        } else {
+            None
+        }
     }
 
     /// This updates a neighbor's debt and outputs a DebtAction if one is necessary.
     fn send_update(&mut self, ident: &Identity) -> Result<DebtAction, RitaCommonError> {
         trace!("debt data: {:?}", self.debt_data);
-        let debt_data = self.get_debt_data_mut(ident);
+        // borrow the field directly rather than using get_debt_data_mut so that the ledger
+        // can be updated while we hold this reference
+        let debt_data = self.debt_data.entry(*ident).or_default();
         // the debt we started this round with
 
         if debt_data.debt != Int256::zero() {
@@ -653,7 +1186,13 @@ impl DebtKeeper {
 
         let payment_settings = settings::get_rita_common().payment;
         let close_threshold = calculate_close_thresh();
-        let pay_threshold = get_pay_thresh();
+        // channel payments cost nothing so we pay neighbors we have a channel with more often
+        let pay_threshold = if payment_settings.payment_channels_enabled && has_open_channel(ident)
+        {
+            payment_settings.channel_payment_threshold
+        } else {
+            get_pay_thresh()
+        };
         let debt_limit_enabled = payment_settings.debt_limit_enabled;
         let apply_incoming_credit_immediately = payment_settings.apply_incoming_credit_immediately;
         let enable_enforcement = payment_settings.enable_enforcement;
@@ -668,9 +1207,29 @@ impl DebtKeeper {
         let should_close = debt_data.debt < close_threshold;
         let should_pay = debt_data.debt > pay_threshold;
         let payment_in_flight = debt_data.payment_in_flight;
+        if !should_close {
+            debt_data.overdue_since = None;
+        }
 
+        // the throttle step is picked from the debt before it is limited
+        let unlimited_debt = debt_data.debt;
         if debt_limit_enabled {
-            debt_data.debt = debt_limit(debt_data.debt, close_threshold);
+            let limited_debt = debt_limit(
+                debt_data.debt,
+                close_threshold,
+                enforcement_limit(&payment_settings.enforcement_policy, close_threshold),
+            );
+            if limited_debt != debt_data.debt {
+                self.ledger.record(
+                    *ident,
+                    LedgerEvent::DebtLimitForgiven {
+                        amount: debt_data.debt - limited_debt,
+                    },
+                    limited_debt,
+                );
+                debt_data.add_denom_debt(DEBT_KEEPER_DENOM, limited_debt - debt_data.debt);
+            }
+            debt_data.debt = limited_debt;
         }
 
         match (should_close, should_pay, payment_in_flight) {
@@ -686,12 +1245,41 @@ impl DebtKeeper {
                 }
 
                 if enable_enforcement {
-                    info!(
-                        "debt {} is below close threshold {} for {}. suspending forwarding",
-                        debt_data.debt, close_threshold, ident.wg_public_key
+                    let now = Instant::now();
+                    let overdue_since = *debt_data.overdue_since.get_or_insert(now);
+                    let grace_period = payment_settings
+                        .enforcement_grace_periods
+                        .get(&ident.wg_public_key)
+                        .copied()
+                        .unwrap_or(payment_settings.enforcement_grace_period);
+                    if in_grace_period(overdue_since, Duration::from_secs(grace_period), now) {
+                        trace!(
+                            "debt {} is below close threshold {} for {}, within grace period",
+                            debt_data.debt,
+                            close_threshold,
+                            ident.wg_public_key
+                        );
+                        debt_data.action = DebtAction::OpenTunnel;
+                        return Ok(DebtAction::OpenTunnel);
+                    }
+
+                    let action = enforcement_action(
+                        &payment_settings.enforcement_policy,
+                        unlimited_debt,
+                        close_threshold,
                     );
-                    debt_data.action = DebtAction::SuspendTunnel;
-                    Ok(DebtAction::SuspendTunnel)
+                    match action {
+                        DebtAction::OpenTunnel => warn!(
+                            "debt {} is below close threshold {} for {}. not enforcing, policy is warn only",
+                            debt_data.debt, close_threshold, ident.wg_public_key
+                        ),
+                        _ => info!(
+                            "debt {} is below close threshold {} for {}. enforcing with {:?}",
+                            debt_data.debt, close_threshold, ident.wg_public_key, action
+                        ),
+                    }
+                    debt_data.action = action.clone();
+                    Ok(action)
                 } else {
                     debt_data.action = DebtAction::OpenTunnel;
                     Ok(DebtAction::OpenTunnel)
@@ -769,6 +1357,7 @@ pub fn save_debt_to_disk(save_frequency: Duration) {
     let dk = get_debt_keeper_write_ref(dk_pin);
     trace!("sending debt keeper update");
     dk.save_if_needed(save_frequency);
+    dk.save_ledger();
 }
 
 /// On an interupt (SIGTERM), saving debtkeeper before exiting, this will only
@@ -783,12 +1372,16 @@ pub fn save_debt_on_shutdown() {
     } else {
         info!("Shutdown: Saving debt data");
     }
+    dk.save_ledger();
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GetDebtsResult {
     pub identity: Identity,
     pub payment_details: NodeDebtData,
+    /// The last comparison of our view of this debt with the neighbor's own view
+    #[serde(default)]
+    pub comparison: Option<DebtComparison>,
 }
 
 impl GetDebtsResult {
@@ -796,6 +1389,7 @@ impl GetDebtsResult {
         GetDebtsResult {
             identity: *identity,
             payment_details: payment_details.clone(),
+            comparison: get_debt_comparison(identity),
         }
     }
 }
@@ -872,6 +1466,146 @@ mod tests {
         assert_eq!(d.send_update(&ident).unwrap(), DebtAction::SuspendTunnel);
     }
 
+    #[test]
+    fn test_enforcement_action() {
+        let close = Int256::from(-100i64);
+        let steps = vec![
+            ThrottleStep {
+                close_threshold_multiple: 4,
+                throughput: 100,
+            },
+            ThrottleStep {
+                close_threshold_multiple: 2,
+                throughput: 500,
+            },
+        ];
+        let progressive = EnforcementPolicy::Progressive(steps);
+        let throttle = |throughput| DebtAction::ThrottleTunnel { throughput };
+
+        assert_eq!(
+            enforcement_action(&EnforcementPolicy::WarnOnly, Int256::from(-150i64), close),
+            DebtAction::OpenTunnel
+        );
+        assert_eq!(
+            enforcement_action(&EnforcementPolicy::FreeTier, Int256::from(-150i64), close),
+            DebtAction::SuspendTunnel
+        );
+        assert_eq!(
+            enforcement_action(
+                &EnforcementPolicy::CaptivePortal("192.168.10.1:80".parse().unwrap()),
+                Int256::from(-150i64),
+                close
+            ),
+            DebtAction::CaptivePortal
+        );
+        // past the close threshold but short of every step
+        assert_eq!(
+            enforcement_action(&progressive, Int256::from(-150i64), close),
+            throttle(500)
+        );
+        assert_eq!(
+            enforcement_action(&progressive, Int256::from(-200i64), close),
+            throttle(500)
+        );
+        assert_eq!(
+            enforcement_action(&progressive, Int256::from(-399i64), close),
+            throttle(500)
+        );
+        assert_eq!(
+            enforcement_action(&progressive, Int256::from(-1000i64), close),
+            throttle(100)
+        );
+        assert_eq!(
+            enforcement_action(
+                &EnforcementPolicy::Progressive(Vec::new()),
+                Int256::from(-1000i64),
+                close
+            ),
+            DebtAction::SuspendTunnel
+        );
+    }
+
+    #[test]
+    fn test_grace_period() {
+        let now = Instant::now();
+        let grace = Duration::from_secs(60);
+        assert!(in_grace_period(now, grace, now));
+        assert!(in_grace_period(now, grace, now + Duration::from_secs(59)));
+        assert!(!in_grace_period(now, grace, now + grace));
+        assert!(!in_grace_period(now, Duration::ZERO, now));
+    }
+
+    #[test]
+    fn test_single_suspend_grace_period() {
+        settings::set_rita_client(RitaClientSettings::default());
+        let mut client = settings::get_rita_client();
+        client.payment.payment_threshold = 1.into();
+        client.payment.enforcement_grace_period = 3600;
+        settings::set_rita_client(client);
+
+        set_oracle_gas_price(0u32.into());
+
+        let mut d = DebtKeeper::new();
+
+        let ident = get_test_identity();
+
+        d.traffic_update(&ident, Int256::from(-100i64));
+
+        assert_eq!(d.send_update(&ident).unwrap(), DebtAction::OpenTunnel);
+        assert!(d.get_debt_data_mut(&ident).overdue_since.is_some());
+        // going back under the close threshold resets the grace period
+        let debt = d.get_debt_data_mut(&ident).debt;
+        d.traffic_update(&ident, -debt);
+        assert_eq!(d.send_update(&ident).unwrap(), DebtAction::OpenTunnel);
+        assert!(d.get_debt_data_mut(&ident).overdue_since.is_none());
+    }
+
+    #[test]
+    fn test_progressive_debt_limit() {
+        settings::set_rita_client(RitaClientSettings::default());
+        let mut client = settings::get_rita_client();
+        client.payment.payment_threshold = 1.into();
+        client.payment.debt_limit_enabled = true;
+        client.payment.enforcement_policy = EnforcementPolicy::Progressive(vec![
+            ThrottleStep {
+                close_threshold_multiple: 2,
+                throughput: 500,
+            },
+            ThrottleStep {
+                close_threshold_multiple: 4,
+                throughput: 100,
+            },
+        ]);
+        settings::set_rita_client(client);
+
+        set_oracle_gas_price(0u32.into());
+
+        let mut d = DebtKeeper::new();
+        let ident = get_test_identity();
+        let close_threshold = calculate_close_thresh();
+
+        // the limit doesn't stop the debt from reaching the last step
+        d.traffic_update(&ident, close_threshold * 2u8.into());
+        assert_eq!(
+            d.send_update(&ident).unwrap(),
+            DebtAction::ThrottleTunnel { throughput: 500 }
+        );
+        d.traffic_update(&ident, close_threshold * 10u8.into());
+        assert_eq!(
+            d.send_update(&ident).unwrap(),
+            DebtAction::ThrottleTunnel { throughput: 100 }
+        );
+        // but the debt past it is forgiven
+        assert_eq!(
+            d.get_debt_data_mut(&ident).debt,
+            close_threshold * 4u8.into() - 1u8.into()
+        );
+        assert_eq!(
+            d.send_update(&ident).unwrap(),
+            DebtAction::ThrottleTunnel { throughput: 100 }
+        );
+    }
+
     #[test]
     fn test_single_overpay() {
         settings::set_rita_client(RitaClientSettings::default());
@@ -914,6 +1648,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_payment_reversed() {
+        let mut d = DebtKeeper::new();
+        let ident = get_test_identity();
+
+        // they overpay, the excess goes into incoming payments
+        d.traffic_update(&ident, Int256::from(-100));
+        d.payment_received(&ident, Uint256::from(150u32)).unwrap();
+        assert_eq!(d.get_debts()[&ident].debt, Int256::from(0));
+        assert_eq!(d.get_debts()[&ident].incoming_payments, 50u32.into());
+
+        d.payment_received_reversed(&ident, Uint256::from(150u32))
+            .unwrap();
+        let debt_data = &d.get_debts()[&ident];
+        assert_eq!(debt_data.debt, Int256::from(-100));
+        assert_eq!(debt_data.incoming_payments, 0u32.into());
+        assert_eq!(debt_data.total_payment_received, 0u32.into());
+
+        let other = get_random_test_identity();
+        d.traffic_update(&other, Int256::from(100));
+        d.payment_succeeded(&other, Uint256::from(100u32)).unwrap();
+        assert_eq!(d.get_debts()[&other].debt, Int256::from(0));
+        d.payment_succeeded_reversed(&other, Uint256::from(100u32))
+            .unwrap();
+        assert_eq!(d.get_debts()[&other].debt, Int256::from(100));
+        assert_eq!(d.get_debts()[&other].total_payment_sent, 0u32.into());
+    }
+
     #[test]
     fn test_single_pay_limited() {
         settings::set_rita_client(RitaClientSettings::default());
@@ -1354,6 +2116,10 @@ mod tests {
             payment_in_flight: false,
             payment_in_flight_start: None,
             last_successful_payment: None,
+            overdue_since: None,
+            payments_received_by_denom: HashMap::new(),
+            payments_sent_by_denom: HashMap::new(),
+            debt_by_denom: HashMap::new(),
         };
 
         let id2 = Identity {
@@ -1376,6 +2142,10 @@ mod tests {
             payment_in_flight: false,
             payment_in_flight_start: None,
             last_successful_payment: None,
+            overdue_since: None,
+            payments_received_by_denom: HashMap::new(),
+            payments_sent_by_denom: HashMap::new(),
+            debt_by_denom: HashMap::new(),
         };
 
         debt_data.insert(id, node_debts);
@@ -1419,4 +2189,153 @@ mod tests {
         // let mut x: Vec<(Identity, NodeDebtData)> = deserialize_from(reader).unwrap();
         // println!("{:?}", x);
     }
+    #[test]
+    fn test_loading_old_debts_file() {
+        let old: DebtDataSerOld = vec![
+            (
+                get_random_test_identity(),
+                NodeDebtDataOld {
+                    total_payment_received: Uint256::from(8u8),
+                    total_payment_sent: Uint256::from(35u8),
+                    debt: Int256::from(34634u64),
+                    incoming_payments: Uint256::from(0u8),
+                    action: DebtAction::OpenTunnel,
+                },
+            ),
+            (
+                get_random_test_identity(),
+                NodeDebtDataOld {
+                    total_payment_received: Uint256::from(9u8),
+                    total_payment_sent: Uint256::from(5u8),
+                    debt: Int256::from(-3460i64),
+                    incoming_payments: Uint256::from(100u8),
+                    action: DebtAction::SuspendTunnel,
+                },
+            ),
+        ];
+
+        let file_path = "testing_old_debt_loading.bincode";
+        let mut file = File::create(file_path).unwrap();
+        file.write_all(&bincode::serialize(&old).unwrap()).unwrap();
+
+        let loaded = deserialize_from_binary(file_path.to_string()).unwrap();
+        let _ = remove_file(file_path);
+
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded[0].0, old[0].0);
+        assert_eq!(loaded[0].1.debt, Int256::from(34634u64));
+        assert_eq!(loaded[0].1.total_payment_sent, Uint256::from(35u8));
+        assert!(loaded[0].1.payments_received_by_denom.is_empty());
+        assert_eq!(
+            loaded[0].1.debt_by_denom[DEBT_KEEPER_DENOM],
+            Int256::from(34634u64)
+        );
+        assert_eq!(loaded[1].0, old[1].0);
+        assert_eq!(loaded[1].1.debt, Int256::from(-3460i64));
+        assert_eq!(loaded[1].1.incoming_payments, Uint256::from(100u8));
+        assert_eq!(loaded[1].1.action, DebtAction::SuspendTunnel);
+        assert_eq!(
+            loaded[1].1.debt_by_denom[DEBT_KEEPER_DENOM],
+            Int256::from(-3360i64)
+        );
+    }
+
+    #[test]
+    fn test_debt_by_denom() {
+        settings::set_rita_client(RitaClientSettings::default());
+        let ident = get_random_test_identity();
+        let usdc = Denom {
+            denom: "ibc/usdc".to_string(),
+            decimal: 1_000_000,
+        };
+        let wei = Denom {
+            denom: DEBT_KEEPER_DENOM.to_string(),
+            decimal: DEBT_KEEPER_DENOM_DECIMAL,
+        };
+
+        // they use 3 dollars of bandwidth and pay for it in a mix of usdc and wei
+        traffic_update(vec![Traffic {
+            from: ident,
+            amount: Int256::from(-3_000_000_000_000_000_000i64),
+        }]);
+        payment_received(ident, 2_000_000u32.into(), usdc.clone()).unwrap();
+        payment_received(ident, 500_000_000_000_000_000u64.into(), wei.clone()).unwrap();
+
+        let data = dump()[&ident].clone();
+        assert_eq!(data.debt_by_denom[&usdc.denom], Int256::from(2_000_000u32));
+        assert_eq!(
+            data.debt_by_denom[&wei.denom],
+            Int256::from(-2_500_000_000_000_000_000i64)
+        );
+        // normalizing rounds the usdc payment up by one wei
+        assert_eq!(data.debt, Int256::from(-499_999_999_999_999_999i64));
+
+        // a reversed payment is owed again in the denom it was made in
+        payment_received_reversed(ident, 2_000_000u32.into(), usdc.clone()).unwrap();
+        let data = dump()[&ident].clone();
+        assert_eq!(data.debt_by_denom[&usdc.denom], Int256::zero());
+        assert_eq!(data.debt, Int256::from(-2_500_000_000_000_000_000i64));
+
+        // per denom debts survive a save and load
+        let file_path = "testing_debt_by_denom.bincode";
+        let serialized = bincode::serialize(&debt_data_to_ser(dump())).unwrap();
+        File::create(file_path)
+            .unwrap()
+            .write_all(&serialized)
+            .unwrap();
+        let loaded = deserialize_from_binary(file_path.to_string()).unwrap();
+        let _ = remove_file(file_path);
+        let (_, loaded) = loaded.into_iter().find(|(id, _)| *id == ident).unwrap();
+        assert_eq!(loaded.debt_by_denom, data.debt_by_denom);
+        assert_eq!(
+            loaded.payments_received_by_denom,
+            data.payments_received_by_denom
+        );
+    }
+
+    #[test]
+    fn test_select_settlement_denom() {
+        let usdc = Denom {
+            denom: "ibc/usdc".to_string(),
+            decimal: 1_000_000,
+        };
+        let usdt = Denom {
+            denom: "ibc/usdt".to_string(),
+            decimal: 1_000_000,
+        };
+        let mut accepted = HashMap::new();
+        accepted.insert("usdc".to_string(), usdc.clone());
+        accepted.insert("usdt".to_string(), usdt.clone());
+
+        let fixed = SettlementDenomPolicy::Fixed("usdt".to_string());
+        let matching = SettlementDenomPolicy::MatchNeighbor("usdc".to_string());
+        let mut received = HashMap::new();
+
+        assert_eq!(
+            select_settlement_denom(&fixed, &accepted, &received),
+            Some(usdt.clone())
+        );
+        // neighbor has never paid us, use the default
+        assert_eq!(
+            select_settlement_denom(&matching, &accepted, &received),
+            Some(usdc.clone())
+        );
+
+        received.insert(usdc.denom.clone(), Uint256::from(100u32));
+        received.insert(usdt.denom.clone(), Uint256::from(5000u32));
+        // a denom we no longer accept is ignored
+        received.insert("wei".to_string(), Uint256::from(1_000_000_000u64));
+        assert_eq!(
+            select_settlement_denom(&matching, &accepted, &received),
+            Some(usdt)
+        );
+        assert_eq!(
+            select_settlement_denom(
+                &SettlementDenomPolicy::Fixed("dai".to_string()),
+                &accepted,
+                &received
+            ),
+            None
+        );
+    }
 }
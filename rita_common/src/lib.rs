@@ -20,11 +20,15 @@ pub static DROPBEAR_AUTHORIZED_KEYS: &str = "/etc/dropbear/authorized_keys";
 
 pub mod blockchain_oracle;
 pub mod dashboard;
+pub mod debt_dispute;
 pub mod debt_keeper;
+pub mod link_prober;
 pub mod logging;
 pub mod middleware;
 pub mod network_endpoints;
 pub mod network_monitor;
+pub mod payment_backend;
+pub mod payment_channel;
 pub mod payment_controller;
 pub mod payment_validator;
 pub mod peer_listener;
@@ -0,0 +1,306 @@
+//! LinkProber actively measures each tunnel rather than relying on babel's rtt and reach bitmask.
+//! Every fast loop tick a short train of timestamped probes is sent to each neighbor over the
+//! tunnel's wg interface, addressed to the link local address every tunnel carries. A dedicated
+//! thread receives probes as they arrive so that their receive times are accurate, from which we
+//! measure one way jitter, loss bursts and the bandwidth the train was squeezed into.
+//!
+//! What we measure is the direction the neighbor sends in, so it is reported back to them inside
+//! our own probes and what they report to us is the direction our shaper controls. Both are kept
+//! as a per tunnel history which network_monitor consults when deciding to shape a tunnel.
+
+pub mod probe;
+pub mod stats;
+
+use crate::tunnel_manager::shaping::{ShapingAdjust, ShapingAdjustAction};
+use crate::tunnel_manager::Neighbor;
+use crate::KI;
+use althea_kernel_interface::open_tunnel::try_to_wg_local;
+use probe::Probe;
+use stats::{LinkHistory, LinkSample, ProbeReceiver};
+use std::collections::HashMap;
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Probes are padded to this size so that a train says something about bandwidth, this fits
+/// comfortably inside the wg interface mtu
+pub const PROBE_SIZE: usize = 1200;
+/// Probes sent back to back each tick to each neighbor
+pub const PROBE_TRAIN_LEN: u8 = 4;
+
+lazy_static! {
+    static ref LINK_PROBER: Arc<RwLock<LinkProber>> = Arc::new(RwLock::new(LinkProber::default()));
+}
+
+#[derive(Default)]
+pub struct LinkProber {
+    /// Shared with the receive thread, None until start_link_prober binds it
+    socket: Option<UdpSocket>,
+    /// wg interface index to interface name of every tunnel being probed, probes arrive from a
+    /// link local address so the interface is the only way to tell which tunnel they came over
+    ifaces: HashMap<u32, String>,
+    receivers: HashMap<String, ProbeReceiver>,
+    history: HashMap<String, LinkHistory>,
+    next_seq: HashMap<String, u32>,
+    next_train: u32,
+    /// The train the last report from each neighbor came in, every probe of a train carries the
+    /// same report so that it survives some of them being lost
+    last_report_train: HashMap<String, u32>,
+}
+
+fn now_micros() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_micros() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// Binds the probe socket and starts the thread receiving probes, does nothing if link probing
+/// is disabled
+pub fn start_link_prober() {
+    let network = settings::get_rita_common().network;
+    if !network.link_probing {
+        return;
+    }
+    let addr = SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, network.link_probe_port, 0, 0);
+    let socket = match UdpSocket::bind(addr) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to bind link probe socket {:?}, not probing", e);
+            return;
+        }
+    };
+    let receive_socket = match socket.try_clone() {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to clone link probe socket {:?}, not probing", e);
+            return;
+        }
+    };
+    LINK_PROBER.write().unwrap().socket = Some(socket);
+
+    thread::spawn(move || {
+        let mut buf = [0u8; PROBE_SIZE];
+        loop {
+            match receive_socket.recv_from(&mut buf) {
+                Ok((bytes, from)) => {
+                    // stamp it before taking the lock
+                    let received = now_micros();
+                    match Probe::decode(&buf[..bytes]) {
+                        Ok(probe) => {
+                            LINK_PROBER
+                                .write()
+                                .unwrap()
+                                .on_probe(from, &probe, bytes, received);
+                        }
+                        Err(e) => trace!("Bad link probe from {}: {}", from, e),
+                    }
+                }
+                Err(e) => error!("Failed to receive link probe {:?}", e),
+            }
+        }
+    });
+}
+
+impl LinkProber {
+    fn on_probe(&mut self, from: SocketAddr, probe: &Probe, size: usize, received: u64) {
+        let iface = match from {
+            SocketAddr::V6(from) => match self.ifaces.get(&from.scope_id()) {
+                Some(iface) => iface.clone(),
+                None => {
+                    trace!("Link probe from {} is not over a tunnel", from);
+                    return;
+                }
+            },
+            SocketAddr::V4(_) => return,
+        };
+        self.receivers
+            .entry(iface.clone())
+            .or_default()
+            .on_probe(probe, size, received);
+        if let Some(report) = probe.report {
+            if self.last_report_train.get(&iface) != Some(&probe.train) {
+                self.last_report_train.insert(iface.clone(), probe.train);
+                self.history
+                    .entry(iface)
+                    .or_default()
+                    .push_outbound(LinkSample::new(report, SystemTime::now()));
+            }
+        }
+    }
+
+    /// Sends a train of probes to every neighbor, each probe carrying the report of what we have
+    /// measured from that neighbor since the last tick
+    fn tick(&mut self, neighbors: &[Neighbor]) {
+        let port = settings::get_rita_common().network.link_probe_port;
+        let mut ifaces = HashMap::new();
+        for neigh in neighbors {
+            match KI.get_ifindex(&neigh.iface_name) {
+                Ok(idx) => {
+                    ifaces.insert(idx as u32, neigh.iface_name.clone());
+                }
+                Err(e) => trace!("No ifindex for {}: {:?}", neigh.iface_name, e),
+            }
+        }
+        // forget tunnels that have gone away
+        self.receivers
+            .retain(|k, _| ifaces.values().any(|v| v == k));
+        self.history.retain(|k, _| ifaces.values().any(|v| v == k));
+        self.next_seq.retain(|k, _| ifaces.values().any(|v| v == k));
+        self.last_report_train
+            .retain(|k, _| ifaces.values().any(|v| v == k));
+        self.ifaces = ifaces;
+
+        let socket = match &self.socket {
+            Some(s) => s,
+            None => return,
+        };
+        let now = SystemTime::now();
+        for neigh in neighbors {
+            let idx = match self.ifaces.iter().find(|(_, v)| **v == neigh.iface_name) {
+                Some((idx, _)) => *idx,
+                None => continue,
+            };
+            let report = match self.receivers.get_mut(&neigh.iface_name) {
+                Some(receiver) => {
+                    let report = receiver.report();
+                    self.history
+                        .entry(neigh.iface_name.clone())
+                        .or_default()
+                        .push_inbound(LinkSample::new(report, now));
+                    Some(report)
+                }
+                None => None,
+            };
+            // the mesh ip comes from the neighbor's hello, so it may not be one to_wg_local takes
+            let dest = match try_to_wg_local(&neigh.identity.global.mesh_ip) {
+                Some(ip) => SocketAddrV6::new(ip, port, 0, idx),
+                None => {
+                    trace!(
+                        "Not probing {} with mesh ip {}",
+                        neigh.iface_name,
+                        neigh.identity.global.mesh_ip
+                    );
+                    continue;
+                }
+            };
+            let seq = self.next_seq.entry(neigh.iface_name.clone()).or_default();
+            self.next_train = self.next_train.wrapping_add(1);
+            for index in 0..PROBE_TRAIN_LEN {
+                let probe = Probe {
+                    seq: *seq,
+                    train: self.next_train,
+                    index,
+                    train_len: PROBE_TRAIN_LEN,
+                    sent: now_micros(),
+                    report,
+                };
+                *seq = seq.wrapping_add(1);
+                if let Err(e) = socket.send_to(&probe.encode(PROBE_SIZE), dest) {
+                    trace!("Failed to send link probe to {}: {:?}", dest, e);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Tunnels the probes show to be congested in the direction we send, these are signaled once
+    /// and then need a fresh set of samples before they are signaled again
+    fn congested(&mut self) -> Vec<String> {
+        let now = SystemTime::now();
+        let mut out = Vec::new();
+        for (iface, history) in self.history.iter_mut() {
+            if history.is_congested() {
+                history.signaled(now);
+                out.push(iface.clone());
+            }
+        }
+        out
+    }
+}
+
+/// Probes every current neighbor, called once per fast loop tick
+pub fn link_prober_tick(neighbors: &[Neighbor]) {
+    if !settings::get_rita_common().network.link_probing {
+        return;
+    }
+    LINK_PROBER.write().unwrap().tick(neighbors)
+}
+
+/// The measurement history of every tunnel by wg interface name
+pub fn get_link_history() -> HashMap<String, LinkHistory> {
+    LINK_PROBER.read().unwrap().history.clone()
+}
+
+/// The bandwidth the probes say the tunnel on iface can carry towards the neighbor, in mbps
+pub fn get_bandwidth_estimate(iface: &str) -> Option<usize> {
+    LINK_PROBER
+        .read()
+        .unwrap()
+        .history
+        .get(iface)
+        .and_then(|h| h.bandwidth_estimate())
+}
+
+/// Speed reductions for tunnels the probes show to be congested, for network monitor to merge
+/// with what it sees from babel
+pub fn get_link_shaping() -> Vec<ShapingAdjust> {
+    let mut prober = LINK_PROBER.write().unwrap();
+    prober
+        .congested()
+        .into_iter()
+        .map(|iface| {
+            let bandwidth_estimate = prober
+                .history
+                .get(&iface)
+                .and_then(|h| h.bandwidth_estimate());
+            ShapingAdjust {
+                iface,
+                action: ShapingAdjustAction::ReduceSpeed,
+                bandwidth_estimate,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::probe::LinkReport;
+    use super::*;
+
+    #[test]
+    fn test_on_probe_by_interface() {
+        let mut prober = LinkProber::default();
+        prober.ifaces.insert(12, "wg3".to_string());
+        let from: SocketAddr = SocketAddrV6::new("fe80::1".parse().unwrap(), 4873, 0, 12).into();
+        let other: SocketAddr = SocketAddrV6::new("fe80::1".parse().unwrap(), 4873, 0, 13).into();
+        let report = LinkReport {
+            jitter_us: 2000,
+            received: 4,
+            ..Default::default()
+        };
+        for seq in 0..PROBE_TRAIN_LEN as u32 {
+            let probe = Probe {
+                seq,
+                train: 1,
+                index: seq as u8,
+                train_len: PROBE_TRAIN_LEN,
+                sent: 100 * seq as u64,
+                report: Some(report),
+            };
+            prober.on_probe(from, &probe, PROBE_SIZE, 1000 + 100 * seq as u64);
+            // not one of our tunnels
+            prober.on_probe(other, &probe, PROBE_SIZE, 1000 + 100 * seq as u64);
+        }
+        assert_eq!(prober.receivers.len(), 1);
+        let history = &prober.history["wg3"];
+        // the report is repeated in every probe of the train but only recorded once
+        assert_eq!(history.outbound.len(), 1);
+        assert_eq!(history.outbound[0].jitter_ms, 2.0);
+        let measured = prober.receivers.get_mut("wg3").unwrap().report();
+        assert_eq!(measured.received, PROBE_TRAIN_LEN as u32);
+        assert_eq!(measured.lost, 0);
+        assert!(measured.bandwidth_kbps > 0);
+    }
+}
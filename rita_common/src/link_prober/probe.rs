@@ -0,0 +1,184 @@
+//! The probe wire format. Probes are sent in short back to back trains, every probe carries the
+//! sender's clock so the receiver can measure one way jitter and a sequence number so it can find
+//! gaps. Probes also carry a report of what the sender measured from the receiver's probes, this
+//! is how each side learns about the direction it sends in, which is the one it can shape.
+//!
+//! magic <u8>, version <u8>, seq <u32>, train <u32>, index <u8>, train len <u8>, sent <u64>,
+//! has report <u8>, [report], zero padding up to the probe size
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::BufMut;
+use std::fmt;
+use std::io::Cursor;
+
+pub const PROBE_MAGIC: u8 = 0x7a;
+pub const PROBE_VERSION: u8 = 1;
+/// The smallest possible probe, without a report or padding
+pub const PROBE_HEADER_LEN: usize = 21;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct LinkReport {
+    /// Interarrival jitter as defined in RFC 3550 in microseconds
+    pub jitter_us: u32,
+    pub received: u32,
+    pub lost: u32,
+    /// Runs of consecutive lost probes
+    pub loss_bursts: u16,
+    /// The longest run of consecutive lost probes
+    pub max_loss_burst: u16,
+    /// The bandwidth estimated from the dispersion of the last probe train, 0 if unknown
+    pub bandwidth_kbps: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Probe {
+    pub seq: u32,
+    /// Probes with the same train number were sent back to back
+    pub train: u32,
+    pub index: u8,
+    pub train_len: u8,
+    /// Microseconds since the unix epoch on the sender's clock, the clocks are never compared
+    /// directly so they don't need to be in sync
+    pub sent: u64,
+    pub report: Option<LinkReport>,
+}
+
+#[derive(Debug)]
+pub enum ProbeError {
+    BadMagic(u8),
+    UnsupportedVersion(u8),
+    BufferUnderflow,
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProbeError::BadMagic(m) => write!(f, "Not a probe, magic {m:#x}"),
+            ProbeError::UnsupportedVersion(v) => write!(f, "Unsupported probe version {v}"),
+            ProbeError::BufferUnderflow => write!(f, "Probe is too short"),
+        }
+    }
+}
+
+impl From<std::io::Error> for ProbeError {
+    fn from(_error: std::io::Error) -> Self {
+        ProbeError::BufferUnderflow
+    }
+}
+
+impl Probe {
+    /// Encodes the probe padded out to size bytes, probes larger than size are not padded
+    pub fn encode(&self, size: usize) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(size);
+        buf.put_u8(PROBE_MAGIC);
+        buf.put_u8(PROBE_VERSION);
+        buf.put_u32(self.seq);
+        buf.put_u32(self.train);
+        buf.put_u8(self.index);
+        buf.put_u8(self.train_len);
+        buf.put_u64(self.sent);
+        match self.report {
+            Some(report) => {
+                buf.put_u8(1);
+                buf.put_u32(report.jitter_us);
+                buf.put_u32(report.received);
+                buf.put_u32(report.lost);
+                buf.put_u16(report.loss_bursts);
+                buf.put_u16(report.max_loss_burst);
+                buf.put_u32(report.bandwidth_kbps);
+            }
+            None => buf.put_u8(0),
+        }
+        if buf.len() < size {
+            buf.resize(size, 0);
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Probe, ProbeError> {
+        let mut pointer = Cursor::new(buf);
+        let magic = pointer.read_u8()?;
+        if magic != PROBE_MAGIC {
+            return Err(ProbeError::BadMagic(magic));
+        }
+        let version = pointer.read_u8()?;
+        if version != PROBE_VERSION {
+            return Err(ProbeError::UnsupportedVersion(version));
+        }
+        let seq = pointer.read_u32::<BigEndian>()?;
+        let train = pointer.read_u32::<BigEndian>()?;
+        let index = pointer.read_u8()?;
+        let train_len = pointer.read_u8()?;
+        let sent = pointer.read_u64::<BigEndian>()?;
+        let report = match pointer.read_u8()? {
+            0 => None,
+            _ => Some(LinkReport {
+                jitter_us: pointer.read_u32::<BigEndian>()?,
+                received: pointer.read_u32::<BigEndian>()?,
+                lost: pointer.read_u32::<BigEndian>()?,
+                loss_bursts: pointer.read_u16::<BigEndian>()?,
+                max_loss_burst: pointer.read_u16::<BigEndian>()?,
+                bandwidth_kbps: pointer.read_u32::<BigEndian>()?,
+            }),
+        };
+        Ok(Probe {
+            seq,
+            train,
+            index,
+            train_len,
+            sent,
+            report,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe_encode_decode() {
+        let probe = Probe {
+            seq: 0xdead_beef,
+            train: 7,
+            index: 2,
+            train_len: 4,
+            sent: 1_650_000_000_000_000,
+            report: None,
+        };
+        let encoded = probe.encode(0);
+        assert_eq!(encoded.len(), PROBE_HEADER_LEN);
+        assert_eq!(Probe::decode(&encoded).unwrap(), probe);
+
+        let probe = Probe {
+            report: Some(LinkReport {
+                jitter_us: 1500,
+                received: 38,
+                lost: 2,
+                loss_bursts: 1,
+                max_loss_burst: 2,
+                bandwidth_kbps: 54_000,
+            }),
+            ..probe
+        };
+        let encoded = probe.encode(1200);
+        assert_eq!(encoded.len(), 1200);
+        assert_eq!(Probe::decode(&encoded).unwrap(), probe);
+
+        match Probe::decode(&encoded[..30]) {
+            Err(ProbeError::BufferUnderflow) => (),
+            r => panic!("Expected a buffer underflow, got {:?}", r),
+        }
+        let mut other = encoded.clone();
+        other[1] = 2;
+        match Probe::decode(&other) {
+            Err(ProbeError::UnsupportedVersion(2)) => (),
+            r => panic!("Expected an unsupported version, got {:?}", r),
+        }
+        other[0] = 0x5b;
+        match Probe::decode(&other) {
+            Err(ProbeError::BadMagic(0x5b)) => (),
+            r => panic!("Expected a bad magic, got {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,399 @@
+//! Turning received probes into link measurements, and the per tunnel history of those
+//! measurements
+
+use super::probe::{LinkReport, Probe};
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+/// How many samples of each direction a tunnel remembers, at one sample per fast loop tick this
+/// is ten minutes
+pub const LINK_HISTORY_LEN: usize = 120;
+/// How many samples the shaper signal is decided on, no signal is given until this many samples
+/// have arrived since the last one
+pub const LINK_SIGNAL_SAMPLES: usize = 6;
+/// Any run of this many lost probes in a row is a signal on its own
+pub const LOSS_BURST_THRESHOLD: u16 = 3;
+/// The fraction of probes that can be lost over the signal samples before it is a signal
+pub const LOSS_THRESHOLD: f32 = 0.05;
+/// Jitter this many times higher than the lowest jitter this link has shown is a signal
+pub const JITTER_FACTOR: f32 = 4.0;
+/// Jitter below this is never a signal no matter how good the link has been
+pub const JITTER_FLOOR_MS: f32 = 5.0;
+/// A sequence number this far either side of the newest we have seen means the sender restarted
+const SEQ_RESET: u32 = 1000;
+
+/// Progress through a probe train, used to estimate bandwidth from how spread out the probes
+/// are when they arrive
+#[derive(Debug, Clone, Copy)]
+struct TrainProgress {
+    train: u32,
+    train_len: u8,
+    count: u8,
+    first_received: u64,
+    last_received: u64,
+    /// Bytes received after the first probe of the train
+    bytes: usize,
+}
+
+impl TrainProgress {
+    fn estimate_kbps(&self) -> Option<u32> {
+        if self.count < 2 || self.last_received <= self.first_received {
+            return None;
+        }
+        let bits = self.bytes as u64 * 8;
+        // bits per microsecond is megabits per second
+        Some((bits * 1000 / (self.last_received - self.first_received)) as u32)
+    }
+}
+
+/// Our measurements of the probes one neighbor sends us
+#[derive(Debug, Clone, Default)]
+pub struct ProbeReceiver {
+    /// receive time minus send time of the last probe, the clock offset cancels out of the
+    /// difference between two of these
+    last_transit: Option<i64>,
+    jitter_us: f64,
+    highest_seq: Option<u32>,
+    received: u32,
+    lost: u32,
+    loss_bursts: u16,
+    max_loss_burst: u16,
+    train: Option<TrainProgress>,
+    bandwidth_kbps: Option<u32>,
+}
+
+impl ProbeReceiver {
+    /// Records a probe of size bytes received at received microseconds since the unix epoch
+    pub fn on_probe(&mut self, probe: &Probe, size: usize, received: u64) {
+        self.received += 1;
+
+        let transit = received as i64 - probe.sent as i64;
+        if let Some(last) = self.last_transit {
+            let d = (transit - last).abs() as f64;
+            self.jitter_us += (d - self.jitter_us) / 16.0;
+        }
+        self.last_transit = Some(transit);
+
+        match self.highest_seq {
+            Some(highest) if probe.seq > highest && probe.seq - highest <= SEQ_RESET => {
+                let gap = probe.seq - highest - 1;
+                if gap > 0 {
+                    self.lost += gap;
+                    self.loss_bursts = self.loss_bursts.saturating_add(1);
+                    self.max_loss_burst = self.max_loss_burst.max(gap.min(u16::MAX as u32) as u16);
+                }
+                self.highest_seq = Some(probe.seq);
+            }
+            // arrived out of order, it was counted as lost when the gap was found
+            Some(highest) if probe.seq <= highest && highest - probe.seq <= SEQ_RESET => {
+                self.lost = self.lost.saturating_sub(1);
+            }
+            // first probe or the sender restarted
+            _ => self.highest_seq = Some(probe.seq),
+        }
+
+        if probe.train_len < 2 {
+            return;
+        }
+        match &mut self.train {
+            Some(t) if t.train == probe.train => {
+                t.count += 1;
+                t.last_received = received;
+                t.bytes += size;
+            }
+            _ => {
+                // a train we never saw the end of, estimate from what did arrive
+                self.finish_train();
+                self.train = Some(TrainProgress {
+                    train: probe.train,
+                    train_len: probe.train_len,
+                    count: 1,
+                    first_received: received,
+                    last_received: received,
+                    bytes: 0,
+                });
+            }
+        }
+        if let Some(t) = self.train {
+            if t.count >= t.train_len {
+                self.finish_train();
+            }
+        }
+    }
+
+    fn finish_train(&mut self) {
+        if let Some(estimate) = self.train.take().and_then(|t| t.estimate_kbps()) {
+            self.bandwidth_kbps = Some(estimate);
+        }
+    }
+
+    /// What we have measured since the last report, the loss counters start over afterwards
+    /// while jitter and bandwidth carry on
+    pub fn report(&mut self) -> LinkReport {
+        let report = LinkReport {
+            jitter_us: self.jitter_us as u32,
+            received: self.received,
+            lost: self.lost,
+            loss_bursts: self.loss_bursts,
+            max_loss_burst: self.max_loss_burst,
+            bandwidth_kbps: self.bandwidth_kbps.unwrap_or(0),
+        };
+        self.received = 0;
+        self.lost = 0;
+        self.loss_bursts = 0;
+        self.max_loss_burst = 0;
+        report
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct LinkSample {
+    pub time: SystemTime,
+    pub jitter_ms: f32,
+    pub received: u32,
+    pub lost: u32,
+    pub loss_bursts: u16,
+    pub max_loss_burst: u16,
+    pub bandwidth_mbps: Option<f32>,
+}
+
+impl LinkSample {
+    pub fn new(report: LinkReport, time: SystemTime) -> LinkSample {
+        LinkSample {
+            time,
+            jitter_ms: report.jitter_us as f32 / 1000.0,
+            received: report.received,
+            lost: report.lost,
+            loss_bursts: report.loss_bursts,
+            max_loss_burst: report.max_loss_burst,
+            bandwidth_mbps: match report.bandwidth_kbps {
+                0 => None,
+                kbps => Some(kbps as f32 / 1000.0),
+            },
+        }
+    }
+}
+
+/// The measurements of one tunnel, oldest first
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LinkHistory {
+    /// What we measured of the probes the neighbor sent us
+    pub inbound: VecDeque<LinkSample>,
+    /// What the neighbor measured of the probes we sent, this is the direction our shaper
+    /// controls
+    pub outbound: VecDeque<LinkSample>,
+    /// When the last shaper signal was given, only samples after it count towards the next
+    last_signal: Option<SystemTime>,
+}
+
+fn push_sample(samples: &mut VecDeque<LinkSample>, sample: LinkSample) {
+    if samples.len() >= LINK_HISTORY_LEN {
+        samples.pop_front();
+    }
+    samples.push_back(sample);
+}
+
+impl LinkHistory {
+    pub fn push_inbound(&mut self, sample: LinkSample) {
+        push_sample(&mut self.inbound, sample)
+    }
+
+    pub fn push_outbound(&mut self, sample: LinkSample) {
+        push_sample(&mut self.outbound, sample)
+    }
+
+    /// The last LINK_SIGNAL_SAMPLES outbound samples since the last signal, None if there are
+    /// not that many yet
+    fn signal_window(&self) -> Option<Vec<&LinkSample>> {
+        let recent: Vec<&LinkSample> = self
+            .outbound
+            .iter()
+            .filter(|s| match self.last_signal {
+                Some(last) => s.time > last,
+                None => true,
+            })
+            .collect();
+        if recent.len() < LINK_SIGNAL_SAMPLES {
+            return None;
+        }
+        Some(recent[recent.len() - LINK_SIGNAL_SAMPLES..].to_vec())
+    }
+
+    /// True if the direction we send in is showing the loss bursts or jitter of a saturated
+    /// link, this can be seen well before babel's rtt moves
+    pub fn is_congested(&self) -> bool {
+        let window = match self.signal_window() {
+            Some(window) => window,
+            None => return false,
+        };
+        if window
+            .iter()
+            .any(|s| s.max_loss_burst >= LOSS_BURST_THRESHOLD)
+        {
+            return true;
+        }
+        let received: u32 = window.iter().map(|s| s.received).sum();
+        let lost: u32 = window.iter().map(|s| s.lost).sum();
+        if received + lost > 0 && lost as f32 / (received + lost) as f32 > LOSS_THRESHOLD {
+            return true;
+        }
+        let baseline = self
+            .outbound
+            .iter()
+            .map(|s| s.jitter_ms)
+            .fold(f32::INFINITY, f32::min);
+        let jitter = window.iter().map(|s| s.jitter_ms).sum::<f32>() / window.len() as f32;
+        jitter > JITTER_FLOOR_MS && jitter > baseline * JITTER_FACTOR
+    }
+
+    /// Marks that a signal was given, the next one needs a fresh set of samples
+    pub fn signaled(&mut self, time: SystemTime) {
+        self.last_signal = Some(time);
+    }
+
+    /// The median of the recent outbound bandwidth estimates in mbps
+    pub fn bandwidth_estimate(&self) -> Option<usize> {
+        let mut estimates: Vec<f32> = self
+            .outbound
+            .iter()
+            .rev()
+            .take(LINK_SIGNAL_SAMPLES)
+            .filter_map(|s| s.bandwidth_mbps)
+            .collect();
+        if estimates.is_empty() {
+            return None;
+        }
+        estimates.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        Some(estimates[estimates.len() / 2] as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn probe(seq: u32, train: u32, index: u8, sent: u64) -> Probe {
+        Probe {
+            seq,
+            train,
+            index,
+            train_len: 4,
+            sent,
+            report: None,
+        }
+    }
+
+    #[test]
+    fn test_receiver_loss() {
+        let mut receiver = ProbeReceiver::default();
+        // 3 and 4 are lost, 6 arrives late, 8 and 9 are lost
+        for seq in [0, 1, 2, 5, 7, 6, 10] {
+            receiver.on_probe(&probe(seq, seq, 0, 0), 100, 0);
+        }
+        let report = receiver.report();
+        assert_eq!(report.received, 7);
+        assert_eq!(report.lost, 4);
+        assert_eq!(report.loss_bursts, 3);
+        assert_eq!(report.max_loss_burst, 2);
+
+        // counters start over but the sequence carries on
+        receiver.on_probe(&probe(11, 11, 0, 0), 100, 0);
+        assert_eq!(receiver.report().lost, 0);
+        // a jump this far either way is the sender restarting rather than loss
+        receiver.on_probe(&probe(5000, 5000, 0, 0), 100, 0);
+        receiver.on_probe(&probe(3, 3, 0, 0), 100, 0);
+        receiver.on_probe(&probe(4, 4, 0, 0), 100, 0);
+        assert_eq!(receiver.report().lost, 0);
+    }
+
+    #[test]
+    fn test_receiver_jitter_and_bandwidth() {
+        let mut receiver = ProbeReceiver::default();
+        // the clocks are far apart but the transit time is steady
+        for i in 0..4u64 {
+            receiver.on_probe(
+                &probe(i as u32, 1, i as u8, 5_000_000 + i * 100),
+                1000,
+                9_000_000 + i * 100,
+            );
+        }
+        let report = receiver.report();
+        assert_eq!(report.jitter_us, 0);
+        // 3000 bytes over 300us is 80mbps
+        assert_eq!(report.bandwidth_kbps, 80_000);
+
+        // every probe now takes 1ms longer or shorter than the last
+        for i in 4..20u64 {
+            let wobble = if i % 2 == 0 { 1000 } else { 0 };
+            receiver.on_probe(
+                &probe(i as u32, i as u32, 0, 5_000_000 + i * 10_000),
+                1000,
+                9_000_000 + i * 10_000 + wobble,
+            );
+        }
+        let report = receiver.report();
+        assert!(report.jitter_us > 500 && report.jitter_us <= 1000);
+        // trains that never got past their first probe leave the last estimate alone
+        assert_eq!(report.bandwidth_kbps, 80_000);
+    }
+
+    fn sample(time: SystemTime, jitter_ms: f32, lost: u32, max_loss_burst: u16) -> LinkSample {
+        LinkSample {
+            time,
+            jitter_ms,
+            received: 40,
+            lost,
+            loss_bursts: if lost > 0 { 1 } else { 0 },
+            max_loss_burst,
+            bandwidth_mbps: Some(20.0),
+        }
+    }
+
+    #[test]
+    fn test_is_congested() {
+        let start = SystemTime::now();
+        let at = |i: u64| start + Duration::from_secs(i * 5);
+
+        let mut history = LinkHistory::default();
+        for i in 0..LINK_SIGNAL_SAMPLES as u64 {
+            history.push_outbound(sample(at(i), 1.0, 0, 0));
+        }
+        assert!(!history.is_congested());
+        assert_eq!(history.bandwidth_estimate(), Some(20));
+
+        // a single long loss burst
+        history.push_outbound(sample(at(6), 1.0, 3, 3));
+        assert!(history.is_congested());
+        history.signaled(at(6));
+        // not enough samples since the signal to say anything
+        history.push_outbound(sample(at(7), 1.0, 3, 3));
+        assert!(!history.is_congested());
+
+        // jitter well above what the link has shown before
+        for i in 8..8 + LINK_SIGNAL_SAMPLES as u64 {
+            history.push_outbound(sample(at(i), 6.0, 0, 0));
+        }
+        assert!(history.is_congested());
+
+        // the same jitter on a link that always had it is normal
+        let mut history = LinkHistory::default();
+        for i in 0..LINK_SIGNAL_SAMPLES as u64 {
+            history.push_outbound(sample(at(i), 6.0, 0, 0));
+        }
+        assert!(!history.is_congested());
+
+        // steady light loss
+        let mut history = LinkHistory::default();
+        for i in 0..LINK_SIGNAL_SAMPLES as u64 {
+            history.push_outbound(sample(at(i), 1.0, 4, 1));
+        }
+        assert!(history.is_congested());
+
+        let mut history = LinkHistory::default();
+        for i in 0..LINK_HISTORY_LEN as u64 + 1 {
+            history.push_inbound(sample(at(i), 1.0, 0, 0));
+        }
+        assert_eq!(history.inbound.len(), LINK_HISTORY_LEN);
+    }
+}
@@ -20,7 +20,7 @@ use actix_web_async::{dev::ServiceRequest, dev::ServiceResponse, Error};
 use actix_web_httpauth_async::extractors::basic::Config;
 use actix_web_httpauth_async::extractors::AuthenticationError;
 use actix_web_httpauth_async::headers::authorization::{Authorization, Basic};
-use futures::future::{ok, LocalBoxFuture, Ready};
+use futures::future::{err, ok, LocalBoxFuture, Ready};
 use futures::FutureExt;
 use regex::Regex;
 
@@ -143,49 +143,113 @@ where
     actix_service::forward_ready!(service);
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
-        let password = settings::get_rita_client().network.rita_dashboard_password;
+        let password = settings::get_rita_common().network.rita_dashboard_password;
         trace!("Password set is {:?}", password);
+        self.call_with_password(req, password)
+    }
+}
 
-        let req_path = req.path().to_string();
-
-        let auth = Authorization::<Basic>::parse(&req);
-
-        let fut = self.service.call(req);
+impl<S> AuthMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error> + 'static,
+{
+    fn call_with_password(
+        &self,
+        req: ServiceRequest,
+        password: Option<String>,
+    ) -> LocalBoxFuture<'static, Result<ServiceResponse<BoxBody>, Error>> {
+        // the /exits path is exempted from authenticaiton so that the
+        // checkup.ash cron script can continue to query it without issue
+        let password = match password {
+            Some(password) if req.path() != "/exits" => password,
+            _ => return self.service.call(req).boxed_local(),
+        };
 
-        async move {
-            // the /exits path is exempted from authenticaiton so that the
-            // checkup.ash cron script can continue to query it without issue
-            if password.is_none() || req_path == "/exits" {
-                let resp = fut.await?;
-                return Ok(resp);
+        // the request is only handed to the service once it's authenticated, an unauthorized
+        // request must not get to make any changes
+        let auth = match Authorization::<Basic>::parse(&req) {
+            Ok(auth) => auth,
+            Err(_) => {
+                let http_resp: HttpResponse<BoxBody> = HttpResponse::Forbidden()
+                    .finish()
+                    .set_body(actix_web_async::body::BoxBody::new("Unauthorized"));
+                let (http_req, _) = req.into_parts();
+                return ok(ServiceResponse::new(http_req, http_resp)).boxed_local();
             }
+        };
 
-            let auth = match auth {
-                Ok(auth) => auth,
-                Err(_) => {
-                    let resp = fut.await?;
-                    let requ = resp.request().clone();
-                    let http_resp: HttpResponse<BoxBody> = HttpResponse::Forbidden()
-                        .finish()
-                        .set_body(actix_web_async::body::BoxBody::new("Unauthorized"));
-                    let resp = ServiceResponse::new(requ, http_resp);
-                    return Ok(resp);
-                }
-            };
-
-            let auth_pass = auth.as_ref().password();
-            // If the user is authenticated, convert request -> response and return, else return Authenticaiton error
-            if auth.as_ref().user_id() == "rita"
-                && auth_pass.is_some()
-                && auth_pass.unwrap() == password.unwrap()
-            {
-                let resp = fut.await?;
-                Ok(resp)
-            } else {
-                let config = Config::default();
-                Err(AuthenticationError::from(config.realm("Admin")).into())
-            }
+        // If the user is authenticated, convert request -> response and return, else return Authenticaiton error
+        if auth.as_ref().user_id() == "rita" && auth.as_ref().password() == Some(&password) {
+            self.service.call(req).boxed_local()
+        } else {
+            let config = Config::default();
+            err(AuthenticationError::from(config.realm("Admin")).into()).boxed_local()
         }
-        .boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_async::System;
+    use actix_service::fn_service;
+    use actix_web_async::http::header::AUTHORIZATION;
+    use actix_web_async::test::TestRequest;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[test]
+    fn test_auth_middleware() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let called = Rc::new(AtomicBool::new(false));
+            let handler_called = called.clone();
+            let middleware = AuthMiddleware {
+                service: fn_service(move |req: ServiceRequest| {
+                    handler_called.store(true, Ordering::SeqCst);
+                    async move { Ok(req.into_response(HttpResponse::Ok().finish())) }
+                }),
+            };
+            let password = Some("hunter2".to_string());
+
+            // no credentials, the handler must never run
+            let req = TestRequest::post().uri("/admin/bans").to_srv_request();
+            let resp = middleware
+                .call_with_password(req, password.clone())
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            assert!(!called.load(Ordering::SeqCst));
+
+            // the wrong password
+            let req = TestRequest::post()
+                .uri("/admin/bans")
+                .insert_header((AUTHORIZATION, "Basic cml0YTp3cm9uZw=="))
+                .to_srv_request();
+            assert!(middleware
+                .call_with_password(req, password.clone())
+                .await
+                .is_err());
+            assert!(!called.load(Ordering::SeqCst));
+
+            // rita:hunter2
+            let req = TestRequest::post()
+                .uri("/admin/bans")
+                .insert_header((AUTHORIZATION, "Basic cml0YTpodW50ZXIy"))
+                .to_srv_request();
+            let resp = middleware
+                .call_with_password(req, password.clone())
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(called.load(Ordering::SeqCst));
+
+            // everything is let through when there is no password
+            called.store(false, Ordering::SeqCst);
+            let req = TestRequest::post().uri("/admin/bans").to_srv_request();
+            let resp = middleware.call_with_password(req, None).await.unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(called.load(Ordering::SeqCst));
+        });
     }
 }
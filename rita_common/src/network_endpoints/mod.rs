@@ -1,5 +1,7 @@
 //! Network endptoints for common Rita functionality (such as exchanging hello messages)
 
+use crate::debt_dispute::{debt_view_received, DebtDisputeError, DebtView};
+use crate::payment_channel::{channel_update_received, ChannelUpdate, PaymentChannelError};
 use crate::payment_validator::{validate_later, ToValidate};
 use crate::peer_listener::structs::Peer;
 use crate::tunnel_manager::id_callback::IdentityCallback;
@@ -57,6 +59,39 @@ pub async fn make_payments_v2(item: Json<HashSet<PaymentTx>>) -> HttpResponse {
     HttpResponse::Ok().json("Payment Received!")
 }
 
+/// The recieve side of a payment channel payment
+pub async fn make_channel_payment(item: Json<ChannelUpdate>) -> HttpResponse {
+    match channel_update_received(item.into_inner()) {
+        Ok(()) => HttpResponse::Ok().json("Payment Received!"),
+        // we respond as if the endpoint does not exist so that our neighbor pays on chain
+        Err(PaymentChannelError::Disabled) => {
+            HttpResponse::build(StatusCode::from_u16(404u16).unwrap())
+                .json("Payment channels disabled")
+        }
+        // our neighbor must settle on chain before we credit any more channel payments
+        Err(e @ PaymentChannelError::OverLimit { .. })
+        | Err(e @ PaymentChannelError::SettlementOverdue) => {
+            HttpResponse::build(StatusCode::from_u16(402u16).unwrap()).json(format!("{e}"))
+        }
+        Err(e) => HttpResponse::build(StatusCode::from_u16(400u16).unwrap()).json(format!("{e}")),
+    }
+}
+
+/// Responds to a neighbor's view of the debt between us with our own
+pub async fn debt_view(item: Json<DebtView>, req: HttpRequest) -> HttpResponse {
+    let sender = req.peer_addr().map(|s| s.ip());
+    match debt_view_received(item.into_inner(), sender) {
+        Ok(ours) => HttpResponse::Ok().json(ours),
+        Err(e @ DebtDisputeError::UnknownNeighbor) => {
+            HttpResponse::build(StatusCode::from_u16(404u16).unwrap()).json(format!("{e}"))
+        }
+        Err(e @ DebtDisputeError::NotNeighbor) | Err(e @ DebtDisputeError::InvalidSignature) => {
+            HttpResponse::build(StatusCode::from_u16(403u16).unwrap()).json(format!("{e}"))
+        }
+        Err(e) => HttpResponse::build(StatusCode::from_u16(400u16).unwrap()).json(format!("{e}")),
+    }
+}
+
 pub async fn hello_response(item: Json<LocalIdentity>, req: HttpRequest) -> HttpResponse {
     info!("In Hello response handler!!");
     let their_id = item.into_inner();
@@ -75,7 +110,7 @@ pub async fn hello_response(item: Json<LocalIdentity>, req: HttpRequest) -> Http
         ifidx: 0, // only works because we lookup ifname in kernel interface
     };
 
-    let tunnel = tm_identity_callback(IdentityCallback::new(their_id, peer, None));
+    let tunnel = tm_identity_callback(IdentityCallback::new(their_id, peer, None, None, None));
     let tunnel = match tunnel {
         Ok(val) => val,
         Err(_) => {
@@ -8,6 +8,8 @@
 //! as a bird flying through the connection rather than actual bloat. The solution here would be to also collect stats
 //! on traffic over every interface and base our action off of spikes in throughput as well as spikes in latency.
 
+use crate::link_prober::get_bandwidth_estimate;
+use crate::link_prober::get_link_shaping;
 use crate::rita_loop::fast_loop::FAST_LOOP_SPEED;
 use crate::set_to_shape;
 use crate::tunnel_manager::shaping::ShapingAdjust;
@@ -35,7 +37,7 @@ lazy_static! {
 
 /// 10 minutes in seconds, the amount of time we wait for an interface to be
 /// 'good' before we start trying to increase it's speed
-const BACK_OFF_TIME: Duration = Duration::from_secs(600);
+pub const BACK_OFF_TIME: Duration = Duration::from_secs(600);
 /// We want to reset our counters every few hours to make sure they don't
 /// become to insensitive to changes, currently 12 hours.
 const WINDOW_TIME: Duration = Duration::from_secs(43200);
@@ -111,6 +113,11 @@ pub fn get_stats() -> Stats {
     stats
 }
 
+/// The latency history of every interface babel has a neighbor on, for the shaper
+pub fn get_latency_history() -> HashMap<String, RunningLatencyStats> {
+    NETWORK_MONITOR.read().unwrap().latency_history.clone()
+}
+
 pub struct GetNetworkInfo;
 
 pub fn get_network_info(_msg: GetNetworkInfo) -> Result<NetworkInfo, RitaCommonError> {
@@ -135,23 +142,28 @@ pub fn update_network_info(msg: NetworkInfo) {
     let babel_neighbors = &msg.babel_neighbors;
     let babel_routes = &msg.babel_routes;
     let rita_neighbors = &msg.rita_neighbors;
-    observe_network(
+    let to_shape = observe_network(
         babel_neighbors,
         rita_neighbors,
         &mut network_monitor.latency_history,
         &mut network_monitor.packet_loss_history,
     );
+    // shape the misbehaving tunnels, we do this all at once for the sake
+    // of efficiency as lots of do_sends have a high chance of getting lost
+    // also there's nontrivial overhead
+    set_to_shape(to_shape);
     network_stats(babel_routes, babel_neighbors);
     network_monitor.last_babel_dump = Some(msg);
 }
 
-/// Attempts to detect bufferbloat by looking at neighbor latency over time
+/// Attempts to detect bufferbloat by looking at neighbor latency over time, returns the tunnels
+/// that should be shaped
 fn observe_network(
     babel_neighbors: &[BabelNeighbor],
     rita_neighbors: &[RitaNeighbor],
     latency_history: &mut HashMap<String, RunningLatencyStats>,
     packet_loss_history: &mut HashMap<String, RunningPacketLossStats>,
-) {
+) -> Vec<ShapingAdjust> {
     // if this assertion is failing you're running this slowly enough
     // that all the sample period logic is not relevent, go disable it
     assert_eq!(SAMPLE_PERIOD as u64, FAST_LOOP_SPEED.as_secs());
@@ -180,6 +192,7 @@ fn observe_network(
                     to_shape.push(ShapingAdjust {
                         iface: iface.to_string(),
                         action: ShapingAdjustAction::ReduceSpeed,
+                        bandwidth_estimate: get_bandwidth_estimate(iface),
                     });
                     // go see the comment in is_bloated() before you consider
                     // touching this, here there be dragons
@@ -196,6 +209,7 @@ fn observe_network(
                     to_shape.push(ShapingAdjust {
                         iface: iface.to_string(),
                         action: ShapingAdjustAction::IncreaseSpeed,
+                        bandwidth_estimate: None,
                     });
                     running_stats.set_last_changed();
                 } else {
@@ -253,10 +267,16 @@ fn observe_network(
         }
     }
 
-    // shape the misbehaving tunnels, we do this all at once for the sake
-    // of efficiency as lots of do_sends have a high chance of getting lost
-    // also there's nontrivial overhead
-    set_to_shape(to_shape);
+    // the link prober sees loss bursts and jitter in the direction we send well before babel's
+    // rtt moves, babel's verdict wins when both have something to say about a tunnel
+    for adjust in get_link_shaping() {
+        if !to_shape.iter().any(|s| s.iface == adjust.iface) {
+            info!("Link probes show {} is congested", adjust.iface);
+            to_shape.push(adjust);
+        }
+    }
+
+    to_shape
 }
 
 fn get_wg_key_by_ifname(neigh: &BabelNeighbor, rita_neighbors: &[RitaNeighbor]) -> Option<WgKey> {
@@ -309,3 +329,82 @@ fn mean(data: &[f32]) -> Option<f32> {
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel_manager::get_test_tunnel;
+    use crate::tunnel_manager::lifecycle::TunnelState;
+    use babel_monitor::subscription::BabelMonitorClient;
+    use mock_babeld::{start_babel_server, MockBabel, MockNeighbor};
+    use std::net::IpAddr;
+    use std::thread;
+
+    /// Waits for the monitor to see the neighbor's latest rtt
+    fn wait_for_rtt(client: &BabelMonitorClient, rtt: f32) -> Vec<BabelNeighbor> {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if let Ok(neighs) = client.get_neighs() {
+                if neighs.len() == 1 && neighs[0].rtt == rtt {
+                    return neighs;
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("babel monitor never saw rtt {}", rtt);
+    }
+
+    #[test]
+    fn test_observe_network_with_mock_babel() {
+        let iface = "wg77";
+        let via: IpAddr = "fe80::77".parse().unwrap();
+        let babel = MockBabel::new();
+        let mut neigh = MockNeighbor::new("1", via, iface);
+        neigh.rtt = 20.0;
+        babel.set_neighbor(neigh.clone());
+        let port = start_babel_server(babel.clone()).unwrap();
+        let client = BabelMonitorClient::start(port, Duration::from_secs(2));
+
+        let tunnel = get_test_tunnel("10.0.0.77".parse().unwrap());
+        let rita_neighbors = vec![RitaNeighbor {
+            identity: tunnel.neigh_id,
+            iface_name: iface.to_string(),
+            tunnel_ip: tunnel.ip,
+            speed_limit: None,
+            state: TunnelState::Up,
+            history: Vec::new(),
+            capabilities: None,
+        }];
+        let mut latency_history = HashMap::new();
+        let mut packet_loss_history = HashMap::new();
+        let mut observe = |rtt: f32| {
+            neigh.rtt = rtt;
+            babel.set_neighbor(neigh.clone());
+            let babel_neighbors = wait_for_rtt(&client, rtt);
+            observe_network(
+                &babel_neighbors,
+                &rita_neighbors,
+                &mut latency_history,
+                &mut packet_loss_history,
+            )
+            .into_iter()
+            .filter(|s| s.iface == iface)
+            .collect::<Vec<ShapingAdjust>>()
+        };
+
+        // a steady link is left alone
+        for _ in 0..3 {
+            assert!(observe(20.0).is_empty());
+        }
+        // the verdict is reached on the tick after the spike
+        assert!(observe(2000.0).is_empty());
+        let to_shape = observe(20.0);
+        assert_eq!(to_shape.len(), 1);
+        assert_eq!(to_shape[0].action, ShapingAdjustAction::ReduceSpeed);
+
+        // the history was reset when the link was declared bloated
+        assert_eq!(latency_history[iface].samples(), 1);
+        assert_eq!(latency_history[iface].get_lowest(), Some(20.0));
+        assert!(packet_loss_history.contains_key(iface));
+    }
+}
@@ -0,0 +1,424 @@
+//! Payments on Althea chain, or any other Cosmos chain with the bank module, over gRPC. A single payment
+//! is sent as a microtx, many payments are sent as a transaction with one MsgSend per payment.
+
+use super::ChainTransaction;
+use super::PaymentAddress;
+use super::PaymentBackend;
+use super::PaymentBackendError;
+use super::SignedTransaction;
+use super::TransactionDetails;
+use super::TxStatus;
+use crate::debt_keeper::get_settlement_denom;
+use crate::payment_validator::{ALTHEA_CHAIN_PREFIX, ALTHEA_CONTACT_TIMEOUT};
+use althea_proto::microtx::v1::MsgMicrotx;
+use althea_types::Denom;
+use althea_types::Identity;
+use althea_types::UnpublishedPaymentTx;
+use async_trait::async_trait;
+use clarity::PrivateKey;
+use cosmos_sdk_proto_althea::cosmos::bank::v1beta1::MsgSend;
+use cosmos_sdk_proto_althea::cosmos::tx::v1beta1::{BroadcastMode, TxBody, TxRaw};
+use deep_space::client::type_urls::{MSG_MICROTX_TYPE_URL, MSG_SEND_TYPE_URL};
+use deep_space::client::ChainStatus;
+use deep_space::client::MEMO;
+use deep_space::error::CosmosGrpcError;
+use deep_space::utils::decode_any;
+use deep_space::PrivateKey as _;
+use deep_space::{Coin, Contact, EthermintPrivateKey, Msg};
+use futures::future::join;
+use num256::Uint256;
+use num_traits::Num;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+use tonic::Code;
+
+pub struct CosmosBackend {
+    grpc: String,
+}
+
+impl CosmosBackend {
+    pub fn new(grpc: String) -> CosmosBackend {
+        CosmosBackend { grpc }
+    }
+
+    fn contact(&self) -> Result<Contact, PaymentBackendError> {
+        Contact::new(&self.grpc, ALTHEA_CONTACT_TIMEOUT, ALTHEA_CHAIN_PREFIX)
+            .map_err(|e| PaymentBackendError::FullNodeError(e.to_string()))
+    }
+}
+
+#[async_trait(?Send)]
+impl PaymentBackend for CosmosBackend {
+    fn full_node(&self) -> String {
+        self.grpc.clone()
+    }
+
+    fn address(&self, id: &Identity) -> PaymentAddress {
+        PaymentAddress::Althea(id.get_althea_address())
+    }
+
+    fn settlement_denom(&self, to: &Identity) -> Option<Denom> {
+        get_settlement_denom(to)
+    }
+
+    fn accepted_denom(&self, denom: &str) -> Option<Denom> {
+        settings::get_rita_common()
+            .payment
+            .accepted_denoms
+            .unwrap_or_default()
+            .into_values()
+            .find(|d| d.denom == denom)
+    }
+
+    fn supports_batching(&self) -> bool {
+        true
+    }
+
+    /// Althea chain has instant finality, a transaction in a block stays there
+    fn can_reorg(&self) -> bool {
+        false
+    }
+
+    async fn sign(
+        &self,
+        payments: &[(UnpublishedPaymentTx, Denom)],
+        key: PrivateKey,
+    ) -> Result<SignedTransaction, PaymentBackendError> {
+        // our althea private key is generated from our eth private key
+        let our_private_key: EthermintPrivateKey = key.into();
+        let contact = self.contact()?;
+
+        let (messages, our_address) = match payments {
+            [] => {
+                return Err(PaymentBackendError::FullNodeError(
+                    "No payments".to_string(),
+                ))
+            }
+            [(pmt, denom)] => {
+                let send = MsgMicrotx {
+                    sender: pmt
+                        .from
+                        .get_althea_address()
+                        .to_bech32(ALTHEA_CHAIN_PREFIX)
+                        .unwrap(),
+                    receiver: pmt
+                        .to
+                        .get_althea_address()
+                        .to_bech32(ALTHEA_CHAIN_PREFIX)
+                        .unwrap(),
+                    amount: Some(
+                        Coin {
+                            amount: pmt.amount,
+                            denom: denom.denom.clone(),
+                        }
+                        .into(),
+                    ),
+                };
+                (
+                    vec![Msg::new(MSG_MICROTX_TYPE_URL, send)],
+                    pmt.from.get_althea_address(),
+                )
+            }
+            [(first, _), ..] => {
+                let mut messages = Vec::new();
+                for (pmt, denom) in payments {
+                    let send = MsgSend {
+                        amount: vec![Coin {
+                            amount: pmt.amount,
+                            denom: denom.denom.clone(),
+                        }
+                        .into()],
+                        from_address: pmt
+                            .from
+                            .get_althea_address()
+                            .to_bech32(ALTHEA_CHAIN_PREFIX)
+                            .unwrap(),
+                        to_address: pmt
+                            .to
+                            .get_althea_address()
+                            .to_bech32(ALTHEA_CHAIN_PREFIX)
+                            .unwrap(),
+                    };
+                    messages.push(Msg::new(MSG_SEND_TYPE_URL, send));
+                }
+                (messages, first.from.get_althea_address())
+            }
+        };
+
+        let fee = contact
+            .get_fee_info(&messages, &[Coin::default()], our_private_key)
+            .await
+            .map_err(|e| PaymentBackendError::FullNodeError(e.to_string()))?;
+        let args = contact
+            .get_message_args(our_address, fee)
+            .await
+            .map_err(|e| PaymentBackendError::FullNodeError(e.to_string()))?;
+        let bytes = our_private_key
+            .sign_std_msg(&messages, args, MEMO)
+            .map_err(|e| PaymentBackendError::InvalidTransaction(e.to_string()))?;
+
+        // the txhash is the sha256 of the signed tx bytes
+        Ok(SignedTransaction {
+            txid: Uint256::from_be_bytes(&Sha256::digest(&bytes)),
+            bytes,
+        })
+    }
+
+    async fn publish(&self, tx: SignedTransaction) -> Result<(), PaymentBackendError> {
+        let contact = self.contact()?;
+        let response = contact
+            .send_transaction(tx.bytes, BroadcastMode::Sync)
+            .await
+            .map_err(|e| PaymentBackendError::FullNodeError(e.to_string()))?;
+        match contact.wait_for_tx(response, Duration::from_secs(30)).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(PaymentBackendError::FullNodeError(e.to_string())),
+        }
+    }
+
+    async fn validate(&self, txid: Uint256) -> Result<TxStatus, PaymentBackendError> {
+        let contact = self.contact()?;
+        // convert to hex string
+        let txhash = txid.to_str_radix(16);
+
+        let (transaction, chain_status) =
+            join(contact.get_tx_by_hash(txhash), contact.get_chain_status()).await;
+        match (transaction, chain_status) {
+            (Ok(transaction), chain_status) => {
+                let tx_resp = match (transaction.tx_response, &chain_status) {
+                    (Some(tx_resp), _) => tx_resp,
+                    (None, Ok(_)) => return Ok(TxStatus::NotFound),
+                    (None, Err(e)) => {
+                        return Err(PaymentBackendError::FullNodeError(e.to_string()))
+                    }
+                };
+                let tx = match tx_resp.tx {
+                    Some(a) => a.value,
+                    None => {
+                        return Err(PaymentBackendError::InvalidTransaction(format!(
+                            "Althea chain tx {tx_resp:?} has no tx field?"
+                        )))
+                    }
+                };
+                let confirmed = match chain_status {
+                    Ok(ChainStatus::Moving { .. }) => true,
+                    status => {
+                        error!(
+                            "Unable to check transaction id {:#066x} because of chain status {:?}",
+                            txid, status
+                        );
+                        false
+                    }
+                };
+                Ok(TxStatus::Found(ChainTransaction {
+                    transfers: decode_transfers(tx)?,
+                    confirmed,
+                    too_old: false,
+                    // the tx service only returns transactions that are in a block
+                    pending: false,
+                }))
+            }
+            // the full node is up and tells us it does not know of this tx
+            (Err(CosmosGrpcError::RequestError { error }), Ok(_))
+                if error.code() == Code::NotFound =>
+            {
+                Ok(TxStatus::NotFound)
+            }
+            // we get an error from the full node but a successful block request, clearly we can contact
+            // the full node so the transaction check has been attempted, but we don't know that the
+            // transaction isn't there
+            (Err(e), Ok(_)) => Err(PaymentBackendError::TxLookupError(e.to_string())),
+            (Err(e), Err(_)) => Err(PaymentBackendError::FullNodeError(e.to_string())),
+        }
+    }
+
+    async fn get_balance(
+        &self,
+        id: &Identity,
+        denom: &Denom,
+    ) -> Result<Option<Uint256>, PaymentBackendError> {
+        match self
+            .contact()?
+            .get_balance(id.get_althea_address(), denom.denom.clone())
+            .await
+        {
+            Ok(balance) => Ok(balance.map(|c| c.amount)),
+            Err(e) => Err(PaymentBackendError::FullNodeError(e.to_string())),
+        }
+    }
+
+    async fn get_nonce(&self, id: &Identity) -> Result<Uint256, PaymentBackendError> {
+        match self
+            .contact()?
+            .get_account_info(id.get_althea_address())
+            .await
+        {
+            Ok(account) => Ok(account.sequence.into()),
+            Err(e) => Err(PaymentBackendError::FullNodeError(e.to_string())),
+        }
+    }
+
+    async fn get_gas_price(&self) -> Result<Uint256, PaymentBackendError> {
+        // Althea chain payments are sent without a fee
+        Ok(0u8.into())
+    }
+}
+
+/// Decodes every bank transfer in a raw transaction
+fn decode_transfers(tx: Vec<u8>) -> Result<Vec<TransactionDetails>, PaymentBackendError> {
+    // Decode TxRaw
+    let raw_tx_any = prost_types::Any {
+        type_url: "/cosmos.tx.v1beta1.Tx".to_string(),
+        value: tx,
+    };
+    let tx_raw: TxRaw = decode_any(raw_tx_any).map_err(|e| {
+        PaymentBackendError::InvalidTransaction(format!("Unable to decode raw_tx with {e}"))
+    })?;
+
+    // Decode TxBody
+    let body_any = prost_types::Any {
+        type_url: "/cosmos.tx.v1beta1.TxBody".to_string(),
+        value: tx_raw.body_bytes,
+    };
+    let tx_body: TxBody = decode_any(body_any).map_err(|e| {
+        PaymentBackendError::InvalidTransaction(format!("Unable to decode body_any with {e}"))
+    })?;
+
+    // Decode each MsgSend, a batched transaction may contain many
+    let mut transfers = Vec::new();
+    for message in tx_body.messages {
+        let msg_send = prost_types::Any {
+            type_url: "/cosmos.bank.v1beta1.MsgSend".to_string(),
+            value: message.value.clone(),
+        };
+        let msg_send: Result<MsgSend, _> = decode_any(msg_send);
+        if let Ok(msg) = msg_send {
+            for coin_tx in msg.amount {
+                let to = match msg.to_address.parse() {
+                    Ok(a) => a,
+                    Err(e) => {
+                        error!("Unable to parse send address {} with {}", msg.to_address, e);
+                        continue;
+                    }
+                };
+                let from = match msg.from_address.parse() {
+                    Ok(a) => a,
+                    Err(e) => {
+                        error!(
+                            "Unable to parse send address {} with {}",
+                            msg.from_address, e
+                        );
+                        continue;
+                    }
+                };
+                let amount = match Uint256::from_str_radix(&coin_tx.amount, 10) {
+                    Ok(a) => a,
+                    Err(e) => {
+                        error!("Unable to parse amount : {:?} with {}", coin_tx.amount, e);
+                        continue;
+                    }
+                };
+                transfers.push(TransactionDetails {
+                    to: PaymentAddress::Althea(to),
+                    from: PaymentAddress::Althea(from),
+                    amount,
+                    denom: coin_tx.denom,
+                });
+            }
+        }
+    }
+    Ok(transfers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage_tracker::tests::test::random_identity;
+    use actix_async::System;
+    use mock_chain::{start_cosmos_server, Account, MockChain, Transfer};
+
+    fn get_account(id: &Identity) -> Account {
+        Account::Cosmos(
+            id.get_althea_address()
+                .to_bech32(ALTHEA_CHAIN_PREFIX)
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_validate_against_mock_chain() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let chain = MockChain::new();
+            let backend = CosmosBackend::new(start_cosmos_server(chain.clone()).unwrap());
+            let a = random_identity();
+            let b = random_identity();
+            let c = random_identity();
+            let denom = Denom {
+                denom: "aalthea".to_string(),
+                decimal: 18,
+            };
+            chain.set_balance(get_account(&a), &denom.denom, 100u8.into());
+            let transfer = |to: &Identity, amount: u8| Transfer {
+                from: get_account(&a),
+                to: get_account(to),
+                amount: amount.into(),
+                denom: denom.denom.clone(),
+            };
+            let txid = chain.submit_cosmos_transfers(vec![transfer(&b, 60), transfer(&c, 30)]);
+
+            // not in a block yet
+            assert_eq!(backend.validate(txid).await.unwrap(), TxStatus::NotFound);
+
+            chain.mine_block();
+            let tx = match backend.validate(txid).await.unwrap() {
+                TxStatus::Found(tx) => tx,
+                TxStatus::NotFound => panic!("Tx not found"),
+            };
+            assert!(tx.confirmed);
+            assert_eq!(tx.transfers.len(), 2);
+            assert_eq!(
+                tx.transfers[0],
+                TransactionDetails {
+                    to: PaymentAddress::Althea(b.get_althea_address()),
+                    from: PaymentAddress::Althea(a.get_althea_address()),
+                    amount: 60u8.into(),
+                    denom: denom.denom.clone(),
+                }
+            );
+            assert_eq!(
+                backend.get_balance(&c, &denom).await.unwrap(),
+                Some(30u8.into())
+            );
+            assert_eq!(backend.get_nonce(&a).await.unwrap(), 1u8.into());
+
+            // we can't trust a syncing node to have the whole chain
+            chain.set_syncing(true);
+            match backend.validate(txid).await.unwrap() {
+                TxStatus::Found(tx) => assert!(!tx.confirmed),
+                TxStatus::NotFound => panic!("Tx not found"),
+            }
+        });
+    }
+
+    #[ignore]
+    #[test]
+    fn test_althea_chain_response() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let contact =
+                Contact::new("http://althea.zone:9090", ALTHEA_CONTACT_TIMEOUT, "althea").unwrap();
+
+            let tx = contact
+                .get_tx_by_hash(
+                    "B0943ECCC5565A39D021EE815A82006B01FC87A9BED4EBDD0A448AC161007FF0".to_string(),
+                )
+                .await
+                .expect("Unable to get tx by hash");
+            println!("{:?}", tx.tx_response.clone().unwrap().tx);
+
+            let transfers = decode_transfers(tx.tx_response.unwrap().tx.unwrap().value).unwrap();
+            println!("{:?}", transfers);
+        });
+    }
+}
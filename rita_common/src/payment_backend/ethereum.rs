@@ -0,0 +1,374 @@
+//! Payments on xDai, or any other EVM chain, using web3 json rpc. Payments are sent in the native token
+//! which is also the debt keeper denom. Nonce and gas price are taken from the blockchain oracle so that
+//! many transactions can be sent before the first one enters a block
+
+use super::ChainTransaction;
+use super::PaymentAddress;
+use super::PaymentBackend;
+use super::PaymentBackendError;
+use super::SignedTransaction;
+use super::TransactionDetails;
+use super::TxStatus;
+use crate::blockchain_oracle::{get_oracle_latest_gas_price, get_oracle_nonce, set_oracle_nonce};
+use crate::payment_controller::TRANSACTION_SUBMISSION_TIMEOUT;
+use crate::payment_validator::TRANSACTION_VERIFICATION_TIMEOUT;
+use althea_types::Denom;
+use althea_types::Identity;
+use althea_types::UnpublishedPaymentTx;
+use async_trait::async_trait;
+use clarity::PrivateKey;
+use clarity::Transaction;
+use futures::future::join;
+use num256::Uint256;
+use settings::{DEBT_KEEPER_DENOM, DEBT_KEEPER_DENOM_DECIMAL};
+use web30::client::Web3;
+use web30::types::{TransactionRequest, TransactionResponse};
+
+/// How many blocks before we assume finality
+const BLOCKS_TO_CONFIRM: u32 = 4;
+/// How old does a txid need to be before we don't accept it?
+/// this is 12 hours
+const BLOCKS_TO_OLD: u32 = 1440;
+
+pub struct EthereumBackend {
+    full_node: String,
+}
+
+impl EthereumBackend {
+    pub fn new(full_node: String) -> EthereumBackend {
+        EthereumBackend { full_node }
+    }
+}
+
+fn get_native_denom() -> Denom {
+    Denom {
+        denom: DEBT_KEEPER_DENOM.to_string(),
+        decimal: DEBT_KEEPER_DENOM_DECIMAL,
+    }
+}
+
+#[async_trait(?Send)]
+impl PaymentBackend for EthereumBackend {
+    fn full_node(&self) -> String {
+        self.full_node.clone()
+    }
+
+    fn address(&self, id: &Identity) -> PaymentAddress {
+        PaymentAddress::Xdai(id.eth_address)
+    }
+
+    fn settlement_denom(&self, _to: &Identity) -> Option<Denom> {
+        Some(get_native_denom())
+    }
+
+    fn accepted_denom(&self, denom: &str) -> Option<Denom> {
+        if denom == DEBT_KEEPER_DENOM {
+            Some(get_native_denom())
+        } else {
+            None
+        }
+    }
+
+    async fn sign(
+        &self,
+        payments: &[(UnpublishedPaymentTx, Denom)],
+        key: PrivateKey,
+    ) -> Result<SignedTransaction, PaymentBackendError> {
+        let pmt = match payments {
+            [(pmt, _)] => pmt,
+            _ => return Err(PaymentBackendError::BatchingUnsupported),
+        };
+        let nonce = get_oracle_nonce();
+        let gas_price = get_oracle_latest_gas_price();
+        info!(
+            "Signing payment of {:?} from address {} to address {} with nonce {}",
+            pmt.amount,
+            key.to_address(),
+            pmt.to.eth_address,
+            nonce
+        );
+
+        let web3 = Web3::new(&self.full_node, TRANSACTION_SUBMISSION_TIMEOUT);
+        let chain_id = web3
+            .net_version()
+            .await
+            .map_err(|e| PaymentBackendError::FullNodeError(e.to_string()))?;
+        let mut transaction = Transaction::Eip1559 {
+            chain_id: chain_id.into(),
+            nonce,
+            max_priority_fee_per_gas: 1u8.into(),
+            max_fee_per_gas: gas_price,
+            gas_limit: 0u8.into(),
+            to: pmt.to.eth_address,
+            value: pmt.amount,
+            data: Vec::new(),
+            signature: None,
+            access_list: Vec::new(),
+        };
+        let gas_limit = web3
+            .eth_estimate_gas(TransactionRequest::from_transaction(
+                &transaction,
+                key.to_address(),
+            ))
+            .await
+            .map_err(|e| PaymentBackendError::FullNodeError(e.to_string()))?;
+        transaction.set_gas_limit(gas_limit);
+        let transaction = transaction.sign(&key, None);
+
+        Ok(SignedTransaction {
+            txid: Uint256::from_be_bytes(&transaction.hash()),
+            bytes: transaction.to_bytes(),
+        })
+    }
+
+    async fn publish(&self, tx: SignedTransaction) -> Result<(), PaymentBackendError> {
+        let web3 = Web3::new(&self.full_node, TRANSACTION_SUBMISSION_TIMEOUT);
+        web3.eth_send_raw_transaction(tx.bytes)
+            .await
+            .map_err(|e| PaymentBackendError::FullNodeError(e.to_string()))?;
+
+        // increment our nonce, this allows us to send another transaction
+        // right away before this one that we just sent out gets into the chain
+        set_oracle_nonce(get_oracle_nonce() + 1u64.into());
+
+        Ok(())
+    }
+
+    async fn validate(&self, txid: Uint256) -> Result<TxStatus, PaymentBackendError> {
+        let web3 = Web3::new(&self.full_node, TRANSACTION_VERIFICATION_TIMEOUT);
+        let (transaction, block_num) = join(
+            web3.eth_get_transaction_by_hash(txid),
+            web3.eth_block_number(),
+        )
+        .await;
+        match (transaction, block_num) {
+            (Ok(Some(transaction)), Ok(block_num)) => {
+                let (to, from, value, block_number) = get_xdai_transaction_details(transaction);
+                let mut transfers = Vec::new();
+                match to {
+                    Some(to) => transfers.push(TransactionDetails {
+                        to: PaymentAddress::Xdai(to),
+                        from: PaymentAddress::Xdai(from),
+                        amount: value,
+                        denom: DEBT_KEEPER_DENOM.to_string(),
+                    }),
+                    None => error!("Invalid TX {:#066x}! No destination!", txid),
+                }
+                Ok(TxStatus::Found(ChainTransaction {
+                    transfers,
+                    confirmed: payment_in_chain(block_num, block_number),
+                    too_old: payment_is_old(block_num, block_number),
+                    pending: block_number.is_none(),
+                }))
+            }
+            // we have a response back from the full node that this tx is not in the mempool
+            (Ok(None), _) => Ok(TxStatus::NotFound),
+            // we get an error from the full node but a successful block request, clearly we can contact
+            // the full node so the transaction check has been attempted, but we don't know that the
+            // transaction isn't there
+            (Err(e), Ok(_)) => Err(PaymentBackendError::TxLookupError(e.to_string())),
+            (Ok(Some(_)), Err(e)) | (Err(_), Err(e)) => {
+                Err(PaymentBackendError::FullNodeError(e.to_string()))
+            }
+        }
+    }
+
+    async fn get_balance(
+        &self,
+        id: &Identity,
+        _denom: &Denom,
+    ) -> Result<Option<Uint256>, PaymentBackendError> {
+        let web3 = Web3::new(&self.full_node, TRANSACTION_VERIFICATION_TIMEOUT);
+        match web3.eth_get_balance(id.eth_address).await {
+            Ok(balance) => Ok(Some(balance)),
+            Err(e) => Err(PaymentBackendError::FullNodeError(e.to_string())),
+        }
+    }
+
+    async fn get_nonce(&self, id: &Identity) -> Result<Uint256, PaymentBackendError> {
+        let web3 = Web3::new(&self.full_node, TRANSACTION_VERIFICATION_TIMEOUT);
+        web3.eth_get_transaction_count(id.eth_address)
+            .await
+            .map_err(|e| PaymentBackendError::FullNodeError(e.to_string()))
+    }
+
+    async fn get_gas_price(&self) -> Result<Uint256, PaymentBackendError> {
+        let web3 = Web3::new(&self.full_node, TRANSACTION_VERIFICATION_TIMEOUT);
+        web3.eth_gas_price()
+            .await
+            .map_err(|e| PaymentBackendError::FullNodeError(e.to_string()))
+    }
+}
+
+fn get_xdai_transaction_details(
+    transaction: TransactionResponse,
+) -> (
+    Option<clarity::Address>,
+    clarity::Address,
+    Uint256,
+    Option<Uint256>,
+) {
+    match transaction {
+        TransactionResponse::Eip1559 {
+            to,
+            from,
+            value,
+            block_number,
+            ..
+        } => (to, from, value, block_number),
+        TransactionResponse::Eip2930 {
+            to,
+            from,
+            value,
+            block_number,
+            ..
+        } => (to, from, value, block_number),
+        TransactionResponse::Legacy {
+            to,
+            from,
+            value,
+            block_number,
+            ..
+        } => (to, from, value, block_number),
+    }
+}
+
+/// Determine if a given payment satisfies our criteria for being in the blockchain
+fn payment_in_chain(chain_height: Uint256, tx_height: Option<Uint256>) -> bool {
+    match tx_height {
+        Some(tx_block) => {
+            // somehow the block is newer than our block height request, wait until later
+            if tx_block > chain_height {
+                false
+            } else {
+                chain_height - tx_block >= Uint256::from(BLOCKS_TO_CONFIRM)
+            }
+        }
+        None => false,
+    }
+}
+
+/// Determine if a given payment is older than what we shoul accept
+fn payment_is_old(chain_height: Uint256, tx_height: Option<Uint256>) -> bool {
+    match tx_height {
+        Some(tx_block) => {
+            // somehow the block is newer than our block height request, wait until later
+            if tx_block > chain_height {
+                false
+            } else {
+                chain_height - tx_block > Uint256::from(BLOCKS_TO_OLD)
+            }
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage_tracker::tests::test::random_identity;
+    use actix_async::System;
+    use mock_chain::{start_ethereum_server, Account, MockChain, ETH_DENOM};
+
+    async fn get_transaction(backend: &EthereumBackend, txid: Uint256) -> ChainTransaction {
+        match backend.validate(txid).await.unwrap() {
+            TxStatus::Found(tx) => tx,
+            TxStatus::NotFound => panic!("Tx {:#066x} not found", txid),
+        }
+    }
+
+    #[test]
+    fn test_validate_against_mock_chain() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let chain = MockChain::new();
+            let backend = EthereumBackend::new(start_ethereum_server(chain.clone()).unwrap());
+            let a = random_identity();
+            let b = random_identity();
+            chain.set_balance(Account::Eth(a.eth_address), ETH_DENOM, 100u8.into());
+            assert_eq!(
+                backend.get_balance(&a, &get_native_denom()).await.unwrap(),
+                Some(100u8.into())
+            );
+
+            // in the mempool
+            let txid = chain.submit_eth_transfer(a.eth_address, b.eth_address, 60u8.into());
+            assert!(!get_transaction(&backend, txid).await.confirmed);
+
+            // included in the first block but not yet deep enough
+            chain.mine_blocks(BLOCKS_TO_CONFIRM.into());
+            assert!(!get_transaction(&backend, txid).await.confirmed);
+
+            chain.mine_block();
+            let tx = get_transaction(&backend, txid).await;
+            assert!(tx.confirmed);
+            assert!(!tx.too_old);
+            assert_eq!(
+                tx.transfers,
+                vec![TransactionDetails {
+                    to: PaymentAddress::Xdai(b.eth_address),
+                    from: PaymentAddress::Xdai(a.eth_address),
+                    amount: 60u8.into(),
+                    denom: DEBT_KEEPER_DENOM.to_string(),
+                }]
+            );
+            assert_eq!(backend.get_nonce(&a).await.unwrap(), 1u8.into());
+
+            // a reorg returns the tx to the mempool, where it may be dropped
+            chain.reorg(u64::from(BLOCKS_TO_CONFIRM) + 1);
+            assert!(!get_transaction(&backend, txid).await.confirmed);
+            assert!(chain.drop_transaction(txid));
+            assert_eq!(backend.validate(txid).await.unwrap(), TxStatus::NotFound);
+
+            let txid = chain.submit_eth_transfer(a.eth_address, b.eth_address, 10u8.into());
+            chain.mine_blocks(u64::from(BLOCKS_TO_OLD) + 1);
+            assert!(!get_transaction(&backend, txid).await.too_old);
+            chain.mine_block();
+            assert!(get_transaction(&backend, txid).await.too_old);
+        });
+    }
+
+    #[test]
+    /// The txid of a signed transaction is known before it is published
+    fn test_sign_and_publish_against_mock_chain() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let chain = MockChain::new();
+            let backend = EthereumBackend::new(start_ethereum_server(chain.clone()).unwrap());
+            let key: PrivateKey =
+                "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f1e"
+                    .parse()
+                    .unwrap();
+            let mut a = random_identity();
+            a.eth_address = key.to_address();
+            let b = random_identity();
+            chain.set_balance(Account::Eth(a.eth_address), ETH_DENOM, 100u8.into());
+            let payments = vec![(
+                UnpublishedPaymentTx {
+                    to: b,
+                    from: a,
+                    amount: 60u8.into(),
+                },
+                get_native_denom(),
+            )];
+
+            let tx = backend.sign(&payments, key).await.unwrap();
+            let txid = tx.txid;
+            assert_eq!(backend.validate(txid).await.unwrap(), TxStatus::NotFound);
+
+            backend.publish(tx).await.unwrap();
+            chain.mine_blocks(u64::from(BLOCKS_TO_CONFIRM) + 1);
+            let tx = get_transaction(&backend, txid).await;
+            assert!(tx.confirmed);
+            assert_eq!(
+                tx.transfers,
+                vec![TransactionDetails {
+                    to: PaymentAddress::Xdai(b.eth_address),
+                    from: PaymentAddress::Xdai(a.eth_address),
+                    amount: 60u8.into(),
+                    denom: DEBT_KEEPER_DENOM.to_string(),
+                }]
+            );
+        });
+    }
+}
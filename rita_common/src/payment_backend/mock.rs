@@ -0,0 +1,359 @@
+//! An in memory chain for unit testing payment code. Every clone of a MockBackend shares the same
+//! chain, so a test can hand one clone to the code under test and use another to script balances,
+//! confirm transactions or take the full node offline.
+
+use super::ChainTransaction;
+use super::PaymentAddress;
+use super::PaymentBackend;
+use super::PaymentBackendError;
+use super::SignedTransaction;
+use super::TransactionDetails;
+use super::TxStatus;
+use althea_types::Denom;
+use althea_types::Identity;
+use althea_types::UnpublishedPaymentTx;
+use async_trait::async_trait;
+use clarity::PrivateKey;
+use num256::Uint256;
+use settings::{DEBT_KEEPER_DENOM, DEBT_KEEPER_DENOM_DECIMAL};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+#[derive(Debug, Default)]
+struct MockChain {
+    balances: HashMap<(PaymentAddress, String), Uint256>,
+    nonces: HashMap<PaymentAddress, Uint256>,
+    transactions: HashMap<Uint256, ChainTransaction>,
+    /// Transactions that have been signed but not yet published
+    signed: HashMap<Uint256, Vec<(UnpublishedPaymentTx, Denom)>>,
+    gas_price: Uint256,
+    next_txid: u64,
+    /// When false new transactions are left unconfirmed until confirm_all is called
+    auto_confirm: bool,
+    /// When true every request fails as if the full node could not be reached
+    offline: bool,
+    /// When true the full node is reachable but every transaction lookup fails
+    lookups_failing: bool,
+    /// When true transactions can't be removed by a reorg, like on Althea chain
+    instant_finality: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MockBackend {
+    chain: Arc<RwLock<MockChain>>,
+}
+
+fn get_mock_denom() -> Denom {
+    Denom {
+        denom: DEBT_KEEPER_DENOM.to_string(),
+        decimal: DEBT_KEEPER_DENOM_DECIMAL,
+    }
+}
+
+impl MockBackend {
+    pub fn new() -> MockBackend {
+        let backend = MockBackend::default();
+        backend.chain.write().unwrap().auto_confirm = true;
+        backend
+    }
+
+    pub fn set_balance(&self, id: &Identity, amount: Uint256) {
+        let address = self.address(id);
+        self.chain
+            .write()
+            .unwrap()
+            .balances
+            .insert((address, DEBT_KEEPER_DENOM.to_string()), amount);
+    }
+
+    pub fn set_gas_price(&self, gas_price: Uint256) {
+        self.chain.write().unwrap().gas_price = gas_price;
+    }
+
+    pub fn set_auto_confirm(&self, auto_confirm: bool) {
+        self.chain.write().unwrap().auto_confirm = auto_confirm;
+    }
+
+    pub fn set_offline(&self, offline: bool) {
+        self.chain.write().unwrap().offline = offline;
+    }
+
+    pub fn set_lookups_failing(&self, lookups_failing: bool) {
+        self.chain.write().unwrap().lookups_failing = lookups_failing;
+    }
+
+    pub fn set_instant_finality(&self, instant_finality: bool) {
+        self.chain.write().unwrap().instant_finality = instant_finality;
+    }
+
+    /// Confirms every transaction sent so far
+    pub fn confirm_all(&self) {
+        for tx in self.chain.write().unwrap().transactions.values_mut() {
+            tx.confirmed = true;
+        }
+    }
+
+    /// Adds a transaction directly to the chain, for example a payment from a neighbor
+    pub fn insert_transaction(&self, txid: Uint256, transaction: ChainTransaction) {
+        self.chain
+            .write()
+            .unwrap()
+            .transactions
+            .insert(txid, transaction);
+    }
+
+    pub fn get_transaction(&self, txid: Uint256) -> Option<ChainTransaction> {
+        self.chain.read().unwrap().transactions.get(&txid).cloned()
+    }
+
+    fn check_online(&self) -> Result<(), PaymentBackendError> {
+        if self.chain.read().unwrap().offline {
+            Err(PaymentBackendError::FullNodeError(
+                "Mock full node offline".to_string(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl PaymentBackend for MockBackend {
+    fn full_node(&self) -> String {
+        "mock".to_string()
+    }
+
+    fn address(&self, id: &Identity) -> PaymentAddress {
+        PaymentAddress::Xdai(id.eth_address)
+    }
+
+    fn settlement_denom(&self, _to: &Identity) -> Option<Denom> {
+        Some(get_mock_denom())
+    }
+
+    fn accepted_denom(&self, denom: &str) -> Option<Denom> {
+        if denom == DEBT_KEEPER_DENOM {
+            Some(get_mock_denom())
+        } else {
+            None
+        }
+    }
+
+    fn supports_batching(&self) -> bool {
+        true
+    }
+
+    fn can_reorg(&self) -> bool {
+        !self.chain.read().unwrap().instant_finality
+    }
+
+    async fn sign(
+        &self,
+        payments: &[(UnpublishedPaymentTx, Denom)],
+        _key: PrivateKey,
+    ) -> Result<SignedTransaction, PaymentBackendError> {
+        self.check_online()?;
+        let mut chain = self.chain.write().unwrap();
+        chain.next_txid += 1;
+        let txid: Uint256 = chain.next_txid.into();
+        chain.signed.insert(txid, payments.to_vec());
+        Ok(SignedTransaction {
+            txid,
+            bytes: Vec::new(),
+        })
+    }
+
+    async fn publish(&self, tx: SignedTransaction) -> Result<(), PaymentBackendError> {
+        self.check_online()?;
+        let mut chain = self.chain.write().unwrap();
+        let payments = match chain.signed.remove(&tx.txid) {
+            Some(payments) => payments,
+            None => {
+                return Err(PaymentBackendError::InvalidTransaction(format!(
+                    "Unknown transaction {}",
+                    tx.txid
+                )))
+            }
+        };
+
+        // check every balance before moving anything so that a failed tx has no effect
+        let mut totals: HashMap<(PaymentAddress, String), Uint256> = HashMap::new();
+        for (pmt, denom) in payments.iter() {
+            *totals
+                .entry((self.address(&pmt.from), denom.denom.clone()))
+                .or_default() += pmt.amount;
+        }
+        for (key, total) in totals.iter() {
+            let balance = chain.balances.get(key).cloned().unwrap_or_default();
+            if balance < *total {
+                return Err(PaymentBackendError::FullNodeError(format!(
+                    "Insufficient funds {balance} < {total}"
+                )));
+            }
+        }
+
+        let mut transfers = Vec::new();
+        for (pmt, denom) in payments.iter() {
+            let from = self.address(&pmt.from);
+            let to = self.address(&pmt.to);
+            *chain
+                .balances
+                .entry((from.clone(), denom.denom.clone()))
+                .or_default() -= pmt.amount;
+            *chain
+                .balances
+                .entry((to.clone(), denom.denom.clone()))
+                .or_default() += pmt.amount;
+            transfers.push(TransactionDetails {
+                to,
+                from,
+                amount: pmt.amount,
+                denom: denom.denom.clone(),
+            });
+        }
+        if let Some((pmt, _)) = payments.first() {
+            *chain.nonces.entry(self.address(&pmt.from)).or_default() += 1u8.into();
+        }
+
+        let confirmed = chain.auto_confirm;
+        chain.transactions.insert(
+            tx.txid,
+            ChainTransaction {
+                transfers,
+                confirmed,
+                too_old: false,
+                pending: false,
+            },
+        );
+        Ok(())
+    }
+
+    async fn validate(&self, txid: Uint256) -> Result<TxStatus, PaymentBackendError> {
+        self.check_online()?;
+        if self.chain.read().unwrap().lookups_failing {
+            return Err(PaymentBackendError::TxLookupError(
+                "Mock transaction lookup failed".to_string(),
+            ));
+        }
+        match self.get_transaction(txid) {
+            Some(tx) => Ok(TxStatus::Found(tx)),
+            None => Ok(TxStatus::NotFound),
+        }
+    }
+
+    async fn get_balance(
+        &self,
+        id: &Identity,
+        denom: &Denom,
+    ) -> Result<Option<Uint256>, PaymentBackendError> {
+        self.check_online()?;
+        Ok(self
+            .chain
+            .read()
+            .unwrap()
+            .balances
+            .get(&(self.address(id), denom.denom.clone()))
+            .cloned())
+    }
+
+    async fn get_nonce(&self, id: &Identity) -> Result<Uint256, PaymentBackendError> {
+        self.check_online()?;
+        Ok(self
+            .chain
+            .read()
+            .unwrap()
+            .nonces
+            .get(&self.address(id))
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn get_gas_price(&self) -> Result<Uint256, PaymentBackendError> {
+        self.check_online()?;
+        Ok(self.chain.read().unwrap().gas_price)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage_tracker::tests::test::random_identity;
+    use actix_async::System;
+
+    fn get_test_key() -> PrivateKey {
+        "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f1e"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_mock_backend_send_and_validate() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let backend = MockBackend::new();
+            let a = random_identity();
+            let b = random_identity();
+            let c = random_identity();
+            backend.set_balance(&a, 100u32.into());
+            backend.set_auto_confirm(false);
+
+            let payments = vec![
+                (
+                    UnpublishedPaymentTx {
+                        to: b,
+                        from: a,
+                        amount: 60u32.into(),
+                    },
+                    get_mock_denom(),
+                ),
+                (
+                    UnpublishedPaymentTx {
+                        to: c,
+                        from: a,
+                        amount: 30u32.into(),
+                    },
+                    get_mock_denom(),
+                ),
+            ];
+            let txid = backend.send(&payments, get_test_key()).await.unwrap();
+            let denom = get_mock_denom();
+            assert_eq!(
+                backend.get_balance(&a, &denom).await.unwrap(),
+                Some(10u32.into())
+            );
+            assert_eq!(
+                backend.get_balance(&b, &denom).await.unwrap(),
+                Some(60u32.into())
+            );
+            assert_eq!(backend.get_nonce(&a).await.unwrap(), 1u32.into());
+
+            match backend.validate(txid).await.unwrap() {
+                TxStatus::Found(tx) => {
+                    assert_eq!(tx.transfers.len(), 2);
+                    assert!(!tx.confirmed);
+                }
+                TxStatus::NotFound => panic!("Tx not found"),
+            }
+            backend.confirm_all();
+            assert_eq!(
+                backend.validate(txid).await.unwrap(),
+                TxStatus::Found(backend.get_transaction(txid).unwrap())
+            );
+            assert!(backend.get_transaction(txid).unwrap().confirmed);
+            assert_eq!(
+                backend.validate(txid + 1u8.into()).await.unwrap(),
+                TxStatus::NotFound
+            );
+
+            // not enough left for this payment, nothing should change
+            assert!(backend.send(&payments[..1], get_test_key()).await.is_err());
+            assert_eq!(
+                backend.get_balance(&a, &denom).await.unwrap(),
+                Some(10u32.into())
+            );
+
+            backend.set_offline(true);
+            assert!(backend.validate(txid).await.is_err());
+        });
+    }
+}
@@ -0,0 +1,183 @@
+//! Payment backends abstract over the blockchains Rita can settle debts on. Payment controller sends
+//! payments and payment validator checks them through the PaymentBackend trait rather than talking to
+//! a full node directly, so adding a chain means adding a backend rather than another branch in every
+//! module that touches payments. EthereumBackend covers xDai and other EVM chains over web3 json rpc,
+//! CosmosBackend covers Althea chain over gRPC and MockBackend is an in memory chain for unit tests.
+
+use crate::rita_loop::get_web3_server;
+use althea_types::Denom;
+use althea_types::Identity;
+use althea_types::SystemChain;
+use althea_types::UnpublishedPaymentTx;
+use async_trait::async_trait;
+use clarity::Address;
+use clarity::PrivateKey;
+use deep_space::Address as AltheaAddress;
+use num256::Uint256;
+use std::error::Error;
+use std::fmt::Result as DisplayResult;
+use std::fmt::{Display, Formatter};
+
+pub mod cosmos;
+pub mod ethereum;
+pub mod mock;
+
+use self::cosmos::CosmosBackend;
+use self::ethereum::EthereumBackend;
+
+/// A transaction that has been signed but not yet published. Its txid is known before it is
+/// published so that payment controller can journal the payment first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedTransaction {
+    pub txid: Uint256,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentBackendError {
+    /// More than one payment was provided to a backend that can only send one per transaction
+    BatchingUnsupported,
+    /// We could not get a response from the full node, or it responded with an error
+    FullNodeError(String),
+    /// The full node is up but failed to look up a transaction, this is not proof that the
+    /// transaction doesn't exist
+    TxLookupError(String),
+    /// The full node returned a transaction we could not decode
+    InvalidTransaction(String),
+}
+
+impl Display for PaymentBackendError {
+    fn fmt(&self, f: &mut Formatter) -> DisplayResult {
+        match self {
+            Self::BatchingUnsupported => write!(f, "Backend can't batch payments"),
+            Self::FullNodeError(e) => write!(f, "Full node error {e}"),
+            Self::TxLookupError(e) => write!(f, "Transaction lookup error {e}"),
+            Self::InvalidTransaction(e) => write!(f, "Invalid transaction {e}"),
+        }
+    }
+}
+
+impl Error for PaymentBackendError {}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub enum PaymentAddress {
+    Xdai(Address),
+    Althea(AltheaAddress),
+}
+
+/// A single transfer of funds within a transaction, as reported by a full node. A batched
+/// transaction contains many of these
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub struct TransactionDetails {
+    pub to: PaymentAddress,
+    pub from: PaymentAddress,
+    pub amount: Uint256,
+    pub denom: String,
+}
+
+/// A transaction found on chain
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ChainTransaction {
+    pub transfers: Vec<TransactionDetails>,
+    /// True once the transaction is far enough behind the head of the chain to be considered final
+    pub confirmed: bool,
+    /// True if the transaction is too old for us to accept it as a new payment
+    pub too_old: bool,
+    /// True if the full node knows of the transaction but it is in the mempool rather than a block,
+    /// a transaction we have already accepted can end up here after a reorg
+    pub pending: bool,
+}
+
+/// The result of asking a full node about a txid
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum TxStatus {
+    /// The full node responded but does not know of this transaction
+    NotFound,
+    Found(ChainTransaction),
+}
+
+/// The chain operations Rita needs to make and validate payments. Futures are not Send because the
+/// underlying http clients run on the actix runtime
+#[async_trait(?Send)]
+pub trait PaymentBackend {
+    /// The full node this backend talks to, for logging
+    fn full_node(&self) -> String;
+
+    /// The on chain address of a node on this chain
+    fn address(&self, id: &Identity) -> PaymentAddress;
+
+    /// The denom a payment to this neighbor is made in, None if we have no acceptable denom
+    fn settlement_denom(&self, to: &Identity) -> Option<Denom>;
+
+    /// The denom of a transfer found on chain, None if we do not accept it as payment
+    fn accepted_denom(&self, denom: &str) -> Option<Denom>;
+
+    /// True if send accepts more than one payment
+    fn supports_batching(&self) -> bool {
+        false
+    }
+
+    /// True if a transaction in a block can still be removed by a reorg, validated payments are
+    /// only watched for reorgs on chains where this is true
+    fn can_reorg(&self) -> bool {
+        true
+    }
+
+    /// Builds and signs a single transaction containing the payments without publishing it. Amounts
+    /// must already be in the denom they are paid in
+    async fn sign(
+        &self,
+        payments: &[(UnpublishedPaymentTx, Denom)],
+        key: PrivateKey,
+    ) -> Result<SignedTransaction, PaymentBackendError>;
+
+    /// Publishes a transaction returned by sign
+    async fn publish(&self, tx: SignedTransaction) -> Result<(), PaymentBackendError>;
+
+    /// Signs and publishes the payments in a single transaction and returns its txid
+    async fn send(
+        &self,
+        payments: &[(UnpublishedPaymentTx, Denom)],
+        key: PrivateKey,
+    ) -> Result<Uint256, PaymentBackendError> {
+        let tx = self.sign(payments, key).await?;
+        let txid = tx.txid;
+        self.publish(tx).await?;
+        Ok(txid)
+    }
+
+    /// Looks up a transaction by txid
+    async fn validate(&self, txid: Uint256) -> Result<TxStatus, PaymentBackendError>;
+
+    async fn get_balance(
+        &self,
+        id: &Identity,
+        denom: &Denom,
+    ) -> Result<Option<Uint256>, PaymentBackendError>;
+
+    async fn get_nonce(&self, id: &Identity) -> Result<Uint256, PaymentBackendError>;
+
+    async fn get_gas_price(&self) -> Result<Uint256, PaymentBackendError>;
+}
+
+/// Gets the backend for the chain we send payments on, None if we can't pay on this chain
+pub fn get_payment_backend(chain: SystemChain) -> Option<Box<dyn PaymentBackend>> {
+    match chain {
+        SystemChain::Xdai => Some(Box::new(EthereumBackend::new(get_web3_server()))),
+        SystemChain::Althea => Some(Box::new(CosmosBackend::new(
+            settings::get_rita_common().payment.althea_grpc_list[0].clone(),
+        ))),
+        SystemChain::Rinkeby | SystemChain::Ethereum => None,
+    }
+}
+
+/// Gets every backend we could have been paid through, since a neighbor may be paying
+/// us on a different chain than we pay on incoming payments are checked against all of them
+pub fn get_validation_backends() -> Vec<Box<dyn PaymentBackend>> {
+    vec![
+        Box::new(CosmosBackend::new(
+            settings::get_rita_common().payment.althea_grpc_list[0].clone(),
+        )),
+        Box::new(EthereumBackend::new(get_web3_server())),
+    ]
+}
@@ -0,0 +1,786 @@
+//! Payment channels let neighbors settle small debts continuously without paying gas for every payment.
+//! Instead of an on chain transaction the payer sends a ChannelUpdate, a signed and strictly incrementing
+//! record of the total amount it has promised to pay over the lifetime of the channel. Updates are signed
+//! with the eth private key from the payment settings and checked against the eth address in the payers
+//! identity. Debt keeper credits channel payments as soon as they are accepted, exactly like a validated
+//! on chain payment.
+//!
+//! The amount promised but not yet paid on chain is the outstanding balance. Once it grows past
+//! channel_max_outstanding, or channel_settlement_interval has passed, the payer settles it with a normal
+//! on chain payment. On chain payments from (or to) a neighbor with an outstanding channel balance are
+//! applied to that balance first, and only the remainder is credited in debt keeper, since the channel
+//! payments it settles have already been credited. Both sides track the same signed balances so they
+//! reach the same result regardless of the order the payments are validated in.
+//!
+//! The receiver enforces its own limits rather than trusting the payer to. Updates that would leave more than
+//! our channel_max_outstanding unsettled, or that arrive after the payer has left a balance unsettled for
+//! longer than our channel_settlement_interval, are refused with a 402 and are not credited. The payer treats
+//! a 402 like reaching its own limit and settles on chain.
+//!
+//! Neighbors whose hello advertised capabilities without FEATURE_PAYMENT_CHANNELS are paid on chain without
+//! trying. Neighbors that only send the older hellos don't advertise anything, so we try them and those that
+//! don't support channels respond to the update endpoint with a 404, in which case we fall
+//! back to on chain payments for a while before trying again.
+
+use crate::debt_keeper::channel_payment_received;
+use crate::debt_keeper::channel_payment_succeeded;
+use crate::peer_listener::capabilities::{Capabilities, FEATURE_PAYMENT_CHANNELS};
+use crate::tunnel_manager::tm_get_neighbors;
+use crate::KI;
+use althea_types::Identity;
+use althea_types::UnpublishedPaymentTx;
+use clarity::utils::get_ethereum_msg_hash;
+use clarity::PrivateKey;
+use clarity::Signature;
+use num256::Uint256;
+use num_traits::Zero;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Result as DisplayResult;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+
+/// How long we wait before trying to open a channel again with a neighbor that does not support them
+pub const CHANNEL_RETRY_INTERVAL: Duration = Duration::from_secs(3600);
+/// How long a settlement may be in flight before we give up on it and allow channel payments again
+pub const CHANNEL_SETTLEMENT_TIMEOUT: Duration = Duration::from_secs(600);
+pub const CHANNEL_UPDATE_TIMEOUT: Duration = Duration::from_secs(15);
+/// Prefixed to the signed bytes of every update so that a channel signature can't be replayed as
+/// a signature over some other message
+const CHANNEL_UPDATE_DOMAIN: &[u8] = b"althea payment channel update";
+
+lazy_static! {
+    static ref CHANNEL_DATA: Arc<RwLock<HashMap<u32, PaymentChannels>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+/// Gets a write ref for the payment channel lock, loading the channels from disk on first use. Since
+/// this is a mutable reference the lock will be held until you drop the return value
+pub fn get_payment_channels_write_ref(
+    input: &mut HashMap<u32, PaymentChannels>,
+) -> &mut PaymentChannels {
+    let netns = KI.check_integration_test_netns();
+    input.entry(netns).or_insert_with(PaymentChannels::load);
+    input.get_mut(&netns).unwrap()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PaymentChannelError {
+    /// Payment channels are not enabled in our settings
+    Disabled,
+    /// We don't have an eth private key to sign updates with
+    NoPrivateKey,
+    /// Our neighbor does not support payment channels, pay on chain
+    Unsupported,
+    /// A settlement is in flight, pay on chain until it has been validated
+    Settling,
+    /// The outstanding balance must be settled, pay this amount on chain instead
+    Settle {
+        amount: Uint256,
+    },
+    InvalidSignature,
+    WrongRecipient,
+    /// The update would leave more than we allow unsettled, our neighbor must settle on chain
+    OverLimit {
+        max_outstanding: Uint256,
+    },
+    /// Our neighbor has not settled its outstanding balance within the settlement interval
+    SettlementOverdue,
+    /// The update does not increase the sequence and balance of the channel
+    StaleUpdate {
+        sequence: u64,
+        balance: Uint256,
+    },
+    /// Our neighbor did not acknowledge the update, it will be resent
+    SendFailed(String),
+}
+
+impl Display for PaymentChannelError {
+    fn fmt(&self, f: &mut Formatter) -> DisplayResult {
+        match self {
+            Self::Disabled => write!(f, "Payment channels are disabled"),
+            Self::NoPrivateKey => write!(f, "No private key to sign channel updates with"),
+            Self::Unsupported => write!(f, "Neighbor does not support payment channels"),
+            Self::Settling => write!(f, "Payment channel settlement in progress"),
+            Self::Settle { amount } => write!(f, "Payment channel must settle {amount} on chain"),
+            Self::InvalidSignature => write!(f, "Invalid channel update signature"),
+            Self::WrongRecipient => write!(f, "Channel update is not addressed to us"),
+            Self::OverLimit { max_outstanding } => write!(
+                f,
+                "Channel update exceeds max outstanding balance {max_outstanding}"
+            ),
+            Self::SettlementOverdue => write!(f, "Payment channel settlement is overdue"),
+            Self::StaleUpdate { sequence, balance } => write!(
+                f,
+                "Stale channel update, current sequence {sequence} and balance {balance}"
+            ),
+            Self::SendFailed(e) => write!(f, "Failed to send channel update {e}"),
+        }
+    }
+}
+
+impl Error for PaymentChannelError {}
+
+/// A signed promise from `from` to pay `to` a total of `balance` over the lifetime of the channel.
+/// The amount is in the debt keeper denom
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChannelUpdate {
+    pub from: Identity,
+    pub to: Identity,
+    /// Strictly increases with every update, so that an old update can't be replayed
+    pub sequence: u64,
+    /// The total amount promised, not the amount of this payment
+    pub balance: Uint256,
+    pub signature: Signature,
+}
+
+impl ChannelUpdate {
+    fn signed_bytes(from: &Identity, to: &Identity, sequence: u64, balance: Uint256) -> Vec<u8> {
+        let mut bytes = CHANNEL_UPDATE_DOMAIN.to_vec();
+        bytes.extend_from_slice(from.eth_address.as_bytes());
+        bytes.extend_from_slice(to.eth_address.as_bytes());
+        bytes.extend_from_slice(&sequence.to_be_bytes());
+        bytes.extend_from_slice(&balance.to_be_bytes());
+        bytes
+    }
+
+    pub fn new(
+        from: Identity,
+        to: Identity,
+        sequence: u64,
+        balance: Uint256,
+        key: PrivateKey,
+    ) -> ChannelUpdate {
+        let signature =
+            key.sign_ethereum_msg(&ChannelUpdate::signed_bytes(&from, &to, sequence, balance));
+        ChannelUpdate {
+            from,
+            to,
+            sequence,
+            balance,
+            signature,
+        }
+    }
+
+    /// Checks that this update was signed by the eth address of the payer
+    pub fn verify(&self) -> bool {
+        let hash = get_ethereum_msg_hash(&ChannelUpdate::signed_bytes(
+            &self.from,
+            &self.to,
+            self.sequence,
+            self.balance,
+        ));
+        match self.signature.recover(&hash) {
+            Ok(address) => address == self.from.eth_address,
+            Err(_) => false,
+        }
+    }
+}
+
+/// One direction of a payment channel
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ChannelBalance {
+    /// The sequence of the latest accepted update
+    pub sequence: u64,
+    /// The total amount promised by the latest accepted update
+    pub balance: Uint256,
+    /// The total amount paid on chain against the balance
+    pub settled: Uint256,
+    /// The latest accepted update, the proof of what is owed
+    pub latest: Option<ChannelUpdate>,
+}
+
+impl ChannelBalance {
+    /// The amount promised but not yet paid on chain
+    pub fn outstanding(&self) -> Uint256 {
+        if self.balance > self.settled {
+            self.balance - self.settled
+        } else {
+            Uint256::zero()
+        }
+    }
+
+    /// Applies an on chain payment to the outstanding balance, returning the part of the
+    /// payment that was not needed to settle it
+    fn settle(&mut self, amount: Uint256) -> Uint256 {
+        let outstanding = self.outstanding();
+        if amount > outstanding {
+            self.settled += outstanding;
+            amount - outstanding
+        } else {
+            self.settled += amount;
+            Uint256::zero()
+        }
+    }
+
+    /// Accepts an update into this balance, returning the amount it pays. Resending the latest
+    /// update is accepted but pays nothing, so that a lost acknowledgement can be retried
+    fn accept(&mut self, update: ChannelUpdate) -> Result<Uint256, PaymentChannelError> {
+        if update.sequence == self.sequence && update.balance == self.balance {
+            return Ok(Uint256::zero());
+        }
+        if update.sequence <= self.sequence || update.balance < self.balance {
+            return Err(PaymentChannelError::StaleUpdate {
+                sequence: self.sequence,
+                balance: self.balance,
+            });
+        }
+        let amount = update.balance - self.balance;
+        self.sequence = update.sequence;
+        self.balance = update.balance;
+        self.latest = Some(update);
+        Ok(amount)
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PaymentChannel {
+    /// What we have promised this neighbor
+    pub sent: ChannelBalance,
+    /// What this neighbor has promised us
+    pub received: ChannelBalance,
+    /// An update we have signed but that our neighbor has not acknowledged, this is
+    /// resent before any new update is created
+    pub unacked: Option<ChannelUpdate>,
+    /// When we last settled our outstanding balance, or opened the channel
+    pub last_settlement: Option<SystemTime>,
+    /// When the balance our neighbor owes us became unsettled, or when they last settled part of it
+    pub received_unsettled_since: Option<SystemTime>,
+    #[serde(skip)]
+    settling_since: Option<Instant>,
+    #[serde(skip)]
+    unsupported_since: Option<Instant>,
+}
+
+impl PaymentChannel {
+    fn is_settling(&self) -> bool {
+        match self.settling_since {
+            Some(start) => start.elapsed() < CHANNEL_SETTLEMENT_TIMEOUT,
+            None => false,
+        }
+    }
+
+    fn is_unsupported(&self) -> bool {
+        match self.unsupported_since {
+            Some(start) => start.elapsed() < CHANNEL_RETRY_INTERVAL,
+            None => false,
+        }
+    }
+
+    fn settlement_due(&self, interval: Duration) -> bool {
+        match self.last_settlement {
+            Some(last) => match last.elapsed() {
+                Ok(elapsed) => elapsed > interval,
+                Err(_) => false,
+            },
+            None => false,
+        }
+    }
+
+    /// True if our neighbor has left a balance unsettled for longer than the settlement interval,
+    /// plus the time its settlement may take to be validated
+    fn settlement_overdue(&self, interval: Duration) -> bool {
+        match self.received_unsettled_since {
+            Some(since) => match since.elapsed() {
+                Ok(elapsed) => elapsed > interval + CHANNEL_SETTLEMENT_TIMEOUT,
+                Err(_) => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Checks an update from our neighbor against our own limits, the payer enforces its limits
+    /// when sending but we can't rely on that. Resends and stale updates are left to accept()
+    fn check_received(
+        &self,
+        update: &ChannelUpdate,
+        max_outstanding: Uint256,
+        interval: Duration,
+    ) -> Result<(), PaymentChannelError> {
+        if update.balance <= self.received.balance {
+            return Ok(());
+        }
+        if update.balance > self.received.settled
+            && update.balance - self.received.settled > max_outstanding
+        {
+            return Err(PaymentChannelError::OverLimit { max_outstanding });
+        }
+        if self.settlement_overdue(interval) {
+            return Err(PaymentChannelError::SettlementOverdue);
+        }
+        Ok(())
+    }
+}
+
+/// The channel state with every neighbor, saved to disk on every change since it represents money owed
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PaymentChannels {
+    channels: HashMap<Identity, PaymentChannel>,
+}
+
+impl PaymentChannels {
+    fn load() -> PaymentChannels {
+        match fs::read(get_channels_file_path()) {
+            Ok(bytes) => match bincode::deserialize(&bytes) {
+                Ok(channels) => channels,
+                Err(e) => {
+                    error!("Failed to deserialize payment channels {:?}", e);
+                    PaymentChannels::default()
+                }
+            },
+            Err(_) => PaymentChannels::default(),
+        }
+    }
+
+    /// Writes the channels to a temporary file then moves them into place, so that a crash
+    /// mid write leaves us with the previous state rather than a corrupt one
+    fn save(&self) {
+        let file_path = get_channels_file_path();
+        let tmp_path = format!("{file_path}.tmp");
+        let res = bincode::serialize(self)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| {
+                let mut file = File::create(&tmp_path).map_err(|e| e.to_string())?;
+                file.write_all(&bytes).map_err(|e| e.to_string())?;
+                file.sync_all().map_err(|e| e.to_string())?;
+                fs::rename(&tmp_path, &file_path).map_err(|e| e.to_string())
+            });
+        if let Err(e) = res {
+            error!("Failed to save payment channels {}", e);
+        }
+    }
+}
+
+/// Channels are stored next to the debts file, with the same name but a different extension
+pub fn get_channels_file_path() -> String {
+    let debts_file = settings::get_rita_common().payment.debts_file;
+    Path::new(&debts_file)
+        .with_extension("channels")
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Returns a copy of the channel with this neighbor if one has been opened
+pub fn get_payment_channel(peer: &Identity) -> Option<PaymentChannel> {
+    let data = &mut *CHANNEL_DATA.write().unwrap();
+    get_payment_channels_write_ref(data)
+        .channels
+        .get(peer)
+        .cloned()
+}
+
+/// True if we have successfully paid this neighbor over a channel, debt keeper uses this to
+/// decide which payment threshold to use
+pub fn has_open_channel(peer: &Identity) -> bool {
+    match get_payment_channel(peer) {
+        Some(channel) => channel.sent.sequence > 0 && !channel.is_unsupported(),
+        None => false,
+    }
+}
+
+/// Applies an on chain payment to or from this neighbor to the outstanding channel balance in
+/// that direction, returning the part of the amount that still needs to be credited in debt
+/// keeper. Amount must already be in the debt keeper denom
+pub fn apply_settlement(peer: &Identity, amount: Uint256, sent: bool) -> Uint256 {
+    let data = &mut *CHANNEL_DATA.write().unwrap();
+    let channels = get_payment_channels_write_ref(data);
+    let channel = match channels.channels.get_mut(peer) {
+        Some(c) => c,
+        None => return amount,
+    };
+    let balance = if sent {
+        &mut channel.sent
+    } else {
+        &mut channel.received
+    };
+    if balance.outstanding() == Uint256::zero() {
+        return amount;
+    }
+    let remaining = balance.settle(amount);
+    info!(
+        "Settled {} of payment channel with {} on chain",
+        amount - remaining,
+        peer.wg_public_key
+    );
+    if sent && channel.sent.outstanding() == Uint256::zero() {
+        channel.settling_since = None;
+        channel.last_settlement = Some(SystemTime::now());
+    } else if !sent {
+        // any settlement restarts the clock on what is still owed
+        channel.received_unsettled_since = if channel.received.outstanding() == Uint256::zero() {
+            None
+        } else {
+            Some(SystemTime::now())
+        };
+    }
+    channels.save();
+    remaining
+}
+
+/// Returns every channel whose outstanding balance has not been settled for longer than the settlement
+/// interval, these are marked as settling so the caller must queue the returned on chain payments
+pub fn get_due_settlements(our_id: Identity) -> Vec<UnpublishedPaymentTx> {
+    let payment_settings = settings::get_rita_common().payment;
+    if !payment_settings.payment_channels_enabled {
+        return Vec::new();
+    }
+    let interval = Duration::from_secs(payment_settings.channel_settlement_interval);
+
+    let data = &mut *CHANNEL_DATA.write().unwrap();
+    let channels = get_payment_channels_write_ref(data);
+    let mut ret = Vec::new();
+    for (peer, channel) in channels.channels.iter_mut() {
+        let outstanding = channel.sent.outstanding();
+        if outstanding > Uint256::zero()
+            && !channel.is_settling()
+            && channel.settlement_due(interval)
+        {
+            channel.settling_since = Some(Instant::now());
+            ret.push(UnpublishedPaymentTx {
+                to: *peer,
+                from: our_id,
+                amount: outstanding,
+            });
+        }
+    }
+    ret
+}
+
+/// False if a tunnel to this neighbor negotiated capabilities without payment channels. Neighbors
+/// that sent no capabilities may still support them, the update endpoint tells us if they don't
+fn channels_negotiated(capabilities: &[Option<Capabilities>]) -> bool {
+    capabilities
+        .iter()
+        .flatten()
+        .all(|c| c.supports(FEATURE_PAYMENT_CHANNELS))
+}
+
+/// Attempts to make this payment over a payment channel. Errors other than SendFailed mean the payment
+/// should be made on chain instead, Settle with a different amount
+pub async fn make_channel_payment(pmt: UnpublishedPaymentTx) -> Result<(), PaymentChannelError> {
+    let payment_settings = settings::get_rita_common().payment;
+    if !payment_settings.payment_channels_enabled {
+        return Err(PaymentChannelError::Disabled);
+    }
+    let key = match payment_settings.eth_private_key {
+        Some(key) => key,
+        None => return Err(PaymentChannelError::NoPrivateKey),
+    };
+    let capabilities: Vec<Option<Capabilities>> = tm_get_neighbors()
+        .into_iter()
+        .filter(|n| n.identity.global == pmt.to)
+        .map(|n| n.capabilities)
+        .collect();
+    let negotiated = channels_negotiated(&capabilities);
+
+    // decide what to send while holding the lock, the update is saved as unacked before it
+    // is sent so that a crash can't lose a payment our neighbor may have accepted
+    let update = {
+        let data = &mut *CHANNEL_DATA.write().unwrap();
+        let channels = get_payment_channels_write_ref(data);
+        let channel = channels.channels.entry(pmt.to).or_default();
+        if channel.is_unsupported() {
+            return Err(PaymentChannelError::Unsupported);
+        }
+        if channel.is_settling() {
+            return Err(PaymentChannelError::Settling);
+        }
+        // an unacked update is still resent, our neighbor may have accepted it before it stopped
+        // advertising channels
+        if !negotiated && channel.unacked.is_none() {
+            return Err(PaymentChannelError::Unsupported);
+        }
+
+        match channel.unacked.clone() {
+            // a resent update is for an earlier payment, debt keeper will make this one again
+            // if it's still needed once the earlier one has been credited
+            Some(unacked) => {
+                info!(
+                    "Resending unacknowledged channel update to {}",
+                    pmt.to.wg_public_key
+                );
+                unacked
+            }
+            None => {
+                let outstanding = channel.sent.outstanding();
+                let interval = Duration::from_secs(payment_settings.channel_settlement_interval);
+                if outstanding + pmt.amount > payment_settings.channel_max_outstanding
+                    || (outstanding > Uint256::zero() && channel.settlement_due(interval))
+                {
+                    channel.settling_since = Some(Instant::now());
+                    return Err(PaymentChannelError::Settle {
+                        amount: outstanding + pmt.amount,
+                    });
+                }
+                let update = ChannelUpdate::new(
+                    pmt.from,
+                    pmt.to,
+                    channel.sent.sequence + 1,
+                    channel.sent.balance + pmt.amount,
+                    key,
+                );
+                channel.unacked = Some(update.clone());
+                channels.save();
+                update
+            }
+        }
+    };
+
+    // testing hack
+    let neighbor_url = if cfg!(not(test)) {
+        format!(
+            "http://[{}]:{}/make_channel_payment",
+            pmt.to.mesh_ip,
+            settings::get_rita_common().network.rita_contact_port,
+        )
+    } else {
+        String::from("http://127.0.0.1:1234/make_channel_payment")
+    };
+
+    let client = awc::Client::default();
+    let res = client
+        .post(&neighbor_url)
+        .timeout(CHANNEL_UPDATE_TIMEOUT)
+        .send_json(&update)
+        .await;
+    let status = match res {
+        Ok(val) => val.status(),
+        Err(e) => return Err(PaymentChannelError::SendFailed(e.to_string())),
+    };
+
+    let amount = {
+        let data = &mut *CHANNEL_DATA.write().unwrap();
+        let channels = get_payment_channels_write_ref(data);
+        let channel = channels.channels.entry(pmt.to).or_default();
+        if status.as_u16() == 402 {
+            // our neighbor refused this update without crediting it, settle on chain
+            let outstanding = channel.sent.outstanding();
+            info!(
+                "{} requires settlement of our payment channel, paying on chain",
+                pmt.to.wg_public_key
+            );
+            channel.unacked = None;
+            channel.settling_since = Some(Instant::now());
+            channels.save();
+            return Err(PaymentChannelError::Settle {
+                amount: outstanding + pmt.amount,
+            });
+        } else if status.as_u16() == 404 {
+            // our neighbor has never seen this update so it's safe to drop
+            info!(
+                "{} does not support payment channels, paying on chain",
+                pmt.to.wg_public_key
+            );
+            channel.unacked = None;
+            channel.unsupported_since = Some(Instant::now());
+            channels.save();
+            return Err(PaymentChannelError::Unsupported);
+        } else if !status.is_success() {
+            return Err(PaymentChannelError::SendFailed(status.to_string()));
+        }
+
+        channel.unacked = None;
+        channel.unsupported_since = None;
+        if channel.last_settlement.is_none() {
+            channel.last_settlement = Some(SystemTime::now());
+        }
+        let amount = channel.sent.accept(update);
+        channels.save();
+        amount
+    };
+
+    // debt keeper must not be called with the channel lock held, it takes the channel lock itself
+    let amount = match amount {
+        Ok(a) => a,
+        Err(e) => {
+            error!("Failed to apply acknowledged channel update {}", e);
+            return Ok(());
+        }
+    };
+    if let Err(e) = channel_payment_succeeded(pmt.to, amount) {
+        error!("Failed to credit channel payment {}", e);
+    }
+    Ok(())
+}
+
+/// Handles an update sent to us by a neighbor, crediting the amount it pays in debt keeper
+pub fn channel_update_received(update: ChannelUpdate) -> Result<(), PaymentChannelError> {
+    let common = settings::get_rita_common();
+    if !common.payment.payment_channels_enabled {
+        return Err(PaymentChannelError::Disabled);
+    }
+    match common.get_identity() {
+        Some(id) if id == update.to => {}
+        _ => return Err(PaymentChannelError::WrongRecipient),
+    }
+    if !update.verify() {
+        return Err(PaymentChannelError::InvalidSignature);
+    }
+
+    let from = update.from;
+    let interval = Duration::from_secs(common.payment.channel_settlement_interval);
+    let amount = {
+        let data = &mut *CHANNEL_DATA.write().unwrap();
+        let channels = get_payment_channels_write_ref(data);
+        let channel = channels.channels.entry(from).or_default();
+        if let Err(e) =
+            channel.check_received(&update, common.payment.channel_max_outstanding, interval)
+        {
+            warn!(
+                "Refusing channel update from {} with {}",
+                from.wg_public_key, e
+            );
+            return Err(e);
+        }
+        let amount = channel.received.accept(update)?;
+        if amount > Uint256::zero() {
+            if channel.received_unsettled_since.is_none() {
+                channel.received_unsettled_since = Some(SystemTime::now());
+            }
+            channels.save();
+        }
+        amount
+    };
+
+    // debt keeper must not be called with the channel lock held, it takes the channel lock itself
+    if amount > Uint256::zero() {
+        info!(
+            "Received channel payment of {} from {}",
+            amount, from.wg_public_key
+        );
+        if let Err(e) = channel_payment_received(from, amount) {
+            error!("Failed to credit channel payment {}", e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage_tracker::tests::test::random_identity;
+
+    fn get_test_key() -> PrivateKey {
+        "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f1e"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_channel_update_signature() {
+        let key = get_test_key();
+        let mut from = random_identity();
+        from.eth_address = key.to_address();
+        let to = random_identity();
+
+        let update = ChannelUpdate::new(from, to, 1, 100u32.into(), key);
+        assert!(update.verify());
+
+        let mut tampered = update.clone();
+        tampered.balance = 1000u32.into();
+        assert!(!tampered.verify());
+
+        // signed by someone other than the payer
+        let mut forged = update;
+        forged.from = random_identity();
+        assert!(!forged.verify());
+    }
+
+    #[test]
+    fn test_channels_negotiated() {
+        let with = Capabilities {
+            features: FEATURE_PAYMENT_CHANNELS,
+            ..Default::default()
+        };
+        let without = Capabilities::default();
+        assert!(channels_negotiated(&[]));
+        // a neighbor that sent no capabilities has to be tried
+        assert!(channels_negotiated(&[None]));
+        assert!(channels_negotiated(&[Some(with.clone()), None]));
+        assert!(!channels_negotiated(&[Some(without.clone())]));
+        assert!(!channels_negotiated(&[Some(with), Some(without)]));
+    }
+
+    #[test]
+    fn test_channel_balance_accept() {
+        let key = get_test_key();
+        let from = random_identity();
+        let to = random_identity();
+        let mut balance = ChannelBalance::default();
+
+        let first = ChannelUpdate::new(from, to, 1, 100u32.into(), key);
+        assert_eq!(balance.accept(first.clone()), Ok(100u32.into()));
+        // a resend pays nothing
+        assert_eq!(balance.accept(first.clone()), Ok(0u32.into()));
+
+        let second = ChannelUpdate::new(from, to, 2, 150u32.into(), key);
+        assert_eq!(balance.accept(second.clone()), Ok(50u32.into()));
+        assert!(balance.accept(first).is_err());
+
+        // sequence must increase even when the balance does
+        let replay = ChannelUpdate::new(from, to, 2, 200u32.into(), key);
+        assert!(balance.accept(replay).is_err());
+        assert_eq!(balance.latest, Some(second));
+    }
+
+    #[test]
+    fn test_channel_balance_settle() {
+        let mut balance = ChannelBalance {
+            sequence: 3,
+            balance: 100u32.into(),
+            settled: 0u32.into(),
+            latest: None,
+        };
+        assert_eq!(balance.settle(60u32.into()), 0u32.into());
+        assert_eq!(balance.outstanding(), 40u32.into());
+        assert_eq!(balance.settle(50u32.into()), 10u32.into());
+        assert_eq!(balance.outstanding(), 0u32.into());
+        assert_eq!(balance.settle(50u32.into()), 50u32.into());
+    }
+
+    #[test]
+    /// A neighbor can't claim more credit than we allow outstanding, or keep claiming credit
+    /// once it has stopped settling
+    fn test_channel_check_received() {
+        let key = get_test_key();
+        let from = random_identity();
+        let to = random_identity();
+        let max: Uint256 = 100u32.into();
+        let interval = Duration::from_secs(3600);
+        let mut channel = PaymentChannel::default();
+
+        let over = ChannelUpdate::new(from, to, 1, 101u32.into(), key);
+        assert_eq!(
+            channel.check_received(&over, max, interval),
+            Err(PaymentChannelError::OverLimit {
+                max_outstanding: max
+            })
+        );
+
+        let first = ChannelUpdate::new(from, to, 1, 100u32.into(), key);
+        assert_eq!(channel.check_received(&first, max, interval), Ok(()));
+        assert_eq!(channel.received.accept(first.clone()), Ok(100u32.into()));
+        let next = ChannelUpdate::new(from, to, 2, 150u32.into(), key);
+        assert!(channel.check_received(&next, max, interval).is_err());
+
+        // once part of the balance is settled on chain there is room for more
+        channel.received.settle(60u32.into());
+        assert_eq!(channel.check_received(&next, max, interval), Ok(()));
+
+        // left unsettled for too long, only a resend of the latest update is let through
+        channel.received_unsettled_since = SystemTime::now()
+            .checked_sub(interval + CHANNEL_SETTLEMENT_TIMEOUT + Duration::from_secs(1));
+        assert_eq!(
+            channel.check_received(&next, max, interval),
+            Err(PaymentChannelError::SettlementOverdue)
+        );
+        assert_eq!(channel.check_received(&first, max, interval), Ok(()));
+    }
+}
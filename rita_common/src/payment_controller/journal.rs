@@ -0,0 +1,434 @@
+//! The payment journal is a crash safe record of every payment payment controller is responsible for
+//! that has not yet reached a final state. Payment controller only keeps its queues in memory, so if
+//! Rita restarts after a tx has been published but before our neighbor has been notified the neighbor
+//! never learns of the payment and we end up enforced upon, or worse, debt keeper pays the same debt
+//! a second time.
+//!
+//! The journal is rewritten to disk every time it changes, before the action it records is taken, and
+//! is replayed once on startup by replay_payment_journal. Replaying requeues unpublished payments,
+//! resends the txid of published ones, and marks every payment as in flight in debt keeper so that we
+//! don't pay twice while the replayed payments are validated. Transactions are signed before they are
+//! published and journaled with their txid in between, so a crash mid send replays as a published
+//! payment. If it never reached the chain its validation times out and debt keeper pays again.
+//!
+//! Payment validator only remembers what it has confirmed in memory, so the journal also records which
+//! of its published payments have been confirmed on chain. Debt keeper has already been credited for
+//! those, after a restart their txid is still resent but they are not validated or credited again.
+
+use super::get_payment_controller_write_ref;
+use super::ResendInfo;
+use super::PAYMENT_DATA;
+use crate::debt_keeper::set_payment_in_flight;
+use crate::payment_validator::validate_later;
+use crate::payment_validator::ToValidate;
+use crate::RitaCommonError;
+use althea_types::PaymentTx;
+use althea_types::UnpublishedPaymentTx;
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PaymentJournal {
+    /// Payments that have been queued but not yet published to the blockchain
+    outgoing: Vec<UnpublishedPaymentTx>,
+    /// Payments that have been published to the blockchain but that we have not yet
+    /// tried to notify our neighbor of
+    unnotified: Vec<PaymentTx>,
+    /// Payments we have failed to notify our neighbor of and are retrying
+    resend: Vec<ResendInfo>,
+    /// Payments in unnotified or resend that payment validator has seen succeed on chain
+    confirmed: Vec<PaymentTx>,
+}
+
+/// What replaying a journal requeues
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Replay {
+    outgoing: Vec<UnpublishedPaymentTx>,
+    resend: Vec<ResendInfo>,
+    /// Published payments that still have to be seen on chain before debt keeper is credited
+    to_validate: Vec<PaymentTx>,
+    /// The journal as it should be saved once the replayed payments are queued
+    journal: PaymentJournal,
+}
+
+/// A payment is identified by its txid and recipient since batched payments share a txid
+fn same_payment(a: &PaymentTx, b: &PaymentTx) -> bool {
+    a.txid == b.txid && a.to == b.to
+}
+
+impl PaymentJournal {
+    fn load(file_path: &str) -> PaymentJournal {
+        match fs::read(file_path) {
+            Ok(bytes) => match bincode::deserialize(&bytes) {
+                Ok(journal) => journal,
+                Err(e) => {
+                    error!("Failed to deserialize payment journal {:?}", e);
+                    PaymentJournal::default()
+                }
+            },
+            Err(_) => PaymentJournal::default(),
+        }
+    }
+
+    /// Writes the journal to a temporary file then moves it into place, so that a crash
+    /// mid write leaves us with the previous journal rather than a corrupt one
+    fn save(&self, file_path: &str) -> Result<(), RitaCommonError> {
+        let serialized = bincode::serialize(self)?;
+        let tmp_path = format!("{file_path}.tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serialized)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, file_path)?;
+        Ok(())
+    }
+
+    pub(super) fn add_outgoing(&mut self, pmt: UnpublishedPaymentTx) {
+        self.outgoing.push(pmt);
+    }
+
+    /// Removes a single outgoing entry matching the provided payment
+    pub(super) fn remove_outgoing(&mut self, pmt: &UnpublishedPaymentTx) {
+        if let Some(i) = self.outgoing.iter().position(|p| p == pmt) {
+            self.outgoing.remove(i);
+        }
+    }
+
+    /// Records that an outgoing payment has been published to the blockchain, published payments
+    /// may have been converted into the settlement denom so we can't match on amount, but there is
+    /// only ever one payment in flight per neighbor
+    pub(super) fn published(&mut self, pmt: PaymentTx) {
+        if let Some(i) = self
+            .outgoing
+            .iter()
+            .position(|p| p.to == pmt.to && p.from == pmt.from)
+        {
+            self.outgoing.remove(i);
+        }
+        self.unnotified.push(pmt);
+    }
+
+    /// Undoes published for a payment whose transaction could not be published
+    pub(super) fn publish_failed(&mut self, pmt: &PaymentTx) {
+        self.unnotified.retain(|p| !same_payment(p, pmt));
+        self.prune_confirmed();
+    }
+
+    pub(super) fn notified(&mut self, pmt: &PaymentTx) {
+        self.unnotified.retain(|p| !same_payment(p, pmt));
+        self.prune_confirmed();
+    }
+
+    /// Adds or replaces the resend entry for this payment
+    pub(super) fn add_resend(&mut self, resend: ResendInfo) {
+        self.remove_resend(&resend.pmt);
+        self.resend.push(resend);
+    }
+
+    pub(super) fn remove_resend(&mut self, pmt: &PaymentTx) {
+        self.resend.retain(|r| !same_payment(&r.pmt, pmt));
+        self.prune_confirmed();
+    }
+
+    /// Records that a published payment has succeeded on chain, payments the journal no longer
+    /// tracks are ignored since nothing would replay them
+    fn confirmed(&mut self, pmt: PaymentTx) {
+        if self.is_tracked(&pmt) && !self.is_confirmed(&pmt) {
+            self.confirmed.push(pmt);
+        }
+    }
+
+    fn is_confirmed(&self, pmt: &PaymentTx) -> bool {
+        self.confirmed.iter().any(|c| same_payment(c, pmt))
+    }
+
+    fn is_tracked(&self, pmt: &PaymentTx) -> bool {
+        self.unnotified.iter().any(|p| same_payment(p, pmt))
+            || self.resend.iter().any(|r| same_payment(&r.pmt, pmt))
+    }
+
+    fn prune_confirmed(&mut self) {
+        let confirmed = std::mem::take(&mut self.confirmed);
+        self.confirmed = confirmed
+            .into_iter()
+            .filter(|c| self.is_tracked(c))
+            .collect();
+    }
+
+    /// Removes duplicate entries
+    fn dedup(&mut self) {
+        let mut seen = HashSet::new();
+        self.resend
+            .retain(|r| seen.insert((r.pmt.txid, r.pmt.to.wg_public_key)));
+        // anything in the resend queue is already being handled
+        self.unnotified
+            .retain(|pmt| seen.insert((pmt.txid, pmt.to.wg_public_key)));
+
+        let mut seen = HashSet::new();
+        self.outgoing.retain(|pmt| seen.insert(*pmt));
+        self.prune_confirmed();
+    }
+
+    /// Turns a journal left by the previous run of Rita into what has to be requeued, published
+    /// payments we haven't notified our neighbor of yet are resent from the first attempt
+    fn replay(mut self, rita_contact_port: u16) -> Replay {
+        self.dedup();
+        let PaymentJournal {
+            outgoing,
+            unnotified,
+            mut resend,
+            confirmed,
+        } = self;
+        for pmt in unnotified {
+            resend.push(ResendInfo {
+                neigh_url: format!(
+                    "http://[{}]:{}/make_payment",
+                    pmt.to.mesh_ip, rita_contact_port,
+                ),
+                pmt,
+                attempt: 0,
+            });
+        }
+        let to_validate = resend
+            .iter()
+            .map(|r| r.pmt)
+            .filter(|pmt| !confirmed.iter().any(|c| same_payment(c, pmt)))
+            .collect();
+
+        Replay {
+            outgoing: outgoing.clone(),
+            resend: resend.clone(),
+            to_validate,
+            journal: PaymentJournal {
+                outgoing,
+                unnotified: Vec::new(),
+                resend,
+                confirmed,
+            },
+        }
+    }
+}
+
+/// The journal is stored next to the debts file, with the same name but a different extension
+pub fn get_journal_file_path() -> String {
+    let debts_file = settings::get_rita_common().payment.debts_file;
+    Path::new(&debts_file)
+        .with_extension("journal")
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Applies a change to the journal, saving it to disk before returning if anything changed
+pub(super) fn update_journal<F: FnOnce(&mut PaymentJournal)>(update: F) {
+    let data = &mut *PAYMENT_DATA.write().unwrap();
+    let data = get_payment_controller_write_ref(data);
+    data.update_journal(update);
+}
+
+/// Called by payment validator once a payment we sent is confirmed on chain and debt keeper has been
+/// credited for it, so that a restart doesn't credit it again
+pub fn journal_payment_confirmed(pmt: PaymentTx) {
+    update_journal(|j| j.confirmed(pmt));
+}
+
+/// Writes the journal to disk, this should be called with the payment controller lock held
+pub(super) fn save_journal(journal: &PaymentJournal) {
+    if let Err(e) = journal.save(&get_journal_file_path()) {
+        error!("Failed to save payment journal {:?}", e);
+    }
+}
+
+/// Loads the payment journal left by the previous run of Rita and requeues everything in it,
+/// should be called once on startup before payment controller runs
+pub fn replay_payment_journal() {
+    let journal = PaymentJournal::load(&get_journal_file_path());
+    let Replay {
+        outgoing,
+        resend,
+        to_validate,
+        journal,
+    } = journal.replay(settings::get_rita_common().network.rita_contact_port);
+    info!(
+        "Replaying payment journal with {} outgoing {} resends of which {} need validation",
+        outgoing.len(),
+        resend.len(),
+        to_validate.len()
+    );
+
+    {
+        let data = &mut *PAYMENT_DATA.write().unwrap();
+        let data = get_payment_controller_write_ref(data);
+        data.outgoing_queue.extend(outgoing.iter().cloned());
+        data.resend_queue.extend(resend);
+        data.update_journal(|j| *j = journal);
+    }
+
+    // we don't hold the payment controller lock here because debt keeper takes its own lock
+    // and then the payment controller lock when queueing payments
+    for pmt in outgoing.iter() {
+        set_payment_in_flight(pmt.to);
+    }
+    for pmt in to_validate {
+        set_payment_in_flight(pmt.to);
+        // we still need to see this payment on chain before we credit it in debt keeper
+        let ts = ToValidate {
+            payment: pmt,
+            received: Instant::now(),
+            checked: false,
+        };
+        if let Err(e) = validate_later(ts.clone()) {
+            warn!(
+                "Failed to queue replayed payment {} for validation {}",
+                ts, e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usage_tracker::tests::test::random_identity;
+
+    fn random_payment() -> PaymentTx {
+        let amount: u128 = rand::random();
+        let txid: u128 = rand::random();
+        PaymentTx {
+            to: random_identity(),
+            from: random_identity(),
+            amount: amount.into(),
+            txid: txid.into(),
+        }
+    }
+
+    fn resend_info(pmt: PaymentTx) -> ResendInfo {
+        ResendInfo {
+            neigh_url: "http://127.0.0.1:1234/make_payment".to_string(),
+            pmt,
+            attempt: 0,
+        }
+    }
+
+    #[test]
+    fn test_journal_lifecycle() {
+        let pmt = random_payment();
+        let unpublished = UnpublishedPaymentTx {
+            to: pmt.to,
+            from: pmt.from,
+            amount: 5u8.into(),
+        };
+        let mut journal = PaymentJournal::default();
+        journal.add_outgoing(unpublished);
+        assert_eq!(journal.outgoing.len(), 1);
+
+        // published with an amount converted into another denom
+        journal.published(pmt);
+        assert!(journal.outgoing.is_empty());
+        assert_eq!(journal.unnotified, vec![pmt]);
+
+        // the transaction could not be published after all
+        journal.publish_failed(&pmt);
+        assert!(journal.unnotified.is_empty());
+        journal.published(pmt);
+
+        let resend = ResendInfo {
+            neigh_url: "http://127.0.0.1:1234/make_payment".to_string(),
+            pmt,
+            attempt: 0,
+        };
+        journal.add_resend(resend.clone());
+        journal.notified(&pmt);
+        assert!(journal.unnotified.is_empty());
+
+        let mut retry = resend;
+        retry.attempt += 1;
+        journal.add_resend(retry.clone());
+        assert_eq!(journal.resend, vec![retry]);
+
+        journal.remove_resend(&pmt);
+        assert_eq!(journal, PaymentJournal::default());
+    }
+
+    #[test]
+    fn test_journal_dedup() {
+        let pending = random_payment();
+        let mut batched = random_payment();
+        batched.txid = pending.txid;
+        let resent = random_payment();
+
+        let mut journal = PaymentJournal {
+            outgoing: Vec::new(),
+            unnotified: vec![pending, batched, pending, resent],
+            resend: vec![resend_info(resent)],
+            confirmed: vec![random_payment()],
+        };
+        journal.dedup();
+
+        assert_eq!(journal.unnotified, vec![pending, batched]);
+        assert_eq!(journal.resend, vec![resend_info(resent)]);
+        assert!(journal.confirmed.is_empty());
+    }
+
+    /// A restart after one payment was confirmed and credited but before either neighbor was
+    /// notified, only the other payment may be validated and credited again
+    #[test]
+    fn test_journal_replay_after_restart() {
+        let path = std::env::temp_dir().join("test_payment_journal_replay.journal");
+        let path = path.to_string_lossy().to_string();
+
+        let credited = random_payment();
+        let pending = random_payment();
+        let queued = UnpublishedPaymentTx {
+            to: random_identity(),
+            from: pending.from,
+            amount: 5u8.into(),
+        };
+        let mut journal = PaymentJournal::default();
+        journal.add_outgoing(queued);
+        journal.published(credited);
+        journal.published(pending);
+        journal.notified(&credited);
+        journal.add_resend(resend_info(credited));
+        journal.confirmed(credited);
+        // not tracked by this journal, so there is nothing to record
+        journal.confirmed(random_payment());
+        assert_eq!(journal.confirmed, vec![credited]);
+        journal.save(&path).unwrap();
+
+        let replay = PaymentJournal::load(&path).replay(4874);
+        let _ = fs::remove_file(&path);
+        assert_eq!(replay.outgoing, vec![queued]);
+        assert_eq!(
+            replay.resend.iter().map(|r| r.pmt).collect::<Vec<_>>(),
+            vec![credited, pending]
+        );
+        assert_eq!(replay.to_validate, vec![pending]);
+        assert_eq!(replay.journal.confirmed, vec![credited]);
+
+        // a second restart before anything changed replays the same way
+        let again = replay.journal.clone().replay(4874);
+        assert_eq!(again.to_validate, vec![pending]);
+
+        // once our neighbor has been notified the confirmation is no longer needed
+        let mut journal = replay.journal;
+        journal.remove_resend(&credited);
+        assert!(journal.confirmed.is_empty());
+    }
+
+    #[test]
+    fn test_journal_save_load() {
+        let path = std::env::temp_dir().join("test_payment_journal.journal");
+        let path = path.to_string_lossy().to_string();
+
+        let mut journal = PaymentJournal::default();
+        journal.published(random_payment());
+        journal.save(&path).unwrap();
+        assert_eq!(PaymentJournal::load(&path), journal);
+
+        let _ = fs::remove_file(&path);
+        assert_eq!(PaymentJournal::load(&path), PaymentJournal::default());
+    }
+}
@@ -3,23 +3,24 @@
 //! until it is successfully in a block, see payment_validator, once the payment is on
 //! the blockchain it's up to the reciever to validate that it's correct
 
-use crate::blockchain_oracle::{
-    get_oracle_balance, get_oracle_latest_gas_price, get_oracle_nonce, set_oracle_nonce,
-};
 use crate::debt_keeper::normalize_payment_amount;
 use crate::debt_keeper::payment_failed;
+use crate::payment_backend::{get_payment_backend, PaymentBackend};
+use crate::payment_channel::get_due_settlements;
+use crate::payment_channel::make_channel_payment;
+use crate::payment_channel::PaymentChannelError;
+use crate::payment_controller::journal::save_journal;
+use crate::payment_controller::journal::update_journal;
+use crate::payment_controller::journal::PaymentJournal;
 use crate::payment_validator::{get_payment_txids, validate_later, ToValidate};
-use crate::payment_validator::{ALTHEA_CHAIN_PREFIX, ALTHEA_CONTACT_TIMEOUT};
-use crate::rita_loop::get_web3_server;
+use crate::peer_listener::capabilities::Capabilities;
+use crate::tunnel_manager::tm_get_neighbors;
 use crate::KI;
 use althea_types::interop::UnpublishedPaymentTx;
-use althea_types::SystemChain;
-use althea_types::{Denom, PaymentTx};
+use althea_types::{Denom, Identity, PaymentTx, SystemChain};
 use awc;
-use deep_space::{Coin, Contact, EthermintPrivateKey};
 use futures::future::{join, join_all};
 use num256::Uint256;
-use num_traits::Num;
 use settings::network::NetworkSettings;
 use settings::payment::PaymentSettings;
 use settings::{DEBT_KEEPER_DENOM, DEBT_KEEPER_DENOM_DECIMAL};
@@ -30,8 +31,8 @@ use std::fmt::{Display, Formatter};
 use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use std::time::Instant;
-use web30::client::Web3;
-use web30::types::SendTxOption;
+
+pub mod journal;
 
 pub const TRANSACTION_SUBMISSION_TIMEOUT: Duration = Duration::from_secs(15);
 pub const MAX_TXID_RETRIES: u8 = 15u8;
@@ -78,6 +79,9 @@ pub struct PaymentController {
     /// info over to our neighbor. Even if we fail to do so we should still consider
     /// this debt as paid
     resend_queue: Vec<ResendInfo>,
+    /// A copy of every payment we are responsible for that has not yet reached a final
+    /// state, kept on disk so that it can be replayed after a restart
+    journal: PaymentJournal,
 }
 
 /// Pushes a payment tx onto the payment controller queue, to be processed
@@ -87,6 +91,7 @@ pub fn queue_payment(payment: UnpublishedPaymentTx) {
     let data = &mut *PAYMENT_DATA.write().unwrap();
     let data = get_payment_controller_write_ref(data);
     data.outgoing_queue.push(payment);
+    data.update_journal(|j| j.add_outgoing(payment));
 }
 
 /// Pushes a resend onto the payment controller queue, to be processed
@@ -95,6 +100,7 @@ fn queue_resend(resend_info: ResendInfo) {
     info!("Resend of {:?} queued!", resend_info);
     let data = &mut *PAYMENT_DATA.write().unwrap();
     let data = get_payment_controller_write_ref(data);
+    data.update_journal(|j| j.add_resend(resend_info.clone()));
     data.resend_queue.push(resend_info);
 }
 
@@ -103,6 +109,16 @@ impl PaymentController {
         PaymentController {
             outgoing_queue: Vec::new(),
             resend_queue: Vec::new(),
+            journal: PaymentJournal::default(),
+        }
+    }
+
+    /// Applies a change to the journal, saving it to disk if anything changed
+    fn update_journal<F: FnOnce(&mut PaymentJournal)>(&mut self, update: F) {
+        let old = self.journal.clone();
+        update(&mut self.journal);
+        if self.journal != old {
+            save_journal(&self.journal);
         }
     }
 }
@@ -138,9 +154,16 @@ impl Error for PaymentControllerError {}
 /// This function is called by the async loop in order to perform payment
 /// controller actions
 pub async fn tick_payment_controller() {
-    let outgoing_payments: Vec<UnpublishedPaymentTx>;
+    let mut outgoing_payments: Vec<UnpublishedPaymentTx>;
     let resend_queue: Vec<ResendInfo>;
 
+    // settlements are queued like any other payment and go out on the next tick
+    if let Some(our_id) = settings::get_rita_common().get_identity() {
+        for settlement in get_due_settlements(our_id) {
+            queue_payment(settlement);
+        }
+    }
+
     {
         let data = &mut *PAYMENT_DATA.write().unwrap();
         let data = get_payment_controller_write_ref(data);
@@ -167,8 +190,46 @@ pub async fn tick_payment_controller() {
     // a long time to timeout, payments are done in series to reduce
     // nonce races
     let mut retry_futures = Vec::new();
-    for pmt in outgoing_payments {
-        let _ = make_payment(pmt).await;
+    let common = settings::get_rita_common();
+    if common.payment.payment_channels_enabled {
+        outgoing_payments = make_channel_payments(outgoing_payments).await;
+    }
+    let backend = get_payment_backend(common.payment.system_chain);
+    if let Some(backend) = backend.filter(|b| {
+        common.payment.batch_payments && b.supports_batching() && outgoing_payments.len() > 1
+    }) {
+        // a tx can only contain one payment to each neighbor without confusing validation, anything
+        // else waits for the next tick, it's already in the journal so we only requeue it in memory
+        let mut batch: Vec<UnpublishedPaymentTx> = Vec::new();
+        for pmt in outgoing_payments {
+            if batch.iter().any(|p| p.to == pmt.to) {
+                let data = &mut *PAYMENT_DATA.write().unwrap();
+                get_payment_controller_write_ref(data)
+                    .outgoing_queue
+                    .push(pmt);
+            } else {
+                batch.push(pmt);
+            }
+        }
+        let _ = make_backend_payment(
+            batch.clone(),
+            backend.as_ref(),
+            common.payment,
+            common.network,
+        )
+        .await;
+        // published payments have already been moved out of outgoing, this removes the failures
+        update_journal(|j| {
+            for pmt in batch.iter() {
+                j.remove_outgoing(pmt)
+            }
+        });
+    } else {
+        for pmt in outgoing_payments {
+            let _ = make_payment(pmt).await;
+            // published payments have already been moved out of outgoing, this removes the failures
+            update_journal(|j| j.remove_outgoing(&pmt));
+        }
     }
     for resend in resend_queue {
         let fut = resend_txid(resend);
@@ -179,273 +240,250 @@ pub async fn tick_payment_controller() {
     let _ = join_all(retry_futures).await;
 }
 
+/// Attempts to make each payment over a payment channel, returning the payments that must be
+/// made on chain instead
+async fn make_channel_payments(pmts: Vec<UnpublishedPaymentTx>) -> Vec<UnpublishedPaymentTx> {
+    let mut on_chain = Vec::new();
+    for pmt in pmts {
+        match make_channel_payment(pmt).await {
+            Ok(()) => update_journal(|j| j.remove_outgoing(&pmt)),
+            Err(PaymentChannelError::Settle { amount }) => {
+                info!(
+                    "Settling payment channel with {} for {} on chain",
+                    pmt.to.wg_public_key, amount
+                );
+                let mut settlement = pmt;
+                settlement.amount = amount;
+                update_journal(|j| {
+                    j.remove_outgoing(&pmt);
+                    j.add_outgoing(settlement);
+                });
+                on_chain.push(settlement);
+            }
+            Err(PaymentChannelError::SendFailed(e)) => {
+                warn!(
+                    "Channel payment to {} failed with {}",
+                    pmt.to.wg_public_key, e
+                );
+                payment_failed(pmt.to);
+                update_journal(|j| j.remove_outgoing(&pmt));
+            }
+            Err(_) => on_chain.push(pmt),
+        }
+    }
+    on_chain
+}
+
 /// This is called by debt_keeper to make payments. It sends a
 /// PaymentTx to the `mesh_ip` in its `to` field.
 async fn make_payment(pmt: UnpublishedPaymentTx) -> Result<(), PaymentControllerError> {
     let common = settings::get_rita_common();
-    let network_settings = common.network;
-    let payment_settings = common.payment;
-    let system_chain = payment_settings.system_chain;
-
-    match system_chain {
-        SystemChain::Althea => make_althea_payment(pmt, payment_settings, network_settings).await,
-        SystemChain::Xdai => make_xdai_payment(pmt, payment_settings, network_settings).await,
-        SystemChain::Rinkeby => {
-            warn!("Payments on Rinkeby not currently supported!");
-            Ok(())
+    let system_chain = common.payment.system_chain;
+
+    match get_payment_backend(system_chain) {
+        Some(backend) => {
+            make_backend_payment(vec![pmt], backend.as_ref(), common.payment, common.network).await
         }
-        SystemChain::Ethereum => {
-            warn!("Payments on Ethereum not currently supported!");
+        None => {
+            warn!("Payments on {} not currently supported!", system_chain);
             Ok(())
         }
     }
 }
 
-async fn make_althea_payment(
-    mut pmt: UnpublishedPaymentTx,
+/// False if a tunnel to this neighbor negotiated capabilities that don't include the chain we pay
+/// on. Neighbors that sent no capabilities are paid anyway, they validate what they receive
+fn chain_negotiated(capabilities: &[Option<Capabilities>], chain: SystemChain) -> bool {
+    capabilities
+        .iter()
+        .flatten()
+        .all(|c| c.accepts_payment_on(chain))
+}
+
+fn neighbor_accepts_payment_on(to: Identity, chain: SystemChain) -> bool {
+    let capabilities: Vec<Option<Capabilities>> = tm_get_neighbors()
+        .into_iter()
+        .filter(|n| n.identity.global == to)
+        .map(|n| n.capabilities)
+        .collect();
+    chain_negotiated(&capabilities, chain)
+}
+
+/// Sends all of the provided payments in a single transaction through the given backend, each
+/// neighbor is then notified of the shared txid. Neighbors validate only the transfer addressed
+/// to them, see payment_validator. Backends that can't batch must be given a single payment
+async fn make_backend_payment(
+    pmts: Vec<UnpublishedPaymentTx>,
+    backend: &dyn PaymentBackend,
     payment_settings: PaymentSettings,
     network_settings: NetworkSettings,
 ) -> Result<(), PaymentControllerError> {
-    // On althea chain, we default to paying with usdc, config must specify this as an accepted denom
-    let usdc_denom = match payment_settings
-        .accepted_denoms
-        .unwrap_or(HashMap::new())
-        .get("usdc")
-    {
-        Some(a) => a.clone(),
+    let our_private_key = match payment_settings.eth_private_key {
+        Some(a) => a,
         None => {
-            error!("No USDC denom found");
+            error!("How are we making a payment with no private key??");
             return Err(PaymentControllerError::FailedToSendPayment);
         }
     };
 
-    let our_address = pmt.from.get_althea_address();
-
-    // our althea private key is generated from our eth private key
-    let our_private_key: EthermintPrivateKey = match payment_settings.eth_private_key {
-        Some(a) => a.into(),
-        None => {
-            error!("How are we making an althea payment with no private key??");
-            return Err(PaymentControllerError::FailedToSendPayment);
+    // convert each payment into the denom we will settle it in, totaling the amount
+    // we need of each denom so that we can check our balances
+    let mut batch: Vec<(UnpublishedPaymentTx, Denom)> = Vec::new();
+    let mut totals: HashMap<Denom, Uint256> = HashMap::new();
+    for mut pmt in pmts {
+        if !neighbor_accepts_payment_on(pmt.to, payment_settings.system_chain) {
+            error!(
+                "{} can't be paid on {}, the chains we negotiated have nothing in common",
+                pmt.to.wg_public_key, payment_settings.system_chain
+            );
+            payment_failed(pmt.to);
+            continue;
         }
-    };
-
-    let to_address = pmt.to.get_althea_address();
-
-    let cosmos_node_grpc = payment_settings.althea_grpc_list[0].clone();
-
-    // Convert Debt keeper denom to USDC
-    pmt.amount = normalize_payment_amount(
-        pmt.amount,
-        Denom {
-            denom: DEBT_KEEPER_DENOM.to_string(),
-            decimal: DEBT_KEEPER_DENOM_DECIMAL,
-        },
-        usdc_denom.clone(),
-    );
-
-    // Create a contact object and get our balance
-    let althea_contact = Contact::new(
-        &cosmos_node_grpc,
-        ALTHEA_CONTACT_TIMEOUT,
-        ALTHEA_CHAIN_PREFIX,
-    )
-    .unwrap();
-
-    let balance = match althea_contact
-        .get_balance(our_address, usdc_denom.denom.clone())
-        .await
-    {
-        Ok(a) => match a {
+        let settlement_denom = match backend.settlement_denom(&pmt.to) {
             Some(a) => a,
             None => {
-                error!("Unable to get balance for wallet {:?}", our_address);
-                return Err(PaymentControllerError::FailedToSendPayment);
+                error!(
+                    "No accepted denom found for settlement policy {:?}",
+                    payment_settings.settlement_denom_policy
+                );
+                payment_failed(pmt.to);
+                continue;
             }
-        },
-        Err(e) => {
-            error!(
-                "Unable to get balance for wallet {:?} with {:?}",
-                our_address, e
-            );
-            return Err(PaymentControllerError::FailedToSendPayment);
-        }
+        };
+        pmt.amount = normalize_payment_amount(
+            pmt.amount,
+            Denom {
+                denom: DEBT_KEEPER_DENOM.to_string(),
+                decimal: DEBT_KEEPER_DENOM_DECIMAL,
+            },
+            settlement_denom.clone(),
+        );
+        let total = totals
+            .entry(settlement_denom.clone())
+            .or_insert_with(|| 0u8.into());
+        *total += pmt.amount;
+        batch.push((pmt, settlement_denom));
+    }
+
+    let our_id = match batch.first() {
+        Some((pmt, _)) => pmt.from,
+        None => return Err(PaymentControllerError::FailedToSendPayment),
     };
 
-    match sanity_check_balance(Some(balance.amount), &pmt) {
-        Ok(_) => {}
-        Err(e) => return Err(e),
+    let mut result = Ok(());
+    for (denom, total) in totals {
+        let balance = match backend.get_balance(&our_id, &denom).await {
+            Ok(a) => a,
+            Err(e) => {
+                error!(
+                    "Unable to get balance for wallet {:?} with {}",
+                    backend.address(&our_id),
+                    e
+                );
+                None
+            }
+        };
+        if balance.is_none() || balance.unwrap() < total {
+            warn!(
+                "Not enough money to pay debts! Cutoff imminent. Balance {:?} {} total {}",
+                balance, denom.denom, total
+            );
+            // having this here really doesn't matter much, either we
+            // tell debt keeper the payment failed and it enqueues another
+            // that also won't succeed right away, or it waits for the timeout
+            // and does the same thing.
+            for (pmt, _) in batch.iter().filter(|(_, d)| *d == denom) {
+                payment_failed(pmt.to);
+            }
+            batch.retain(|(_, d)| *d != denom);
+            result = Err(PaymentControllerError::InsufficientFunds {
+                amount: total,
+                balance: balance.unwrap_or_default(),
+            });
+        }
+    }
+    batch.retain(|(pmt, _)| {
+        if pmt.amount == 0u8.into() {
+            // in this case we just drop the tx, no retry no other messages
+            error!("Trying to pay nothing!");
+            result = Err(PaymentControllerError::ZeroPayment);
+            false
+        } else {
+            true
+        }
+    });
+    if batch.is_empty() {
+        return result;
     }
 
     info!(
-        "current USDC balance on Althea Chain: {:?}, payment of {:?}, from address {} to address {}",
-        balance, pmt.amount, our_address, to_address
+        "Sending {} payments from address {:?} using node {}",
+        batch.len(),
+        backend.address(&our_id),
+        backend.full_node()
     );
-
-    let coin = Coin {
-        amount: pmt.amount,
-        denom: usdc_denom.denom.clone(),
-    };
-    // Make microtx transaction.
-    let transaction = match althea_contact
-        .send_microtx(
-            coin,
-            None,
-            to_address,
-            Some(Duration::from_secs(30)),
-            our_private_key,
-        )
-        .await
-    {
+    let tx = match backend.sign(&batch, our_private_key).await {
         Ok(a) => a,
         Err(e) => {
-            error!(
-                "Failed to send payment {:?} to {:?} with {:?}",
-                pmt, pmt.to, e
-            );
-            payment_failed(pmt.to);
+            error!("Failed to sign payments {:?} with {}", batch, e);
+            for (pmt, _) in batch {
+                payment_failed(pmt.to);
+            }
             return Err(PaymentControllerError::FailedToSendPayment);
         }
     };
+    let txid = tx.txid;
 
-    // setup tx hash
-    let pmt = pmt.publish(Uint256::from_str_radix(&transaction.txhash, 16).unwrap());
-
-    send_make_payment_endpoints(pmt, network_settings, None, Some(cosmos_node_grpc)).await;
-
-    // place this payment in the validation queue to handle later.
-    let ts = ToValidate {
-        payment: pmt,
-        received: Instant::now(),
-        checked: false,
-    };
-
-    if let Err(e) = validate_later(ts.clone()) {
-        error!("Received error trying to validate {:?} Error: {:?}", ts, e);
-    }
-
-    Ok(())
-}
-
-async fn make_xdai_payment(
-    pmt: UnpublishedPaymentTx,
-    payment_settings: PaymentSettings,
-    network_settings: NetworkSettings,
-) -> Result<(), PaymentControllerError> {
-    let balance = get_oracle_balance();
-    let nonce = get_oracle_nonce();
-    let gas_price = get_oracle_latest_gas_price();
-    let our_private_key = &payment_settings
-        .eth_private_key
-        .expect("No private key configured!");
-    let our_address = our_private_key.to_address();
-
-    match sanity_check_balance(balance, &pmt) {
-        Ok(_) => {}
-        Err(e) => return Err(e),
-    }
-
-    info!(
-        "current xdai balance: {:?}, payment of {:?}, from address {} to address {} with nonce {}",
-        balance, pmt.amount, our_address, pmt.to.eth_address, nonce
-    );
-
-    let full_node = get_web3_server();
-    let web3 = Web3::new(&full_node, TRANSACTION_SUBMISSION_TIMEOUT);
-
-    let transaction_status = web3
-        .send_transaction(
-            pmt.to.eth_address,
-            Vec::new(),
-            pmt.amount,
-            *our_private_key,
-            vec![
-                SendTxOption::Nonce(nonce),
-                SendTxOption::GasPrice(gas_price),
-            ],
-        )
-        .await;
+    // this must hit the disk before we publish, if we crash while publishing the replay treats
+    // these payments as published and validates them rather than paying a second time
+    update_journal(|j| {
+        for (pmt, _) in batch.iter() {
+            j.published(pmt.publish(txid))
+        }
+    });
 
-    if transaction_status.is_err() {
-        error!(
-            "Failed to send payment {:?} to {:?} with {:?}",
-            pmt, pmt.to, transaction_status
-        );
+    if let Err(e) = backend.publish(tx).await {
+        error!("Failed to send payments {:?} with {}", batch, e);
         // we have not yet published the tx (at least hopefully)
         // so it's safe to add this debt back to our balances
-        payment_failed(pmt.to);
+        update_journal(|j| {
+            for (pmt, _) in batch.iter() {
+                j.publish_failed(&pmt.publish(txid))
+            }
+        });
+        for (pmt, _) in batch {
+            payment_failed(pmt.to);
+        }
         return Err(PaymentControllerError::FailedToSendPayment);
     }
-    let tx_id = transaction_status.unwrap();
-
-    // increment our nonce, this allows us to send another transaction
-    // right away before this one that we just sent out gets into the chain
-    {
-        set_oracle_nonce(get_oracle_nonce() + 1u64.into());
-    }
-
-    info!("Sending bw payment with txid {:#066x} current balance: {:?}, payment of {:?}, from address {} to address {} with nonce {}",
-                            tx_id, balance, pmt.amount, our_address, pmt.to.eth_address, nonce);
-
-    // add published txid to submission
-    let pmt = pmt.publish(tx_id);
-
-    send_make_payment_endpoints(pmt, network_settings, Some(full_node), None).await;
-
-    // place this payment in the validation queue to handle later.
-    let ts = ToValidate {
-        payment: pmt,
-        received: Instant::now(),
-        checked: false,
-    };
-
-    if let Err(e) = validate_later(ts.clone()) {
-        error!("Received error trying to validate {:?} Error: {:?}", ts, e);
-    }
 
-    Ok(())
-}
-
-fn sanity_check_balance(
-    balance: Option<Uint256>,
-    pmt: &UnpublishedPaymentTx,
-) -> Result<(), PaymentControllerError> {
-    match balance {
-        Some(value) => {
-            if value < pmt.amount {
-                warn!("Not enough money to pay debts! Cutoff imminent");
-                // having this here really doesn't matter much, either we
-                // tell debt keeper the payment failed and it enqueues another
-                // that also won't succeed right away, or it waits for the timeout
-                // and does the same thing.
-                payment_failed(pmt.to);
-                Err(PaymentControllerError::InsufficientFunds {
-                    amount: pmt.amount,
-                    balance: value,
-                })
-            } else if pmt.amount == 0u32.into() {
-                // in this case we just drop the tx, no retry no other messages
-                error!("Trying to pay nothing!");
-                return Err(PaymentControllerError::ZeroPayment);
-            } else {
-                Ok(())
-            }
-        }
-        None => {
-            warn!("Balance is none");
-            Err(PaymentControllerError::InsufficientFunds {
-                amount: pmt.amount,
-                balance: balance.unwrap_or_else(|| 0u64.into()),
-            })
+    let mut notify_futures = Vec::new();
+    for (pmt, _) in batch {
+        let pmt = pmt.publish(txid);
+        // place this payment in the validation queue to handle later.
+        let ts = ToValidate {
+            payment: pmt,
+            received: Instant::now(),
+            checked: false,
+        };
+        if let Err(e) = validate_later(ts.clone()) {
+            error!("Received error trying to validate {:?} Error: {:?}", ts, e);
         }
+        notify_futures.push(send_make_payment_endpoints(
+            pmt,
+            network_settings.clone(),
+            backend.full_node(),
+        ));
     }
+    join_all(notify_futures).await;
+
+    result
 }
 
 async fn send_make_payment_endpoints(
     pmt: PaymentTx,
     network_settings: NetworkSettings,
-    full_node: Option<String>,
-    cosmos_node_grpc: Option<String>,
+    full_node: String,
 ) {
     // testing hack
     let neighbor_url = if cfg!(not(test)) {
@@ -499,18 +537,12 @@ async fn send_make_payment_endpoints(
             match (val2.status().is_success(), val.status().is_success()) {
                 (true, _) => {
                     info!(
-                        "Payment pmt with tx identifier: {} is sent to our neighbor with status {:?} and body {:?} via url {}, using node {:?} and amount {}",
+                        "Payment pmt with tx identifier: {} is sent to our neighbor with status {:?} and body {:?} via url {}, using node {} and amount {}",
                         format!("{:#066x}", tx_id),
                         val2.status(),
                         val2.body().await,
                         neighbor_url_v2,
-                        {
-                            if full_node.is_some() {
-                                full_node
-                            } else {
-                                cosmos_node_grpc
-                            }
-                        },
+                        full_node,
                         pmt.amount
                     );
                 }
@@ -522,18 +554,12 @@ async fn send_make_payment_endpoints(
                         val2.body().await
                     );
                     info!(
-                        "Payment pmt with tx identifier: {} is sent to our neighbor with status {:?} and body {:?} via url {}, using node {:?} and amount {}",
+                        "Payment pmt with tx identifier: {} is sent to our neighbor with status {:?} and body {:?} via url {}, using node {} and amount {}",
                         format!("{:#066x}", tx_id),
                         val.status(),
                         val.body().await,
                         neighbor_url,
-                        {
-                            if full_node.is_some() {
-                                full_node
-                            } else {
-                                cosmos_node_grpc
-                            }
-                        },
+                        full_node,
                         pmt.amount
                     );
                 }
@@ -549,18 +575,12 @@ async fn send_make_payment_endpoints(
             // probably a b19 router
             if val.status().is_success() {
                 info!(
-                    "Payment pmt with txid: {} is sent to our neighbor with status {:?} and body {:?} via url {}, using node {:?} and amount {}",
+                    "Payment pmt with txid: {} is sent to our neighbor with status {:?} and body {:?} via url {}, using node {} and amount {}",
                     format!("{:#066x}", tx_id),
                     val.status(),
                     val.body().await,
                     neighbor_url,
-                    {
-                        if full_node.is_some() {
-                            full_node
-                        } else {
-                            cosmos_node_grpc
-                        }
-                    },
+                    full_node,
                     pmt.amount
                 );
             } else {
@@ -577,18 +597,12 @@ async fn send_make_payment_endpoints(
             // has been removed, all this legacy code can be removed also
             if val2.status().is_success() {
                 info!(
-                    "Payment pmt with txid: {} is sent to our neighbor with status {:?} and body {:?} via url {}, using node {:?} and amount {}",
+                    "Payment pmt with txid: {} is sent to our neighbor with status {:?} and body {:?} via url {}, using node {} and amount {}",
                     format!("{:#066x}", tx_id),
                     val2.status(),
                     val2.body().await,
                     neighbor_url_v2,
-                    {
-                        if full_node.is_some() {
-                            full_node
-                        } else {
-                            cosmos_node_grpc
-                        }
-                    },
+                    full_node,
                     pmt.amount
                 );
             } else {
@@ -610,9 +624,11 @@ async fn send_make_payment_endpoints(
             queue_resend(resend_info)
         }
     }
+    // either our neighbor has been notified or a resend has been journaled
+    update_journal(|j| j.notified(&pmt));
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 struct ResendInfo {
     neigh_url: String,
     pmt: PaymentTx,
@@ -633,6 +649,7 @@ async fn resend_txid(input: ResendInfo) -> Result<(), PaymentControllerError> {
             "We have failed to send txid {} this payment will remain uncredited!",
             { format!("{:#066x}", tx_id) },
         );
+        update_journal(|j| j.remove_resend(&input.pmt));
         return Err(PaymentControllerError::ResendFailed);
     }
 
@@ -648,13 +665,40 @@ async fn resend_txid(input: ResendInfo) -> Result<(), PaymentControllerError> {
             format!("{:#066x}", tx_id)
         },);
         queue_resend(input);
+    } else {
+        update_journal(|j| j.remove_resend(&input.pmt));
     }
 
     Ok(())
 }
 
+#[test]
+fn test_chain_negotiated() {
+    let negotiated = |chains: Vec<SystemChain>| {
+        Some(Capabilities {
+            payment_chains: chains,
+            ..Default::default()
+        })
+    };
+    assert!(chain_negotiated(&[], SystemChain::Xdai));
+    assert!(chain_negotiated(&[None], SystemChain::Xdai));
+    assert!(chain_negotiated(
+        &[
+            None,
+            negotiated(vec![SystemChain::Xdai, SystemChain::Althea])
+        ],
+        SystemChain::Xdai
+    ));
+    assert!(!chain_negotiated(
+        &[negotiated(vec![SystemChain::Althea])],
+        SystemChain::Xdai
+    ));
+    assert!(!chain_negotiated(&[negotiated(vec![])], SystemChain::Xdai));
+}
+
 #[test]
 fn parse_althea_txhash() {
+    use num_traits::Num;
     let hash = "2B8884553F72CB4C313B2169B29F2279CCD6968A5512EFABAB1C6FE78ED86B57";
     let parsed: Uint256 = Uint256::from_str_radix(hash, 16).unwrap();
     println!("Parsed: {:?}", parsed);
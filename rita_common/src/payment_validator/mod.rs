@@ -6,31 +6,31 @@
 //! attempt to validate these payments every 5 seconds, if successful the payment is sent
 //! off to debt keeper to be removed from the owed balance. Payments may time out after a
 //! configured period.
+//! Validated payments are not immediately forgotten, they are watched for the configured
+//! reorg_watch_window. If one disappears from the chain, or falls back into the mempool, during
+//! that window the credit is reversed in debt keeper and the payment goes back into the
+//! validation queue in case it is mined again on the new fork.
 
 use crate::debt_keeper::payment_received;
+use crate::debt_keeper::payment_received_reversed;
 use crate::debt_keeper::payment_succeeded;
+use crate::debt_keeper::payment_succeeded_reversed;
+use crate::debt_keeper::set_payment_in_flight;
+use crate::payment_backend::get_validation_backends;
+use crate::payment_backend::PaymentBackend;
+use crate::payment_backend::TransactionDetails;
+use crate::payment_backend::TxStatus;
+use crate::payment_backend::{ChainTransaction, PaymentBackendError};
+use crate::payment_controller::journal::journal_payment_confirmed;
 use crate::rita_loop::fast_loop::FAST_LOOP_TIMEOUT;
-use crate::rita_loop::get_web3_server;
 use crate::usage_tracker::update_payments;
 use crate::RitaCommonError;
 use crate::KI;
 use althea_types::Denom;
 use althea_types::Identity;
 use althea_types::PaymentTx;
-use clarity::Address;
-use cosmos_sdk_proto_althea::cosmos::bank::v1beta1::MsgSend;
-use cosmos_sdk_proto_althea::cosmos::tx::v1beta1::{TxBody, TxRaw};
-use deep_space::client::ChainStatus;
-use deep_space::utils::decode_any;
-use deep_space::Address as AltheaAddress;
-use deep_space::Contact;
-use futures::future::join;
 use futures::future::join_all;
 use num256::Uint256;
-use num_traits::Num;
-use settings::get_rita_common;
-use settings::DEBT_KEEPER_DENOM;
-use settings::DEBT_KEEPER_DENOM_DECIMAL;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::fmt;
@@ -40,8 +40,6 @@ use std::hash::Hasher;
 use std::sync::Arc;
 use std::sync::RwLock;
 use std::time::{Duration, Instant};
-use web30::client::Web3;
-use web30::types::TransactionResponse;
 
 pub const TRANSACTION_VERIFICATION_TIMEOUT: Duration = FAST_LOOP_TIMEOUT;
 
@@ -55,12 +53,6 @@ pub const PAYMENT_RECEIVE_TIMEOUT: Duration = Duration::from_secs(259200u64);
 /// we will not send another payment while one is in flight. On Xdai the block time is
 /// once every 5 seconds, meaning a minimum of 20 seconds is required to ensure 4 confirms
 pub const PAYMENT_SEND_TIMEOUT: Duration = Duration::from_secs(60u64);
-/// How many blocks before we assume finality
-const BLOCKS_TO_CONFIRM: u32 = 4;
-/// How old does a txid need to be before we don't accept it?
-/// this is 12 hours
-const BLOCKS_TO_OLD: u32 = 1440;
-
 // These parameters are used to set up a contact with althea chain
 pub const ALTHEA_CHAIN_PREFIX: &str = "althea";
 pub const ALTHEA_CONTACT_TIMEOUT: Duration = Duration::from_secs(30);
@@ -93,22 +85,6 @@ pub fn get_payment_validator_write_ref(
     input.get_mut(&netns).unwrap()
 }
 
-/// Details we pass into handle_tx_handling while validating a transaction
-/// These are made options in case althea chain parsing fails
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
-pub struct TransactionDetails {
-    pub to: AltheaAddress,
-    pub from: AltheaAddress,
-    pub amount: Uint256,
-    pub denom: String,
-}
-
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
-pub enum PaymentAddress {
-    Xdai(Address),
-    Althea(AltheaAddress),
-}
-
 #[derive(PartialEq, Eq, Clone, Debug)]
 pub struct ToValidate {
     /// details of the payment from the user in the format they where sent
@@ -138,6 +114,19 @@ impl fmt::Display for ToValidate {
     }
 }
 
+/// A payment that has been validated and credited in debt keeper but may still be reversed
+/// if it is removed from the chain by a reorg
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Watched {
+    /// The denom the payment was credited in
+    pub denom: Denom,
+    /// The full node of the backend that validated this payment, only that backend can tell
+    /// us that it has disappeared
+    pub full_node: String,
+    /// When we validated this payment
+    pub validated: Instant,
+}
+
 #[derive(Clone)]
 pub struct PaymentValidator {
     unvalidated_transactions: HashSet<ToValidate>,
@@ -145,6 +134,8 @@ pub struct PaymentValidator {
     successful_transactions_sent: HashMap<Identity, HashSet<PaymentTx>>,
     /// All successful txids this router has verified, used to check for duplicate payments
     successful_transactions: HashSet<PaymentTx>,
+    /// Recently validated payments that are checked for reorgs until the watch window passes
+    watched_transactions: HashMap<PaymentTx, Watched>,
 }
 
 // Setters and getters HISTORY lazy static
@@ -186,12 +177,32 @@ pub fn add_successful_tx(v: PaymentTx) {
         .insert(v);
 }
 
+pub fn get_watched_transactions() -> HashMap<PaymentTx, Watched> {
+    get_payment_validator().watched_transactions
+}
+
+fn add_watched_transaction(pmt: PaymentTx, watched: Watched) {
+    let writer = &mut *HISTORY.write().unwrap();
+    get_payment_validator_write_ref(writer)
+        .watched_transactions
+        .insert(pmt, watched);
+}
+
+fn remove_watched_transaction(pmt: &PaymentTx) -> bool {
+    let writer = &mut *HISTORY.write().unwrap();
+    get_payment_validator_write_ref(writer)
+        .watched_transactions
+        .remove(pmt)
+        .is_some()
+}
+
 impl PaymentValidator {
     pub fn new() -> Self {
         PaymentValidator {
             unvalidated_transactions: HashSet::new(),
             successful_transactions_sent: HashMap::new(),
             successful_transactions: HashSet::new(),
+            watched_transactions: HashMap::new(),
         }
     }
 }
@@ -218,6 +229,15 @@ pub fn store_payment(pmt: PaymentTx) {
     set_successful_tx_sent(data);
 }
 
+/// Removes a payment stored by store_payment, used when the payment is reversed
+fn remove_stored_payment(pmt: &PaymentTx) {
+    let mut data = get_successful_tx_sent();
+    if let Some(e) = data.get_mut(&pmt.to) {
+        e.remove(pmt);
+    }
+    set_successful_tx_sent(data);
+}
+
 /// Given an id, get all payments made to that id
 pub fn get_payment_txids(id: Identity) -> HashSet<PaymentTx> {
     let data: HashSet<PaymentTx> = HashSet::new();
@@ -243,10 +263,14 @@ pub fn calculate_unverified_payments(router: Identity) -> Uint256 {
 }
 
 /// Checks if we already have a given txid in our to_validate list
-/// true if we have it false if we do not
+/// true if we have it false if we do not. Batched transactions share a txid
+/// between several payments, so the sender and receiver must also match
 fn check_for_unvalidated_tx(ts: &ToValidate, payment_validator: &mut PaymentValidator) -> bool {
     for tx in &payment_validator.unvalidated_transactions {
-        if tx.payment.txid == ts.payment.txid {
+        if tx.payment.txid == ts.payment.txid
+            && tx.payment.to == ts.payment.to
+            && tx.payment.from == ts.payment.from
+        {
             return true;
         }
     }
@@ -319,14 +343,45 @@ fn checked(msg: ToValidate) {
 }
 
 pub async fn validate() {
+    let our_id = match settings::get_rita_common().get_identity() {
+        Some(id) => id,
+        None => {
+            error!("No identity, unable to validate transactions");
+            return;
+        }
+    };
+    let backends = get_validation_backends();
+    validate_transactions(
+        get_unvalidated_transactions(),
+        our_id,
+        &backends,
+        Instant::now(),
+    )
+    .await;
+    let watch_window = Duration::from_secs(settings::get_rita_common().payment.reorg_watch_window);
+    check_for_reorgs(
+        get_watched_transactions(),
+        our_id,
+        &backends,
+        watch_window,
+        Instant::now(),
+    )
+    .await
+}
+
+/// Checks the given transactions against the backends, dropping any that have timed out as of `now`
+async fn validate_transactions(
+    unvalidated_transactions: HashSet<ToValidate>,
+    our_id: Identity,
+    backends: &[Box<dyn PaymentBackend>],
+    now: Instant,
+) {
     // we panic on a failed receive so it should always be longer than the minimum
     // time we expect payments to take to enter the blockchain (the send timeout)
     assert!(PAYMENT_RECEIVE_TIMEOUT > PAYMENT_SEND_TIMEOUT);
 
-    let our_address = settings::get_rita_common().payment.eth_address.unwrap();
     let mut to_delete = Vec::new();
 
-    let unvalidated_transactions = get_unvalidated_transactions();
     info!(
         "Attempting to validate {} transactions {}",
         unvalidated_transactions.len(),
@@ -335,8 +390,8 @@ pub async fn validate() {
 
     let mut futs = Vec::new();
     for item in unvalidated_transactions {
-        let elapsed = Instant::now().checked_duration_since(item.received);
-        let from_us = item.payment.from.eth_address == our_address;
+        let elapsed = now.checked_duration_since(item.received);
+        let from_us = item.payment.from.eth_address == our_id.eth_address;
 
         if elapsed.is_some() && elapsed.unwrap() > PAYMENT_RECEIVE_TIMEOUT {
             error!(
@@ -371,7 +426,7 @@ pub async fn validate() {
             // we take all these futures and put them onto an array that we will execute
             // in parallel, this is essential on the exit where in the worst case scenario
             // we could have a thousand or more payments in the queue
-            let fut = validate_transaction(item);
+            let fut = validate_transaction_with(item, our_id, backends);
             futs.push(fut);
         }
     }
@@ -399,234 +454,98 @@ pub async fn validate() {
 }
 
 /// Attempt to validate that a given transaction has been accepted into the blockchain and
-/// is at least some configurable number of blocks behind the head.
-pub async fn validate_transaction(ts: ToValidate) {
+/// is at least some configurable number of blocks behind the head. The transaction is checked
+/// against every given backend in parallel since we don't know what chain it is on
+async fn validate_transaction_with(
+    ts: ToValidate,
+    our_id: Identity,
+    backends: &[Box<dyn PaymentBackend>],
+) {
     trace!("validating transaction");
-    // check both in parallel since we don't know what chain this is on
-    join(
-        handle_althea_tx_checking(ts.clone()),
-        handle_xdai_tx_checking(ts),
-    )
-    .await;
-}
-
-async fn handle_xdai_tx_checking(ts: ToValidate) {
-    let full_node = get_web3_server();
-    let web3 = Web3::new(&full_node, TRANSACTION_VERIFICATION_TIMEOUT);
-
     let txid = ts.payment.txid;
+    let results: Vec<Result<TxStatus, PaymentBackendError>> =
+        join_all(backends.iter().map(|b| b.validate(txid))).await;
+
+    // any response from a full node, even that it does not know of this tx, satisfies our
+    // checked requirement
+    if !ts.checked
+        && results
+            .iter()
+            .any(|r| matches!(r, Ok(_) | Err(PaymentBackendError::TxLookupError(_))))
+    {
+        checked(ts.clone());
+    }
 
-    let eth_block_num = web3.eth_block_number().await;
-    let eth_transaction = web3.eth_get_transaction_by_hash(txid).await;
-    match (eth_transaction, eth_block_num) {
-        (Ok(Some(transaction)), Ok(block_num)) => {
-            if !ts.checked {
-                checked(ts.clone());
-            }
-            handle_tx_messaging_xdai(ts.payment.txid, transaction, ts.clone(), block_num);
-        }
-        (Ok(None), _) => {
-            // we have a response back from the full node that this tx is not in the mempool this
-            // satisfies our checked requirement
-            if !ts.checked {
-                checked(ts.clone());
-            }
-        }
-        (Err(_), Ok(_)) => {
-            // we get an error from the full node but a successful block request, clearly we can contact
-            // the full node so the transaction check has been attempted
-            if !ts.checked {
-                checked(ts.clone());
+    for (backend, result) in backends.iter().zip(results) {
+        match result {
+            Ok(TxStatus::Found(transaction)) => {
+                handle_tx_messaging(backend.as_ref(), transaction, ts.clone(), our_id)
             }
+            Ok(TxStatus::NotFound) => {}
+            Err(e) => trace!(
+                "Failed to check transaction {:#066x} with {} {}",
+                txid,
+                backend.full_node(),
+                e
+            ),
         }
-        (Ok(Some(_)), Err(_)) => trace!("Failed to check transaction {:#066x}", txid),
-        (Err(_), Err(_)) => trace!("Failed to check transaction {:#066x}", txid),
-    }
-}
-
-fn get_xdai_transaction_details(
-    transaction: TransactionResponse,
-) -> (Option<Address>, Address, Uint256, Option<Uint256>) {
-    match transaction {
-        TransactionResponse::Eip1559 {
-            to,
-            from,
-            value,
-            block_number,
-            ..
-        } => (to, from, value, block_number),
-        TransactionResponse::Eip2930 {
-            to,
-            from,
-            value,
-            block_number,
-            ..
-        } => (to, from, value, block_number),
-        TransactionResponse::Legacy {
-            to,
-            from,
-            value,
-            block_number,
-            ..
-        } => (to, from, value, block_number),
     }
 }
 
-async fn handle_althea_tx_checking(ts: ToValidate) {
-    let cosmos_node_grpc = get_rita_common().payment.althea_grpc_list[0].clone();
-    let althea_contact = Contact::new(
-        &cosmos_node_grpc,
-        ALTHEA_CONTACT_TIMEOUT,
-        ALTHEA_CHAIN_PREFIX,
-    )
-    .unwrap();
-    // convert to hex string
-    let txhash = ts.payment.txid.to_str_radix(16);
-
-    let althea_transaction = althea_contact.get_tx_by_hash(txhash.clone()).await;
-    let althea_chain_status = althea_contact.get_chain_status().await;
-
-    match (althea_transaction, althea_chain_status) {
-        (Ok(transaction), Ok(chain_status)) => {
-            if let Some(tx_resp) = transaction.tx_response {
-                if !ts.checked {
-                    checked(ts.clone());
-                }
-
-                let tx = match tx_resp.tx {
-                    Some(a) => a.value,
-                    None => {
-                        error!("Althea chain tx {:?} has no tx field?", tx_resp);
-                        return;
-                    }
-                };
-
-                if let ChainStatus::Moving { block_height: _ } = chain_status {
-                    // Decode TxRaw
-                    let raw_tx_any = prost_types::Any {
-                        type_url: "/cosmos.tx.v1beta1.Tx".to_string(),
-                        value: tx,
-                    };
-                    let tx_raw: TxRaw = match decode_any(raw_tx_any) {
-                        Ok(a) => a,
-                        Err(e) => {
-                            error!("Unable to decode raw_tx with {}", e);
-                            return;
-                        }
-                    };
-
-                    // Decode TxBody
-                    let body_any = prost_types::Any {
-                        type_url: "/cosmos.tx.v1beta1.TxBody".to_string(),
-                        value: tx_raw.body_bytes,
-                    };
-                    let tx_body: TxBody = match decode_any(body_any) {
-                        Ok(a) => a,
-                        Err(e) => {
-                            error!("Unable to decode body_any with {}", e);
-                            return;
-                        }
-                    };
-
-                    // Decode MsgSend and send each one to validator
-                    for message in tx_body.messages {
-                        let msg_send = prost_types::Any {
-                            type_url: "/cosmos.bank.v1beta1.MsgSend".to_string(),
-                            value: message.value.clone(),
-                        };
-                        let msg_send: Result<MsgSend, _> = decode_any(msg_send);
-                        if let Ok(msg) = msg_send {
-                            for coin_tx in msg.amount {
-                                let transaction_details = TransactionDetails {
-                                    to: match msg.to_address.parse() {
-                                        Ok(a) => a,
-                                        Err(e) => {
-                                            error!(
-                                                    "Unable to parse send address {}f for tx {} with {}",
-                                                    msg.to_address,
-                                                    ts.clone(),
-                                                    e
-                                                );
-                                            continue;
-                                        }
-                                    },
-                                    from: match msg.from_address.parse() {
-                                        Ok(a) => a,
-                                        Err(e) => {
-                                            error!(
-                                                "Unable to parse send address {} for tx {} with {}",
-                                                msg.to_address,
-                                                ts.clone(),
-                                                e
-                                            );
-                                            continue;
-                                        }
-                                    },
-                                    amount: match Uint256::from_str_radix(&coin_tx.amount, 10) {
-                                        Ok(a) => a,
-                                        Err(e) => {
-                                            error!(
-                                                "Unable to parse amount : {:?} for tx {:?} with {}",
-                                                coin_tx.amount,
-                                                ts.clone(),
-                                                e
-                                            );
-                                            continue;
-                                        }
-                                    },
-                                    denom: coin_tx.denom,
-                                };
-                                handle_tx_messaging_althea(transaction_details, ts.clone());
-                            }
-                        }
-                    }
-                } else {
-                    error!(
-                        "Unable to check transaction id {} because of chain status {:?}",
-                        txhash.clone(),
-                        chain_status
-                    )
-                }
-            }
-        }
-        (Ok(transaction), _) => {
-            // we have a response back from the full node that this tx is not in the mempool this
-            // satisfies our checked requirement
-            if transaction.tx_response.is_some() && !ts.checked {
-                checked(ts.clone());
-            }
-        }
-        (Err(_), Ok(_)) => {
-            // we get an error from the full node but a successful block request, clearly we can contact
-            // the full node so the transaction check has been attempted
-            if !ts.checked {
-                checked(ts.clone());
-            }
+/// Selects the transfer in a (possibly batched) transaction that corresponds to the given payment,
+/// if there is no transfer between the payment's sender and receiver we return the first one so
+/// that validation can reject it
+fn find_payment_message(
+    backend: &dyn PaymentBackend,
+    messages: Vec<TransactionDetails>,
+    pmt: &PaymentTx,
+) -> Option<TransactionDetails> {
+    let to = backend.address(&pmt.to);
+    let from = backend.address(&pmt.from);
+    let mut best = None;
+    for message in messages {
+        let parties_match = message.to == to && message.from == from;
+        if parties_match && message.amount == pmt.amount {
+            return Some(message);
+        } else if parties_match || best.is_none() {
+            best = Some(message);
         }
-        _ => trace!("Failed to check transaction {:?}", txhash),
     }
+    best
 }
 
-fn handle_tx_messaging_xdai(
-    txid: Uint256,
-    transaction: TransactionResponse,
+/// Handles the tx response from the full node and it's various cases
+/// pulled out of validate_transaction_with purely for cosmetic reasons
+fn handle_tx_messaging(
+    backend: &dyn PaymentBackend,
+    transaction: ChainTransaction,
     ts: ToValidate,
-    current_block: Uint256,
+    our_id: Identity,
 ) {
-    let from_address = ts.payment.from.eth_address;
-    let amount = ts.payment.amount;
     let pmt = ts.payment;
-    let our_address = settings::get_rita_common()
-        .payment
-        .eth_address
-        .expect("No Address!");
+    let txid = ts.payment.txid;
+    let amount = ts.payment.amount;
 
-    let (tx_to, tx_from, tx_value, tx_block_number) = get_xdai_transaction_details(transaction);
+    let transfer = match find_payment_message(backend, transaction.transfers, &pmt) {
+        Some(a) => a,
+        None => {
+            error!("Invalid TX {:#066x}! No transfers!", txid);
+            remove(Remove {
+                tx: ts,
+                success: false,
+            });
+            return;
+        }
+    };
 
-    let to = match tx_to {
-        Some(val) => val,
+    // Verify that denom is valid
+    let denom = match backend.accepted_denom(&transfer.denom) {
+        Some(a) => a,
         None => {
-            error!("Invalid TX! No destination!");
+            error!(
+                "Invalid Denom! We do not currently support {}!",
+                transfer.denom
+            );
             remove(Remove {
                 tx: ts,
                 success: false,
@@ -635,14 +554,14 @@ fn handle_tx_messaging_xdai(
         }
     };
 
+    let our_address = backend.address(&our_id);
+
     // notice we get these values from the blockchain using 'transaction' not ts which may be a lie since we don't
     // actually cryptographically validate the txhash locally. Instead we just compare the value we get from the full
     // node
-    let to_us = to == our_address;
-    let from_us = tx_from == our_address;
-    let value_correct = tx_value == amount;
-    let is_in_chain = payment_in_chain(current_block, tx_block_number);
-    let is_old = payment_is_old(current_block, tx_block_number);
+    let to_us = transfer.to == our_address;
+    let from_us = transfer.from == our_address;
+    let value_correct = transfer.amount == amount;
 
     if !value_correct {
         error!("Transaction with invalid amount!");
@@ -653,7 +572,7 @@ fn handle_tx_messaging_xdai(
         return;
     }
 
-    if is_old {
+    if transaction.too_old {
         error!("Transaction is more than 6 hours old! {:#066x}", txid);
         remove(Remove {
             tx: ts,
@@ -662,7 +581,7 @@ fn handle_tx_messaging_xdai(
         return;
     }
 
-    match (to_us, from_us, is_in_chain) {
+    match (to_us, from_us, transaction.confirmed) {
         // we were successfully paid
         (true, false, true) => {
             // remove this transaction from our storage
@@ -671,43 +590,33 @@ fn handle_tx_messaging_xdai(
                 success: true,
             });
             info!(
-                "payment {:#066x} from {} for {} wei successfully validated!",
-                txid, from_address, amount
-            );
-            // update debt keeper with the details of this payment
-            let _ = payment_received(
-                pmt.from,
-                pmt.amount,
-                Denom {
-                    denom: DEBT_KEEPER_DENOM.to_string(),
-                    decimal: DEBT_KEEPER_DENOM_DECIMAL,
-                },
+                "payment {:#066x} from {:?} for {} {} successfully validated!",
+                txid, transfer.from, amount, denom.denom
             );
 
+            // update debt keeper with the details of this payment
+            let _ = payment_received(pmt.from, pmt.amount, denom.clone());
+            watch(backend, pmt, denom);
             // update the usage tracker with the details of this payment
             update_payments(pmt);
         }
         // we successfully paid someone
         (false, true, true) => {
             info!(
-                "payment {:#066x} from {} for {} wei successfully sent!",
-                txid, from_address, amount
+                "payment {:#066x} from {:?} for {} {} successfully sent!",
+                txid, transfer.from, amount, denom.denom
             );
+
             // remove this transaction from our storage
             remove(Remove {
                 tx: ts,
                 success: true,
             });
-            // update debt keeper with the details of this payment
-            let _ = payment_succeeded(
-                pmt.to,
-                pmt.amount,
-                Denom {
-                    denom: DEBT_KEEPER_DENOM.to_string(),
-                    decimal: DEBT_KEEPER_DENOM_DECIMAL,
-                },
-            );
 
+            // update debt keeper with the details of this payment
+            let _ = payment_succeeded(pmt.to, pmt.amount, denom.clone());
+            journal_payment_confirmed(pmt);
+            watch(backend, pmt, denom);
             // update the usage tracker with the details of this payment
             update_payments(pmt);
 
@@ -734,160 +643,113 @@ fn handle_tx_messaging_xdai(
     }
 }
 
-/// Handles the tx response from the full node and it's various cases
-/// pulled out of validate_transaction purely for cosmetic reasons
-fn handle_tx_messaging_althea(transaction: TransactionDetails, ts: ToValidate) {
-    let pmt = ts.payment;
-
-    // txid is for eth chain and txhash is for althea chain, only one of these should be
-    // Some(..). This was verified before
-    let txid = ts.payment.txid;
-
-    // Verify that denom is valid
-    let mut denom: Option<Denom> = None;
-    for d in get_rita_common()
-        .payment
-        .accepted_denoms
-        .unwrap_or(HashMap::new())
-    {
-        if transaction.denom == d.1.denom {
-            denom = Some(d.1);
-        }
-    }
-    if denom.is_none() {
-        error!(
-            "Invalid Denom! We do not currently support {}!",
-            transaction.denom
-        );
-        remove(Remove {
-            tx: ts,
-            success: false,
-        });
-        return;
-    }
-
-    let amount = ts.payment.amount;
-
-    let our_id = settings::get_rita_common().get_identity().unwrap();
-    let our_address_althea = our_id.get_althea_address();
-
-    let to = transaction.to;
-    let from = transaction.from;
-
-    // notice we get these values from the blockchain using 'transaction' not ts which may be a lie since we don't
-    // actually cryptographically validate the txhash locally. Instead we just compare the value we get from the full
-    // node
-    let to_us = to == our_address_althea;
-    let from_us = from == our_address_althea;
-    let value_correct = transaction.amount == amount;
-
-    if !value_correct {
-        error!("Transaction with invalid amount!");
-        remove(Remove {
-            tx: ts,
-            success: false,
-        });
+/// Starts watching a payment we just credited for reorgs, if it is on a chain that has them
+fn watch(backend: &dyn PaymentBackend, pmt: PaymentTx, denom: Denom) {
+    if !backend.can_reorg() {
         return;
     }
+    add_watched_transaction(
+        pmt,
+        Watched {
+            denom,
+            full_node: backend.full_node(),
+            validated: Instant::now(),
+        },
+    );
+}
 
-    match (to_us, from_us) {
-        // we were successfully paid
-        (true, false) => {
-            // remove this transaction from our storage
-            remove(Remove {
-                tx: ts,
-                success: true,
-            });
-            info!(
-                "payment {:#066x} from {} for {} {} successfully validated!",
-                txid,
-                from,
-                amount,
-                denom.clone().expect("Already verified existance").denom
-            );
-
-            // update debt keeper with the details of this payment
-            let _ = payment_received(
-                pmt.from,
-                pmt.amount,
-                denom.expect("How did this happen when we already verified existence"),
-            );
-            // update the usage tracker with the details of this payment
-            update_payments(pmt);
-        }
-        // we successfully paid someone
-        (false, true) => {
-            info!(
-                "payment {:#066x} from {} for {} {} successfully sent!",
-                txid,
-                from,
-                amount,
-                denom.clone().expect("Already verified existance").denom
-            );
-
-            // remove this transaction from our storage
-            remove(Remove {
-                tx: ts,
-                success: true,
-            });
-
-            // update debt keeper with the details of this payment
-            let _ = payment_succeeded(
-                pmt.to,
-                pmt.amount,
-                denom.expect("How did this happen when we already verified existence"),
-            );
-            // update the usage tracker with the details of this payment
-            update_payments(pmt);
-
-            // Store this payment as a receipt to send in the future if this receiver doesnt see the payment
-            store_payment(pmt);
-        }
-        (true, true) => {
-            error!("Transaction to ourselves!");
-            remove(Remove {
-                tx: ts,
-                success: false,
-            });
-        }
-        (false, false) => {
-            error!("Transaction has nothing to do with us?");
-            remove(Remove {
-                tx: ts,
-                success: false,
-            });
+/// Checks every watched payment against the backend that validated it, payments that are older
+/// than the watch window as of `now` are final and no longer watched
+async fn check_for_reorgs(
+    watched_transactions: HashMap<PaymentTx, Watched>,
+    our_id: Identity,
+    backends: &[Box<dyn PaymentBackend>],
+    watch_window: Duration,
+    now: Instant,
+) {
+    let mut futs = Vec::new();
+    for (pmt, watched) in watched_transactions {
+        match now.checked_duration_since(watched.validated) {
+            Some(elapsed) if elapsed > watch_window => {
+                trace!("Payment {:#066x} is past the reorg window", pmt.txid);
+                remove_watched_transaction(&pmt);
+            }
+            // if the backends have changed since this payment was validated we can't check it,
+            // it will age out of the watch window
+            _ => {
+                if let Some(backend) = backends.iter().find(|b| b.full_node() == watched.full_node)
+                {
+                    futs.push(check_for_reorg(pmt, watched, our_id, backend.as_ref()));
+                }
+            }
         }
     }
+    join_all(futs).await;
 }
 
-/// Determine if a given payment satisfies our criteria for being in the blockchain
-fn payment_in_chain(chain_height: Uint256, tx_height: Option<Uint256>) -> bool {
-    match tx_height {
-        Some(tx_block) => {
-            // somehow the block is newer than our block height request, wait until later
-            if tx_block > chain_height {
-                false
-            } else {
-                chain_height - tx_block >= Uint256::from(BLOCKS_TO_CONFIRM)
-            }
-        }
-        None => false,
+/// Checks that a watched payment is still in a block, if the full node tells us it has been
+/// removed from the chain or is back in the mempool the payment is reversed
+async fn check_for_reorg(
+    pmt: PaymentTx,
+    watched: Watched,
+    our_id: Identity,
+    backend: &dyn PaymentBackend,
+) {
+    match backend.validate(pmt.txid).await {
+        Ok(TxStatus::Found(transaction)) if !transaction.pending => {}
+        Ok(_) => reverse_payment(pmt, watched, our_id),
+        Err(e) => trace!(
+            "Failed to check watched transaction {:#066x} with {} {}",
+            pmt.txid,
+            backend.full_node(),
+            e
+        ),
     }
 }
 
-/// Determine if a given payment is older than what we shoul accept
-fn payment_is_old(chain_height: Uint256, tx_height: Option<Uint256>) -> bool {
-    match tx_height {
-        Some(tx_block) => {
-            // somehow the block is newer than our block height request, wait until later
-            if tx_block > chain_height {
-                false
-            } else {
-                chain_height - tx_block > Uint256::from(BLOCKS_TO_OLD)
-            }
-        }
-        None => false,
+/// Undoes the effects of a validated payment that has been removed from the chain by a reorg
+/// and puts it back in the validation queue, it will be credited again if it is mined on the
+/// new fork
+fn reverse_payment(pmt: PaymentTx, watched: Watched, our_id: Identity) {
+    // another check may have already reversed this payment
+    if !remove_watched_transaction(&pmt) {
+        return;
+    }
+    let from_us = pmt.from.eth_address == our_id.eth_address;
+    error!(
+        "Payment {:#066x} from {} to {} for {} {} was removed from the chain by a reorg after \
+         being validated on {}, reversing it! Please review this payment",
+        pmt.txid,
+        pmt.from.wg_public_key,
+        pmt.to.wg_public_key,
+        pmt.amount,
+        watched.denom.denom,
+        watched.full_node
+    );
+
+    let res = if from_us {
+        let res = payment_succeeded_reversed(pmt.to, pmt.amount, watched.denom);
+        // don't make another payment right away, the original may still be mined on the new fork
+        set_payment_in_flight(pmt.to);
+        remove_stored_payment(&pmt);
+        res
+    } else {
+        payment_received_reversed(pmt.from, pmt.amount, watched.denom)
+    };
+    if let Err(e) = res {
+        error!("Failed to reverse payment {:#066x} {:?}", pmt.txid, e);
     }
+
+    let writer = &mut *HISTORY.write().unwrap();
+    let payment_validator = get_payment_validator_write_ref(writer);
+    payment_validator.successful_transactions.remove(&pmt);
+    payment_validator
+        .unvalidated_transactions
+        .insert(ToValidate {
+            payment: pmt,
+            received: Instant::now(),
+            checked: false,
+        });
 }
 
 fn print_txids(list: &HashSet<ToValidate>) -> String {
@@ -900,11 +762,17 @@ fn print_txids(list: &HashSet<ToValidate>) -> String {
 
 #[cfg(test)]
 mod tests {
-    use actix_async::System;
-    use cosmos_sdk_proto_althea::cosmos::bank::v1beta1::MsgSend;
-    use deep_space::utils::decode_any;
-
+    use crate::debt_keeper::dump;
+    use crate::payment_backend::cosmos::CosmosBackend;
+    use crate::payment_backend::ethereum::EthereumBackend;
+    use crate::payment_backend::mock::MockBackend;
+    use crate::payment_backend::PaymentAddress;
     use crate::usage_tracker::tests::test::random_identity;
+    use actix_async::System;
+    use althea_types::UnpublishedPaymentTx;
+    use clarity::PrivateKey;
+    use mock_chain::{start_ethereum_server, Account, MockChain, ETH_DENOM};
+    use settings::{DEBT_KEEPER_DENOM, DEBT_KEEPER_DENOM_DECIMAL};
 
     use super::*;
 
@@ -997,47 +865,411 @@ mod tests {
         assert_eq!(get_payment_txids(pmt3.to), sent_hashset);
     }
 
-    #[ignore]
     #[test]
-    fn test_althea_chain_response() {
+    /// Payments sharing a txid are allowed as long as they are between different nodes
+    fn test_batched_tx_validate_later() {
+        let payment = generate_fake_payment();
+        let mut batched = generate_fake_payment();
+        batched.payment.txid = payment.payment.txid;
+        assert!(validate_later(payment.clone()).is_ok());
+        assert!(validate_later(batched).is_ok());
+        assert!(validate_later(payment).is_err());
+    }
+
+    #[test]
+    fn test_find_payment_message() {
+        let backend = CosmosBackend::new(String::new());
+        let pmt = generate_fake_payment().payment;
+        let other = generate_fake_payment().payment;
+        let to_other = TransactionDetails {
+            to: PaymentAddress::Althea(other.to.get_althea_address()),
+            from: PaymentAddress::Althea(pmt.from.get_althea_address()),
+            amount: pmt.amount,
+            denom: "usdc".to_string(),
+        };
+        let to_us_wrong_amount = TransactionDetails {
+            to: PaymentAddress::Althea(pmt.to.get_althea_address()),
+            from: PaymentAddress::Althea(pmt.from.get_althea_address()),
+            amount: pmt.amount + 1u8.into(),
+            denom: "usdc".to_string(),
+        };
+        let to_us = TransactionDetails {
+            to: PaymentAddress::Althea(pmt.to.get_althea_address()),
+            from: PaymentAddress::Althea(pmt.from.get_althea_address()),
+            amount: pmt.amount,
+            denom: "usdc".to_string(),
+        };
+
+        assert_eq!(find_payment_message(&backend, Vec::new(), &pmt), None);
+        // nothing addressed to us, we still return a message so that it can be rejected
+        assert_eq!(
+            find_payment_message(&backend, vec![to_other.clone()], &pmt),
+            Some(to_other.clone())
+        );
+        assert_eq!(
+            find_payment_message(
+                &backend,
+                vec![to_other.clone(), to_us_wrong_amount.clone(), to_us.clone()],
+                &pmt
+            ),
+            Some(to_us)
+        );
+        assert_eq!(
+            find_payment_message(&backend, vec![to_other, to_us_wrong_amount.clone()], &pmt),
+            Some(to_us_wrong_amount)
+        );
+    }
+
+    #[test]
+    /// Runs an incoming payment through validation against the mock chain
+    fn test_validate_with_mock_backend() {
         let runner = System::new();
         runner.block_on(async move {
-            let contact =
-                Contact::new("http://althea.zone:9090", ALTHEA_CONTACT_TIMEOUT, "althea").unwrap();
-
-            let tx = contact
-                .get_tx_by_hash(
-                    "B0943ECCC5565A39D021EE815A82006B01FC87A9BED4EBDD0A448AC161007FF0".to_string(),
+            let backend = MockBackend::new();
+            let mut ts = generate_fake_payment();
+            let our_id = ts.payment.to;
+            backend.set_auto_confirm(false);
+            backend.set_balance(&ts.payment.from, ts.payment.amount);
+            let txid = backend
+                .send(
+                    &[(
+                        UnpublishedPaymentTx {
+                            to: ts.payment.to,
+                            from: ts.payment.from,
+                            amount: ts.payment.amount,
+                        },
+                        Denom {
+                            denom: DEBT_KEEPER_DENOM.to_string(),
+                            decimal: DEBT_KEEPER_DENOM_DECIMAL,
+                        },
+                    )],
+                    PrivateKey::from_bytes([1u8; 32]).unwrap(),
                 )
                 .await
-                .expect("Unable to get tx by hash");
-            println!("{:?}", tx.tx_response.clone().unwrap().tx);
+                .unwrap();
+            ts.payment.txid = txid;
+            let backends: Vec<Box<dyn PaymentBackend>> = vec![Box::new(backend.clone())];
+            assert!(validate_later(ts.clone()).is_ok());
+
+            // not yet confirmed, we have talked to a full node but must wait
+            validate_transaction_with(ts.clone(), our_id, &backends).await;
+            let mut checked_ts = ts.clone();
+            checked_ts.checked = true;
+            assert!(get_unvalidated_transactions().contains(&checked_ts));
+            assert!(!get_all_successful_tx().contains(&ts.payment));
+
+            backend.confirm_all();
+            validate_transaction_with(checked_ts.clone(), our_id, &backends).await;
+            assert!(!get_unvalidated_transactions().contains(&checked_ts));
+            assert!(get_all_successful_tx().contains(&ts.payment));
+        });
+    }
 
-            let raw_tx_any = prost_types::Any {
-                type_url: "/cosmos.tx.v1beta1.Tx".to_string(),
-                value: tx.tx_response.unwrap().tx.unwrap().value,
-            };
-            let tx_raw: TxRaw = decode_any(raw_tx_any).unwrap();
+    #[test]
+    /// A payment claiming a txid that paid someone else is rejected
+    fn test_validate_wrong_recipient_with_mock_backend() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let backend = MockBackend::new();
+            let ts = generate_fake_payment();
+            let other = random_identity();
+            backend.insert_transaction(
+                ts.payment.txid,
+                ChainTransaction {
+                    transfers: vec![TransactionDetails {
+                        to: backend.address(&other),
+                        from: backend.address(&ts.payment.from),
+                        amount: ts.payment.amount,
+                        denom: DEBT_KEEPER_DENOM.to_string(),
+                    }],
+                    confirmed: true,
+                    too_old: false,
+                    pending: false,
+                },
+            );
+            let backends: Vec<Box<dyn PaymentBackend>> = vec![Box::new(backend)];
+            assert!(validate_later(ts.clone()).is_ok());
+
+            validate_transaction_with(ts.clone(), ts.payment.to, &backends).await;
+            let mut checked_ts = ts.clone();
+            checked_ts.checked = true;
+            assert!(!get_unvalidated_transactions().contains(&checked_ts));
+            assert!(!get_all_successful_tx().contains(&ts.payment));
+        });
+    }
 
-            println!("{:?}", tx_raw);
+    /// The current version of this payment in the queue, other tests share it
+    fn pending(ts: &ToValidate) -> HashSet<ToValidate> {
+        get_unvalidated_transactions()
+            .into_iter()
+            .filter(|t| t.payment == ts.payment)
+            .collect()
+    }
 
-            let body_any = prost_types::Any {
-                type_url: "/cosmos.tx.v1beta1.TxBody".to_string(),
-                value: tx_raw.body_bytes,
-            };
-            let tx_body: TxBody = decode_any(body_any).unwrap();
+    fn is_unvalidated(ts: &ToValidate) -> bool {
+        !pending(ts).is_empty()
+    }
 
-            println!("{:?}", tx_body);
+    #[test]
+    /// Incoming payments are dropped once PAYMENT_RECEIVE_TIMEOUT passes, outgoing ones
+    /// after PAYMENT_SEND_TIMEOUT
+    fn test_validate_timeouts() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let backend = MockBackend::new();
+            let backends: Vec<Box<dyn PaymentBackend>> = vec![Box::new(backend)];
+            let start = Instant::now();
+            let now = start + PAYMENT_RECEIVE_TIMEOUT + Duration::from_secs(1);
+            let our_id = random_identity();
+
+            let mut timed_out = generate_fake_payment();
+            timed_out.payment.to = our_id;
+            timed_out.received = start;
+            timed_out.checked = true;
+            let mut incoming = generate_fake_payment();
+            incoming.payment.to = our_id;
+            incoming.received = now - PAYMENT_SEND_TIMEOUT - Duration::from_secs(1);
+            let mut outgoing = generate_fake_payment();
+            outgoing.payment.from = our_id;
+            outgoing.received = incoming.received;
+
+            let items: HashSet<ToValidate> =
+                vec![timed_out.clone(), incoming.clone(), outgoing.clone()]
+                    .into_iter()
+                    .collect();
+            for item in items.iter() {
+                assert!(validate_later(item.clone()).is_ok());
+            }
+            validate_transactions(items, our_id, &backends, now).await;
 
-            for message in tx_body.messages {
-                let msg_send = prost_types::Any {
-                    type_url: "/cosmos.bank.v1beta1.MsgSend".to_string(),
-                    value: message.value.clone(),
-                };
-                let msg_send: Result<MsgSend, _> = decode_any(msg_send);
+            assert!(!is_unvalidated(&timed_out));
+            assert!(!get_all_successful_tx().contains(&timed_out.payment));
+            assert!(!is_unvalidated(&outgoing));
+            // still within the receive timeout, we keep waiting for it
+            assert!(is_unvalidated(&incoming));
+        });
+    }
 
-                println!("\n\n{:?}", msg_send);
+    #[test]
+    /// A payment on an Ethereum chain is only accepted once it is BLOCKS_TO_CONFIRM deep
+    fn test_validate_blocks_to_confirm() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let chain = MockChain::new();
+            let backend = EthereumBackend::new(start_ethereum_server(chain.clone()).unwrap());
+            let backends: Vec<Box<dyn PaymentBackend>> = vec![Box::new(backend)];
+            let mut ts = generate_fake_payment();
+            let our_id = ts.payment.to;
+            chain.set_balance(
+                Account::Eth(ts.payment.from.eth_address),
+                ETH_DENOM,
+                ts.payment.amount,
+            );
+            chain.set_inclusion_delay(1);
+            ts.payment.txid = chain.submit_eth_transfer(
+                ts.payment.from.eth_address,
+                ts.payment.to.eth_address,
+                ts.payment.amount,
+            );
+            assert!(validate_later(ts.clone()).is_ok());
+
+            // in the mempool, then in a block but not deep enough
+            for blocks in [0, 2, 3] {
+                chain.mine_blocks(blocks);
+                validate_transactions(pending(&ts), our_id, &backends, Instant::now()).await;
+                assert!(is_unvalidated(&ts));
+                assert!(!get_all_successful_tx().contains(&ts.payment));
             }
+
+            chain.mine_block();
+            validate_transactions(pending(&ts), our_id, &backends, Instant::now()).await;
+            assert!(!is_unvalidated(&ts));
+            assert!(get_all_successful_tx().contains(&ts.payment));
+        });
+    }
+
+    /// The total debt keeper has credited this node with
+    fn total_received(id: &Identity) -> Uint256 {
+        dump()
+            .get(id)
+            .map(|d| d.total_payment_received)
+            .unwrap_or_default()
+    }
+
+    #[test]
+    /// A validated payment that a reorg returns to the mempool is reversed and queued again, then
+    /// credited once more when it is mined on the new fork
+    fn test_validate_reorg() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let chain = MockChain::new();
+            let backend = EthereumBackend::new(start_ethereum_server(chain.clone()).unwrap());
+            let backends: Vec<Box<dyn PaymentBackend>> = vec![Box::new(backend)];
+            let window = Duration::from_secs(3600);
+            let mut ts = generate_fake_payment();
+            let our_id = ts.payment.to;
+            chain.set_balance(
+                Account::Eth(ts.payment.from.eth_address),
+                ETH_DENOM,
+                ts.payment.amount,
+            );
+            ts.payment.txid = chain.submit_eth_transfer(
+                ts.payment.from.eth_address,
+                ts.payment.to.eth_address,
+                ts.payment.amount,
+            );
+            chain.mine_blocks(5);
+            assert!(validate_later(ts.clone()).is_ok());
+            validate_transactions(pending(&ts), our_id, &backends, Instant::now()).await;
+            assert!(get_all_successful_tx().contains(&ts.payment));
+            assert!(get_watched_transactions().contains_key(&ts.payment));
+            assert_eq!(total_received(&ts.payment.from), ts.payment.amount);
+
+            // still in a block, nothing changes
+            check_for_reorgs(
+                get_watched_transactions(),
+                our_id,
+                &backends,
+                window,
+                Instant::now(),
+            )
+            .await;
+            assert!(get_watched_transactions().contains_key(&ts.payment));
+            assert_eq!(total_received(&ts.payment.from), ts.payment.amount);
+
+            chain.reorg(5);
+            check_for_reorgs(
+                get_watched_transactions(),
+                our_id,
+                &backends,
+                window,
+                Instant::now(),
+            )
+            .await;
+            assert!(!get_watched_transactions().contains_key(&ts.payment));
+            assert!(!get_all_successful_tx().contains(&ts.payment));
+            assert!(is_unvalidated(&ts));
+            assert_eq!(total_received(&ts.payment.from), 0u8.into());
+
+            chain.mine_blocks(5);
+            validate_transactions(pending(&ts), our_id, &backends, Instant::now()).await;
+            assert!(!is_unvalidated(&ts));
+            assert!(get_all_successful_tx().contains(&ts.payment));
+            assert_eq!(total_received(&ts.payment.from), ts.payment.amount);
+        });
+    }
+
+    #[test]
+    /// Payments are final once they are older than the watch window
+    fn test_reorg_watch_window() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let backend = MockBackend::new();
+            let backends: Vec<Box<dyn PaymentBackend>> = vec![Box::new(backend.clone())];
+            let window = Duration::from_secs(3600);
+            let ts = generate_fake_payment();
+            let our_id = ts.payment.to;
+            let validated = Instant::now();
+            add_watched_transaction(
+                ts.payment,
+                Watched {
+                    denom: Denom {
+                        denom: DEBT_KEEPER_DENOM.to_string(),
+                        decimal: DEBT_KEEPER_DENOM_DECIMAL,
+                    },
+                    full_node: backend.full_node(),
+                    validated,
+                },
+            );
+
+            // the full node can't find it but it is past the window, so it is not reversed
+            check_for_reorgs(
+                get_watched_transactions(),
+                our_id,
+                &backends,
+                window,
+                validated + window + Duration::from_secs(1),
+            )
+            .await;
+            assert!(!get_watched_transactions().contains_key(&ts.payment));
+            assert!(!is_unvalidated(&ts));
+        });
+    }
+
+    #[test]
+    /// A full node that fails to look up a watched payment is not a reorg, and payments on chains
+    /// with instant finality are never watched
+    fn test_reorg_lookup_error() {
+        let runner = System::new();
+        runner.block_on(async move {
+            let backend = MockBackend::new();
+            let backends: Vec<Box<dyn PaymentBackend>> = vec![Box::new(backend.clone())];
+            let window = Duration::from_secs(3600);
+            let ts = generate_fake_payment();
+            let our_id = ts.payment.to;
+            backend.insert_transaction(
+                ts.payment.txid,
+                ChainTransaction {
+                    transfers: vec![TransactionDetails {
+                        to: backend.address(&ts.payment.to),
+                        from: backend.address(&ts.payment.from),
+                        amount: ts.payment.amount,
+                        denom: DEBT_KEEPER_DENOM.to_string(),
+                    }],
+                    confirmed: true,
+                    too_old: false,
+                    pending: false,
+                },
+            );
+            assert!(validate_later(ts.clone()).is_ok());
+            validate_transaction_with(ts.clone(), our_id, &backends).await;
+            assert!(get_all_successful_tx().contains(&ts.payment));
+            assert!(get_watched_transactions().contains_key(&ts.payment));
+
+            backend.set_lookups_failing(true);
+            check_for_reorgs(
+                get_watched_transactions(),
+                our_id,
+                &backends,
+                window,
+                Instant::now(),
+            )
+            .await;
+            assert!(get_watched_transactions().contains_key(&ts.payment));
+            assert!(get_all_successful_tx().contains(&ts.payment));
+            assert!(!is_unvalidated(&ts));
+            remove_watched_transaction(&ts.payment);
+
+            // the lookup error still counts as talking to a full node
+            let ts = generate_fake_payment();
+            assert!(validate_later(ts.clone()).is_ok());
+            validate_transaction_with(ts.clone(), our_id, &backends).await;
+            let mut checked_ts = ts.clone();
+            checked_ts.checked = true;
+            assert!(get_unvalidated_transactions().contains(&checked_ts));
+
+            backend.set_lookups_failing(false);
+            backend.set_instant_finality(true);
+            let mut ts = generate_fake_payment();
+            ts.payment.to = our_id;
+            backend.insert_transaction(
+                ts.payment.txid,
+                ChainTransaction {
+                    transfers: vec![TransactionDetails {
+                        to: backend.address(&ts.payment.to),
+                        from: backend.address(&ts.payment.from),
+                        amount: ts.payment.amount,
+                        denom: DEBT_KEEPER_DENOM.to_string(),
+                    }],
+                    confirmed: true,
+                    too_old: false,
+                    pending: false,
+                },
+            );
+            assert!(validate_later(ts.clone()).is_ok());
+            validate_transaction_with(ts.clone(), our_id, &backends).await;
+            assert!(get_all_successful_tx().contains(&ts.payment));
+            assert!(!get_watched_transactions().contains_key(&ts.payment));
         });
     }
 }
@@ -0,0 +1,139 @@
+//! What a node supports, advertised in every tlv hello so that neighbors can decide which
+//! features to use with each other rather than assuming both ends run the same version of Rita.
+//! Neighbors that only send the older bincode hellos have no capabilities at all.
+
+use althea_types::SystemChain;
+use clarity::Address;
+use std::net::IpAddr;
+
+/// The bincode hello, signed or unsigned
+pub const HELLO_PROTOCOL_V1: u8 = 1;
+/// The tlv hello
+pub const HELLO_PROTOCOL_V2: u8 = 2;
+/// Every peer listener protocol version we can speak
+pub const SUPPORTED_PROTOCOL_VERSIONS: &[u8] = &[HELLO_PROTOCOL_V1, HELLO_PROTOCOL_V2];
+
+/// Payment channel updates are accepted, see payment_channel
+pub const FEATURE_PAYMENT_CHANNELS: u32 = 1;
+/// Light client tunnels can be opened to this node. Light clients have been removed so we never
+/// advertise this, but older neighbors still may
+pub const FEATURE_LIGHT_CLIENT: u32 = 1 << 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Peer listener protocol versions this node can speak
+    pub protocol_versions: Vec<u8>,
+    /// Chains this node can be paid on
+    pub payment_chains: Vec<SystemChain>,
+    pub mesh_ip_v2: Option<IpAddr>,
+    /// A bit field of the FEATURE_ constants, bits we don't know about are kept as is
+    pub features: u32,
+    /// The operator managing this node, if any
+    pub operator_address: Option<Address>,
+}
+
+/// Exits have no operator, clients may
+pub fn our_operator_address() -> Option<Address> {
+    if settings::check_if_exit() {
+        None
+    } else {
+        settings::get_rita_client().operator.operator_address
+    }
+}
+
+impl Capabilities {
+    /// The capabilities of this node given the current settings
+    pub fn ours() -> Capabilities {
+        let settings = settings::get_rita_common();
+        let mut features = 0;
+        if settings.payment.payment_channels_enabled {
+            features |= FEATURE_PAYMENT_CHANNELS;
+        }
+        Capabilities {
+            protocol_versions: SUPPORTED_PROTOCOL_VERSIONS.to_vec(),
+            payment_chains: vec![settings.payment.system_chain],
+            mesh_ip_v2: settings.network.mesh_ip_v2,
+            features,
+            operator_address: our_operator_address(),
+        }
+    }
+
+    pub fn supports(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+
+    /// The newest peer listener protocol version in this set, on negotiated capabilities this is
+    /// the version we should speak to the neighbor
+    pub fn best_protocol_version(&self) -> Option<u8> {
+        self.protocol_versions.iter().max().copied()
+    }
+
+    /// On negotiated capabilities, true if the neighbor can be paid on this chain
+    pub fn accepts_payment_on(&self, chain: SystemChain) -> bool {
+        self.payment_chains.contains(&chain)
+    }
+
+    /// What we and a neighbor both support, the neighbor's v2 mesh ip and operator are kept as
+    /// is since they are theirs to advertise
+    pub fn negotiate(&self, theirs: &Capabilities) -> Capabilities {
+        Capabilities {
+            protocol_versions: self
+                .protocol_versions
+                .iter()
+                .filter(|v| theirs.protocol_versions.contains(v))
+                .copied()
+                .collect(),
+            payment_chains: self
+                .payment_chains
+                .iter()
+                .filter(|c| theirs.payment_chains.contains(c))
+                .copied()
+                .collect(),
+            mesh_ip_v2: theirs.mesh_ip_v2,
+            features: self.features & theirs.features,
+            operator_address: theirs.operator_address,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_negotiate() {
+        let ours = Capabilities {
+            protocol_versions: vec![HELLO_PROTOCOL_V1, HELLO_PROTOCOL_V2],
+            payment_chains: vec![SystemChain::Xdai, SystemChain::Althea],
+            mesh_ip_v2: None,
+            features: FEATURE_PAYMENT_CHANNELS | 1 << 30,
+            operator_address: None,
+        };
+        let theirs = Capabilities {
+            protocol_versions: vec![HELLO_PROTOCOL_V2, 3],
+            payment_chains: vec![SystemChain::Althea],
+            mesh_ip_v2: Some("fd00::2".parse().unwrap()),
+            // a feature we have never heard of
+            features: FEATURE_PAYMENT_CHANNELS | FEATURE_LIGHT_CLIENT | 1 << 31,
+            operator_address: None,
+        };
+
+        let agreed = ours.negotiate(&theirs);
+        assert_eq!(agreed.protocol_versions, vec![HELLO_PROTOCOL_V2]);
+        assert_eq!(agreed.best_protocol_version(), Some(HELLO_PROTOCOL_V2));
+        assert_eq!(agreed.payment_chains, vec![SystemChain::Althea]);
+        assert!(agreed.accepts_payment_on(SystemChain::Althea));
+        assert!(!agreed.accepts_payment_on(SystemChain::Xdai));
+        assert!(theirs.supports(FEATURE_LIGHT_CLIENT));
+        assert!(!agreed.supports(FEATURE_LIGHT_CLIENT));
+        assert_eq!(agreed.mesh_ip_v2, theirs.mesh_ip_v2);
+        assert!(agreed.supports(FEATURE_PAYMENT_CHANNELS));
+        assert!(!agreed.supports(1 << 30));
+        assert!(!agreed.supports(1 << 31));
+
+        let agreed = ours.negotiate(&Capabilities::default());
+        assert!(agreed.protocol_versions.is_empty());
+        assert_eq!(agreed.best_protocol_version(), None);
+        assert!(!agreed.supports(FEATURE_PAYMENT_CHANNELS));
+    }
+}
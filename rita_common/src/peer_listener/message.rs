@@ -1,13 +1,19 @@
+use super::capabilities::HELLO_PROTOCOL_V2;
+use super::tlv::HelloV2;
 use althea_types::LocalIdentity;
 use bincode;
 use byteorder::{BigEndian, ReadBytesExt};
 use bytes::BufMut;
+use clarity::utils::get_ethereum_msg_hash;
+use clarity::PrivateKey;
+use clarity::Signature;
 use serde_derive::{Deserialize, Serialize};
 use std::convert::From;
 use std::error::Error;
 use std::fmt::Display;
 use std::io::Cursor;
-use std::net::Ipv6Addr;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::{fmt, io};
 
 #[derive(Debug)]
@@ -24,6 +30,10 @@ pub enum MessageError {
     InvalidIpAddress,
     // Deserialization Error in Decode
     DeserializationError,
+    /// MSG_SIGNED_HELLO or MSG_TLV_HELLO: Received a version of the hello we don't understand
+    UnsupportedVersion(u8),
+    /// MSG_TLV_HELLO: A required record of this type is missing
+    MissingField(u8),
 }
 
 impl Error for MessageError {}
@@ -39,6 +49,10 @@ impl Display for MessageError {
             MessageError::DeserializationError => {
                 write!(f, "Error when Deserializing Hello Message")
             }
+            MessageError::UnsupportedVersion(v) => {
+                write!(f, "Received Hello with unsupported version {v}")
+            }
+            MessageError::MissingField(t) => write!(f, "Received Hello without a type {t} record"),
         }
     }
 }
@@ -52,11 +66,57 @@ impl From<io::Error> for MessageError {
 const MSG_IM_HERE: u8 = 0x5b;
 const MSG_IM_HERE_LEN: u16 = 19;
 const MSG_HELLO: u8 = 0x6c;
+/// Nodes that don't know this magic drop the message as invalid, so a signed hello is never
+/// misread by an older node, it is simply not answered
+const MSG_SIGNED_HELLO: u8 = 0x6d;
+/// Sent as the first byte of a signed hello payload, bumped if the signed fields ever change
+pub const SIGNED_HELLO_VERSION: u8 = 2;
+/// Signed hellos carry the time they were signed at and are refused once they are more than this
+/// many seconds old, or this far in the future, so a captured hello can't be replayed later on.
+/// The sender's clock is used so it has to be roughly right
+pub const SIGNED_HELLO_MAX_AGE: u64 = 300;
+/// The tlv encoded hello, see tlv.rs, the payload starts with the protocol version <u8>
+const MSG_TLV_HELLO: u8 = 0x6e;
+/// Prefixed to the signed bytes of every hello so that a hello signature can't be replayed as
+/// a signature over some other message
+pub(super) const HELLO_DOMAIN: &[u8] = b"althea peer hello";
+
+/// The time to put in a hello we sign, seconds since the unix epoch
+pub(super) fn hello_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// True if a hello signed at timestamp is recent enough to act on
+pub(super) fn hello_is_fresh(timestamp: u64) -> bool {
+    hello_timestamp().abs_diff(timestamp) <= SIGNED_HELLO_MAX_AGE
+}
+
+fn ip_octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+/// The wire formats a hello can be sent in, we always answer a hello in the format it came in
+/// since the sender may not understand anything newer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HelloFormat {
+    /// The bincode hello understood by every version of Rita
+    Legacy,
+    /// The bincode hello with a signature, sent unsigned if we have no eth key
+    Signed,
+    /// The tlv hello with our capabilities, signed if we have an eth key
+    Tlv,
+}
 
 /**
  * An enum that contains all supported p2p packets
  */
-#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PeerMessage {
     ImHere(Ipv6Addr),
     /// This is the message sent over the udp socket. It contains the necessary information to set up a tunnel
@@ -66,13 +126,123 @@ pub enum PeerMessage {
         response: bool,
         sender_wgport: u16,
     },
+    /// A Hello signed with the eth key of the identity it carries, the signature also covers the
+    /// link local address it is sent from so it can't be replayed by another node on the segment,
+    /// and the time it was signed at so it can't be replayed later
+    SignedHello {
+        my_id: Box<LocalIdentity>,
+        response: bool,
+        sender_wgport: u16,
+        timestamp: u64,
+        signature: Signature,
+    },
+    /// The tlv encoded hello, which carries the sender's capabilities and can gain fields
+    /// without breaking older decoders
+    HelloV2(Box<HelloV2>),
 }
 
 impl PeerMessage {
+    fn hello_signed_bytes(
+        my_id: &LocalIdentity,
+        response: bool,
+        sender_wgport: u16,
+        timestamp: u64,
+        sender_ip: IpAddr,
+    ) -> Vec<u8> {
+        let mut bytes = HELLO_DOMAIN.to_vec();
+        bytes.push(SIGNED_HELLO_VERSION);
+        bytes.extend_from_slice(&ip_octets(sender_ip));
+        // the identity the tunnel is set up with is signed field by field so a signature can
+        // never be moved onto another mesh ip or wg key
+        bytes.extend_from_slice(&ip_octets(my_id.global.mesh_ip));
+        bytes.extend_from_slice(my_id.global.wg_public_key.as_ref());
+        bytes.extend_from_slice(my_id.global.eth_address.as_bytes());
+        // bincode output is deterministic for a given struct, so both sides get the same bytes
+        bytes.extend_from_slice(&bincode::serialize(my_id).unwrap_or_default());
+        bytes.push(response as u8);
+        bytes.extend_from_slice(&sender_wgport.to_be_bytes());
+        bytes.extend_from_slice(&timestamp.to_be_bytes());
+        bytes
+    }
+
+    /// Builds a SignedHello, sender_ip is the link local address the hello will be sent from
+    pub fn new_signed_hello(
+        my_id: LocalIdentity,
+        response: bool,
+        sender_wgport: u16,
+        sender_ip: IpAddr,
+        key: PrivateKey,
+    ) -> PeerMessage {
+        PeerMessage::signed_hello_at(
+            my_id,
+            response,
+            sender_wgport,
+            hello_timestamp(),
+            sender_ip,
+            key,
+        )
+    }
+
+    fn signed_hello_at(
+        my_id: LocalIdentity,
+        response: bool,
+        sender_wgport: u16,
+        timestamp: u64,
+        sender_ip: IpAddr,
+        key: PrivateKey,
+    ) -> PeerMessage {
+        let signature = key.sign_ethereum_msg(&PeerMessage::hello_signed_bytes(
+            &my_id,
+            response,
+            sender_wgport,
+            timestamp,
+            sender_ip,
+        ));
+        PeerMessage::SignedHello {
+            my_id: Box::new(my_id),
+            response,
+            sender_wgport,
+            timestamp,
+            signature,
+        }
+    }
+
+    /// Checks that a SignedHello or HelloV2 was signed by the eth address of the identity it carries,
+    /// sent from sender_ip and signed recently, always false for any other message
+    pub fn verify(&self, sender_ip: IpAddr) -> bool {
+        match self {
+            PeerMessage::SignedHello {
+                my_id,
+                response,
+                sender_wgport,
+                timestamp,
+                signature,
+            } => {
+                if !hello_is_fresh(*timestamp) {
+                    return false;
+                }
+                let hash = get_ethereum_msg_hash(&PeerMessage::hello_signed_bytes(
+                    my_id,
+                    *response,
+                    *sender_wgport,
+                    *timestamp,
+                    sender_ip,
+                ));
+                match signature.recover(&hash) {
+                    Ok(address) => address == my_id.global.eth_address,
+                    Err(_) => false,
+                }
+            }
+            PeerMessage::HelloV2(hello) => hello.verify(sender_ip),
+            _ => false,
+        }
+    }
+
     /**
-     * Encode an ImHere or Hello message
+     * Encode an ImHere, Hello or SignedHello message
      * Message format is very simple
      * Magic <u8>, Size <u16>, Payload (Ipaddr &[u16; 8] for ImHere)
+     * SignedHello and HelloV2 payloads start with a version <u8>
      */
     pub fn encode(&self) -> Vec<u8> {
         let mut buf = Vec::new();
@@ -89,10 +259,22 @@ impl PeerMessage {
                 buf
             }
 
-            //This is a PeerMessage::Hello{ }
+            PeerMessage::HelloV2(ref hello) => {
+                buf.put_u8(MSG_TLV_HELLO);
+                let records = hello.encode_records();
+                let buf_len: u16 = 1 + 2 + 1 + records.len() as u16;
+                buf.put_u16(buf_len);
+                buf.put_u8(HELLO_PROTOCOL_V2);
+                buf.put_slice(&records);
+                trace!("Encoded HelloV2 packet {:x?}", buf);
+                buf
+            }
+
+            //This is a PeerMessage::Hello{ } or PeerMessage::SignedHello{ }
             _ => {
-                buf.put_u8(MSG_HELLO);
-                let encoded_hello = match bincode::serialize(self) {
+                let signed = matches!(self, PeerMessage::SignedHello { .. });
+                buf.put_u8(if signed { MSG_SIGNED_HELLO } else { MSG_HELLO });
+                let mut encoded_hello = match bincode::serialize(self) {
                     Ok(a) => a,
                     Err(_) => {
                         info!(
@@ -101,6 +283,9 @@ impl PeerMessage {
                         return Vec::new();
                     }
                 };
+                if signed {
+                    encoded_hello.insert(0, SIGNED_HELLO_VERSION);
+                }
                 let buf_len: u16 = 1 + 2 + encoded_hello.len() as u16;
                 buf.put_u16(buf_len);
                 for i in encoded_hello.iter() {
@@ -172,14 +357,48 @@ impl PeerMessage {
 
                 // First 3 bytes are overhead (Magic <u8>, Size <u16>)
                 let des_buf = &buf[3..];
-                let hello_peer_message = match bincode::deserialize(des_buf) {
+                let hello_peer_message: PeerMessage = match bincode::deserialize(des_buf) {
                     Ok(a) => a,
                     Err(_) => {
                         return Err(MessageError::DeserializationError);
                     }
                 };
 
-                Ok(hello_peer_message)
+                match hello_peer_message {
+                    PeerMessage::Hello { .. } => Ok(hello_peer_message),
+                    _ => Err(MessageError::DeserializationError),
+                }
+            }
+
+            MSG_SIGNED_HELLO => {
+                let _packet_size = pointer.read_u16::<BigEndian>()?;
+                let version = pointer.read_u8()?;
+                if version != SIGNED_HELLO_VERSION {
+                    return Err(MessageError::UnsupportedVersion(version));
+                }
+
+                // First 4 bytes are overhead (Magic <u8>, Size <u16>, Version <u8>)
+                let des_buf = &buf[4..];
+                match bincode::deserialize(des_buf) {
+                    Ok(msg @ PeerMessage::SignedHello { .. }) => Ok(msg),
+                    _ => Err(MessageError::DeserializationError),
+                }
+            }
+
+            MSG_TLV_HELLO => {
+                let packet_size = pointer.read_u16::<BigEndian>()? as usize;
+                let version = pointer.read_u8()?;
+                if version != HELLO_PROTOCOL_V2 {
+                    return Err(MessageError::UnsupportedVersion(version));
+                }
+                // the receive buffer is zero padded, so the size is needed to find the end
+                if packet_size < 4 || packet_size > buf.len() {
+                    return Err(MessageError::BufferUnderflow);
+                }
+
+                // First 4 bytes are overhead (Magic <u8>, Size <u16>, Version <u8>)
+                let hello = HelloV2::decode_records(&buf[4..packet_size])?;
+                Ok(PeerMessage::HelloV2(Box::new(hello)))
             }
             _ => {
                 trace!("Received packet with an unknown magic: {:X?}", packet_magic);
@@ -464,3 +683,181 @@ fn test_deserialize_with_wrong_serialization() {
         Err(_) => panic!("Wrong Error"),
     };
 }
+
+#[cfg(test)]
+pub fn get_signed_test_hello(response: bool) -> (PeerMessage, IpAddr) {
+    use crate::usage_tracker::tests::test::random_identity;
+
+    let key: PrivateKey = "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f1e"
+        .parse()
+        .unwrap();
+    let mut global = random_identity();
+    global.eth_address = key.to_address();
+    let my_id = LocalIdentity {
+        global,
+        wg_port: 0x3b23,
+        have_tunnel: Some(true),
+    };
+    let sender_ip: IpAddr = "fe80::1".parse().unwrap();
+    (
+        PeerMessage::new_signed_hello(my_id, response, 0x1232, sender_ip, key),
+        sender_ip,
+    )
+}
+
+#[test]
+fn test_signed_hello_encode_decode() {
+    let (hello, sender_ip) = get_signed_test_hello(true);
+    let result = hello.encode();
+    assert_eq!(result[0], MSG_SIGNED_HELLO);
+    assert_eq!(result[3], SIGNED_HELLO_VERSION);
+    let size = ((result[1] as u16) << 8) | result[2] as u16;
+    assert_eq!(size, result.len() as u16);
+    // must fit in the receive buffer in receive_hello
+    assert!(size < 500);
+
+    let decoded = PeerMessage::decode(&result).unwrap();
+    assert_eq!(decoded, hello);
+    assert!(decoded.verify(sender_ip));
+}
+
+#[test]
+fn test_signed_hello_verify() {
+    let (hello, sender_ip) = get_signed_test_hello(false);
+    assert!(hello.verify(sender_ip));
+
+    // replayed by another node on the segment
+    assert!(!hello.verify("fe80::2".parse().unwrap()));
+
+    let tamper = |f: &dyn Fn(&mut LocalIdentity, &mut u16, &mut u64)| {
+        let mut tampered = hello.clone();
+        if let PeerMessage::SignedHello {
+            ref mut my_id,
+            ref mut sender_wgport,
+            ref mut timestamp,
+            ..
+        } = tampered
+        {
+            f(my_id, sender_wgport, timestamp);
+        }
+        tampered.verify(sender_ip)
+    };
+    assert!(tamper(&|_, _, _| ()));
+    assert!(!tamper(&|_, port, _| *port += 1));
+    assert!(!tamper(&|_, _, timestamp| *timestamp += 1));
+    // someone else's identity with our signature
+    assert!(!tamper(
+        &|id, _, _| id.global.mesh_ip = "fd00::5".parse().unwrap()
+    ));
+    assert!(!tamper(&|id, _, _| id.global.wg_public_key = [7; 32].into()));
+
+    // an old hello can't be replayed, even by the address it was signed for
+    let (hello, sender_ip) = get_signed_test_hello(false);
+    if let PeerMessage::SignedHello { my_id, .. } = hello {
+        let key: PrivateKey = "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f1e"
+            .parse()
+            .unwrap();
+        let now = hello_timestamp();
+        let old = PeerMessage::signed_hello_at(
+            *my_id.clone(),
+            false,
+            0x1232,
+            now - SIGNED_HELLO_MAX_AGE - 1,
+            sender_ip,
+            key,
+        );
+        assert!(!old.verify(sender_ip));
+        let future = PeerMessage::signed_hello_at(
+            *my_id,
+            false,
+            0x1232,
+            now + SIGNED_HELLO_MAX_AGE + 1,
+            sender_ip,
+            key,
+        );
+        assert!(!future.verify(sender_ip));
+    }
+
+    // unsigned hellos never verify
+    let (hello, sender_ip) = get_signed_test_hello(false);
+    if let PeerMessage::SignedHello {
+        my_id,
+        response,
+        sender_wgport,
+        ..
+    } = hello
+    {
+        let unsigned = PeerMessage::Hello {
+            my_id,
+            response,
+            sender_wgport,
+        };
+        assert!(!unsigned.verify(sender_ip));
+    }
+}
+
+#[test]
+fn test_signed_hello_unsupported_version() {
+    let (hello, _) = get_signed_test_hello(false);
+    let mut result = hello.encode();
+    result[3] = SIGNED_HELLO_VERSION + 1;
+    match PeerMessage::decode(&result) {
+        Err(MessageError::UnsupportedVersion(v)) => assert_eq!(v, SIGNED_HELLO_VERSION + 1),
+        r => panic!("Expected an unsupported version, got {:?}", r),
+    }
+
+    // a signed hello can't be smuggled in under the unsigned magic
+    let mut result = hello.encode();
+    result.remove(3);
+    result[0] = MSG_HELLO;
+    match PeerMessage::decode(&result) {
+        Err(MessageError::DeserializationError) => (),
+        r => panic!("Expected a deserialization error, got {:?}", r),
+    }
+}
+
+#[test]
+fn test_tlv_hello_encode_decode() {
+    use crate::peer_listener::capabilities::Capabilities;
+
+    let (hello, sender_ip) = get_signed_test_hello(false);
+    let my_id = match hello {
+        PeerMessage::SignedHello { my_id, .. } => *my_id,
+        _ => panic!("Expected a signed hello"),
+    };
+    let key: PrivateKey = "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f1e"
+        .parse()
+        .unwrap();
+    let capabilities = Capabilities {
+        protocol_versions: vec![HELLO_PROTOCOL_V2],
+        ..Default::default()
+    };
+    let hello = PeerMessage::HelloV2(Box::new(HelloV2::new(
+        my_id,
+        false,
+        0x1232,
+        capabilities,
+        sender_ip,
+        Some(key),
+    )));
+    let result = hello.encode();
+    assert_eq!(result[0], MSG_TLV_HELLO);
+    assert_eq!(result[3], HELLO_PROTOCOL_V2);
+    let size = ((result[1] as u16) << 8) | result[2] as u16;
+    assert_eq!(size, result.len() as u16);
+    assert!(size < 500);
+
+    // receive_hello hands us the whole zero padded receive buffer
+    let mut datagram = [0u8; 500];
+    datagram[..result.len()].copy_from_slice(&result);
+    let decoded = PeerMessage::decode(&datagram).unwrap();
+    assert_eq!(decoded, hello);
+    assert!(decoded.verify(sender_ip));
+
+    let mut result = result;
+    result[3] = HELLO_PROTOCOL_V2 + 1;
+    match PeerMessage::decode(&result) {
+        Err(MessageError::UnsupportedVersion(v)) => assert_eq!(v, HELLO_PROTOCOL_V2 + 1),
+        r => panic!("Expected an unsupported version, got {:?}", r),
+    }
+}
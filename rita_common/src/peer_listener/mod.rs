@@ -6,22 +6,35 @@
 //! rita_loop iteration we send out our own IP as a UDP broadcast packet and then get our peers
 //! off the queue. These are turned into Peer structs which are passed to TunnelManager to do
 //! whatever remaining work there may be.
+pub mod capabilities;
 pub mod message;
+pub mod tlv;
 
+use self::capabilities::Capabilities;
+use self::message::HelloFormat;
 use self::message::PeerMessage;
 use self::structs::Hello;
 use self::structs::Peer;
+use self::structs::SignedPeer;
+use self::tlv::HelloV2;
 use crate::peer_listener::structs::PeerListener;
 use crate::tm_identity_callback;
 use crate::IdentityCallback;
 use crate::RitaCommonError;
 use crate::KI;
 use althea_types::LocalIdentity;
+use althea_types::WgKey;
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, UdpSocket};
+use std::time::{Duration, Instant};
 
 pub mod structs;
 
+/// How long an identity that signed its hellos to us is protected from unsigned hellos claiming
+/// it after its last signed hello, long enough to ride out a reboot but a node that is moved back
+/// to an older version isn't locked out for good
+pub const SIGNED_PEER_TIMEOUT: Duration = Duration::from_secs(3600);
+
 /// Creates a listen interface on all interfaces in the peer_interfaces hashmap.
 fn listen_to_available_ifaces(pl_interfaces: &mut HashMap<String, ListenInterface>) {
     info!("PEER LISTENER: starting to listen to interfaces");
@@ -237,19 +250,39 @@ fn receive_im_here(
     (output, interface_map)
 }
 
-/// Send UDP hello message over IPV6
+/// Send UDP hello message over IPV6 in the given format, signed and tlv hellos are signed if we
+/// have an eth key so the peer can check that the identity in them is really ours
 pub fn send_hello(
     msg: &Hello,
     socket: &UdpSocket,
     send_addr: SocketAddr,
     sender_wgport: u16,
+    format: HelloFormat,
 ) -> Result<(), RitaCommonError> {
-    trace!("Sending a Hello message");
+    trace!("Sending a {:?} Hello message", format);
 
-    let message = PeerMessage::Hello {
-        my_id: Box::new(msg.my_id),
-        response: msg.response,
-        sender_wgport,
+    let key = settings::get_rita_common().payment.eth_private_key;
+    let message = match (format, key) {
+        (HelloFormat::Tlv, key) => PeerMessage::HelloV2(Box::new(HelloV2::new(
+            msg.my_id,
+            msg.response,
+            sender_wgport,
+            Capabilities::ours(),
+            socket.local_addr()?.ip(),
+            key,
+        ))),
+        (HelloFormat::Signed, Some(key)) => PeerMessage::new_signed_hello(
+            msg.my_id,
+            msg.response,
+            sender_wgport,
+            socket.local_addr()?.ip(),
+            key,
+        ),
+        (HelloFormat::Signed, None) | (HelloFormat::Legacy, _) => PeerMessage::Hello {
+            my_id: Box::new(msg.my_id),
+            response: msg.response,
+            sender_wgport,
+        },
     };
     let encoded_message = PeerMessage::encode(&message).to_vec();
     let result = socket.send_to(&encoded_message, send_addr);
@@ -262,9 +295,126 @@ pub fn send_hello(
     }
 }
 
+/// A hello that has passed check_hello
+#[derive(Debug)]
+struct ReceivedHello {
+    my_id: LocalIdentity,
+    response: bool,
+    sender_wgport: u16,
+    /// The format it came in, which is the format we answer in
+    format: HelloFormat,
+    /// None if the hello was in one of the bincode formats
+    capabilities: Option<Capabilities>,
+}
+
+/// Decides if a decoded hello should be acted on. Signed hellos must verify, unsigned hellos are
+/// accepted from older nodes unless the settings require signatures or the identity they claim
+/// has signed its hellos to us recently
+fn check_hello(
+    msg: PeerMessage,
+    sender_ip: IpAddr,
+    signed_peers: &mut HashMap<WgKey, SignedPeer>,
+    require_signed: bool,
+) -> Result<ReceivedHello, String> {
+    let verified = msg.verify(sender_ip);
+    let (hello, signed) = match msg {
+        PeerMessage::SignedHello {
+            my_id,
+            response,
+            sender_wgport,
+            ..
+        } => (
+            ReceivedHello {
+                my_id: *my_id,
+                response,
+                sender_wgport,
+                format: HelloFormat::Signed,
+                capabilities: None,
+            },
+            true,
+        ),
+        PeerMessage::Hello {
+            my_id,
+            response,
+            sender_wgport,
+        } => (
+            ReceivedHello {
+                my_id: *my_id,
+                response,
+                sender_wgport,
+                format: HelloFormat::Legacy,
+                capabilities: None,
+            },
+            false,
+        ),
+        PeerMessage::HelloV2(hello) => {
+            let signed = hello.is_signed();
+            let hello = *hello;
+            (
+                ReceivedHello {
+                    my_id: hello.my_id,
+                    response: hello.response,
+                    sender_wgport: hello.sender_wgport,
+                    format: HelloFormat::Tlv,
+                    capabilities: Some(hello.capabilities),
+                },
+                signed,
+            )
+        }
+        PeerMessage::ImHere(_) => {
+            return Err("Should not receive Im Here on linklocal socket".to_string())
+        }
+    };
+
+    let id = hello.my_id.global;
+    // the signature only proves the eth address, so the wg key and mesh ip are bound to the
+    // first eth address that signs for them for as long as it keeps signing
+    signed_peers.retain(|_, peer| peer.last_signed.elapsed() < SIGNED_PEER_TIMEOUT);
+    let mut claimed = signed_peers
+        .iter()
+        .filter(|(key, peer)| **key == id.wg_public_key || peer.mesh_ip == id.mesh_ip);
+    if signed {
+        if !verified {
+            return Err(format!(
+                "Signed Hello from {sender_ip} does not match {} or is too old",
+                id.eth_address
+            ));
+        }
+        if let Some((key, peer)) = claimed.find(|(key, peer)| {
+            **key != id.wg_public_key
+                || peer.mesh_ip != id.mesh_ip
+                || peer.eth_address != id.eth_address
+        }) {
+            return Err(format!(
+                "Signed Hello from {sender_ip} claiming {} {} which {} signs for as {key}",
+                id.wg_public_key, id.mesh_ip, peer.eth_address
+            ));
+        }
+        signed_peers.insert(
+            id.wg_public_key,
+            SignedPeer {
+                mesh_ip: id.mesh_ip,
+                eth_address: id.eth_address,
+                last_signed: Instant::now(),
+            },
+        );
+    } else if require_signed {
+        return Err(format!(
+            "Unsigned Hello from {sender_ip} and signatures are required"
+        ));
+    } else if claimed.next().is_some() {
+        return Err(format!(
+            "Unsigned Hello from {sender_ip} claiming {} {} which signs its hellos",
+            id.wg_public_key, id.mesh_ip
+        ));
+    }
+    Ok(hello)
+}
+
 /// receive UDP hello messages over IPV6 link local ports
 pub fn receive_hello(pl: &mut PeerListener) {
     info!("Receiving Hellos");
+    let require_signed = settings::get_rita_common().network.require_signed_hellos;
     for obj in pl.interfaces.iter() {
         let listen_interface = obj.1;
 
@@ -300,83 +450,246 @@ pub fn receive_hello(pl: &mut PeerListener) {
                 .insert(sock_addr, listen_interface.ifname.clone());
 
             let encoded_msg = datagram.to_vec();
-            match PeerMessage::decode(&encoded_msg) {
-                Ok(PeerMessage::ImHere(_ipaddr)) => {
-                    error!("Should not revceive Im Here on linklocal socket, Error");
+            let msg = match PeerMessage::decode(&encoded_msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    error!("Hello decode failed: {:?}", e);
                     continue;
                 }
-                Ok(PeerMessage::Hello {
-                    my_id,
-                    response,
-                    sender_wgport,
-                }) => {
-                    //We received an initial hello contact message
-                    if !response {
-                        info!(
-                            "Received a PeerMessage with fields: {:?}, {:?}, {:?}",
-                            my_id, response, sender_wgport
-                        );
-
-                        let their_id = my_id;
-                        let peer = Peer {
-                            contact_socket: sock_addr,
-                            ifidx: 0,
-                        };
-
-                        let tunnel =
-                            tm_identity_callback(IdentityCallback::new(*their_id, peer, None));
-                        let tunnel = match tunnel {
-                            Ok(val) => val,
-                            Err(e) => {
-                                error!("Tunnel Open failure from peer listener {:?}", e);
-                                return;
-                            }
-                        };
-
-                        let our_id = LocalIdentity {
-                            global: match settings::get_rita_common().get_identity() {
-                                Some(id) => id,
-                                None => {
-                                    error!("Identity has no mesh IP ready yet in peer listener");
-                                    return;
-                                }
-                            },
-                            wg_port: tunnel.0.listen_port,
-                            have_tunnel: Some(tunnel.1),
-                        };
-
-                        let response_hello = Hello::new(our_id, peer, true);
-                        if let Err(e) = send_hello(
-                            &response_hello,
-                            &listen_interface.linklocal_socket,
-                            sock_addr,
-                            sender_wgport,
-                        ) {
-                            error!("Error sending hello to {:?}", e);
-                        }
-
-                        //we received a hello response message
-                    } else {
-                        info!(
-                            "Received a hello response with id wgport and peer: {:?}",
-                            my_id.wg_port
-                        );
-                        let their_id = my_id;
-                        if let Err(e) = tm_identity_callback(IdentityCallback::new(
-                            *their_id,
-                            peer_to_send,
-                            Some(sender_wgport),
-                        )) {
-                            error!("Failed to open tunnel! {:?}", e);
-                        }
-                    }
-                }
+            };
+            let hello = match check_hello(msg, sock_addr.ip(), &mut pl.signed_peers, require_signed)
+            {
+                Ok(hello) => hello,
                 Err(e) => {
-                    error!("Hello decode failed: {:?}", e);
+                    warn!("Ignoring Hello: {}", e);
                     continue;
                 }
             };
+
+            //We received an initial hello contact message
+            if !hello.response {
+                info!(
+                    "Received a {:?} PeerMessage with fields: {:?}, {:?}, {:?}, {:?}",
+                    hello.format,
+                    hello.my_id,
+                    hello.response,
+                    hello.sender_wgport,
+                    hello.capabilities
+                );
+
+                let peer = Peer {
+                    contact_socket: sock_addr,
+                    ifidx: 0,
+                };
+
+                let tunnel = tm_identity_callback(IdentityCallback::new(
+                    hello.my_id,
+                    peer,
+                    None,
+                    hello.capabilities,
+                    Some(listen_interface.ifname.clone()),
+                ));
+                let tunnel = match tunnel {
+                    Ok(val) => val,
+                    Err(e) => {
+                        error!("Tunnel Open failure from peer listener {:?}", e);
+                        return;
+                    }
+                };
+
+                let our_id = LocalIdentity {
+                    global: match settings::get_rita_common().get_identity() {
+                        Some(id) => id,
+                        None => {
+                            error!("Identity has no mesh IP ready yet in peer listener");
+                            return;
+                        }
+                    },
+                    wg_port: tunnel.0.listen_port,
+                    have_tunnel: Some(tunnel.1),
+                };
+
+                // answer in kind, an older node would drop a hello in a newer format
+                let response_hello = Hello::new(our_id, peer, true);
+                if let Err(e) = send_hello(
+                    &response_hello,
+                    &listen_interface.linklocal_socket,
+                    sock_addr,
+                    hello.sender_wgport,
+                    hello.format,
+                ) {
+                    error!("Error sending hello to {:?}", e);
+                }
+
+                //we received a hello response message
+            } else {
+                info!(
+                    "Received a {:?} hello response with id wgport and peer: {:?}",
+                    hello.format, hello.my_id.wg_port
+                );
+                if let Err(e) = tm_identity_callback(IdentityCallback::new(
+                    hello.my_id,
+                    peer_to_send,
+                    Some(hello.sender_wgport),
+                    hello.capabilities,
+                    Some(listen_interface.ifname.clone()),
+                )) {
+                    error!("Failed to open tunnel! {:?}", e);
+                }
+            }
         }
     }
     trace!("Done receiving hellos");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_listener::capabilities::HELLO_PROTOCOL_V2;
+    use crate::peer_listener::message::get_signed_test_hello;
+    use clarity::PrivateKey;
+
+    fn unsigned(msg: &PeerMessage) -> PeerMessage {
+        match msg {
+            PeerMessage::SignedHello {
+                my_id,
+                response,
+                sender_wgport,
+                ..
+            } => PeerMessage::Hello {
+                my_id: my_id.clone(),
+                response: *response,
+                sender_wgport: *sender_wgport,
+            },
+            _ => panic!("Expected a signed hello"),
+        }
+    }
+
+    #[test]
+    fn test_check_hello() {
+        let mut signed_peers = HashMap::new();
+        let (hello, sender_ip) = get_signed_test_hello(false);
+
+        // older nodes keep working until signatures are required
+        let received = check_hello(unsigned(&hello), sender_ip, &mut signed_peers, false).unwrap();
+        assert_eq!(received.format, HelloFormat::Legacy);
+        assert!(check_hello(unsigned(&hello), sender_ip, &mut signed_peers, true).is_err());
+
+        // signed hellos must come from the address they were signed for
+        let other_ip = "fe80::2".parse().unwrap();
+        assert!(check_hello(hello.clone(), other_ip, &mut signed_peers, false).is_err());
+        assert!(signed_peers.is_empty());
+
+        let received = check_hello(hello.clone(), sender_ip, &mut signed_peers, false).unwrap();
+        assert_eq!(received.format, HelloFormat::Signed);
+        let signed_key = received.my_id.global.wg_public_key;
+        assert!(signed_peers.contains_key(&signed_key));
+
+        // once a peer has signed to us an unsigned hello claiming to be them is a downgrade
+        assert!(check_hello(unsigned(&hello), sender_ip, &mut signed_peers, false).is_err());
+        let tlv = HelloV2::new(
+            received.my_id,
+            false,
+            received.sender_wgport,
+            Capabilities::default(),
+            sender_ip,
+            None,
+        );
+        assert!(check_hello(
+            PeerMessage::HelloV2(Box::new(tlv)),
+            sender_ip,
+            &mut signed_peers,
+            false
+        )
+        .is_err());
+
+        // until the peer hasn't signed anything for a while
+        if let Some(last_signed) = Instant::now().checked_sub(SIGNED_PEER_TIMEOUT) {
+            signed_peers.get_mut(&signed_key).unwrap().last_signed = last_signed;
+            assert!(check_hello(unsigned(&hello), sender_ip, &mut signed_peers, false).is_ok());
+            assert!(signed_peers.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_check_hello_forged_key() {
+        // our neighbor signs its hellos
+        let mut signed_peers = HashMap::new();
+        let (hello, sender_ip) = get_signed_test_hello(false);
+        let victim = match &hello {
+            PeerMessage::SignedHello { my_id, .. } => *my_id.clone(),
+            _ => panic!("Expected a signed hello"),
+        };
+        assert!(check_hello(hello.clone(), sender_ip, &mut signed_peers, false).is_ok());
+
+        // someone signs a hello with their own eth key claiming the neighbor's wg key
+        let key: PrivateKey = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020101"
+            .parse()
+            .unwrap();
+        let mut forged = victim;
+        forged.global.eth_address = key.to_address();
+        let forged_hello = PeerMessage::new_signed_hello(forged, false, 0x1232, sender_ip, key);
+        assert!(check_hello(forged_hello, sender_ip, &mut signed_peers, false).is_err());
+
+        // or its mesh ip with a wg key of their own
+        forged.global.wg_public_key = "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+            .parse()
+            .unwrap();
+        let forged_hello = PeerMessage::new_signed_hello(forged, false, 0x1232, sender_ip, key);
+        assert!(check_hello(forged_hello, sender_ip, &mut signed_peers, false).is_err());
+        assert_eq!(signed_peers.len(), 1);
+        assert_eq!(
+            signed_peers[&victim.global.wg_public_key].eth_address,
+            victim.global.eth_address
+        );
+
+        // or an unsigned hello with any eth address
+        forged = victim;
+        forged.global.eth_address = key.to_address();
+        let forged_hello = PeerMessage::Hello {
+            my_id: Box::new(forged),
+            response: false,
+            sender_wgport: 0x1232,
+        };
+        assert!(check_hello(forged_hello, sender_ip, &mut signed_peers, false).is_err());
+
+        // while the neighbor itself keeps getting through
+        assert!(check_hello(hello, sender_ip, &mut signed_peers, false).is_ok());
+    }
+
+    #[test]
+    fn test_check_tlv_hello() {
+        let mut signed_peers = HashMap::new();
+        let (hello, sender_ip) = get_signed_test_hello(false);
+        let my_id = match hello {
+            PeerMessage::SignedHello { my_id, .. } => *my_id,
+            _ => panic!("Expected a signed hello"),
+        };
+        let key = "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f1e"
+            .parse()
+            .unwrap();
+        let capabilities = Capabilities {
+            protocol_versions: vec![HELLO_PROTOCOL_V2],
+            ..Default::default()
+        };
+        let tlv = HelloV2::new(
+            my_id,
+            true,
+            1234,
+            capabilities.clone(),
+            sender_ip,
+            Some(key),
+        );
+        let received = check_hello(
+            PeerMessage::HelloV2(Box::new(tlv)),
+            sender_ip,
+            &mut signed_peers,
+            true,
+        )
+        .unwrap();
+        assert_eq!(received.format, HelloFormat::Tlv);
+        assert_eq!(received.capabilities, Some(capabilities));
+        assert!(received.response);
+        assert_eq!(received.sender_wgport, 1234);
+    }
+}
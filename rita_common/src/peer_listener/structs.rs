@@ -1,10 +1,13 @@
 use super::ListenInterface;
 use althea_types::LocalIdentity;
+use althea_types::WgKey;
+use clarity::Address;
 use std::collections::HashMap;
 use std::net::IpAddr;
 use std::net::Ipv6Addr;
 use std::net::SocketAddr;
 use std::net::SocketAddrV6;
+use std::time::Instant;
 
 #[derive(Debug)]
 pub struct PeerListener {
@@ -15,6 +18,18 @@ pub struct PeerListener {
     /// all the information of the interface after receiving a hello message. For instance, when receiving a
     /// Hello, we are able to determine the udp port to sent the response on using this map.
     pub interface_map: HashMap<SocketAddr, String>,
+    /// Peers that have sent us a verified signed hello by WireGuard key, hellos claiming one of
+    /// these wg keys or mesh ips that aren't signed by the same eth address are ignored until the
+    /// entry expires, see SIGNED_PEER_TIMEOUT
+    pub signed_peers: HashMap<WgKey, SignedPeer>,
+}
+
+/// The rest of the identity a wg key was signed for and when it last was
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SignedPeer {
+    pub mesh_ip: IpAddr,
+    pub eth_address: Address,
+    pub last_signed: Instant,
 }
 
 ///There are two types of hello messages. When we receive a inital hello (not a response)
@@ -67,6 +82,7 @@ impl PeerListener {
             interfaces: HashMap::new(),
             peers: HashMap::new(),
             interface_map: HashMap::new(),
+            signed_peers: HashMap::new(),
         }
     }
 }
@@ -111,6 +127,7 @@ impl Clone for PeerListener {
             interfaces: clone_interfaces,
             peers: self.peers.clone(),
             interface_map: self.interface_map.clone(),
+            signed_peers: self.signed_peers.clone(),
         }
     }
 }
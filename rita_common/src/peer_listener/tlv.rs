@@ -0,0 +1,483 @@
+//! The tlv encoded hello. Every field is a record of type <u8>, length <u16> followed by the value,
+//! records of a type we don't know are skipped so that fields can be added to the hello without
+//! breaking nodes running an older version. The signature, if there is one, is always the last
+//! record and covers every record before it including the ones we skipped.
+
+use super::capabilities::{Capabilities, HELLO_PROTOCOL_V2};
+use super::message::{hello_is_fresh, hello_timestamp, MessageError, HELLO_DOMAIN};
+use althea_types::{Identity, LocalIdentity, SystemChain, WgKey};
+use arrayvec::ArrayString;
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::BufMut;
+use clarity::utils::get_ethereum_msg_hash;
+use clarity::Address;
+use clarity::PrivateKey;
+use clarity::Signature;
+use std::convert::TryInto;
+use std::io::Cursor;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+const TLV_MESH_IP: u8 = 1;
+const TLV_ETH_ADDRESS: u8 = 2;
+const TLV_WG_KEY: u8 = 3;
+const TLV_NICKNAME: u8 = 4;
+const TLV_WG_PORT: u8 = 5;
+const TLV_HAVE_TUNNEL: u8 = 6;
+const TLV_RESPONSE: u8 = 7;
+const TLV_SENDER_WGPORT: u8 = 8;
+const TLV_PROTOCOL_VERSIONS: u8 = 9;
+const TLV_PAYMENT_CHAINS: u8 = 10;
+const TLV_MESH_IP_V2: u8 = 11;
+const TLV_FEATURES: u8 = 12;
+const TLV_OPERATOR_ADDRESS: u8 = 13;
+const TLV_TIMESTAMP: u8 = 14;
+const TLV_SIGNATURE: u8 = 0xff;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HelloV2 {
+    pub my_id: LocalIdentity,
+    pub response: bool,
+    pub sender_wgport: u16,
+    pub capabilities: Capabilities,
+    /// When the hello was made, a signed hello without a recent timestamp doesn't verify
+    timestamp: Option<u64>,
+    /// The records as they were sent, this is what the signature covers
+    records: Vec<u8>,
+    signature: Option<Signature>,
+}
+
+fn put_record(buf: &mut Vec<u8>, tlv_type: u8, value: &[u8]) {
+    buf.put_u8(tlv_type);
+    buf.put_u16(value.len() as u16);
+    buf.put_slice(value);
+}
+
+fn ip_to_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn ip_from_bytes(value: &[u8]) -> Result<IpAddr, MessageError> {
+    match value.len() {
+        4 => {
+            let octets: [u8; 4] = value.try_into().unwrap();
+            Ok(Ipv4Addr::from(octets).into())
+        }
+        16 => {
+            let octets: [u8; 16] = value.try_into().unwrap();
+            Ok(Ipv6Addr::from(octets).into())
+        }
+        _ => Err(MessageError::DeserializationError),
+    }
+}
+
+fn u16_from_bytes(value: &[u8]) -> Result<u16, MessageError> {
+    match value.try_into() {
+        Ok(bytes) => Ok(u16::from_be_bytes(bytes)),
+        Err(_) => Err(MessageError::DeserializationError),
+    }
+}
+
+fn bool_from_bytes(value: &[u8]) -> Result<bool, MessageError> {
+    match value {
+        [0] => Ok(false),
+        [1] => Ok(true),
+        _ => Err(MessageError::DeserializationError),
+    }
+}
+
+fn encode_records(
+    my_id: &LocalIdentity,
+    response: bool,
+    sender_wgport: u16,
+    capabilities: &Capabilities,
+    timestamp: u64,
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    put_record(&mut buf, TLV_MESH_IP, &ip_to_bytes(my_id.global.mesh_ip));
+    put_record(
+        &mut buf,
+        TLV_ETH_ADDRESS,
+        my_id.global.eth_address.as_bytes(),
+    );
+    put_record(&mut buf, TLV_WG_KEY, my_id.global.wg_public_key.as_ref());
+    if let Some(nickname) = my_id.global.nickname {
+        put_record(&mut buf, TLV_NICKNAME, nickname.as_bytes());
+    }
+    put_record(&mut buf, TLV_WG_PORT, &my_id.wg_port.to_be_bytes());
+    if let Some(have_tunnel) = my_id.have_tunnel {
+        put_record(&mut buf, TLV_HAVE_TUNNEL, &[have_tunnel as u8]);
+    }
+    put_record(&mut buf, TLV_RESPONSE, &[response as u8]);
+    put_record(&mut buf, TLV_SENDER_WGPORT, &sender_wgport.to_be_bytes());
+
+    put_record(
+        &mut buf,
+        TLV_PROTOCOL_VERSIONS,
+        &capabilities.protocol_versions,
+    );
+    // chains are sent by name, each prefixed by its length, so a chain we don't know of can be
+    // skipped without knowing anything about it
+    let mut chains = Vec::new();
+    for chain in capabilities.payment_chains.iter() {
+        let name = chain.to_string();
+        chains.put_u8(name.len() as u8);
+        chains.put_slice(name.as_bytes());
+    }
+    put_record(&mut buf, TLV_PAYMENT_CHAINS, &chains);
+    if let Some(ip) = capabilities.mesh_ip_v2 {
+        put_record(&mut buf, TLV_MESH_IP_V2, &ip_to_bytes(ip));
+    }
+    put_record(&mut buf, TLV_FEATURES, &capabilities.features.to_be_bytes());
+    if let Some(address) = capabilities.operator_address {
+        put_record(&mut buf, TLV_OPERATOR_ADDRESS, address.as_bytes());
+    }
+    put_record(&mut buf, TLV_TIMESTAMP, &timestamp.to_be_bytes());
+    buf
+}
+
+fn decode_payment_chains(value: &[u8]) -> Result<Vec<SystemChain>, MessageError> {
+    let mut chains = Vec::new();
+    let mut pointer = Cursor::new(value);
+    while (pointer.position() as usize) < value.len() {
+        let len = pointer.read_u8()? as usize;
+        let start = pointer.position() as usize;
+        let name = match value.get(start..start + len) {
+            Some(name) => name,
+            None => return Err(MessageError::BufferUnderflow),
+        };
+        pointer.set_position((start + len) as u64);
+        match std::str::from_utf8(name).map(SystemChain::from_str) {
+            Ok(Ok(chain)) => chains.push(chain),
+            _ => trace!("Skipping unknown payment chain {:?}", name),
+        }
+    }
+    Ok(chains)
+}
+
+/// The bytes a v2 hello signature is made over
+fn signed_bytes(records: &[u8], sender_ip: IpAddr) -> Vec<u8> {
+    let mut bytes = HELLO_DOMAIN.to_vec();
+    bytes.push(HELLO_PROTOCOL_V2);
+    bytes.extend_from_slice(&ip_to_bytes(sender_ip));
+    bytes.extend_from_slice(records);
+    bytes
+}
+
+impl HelloV2 {
+    /// Builds a hello, signed if we have a key. sender_ip is the link local address the hello
+    /// will be sent from
+    pub fn new(
+        my_id: LocalIdentity,
+        response: bool,
+        sender_wgport: u16,
+        capabilities: Capabilities,
+        sender_ip: IpAddr,
+        key: Option<PrivateKey>,
+    ) -> HelloV2 {
+        HelloV2::new_at(
+            my_id,
+            response,
+            sender_wgport,
+            capabilities,
+            hello_timestamp(),
+            sender_ip,
+            key,
+        )
+    }
+
+    fn new_at(
+        my_id: LocalIdentity,
+        response: bool,
+        sender_wgport: u16,
+        capabilities: Capabilities,
+        timestamp: u64,
+        sender_ip: IpAddr,
+        key: Option<PrivateKey>,
+    ) -> HelloV2 {
+        let records = encode_records(&my_id, response, sender_wgport, &capabilities, timestamp);
+        let signature = key.map(|key| key.sign_ethereum_msg(&signed_bytes(&records, sender_ip)));
+        HelloV2 {
+            my_id,
+            response,
+            sender_wgport,
+            capabilities,
+            timestamp: Some(timestamp),
+            records,
+            signature,
+        }
+    }
+
+    pub fn is_signed(&self) -> bool {
+        self.signature.is_some()
+    }
+
+    /// Checks that this hello was signed by the eth address of the identity it carries, sent
+    /// from sender_ip and signed recently, false if it is not signed
+    pub fn verify(&self, sender_ip: IpAddr) -> bool {
+        let signature = match &self.signature {
+            Some(signature) => signature,
+            None => return false,
+        };
+        match self.timestamp {
+            Some(timestamp) if hello_is_fresh(timestamp) => {}
+            _ => return false,
+        }
+        let hash = get_ethereum_msg_hash(&signed_bytes(&self.records, sender_ip));
+        match signature.recover(&hash) {
+            Ok(address) => address == self.my_id.global.eth_address,
+            Err(_) => false,
+        }
+    }
+
+    /// The records followed by the signature record, if any
+    pub fn encode_records(&self) -> Vec<u8> {
+        let mut buf = self.records.clone();
+        if let Some(signature) = &self.signature {
+            match bincode::serialize(signature) {
+                Ok(value) => put_record(&mut buf, TLV_SIGNATURE, &value),
+                Err(e) => error!("Unable to serialize hello signature {:?}", e),
+            }
+        }
+        buf
+    }
+
+    pub fn decode_records(buf: &[u8]) -> Result<HelloV2, MessageError> {
+        let mut mesh_ip = None;
+        let mut eth_address = None;
+        let mut wg_public_key = None;
+        let mut nickname = None;
+        let mut wg_port = None;
+        let mut have_tunnel = None;
+        let mut response = None;
+        let mut sender_wgport = None;
+        let mut timestamp = None;
+        let mut capabilities = Capabilities::default();
+        let mut records = buf;
+        let mut signature = None;
+
+        let mut pointer = Cursor::new(buf);
+        while (pointer.position() as usize) < buf.len() {
+            let record_start = pointer.position() as usize;
+            let tlv_type = pointer.read_u8()?;
+            let len = pointer.read_u16::<BigEndian>()? as usize;
+            let start = pointer.position() as usize;
+            let value = match buf.get(start..start + len) {
+                Some(value) => value,
+                None => return Err(MessageError::BufferUnderflow),
+            };
+            pointer.set_position((start + len) as u64);
+
+            match tlv_type {
+                TLV_MESH_IP => mesh_ip = Some(ip_from_bytes(value)?),
+                TLV_ETH_ADDRESS => match Address::from_slice(value) {
+                    Ok(address) => eth_address = Some(address),
+                    Err(_) => return Err(MessageError::DeserializationError),
+                },
+                TLV_WG_KEY => {
+                    let key: [u8; 32] = match value.try_into() {
+                        Ok(key) => key,
+                        Err(_) => return Err(MessageError::DeserializationError),
+                    };
+                    wg_public_key = Some(WgKey::from(key));
+                }
+                TLV_NICKNAME => match std::str::from_utf8(value).map(ArrayString::<32>::from) {
+                    Ok(Ok(nick)) => nickname = Some(nick),
+                    _ => return Err(MessageError::DeserializationError),
+                },
+                TLV_WG_PORT => wg_port = Some(u16_from_bytes(value)?),
+                TLV_HAVE_TUNNEL => have_tunnel = Some(bool_from_bytes(value)?),
+                TLV_RESPONSE => response = Some(bool_from_bytes(value)?),
+                TLV_SENDER_WGPORT => sender_wgport = Some(u16_from_bytes(value)?),
+                TLV_PROTOCOL_VERSIONS => capabilities.protocol_versions = value.to_vec(),
+                TLV_PAYMENT_CHAINS => capabilities.payment_chains = decode_payment_chains(value)?,
+                TLV_MESH_IP_V2 => capabilities.mesh_ip_v2 = Some(ip_from_bytes(value)?),
+                TLV_FEATURES => match value.try_into() {
+                    Ok(bytes) => capabilities.features = u32::from_be_bytes(bytes),
+                    Err(_) => return Err(MessageError::DeserializationError),
+                },
+                TLV_OPERATOR_ADDRESS => match Address::from_slice(value) {
+                    Ok(address) => capabilities.operator_address = Some(address),
+                    Err(_) => return Err(MessageError::DeserializationError),
+                },
+                TLV_TIMESTAMP => match value.try_into() {
+                    Ok(bytes) => timestamp = Some(u64::from_be_bytes(bytes)),
+                    Err(_) => return Err(MessageError::DeserializationError),
+                },
+                TLV_SIGNATURE => {
+                    // nothing may follow the signature, it would not be covered by it
+                    if start + len != buf.len() {
+                        return Err(MessageError::DeserializationError);
+                    }
+                    match bincode::deserialize(value) {
+                        Ok(sig) => signature = Some(sig),
+                        Err(_) => return Err(MessageError::DeserializationError),
+                    }
+                    records = &buf[..record_start];
+                }
+                _ => trace!("Skipping unknown hello record {}", tlv_type),
+            }
+        }
+
+        let my_id = LocalIdentity {
+            wg_port: wg_port.ok_or(MessageError::MissingField(TLV_WG_PORT))?,
+            have_tunnel,
+            global: Identity {
+                mesh_ip: mesh_ip.ok_or(MessageError::MissingField(TLV_MESH_IP))?,
+                eth_address: eth_address.ok_or(MessageError::MissingField(TLV_ETH_ADDRESS))?,
+                wg_public_key: wg_public_key.ok_or(MessageError::MissingField(TLV_WG_KEY))?,
+                nickname,
+            },
+        };
+        Ok(HelloV2 {
+            my_id,
+            response: response.ok_or(MessageError::MissingField(TLV_RESPONSE))?,
+            sender_wgport: sender_wgport.ok_or(MessageError::MissingField(TLV_SENDER_WGPORT))?,
+            capabilities,
+            timestamp,
+            records: records.to_vec(),
+            signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_listener::capabilities::{FEATURE_PAYMENT_CHANNELS, HELLO_PROTOCOL_V1};
+    use crate::peer_listener::message::SIGNED_HELLO_MAX_AGE;
+    use crate::usage_tracker::tests::test::random_identity;
+
+    fn get_test_key() -> PrivateKey {
+        "0102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f1e"
+            .parse()
+            .unwrap()
+    }
+
+    fn get_test_hello(key: Option<PrivateKey>) -> (HelloV2, IpAddr) {
+        let mut global = random_identity();
+        global.eth_address = get_test_key().to_address();
+        global.nickname = Some(ArrayString::from("neighbor").unwrap());
+        let my_id = LocalIdentity {
+            global,
+            wg_port: 0x3b23,
+            have_tunnel: Some(false),
+        };
+        let capabilities = Capabilities {
+            protocol_versions: vec![HELLO_PROTOCOL_V1, HELLO_PROTOCOL_V2],
+            payment_chains: vec![SystemChain::Xdai, SystemChain::Althea],
+            mesh_ip_v2: Some("fd00::2".parse().unwrap()),
+            features: FEATURE_PAYMENT_CHANNELS,
+            operator_address: Some(get_test_key().to_address()),
+        };
+        let sender_ip: IpAddr = "fe80::1".parse().unwrap();
+        (
+            HelloV2::new(my_id, true, 0x1232, capabilities, sender_ip, key),
+            sender_ip,
+        )
+    }
+
+    #[test]
+    fn test_tlv_round_trip() {
+        let (hello, sender_ip) = get_test_hello(Some(get_test_key()));
+        let decoded = HelloV2::decode_records(&hello.encode_records()).unwrap();
+        assert_eq!(decoded, hello);
+        assert!(decoded.verify(sender_ip));
+        assert!(!decoded.verify("fe80::2".parse().unwrap()));
+
+        let (hello, sender_ip) = get_test_hello(None);
+        let decoded = HelloV2::decode_records(&hello.encode_records()).unwrap();
+        assert_eq!(decoded, hello);
+        assert!(!decoded.is_signed());
+        assert!(!decoded.verify(sender_ip));
+    }
+
+    #[test]
+    fn test_tlv_replay() {
+        let (hello, sender_ip) = get_test_hello(None);
+        let old = HelloV2::new_at(
+            hello.my_id,
+            hello.response,
+            hello.sender_wgport,
+            hello.capabilities.clone(),
+            hello_timestamp() - SIGNED_HELLO_MAX_AGE - 1,
+            sender_ip,
+            Some(get_test_key()),
+        );
+        let decoded = HelloV2::decode_records(&old.encode_records()).unwrap();
+        assert!(!decoded.verify(sender_ip));
+
+        // a signed hello from an older build without a timestamp
+        let mut records = Vec::new();
+        let mut pointer = Cursor::new(&hello.records[..]);
+        while (pointer.position() as usize) < hello.records.len() {
+            let start = pointer.position() as usize;
+            let tlv_type = pointer.read_u8().unwrap();
+            let len = pointer.read_u16::<BigEndian>().unwrap() as usize;
+            pointer.set_position((start + 3 + len) as u64);
+            if tlv_type != TLV_TIMESTAMP {
+                records.extend_from_slice(&hello.records[start..start + 3 + len]);
+            }
+        }
+        let signature = get_test_key().sign_ethereum_msg(&signed_bytes(&records, sender_ip));
+        put_record(
+            &mut records,
+            TLV_SIGNATURE,
+            &bincode::serialize(&signature).unwrap(),
+        );
+        let decoded = HelloV2::decode_records(&records).unwrap();
+        assert_eq!(decoded.my_id, hello.my_id);
+        assert!(!decoded.verify(sender_ip));
+    }
+
+    #[test]
+    fn test_tlv_unknown_records() {
+        // a newer node sends a record we don't know about, we skip it but the signature still
+        // covers it
+        let (hello, sender_ip) = get_test_hello(None);
+        let mut records = hello.records.clone();
+        put_record(&mut records, 200, b"from the future");
+        put_record(&mut records, TLV_PAYMENT_CHAINS, b"\x03Foo\x04Xdai");
+        let signature = get_test_key().sign_ethereum_msg(&signed_bytes(&records, sender_ip));
+        put_record(
+            &mut records,
+            TLV_SIGNATURE,
+            &bincode::serialize(&signature).unwrap(),
+        );
+
+        let decoded = HelloV2::decode_records(&records).unwrap();
+        assert_eq!(decoded.my_id, hello.my_id);
+        assert_eq!(decoded.capabilities.payment_chains, vec![SystemChain::Xdai]);
+        assert!(decoded.verify(sender_ip));
+    }
+
+    #[test]
+    fn test_tlv_errors() {
+        let (hello, _) = get_test_hello(Some(get_test_key()));
+        let encoded = hello.encode_records();
+
+        // cut off halfway through the signature
+        match HelloV2::decode_records(&encoded[..encoded.len() - 10]) {
+            Err(MessageError::BufferUnderflow) => (),
+            r => panic!("Expected a buffer underflow, got {:?}", r),
+        }
+
+        // records appended after the signature
+        let mut appended = encoded.clone();
+        put_record(&mut appended, TLV_WG_PORT, &1u16.to_be_bytes());
+        match HelloV2::decode_records(&appended) {
+            Err(MessageError::DeserializationError) => (),
+            r => panic!("Expected a deserialization error, got {:?}", r),
+        }
+
+        let mut records = Vec::new();
+        put_record(&mut records, TLV_MESH_IP, &[0xfd; 16]);
+        match HelloV2::decode_records(&records) {
+            Err(MessageError::MissingField(TLV_WG_PORT)) => (),
+            r => panic!("Expected a missing wg port, got {:?}", r),
+        }
+    }
+}
@@ -1,18 +1,17 @@
 use crate::blockchain_oracle::update as BlockchainOracleUpdate;
 use crate::debt_keeper::send_debt_update;
+use crate::link_prober::link_prober_tick;
 use crate::network_monitor::update_network_info;
 use crate::network_monitor::NetworkInfo as NetworkMonitorTick;
 use crate::payment_controller::tick_payment_controller;
 use crate::payment_validator::validate;
 use crate::peer_listener::peerlistener_tick;
 use crate::peer_listener::structs::PeerListener;
+use crate::rita_loop::get_babel_monitor;
 use crate::traffic_watcher::watch;
 use crate::tunnel_manager::contact_peers::tm_contact_peers;
 use crate::tunnel_manager::tm_get_neighbors;
 use actix_async::System as AsyncSystem;
-use babel_monitor::open_babel_stream;
-use babel_monitor::parse_neighs;
-use babel_monitor::parse_routes;
 
 use std::thread;
 use std::time::{Duration, Instant};
@@ -44,7 +43,6 @@ pub fn start_rita_fast_loop() {
 
                 let runner = AsyncSystem::new();
                 runner.block_on(async move {
-                    let babel_port = settings::get_rita_common().network.babel_port;
                     trace!("Common tick!");
 
                     let res = tm_get_neighbors();
@@ -52,27 +50,29 @@ pub fn start_rita_fast_loop() {
                     let neighbors = res;
                     let neigh = Instant::now();
 
-                    if let Ok(mut stream) = open_babel_stream(babel_port, FAST_LOOP_TIMEOUT) {
-                        if let Ok(babel_routes) = parse_routes(&mut stream) {
-                            if let Err(e) = watch(babel_routes.clone(), &neighbors) {
-                                error!("Error for Rita common traffic watcher {}", e);
-                            }
-                            info!(
-                                "TrafficWatcher completed in {}s {}ms",
-                                neigh.elapsed().as_secs(),
-                                neigh.elapsed().subsec_millis()
-                            );
+                    // probe before the network monitor runs so it has this tick's reports
+                    link_prober_tick(&neighbors);
+
+                    let babel = get_babel_monitor();
+                    if let Ok(babel_routes) = babel.get_routes() {
+                        if let Err(e) = watch(babel_routes.clone(), &neighbors) {
+                            error!("Error for Rita common traffic watcher {}", e);
+                        }
+                        info!(
+                            "TrafficWatcher completed in {}s {}ms",
+                            neigh.elapsed().as_secs(),
+                            neigh.elapsed().subsec_millis()
+                        );
 
-                            // Observe the dataplane for status and problems.
-                            if let Ok(babel_neighbors) = parse_neighs(&mut stream) {
-                                let rita_neighbors = tm_get_neighbors();
-                                trace!("Sending network monitor tick");
-                                update_network_info(NetworkMonitorTick {
-                                    babel_neighbors,
-                                    babel_routes,
-                                    rita_neighbors,
-                                });
-                            }
+                        // Observe the dataplane for status and problems.
+                        if let Ok(babel_neighbors) = babel.get_neighs() {
+                            let rita_neighbors = tm_get_neighbors();
+                            trace!("Sending network monitor tick");
+                            update_network_info(NetworkMonitorTick {
+                                babel_neighbors,
+                                babel_routes,
+                                rita_neighbors,
+                            });
                         }
                     }
 
@@ -6,13 +6,22 @@
 //! halt essential functions like opening tunnels and managing peers
 
 use crate::network_endpoints::*;
+use crate::payment_controller::journal::replay_payment_journal;
 use crate::traffic_watcher::init_traffic_watcher;
+use crate::tunnel_manager::persist::restore_tunnels;
+use crate::tunnel_manager::tm_babel_interface_removed;
+use crate::KI;
 use actix_async::System;
 use actix_web_async::{web, App, HttpServer};
+use babel_monitor::subscription::BabelEvent;
+use babel_monitor::subscription::BabelMonitorClient;
+use fast_loop::FAST_LOOP_TIMEOUT;
 use rand::thread_rng;
 use rand::Rng;
+use std::collections::HashMap;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
 use std::thread;
 
 pub mod fast_loop;
@@ -27,6 +36,10 @@ lazy_static! {
     /// to create NAT punching tunnels to the exit and setting routes to prevent
     /// exit traffic from going over the exit tunnel (which obviously doesn't work)
     static ref IS_GATEWAY: AtomicBool = AtomicBool::new(false);
+    /// The long lived babel monitor connection, loops read babel's routing table from here
+    /// rather than running a dump of their own
+    static ref BABEL_MONITOR: Arc<RwLock<HashMap<u32, BabelMonitorClient>>> =
+        Arc::new(RwLock::new(HashMap::new()));
 }
 
 pub fn is_gateway() -> bool {
@@ -37,6 +50,23 @@ pub fn set_gateway(input: bool) {
     IS_GATEWAY.store(input, Ordering::Relaxed)
 }
 
+/// Gets the babel monitor client for this instance, connecting to babel if this is the first call
+pub fn get_babel_monitor() -> BabelMonitorClient {
+    let netns = KI.check_integration_test_netns();
+    if let Some(client) = BABEL_MONITOR.read().unwrap().get(&netns) {
+        return client.clone();
+    }
+    BABEL_MONITOR
+        .write()
+        .unwrap()
+        .entry(netns)
+        .or_insert_with(|| {
+            let babel_port = settings::get_rita_common().network.babel_port;
+            BabelMonitorClient::start(babel_port, FAST_LOOP_TIMEOUT)
+        })
+        .clone()
+}
+
 /// Checks the list of full nodes, panics if none exist, if there exist
 /// one or more a random entry from the list is returned in an attempt
 /// to load balance across fullnodes
@@ -81,6 +111,11 @@ pub fn start_core_rita_endpoints(workers: usize) {
                 App::new()
                     .route("/make_payment", web::post().to(make_payments))
                     .route("/make_payment_v2", web::post().to(make_payments_v2))
+                    .route(
+                        "/make_channel_payment",
+                        web::post().to(make_channel_payment),
+                    )
+                    .route("/debt_view", web::post().to(debt_view))
             })
             .workers(workers)
             .bind(format!("[::0]:{}", common.network.rita_contact_port))
@@ -93,9 +128,30 @@ pub fn start_core_rita_endpoints(workers: usize) {
     });
 }
 
+/// Reacts to changes in babel's table as the monitor reports them
+fn start_babel_event_loop() {
+    let babel = get_babel_monitor();
+    thread::spawn(move || loop {
+        for event in babel.subscribe().iter() {
+            if let BabelEvent::InterfaceRemoved(iface) = event {
+                tm_babel_interface_removed(&iface.name);
+            }
+        }
+        // the monitor drops subscribers that fall behind, anything missed in the meantime
+        // is caught by the slow loop's check of the babel interfaces
+        warn!("Babel event subscription closed, resubscribing");
+    });
+}
+
 pub fn start_rita_common_loops() {
+    // must run before the loops start opening tunnels
+    restore_tunnels();
     init_traffic_watcher();
+    // connect to babel now so that the table is ready by the first fast loop tick
+    start_babel_event_loop();
+    replay_payment_journal();
     crate::rita_loop::slow_loop::start_rita_slow_loop();
     crate::rita_loop::fast_loop::start_rita_fast_loop();
     crate::rita_loop::fast_loop::peer_discovery_loop();
+    crate::link_prober::start_link_prober();
 }
@@ -1,17 +1,15 @@
+use crate::debt_dispute::exchange_debt_views;
 use crate::handle_shaping;
+use crate::rita_loop::get_babel_monitor;
 use crate::simulated_txfee_manager::tick_simulated_tx;
 use crate::token_bridge::tick_token_bridge;
 use crate::tunnel_manager::tm_common_slow_loop_helper;
 use crate::KI;
 use actix_async::System as AsyncSystem;
 use althea_kernel_interface::hardware_info::get_hardware_info;
-use babel_monitor::open_babel_stream;
-use babel_monitor::parse_interfaces;
-use babel_monitor::set_local_fee;
-use babel_monitor::set_metric_factor;
 use babel_monitor::structs::BabelMonitorError;
+use babel_monitor::subscription::BabelMonitorClient;
 use settings::get_rita_common;
-use std::net::TcpStream;
 use std::thread;
 use std::time::Duration;
 use std::time::Instant;
@@ -46,46 +44,36 @@ pub fn start_rita_slow_loop() {
                     tick_token_bridge().await;
                     info!("Ticking simulated tx!");
                     tick_simulated_tx().await;
+                    info!("Exchanging debt views!");
+                    exchange_debt_views().await;
                     info!("Common Slow tick async completed!");
                     AsyncSystem::current().stop();
                 });
 
                 // This checks that all tunnels are attached to babel. This may not be the case when babel restarts
-                let babel_port = settings::get_rita_common().network.babel_port;
-                match open_babel_stream(babel_port, SLOW_LOOP_TIMEOUT) {
-                    Ok(mut stream) => {
-                        // we really only need to run this on startup, but doing so periodically
-                        // could catch the edge case where babel is restarted under us
-                        if let Err(e) = set_babel_price(&mut stream) {
-                            warn!("Failed to set babel price with {:?}", e);
-                            num_babel_failures += 1;
-                        }
+                let babel = get_babel_monitor();
+                // we really only need to run this on startup, but doing so periodically
+                // could catch the edge case where babel is restarted under us
+                if let Err(e) = set_babel_price(&babel) {
+                    warn!("Failed to set babel price with {:?}", e);
+                    num_babel_failures += 1;
+                }
 
-                        match parse_interfaces(&mut stream) {
-                            Ok(babel_interfaces) => {
-                                // performs tunnel GC + checks babel interfaces
-                                tm_common_slow_loop_helper(babel_interfaces);
+                match babel.get_interfaces() {
+                    Ok(babel_interfaces) => {
+                        // performs tunnel GC + checks babel interfaces
+                        tm_common_slow_loop_helper(babel_interfaces);
 
-                                // reset failure count
-                                num_babel_failures = 0;
-                            }
-                            Err(e) => {
-                                num_babel_failures += 1;
-                                error!(
-                                    "Failed to parse babel interfaces in common slow loop with {:?}",
-                                    e
-                                );
-                            }
-                        }
-                    },
+                        // reset failure count
+                        num_babel_failures = 0;
+                    }
                     Err(e) => {
-                                num_babel_failures += 1;
-                            error!(
-                                "Failed to connect to babel in common slow loop with {:?}",
-                                e
-                            );
-
-                    },
+                        num_babel_failures += 1;
+                        error!(
+                            "Failed to get babel interfaces in common slow loop with {:?}",
+                            e
+                        );
+                    }
                 }
                 // auto recovery when babel crashes or otherwise behaves poorly
                 num_babel_failures += 1;
@@ -132,12 +120,12 @@ fn check_for_hap_reboot() {
     }
 }
 
-fn set_babel_price(stream: &mut TcpStream) -> Result<(), BabelMonitorError> {
+fn set_babel_price(babel: &BabelMonitorClient) -> Result<(), BabelMonitorError> {
     let start = Instant::now();
     let common = settings::get_rita_common();
     let local_fee = common.payment.local_fee;
     let metric_factor = common.network.metric_factor;
-    let result = set_local_fee(stream, local_fee);
+    let result = babel.set_local_fee(local_fee);
     if let Err(e) = result {
         warn!(
             "Failed to set local fee with {} in {} ms",
@@ -146,7 +134,7 @@ fn set_babel_price(stream: &mut TcpStream) -> Result<(), BabelMonitorError> {
         );
         return Err(e);
     }
-    let result = set_metric_factor(stream, metric_factor);
+    let result = babel.set_metric_factor(metric_factor);
     if let Err(e) = result {
         warn!(
             "Failed to set metric factor with {} in {} ms",
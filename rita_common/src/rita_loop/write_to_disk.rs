@@ -1,3 +1,4 @@
+use crate::tunnel_manager::persist::save_tunnels_to_disk;
 use crate::{debt_keeper::save_debt_to_disk, usage_tracker::save_usage_to_disk};
 use settings::{
     check_if_exit, client::RitaClientSettings, exit::RitaExitSettingsStruct, get_rita_client,
@@ -95,6 +96,9 @@ pub fn save_to_disk_loop(mut old_settings: SettingsOnDisk) {
 
         // usage tracker monitors and saves bandwidth usage info and payment metadata
         save_usage_to_disk();
+
+        // the tunnel table, only written when tunnels have been opened or closed
+        save_tunnels_to_disk();
     });
 }
 /// If the router storage is small/16mb
@@ -3,19 +3,33 @@ use crate::token_bridge::*;
 use auto_bridge::default_bridge_addresses;
 use auto_bridge::TokenBridge;
 use auto_bridge::{encode_relaytokens, get_relay_message_hash};
+use auto_bridge::{MINIMUM_DAI_TO_SEND, MINIMUM_USDC_TO_CONVERT};
 use clarity::Address;
 use clarity::PrivateKey;
+use futures::future::select;
+use mock_chain::{erc20_denom, start_ethereum_server, Account, MockChain, ETH_DENOM};
 use num256::Uint256;
+use settings::client::RitaClientSettings;
 use std::str::FromStr;
+use std::sync::Mutex;
 use std::time::Duration;
+use web30::amm::{DAI_CONTRACT_ADDRESS, USDC_CONTRACT_ADDRESS};
+use web30::client::Web3;
 
 const TIMEOUT: Duration = Duration::from_secs(600);
 
+lazy_static! {
+    /// The bridge state is global, so tests that change it take turns
+    static ref BRIDGE_TEST_LOCK: Mutex<()> = Mutex::new(());
+}
+
 /// This simply test that the lazy static lock is being updated correctly after calling the function setup_withdrawal.
 /// We call the function with the 'Withdraw' struct and check if the information is being updated correctly. This is necessary
 /// that the correct information about the withdrawal is being processed.
 #[test]
 fn test_xdai_setup_withdraw() {
+    let _lock = BRIDGE_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    set_bridge_state(TokenBridgeState::default());
     let pk = PrivateKey::from_str(&format!(
         "983aa7cb3e22b5aa8425facb9703a{}e04bd829e675b{}e5df",
         "632c1e54099", "51b0281"
@@ -216,3 +230,101 @@ fn test_transfer_dai() {
         }
     })
 }
+
+fn set_bridge_settings(pk: PrivateKey, eth_full_node: &str, xdai_full_node: &str, enabled: bool) {
+    settings::set_rita_client(RitaClientSettings::default());
+    let mut common = settings::get_rita_common();
+    common.payment.bridge_enabled = enabled;
+    common.payment.system_chain = SystemChain::Xdai;
+    common.payment.eth_address = Some(pk.to_address());
+    common.payment.eth_private_key = Some(pk);
+    common.payment.bridge_addresses.eth_full_node_url = eth_full_node.to_string();
+    common.payment.bridge_addresses.xdai_full_node_url = xdai_full_node.to_string();
+    settings::set_rita_common(common);
+}
+
+/// Runs a bridge tick while mining a block on the chain every 100ms, for ticks that wait on
+/// their transactions
+async fn tick_while_mining(chain: &MockChain) {
+    let miner = async {
+        loop {
+            actix_async::clock::sleep(Duration::from_millis(100)).await;
+            chain.mine_block();
+        }
+    };
+    select(Box::pin(tick_token_bridge()), Box::pin(miner)).await;
+}
+
+/// Runs the bridge tick against mock Ethereum and xDai chains, checking that it only moves
+/// funds once they are worth bridging and that pending withdraws are taken up first
+#[test]
+fn test_tick_token_bridge_against_mock_chain() {
+    let _lock = BRIDGE_TEST_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    set_bridge_state(TokenBridgeState::default());
+    let pk = PrivateKey::from_str(&format!(
+        "983aa7cb3e22b5aa8425facb9703a{}e04bd829e675b{}e5df",
+        "632c1e54099", "51b0281"
+    ))
+    .unwrap();
+    let us = Account::Eth(pk.to_address());
+
+    let runner = actix_async::System::new();
+    runner.block_on(async move {
+        let eth = MockChain::new();
+        let xdai = MockChain::new();
+        // withdraw events are searched for over the last BLOCKS blocks
+        xdai.mine_blocks(BLOCKS + 1);
+        let eth_url = start_ethereum_server(eth.clone()).unwrap();
+        let xdai_url = start_ethereum_server(xdai.clone()).unwrap();
+        let eth_web3 = Web3::new(&eth_url, TIMEOUT);
+        let dai = erc20_denom(*DAI_CONTRACT_ADDRESS);
+        let usdc = erc20_denom(*USDC_CONTRACT_ADDRESS);
+
+        // a disabled bridge does nothing
+        detailed_state_change(DetailedBridgeState::Swap);
+        set_bridge_settings(pk, &eth_url, &xdai_url, false);
+        tick_token_bridge().await;
+        assert_eq!(get_bridge_status().state, DetailedBridgeState::Swap);
+
+        // without eth to pay for gas our balances can't be checked
+        set_bridge_settings(pk, &eth_url, &xdai_url, true);
+        tick_token_bridge().await;
+        assert_eq!(get_bridge_status().state, DetailedBridgeState::Swap);
+
+        // nothing is worth bridging yet
+        eth.set_balance(us.clone(), ETH_DENOM, eth_to_wei(1));
+        eth.set_balance(us.clone(), &dai, (MINIMUM_DAI_TO_SEND - 1).into());
+        eth.set_balance(us.clone(), &usdc, (MINIMUM_USDC_TO_CONVERT - 1).into());
+        tick_token_bridge().await;
+        assert_eq!(get_bridge_status().state, DetailedBridgeState::NoOp);
+
+        // a pending withdraw is taken up before anything else
+        let withdraw = Withdraw {
+            to: pk.to_address(),
+            amount: 1u8.into(),
+        };
+        setup_withdraw(withdraw.clone()).unwrap();
+        eth.set_balance(us.clone(), &dai, MINIMUM_DAI_TO_SEND.into());
+        tick_token_bridge().await;
+        let state = get_bridge_state();
+        assert!(!state.withdraw_in_progress);
+        assert_eq!(state.withdraw_details, None);
+        assert_eq!(state.detailed_state, DetailedBridgeState::NoOp);
+        assert!(setup_withdraw(withdraw).is_ok());
+        set_bridge_state(TokenBridgeState::default());
+
+        // once there is enough dai it is sent over the bridge
+        tick_while_mining(&eth).await;
+        assert_eq!(
+            get_bridge_status().state,
+            DetailedBridgeState::DaiToXdai {
+                amount: MINIMUM_DAI_TO_SEND.into()
+            }
+        );
+        let us = pk.to_address();
+        assert_eq!(
+            eth_web3.eth_get_transaction_count(us).await.unwrap(),
+            1u8.into()
+        );
+    });
+}
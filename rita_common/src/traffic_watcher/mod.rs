@@ -353,9 +353,15 @@ pub fn watch(routes: Vec<Route>, neighbors: &[Neighbor]) -> Result<(), RitaCommo
 
 #[cfg(test)]
 mod tests {
+    use super::get_babel_info;
+    use babel_monitor::{open_babel_stream, parse_routes};
+    use mock_babeld::{start_babel_server, MockBabel, MockRoute};
+    use settings::client::RitaClientSettings;
     use std::collections::HashMap;
     use std::net::IpAddr;
     use std::net::Ipv6Addr;
+    use std::time::Duration;
+
     #[test]
     fn test_ip_lookup() {
         let ip_a: IpAddr = "fd00::1337:e8f".parse().unwrap();
@@ -366,4 +372,41 @@ mod tests {
         map.insert(ip_b, "test");
         assert!(map.get(&ip_a).is_some());
     }
+
+    #[test]
+    fn test_get_babel_info() {
+        let mesh_ip: IpAddr = "fd00::1".parse().unwrap();
+        let via: IpAddr = "fe80::2".parse().unwrap();
+        let babel = MockBabel::new();
+        let mut route = MockRoute::new("1", "fd00::2/128", via, "wg0");
+        route.price = 50;
+        babel.set_route(route);
+        let mut route = MockRoute::new("2", "fd00::3/128", via, "wg0");
+        route.price = 5000;
+        babel.set_route(route);
+        let mut route = MockRoute::new("3", "fd00::4/128", via, "wg0");
+        route.installed = false;
+        babel.set_route(route);
+        babel.set_route(MockRoute::new("4", "10.0.0.1/32", via, "wg0"));
+        let port = start_babel_server(babel).unwrap();
+
+        settings::set_rita_client(RitaClientSettings::default());
+        let mut common = settings::get_rita_common();
+        common.network.mesh_ip = Some(mesh_ip);
+        common.payment.local_fee = 10;
+        common.payment.max_fee = 1000;
+        settings::set_rita_common(common);
+
+        let mut stream = open_babel_stream(port, Duration::from_secs(2)).unwrap();
+        let routes = parse_routes(&mut stream).unwrap();
+        assert_eq!(routes.len(), 4);
+        let (destinations, local_fee) = get_babel_info(routes).unwrap();
+        assert_eq!(local_fee, 10);
+        // our own ip is free, prices are capped at max_fee and uninstalled or ipv4 routes
+        // are ignored
+        assert_eq!(destinations.len(), 3);
+        assert_eq!(destinations[&mesh_ip], 0);
+        assert_eq!(destinations[&"fd00::2".parse::<IpAddr>().unwrap()], 60);
+        assert_eq!(destinations[&"fd00::3".parse::<IpAddr>().unwrap()], 1010);
+    }
 }
@@ -4,6 +4,8 @@
 //! it's mostly used for Gateways to reach exits and bridge them into the local babel mesh network, allowing clients
 //! to reach them and send traffic to the internet.
 
+use crate::peer_listener::capabilities::{Capabilities, HELLO_PROTOCOL_V2};
+use crate::peer_listener::message::HelloFormat;
 use crate::peer_listener::send_hello;
 use crate::peer_listener::structs::Hello as NewHello;
 use crate::peer_listener::structs::Peer;
@@ -137,12 +139,35 @@ pub async fn tm_neighbor_inquiry_manual_peer(peer: Peer) -> Result<(), RitaCommo
     info!("Received a local identity, setting a tunnel");
     let peer = msg.to;
     let wg_port = msg.my_id.wg_port;
-    match tm_identity_callback(IdentityCallback::new(response, peer, Some(wg_port))) {
+    match tm_identity_callback(IdentityCallback::new(
+        response,
+        peer,
+        Some(wg_port),
+        None,
+        None,
+    )) {
         Ok(_) => Ok(()),
         Err(e) => Err(e),
     }
 }
 
+/// The format to contact a neighbor in given what our tunnels to it negotiated. Neighbors we have
+/// no tunnel to get the newest format, older peers ignore it but still contact us with their own
+/// hello which we answer in the same format. Neighbors that have only ever sent us bincode hellos,
+/// or whose capabilities don't include the tlv hello, are contacted in bincode
+fn hello_format(capabilities: &[Option<Capabilities>]) -> HelloFormat {
+    if capabilities.is_empty()
+        || capabilities
+            .iter()
+            .flatten()
+            .any(|c| c.best_protocol_version() >= Some(HELLO_PROTOCOL_V2))
+    {
+        HelloFormat::Tlv
+    } else {
+        HelloFormat::Signed
+    }
+}
+
 /// Contacts one neighbor with our LocalIdentity to get their LocalIdentity and wireguard tunnel
 /// interface name. Sends a Hello over udp
 pub fn tm_neighbor_inquiry_udp_peer(peer: &Peer, pl: &PeerListener) -> Result<(), RitaCommonError> {
@@ -179,9 +204,23 @@ pub fn tm_neighbor_inquiry_udp_peer(peer: &Peer, pl: &PeerListener) -> Result<()
         response: false,
     };
 
+    let capabilities: Vec<Option<Capabilities>> = get_tunnel_manager()
+        .tunnels
+        .values()
+        .flatten()
+        .filter(|t| t.ip == peer.contact_socket.ip())
+        .map(|t| t.capabilities.clone())
+        .collect();
+
     // new send_hello call using udp socket
     // We do not need the old http hello except for exits, which are called as manual peers
-    send_hello(&new_msg, udp_socket, peer.contact_socket, our_port)
+    send_hello(
+        &new_msg,
+        udp_socket,
+        peer.contact_socket,
+        our_port,
+        hello_format(&capabilities),
+    )
 }
 
 /// takes a list of peers to contact and dispatches UDP hello messages to peers discovered via IPv6 link local
@@ -249,3 +288,36 @@ pub async fn tm_contact_peers(pl: &PeerListener) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer_listener::capabilities::HELLO_PROTOCOL_V1;
+
+    #[test]
+    fn test_hello_format() {
+        let negotiated = |versions: Vec<u8>| {
+            Some(Capabilities {
+                protocol_versions: versions,
+                ..Default::default()
+            })
+        };
+
+        assert_eq!(hello_format(&[]), HelloFormat::Tlv);
+        assert_eq!(
+            hello_format(&[negotiated(vec![HELLO_PROTOCOL_V1, HELLO_PROTOCOL_V2])]),
+            HelloFormat::Tlv
+        );
+        // a neighbor that has only ever sent bincode hellos
+        assert_eq!(hello_format(&[None]), HelloFormat::Signed);
+        assert_eq!(
+            hello_format(&[negotiated(vec![HELLO_PROTOCOL_V1])]),
+            HelloFormat::Signed
+        );
+        // one tunnel negotiated the tlv hello, the other hasn't heard one yet
+        assert_eq!(
+            hello_format(&[None, negotiated(vec![HELLO_PROTOCOL_V2])]),
+            HelloFormat::Tlv
+        );
+    }
+}
@@ -1,3 +1,4 @@
+use super::policy::PolicyRejection;
 use std::fmt;
 use std::fmt::Result as FormatResult;
 
@@ -5,6 +6,7 @@ use std::fmt::Result as FormatResult;
 pub enum TunnelManagerError {
     KernelInterfaceError(althea_kernel_interface::KernelInterfaceError),
     NoFreePortsError,
+    PeerRejected(PolicyRejection),
 }
 
 impl fmt::Display for TunnelManagerError {
@@ -12,6 +14,7 @@ impl fmt::Display for TunnelManagerError {
         match self {
             TunnelManagerError::KernelInterfaceError(e) => write!(f, "TunnelManagerError{:?}", e),
             TunnelManagerError::NoFreePortsError => write!(f, "NoFreePortsError"),
+            TunnelManagerError::PeerRejected(e) => write!(f, "Peer rejected by policy: {e}"),
         }
     }
 }
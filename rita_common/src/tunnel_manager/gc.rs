@@ -1,3 +1,4 @@
+use super::lifecycle::{TransitionReason, TunnelState};
 use super::{Tunnel, TunnelManager};
 use crate::KI;
 use althea_types::Identity;
@@ -37,23 +38,32 @@ impl TunnelManager {
         // checker issues, we should consider a method that does modify in place
         for (identity, tunnels) in self.tunnels.iter() {
             for tunnel in tunnels.iter() {
-                if tunnel_should_be_kept(
+                let mut tunnel = tunnel.clone();
+                match check_tunnel(
                     *identity,
-                    tunnel,
+                    &tunnel,
                     tunnel_handshake_timeout,
                     tunnel_timeout,
                     &interfaces,
                 ) {
-                    insert_into_tunnel_list(tunnel, &mut good);
-                } else {
-                    insert_into_tunnel_list(tunnel, &mut to_delete)
+                    TunnelCheck::Keep => {}
+                    // payment enforcement takes priority over link state, once paid gc
+                    // will correct the state on the next round
+                    TunnelCheck::Healthy if tunnel.state() != TunnelState::Suspended => {
+                        tunnel.transition(TunnelState::Up, TransitionReason::LinkHealthy);
+                    }
+                    TunnelCheck::Degraded(reason) if tunnel.state() != TunnelState::Suspended => {
+                        tunnel.transition(TunnelState::Degraded, reason);
+                    }
+                    TunnelCheck::Healthy | TunnelCheck::Degraded(_) => {}
+                    TunnelCheck::Remove(reason) => {
+                        tunnel.transition(TunnelState::Draining, reason);
+                        info!("TriggerGC: removing tunnel: {} {}", identity, tunnel);
+                        insert_into_tunnel_list(&tunnel, &mut to_delete);
+                        continue;
+                    }
                 }
-            }
-        }
-
-        for (id, tunnels) in to_delete.iter() {
-            for tunnel in tunnels {
-                info!("TriggerGC: removing tunnel: {} {}", id, tunnel);
+                insert_into_tunnel_list(&tunnel, &mut good);
             }
         }
 
@@ -67,13 +77,17 @@ impl TunnelManager {
         // would lead to nasty bugs in case del_interface() goes wrong for whatever reason.
         self.tunnels = good;
 
-        unmonitor_tunnels(to_delete);
+        for tunnel in unmonitor_tunnels(to_delete) {
+            self.record_closed(tunnel);
+        }
     }
 }
 
-fn unmonitor_tunnels(to_delete: HashMap<Identity, Vec<Tunnel>>) {
+/// Removes the tunnels from babel and deletes their interfaces, returning them closed
+pub(super) fn unmonitor_tunnels(to_delete: HashMap<Identity, Vec<Tunnel>>) -> Vec<Tunnel> {
+    let mut closed = Vec::new();
     for (_ident, tunnels) in to_delete {
-        for tunnel in tunnels {
+        for mut tunnel in tunnels {
             // In the same spirit, we return the port to the free port pool only after tunnel
             // deletion goes well.
             if let Err(e) = tunnel.unmonitor() {
@@ -81,9 +95,27 @@ fn unmonitor_tunnels(to_delete: HashMap<Identity, Vec<Tunnel>>) {
                     "Tunnel unmonitor failed during gc, garbage idle tunnel! {:?}",
                     e
                 );
+                tunnel.transition(TunnelState::Closed, TransitionReason::RemovalFailed);
+            } else {
+                tunnel.transition(TunnelState::Closed, TransitionReason::Removed);
             }
+            closed.push(tunnel);
         }
     }
+    closed
+}
+
+/// What gc should do with a tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TunnelCheck {
+    /// Leave the tunnel as it is
+    Keep,
+    /// The tunnel is working, it should be Up
+    Healthy,
+    /// The tunnel is kept but is not fully working
+    Degraded(TransitionReason),
+    /// The tunnel should be removed so that it can be recreated
+    Remove(TransitionReason),
 }
 
 /// This routine has two independent purposes, first is to clear out tunnels
@@ -124,16 +156,19 @@ fn unmonitor_tunnels(to_delete: HashMap<Identity, Vec<Tunnel>>) {
 ///   the wireguard tunnel, which is the same as asking if unicast communication over this tunnel has been recently
 ///   successful. In theory we could look for a neighbor that's online from the tunnel interface in the babel routing
 ///   table and solve both this and the previous complication at once. So that's a possible improvement to this routine.
-fn tunnel_should_be_kept(
+///
+/// Tunnels that are kept are also sorted into healthy or degraded so their lifecycle state can be
+/// updated, and tunnels that are removed carry the reason they were removed.
+fn check_tunnel(
     category_id: Identity,
     tunnel: &Tunnel,
     tunnel_handshake_timeout: Duration,
     tunnel_timeout: Duration,
     interfaces: &HashMap<String, bool>,
-) -> bool {
+) -> TunnelCheck {
     // tunnel misfiled under the wrong id, this should never happen but we protect against it
     if category_id != tunnel.neigh_id.global {
-        return false;
+        return TunnelCheck::Remove(TransitionReason::MisfiledIdentity);
     }
 
     let since_created = Instant::now().checked_duration_since(tunnel.created());
@@ -153,27 +188,32 @@ fn tunnel_should_be_kept(
             tunnel_up,
         ) {
             // recently created tunnels should always be kept while they converge
-            (true, _, _, _) => true,
+            (true, false, false, true) => TunnelCheck::Healthy,
+            (true, _, _, _) => TunnelCheck::Keep,
             // this is a good tunnel
-            (false, false, false, true) => true,
+            (false, false, false, true) => TunnelCheck::Healthy,
             // contact timeout, but handshakes are going and babel is connected, this is a good tunnel suffering
             // from multicast optimization
-            (false, true, false, true) => true,
+            (false, true, false, true) => TunnelCheck::Degraded(TransitionReason::ContactTimeout),
             // we haven't heard from the neighbors Rita or Wireguard, fully disconnected
-            (false, true, true, _) => false,
+            (false, true, true, _) => TunnelCheck::Remove(TransitionReason::PeerOffline),
             // No recent handshakes, but recent contact from Rita, we should recreate this tunnel as it's probably
             // suffering from the negotiation race condition
-            (false, false, true, _) => false,
+            (false, false, true, _) => TunnelCheck::Remove(TransitionReason::HandshakeTimeout),
             // tunnel up, handshakes moving, but not up in babel, needs to be recreated in order to pass traffic
-            (false, false, false, false) => false,
+            (false, false, false, false) => {
+                TunnelCheck::Remove(TransitionReason::BabelInterfaceDown)
+            }
             // tunnel up, handshakes moving, but not up in babel, needs to be recreated in order to pass traffic but
             // it's also suffering from multicast optimization, meaning this tunnel may not re-open quickly.
             // Better to take that chance than simply not function. A better solution her may be to unlisten
             // and re-listen in babel on this tunnel
-            (false, true, false, false) => false,
+            (false, true, false, false) => {
+                TunnelCheck::Remove(TransitionReason::BabelInterfaceDown)
+            }
         }
     } else {
-        true
+        TunnelCheck::Keep
     }
 }
 
@@ -1,3 +1,6 @@
+use crate::peer_listener::capabilities::{our_operator_address, Capabilities};
+use crate::tunnel_manager::error::TunnelManagerError;
+use crate::tunnel_manager::policy::check_peer_policy;
 use crate::tunnel_manager::{get_tunnel_manager_write_ref, Tunnel, TUNNEL_MANAGER};
 use crate::{peer_listener::structs::Peer, RitaCommonError};
 use althea_types::LocalIdentity;
@@ -7,6 +10,11 @@ pub struct IdentityCallback {
     pub local_identity: LocalIdentity,
     pub peer: Peer,
     pub our_port: Option<u16>,
+    /// What the neighbor advertised in its hello, None if it sent one of the bincode hellos
+    pub capabilities: Option<Capabilities>,
+    /// The physical interface the hello arrived on, None if it did not come through the peer
+    /// listener
+    pub iface: Option<String>,
 }
 
 impl IdentityCallback {
@@ -14,11 +22,15 @@ impl IdentityCallback {
         local_identity: LocalIdentity,
         peer: Peer,
         our_port: Option<u16>,
+        capabilities: Option<Capabilities>,
+        iface: Option<String>,
     ) -> IdentityCallback {
         IdentityCallback {
             local_identity,
             peer,
             our_port,
+            capabilities,
+            iface,
         }
     }
 }
@@ -28,10 +40,53 @@ impl IdentityCallback {
 /// that a neighbor contacts us we don't have a port already allocated and we need to choose one
 /// in the case that we have attempted to contact a neighbor we have already sent them a port that
 /// we now must attach to their tunnel entry. If we also return a bool for if the tunnel already
-/// exists
+/// exists. Peers that the peer policy rejects get no tunnel and are recorded for the dashboard, the
+/// policy only applies to peers that said hello on a peer interface. Manual peers, which include our
+/// exits, were configured by the operator and are always accepted
 pub fn tm_identity_callback(msg: IdentityCallback) -> Result<(Tunnel, bool), RitaCommonError> {
     info!("Tm identity callback with msg: {:?}", msg);
+    let policy = settings::get_rita_common().network.peer_policy;
     let tm_pin = &mut *TUNNEL_MANAGER.write().unwrap();
     let tunnel_manager = get_tunnel_manager_write_ref(tm_pin);
-    tunnel_manager.open_tunnel(msg.local_identity, msg.peer)
+
+    let identity = msg.local_identity.global;
+    if let Some(iface) = &msg.iface {
+        let iface_peers = tunnel_manager.peers_on_interface(iface, &identity);
+        if let Err(reason) = check_peer_policy(
+            &policy,
+            &identity,
+            msg.capabilities.as_ref(),
+            our_operator_address(),
+            Some(iface),
+            iface_peers,
+        ) {
+            warn!("Refusing a tunnel to {:?}: {}", identity, reason);
+            tunnel_manager.record_rejected(identity, msg.iface.clone(), reason.clone());
+            return Err(TunnelManagerError::PeerRejected(reason).into());
+        }
+    }
+
+    let (mut tunnel, exists) = tunnel_manager.open_tunnel(msg.local_identity, msg.peer)?;
+    if let Some(iface) = msg.iface {
+        if let Some(stored) =
+            tunnel_manager.get_tunnel_mut(msg.peer.ifidx, msg.peer.contact_socket.ip(), identity)
+        {
+            stored.peer_iface = Some(iface.clone());
+        }
+        tunnel.peer_iface = Some(iface);
+    }
+    // a neighbor that only sends bincode hellos keeps whatever it last negotiated, it may have
+    // been answering one of our tlv hellos
+    if let Some(theirs) = msg.capabilities {
+        let agreed = Capabilities::ours().negotiate(&theirs);
+        if let Some(stored) = tunnel_manager.get_tunnel_mut(
+            msg.peer.ifidx,
+            msg.peer.contact_socket.ip(),
+            msg.local_identity.global,
+        ) {
+            stored.capabilities = Some(agreed.clone());
+        }
+        tunnel.capabilities = Some(agreed);
+    }
+    Ok((tunnel, exists))
 }
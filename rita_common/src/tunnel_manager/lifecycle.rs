@@ -0,0 +1,254 @@
+//! The lifecycle of a single tunnel. Every tunnel carries its current TunnelState along with the
+//! transitions that led there and why they happened, this is what operators see on the
+//! neighbors page when trying to work out why a tunnel was torn down or recreated.
+
+use super::{Tunnel, TunnelManager};
+use althea_types::Identity;
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::SystemTime;
+
+/// How many transitions each tunnel remembers, the oldest are dropped first
+pub const TUNNEL_HISTORY_LEN: usize = 16;
+/// How many closed tunnels TunnelManager remembers, the oldest are dropped first
+pub const CLOSED_TUNNELS_LEN: usize = 32;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Serialize, Deserialize)]
+pub enum TunnelState {
+    /// The tunnel has been created but has not yet been seen working, babel does not have it
+    /// up or no handshake has completed
+    Negotiating,
+    /// Handshakes are recent and babel has the interface up
+    Up,
+    /// The tunnel still passes traffic but we have not heard from the peer directly in a while,
+    /// usually a sector antenna swallowing multicast hellos
+    Degraded,
+    /// The peer is behind on payments and the tunnel is limited
+    Suspended,
+    /// The tunnel has been chosen for removal and is being torn down
+    Draining,
+    /// The tunnel has been removed from babel and the interface deleted
+    Closed,
+}
+
+impl fmt::Display for TunnelState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl TunnelState {
+    /// Transitions that the lifecycle allows, anything else is a bug in the caller
+    pub fn can_transition_to(self, to: TunnelState) -> bool {
+        use TunnelState::*;
+        match (self, to) {
+            (Negotiating, Up) | (Negotiating, Degraded) => true,
+            (Up, Degraded) | (Degraded, Up) => true,
+            // payment enforcement applies no matter the link state
+            (Negotiating, Suspended) | (Up, Suspended) | (Degraded, Suspended) => true,
+            // once paid the tunnel returns to wherever it was before enforcement
+            (Suspended, Negotiating) | (Suspended, Up) | (Suspended, Degraded) => true,
+            (Draining, Closed) => true,
+            (Closed, _) | (Draining, _) => false,
+            (_, Draining) => true,
+            _ => false,
+        }
+    }
+}
+
+/// Why a tunnel changed state
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash, Serialize, Deserialize)]
+pub enum TransitionReason {
+    /// A peer contacted us or answered our hello and we did not have a tunnel for them
+    PeerContact,
+    /// We had a tunnel for a peer that told us it did not have one, so ours was replaced
+    PeerLostTunnel,
+    /// The tunnel was saved before Rita restarted and its interface was adopted on startup
+    Restored,
+    /// Babel has the interface up and handshakes are recent
+    LinkHealthy,
+    /// Handshakes are recent but we have not heard from the peer in the tunnel timeout
+    ContactTimeout,
+    /// No handshake within the handshake timeout even though the peer is still contacting us
+    HandshakeTimeout,
+    /// No handshake and no contact from the peer within the timeouts, the peer is gone
+    PeerOffline,
+    /// Babel reports the interface as down
+    BabelInterfaceDown,
+    /// The tunnel was stored under an identity that is not its peer's
+    MisfiledIdentity,
+    /// The peer policy no longer accepts this peer
+    PolicyRejected,
+    PaymentOverdue,
+    PaymentThrottled,
+    PaidOnTime,
+    /// The interface was flushed from babel and deleted
+    Removed,
+    /// Flushing the interface from babel or deleting it failed, it may be orphaned
+    RemovalFailed,
+}
+
+impl fmt::Display for TransitionReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash, Serialize, Deserialize)]
+pub struct TunnelTransition {
+    /// None for the transition that created the tunnel
+    pub from: Option<TunnelState>,
+    pub to: TunnelState,
+    pub reason: TransitionReason,
+    pub time: SystemTime,
+}
+
+/// The history of a tunnel that has been removed, kept so the reason it was removed can still be
+/// looked up after it is gone
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClosedTunnel {
+    pub identity: Identity,
+    pub iface_name: String,
+    pub transitions: Vec<TunnelTransition>,
+}
+
+impl Tunnel {
+    pub fn state(&self) -> TunnelState {
+        self.state
+    }
+
+    pub fn history(&self) -> &[TunnelTransition] {
+        &self.history
+    }
+
+    /// Moves the tunnel into a new state and records why, returns false without changing
+    /// anything if the tunnel is already in that state or the lifecycle does not allow it
+    pub fn transition(&mut self, to: TunnelState, reason: TransitionReason) -> bool {
+        if self.state == to {
+            return false;
+        }
+        if !self.state.can_transition_to(to) {
+            warn!(
+                "Refusing tunnel {} transition from {} to {} because {}",
+                self.iface_name, self.state, to, reason
+            );
+            return false;
+        }
+        info!(
+            "Tunnel {} moving from {} to {} because {}",
+            self.iface_name, self.state, to, reason
+        );
+        self.record(Some(self.state), to, reason);
+        self.state = to;
+        true
+    }
+
+    /// The state this tunnel was in before payment enforcement started, the tunnel returns here
+    /// once it is paid up
+    pub fn state_before_suspension(&self) -> TunnelState {
+        self.history
+            .iter()
+            .rev()
+            .find(|t| t.to == TunnelState::Suspended)
+            .and_then(|t| t.from)
+            .unwrap_or(TunnelState::Up)
+    }
+
+    pub(super) fn record(
+        &mut self,
+        from: Option<TunnelState>,
+        to: TunnelState,
+        reason: TransitionReason,
+    ) {
+        if self.history.len() >= TUNNEL_HISTORY_LEN {
+            self.history.remove(0);
+        }
+        self.history.push(TunnelTransition {
+            from,
+            to,
+            reason,
+            time: SystemTime::now(),
+        });
+    }
+}
+
+impl TunnelManager {
+    /// Remembers a tunnel that has been removed, tunnels should be Closed before they get here
+    pub(super) fn record_closed(&mut self, tunnel: Tunnel) {
+        if self.closed.len() >= CLOSED_TUNNELS_LEN {
+            self.closed.pop_front();
+        }
+        self.closed.push_back(ClosedTunnel {
+            identity: tunnel.neigh_id.global,
+            iface_name: tunnel.iface_name,
+            transitions: tunnel.history,
+        });
+    }
+
+    pub fn closed_tunnels(&self) -> &VecDeque<ClosedTunnel> {
+        &self.closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel_manager::get_test_tunnel;
+
+    #[test]
+    fn test_transitions() {
+        let mut tunnel = get_test_tunnel("0.0.0.0".parse().unwrap());
+        assert_eq!(tunnel.state(), TunnelState::Negotiating);
+        assert!(tunnel.transition(TunnelState::Up, TransitionReason::LinkHealthy));
+        // no op transitions are not recorded
+        assert!(!tunnel.transition(TunnelState::Up, TransitionReason::LinkHealthy));
+        assert!(tunnel.transition(TunnelState::Degraded, TransitionReason::ContactTimeout));
+        assert!(tunnel.transition(TunnelState::Suspended, TransitionReason::PaymentOverdue));
+        assert_eq!(tunnel.state_before_suspension(), TunnelState::Degraded);
+        assert!(tunnel.transition(
+            tunnel.state_before_suspension(),
+            TransitionReason::PaidOnTime
+        ));
+        assert_eq!(tunnel.state(), TunnelState::Degraded);
+        assert!(tunnel.transition(TunnelState::Draining, TransitionReason::PeerOffline));
+        // a draining tunnel can only be closed
+        assert!(!tunnel.transition(TunnelState::Up, TransitionReason::LinkHealthy));
+        assert!(tunnel.transition(TunnelState::Closed, TransitionReason::Removed));
+        assert!(!tunnel.transition(TunnelState::Draining, TransitionReason::PeerOffline));
+
+        let reasons: Vec<TransitionReason> = tunnel.history().iter().map(|t| t.reason).collect();
+        assert_eq!(
+            reasons,
+            vec![
+                TransitionReason::PeerContact,
+                TransitionReason::LinkHealthy,
+                TransitionReason::ContactTimeout,
+                TransitionReason::PaymentOverdue,
+                TransitionReason::PaidOnTime,
+                TransitionReason::PeerOffline,
+                TransitionReason::Removed,
+            ]
+        );
+        assert_eq!(tunnel.history()[0].from, None);
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut tunnel = get_test_tunnel("0.0.0.0".parse().unwrap());
+        for _ in 0..TUNNEL_HISTORY_LEN {
+            tunnel.transition(TunnelState::Up, TransitionReason::LinkHealthy);
+            tunnel.transition(TunnelState::Degraded, TransitionReason::ContactTimeout);
+        }
+        assert_eq!(tunnel.history().len(), TUNNEL_HISTORY_LEN);
+        assert_eq!(
+            tunnel.history().last().unwrap().reason,
+            TransitionReason::ContactTimeout
+        );
+
+        let mut tm = TunnelManager::new();
+        for _ in 0..CLOSED_TUNNELS_LEN + 1 {
+            tm.record_closed(tunnel.clone());
+        }
+        assert_eq!(tm.closed_tunnels().len(), CLOSED_TUNNELS_LEN);
+    }
+}
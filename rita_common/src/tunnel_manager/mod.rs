@@ -8,29 +8,36 @@ pub mod contact_peers;
 pub mod error;
 pub mod gc;
 pub mod id_callback;
+pub mod lifecycle;
 pub mod neighbor_status;
+pub mod persist;
+pub mod policy;
 pub mod shaping;
 
 use crate::blockchain_oracle::potential_payment_issues_detected;
 use crate::insert_into_tunnel_list;
+use crate::peer_listener::capabilities::{our_operator_address, Capabilities};
 use crate::peer_listener::structs::Peer;
+use crate::rita_loop::get_babel_monitor;
 use crate::tunnel_manager::error::TunnelManagerError;
+use crate::tunnel_manager::lifecycle::{
+    ClosedTunnel, TransitionReason, TunnelState, TunnelTransition,
+};
+use crate::tunnel_manager::persist::SavedTunnel;
+use crate::tunnel_manager::policy::RejectedPeer;
 use crate::RitaCommonError;
 use crate::Shaper;
-use crate::FAST_LOOP_TIMEOUT;
 use crate::KI;
 use crate::TUNNEL_HANDSHAKE_TIMEOUT;
 use crate::TUNNEL_TIMEOUT;
 use althea_kernel_interface::open_tunnel::TunnelOpenArgs;
 use althea_types::Identity;
 use althea_types::LocalIdentity;
-use babel_monitor::monitor;
-use babel_monitor::open_babel_stream;
 use babel_monitor::structs::BabelMonitorError;
 use babel_monitor::structs::Interface;
-use babel_monitor::unmonitor;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::fmt;
 use std::fmt::Display;
 use std::hash::Hash;
@@ -73,6 +80,8 @@ pub enum TunnelAction {
     PaymentOverdue,
     /// Payment has resumed
     PaidOnTime,
+    /// Payment is far enough behind that the tunnel is limited to this throughput in kbit/s
+    PaymentThrottled { throughput: u32 },
 }
 
 impl fmt::Display for TunnelAction {
@@ -84,12 +93,14 @@ impl fmt::Display for TunnelAction {
 /// TunnelState indicates the payment state a tunnel is currently in
 /// if this is Overdue the tunnel will use a tbf qdisc to limit traffic on
 /// the interface
-#[derive(PartialEq, Debug, Clone, Copy, Eq, Hash)]
+#[derive(PartialEq, Debug, Clone, Copy, Eq, Hash, Serialize, Deserialize)]
 pub enum PaymentState {
     /// Tunnel is paid (default)
     Paid,
     /// Tunnel is not paid
     Overdue,
+    /// Tunnel is not paid and limited to this throughput in kbit/s rather than the free tier
+    Throttled { throughput: u32 },
 }
 
 impl fmt::Display for PaymentState {
@@ -102,6 +113,10 @@ impl fmt::Display for PaymentState {
 fn test_payment_state() {
     assert_eq!(PaymentState::Paid.to_string(), "Paid");
     assert_eq!(PaymentState::Overdue.to_string(), "Overdue");
+    assert_eq!(
+        PaymentState::Throttled { throughput: 100 }.to_string(),
+        "Throttled { throughput: 100 }"
+    );
 }
 
 #[derive(PartialEq, Debug, Clone, Eq, Hash)]
@@ -127,11 +142,20 @@ pub struct Tunnel {
     /// all routers do only exits are in question
     pub speed_limit: Option<usize>,
     payment_state: PaymentState,
+    /// Where this tunnel is in its lifecycle, see lifecycle.rs
+    state: TunnelState,
+    /// The most recent lifecycle transitions of this tunnel, oldest first
+    history: Vec<TunnelTransition>,
+    /// What we and the neighbor agreed to support, None until they send us a tlv hello
+    pub capabilities: Option<Capabilities>,
+    /// The name of the physical interface the neighbor last said hello on, None until a hello
+    /// arrives through the peer listener
+    pub peer_iface: Option<String>,
 }
 
 impl Display for Tunnel {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Tunnel: IP: {} IFACE_NAME: {} IFIDX: {}, PORT: {} WG: {} ETH: {} MESH_IP: {} LAST_SEEN {}, SPEED_LIMIT {:?}, PAYMENT_STATE: {:?}" , 
+        write!(f, "Tunnel: IP: {} IFACE_NAME: {} IFIDX: {}, PORT: {} WG: {} ETH: {} MESH_IP: {} LAST_SEEN {}, SPEED_LIMIT {:?}, PAYMENT_STATE: {:?}, STATE: {}" , 
         self.ip,
         self.iface_name,
         self.listen_ifidx,
@@ -141,7 +165,8 @@ impl Display for Tunnel {
         self.neigh_id.global.mesh_ip,
         (Instant::now() - self.last_contact).as_secs(),
         self.speed_limit,
-        self.payment_state)
+        self.payment_state,
+        self.state)
     }
 }
 
@@ -151,6 +176,7 @@ impl Tunnel {
         our_listen_port: u16,
         ifidx: u32,
         neigh_id: LocalIdentity,
+        reason: TransitionReason,
     ) -> Result<Tunnel, RitaCommonError> {
         let speed_limit = None;
         let iface_name = KI.setup_wg_if()?;
@@ -178,7 +204,7 @@ impl Tunnel {
         KI.set_codel_shaping(&iface_name, speed_limit)?;
 
         let now = Instant::now();
-        let t = Tunnel {
+        let mut t = Tunnel {
             ip,
             iface_name,
             listen_ifidx: ifidx,
@@ -189,7 +215,12 @@ impl Tunnel {
             speed_limit,
             // By default new tunnels are in paid state
             payment_state: PaymentState::Paid,
+            state: TunnelState::Negotiating,
+            history: Vec::new(),
+            capabilities: None,
+            peer_iface: None,
         };
+        t.record(None, TunnelState::Negotiating, reason);
 
         // attach to babel
         t.monitor()?;
@@ -204,23 +235,17 @@ impl Tunnel {
     /// Register this tunnel into Babel monitor
     pub fn monitor(&self) -> Result<(), BabelMonitorError> {
         info!("Monitoring tunnel {}", self.iface_name);
-        let iface_name = self.iface_name.clone();
-        let babel_port = settings::get_rita_common().network.babel_port;
 
         // this operation blocks while opening and using a tcp stream
-        let mut stream = open_babel_stream(babel_port, FAST_LOOP_TIMEOUT)?;
-        monitor(&mut stream, &iface_name)
+        get_babel_monitor().monitor(&self.iface_name)
     }
 
     pub fn unmonitor(&self) -> Result<(), RitaCommonError> {
         warn!("Unmonitoring tunnel {}", self.iface_name);
-        let iface_name = self.iface_name.clone();
-        let babel_port = settings::get_rita_common().network.babel_port;
         let tunnel = self.clone();
 
         // this operation blocks while opening and using a tcp stream
-        let mut stream = open_babel_stream(babel_port, FAST_LOOP_TIMEOUT)?;
-        unmonitor(&mut stream, &iface_name)?;
+        get_babel_monitor().unmonitor(&self.iface_name)?;
 
         // We must wait until we have flushed the interface before deleting it
         // otherwise we will experience this error
@@ -237,6 +262,12 @@ impl Tunnel {
 pub struct TunnelManager {
     tunnels: HashMap<Identity, Vec<Tunnel>>,
     shaper: Shaper,
+    /// Recently removed tunnels, oldest first
+    closed: VecDeque<ClosedTunnel>,
+    /// The tunnel table as it was last written to disk, see persist.rs
+    last_saved: Vec<SavedTunnel>,
+    /// Peers recently refused a tunnel by the peer policy, see policy.rs
+    rejected: VecDeque<RejectedPeer>,
 }
 
 impl Default for TunnelManager {
@@ -251,20 +282,21 @@ pub struct Neighbor {
     pub iface_name: String,
     pub tunnel_ip: IpAddr,
     pub speed_limit: Option<usize>,
+    pub state: TunnelState,
+    pub history: Vec<TunnelTransition>,
+    pub capabilities: Option<Capabilities>,
 }
 
 impl Neighbor {
-    fn new(
-        identity: LocalIdentity,
-        iface_name: String,
-        tunnel_ip: IpAddr,
-        speed_limit: Option<usize>,
-    ) -> Neighbor {
+    fn new(tunnel: &Tunnel) -> Neighbor {
         Neighbor {
-            identity,
-            iface_name,
-            tunnel_ip,
-            speed_limit,
+            identity: tunnel.neigh_id,
+            iface_name: tunnel.iface_name.clone(),
+            tunnel_ip: tunnel.ip,
+            speed_limit: tunnel.speed_limit,
+            state: tunnel.state,
+            history: tunnel.history.clone(),
+            capabilities: tunnel.capabilities.clone(),
         }
     }
 }
@@ -274,31 +306,73 @@ pub fn tm_get_neighbors() -> Vec<Neighbor> {
     let mut res = Vec::new();
     for (_, tunnels) in tunnel_manager.tunnels.iter() {
         for tunnel in tunnels.iter() {
-            res.push(Neighbor::new(
-                tunnel.neigh_id,
-                tunnel.iface_name.clone(),
-                tunnel.ip,
-                tunnel.speed_limit,
-            ));
+            res.push(Neighbor::new(tunnel));
         }
     }
     res
 }
 
+/// Gets the history of recently removed tunnels, oldest first
+pub fn tm_get_closed_tunnels() -> Vec<ClosedTunnel> {
+    get_tunnel_manager()
+        .closed_tunnels()
+        .iter()
+        .cloned()
+        .collect()
+}
+
+/// Gets the peers recently refused a tunnel by the peer policy, least recently rejected first
+pub fn tm_get_rejected_peers() -> Vec<RejectedPeer> {
+    get_tunnel_manager()
+        .rejected_peers()
+        .iter()
+        .cloned()
+        .collect()
+}
+
 /// Simple helper function to run tunnel GC + check babel interfaces
 pub fn tm_common_slow_loop_helper(babel_interfaces: Vec<Interface>) {
     let tm_pin = &mut *TUNNEL_MANAGER.write().unwrap();
     let tunnel_manager = get_tunnel_manager_write_ref(tm_pin);
     tunnel_manager.monitor_check(&babel_interfaces);
+    tunnel_manager.remove_rejected_tunnels(
+        &settings::get_rita_common().network.peer_policy,
+        our_operator_address(),
+    );
     trace!("Sending tunnel GC");
     tunnel_manager.tunnel_gc(TUNNEL_TIMEOUT, TUNNEL_HANDSHAKE_TIMEOUT, babel_interfaces);
 }
 
+/// Called when babel stops routing over an interface, if it belongs to one of our tunnels that
+/// we are not tearing down babel was restarted or lost it and we attach it again right away
+/// rather than waiting for the slow loop to notice
+pub fn tm_babel_interface_removed(iface_name: &str) {
+    // tunnels are removed from the table before babel is told to flush them, so a tunnel that
+    // is being torn down is either gone or draining by the time we see the event
+    let tunnel_manager = get_tunnel_manager();
+    let tunnel = tunnel_manager
+        .tunnels
+        .values()
+        .flatten()
+        .find(|t| t.iface_name == iface_name);
+    if let Some(tunnel) = tunnel {
+        if tunnel.state() != TunnelState::Draining {
+            info!("Babel dropped tunnel {}, readding it", iface_name);
+            if let Err(e) = tunnel.monitor() {
+                error!("Unable to re-add tunnel to babel with: {:?}", e);
+            }
+        }
+    }
+}
+
 impl TunnelManager {
     pub fn new() -> Self {
         TunnelManager {
             tunnels: HashMap::new(),
             shaper: Shaper::default(),
+            closed: VecDeque::new(),
+            last_saved: Vec::new(),
+            rejected: VecDeque::new(),
         }
     }
 
@@ -408,10 +482,11 @@ impl TunnelManager {
         peer_ip: IpAddr,
         ifidx: u32,
         their_localid: LocalIdentity,
+        reason: TransitionReason,
     ) -> Result<Tunnel, RitaCommonError> {
         let our_port = self.get_next_available_port()?;
         // Create new tunnel
-        let tunnel = Tunnel::new(peer_ip, our_port, ifidx, their_localid);
+        let tunnel = Tunnel::new(peer_ip, our_port, ifidx, their_localid, reason);
         match tunnel {
             Ok(tunnel) => {
                 trace!("Tunnel {:?} is open", tunnel);
@@ -456,24 +531,30 @@ impl TunnelManager {
                         "We have a tunnel but our peer {:?} does not! Handling",
                         peer.contact_socket.ip()
                     );
+                    our_tunnel.transition(TunnelState::Draining, TransitionReason::PeerLostTunnel);
                     // tell Babel to flush the interface and then delete it, if this fails we continue
                     // with what we're doing becuase we don't know the state of the remaining tunnel
                     // so we leave it orphaned to be cleared on system reboot.
                     let res = our_tunnel.unmonitor();
+                    // drop the mutable tunnel reference via cloning
+                    let mut our_tunnel = our_tunnel.clone();
                     if res.is_err() {
                         error!(
                             "We failed to unmonitor the interface {:?} with {:?} it's now orphaned",
                             our_tunnel.iface_name, res
                         );
+                        our_tunnel.transition(TunnelState::Closed, TransitionReason::RemovalFailed);
+                    } else {
+                        our_tunnel.transition(TunnelState::Closed, TransitionReason::Removed);
                     }
-                    // drop the mutable tunnel reference via cloning
-                    let our_tunnel = our_tunnel.clone();
-                    self.del_tunnel(our_tunnel);
+                    self.del_tunnel(our_tunnel.clone());
+                    self.record_closed(our_tunnel);
                     // create a new tunnel with details from this message
                     let tunnel = self.create_new_tunnel(
                         peer.contact_socket.ip(),
                         peer.ifidx,
                         their_localid,
+                        TransitionReason::PeerLostTunnel,
                     )?;
                     Ok((tunnel, true))
                 }
@@ -484,8 +565,12 @@ impl TunnelManager {
                     peer.contact_socket.ip(),
                     peer.ifidx,
                 );
-                let tunnel =
-                    self.create_new_tunnel(peer.contact_socket.ip(), peer.ifidx, their_localid)?;
+                let tunnel = self.create_new_tunnel(
+                    peer.contact_socket.ip(),
+                    peer.ifidx,
+                    their_localid,
+                    TransitionReason::PeerContact,
+                )?;
                 Ok((tunnel, false))
             }
         }
@@ -514,12 +599,16 @@ impl TunnelManager {
                                 PaymentState::Paid => {
                                     continue;
                                 }
-                                PaymentState::Overdue => {
+                                PaymentState::Overdue | PaymentState::Throttled { .. } => {
                                     info!(
                                         "Tunnel {} has returned to a paid state.",
                                         tunnel.neigh_id.global.wg_public_key
                                     );
                                     tunnel.payment_state = PaymentState::Paid;
+                                    tunnel.transition(
+                                        tunnel.state_before_suspension(),
+                                        TransitionReason::PaidOnTime,
+                                    );
                                     tunnel_bw_limits_need_change = true;
                                     // latency detector probably got confused while enforcement
                                     // occurred
@@ -530,12 +619,16 @@ impl TunnelManager {
                         TunnelAction::PaymentOverdue => {
                             trace!("No payment from identity {:?}", id);
                             match tunnel.payment_state {
-                                PaymentState::Paid => {
+                                PaymentState::Paid | PaymentState::Throttled { .. } => {
                                     info!(
                                         "Tunnel {} has entered an overdue state.",
                                         tunnel.neigh_id.global.wg_public_key
                                     );
                                     tunnel.payment_state = PaymentState::Overdue;
+                                    tunnel.transition(
+                                        TunnelState::Suspended,
+                                        TransitionReason::PaymentOverdue,
+                                    );
                                     tunnel_bw_limits_need_change = true;
                                 }
                                 PaymentState::Overdue => {
@@ -543,6 +636,23 @@ impl TunnelManager {
                                 }
                             }
                         }
+                        TunnelAction::PaymentThrottled { throughput } => {
+                            trace!("Throttling identity {:?} to {} kbit/s", id, throughput);
+                            let state = PaymentState::Throttled { throughput };
+                            if tunnel.payment_state == state {
+                                continue;
+                            }
+                            info!(
+                                "Tunnel {} has been throttled to {} kbit/s.",
+                                tunnel.neigh_id.global.wg_public_key, throughput
+                            );
+                            tunnel.payment_state = state;
+                            tunnel.transition(
+                                TunnelState::Suspended,
+                                TransitionReason::PaymentThrottled,
+                            );
+                            tunnel_bw_limits_need_change = true;
+                        }
                     }
                 }
             }
@@ -617,10 +727,14 @@ fn tunnel_bw_limit_update(tunnels: &HashMap<Identity, Vec<Tunnel>>) -> Result<()
             let iface_name = &tunnel.iface_name;
             let has_limit = KI.has_limit(iface_name)?;
 
-            if *payment_state == PaymentState::Overdue {
-                KI.set_classless_limit(iface_name, bw_per_iface)?;
-            } else if *payment_state == PaymentState::Paid && has_limit {
-                KI.set_codel_shaping(iface_name, None)?;
+            match *payment_state {
+                PaymentState::Overdue => KI.set_classless_limit(iface_name, bw_per_iface)?,
+                // throttled tunnels get their own limit rather than a share of the free tier
+                PaymentState::Throttled { throughput } => {
+                    KI.set_classless_limit(iface_name, throughput)?
+                }
+                PaymentState::Paid if has_limit => KI.set_codel_shaping(iface_name, None)?,
+                PaymentState::Paid => {}
             }
         }
     }
@@ -641,7 +755,7 @@ pub fn get_test_id() -> Identity {
 }
 
 pub fn get_test_tunnel(ip: Ipv4Addr) -> Tunnel {
-    Tunnel {
+    let mut tunnel = Tunnel {
         ip: ip.into(),
         iface_name: "iface".to_string(),
         listen_ifidx: 0,
@@ -655,7 +769,17 @@ pub fn get_test_tunnel(ip: Ipv4Addr) -> Tunnel {
         created: Instant::now(),
         speed_limit: None,
         payment_state: PaymentState::Paid,
-    }
+        state: TunnelState::Negotiating,
+        history: Vec::new(),
+        capabilities: None,
+        peer_iface: None,
+    };
+    tunnel.record(
+        None,
+        TunnelState::Negotiating,
+        TransitionReason::PeerContact,
+    );
+    tunnel
 }
 
 #[cfg(test)]
@@ -26,7 +26,7 @@ pub fn get_neighbor_status() -> HashMap<Identity, NeighborStatus> {
                 (None, Some(_)) => {}
                 (None, None) => {}
             }
-            if tunnel.payment_state == PaymentState::Overdue {
+            if tunnel.payment_state != PaymentState::Paid {
                 enforced = true;
             }
         }
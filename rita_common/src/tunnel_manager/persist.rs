@@ -0,0 +1,327 @@
+//! Saves the tunnel table to disk so that a restarted Rita can adopt the WireGuard interfaces it
+//! left behind rather than tearing them down and waiting for every neighbor to hello again. A
+//! saved tunnel is only adopted if its interface still exists and its only peer is the neighbor
+//! we saved, anything else is deleted just like it was before tunnels were persisted.
+
+use super::lifecycle::{TransitionReason, TunnelState};
+use super::{get_tunnel_manager_write_ref, PaymentState, Tunnel, TunnelManager, TUNNEL_MANAGER};
+use crate::insert_into_tunnel_list;
+use crate::KI;
+use althea_types::{LocalIdentity, WgKey};
+use std::collections::HashSet;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Instant;
+
+/// The part of a tunnel that is needed to adopt its interface after a restart
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SavedTunnel {
+    pub ip: IpAddr,
+    pub iface_name: String,
+    pub listen_ifidx: u32,
+    pub listen_port: u16,
+    pub neigh_id: LocalIdentity,
+    pub speed_limit: Option<usize>,
+    /// The physical interface the peer contacted us on, so that restored tunnels count toward
+    /// the peer policy's per interface limits
+    pub peer_iface: Option<String>,
+    /// An enforced or throttled tunnel keeps its kernel shaping across a restart, so it has to
+    /// come back enforced for the shaping to be lifted once the neighbor pays
+    pub payment_state: PaymentState,
+}
+
+impl From<&Tunnel> for SavedTunnel {
+    fn from(tunnel: &Tunnel) -> Self {
+        SavedTunnel {
+            ip: tunnel.ip,
+            iface_name: tunnel.iface_name.clone(),
+            listen_ifidx: tunnel.listen_ifidx,
+            listen_port: tunnel.listen_port,
+            neigh_id: tunnel.neigh_id,
+            speed_limit: tunnel.speed_limit,
+            peer_iface: tunnel.peer_iface.clone(),
+            payment_state: tunnel.payment_state,
+        }
+    }
+}
+
+impl SavedTunnel {
+    /// Rebuilds the tunnel, it starts over as Negotiating since we know nothing about its link
+    /// other than that the interface is still there. An unpaid tunnel goes straight on to
+    /// Suspended so that debt keeper can return it to paid and remove its shaping as usual
+    fn into_tunnel(self) -> Tunnel {
+        let now = Instant::now();
+        let mut tunnel = Tunnel {
+            ip: self.ip,
+            iface_name: self.iface_name,
+            listen_ifidx: self.listen_ifidx,
+            listen_port: self.listen_port,
+            neigh_id: self.neigh_id,
+            last_contact: now,
+            created: now,
+            speed_limit: self.speed_limit,
+            payment_state: self.payment_state,
+            state: TunnelState::Negotiating,
+            history: Vec::new(),
+            capabilities: None,
+            peer_iface: self.peer_iface,
+        };
+        tunnel.record(None, TunnelState::Negotiating, TransitionReason::Restored);
+        match self.payment_state {
+            PaymentState::Paid => {}
+            PaymentState::Overdue => {
+                tunnel.transition(TunnelState::Suspended, TransitionReason::PaymentOverdue);
+            }
+            PaymentState::Throttled { .. } => {
+                tunnel.transition(TunnelState::Suspended, TransitionReason::PaymentThrottled);
+            }
+        }
+        tunnel
+    }
+}
+
+/// Tunnels are stored next to the debts file, with the same name but a different extension
+pub fn get_tunnels_file_path() -> String {
+    let debts_file = settings::get_rita_common().payment.debts_file;
+    Path::new(&debts_file)
+        .with_extension("tunnels")
+        .to_string_lossy()
+        .to_string()
+}
+
+fn load_saved_tunnels() -> Vec<SavedTunnel> {
+    match fs::read(get_tunnels_file_path()) {
+        Ok(bytes) => match bincode::deserialize(&bytes) {
+            Ok(tunnels) => tunnels,
+            Err(e) => {
+                error!("Failed to deserialize saved tunnels {:?}", e);
+                Vec::new()
+            }
+        },
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Writes the tunnels to a temporary file then moves them into place, so that a crash mid write
+/// leaves us with the previous table rather than a corrupt one
+fn write_saved_tunnels(tunnels: &[SavedTunnel]) -> Result<(), String> {
+    let file_path = get_tunnels_file_path();
+    let tmp_path = format!("{file_path}.tmp");
+    let bytes = bincode::serialize(tunnels).map_err(|e| e.to_string())?;
+    let mut file = File::create(&tmp_path).map_err(|e| e.to_string())?;
+    file.write_all(&bytes).map_err(|e| e.to_string())?;
+    file.sync_all().map_err(|e| e.to_string())?;
+    fs::rename(&tmp_path, &file_path).map_err(|e| e.to_string())
+}
+
+/// True for the wg# interfaces used by per hop tunnels, as opposed to wg_exit and friends
+pub fn is_per_hop_tunnel(iface: &str) -> bool {
+    match iface.strip_prefix("wg") {
+        Some(num) => !num.is_empty() && num.chars().all(|c| c.is_ascii_digit()),
+        None => false,
+    }
+}
+
+/// A saved tunnel can be adopted if its interface is still around and WireGuard still has the
+/// neighbor we saved as its one and only peer
+fn can_adopt(saved: &SavedTunnel, interfaces: &HashSet<String>, peers: &[WgKey]) -> bool {
+    interfaces.contains(&saved.iface_name)
+        && peers.len() == 1
+        && peers[0] == saved.neigh_id.global.wg_public_key
+}
+
+impl TunnelManager {
+    fn saved_tunnels(&self) -> Vec<SavedTunnel> {
+        let mut saved: Vec<SavedTunnel> = self
+            .tunnels
+            .values()
+            .flatten()
+            .map(SavedTunnel::from)
+            .collect();
+        saved.sort_by(|a, b| a.iface_name.cmp(&b.iface_name));
+        saved
+    }
+
+    /// Saves the tunnel table if it has changed since the last save
+    pub fn save_if_changed(&mut self) {
+        let tunnels = self.saved_tunnels();
+        if tunnels == self.last_saved {
+            return;
+        }
+        match write_saved_tunnels(&tunnels) {
+            Ok(()) => self.last_saved = tunnels,
+            Err(e) => error!("Failed to save tunnels {}", e),
+        }
+    }
+
+    /// Adopts every saved tunnel whose interface still matches, returning the names of the
+    /// interfaces that were adopted
+    fn adopt_tunnels(
+        &mut self,
+        saved: Vec<SavedTunnel>,
+        interfaces: &HashSet<String>,
+    ) -> HashSet<String> {
+        let mut adopted = HashSet::new();
+        let mut used_ports = HashSet::new();
+        for saved_tunnel in saved {
+            if !interfaces.contains(&saved_tunnel.iface_name) {
+                continue;
+            }
+            let peers = match KI.get_peers(&saved_tunnel.iface_name) {
+                Ok(peers) => peers,
+                Err(e) => {
+                    warn!(
+                        "Could not get peers for saved tunnel {} {:?}",
+                        saved_tunnel.iface_name, e
+                    );
+                    continue;
+                }
+            };
+            // the file should never contain duplicates, but if it does only adopt once
+            if !can_adopt(&saved_tunnel, interfaces, &peers)
+                || adopted.contains(&saved_tunnel.iface_name)
+                || !used_ports.insert(saved_tunnel.listen_port)
+            {
+                continue;
+            }
+            info!(
+                "Adopting saved tunnel {} to {}",
+                saved_tunnel.iface_name, saved_tunnel.neigh_id.global.wg_public_key
+            );
+            adopted.insert(saved_tunnel.iface_name.clone());
+            let tunnel = saved_tunnel.into_tunnel();
+            // babel was restarted, if this fails monitor_check will try again later
+            if let Err(e) = tunnel.monitor() {
+                warn!(
+                    "Failed to monitor adopted tunnel {} {:?}",
+                    tunnel.iface_name, e
+                );
+            }
+            insert_into_tunnel_list(&tunnel, &mut self.tunnels);
+        }
+        self.last_saved = self.saved_tunnels();
+        adopted
+    }
+}
+
+/// Called once on startup before any tunnels are opened. Adopts the saved tunnels that still
+/// match their interfaces and deletes every other per hop tunnel interface left over from the
+/// last time Rita ran
+pub fn restore_tunnels() {
+    let interfaces: HashSet<String> = match KI.get_interfaces() {
+        Ok(interfaces) => interfaces.into_iter().collect(),
+        Err(e) => {
+            error!("Failed to list interfaces, can't restore tunnels {:?}", e);
+            return;
+        }
+    };
+
+    let adopted = {
+        let tm_pin = &mut *TUNNEL_MANAGER.write().unwrap();
+        let tunnel_manager = get_tunnel_manager_write_ref(tm_pin);
+        tunnel_manager.adopt_tunnels(load_saved_tunnels(), &interfaces)
+    };
+    info!("Adopted {} tunnels from before restart", adopted.len());
+
+    for iface in interfaces {
+        if is_per_hop_tunnel(&iface) && !adopted.contains(&iface) {
+            info!("Removing leftover tunnel {}", iface);
+            if let Err(e) = KI.del_interface(&iface) {
+                error!("Failed to delete leftover tunnel {} {:?}", iface, e);
+            }
+        }
+    }
+}
+
+/// Saves the tunnel table if it has changed, called from the save to disk loop
+pub fn save_tunnels_to_disk() {
+    let tm_pin = &mut *TUNNEL_MANAGER.write().unwrap();
+    let tunnel_manager = get_tunnel_manager_write_ref(tm_pin);
+    tunnel_manager.save_if_changed();
+}
+
+/// On an interupt (SIGTERM), saving the tunnel table before exiting so the tunnels can be
+/// adopted when we come back up
+pub fn save_tunnels_on_shutdown() {
+    let tm_pin = &mut *TUNNEL_MANAGER.write().unwrap();
+    let tunnel_manager = get_tunnel_manager_write_ref(tm_pin);
+    if let Err(e) = write_saved_tunnels(&tunnel_manager.saved_tunnels()) {
+        error!("Failed to save tunnels {}", e);
+    } else {
+        info!("Shutdown: Saving tunnels");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel_manager::get_test_tunnel;
+
+    #[test]
+    fn test_is_per_hop_tunnel() {
+        assert!(is_per_hop_tunnel("wg0"));
+        assert!(is_per_hop_tunnel("wg42"));
+        assert!(!is_per_hop_tunnel("wg"));
+        assert!(!is_per_hop_tunnel("wg_exit"));
+        assert!(!is_per_hop_tunnel("wg_exit_v2"));
+        assert!(!is_per_hop_tunnel("eth0"));
+    }
+
+    #[test]
+    fn test_can_adopt() {
+        let mut tunnel = get_test_tunnel("10.0.0.1".parse().unwrap());
+        tunnel.iface_name = "wg3".to_string();
+        let saved = SavedTunnel::from(&tunnel);
+        let key = saved.neigh_id.global.wg_public_key;
+        let other: WgKey = "8BeCExnthLe5ou0EYec5jNqJ/PduZ1x2o7lpXJOpgXk="
+            .parse()
+            .unwrap();
+        let interfaces: HashSet<String> = vec!["wg3".to_string()].into_iter().collect();
+
+        assert!(can_adopt(&saved, &interfaces, &[key]));
+        // the interface has been reused for someone else
+        assert!(!can_adopt(&saved, &interfaces, &[other]));
+        assert!(!can_adopt(&saved, &interfaces, &[key, other]));
+        assert!(!can_adopt(&saved, &interfaces, &[]));
+        assert!(!can_adopt(&saved, &HashSet::new(), &[key]));
+    }
+
+    #[test]
+    fn test_saved_tunnel_round_trip() {
+        let mut tunnel = get_test_tunnel("10.0.0.1".parse().unwrap());
+        tunnel.speed_limit = Some(50);
+        let saved = SavedTunnel::from(&tunnel);
+        let bytes = bincode::serialize(&vec![saved.clone()]).unwrap();
+        let loaded: Vec<SavedTunnel> = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(loaded, vec![saved]);
+
+        let restored = loaded[0].clone().into_tunnel();
+        assert_eq!(restored.iface_name, tunnel.iface_name);
+        assert_eq!(restored.listen_port, tunnel.listen_port);
+        assert_eq!(restored.neigh_id, tunnel.neigh_id);
+        assert_eq!(restored.speed_limit, Some(50));
+        assert_eq!(restored.state(), TunnelState::Negotiating);
+        assert_eq!(restored.history()[0].reason, TransitionReason::Restored);
+        assert_eq!(restored.payment_state, PaymentState::Paid);
+    }
+
+    #[test]
+    fn test_restored_tunnel_keeps_enforcement() {
+        let mut tunnel = get_test_tunnel("10.0.0.1".parse().unwrap());
+        tunnel.payment_state = PaymentState::Throttled { throughput: 500 };
+        let bytes = bincode::serialize(&vec![SavedTunnel::from(&tunnel)]).unwrap();
+        let loaded: Vec<SavedTunnel> = bincode::deserialize(&bytes).unwrap();
+
+        let restored = loaded[0].clone().into_tunnel();
+        assert_eq!(
+            restored.payment_state,
+            PaymentState::Throttled { throughput: 500 }
+        );
+        assert_eq!(restored.state(), TunnelState::Suspended);
+        // once paid the tunnel goes back to negotiating rather than straight to up
+        assert_eq!(restored.state_before_suspension(), TunnelState::Negotiating);
+    }
+}
@@ -0,0 +1,385 @@
+//! Operator controls over which neighbors get a tunnel. Every hello on a peer interface that would
+//! open a tunnel is checked against the PeerPolicy in NetworkSettings first, peers that fail it are
+//! not given a tunnel and are remembered so that the dashboard can show who was turned away and why.
+//! Tunnels that already exist for a peer that the policy now rejects are removed on the next slow
+//! loop. Manual peers and exits are configured by the operator and are not subject to the policy.
+//!
+//! The eth address, mesh ip and operator address a peer is matched on are taken from its hello.
+//! Unless require_signed_hellos is on any neighbor can claim any of them, only the wg key is
+//! proven by the tunnel handshake.
+
+use super::gc::unmonitor_tunnels;
+use super::lifecycle::{TransitionReason, TunnelState};
+use super::{Tunnel, TunnelManager};
+use crate::insert_into_tunnel_list;
+use crate::peer_listener::capabilities::Capabilities;
+use althea_types::{Identity, PeerMatch, PeerPolicy};
+use clarity::Address;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::time::SystemTime;
+
+/// How many rejected peers TunnelManager remembers, the least recently rejected are dropped first
+pub const REJECTED_PEERS_LEN: usize = 32;
+
+#[derive(PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+pub enum PolicyRejection {
+    /// The peer matched this entry of the denylist
+    Denied(PeerMatch),
+    /// There is an allowlist and the peer is not on it
+    NotAllowed,
+    /// The interface the peer contacted us on already has as many peers as it is allowed
+    InterfaceFull { iface: String, max: usize },
+    /// Only peers managed by our operator are accepted, theirs is None if they did not advertise
+    /// one or only sent bincode hellos
+    OperatorMismatch {
+        ours: Option<Address>,
+        theirs: Option<Address>,
+    },
+}
+
+impl fmt::Display for PolicyRejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyRejection::Denied(m) => write!(f, "Peer is denied by {m}"),
+            PolicyRejection::NotAllowed => write!(f, "Peer is not on the allowlist"),
+            PolicyRejection::InterfaceFull { iface, max } => {
+                write!(f, "Interface {iface} already has {max} peers")
+            }
+            PolicyRejection::OperatorMismatch { ours, theirs } => write!(
+                f,
+                "Peer operator {theirs:?} does not match our operator {ours:?}"
+            ),
+        }
+    }
+}
+
+/// A peer that was refused a tunnel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RejectedPeer {
+    pub identity: Identity,
+    /// The interface the peer contacted us on, if known
+    pub iface: Option<String>,
+    /// Why the peer was last rejected
+    pub reason: PolicyRejection,
+    /// How many times this peer has been rejected
+    pub count: u64,
+    pub last_seen: SystemTime,
+}
+
+/// Checks a peer against the policy, the denylist wins over the allowlist and an allowlisted peer
+/// must still satisfy the operator and interface limits. iface_peers is the number of other peers
+/// we already have on iface, a peer without a known interface is not subject to interface limits
+pub fn check_peer_policy(
+    policy: &PeerPolicy,
+    identity: &Identity,
+    capabilities: Option<&Capabilities>,
+    our_operator: Option<Address>,
+    iface: Option<&str>,
+    iface_peers: usize,
+) -> Result<(), PolicyRejection> {
+    if let Some(m) = policy.deny.iter().find(|m| m.matches(identity)) {
+        return Err(PolicyRejection::Denied(*m));
+    }
+    if !policy.allow.is_empty() && !policy.allow.iter().any(|m| m.matches(identity)) {
+        return Err(PolicyRejection::NotAllowed);
+    }
+    if policy.require_operator_match {
+        let theirs = capabilities.and_then(|c| c.operator_address);
+        if our_operator.is_none() || theirs != our_operator {
+            return Err(PolicyRejection::OperatorMismatch {
+                ours: our_operator,
+                theirs,
+            });
+        }
+    }
+    if let Some(iface) = iface {
+        if let Some(max) = policy.max_peers_per_interface.get(iface) {
+            if iface_peers >= *max {
+                return Err(PolicyRejection::InterfaceFull {
+                    iface: iface.to_string(),
+                    max: *max,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+impl TunnelManager {
+    /// The number of distinct peers with a tunnel over iface, not counting exclude
+    pub fn peers_on_interface(&self, iface: &str, exclude: &Identity) -> usize {
+        let mut peers = HashSet::new();
+        for (id, tunnels) in self.tunnels.iter() {
+            if id == exclude {
+                continue;
+            }
+            if tunnels
+                .iter()
+                .any(|t| t.peer_iface.as_deref() == Some(iface))
+            {
+                peers.insert(id);
+            }
+        }
+        peers.len()
+    }
+
+    /// Removes the tunnels of peers that the policy no longer accepts, gc would otherwise keep them
+    /// for as long as they handshake. Tunnels without a peer interface were opened to manual peers
+    /// or exits and are kept, interface limits only apply to new peers
+    pub fn remove_rejected_tunnels(&mut self, policy: &PeerPolicy, our_operator: Option<Address>) {
+        let to_delete = self.take_rejected_tunnels(policy, our_operator);
+        for tunnel in unmonitor_tunnels(to_delete) {
+            self.record_closed(tunnel);
+        }
+    }
+
+    /// Takes the tunnels the policy rejects out of the tunnel table and records their peers as
+    /// rejected, the caller must remove the returned tunnels
+    fn take_rejected_tunnels(
+        &mut self,
+        policy: &PeerPolicy,
+        our_operator: Option<Address>,
+    ) -> HashMap<Identity, Vec<Tunnel>> {
+        let mut to_delete: HashMap<Identity, Vec<Tunnel>> = HashMap::new();
+        let mut rejected = Vec::new();
+        for (identity, tunnels) in self.tunnels.iter_mut() {
+            tunnels.retain(|tunnel| {
+                if tunnel.peer_iface.is_none() {
+                    return true;
+                }
+                match check_peer_policy(
+                    policy,
+                    identity,
+                    tunnel.capabilities.as_ref(),
+                    our_operator,
+                    None,
+                    0,
+                ) {
+                    Ok(()) => true,
+                    Err(reason) => {
+                        info!("Removing tunnel {} rejected by policy: {}", tunnel, reason);
+                        let mut tunnel = tunnel.clone();
+                        tunnel.transition(TunnelState::Draining, TransitionReason::PolicyRejected);
+                        rejected.push((*identity, tunnel.peer_iface.clone(), reason));
+                        insert_into_tunnel_list(&tunnel, &mut to_delete);
+                        false
+                    }
+                }
+            });
+        }
+        self.tunnels.retain(|_, tunnels| !tunnels.is_empty());
+
+        for (identity, iface, reason) in rejected {
+            self.record_rejected(identity, iface, reason);
+        }
+        to_delete
+    }
+
+    /// Remembers a rejected peer, a peer that is rejected again is moved to the back with its
+    /// reason updated rather than being added twice
+    pub(super) fn record_rejected(
+        &mut self,
+        identity: Identity,
+        iface: Option<String>,
+        reason: PolicyRejection,
+    ) {
+        let mut count = 1;
+        if let Some(pos) = self
+            .rejected
+            .iter()
+            .position(|r| r.identity.wg_public_key == identity.wg_public_key)
+        {
+            if let Some(previous) = self.rejected.remove(pos) {
+                count += previous.count;
+            }
+        }
+        if self.rejected.len() >= REJECTED_PEERS_LEN {
+            self.rejected.pop_front();
+        }
+        self.rejected.push_back(RejectedPeer {
+            identity,
+            iface,
+            reason,
+            count,
+            last_seen: SystemTime::now(),
+        });
+    }
+
+    pub fn rejected_peers(&self) -> &VecDeque<RejectedPeer> {
+        &self.rejected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tunnel_manager::get_test_tunnel;
+    use crate::usage_tracker::tests::test::random_identity;
+
+    #[test]
+    fn test_check_peer_policy() {
+        let id = random_identity();
+        let other = random_identity();
+        let operator: Address = "0x0101010101010101010101010101010101010101"
+            .parse()
+            .unwrap();
+
+        // the default policy accepts everyone
+        let mut policy = PeerPolicy::default();
+        assert_eq!(
+            check_peer_policy(&policy, &id, None, None, Some("wlan0"), 100),
+            Ok(())
+        );
+
+        policy.allow = vec![PeerMatch::EthAddress(id.eth_address)];
+        assert_eq!(check_peer_policy(&policy, &id, None, None, None, 0), Ok(()));
+        assert_eq!(
+            check_peer_policy(&policy, &other, None, None, None, 0),
+            Err(PolicyRejection::NotAllowed)
+        );
+
+        // deny wins over allow
+        policy.deny = vec![PeerMatch::MeshIp(id.mesh_ip)];
+        assert_eq!(
+            check_peer_policy(&policy, &id, None, None, None, 0),
+            Err(PolicyRejection::Denied(PeerMatch::MeshIp(id.mesh_ip)))
+        );
+
+        let mut policy = PeerPolicy::default();
+        policy
+            .max_peers_per_interface
+            .insert("wlan0".to_string(), 2);
+        assert_eq!(
+            check_peer_policy(&policy, &id, None, None, Some("wlan0"), 1),
+            Ok(())
+        );
+        assert_eq!(
+            check_peer_policy(&policy, &id, None, None, Some("wlan0"), 2),
+            Err(PolicyRejection::InterfaceFull {
+                iface: "wlan0".to_string(),
+                max: 2
+            })
+        );
+        assert_eq!(
+            check_peer_policy(&policy, &id, None, None, Some("eth0"), 2),
+            Ok(())
+        );
+
+        let mut policy = PeerPolicy {
+            require_operator_match: true,
+            ..Default::default()
+        };
+        let theirs = Capabilities {
+            operator_address: Some(operator),
+            ..Default::default()
+        };
+        assert_eq!(
+            check_peer_policy(&policy, &id, Some(&theirs), Some(operator), None, 0),
+            Ok(())
+        );
+        // a peer that only sent a bincode hello can't prove its operator
+        assert_eq!(
+            check_peer_policy(&policy, &id, None, Some(operator), None, 0),
+            Err(PolicyRejection::OperatorMismatch {
+                ours: Some(operator),
+                theirs: None
+            })
+        );
+        // nor can anyone match when we have no operator
+        assert_eq!(
+            check_peer_policy(&policy, &id, Some(&theirs), None, None, 0),
+            Err(PolicyRejection::OperatorMismatch {
+                ours: None,
+                theirs: Some(operator)
+            })
+        );
+        policy.require_operator_match = false;
+        assert_eq!(
+            check_peer_policy(&policy, &id, None, Some(operator), None, 0),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_peers_on_interface() {
+        let mut tm = TunnelManager::new();
+        let mut a = get_test_tunnel("0.0.0.0".parse().unwrap());
+        a.peer_iface = Some("wlan0".to_string());
+        let mut b = get_test_tunnel("0.0.0.1".parse().unwrap());
+        b.neigh_id.global = random_identity();
+        b.peer_iface = Some("wlan0".to_string());
+        let mut c = get_test_tunnel("0.0.0.2".parse().unwrap());
+        c.neigh_id.global = random_identity();
+        c.peer_iface = Some("eth0".to_string());
+        let a_id = a.neigh_id.global;
+        for t in [a, b, c] {
+            tm.tunnels.entry(t.neigh_id.global).or_default().push(t);
+        }
+
+        assert_eq!(tm.peers_on_interface("wlan0", &random_identity()), 2);
+        assert_eq!(tm.peers_on_interface("wlan0", &a_id), 1);
+        assert_eq!(tm.peers_on_interface("eth1", &a_id), 0);
+    }
+
+    #[test]
+    fn test_rejected_peers_are_bounded() {
+        let mut tm = TunnelManager::new();
+        let id = random_identity();
+        tm.record_rejected(id, None, PolicyRejection::NotAllowed);
+        tm.record_rejected(
+            id,
+            Some("wlan0".to_string()),
+            PolicyRejection::Denied(PeerMatch::WgKey(id.wg_public_key)),
+        );
+        assert_eq!(tm.rejected_peers().len(), 1);
+        assert_eq!(tm.rejected_peers()[0].count, 2);
+        assert_eq!(tm.rejected_peers()[0].iface, Some("wlan0".to_string()));
+
+        for _ in 0..REJECTED_PEERS_LEN {
+            tm.record_rejected(random_identity(), None, PolicyRejection::NotAllowed);
+        }
+        assert_eq!(tm.rejected_peers().len(), REJECTED_PEERS_LEN);
+        assert!(tm
+            .rejected_peers()
+            .iter()
+            .all(|r| r.identity.wg_public_key != id.wg_public_key));
+    }
+
+    #[test]
+    fn test_take_rejected_tunnels() {
+        let mut tm = TunnelManager::new();
+        let mut denied = get_test_tunnel("0.0.0.0".parse().unwrap());
+        denied.peer_iface = Some("wlan0".to_string());
+        let mut allowed = get_test_tunnel("0.0.0.1".parse().unwrap());
+        allowed.neigh_id.global = random_identity();
+        allowed.peer_iface = Some("wlan0".to_string());
+        // a manual peer or exit, never subject to the policy
+        let mut manual = get_test_tunnel("0.0.0.2".parse().unwrap());
+        manual.neigh_id.global = denied.neigh_id.global;
+        let denied_id = denied.neigh_id.global;
+        for t in [denied, allowed, manual] {
+            tm.tunnels.entry(t.neigh_id.global).or_default().push(t);
+        }
+
+        // interface limits don't apply to peers that already have a tunnel
+        let mut policy = PeerPolicy::default();
+        policy
+            .max_peers_per_interface
+            .insert("wlan0".to_string(), 1);
+        assert!(tm.take_rejected_tunnels(&policy, None).is_empty());
+
+        policy.deny = vec![PeerMatch::WgKey(denied_id.wg_public_key)];
+        let removed = tm.take_rejected_tunnels(&policy, None);
+        assert_eq!(removed[&denied_id].len(), 1);
+        assert_eq!(removed[&denied_id][0].state(), TunnelState::Draining);
+        assert_eq!(tm.tunnels[&denied_id].len(), 1);
+        assert!(tm.tunnels[&denied_id][0].peer_iface.is_none());
+        assert_eq!(tm.tunnels.len(), 2);
+        assert_eq!(tm.rejected_peers().len(), 1);
+        assert_eq!(
+            tm.rejected_peers()[0].reason,
+            PolicyRejection::Denied(PeerMatch::WgKey(denied_id.wg_public_key))
+        );
+    }
+}
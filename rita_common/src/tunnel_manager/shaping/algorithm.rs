@@ -0,0 +1,166 @@
+//! The interface between the shaper and the algorithms that pick tunnel speeds. The shaper hands
+//! an algorithm everything known about a tunnel's link each time it runs and applies whatever
+//! limit comes back, so algorithms can be swapped through ShaperSettings without touching how
+//! limits are applied or reset.
+
+use super::delay_gradient::DelayGradient;
+use super::ShapingAdjustAction;
+use althea_types::ShaperAlgorithmKind;
+
+/// The speeds in mbps an algorithm must stay between once it limits a tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShapingBounds {
+    /// The speed to start at when a tunnel is first limited and the most it will be raised to
+    pub max: usize,
+    pub min: usize,
+}
+
+/// What is known about a tunnel's link when the shaper runs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkObservation<'a> {
+    /// Babel's rtt to the neighbor in ms, one sample per fast loop tick, oldest first
+    pub rtts: &'a [f32],
+    /// The lowest rtt ever seen on this link, the delay of the link with its queues empty
+    pub base_rtt: Option<f32>,
+    /// The network monitor's bloat verdict, if it has one for this tunnel
+    pub action: Option<ShapingAdjustAction>,
+    /// What the link prober thinks the tunnel can carry in mbps
+    pub bandwidth_estimate: Option<usize>,
+}
+
+pub trait ShaperAlgorithm {
+    fn name(&self) -> &'static str;
+
+    /// The speed limit the tunnel should have given its current one, None is unlimited.
+    /// Returning current leaves the tunnel as is
+    fn next_limit(
+        &self,
+        current: Option<usize>,
+        observation: &LinkObservation,
+        bounds: ShapingBounds,
+    ) -> Option<usize>;
+}
+
+pub fn get_algorithm(kind: ShaperAlgorithmKind) -> Box<dyn ShaperAlgorithm> {
+    match kind {
+        ShaperAlgorithmKind::Step => Box::new(Step),
+        ShaperAlgorithmKind::DelayGradient => Box::new(DelayGradient),
+    }
+}
+
+/// The limit an unshaped tunnel starts at, a bandwidth estimate only ever lowers it and never
+/// below the minimum
+pub fn starting_limit(bounds: ShapingBounds, estimate: Option<usize>) -> usize {
+    match estimate {
+        Some(estimate) => estimate.clamp(bounds.min, bounds.max.max(bounds.min)),
+        None => bounds.max,
+    }
+}
+
+/// The original shaper, it follows the network monitor's verdicts and cuts the speed by 20% each
+/// time the link looks bloated then raises it 5% at a time once it looks good
+#[derive(Debug, Clone, Copy)]
+pub struct Step;
+
+impl ShaperAlgorithm for Step {
+    fn name(&self) -> &'static str {
+        "step"
+    }
+
+    fn next_limit(
+        &self,
+        current: Option<usize>,
+        observation: &LinkObservation,
+        bounds: ShapingBounds,
+    ) -> Option<usize> {
+        match (current, observation.action) {
+            (current, None) => current,
+            // nothing to do in this case
+            (None, Some(ShapingAdjustAction::IncreaseSpeed)) => None,
+            // start at the starting limit, or what the link prober measured if that is lower
+            (None, Some(ShapingAdjustAction::ReduceSpeed)) => {
+                Some(starting_limit(bounds, observation.bandwidth_estimate))
+            }
+            // after that cut the value by 20% each time
+            (Some(val), Some(ShapingAdjustAction::ReduceSpeed)) => {
+                let new_val = (val as f32 * 0.8f32) as usize;
+                if new_val < bounds.min {
+                    Some(val)
+                } else {
+                    Some(new_val)
+                }
+            }
+            // increase the value by 5% until we reach the starting value
+            (Some(val), Some(ShapingAdjustAction::IncreaseSpeed)) => {
+                let new_val = increase_speed(val);
+                if new_val < bounds.max {
+                    Some(new_val)
+                } else {
+                    Some(val)
+                }
+            }
+        }
+    }
+}
+
+/// increase the speed by 5% or 1mbps if the value is too small
+/// for a 5% increase
+fn increase_speed(input: usize) -> usize {
+    let new = (input as f32 * 1.05f32) as usize;
+    if new == input {
+        input + 1
+    } else {
+        new
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDS: ShapingBounds = ShapingBounds {
+        max: 10000,
+        min: 50,
+    };
+
+    fn observe(action: Option<ShapingAdjustAction>) -> LinkObservation<'static> {
+        LinkObservation {
+            rtts: &[],
+            base_rtt: None,
+            action,
+            bandwidth_estimate: None,
+        }
+    }
+
+    #[test]
+    fn test_starting_limit() {
+        assert_eq!(starting_limit(BOUNDS, None), 10000);
+        assert_eq!(starting_limit(BOUNDS, Some(80)), 80);
+        // an estimate never raises the limit or takes it below the minimum
+        assert_eq!(starting_limit(BOUNDS, Some(20000)), 10000);
+        assert_eq!(starting_limit(BOUNDS, Some(3)), 50);
+        let inverted = ShapingBounds { max: 40, min: 50 };
+        assert_eq!(starting_limit(inverted, Some(3)), 50);
+    }
+
+    #[test]
+    fn test_step() {
+        let reduce = observe(Some(ShapingAdjustAction::ReduceSpeed));
+        let increase = observe(Some(ShapingAdjustAction::IncreaseSpeed));
+        assert_eq!(Step.next_limit(None, &observe(None), BOUNDS), None);
+        assert_eq!(
+            Step.next_limit(Some(100), &observe(None), BOUNDS),
+            Some(100)
+        );
+        assert_eq!(Step.next_limit(None, &increase, BOUNDS), None);
+        assert_eq!(Step.next_limit(None, &reduce, BOUNDS), Some(10000));
+        assert_eq!(Step.next_limit(Some(100), &reduce, BOUNDS), Some(80));
+        // can't go below the minimum
+        assert_eq!(Step.next_limit(Some(60), &reduce, BOUNDS), Some(60));
+        // 5% is computed in f32 and truncated
+        assert_eq!(Step.next_limit(Some(100), &increase, BOUNDS), Some(104));
+        assert_eq!(Step.next_limit(Some(10), &increase, BOUNDS), Some(11));
+        // nor above the maximum
+        assert_eq!(Step.next_limit(Some(9999), &increase, BOUNDS), Some(9999));
+    }
+}
@@ -0,0 +1,245 @@
+//! A delay based shaper in the spirit of TCP Vegas and BBR. Rather than waiting for the network
+//! monitor to declare a link bloated it looks at the recent rtts directly, the queueing delay is
+//! how far they sit above the link's base rtt and the gradient is whether that queue is growing.
+//! A growing or large queue means we are sending faster than the link can carry so the limit is
+//! cut in proportion to the queue, an empty queue means there is room so the limit is raised, in
+//! between the limit is held. Over time this settles just under the link capacity.
+//!
+//! The network monitor and link prober still get a say, a ReduceSpeed verdict from either is
+//! treated as congestion even if the recent rtts don't show it, or when there are too few of them
+//! to decide anything.
+
+use super::algorithm::{starting_limit, LinkObservation, ShaperAlgorithm, ShapingBounds};
+use super::ShapingAdjustAction;
+
+/// Samples needed before anything is decided
+pub const MIN_SAMPLES: usize = 6;
+
+/// Queueing delay below this in ms, or a quarter of the base rtt if that is more, is an empty
+/// queue
+const QUEUE_LOW_MS: f32 = 5.0;
+
+/// Queueing delay above this in ms, or twice the base rtt if that is more, is congestion no
+/// matter which way it is trending
+const QUEUE_HIGH_MS: f32 = 50.0;
+
+/// An rtt rising faster than this in ms per sample is a queue building
+const GRADIENT_THRESHOLD: f32 = 1.0;
+
+/// A single decrease never cuts more than half the limit
+const MAX_DECREASE: f32 = 0.5;
+
+/// A single decrease always cuts at least 5% of the limit
+const MIN_DECREASE: f32 = 0.95;
+
+/// How much a verdict cuts the limit when there are too few samples to size the cut from the
+/// queue, the same cut the step shaper makes
+const VERDICT_DECREASE: f32 = 0.8;
+
+/// How much the limit is raised while the queue is empty
+const INCREASE: f32 = 0.1;
+
+/// The least squares slope of samples taken at a fixed interval, in units per sample
+pub fn gradient(samples: &[f32]) -> f32 {
+    let n = samples.len() as f32;
+    if samples.len() < 2 {
+        return 0.0;
+    }
+    let mean_x = (n - 1.0) / 2.0;
+    let mean_y = samples.iter().sum::<f32>() / n;
+    let mut num = 0.0;
+    let mut den = 0.0;
+    for (x, y) in samples.iter().enumerate() {
+        let dx = x as f32 - mean_x;
+        num += dx * (y - mean_y);
+        den += dx * dx;
+    }
+    num / den
+}
+
+fn median(samples: &[f32]) -> f32 {
+    let mut sorted = samples.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    sorted[sorted.len() / 2]
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DelayGradient;
+
+impl ShaperAlgorithm for DelayGradient {
+    fn name(&self) -> &'static str {
+        "delay gradient"
+    }
+
+    fn next_limit(
+        &self,
+        current: Option<usize>,
+        observation: &LinkObservation,
+        bounds: ShapingBounds,
+    ) -> Option<usize> {
+        let rtts = observation.rtts;
+        let verdict = observation.action == Some(ShapingAdjustAction::ReduceSpeed);
+        if rtts.len() < MIN_SAMPLES {
+            return match current {
+                _ if !verdict => current,
+                None => Some(starting_limit(bounds, observation.bandwidth_estimate)),
+                Some(limit) => Some(((limit as f32 * VERDICT_DECREASE) as usize).max(bounds.min)),
+            };
+        }
+        let lowest = rtts.iter().copied().fold(f32::INFINITY, f32::min);
+        let base = match observation.base_rtt {
+            Some(base) => base.min(lowest),
+            None => lowest,
+        }
+        .max(0.1);
+        let queue = (median(rtts) - base).max(0.0);
+        let trend = gradient(rtts);
+
+        let congested = verdict
+            || queue > QUEUE_HIGH_MS.max(base * 2.0)
+            || (trend > GRADIENT_THRESHOLD && queue > QUEUE_LOW_MS.max(base / 4.0));
+        let empty = queue < QUEUE_LOW_MS.max(base / 4.0) && trend < GRADIENT_THRESHOLD / 4.0;
+
+        match current {
+            None if congested => Some(starting_limit(bounds, observation.bandwidth_estimate)),
+            None => None,
+            Some(limit) if congested => {
+                // the fraction of the rtt that is the link rather than the queue is roughly the
+                // share of our current rate the link is actually carrying
+                let factor = (base / (base + queue)).clamp(MAX_DECREASE, MIN_DECREASE);
+                Some(((limit as f32 * factor) as usize).max(bounds.min))
+            }
+            Some(limit) if empty => {
+                let step = ((limit as f32 * INCREASE) as usize).max(1);
+                Some((limit + step).min(bounds.max))
+            }
+            Some(limit) => Some(limit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOUNDS: ShapingBounds = ShapingBounds { max: 1000, min: 10 };
+
+    fn observe(rtts: &[f32]) -> LinkObservation<'_> {
+        LinkObservation {
+            rtts,
+            base_rtt: Some(10.0),
+            action: None,
+            bandwidth_estimate: None,
+        }
+    }
+
+    #[test]
+    fn test_gradient() {
+        assert_eq!(gradient(&[]), 0.0);
+        assert_eq!(gradient(&[5.0, 5.0, 5.0]), 0.0);
+        assert!((gradient(&[1.0, 3.0, 5.0, 7.0]) - 2.0).abs() < 0.001);
+        assert!(gradient(&[9.0, 7.0, 8.0, 4.0]) < 0.0);
+    }
+
+    #[test]
+    fn test_delay_gradient() {
+        let quiet = [10.0, 11.0, 10.5, 10.0, 11.0, 10.2];
+        let building = [12.0, 14.0, 17.0, 19.0, 22.0, 25.0];
+        let flooded = [90.0, 85.0, 95.0, 88.0, 92.0, 90.0];
+
+        // not enough samples
+        assert_eq!(
+            DelayGradient.next_limit(Some(100), &observe(&building[..3]), BOUNDS),
+            Some(100)
+        );
+
+        // an unlimited tunnel stays that way until it is congested
+        assert_eq!(
+            DelayGradient.next_limit(None, &observe(&quiet), BOUNDS),
+            None
+        );
+        assert_eq!(
+            DelayGradient.next_limit(None, &observe(&building), BOUNDS),
+            Some(1000)
+        );
+        let estimated = LinkObservation {
+            bandwidth_estimate: Some(200),
+            ..observe(&building)
+        };
+        assert_eq!(
+            DelayGradient.next_limit(None, &estimated, BOUNDS),
+            Some(200)
+        );
+
+        // a growing queue cuts in proportion, base 10 and a queue of ~9 is roughly half
+        let cut = DelayGradient
+            .next_limit(Some(100), &observe(&building), BOUNDS)
+            .unwrap();
+        assert!((50..60).contains(&cut), "{}", cut);
+        // a huge queue never cuts more than half at once
+        assert_eq!(
+            DelayGradient.next_limit(Some(100), &observe(&flooded), BOUNDS),
+            Some(50)
+        );
+        assert_eq!(
+            DelayGradient.next_limit(Some(12), &observe(&flooded), BOUNDS),
+            Some(10)
+        );
+
+        // an empty queue makes room
+        assert_eq!(
+            DelayGradient.next_limit(Some(100), &observe(&quiet), BOUNDS),
+            Some(110)
+        );
+        assert_eq!(
+            DelayGradient.next_limit(Some(990), &observe(&quiet), BOUNDS),
+            Some(1000)
+        );
+
+        // a steady queue that isn't growing is left alone
+        let steady = [20.0, 21.0, 20.0, 21.0, 20.0, 21.0];
+        assert_eq!(
+            DelayGradient.next_limit(Some(100), &observe(&steady), BOUNDS),
+            Some(100)
+        );
+    }
+
+    #[test]
+    fn test_delay_gradient_verdict() {
+        let quiet = [10.0, 11.0, 10.5, 10.0, 11.0, 10.2];
+        let reduce = |rtts| LinkObservation {
+            action: Some(ShapingAdjustAction::ReduceSpeed),
+            ..observe(rtts)
+        };
+
+        // a bloat verdict is congestion even when the rtts look fine
+        assert_eq!(
+            DelayGradient.next_limit(None, &reduce(&quiet), BOUNDS),
+            Some(1000)
+        );
+        assert_eq!(
+            DelayGradient.next_limit(Some(100), &reduce(&quiet), BOUNDS),
+            Some(95)
+        );
+        // or when there are too few samples to go on
+        assert_eq!(
+            DelayGradient.next_limit(Some(100), &reduce(&quiet[..2]), BOUNDS),
+            Some(80)
+        );
+        assert_eq!(
+            DelayGradient.next_limit(None, &reduce(&[]), BOUNDS),
+            Some(1000)
+        );
+        assert_eq!(
+            DelayGradient.next_limit(Some(11), &reduce(&[]), BOUNDS),
+            Some(10)
+        );
+        // an increase verdict doesn't override an rtt that says otherwise
+        let building = [12.0, 14.0, 17.0, 19.0, 22.0, 25.0];
+        let increase = LinkObservation {
+            action: Some(ShapingAdjustAction::IncreaseSpeed),
+            ..observe(&building)
+        };
+        assert!(DelayGradient.next_limit(Some(100), &increase, BOUNDS) < Some(100));
+    }
+}
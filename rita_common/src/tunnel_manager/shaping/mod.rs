@@ -0,0 +1,155 @@
+pub mod algorithm;
+pub mod delay_gradient;
+pub mod simulation;
+
+use super::get_tunnel_manager_write_ref;
+use super::TunnelManager;
+use super::TUNNEL_MANAGER;
+use crate::network_monitor::get_latency_history;
+use crate::rita_loop::fast_loop::FAST_LOOP_SPEED;
+use crate::rita_loop::slow_loop::SLOW_LOOP_SPEED;
+use crate::KI;
+use algorithm::{get_algorithm, LinkObservation, ShapingBounds};
+use althea_types::RunningLatencyStats;
+use std::collections::HashMap;
+
+/// Fast loop ticks between runs of the shaper, network monitor samples every tunnel once per fast
+/// loop tick and the shaper runs once per slow loop tick
+pub const SHAPER_INTERVAL: usize = (SLOW_LOOP_SPEED.as_secs() / FAST_LOOP_SPEED.as_secs()) as usize;
+
+/// contains the state for the shaper
+#[derive(Debug, Default, Clone)]
+pub struct Shaper {
+    reset_flag: bool,
+    to_shape: Vec<ShapingAdjust>,
+}
+
+pub fn flag_reset_shaper() {
+    let tm_pin = &mut *TUNNEL_MANAGER.write().unwrap();
+    let tunnel_manager = get_tunnel_manager_write_ref(tm_pin);
+    tunnel_manager.shaper.reset_flag = true;
+}
+
+/// Queues adjustments for the next run of the shaper, network monitor runs several times between
+/// runs so a later adjustment to an interface replaces an earlier one
+pub fn set_to_shape(input: Vec<ShapingAdjust>) {
+    let tm_pin = &mut *TUNNEL_MANAGER.write().unwrap();
+    let tunnel_manager = get_tunnel_manager_write_ref(tm_pin);
+    for adjust in input {
+        let to_shape = &mut tunnel_manager.shaper.to_shape;
+        match to_shape.iter_mut().find(|s| s.iface == adjust.iface) {
+            Some(existing) => *existing = adjust,
+            None => to_shape.push(adjust),
+        }
+    }
+}
+
+pub struct ShapeMany {
+    pub to_shape: Vec<ShapingAdjust>,
+}
+
+/// Message sent by network monitor when it determines that an iface is bloated
+#[derive(Debug, Clone)]
+pub struct ShapingAdjust {
+    pub iface: String,
+    pub action: ShapingAdjustAction,
+    /// What the link prober thinks the tunnel can carry in mbps, an unshaped tunnel starts here
+    /// rather than at the configured max speed when it is known
+    pub bandwidth_estimate: Option<usize>,
+}
+
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum ShapingAdjustAction {
+    IncreaseSpeed,
+    ReduceSpeed,
+}
+
+pub fn handle_shaping() {
+    // network monitor takes its own lock before ours, so its history is fetched first
+    let latency_history = get_latency_history();
+    let tm_pin = &mut *TUNNEL_MANAGER.write().unwrap();
+    let tunnel_manager = get_tunnel_manager_write_ref(tm_pin);
+    tunnel_manager.handle_shaping(&latency_history)
+}
+
+impl TunnelManager {
+    /// Updates the traffic shaper based on signals from network monitor and the latency history
+    /// of each tunnel, the configured algorithm picks the speeds
+    pub fn handle_shaping(&mut self, latency_history: &HashMap<String, RunningLatencyStats>) {
+        let network_settings = settings::get_rita_common().network;
+        let bounds = ShapingBounds {
+            max: network_settings.shaper_settings.max_speed,
+            min: network_settings.shaper_settings.min_speed,
+        };
+        let bandwidth_limit_enabled = network_settings.shaper_settings.enabled;
+        let to_shape = std::mem::take(&mut self.shaper.to_shape);
+
+        // removes shaping without requiring a restart if the flag is set or
+        // if it's set in the settings
+        if !bandwidth_limit_enabled || self.shaper.reset_flag {
+            for (_id, tunnel_list) in self.tunnels.iter_mut() {
+                for tunnel in tunnel_list {
+                    if tunnel.speed_limit.is_some() {
+                        set_shaping_or_error(&tunnel.iface_name, None);
+                        tunnel.speed_limit = None;
+                    }
+                }
+            }
+            self.shaper.reset_flag = false;
+            return;
+        }
+
+        let algorithm = get_algorithm(network_settings.shaper_settings.algorithm);
+        for (id, tunnel_list) in self.tunnels.iter_mut() {
+            for tunnel in tunnel_list {
+                let iface = &tunnel.iface_name;
+                let command = to_shape.iter().find(|s| &s.iface == iface);
+                let stats = latency_history.get(iface);
+                let rtts = match stats {
+                    Some(stats) => stats.recent(SHAPER_INTERVAL),
+                    None => Vec::new(),
+                };
+                let observation = LinkObservation {
+                    rtts: &rtts,
+                    base_rtt: stats.and_then(|s| s.get_lowest()),
+                    action: command.map(|c| c.action),
+                    bandwidth_estimate: command.and_then(|c| c.bandwidth_estimate),
+                };
+                let new_limit = algorithm.next_limit(tunnel.speed_limit, &observation, bounds);
+
+                if new_limit == tunnel.speed_limit {
+                    match (tunnel.speed_limit, observation.action) {
+                        (Some(val), Some(ShapingAdjustAction::ReduceSpeed)) => {
+                            error!("Interface {} for peer {} is showing bloat but we can't reduce it's bandwidth any further. Current value {}", iface, id.wg_public_key, val);
+                        }
+                        (Some(_), Some(ShapingAdjustAction::IncreaseSpeed)) => {
+                            info!(
+                                "Can not increase on Interface {} for peer {}",
+                                iface, id.wg_public_key
+                            );
+                        }
+                        _ => {}
+                    }
+                } else {
+                    info!(
+                        "Interface {} for peer {} {} shaper changing speed from {:?} to {:?}",
+                        iface,
+                        id.wg_public_key,
+                        algorithm.name(),
+                        tunnel.speed_limit,
+                        new_limit
+                    );
+                    set_shaping_or_error(iface, new_limit);
+                    tunnel.speed_limit = new_limit;
+                }
+            }
+        }
+    }
+}
+
+/// tiny little helper function for GotBloat() limit is in mbps
+fn set_shaping_or_error(iface: &str, limit: Option<usize>) {
+    if let Err(e) = KI.set_codel_shaping(iface, limit) {
+        error!("Failed to shape tunnel for bloat! {}", e);
+    }
+}
@@ -0,0 +1,222 @@
+//! Replays recorded rtt traces through the shaper algorithms so they can be compared offline.
+//! A trace is the babel rtt to a single neighbor sampled once per fast loop tick, the same thing
+//! network monitor sees. The network monitor's bloat verdicts and the shaper's slow loop cadence
+//! are reproduced here so an algorithm sees what it would on a router.
+//!
+//! A trace was recorded at whatever speed the link was shaped to at the time so on its own it
+//! can't say what a different limit would have done. A LinkModel fills that in, given the
+//! capacity of the link it removes the queueing delay a limit below that capacity would have
+//! prevented. Without a capacity the trace is replayed as is and only the decisions are useful.
+//!
+//! The shaper_trace example records traces from babel on a router and replays them through every
+//! algorithm from the command line.
+
+use super::algorithm::{get_algorithm, LinkObservation, ShaperAlgorithm, ShapingBounds};
+use super::{ShapingAdjustAction, SHAPER_INTERVAL};
+use crate::network_monitor::BACK_OFF_TIME;
+use crate::rita_loop::fast_loop::FAST_LOOP_SPEED;
+use althea_types::{RunningLatencyStats, ShaperAlgorithmKind};
+use std::num::ParseFloatError;
+
+/// Fast loop ticks network monitor waits after a change before raising the speed
+pub const BACK_OFF_SAMPLES: usize = (BACK_OFF_TIME.as_secs() / FAST_LOOP_SPEED.as_secs()) as usize;
+
+/// Parses a trace, one rtt in ms per line. Blank lines and lines starting with # are ignored
+pub fn parse_trace(text: &str) -> Result<Vec<f32>, ParseFloatError> {
+    text.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.parse())
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LinkModel {
+    /// What the link can carry in mbps, None replays the trace without modeling the limit
+    pub capacity: Option<f32>,
+}
+
+impl LinkModel {
+    /// The rtt we would have seen had the tunnel been limited to limit when recorded was seen.
+    /// Queueing delay builds up sharply as the offered load nears capacity so the delay above
+    /// base falls off steeply as the limit drops below it
+    pub fn rtt(&self, recorded: f32, base: f32, limit: Option<usize>) -> f32 {
+        match (self.capacity, limit) {
+            (Some(capacity), Some(limit)) if (limit as f32) < capacity => {
+                let utilization = limit as f32 / capacity;
+                base + (recorded - base).max(0.0) * utilization.powi(4)
+            }
+            _ => recorded,
+        }
+    }
+
+    /// The throughput a saturating flow would get with the tunnel limited to limit
+    pub fn throughput(&self, limit: Option<usize>) -> Option<f32> {
+        let capacity = self.capacity?;
+        Some(match limit {
+            Some(limit) => capacity.min(limit as f32),
+            None => capacity,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulationResult {
+    pub algorithm: &'static str,
+    /// The limit in effect at every sample of the trace
+    pub limits: Vec<Option<usize>>,
+    pub mean_rtt: f32,
+    pub p95_rtt: f32,
+    /// Only known when the link model has a capacity
+    pub mean_throughput: Option<f32>,
+    /// How many times the limit was changed
+    pub changes: usize,
+}
+
+fn percentile(samples: &[f32], p: f32) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let mut sorted = samples.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let idx = ((sorted.len() - 1) as f32 * p).round() as usize;
+    sorted[idx]
+}
+
+/// Replays a trace through a single algorithm
+pub fn simulate(
+    trace: &[f32],
+    model: LinkModel,
+    algorithm: &dyn ShaperAlgorithm,
+    bounds: ShapingBounds,
+) -> SimulationResult {
+    let base = trace.iter().copied().fold(f32::INFINITY, f32::min);
+    let mut stats = RunningLatencyStats::new();
+    let mut limit = None;
+    let mut pending = None;
+    let mut since_change = 0;
+    let mut limits = Vec::with_capacity(trace.len());
+    let mut rtts = Vec::with_capacity(trace.len());
+    let mut throughput = 0.0;
+    let mut changes = 0;
+
+    for (tick, recorded) in trace.iter().enumerate() {
+        let rtt = model.rtt(*recorded, base, limit);
+        limits.push(limit);
+        rtts.push(rtt);
+        throughput += model.throughput(limit).unwrap_or_default();
+
+        // the verdicts network monitor would reach, checked before the sample is added
+        since_change += 1;
+        if stats.get_avg().is_some() {
+            if stats.is_bloated() {
+                pending = Some(ShapingAdjustAction::ReduceSpeed);
+                stats.reset();
+                since_change = 0;
+            } else if since_change > BACK_OFF_SAMPLES && stats.is_good() {
+                pending = Some(ShapingAdjustAction::IncreaseSpeed);
+                since_change = 0;
+            }
+        }
+        stats.add_sample(rtt);
+
+        if (tick + 1) % SHAPER_INTERVAL == 0 {
+            let recent = stats.recent(SHAPER_INTERVAL);
+            let observation = LinkObservation {
+                rtts: &recent,
+                base_rtt: stats.get_lowest(),
+                action: pending.take(),
+                bandwidth_estimate: None,
+            };
+            let next = algorithm.next_limit(limit, &observation, bounds);
+            if next != limit {
+                limit = next;
+                changes += 1;
+            }
+        }
+    }
+
+    SimulationResult {
+        algorithm: algorithm.name(),
+        limits,
+        mean_rtt: rtts.iter().sum::<f32>() / rtts.len().max(1) as f32,
+        p95_rtt: percentile(&rtts, 0.95),
+        mean_throughput: model
+            .capacity
+            .map(|_| throughput / trace.len().max(1) as f32),
+        changes,
+    }
+}
+
+/// Replays a trace through every algorithm
+pub fn compare(trace: &[f32], model: LinkModel, bounds: ShapingBounds) -> Vec<SimulationResult> {
+    [
+        ShaperAlgorithmKind::Step,
+        ShaperAlgorithmKind::DelayGradient,
+    ]
+    .iter()
+    .map(|kind| simulate(trace, model, get_algorithm(*kind).as_ref(), bounds))
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONGESTED_WIRELESS: &str = include_str!("traces/congested_wireless.txt");
+
+    const BOUNDS: ShapingBounds = ShapingBounds { max: 400, min: 20 };
+
+    #[test]
+    fn test_parse_trace() {
+        assert_eq!(
+            parse_trace("# a comment\n8.5\n\n  9\n").unwrap(),
+            vec![8.5, 9.0]
+        );
+        assert!(parse_trace("8.5\nfast\n").is_err());
+        let trace = parse_trace(CONGESTED_WIRELESS).unwrap();
+        assert_eq!(trace.len(), 240);
+    }
+
+    #[test]
+    fn test_link_model() {
+        let model = LinkModel {
+            capacity: Some(100.0),
+        };
+        assert_eq!(model.rtt(50.0, 10.0, None), 50.0);
+        assert_eq!(model.rtt(50.0, 10.0, Some(200)), 50.0);
+        assert_eq!(model.rtt(50.0, 10.0, Some(50)), 12.5);
+        assert_eq!(model.throughput(Some(50)), Some(50.0));
+        assert_eq!(model.throughput(None), Some(100.0));
+        assert_eq!(LinkModel::default().rtt(50.0, 10.0, Some(50)), 50.0);
+        assert_eq!(LinkModel::default().throughput(Some(50)), None);
+    }
+
+    #[test]
+    fn test_compare_congested_wireless() {
+        let trace = parse_trace(CONGESTED_WIRELESS).unwrap();
+        let recorded_p95 = percentile(&trace, 0.95);
+        let model = LinkModel {
+            capacity: Some(100.0),
+        };
+        let results = compare(&trace, model, BOUNDS);
+        assert_eq!(results.len(), 2);
+        let step = &results[0];
+        let delay = &results[1];
+        assert_eq!(step.algorithm, "step");
+        assert_eq!(delay.algorithm, "delay gradient");
+        for result in &results {
+            assert_eq!(result.limits.len(), trace.len());
+            assert!(result.limits.iter().flatten().all(|l| *l >= BOUNDS.min));
+        }
+        // step cuts 20% at a time from the max speed and never gets under the capacity of the
+        // link so nothing is gained over the recorded trace
+        assert_eq!(step.p95_rtt, recorded_p95);
+        // the delay gradient shaper cuts in proportion to the queue and reacts to it building
+        // rather than waiting for the link to be declared bloated
+        assert!(delay.p95_rtt < step.p95_rtt);
+        assert!(delay.mean_rtt < step.mean_rtt / 1.5);
+        // without giving away most of the link to do it
+        assert!(delay.mean_throughput.unwrap() > 50.0);
+    }
+}
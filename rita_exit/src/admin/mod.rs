@@ -0,0 +1,407 @@
+//! The exit admin api, lets the operator look after registered clients from the exit dashboard
+//! rather than editing the database by hand. Everything here is served under /admin and needs the
+//! dashboard password, see start_rita_exit_dashboard. Clients are addressed by mesh ip since wg
+//! keys can contain a '/', which doesn't fit in a path, so bans take the key in the request body.
+
+use crate::database::client_store::{ClientSelector, ClientStore};
+use crate::database::database_tools::{
+    ban_client, delete_client, reassign_client_ipv6, set_client_internal_ip, verify_db_client,
+};
+use crate::database::struct_tools::to_exit_client;
+use crate::get_client_store;
+use crate::rita_loop::get_client_usage;
+use crate::RitaExitError;
+use actix_web_async::http::StatusCode;
+use actix_web_async::web::{self, Json, Path, Query, ServiceConfig};
+use actix_web_async::HttpResponse;
+use althea_kernel_interface::wg_iface_counter::WgUsage;
+use althea_types::WgKey;
+use exit_db::models::Client;
+use rita_common::debt_keeper::{get_debts_list, NodeDebtData};
+use rita_common::middleware;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Filters for the client list, both are optional
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ClientQuery {
+    /// Case insensitive text to look for in the client's ips, keys, nickname, email or phone
+    pub search: Option<String>,
+    pub verified: Option<bool>,
+}
+
+/// Everything the exit knows about a client, the debt and usage are only there while the
+/// client is online and being billed
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientDetails {
+    pub client: Client,
+    pub debt: Option<NodeDebtData>,
+    /// The client's tunnel counters as of the last time it was billed
+    pub usage: Option<WgUsage>,
+}
+
+/// The internal ip to move a client to, the next free ip if not given
+#[derive(Debug, Clone, Deserialize)]
+pub struct InternalIpRequest {
+    pub ip: Option<Ipv4Addr>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BanRequest {
+    pub wg_pubkey: WgKey,
+    #[serde(default)]
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct UnbanRequest {
+    pub wg_pubkey: WgKey,
+}
+
+/// Adds the /admin routes to the dashboard. The auth middleware lets everything through when
+/// there is no dashboard password, so the routes are left out entirely unless one is set
+pub fn configure_admin(cfg: &mut ServiceConfig) {
+    if settings::get_rita_exit()
+        .network
+        .rita_dashboard_password
+        .is_none()
+    {
+        warn!("No dashboard password is set, the admin api is disabled");
+        return;
+    }
+    cfg.service(
+        web::scope("/admin")
+            .wrap(middleware::AuthMiddlewareFactory)
+            .route("/clients", web::get().to(get_clients))
+            .route("/clients/{mesh_ip}", web::get().to(get_client_details))
+            .route("/clients/{mesh_ip}", web::delete().to(remove_client))
+            .route("/clients/{mesh_ip}/verify", web::post().to(verify_client))
+            .route(
+                "/clients/{mesh_ip}/unverify",
+                web::post().to(unverify_client),
+            )
+            .route(
+                "/clients/{mesh_ip}/internal_ip",
+                web::post().to(set_internal_ip),
+            )
+            .route("/clients/{mesh_ip}/ipv6", web::post().to(reassign_ipv6))
+            .route("/bans", web::get().to(get_bans))
+            .route("/bans", web::post().to(ban))
+            .route("/bans/remove", web::post().to(unban)),
+    );
+}
+
+fn internal_error(e: Box<RitaExitError>) -> HttpResponse {
+    error!("Admin request failed with {}", e);
+    HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR).json(format!("{e}"))
+}
+
+/// True if search appears in any of the client's identifying or contact details
+fn matches_search(client: &Client, search: &str) -> bool {
+    let search = search.to_lowercase();
+    [
+        &client.mesh_ip,
+        &client.wg_pubkey,
+        &client.eth_address,
+        &client.internal_ip,
+        &client.internet_ipv6,
+        &client.nickname,
+        &client.email,
+        &client.phone,
+    ]
+    .iter()
+    .any(|field| field.to_lowercase().contains(&search))
+}
+
+/// Looks up the client at mesh_ip, or the response to send back if there isn't one
+fn get_record(mesh_ip: &str, store: &dyn ClientStore) -> Result<Client, HttpResponse> {
+    // parsing gets the address in the same form it was stored in
+    let mesh_ip: IpAddr = match mesh_ip.parse() {
+        Ok(ip) => ip,
+        Err(_) => return Err(HttpResponse::BadRequest().json(format!("Invalid ip {mesh_ip}"))),
+    };
+    match store.get_clients(ClientSelector::MeshIp(mesh_ip.to_string())) {
+        Ok(mut records) => match records.pop() {
+            Some(record) => Ok(record),
+            None => Err(HttpResponse::NotFound().json("No client by that ID")),
+        },
+        Err(e) => Err(internal_error(e)),
+    }
+}
+
+pub async fn get_clients(query: Query<ClientQuery>) -> HttpResponse {
+    trace!("get_clients: Hit");
+    let query = query.into_inner();
+    match get_client_store().get_clients(ClientSelector::All) {
+        Ok(clients) => {
+            let clients: Vec<Client> = clients
+                .into_iter()
+                .filter(|c| query.verified.is_none_or(|v| c.verified == v))
+                .filter(|c| {
+                    query
+                        .search
+                        .as_ref()
+                        .is_none_or(|search| matches_search(c, search))
+                })
+                .collect();
+            HttpResponse::Ok().json(clients)
+        }
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn get_client_details(mesh_ip: Path<String>) -> HttpResponse {
+    let store = get_client_store();
+    let client = match get_record(&mesh_ip, store.as_ref()) {
+        Ok(record) => record,
+        Err(resp) => return resp,
+    };
+    let wg_key: Option<WgKey> = client.wg_pubkey.parse().ok();
+    let debt = get_debts_list()
+        .into_iter()
+        .find(|d| Some(d.identity.wg_public_key) == wg_key)
+        .map(|d| d.payment_details);
+    let usage = wg_key.and_then(|key| get_client_usage(&key));
+    HttpResponse::Ok().json(ClientDetails {
+        client,
+        debt,
+        usage,
+    })
+}
+
+fn set_verified(mesh_ip: &str, verified: bool) -> HttpResponse {
+    let store = get_client_store();
+    let record = match get_record(mesh_ip, store.as_ref()) {
+        Ok(record) => record,
+        Err(resp) => return resp,
+    };
+    info!(
+        "Operator set verified to {} for {}",
+        verified, record.wg_pubkey
+    );
+    match verify_db_client(&record, verified, store.as_ref()) {
+        Ok(()) => HttpResponse::Ok().json(()),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn verify_client(mesh_ip: Path<String>) -> HttpResponse {
+    set_verified(&mesh_ip, true)
+}
+
+pub async fn unverify_client(mesh_ip: Path<String>) -> HttpResponse {
+    set_verified(&mesh_ip, false)
+}
+
+pub async fn set_internal_ip(
+    mesh_ip: Path<String>,
+    request: Json<InternalIpRequest>,
+) -> HttpResponse {
+    let store = get_client_store();
+    let record = match get_record(&mesh_ip, store.as_ref()) {
+        Ok(record) => record,
+        Err(resp) => return resp,
+    };
+    match set_client_internal_ip(&record, request.ip, store.as_ref()) {
+        Ok(ip) => HttpResponse::Ok().json(ip),
+        // most likely the requested ip is taken or outside the range
+        Err(e) => HttpResponse::BadRequest().json(format!("{e}")),
+    }
+}
+
+pub async fn reassign_ipv6(mesh_ip: Path<String>) -> HttpResponse {
+    let store = get_client_store();
+    let record = match get_record(&mesh_ip, store.as_ref()) {
+        Ok(record) => record,
+        Err(resp) => return resp,
+    };
+    match reassign_client_ipv6(&record, store.as_ref()) {
+        Ok(subnet) => HttpResponse::Ok().json(subnet),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn remove_client(mesh_ip: Path<String>) -> HttpResponse {
+    let store = get_client_store();
+    let record = match get_record(&mesh_ip, store.as_ref()) {
+        Ok(record) => record,
+        Err(resp) => return resp,
+    };
+    info!("Operator deleting client {}", record.wg_pubkey);
+    let res = to_exit_client(record).and_then(|client| delete_client(client, store.as_ref()));
+    match res {
+        Ok(()) => HttpResponse::Ok().json(()),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn get_bans() -> HttpResponse {
+    trace!("get_bans: Hit");
+    match get_client_store().get_bans() {
+        Ok(bans) => HttpResponse::Ok().json(bans),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn ban(request: Json<BanRequest>) -> HttpResponse {
+    let request = request.into_inner();
+    let store = get_client_store();
+    match ban_client(
+        &request.wg_pubkey.to_string(),
+        request.reason,
+        store.as_ref(),
+    ) {
+        Ok(()) => HttpResponse::Ok().json(()),
+        Err(e) => internal_error(e),
+    }
+}
+
+pub async fn unban(request: Json<UnbanRequest>) -> HttpResponse {
+    info!("Operator unbanning {}", request.wg_pubkey);
+    match get_client_store().delete_ban(&request.wg_pubkey.to_string()) {
+        Ok(()) => HttpResponse::Ok().json(()),
+        Err(e) => internal_error(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::client_store::memory::MemoryStore;
+    use crate::database::database_tools::get_client_subnet;
+    use crate::database::ip_allocator::{reserve_internal_ip, InternalIpRange};
+    use crate::CLIENT_STORE;
+    use crate::EXIT_GLOBALS_TEST_LOCK;
+    use actix_async::System;
+    use actix_web_async::http::header::AUTHORIZATION;
+    use actix_web_async::test::{call_service, init_service, TestRequest};
+    use actix_web_async::App;
+    use althea_kernel_interface::KI;
+    use ipnetwork::IpNetwork;
+    use settings::exit::RitaExitSettingsStruct;
+    use std::sync::Arc;
+
+    const MESH_IP: &str = "fd00::1337";
+    const WG_KEY: &str = "Ha2YlTfDimJNboqxOSCh6M29W/H0jKtB4utitjaTO3A=";
+    /// rita:hunter2
+    const AUTH: &str = "Basic cml0YTpodW50ZXIy";
+
+    #[test]
+    fn test_matches_search() {
+        let client = Client {
+            mesh_ip: "fd00::1337".to_string(),
+            wg_pubkey: "Ym9vcFRoaXNJc0FUZXN0S2V5".to_string(),
+            internal_ip: "172.16.0.5".to_string(),
+            nickname: "Corner Store".to_string(),
+            email: "store@example.com".to_string(),
+            ..Default::default()
+        };
+        assert!(matches_search(&client, "fd00::13"));
+        assert!(matches_search(&client, "corner"));
+        assert!(matches_search(&client, "ym9vc"));
+        assert!(matches_search(&client, "172.16.0.5"));
+        assert!(matches_search(&client, "EXAMPLE.com"));
+        assert!(!matches_search(&client, "172.16.0.6"));
+        assert!(!matches_search(&client, "+1555"));
+    }
+
+    /// The settings and client store are globals, so everything that goes through them is kept
+    /// in this one test
+    #[test]
+    fn test_admin_endpoints() {
+        let _lock = EXIT_GLOBALS_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut exit = RitaExitSettingsStruct::test_default();
+        exit.network.rita_dashboard_password = Some("hunter2".to_string());
+        exit.exit_network.subnet = Some("fbad::/40".parse().unwrap());
+        exit.exit_network.client_subnet_size = Some(48);
+        settings::set_rita_exit(exit.clone());
+
+        let store = Arc::new(MemoryStore::default());
+        CLIENT_STORE
+            .write()
+            .unwrap()
+            .insert(KI.check_integration_test_netns(), store.clone());
+
+        let exit_sub = exit.exit_network.subnet.unwrap();
+        let range = InternalIpRange::from_settings();
+        let old_ipv6 = get_client_subnet(exit_sub, store.as_ref()).unwrap();
+        let old_internal_ip = reserve_internal_ip(store.as_ref(), &range).unwrap();
+        store
+            .insert_client(&Client {
+                mesh_ip: MESH_IP.to_string(),
+                wg_pubkey: WG_KEY.to_string(),
+                eth_address: "0x0000000000000000000000000000000000000001".to_string(),
+                internal_ip: old_internal_ip.to_string(),
+                internet_ipv6: old_ipv6.to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        let get_client = || {
+            store
+                .get_clients(ClientSelector::MeshIp(MESH_IP.to_string()))
+                .unwrap()
+                .pop()
+        };
+
+        let runner = System::new();
+        runner.block_on(async {
+            let app = init_service(App::new().configure(configure_admin)).await;
+            let ban = serde_json::json!({ "wg_pubkey": WG_KEY, "reason": "test" });
+
+            // without the password nothing is changed
+            let req = TestRequest::post()
+                .uri("/admin/bans")
+                .set_json(&ban)
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            assert!(store.get_bans().unwrap().is_empty());
+            assert!(get_client().is_some());
+
+            // the client is moved to a new subnet and the old one goes back to the exit
+            let req = TestRequest::post()
+                .uri(&format!("/admin/clients/{MESH_IP}/ipv6"))
+                .insert_header((AUTHORIZATION, AUTH))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let client = get_client().unwrap();
+            let new_ipv6: IpNetwork = client.internet_ipv6.parse().unwrap();
+            assert_ne!(new_ipv6, old_ipv6);
+            assert!(exit_sub.contains(new_ipv6.ip()));
+            let assigned = store.get_assigned_ips().unwrap().pop().unwrap();
+            assert_eq!(assigned.available_subnets, "0");
+
+            // the client gets the next free internal ip, its old one can't be claimed yet
+            let req = TestRequest::post()
+                .uri(&format!("/admin/clients/{MESH_IP}/internal_ip"))
+                .insert_header((AUTHORIZATION, AUTH))
+                .set_json(serde_json::json!({ "ip": null }))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            let client = get_client().unwrap();
+            assert_ne!(client.internal_ip, old_internal_ip.to_string());
+            let req = TestRequest::post()
+                .uri(&format!("/admin/clients/{MESH_IP}/internal_ip"))
+                .insert_header((AUTHORIZATION, AUTH))
+                .set_json(serde_json::json!({ "ip": old_internal_ip }))
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+            assert_eq!(get_client().unwrap().internal_ip, client.internal_ip);
+
+            // banning deletes the client
+            let req = TestRequest::post()
+                .uri("/admin/bans")
+                .insert_header((AUTHORIZATION, AUTH))
+                .set_json(&ban)
+                .to_request();
+            let resp = call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert!(store.is_banned(WG_KEY).unwrap());
+            assert!(get_client().is_none());
+        });
+    }
+}
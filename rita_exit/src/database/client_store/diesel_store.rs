@@ -0,0 +1,535 @@
+//! The ClientStore backends built on diesel. Postgres and SQLite share the schema in exit_db and
+//! every query is written the same way for both, so the store is generated for each connection
+//! type by diesel_client_store! rather than written out twice.
+
+use super::{ClientSelector, ClientStore, ClientUpdate};
+use crate::RitaExitError;
+use diesel::connection::Connection;
+use diesel::prelude::{ExpressionMethods, OptionalExtension, PgConnection, QueryDsl, RunQueryDsl};
+use diesel::r2d2::{ConnectionManager, Pool, PooledConnection};
+use exit_db::models::{AssignedIps, BannedClient, Client, InternalIp};
+#[cfg(feature = "sqlite")]
+use {
+    diesel::connection::SimpleConnection, diesel::prelude::SqliteConnection,
+    diesel::r2d2::CustomizeConnection, exit_db::migrations::MIGRATIONS,
+};
+
+/// Migrations that only apply to postgres. The initial setup defines plpgsql helpers and SQLite
+/// doesn't enforce varchar lengths, so there is nothing for the ipv6 varchar update to widen
+#[cfg(feature = "sqlite")]
+const POSTGRES_ONLY_MIGRATIONS: &[&str] = &["00000000000000", "20230703185432"];
+
+/// How long a SQLite connection waits on another one holding the write lock, in ms
+#[cfg(feature = "sqlite")]
+const SQLITE_BUSY_TIMEOUT: u32 = 5000;
+
+fn db_error(e: diesel::result::Error) -> Box<RitaExitError> {
+    Box::new(e.into())
+}
+
+/// Restricts a boxed query on the clients table to the clients selected
+macro_rules! filter_clients {
+    ($query:expr, $selector:expr) => {{
+        let query = $query;
+        // the columns are imported in a block of their own so they don't shadow the ones the
+        // caller's query was built with
+        {
+            use exit_db::schema::clients::dsl::{email, eth_address, mesh_ip, wg_pubkey};
+            match $selector {
+                ClientSelector::Exact {
+                    mesh_ip: ip,
+                    wg_pubkey: wg,
+                    eth_address: eth,
+                } => query
+                    .filter(mesh_ip.eq(ip))
+                    .filter(wg_pubkey.eq(wg))
+                    .filter(eth_address.eq(eth)),
+                ClientSelector::MeshIp(ip) => query.filter(mesh_ip.eq(ip)),
+                ClientSelector::WgKey(wg) => query.filter(wg_pubkey.eq(wg)),
+                ClientSelector::EthAddress(eth) => query.filter(eth_address.eq(eth)),
+                ClientSelector::Email(mail) => query.filter(email.eq(mail)),
+                ClientSelector::All => query,
+            }
+        }
+    }};
+}
+
+/// Applies a ClientUpdate to the clients targeted by an update statement
+macro_rules! set_client_field {
+    ($target:expr, $update:expr, $conn:expr) => {{
+        use exit_db::schema::clients::dsl::{
+            email, email_sent_time, internal_ip, internet_ipv6, last_seen, phone, text_sent,
+            verified,
+        };
+        let target = $target;
+        match $update {
+            ClientUpdate::Email(v) => diesel::update(target).set(email.eq(v)).execute($conn),
+            ClientUpdate::Phone(v) => diesel::update(target).set(phone.eq(v)).execute($conn),
+            ClientUpdate::Verified(v) => diesel::update(target).set(verified.eq(v)).execute($conn),
+            ClientUpdate::TextSent(v) => diesel::update(target).set(text_sent.eq(v)).execute($conn),
+            ClientUpdate::EmailSentTime(v) => diesel::update(target)
+                .set(email_sent_time.eq(v))
+                .execute($conn),
+            ClientUpdate::LastSeen(v) => diesel::update(target).set(last_seen.eq(v)).execute($conn),
+            ClientUpdate::InternalIp(v) => {
+                diesel::update(target).set(internal_ip.eq(v)).execute($conn)
+            }
+            ClientUpdate::InternetIpv6(v) => diesel::update(target)
+                .set(internet_ipv6.eq(v))
+                .execute($conn),
+        }
+    }};
+}
+
+macro_rules! diesel_client_store {
+    ($name:ident, $conn:ty) => {
+        pub struct $name {
+            pool: Pool<ConnectionManager<$conn>>,
+        }
+
+        impl $name {
+            /// Gets a connection from the pool, since there are dedicated connections for each
+            /// worker error if none is available right away
+            fn connection(
+                &self,
+            ) -> Result<PooledConnection<ConnectionManager<$conn>>, Box<RitaExitError>> {
+                match self.pool.try_get() {
+                    Some(connection) => Ok(connection),
+                    None => {
+                        error!("No available db connection!");
+                        Err(Box::new(RitaExitError::MiscStringError(
+                            "No Database connection available!".to_string(),
+                        )))
+                    }
+                }
+            }
+
+            /// The mesh ips of the selected clients, updates and deletes go through these since
+            /// diesel can't box those statements
+            fn select_mesh_ips(
+                &self,
+                conn: &$conn,
+                selector: ClientSelector,
+            ) -> Result<Vec<String>, Box<RitaExitError>> {
+                use exit_db::schema::clients::dsl::{clients, mesh_ip};
+                filter_clients!(clients.select(mesh_ip).into_boxed(), selector)
+                    .load::<String>(conn)
+                    .map_err(db_error)
+            }
+        }
+
+        impl ClientStore for $name {
+            fn get_clients(
+                &self,
+                selector: ClientSelector,
+            ) -> Result<Vec<Client>, Box<RitaExitError>> {
+                use exit_db::schema::clients::dsl::clients;
+                let conn = self.connection()?;
+                filter_clients!(clients.into_boxed(), selector)
+                    .load::<Client>(&*conn)
+                    .map_err(db_error)
+            }
+
+            fn client_exists(&self, selector: ClientSelector) -> Result<bool, Box<RitaExitError>> {
+                use exit_db::schema::clients::dsl::{clients, mesh_ip};
+                let conn = self.connection()?;
+                let found = filter_clients!(clients.select(mesh_ip).into_boxed(), selector)
+                    .limit(1)
+                    .load::<String>(&*conn)
+                    .map_err(db_error)?;
+                Ok(!found.is_empty())
+            }
+
+            fn insert_client(&self, client: &Client) -> Result<(), Box<RitaExitError>> {
+                use exit_db::schema::clients::dsl::clients;
+                let conn = self.connection()?;
+                diesel::insert_into(clients)
+                    .values(client)
+                    .execute(&*conn)
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            fn update_clients(
+                &self,
+                selector: ClientSelector,
+                update: ClientUpdate,
+            ) -> Result<(), Box<RitaExitError>> {
+                use exit_db::schema::clients::dsl::{clients, mesh_ip};
+                let conn = self.connection()?;
+                let res = match selector {
+                    ClientSelector::All => set_client_field!(clients, update, &*conn),
+                    selector => {
+                        let ips = self.select_mesh_ips(&conn, selector)?;
+                        set_client_field!(clients.filter(mesh_ip.eq_any(ips)), update, &*conn)
+                    }
+                };
+                res.map_err(db_error)?;
+                Ok(())
+            }
+
+            fn delete_clients(&self, selector: ClientSelector) -> Result<(), Box<RitaExitError>> {
+                use exit_db::schema::clients::dsl::{clients, mesh_ip};
+                let conn = self.connection()?;
+                let res = match selector {
+                    ClientSelector::All => diesel::delete(clients).execute(&*conn),
+                    selector => {
+                        let ips = self.select_mesh_ips(&conn, selector)?;
+                        diesel::delete(clients.filter(mesh_ip.eq_any(ips))).execute(&*conn)
+                    }
+                };
+                res.map_err(db_error)?;
+                Ok(())
+            }
+
+            fn get_assigned_ips(&self) -> Result<Vec<AssignedIps>, Box<RitaExitError>> {
+                use exit_db::schema::assigned_ips::dsl::assigned_ips;
+                let conn = self.connection()?;
+                assigned_ips.load::<AssignedIps>(&*conn).map_err(db_error)
+            }
+
+            fn insert_assigned_ips(&self, record: &AssignedIps) -> Result<(), Box<RitaExitError>> {
+                use exit_db::schema::assigned_ips::dsl::assigned_ips;
+                let conn = self.connection()?;
+                diesel::insert_into(assigned_ips)
+                    .values(record)
+                    .execute(&*conn)
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            fn swap_assigned_ips(
+                &self,
+                current: &AssignedIps,
+                new: &AssignedIps,
+            ) -> Result<bool, Box<RitaExitError>> {
+                use exit_db::schema::assigned_ips::dsl::{
+                    assigned_ips, available_subnets, iterative_index, subnet,
+                };
+                let conn = self.connection()?;
+                // the same conditional update as swap_internal_ip
+                let changed = diesel::update(
+                    assigned_ips
+                        .filter(subnet.eq(&current.subnet))
+                        .filter(available_subnets.eq(&current.available_subnets))
+                        .filter(iterative_index.eq(current.iterative_index)),
+                )
+                .set((
+                    available_subnets.eq(&new.available_subnets),
+                    iterative_index.eq(new.iterative_index),
+                ))
+                .execute(&*conn)
+                .map_err(db_error)?;
+                Ok(changed == 1)
+            }
+
+            fn clear_assigned_ips(&self) -> Result<(), Box<RitaExitError>> {
+                use exit_db::schema::assigned_ips::dsl::assigned_ips;
+                let conn = self.connection()?;
+                diesel::delete(assigned_ips)
+                    .execute(&*conn)
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            fn get_internal_ips(
+                &self,
+                pool_name: &str,
+            ) -> Result<Vec<InternalIp>, Box<RitaExitError>> {
+                use exit_db::schema::internal_ips::dsl::{internal_ips, ip_offset, pool};
+                let conn = self.connection()?;
+                internal_ips
+                    .filter(pool.eq(pool_name))
+                    .order(ip_offset.asc())
+                    .load::<InternalIp>(&*conn)
+                    .map_err(db_error)
+            }
+
+            fn get_internal_ip(
+                &self,
+                pool_name: &str,
+                offset: i64,
+            ) -> Result<Option<InternalIp>, Box<RitaExitError>> {
+                use exit_db::schema::internal_ips::dsl::{internal_ips, ip_offset, pool};
+                let conn = self.connection()?;
+                internal_ips
+                    .filter(pool.eq(pool_name))
+                    .filter(ip_offset.eq(offset))
+                    .first::<InternalIp>(&*conn)
+                    .optional()
+                    .map_err(db_error)
+            }
+
+            fn first_free_internal_ip(
+                &self,
+                pool_name: &str,
+                released_before: i64,
+            ) -> Result<Option<InternalIp>, Box<RitaExitError>> {
+                use exit_db::schema::internal_ips::dsl::{
+                    internal_ips, ip_offset, pool, released_at,
+                };
+                let conn = self.connection()?;
+                internal_ips
+                    .filter(pool.eq(pool_name))
+                    .filter(released_at.ne(0))
+                    .filter(released_at.le(released_before))
+                    .order(ip_offset.asc())
+                    .first::<InternalIp>(&*conn)
+                    .optional()
+                    .map_err(db_error)
+            }
+
+            fn next_internal_ip_offset(&self, pool_name: &str) -> Result<i64, Box<RitaExitError>> {
+                use exit_db::schema::internal_ips::dsl::{internal_ips, ip_offset, pool};
+                let conn = self.connection()?;
+                let highest = internal_ips
+                    .filter(pool.eq(pool_name))
+                    .select(diesel::dsl::max(ip_offset))
+                    .first::<Option<i64>>(&*conn)
+                    .map_err(db_error)?;
+                Ok(highest.map_or(0, |offset| offset + 1))
+            }
+
+            fn insert_internal_ips(&self, ips: &[InternalIp]) -> Result<bool, Box<RitaExitError>> {
+                use diesel::result::{DatabaseErrorKind, Error};
+                use exit_db::schema::internal_ips::dsl::internal_ips;
+                if ips.is_empty() {
+                    return Ok(true);
+                }
+                let conn = self.connection()?;
+                let res = conn.transaction::<_, Error, _>(|| {
+                    diesel::insert_into(internal_ips)
+                        .values(ips)
+                        .execute(&*conn)
+                });
+                match res {
+                    Ok(_) => Ok(true),
+                    Err(Error::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Ok(false),
+                    Err(e) => Err(db_error(e)),
+                }
+            }
+
+            fn swap_internal_ip(
+                &self,
+                current: &InternalIp,
+                new: &InternalIp,
+            ) -> Result<bool, Box<RitaExitError>> {
+                use exit_db::schema::internal_ips::dsl::{
+                    internal_ips, ip_offset, pool, released_at, reserved_at,
+                };
+                let conn = self.connection()?;
+                // a single conditional update is atomic on both backends, if another worker
+                // got there first the filter matches nothing and no rows change
+                let changed = diesel::update(
+                    internal_ips
+                        .filter(pool.eq(&current.pool))
+                        .filter(ip_offset.eq(current.ip_offset))
+                        .filter(reserved_at.eq(current.reserved_at))
+                        .filter(released_at.eq(current.released_at)),
+                )
+                .set((
+                    reserved_at.eq(new.reserved_at),
+                    released_at.eq(new.released_at),
+                ))
+                .execute(&*conn)
+                .map_err(db_error)?;
+                Ok(changed == 1)
+            }
+
+            fn get_bans(&self) -> Result<Vec<BannedClient>, Box<RitaExitError>> {
+                use exit_db::schema::banned_clients::dsl::banned_clients;
+                let conn = self.connection()?;
+                banned_clients
+                    .load::<BannedClient>(&*conn)
+                    .map_err(db_error)
+            }
+
+            fn is_banned(&self, key: &str) -> Result<bool, Box<RitaExitError>> {
+                use exit_db::schema::banned_clients::dsl::{banned_clients, wg_pubkey};
+                let conn = self.connection()?;
+                let found = banned_clients
+                    .find(key)
+                    .select(wg_pubkey)
+                    .load::<String>(&*conn)
+                    .map_err(db_error)?;
+                Ok(!found.is_empty())
+            }
+
+            fn insert_ban(&self, ban: &BannedClient) -> Result<(), Box<RitaExitError>> {
+                use exit_db::schema::banned_clients::dsl::banned_clients;
+                let conn = self.connection()?;
+                diesel::insert_into(banned_clients)
+                    .values(ban)
+                    .execute(&*conn)
+                    .map_err(db_error)?;
+                Ok(())
+            }
+
+            fn delete_ban(&self, key: &str) -> Result<(), Box<RitaExitError>> {
+                use exit_db::schema::banned_clients::dsl::banned_clients;
+                let conn = self.connection()?;
+                diesel::delete(banned_clients.find(key))
+                    .execute(&*conn)
+                    .map_err(db_error)?;
+                Ok(())
+            }
+        }
+    };
+}
+
+diesel_client_store!(PostgresStore, PgConnection);
+#[cfg(feature = "sqlite")]
+diesel_client_store!(SqliteStore, SqliteConnection);
+
+impl PostgresStore {
+    pub fn new(db_uri: &str, pool_size: u32) -> Result<PostgresStore, Box<RitaExitError>> {
+        let manager = ConnectionManager::new(db_uri);
+        match Pool::builder().max_size(pool_size).build(manager) {
+            Ok(pool) => Ok(PostgresStore { pool }),
+            Err(e) => Err(Box::new(RitaExitError::MiscStringError(format!(
+                "Failed to create pool, check exit IP is trusted to access postgresql: {e}"
+            )))),
+        }
+    }
+}
+
+/// Sets up every SQLite connection the pool opens to wait on the others rather than failing
+/// when two workers write at once
+#[cfg(feature = "sqlite")]
+#[derive(Debug)]
+struct SqliteBusyTimeout;
+
+#[cfg(feature = "sqlite")]
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for SqliteBusyTimeout {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute(&format!("PRAGMA busy_timeout = {SQLITE_BUSY_TIMEOUT};"))
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// Runs the exit_db migrations this database hasn't seen yet. They are recorded in the same table
+/// diesel_cli uses, each one in the same transaction as the migration itself
+#[cfg(feature = "sqlite")]
+fn run_sqlite_migrations(conn: &SqliteConnection) -> Result<(), diesel::result::Error> {
+    use diesel::sql_types::Text;
+    conn.batch_execute(
+        "CREATE TABLE IF NOT EXISTS __diesel_schema_migrations (
+            version VARCHAR(50) PRIMARY KEY NOT NULL,
+            run_on TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );",
+    )?;
+    for migration in MIGRATIONS {
+        conn.transaction::<_, diesel::result::Error, _>(|| {
+            let new = diesel::sql_query(
+                "INSERT OR IGNORE INTO __diesel_schema_migrations (version) VALUES (?)",
+            )
+            .bind::<Text, _>(migration.version)
+            .execute(conn)?;
+            if new == 1 && !POSTGRES_ONLY_MIGRATIONS.contains(&migration.version) {
+                info!("Running migration {}", migration.name);
+                conn.batch_execute(migration.up_sql)?;
+            }
+            Ok(())
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    /// Opens or creates the database file at path and brings its tables up to date
+    pub fn new(path: &str, pool_size: u32) -> Result<SqliteStore, Box<RitaExitError>> {
+        let manager = ConnectionManager::new(path);
+        let pool = match Pool::builder()
+            .max_size(pool_size)
+            .connection_customizer(Box::new(SqliteBusyTimeout))
+            .build(manager)
+        {
+            Ok(pool) => pool,
+            Err(e) => {
+                return Err(Box::new(RitaExitError::MiscStringError(format!(
+                    "Failed to open sqlite database {path}: {e}"
+                ))))
+            }
+        };
+        let store = SqliteStore { pool };
+        run_sqlite_migrations(&*store.connection()?).map_err(db_error)?;
+        Ok(store)
+    }
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use super::*;
+    use crate::database::client_store::store_tests;
+    use std::path::PathBuf;
+
+    /// A fresh database file for one test, removed again when it's dropped
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> TempDb {
+            let path = std::env::temp_dir().join(format!(
+                "rita_exit_{}_{}.sqlite",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_file(&path);
+            TempDb(path)
+        }
+
+        fn open(&self) -> SqliteStore {
+            SqliteStore::new(self.0.to_str().unwrap(), 2).unwrap()
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_sqlite_migrations() {
+        let applied = |store: &SqliteStore| -> i64 {
+            diesel::dsl::sql::<diesel::sql_types::BigInt>(
+                "SELECT COUNT(*) FROM __diesel_schema_migrations",
+            )
+            .get_result(&*store.connection().unwrap())
+            .unwrap()
+        };
+        let db = TempDb::new("migrations");
+        let store = db.open();
+        assert_eq!(applied(&store), MIGRATIONS.len() as i64);
+        store_tests::check_clients(&store);
+        drop(store);
+        // opening it again has nothing left to run
+        let store = db.open();
+        assert_eq!(applied(&store), MIGRATIONS.len() as i64);
+        store_tests::check_bans(&store);
+    }
+
+    #[test]
+    fn test_sqlite_store_clients() {
+        let db = TempDb::new("clients");
+        store_tests::check_clients(&db.open());
+    }
+
+    #[test]
+    fn test_sqlite_store_assigned_ips() {
+        let db = TempDb::new("assigned_ips");
+        store_tests::check_assigned_ips(&db.open());
+    }
+
+    #[test]
+    fn test_sqlite_store_internal_ips() {
+        let db = TempDb::new("internal_ips");
+        store_tests::check_internal_ips(&db.open());
+    }
+
+    #[test]
+    fn test_sqlite_store_bans() {
+        let db = TempDb::new("bans");
+        store_tests::check_bans(&db.open());
+    }
+}
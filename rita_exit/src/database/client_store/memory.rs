@@ -0,0 +1,263 @@
+//! A ClientStore that only lives in memory, selected with memory:// it lets tests and
+//! development exits run without a database server. Everything is lost on restart.
+
+use super::{ClientSelector, ClientStore, ClientUpdate};
+use crate::RitaExitError;
+use exit_db::models::{AssignedIps, BannedClient, Client, InternalIp};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    /// Clients by mesh ip
+    clients: RwLock<BTreeMap<String, Client>>,
+    /// Allocation state by exit subnet
+    assigned_ips: RwLock<BTreeMap<String, AssignedIps>>,
+    /// Internal ips handed out by pool and offset
+    internal_ips: RwLock<BTreeMap<(String, i64), InternalIp>>,
+    /// Banned clients by wg key
+    bans: RwLock<BTreeMap<String, BannedClient>>,
+}
+
+fn apply_update(client: &mut Client, update: &ClientUpdate) {
+    match update {
+        ClientUpdate::Email(v) => client.email = v.clone(),
+        ClientUpdate::Phone(v) => client.phone = v.clone(),
+        ClientUpdate::Verified(v) => client.verified = *v,
+        ClientUpdate::TextSent(v) => client.text_sent = *v,
+        ClientUpdate::EmailSentTime(v) => client.email_sent_time = *v,
+        ClientUpdate::LastSeen(v) => client.last_seen = *v,
+        ClientUpdate::InternalIp(v) => client.internal_ip = v.clone(),
+        ClientUpdate::InternetIpv6(v) => client.internet_ipv6 = v.clone(),
+    }
+}
+
+impl ClientStore for MemoryStore {
+    fn get_clients(&self, selector: ClientSelector) -> Result<Vec<Client>, Box<RitaExitError>> {
+        Ok(self
+            .clients
+            .read()
+            .unwrap()
+            .values()
+            .filter(|c| selector.matches(c))
+            .cloned()
+            .collect())
+    }
+
+    fn client_exists(&self, selector: ClientSelector) -> Result<bool, Box<RitaExitError>> {
+        Ok(self
+            .clients
+            .read()
+            .unwrap()
+            .values()
+            .any(|c| selector.matches(c)))
+    }
+
+    fn insert_client(&self, client: &Client) -> Result<(), Box<RitaExitError>> {
+        let mut clients = self.clients.write().unwrap();
+        if clients.contains_key(&client.mesh_ip) {
+            return Err(Box::new(RitaExitError::MiscStringError(format!(
+                "A client with mesh ip {} already exists",
+                client.mesh_ip
+            ))));
+        }
+        clients.insert(client.mesh_ip.clone(), client.clone());
+        Ok(())
+    }
+
+    fn update_clients(
+        &self,
+        selector: ClientSelector,
+        update: ClientUpdate,
+    ) -> Result<(), Box<RitaExitError>> {
+        for client in self.clients.write().unwrap().values_mut() {
+            if selector.matches(client) {
+                apply_update(client, &update);
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_clients(&self, selector: ClientSelector) -> Result<(), Box<RitaExitError>> {
+        self.clients
+            .write()
+            .unwrap()
+            .retain(|_, c| !selector.matches(c));
+        Ok(())
+    }
+
+    fn get_assigned_ips(&self) -> Result<Vec<AssignedIps>, Box<RitaExitError>> {
+        Ok(self
+            .assigned_ips
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect())
+    }
+
+    fn insert_assigned_ips(&self, record: &AssignedIps) -> Result<(), Box<RitaExitError>> {
+        let mut assigned_ips = self.assigned_ips.write().unwrap();
+        if assigned_ips.contains_key(&record.subnet) {
+            return Err(Box::new(RitaExitError::MiscStringError(format!(
+                "Subnet {} already has an entry",
+                record.subnet
+            ))));
+        }
+        assigned_ips.insert(record.subnet.clone(), record.clone());
+        Ok(())
+    }
+
+    fn swap_assigned_ips(
+        &self,
+        current: &AssignedIps,
+        new: &AssignedIps,
+    ) -> Result<bool, Box<RitaExitError>> {
+        match self.assigned_ips.write().unwrap().get_mut(&current.subnet) {
+            Some(existing) if existing == current => {
+                *existing = new.clone();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn clear_assigned_ips(&self) -> Result<(), Box<RitaExitError>> {
+        self.assigned_ips.write().unwrap().clear();
+        Ok(())
+    }
+
+    fn get_internal_ips(&self, pool: &str) -> Result<Vec<InternalIp>, Box<RitaExitError>> {
+        Ok(self
+            .internal_ips
+            .read()
+            .unwrap()
+            .values()
+            .filter(|ip| ip.pool == pool)
+            .cloned()
+            .collect())
+    }
+
+    fn get_internal_ip(
+        &self,
+        pool: &str,
+        ip_offset: i64,
+    ) -> Result<Option<InternalIp>, Box<RitaExitError>> {
+        Ok(self
+            .internal_ips
+            .read()
+            .unwrap()
+            .get(&(pool.to_string(), ip_offset))
+            .cloned())
+    }
+
+    fn first_free_internal_ip(
+        &self,
+        pool: &str,
+        released_before: i64,
+    ) -> Result<Option<InternalIp>, Box<RitaExitError>> {
+        Ok(self
+            .internal_ips
+            .read()
+            .unwrap()
+            .values()
+            .find(|ip| ip.pool == pool && ip.released_at != 0 && ip.released_at <= released_before)
+            .cloned())
+    }
+
+    fn next_internal_ip_offset(&self, pool: &str) -> Result<i64, Box<RitaExitError>> {
+        Ok(self
+            .internal_ips
+            .read()
+            .unwrap()
+            .values()
+            .filter(|ip| ip.pool == pool)
+            .map(|ip| ip.ip_offset + 1)
+            .max()
+            .unwrap_or(0))
+    }
+
+    fn insert_internal_ips(&self, ips: &[InternalIp]) -> Result<bool, Box<RitaExitError>> {
+        let mut internal_ips = self.internal_ips.write().unwrap();
+        if ips
+            .iter()
+            .any(|ip| internal_ips.contains_key(&(ip.pool.clone(), ip.ip_offset)))
+        {
+            return Ok(false);
+        }
+        for ip in ips {
+            internal_ips.insert((ip.pool.clone(), ip.ip_offset), ip.clone());
+        }
+        Ok(true)
+    }
+
+    fn swap_internal_ip(
+        &self,
+        current: &InternalIp,
+        new: &InternalIp,
+    ) -> Result<bool, Box<RitaExitError>> {
+        match self
+            .internal_ips
+            .write()
+            .unwrap()
+            .get_mut(&(current.pool.clone(), current.ip_offset))
+        {
+            Some(existing) if existing == current => {
+                *existing = new.clone();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    fn get_bans(&self) -> Result<Vec<BannedClient>, Box<RitaExitError>> {
+        Ok(self.bans.read().unwrap().values().cloned().collect())
+    }
+
+    fn is_banned(&self, wg_pubkey: &str) -> Result<bool, Box<RitaExitError>> {
+        Ok(self.bans.read().unwrap().contains_key(wg_pubkey))
+    }
+
+    fn insert_ban(&self, ban: &BannedClient) -> Result<(), Box<RitaExitError>> {
+        let mut bans = self.bans.write().unwrap();
+        if bans.contains_key(&ban.wg_pubkey) {
+            return Err(Box::new(RitaExitError::MiscStringError(format!(
+                "{} is already banned",
+                ban.wg_pubkey
+            ))));
+        }
+        bans.insert(ban.wg_pubkey.clone(), ban.clone());
+        Ok(())
+    }
+
+    fn delete_ban(&self, wg_pubkey: &str) -> Result<(), Box<RitaExitError>> {
+        self.bans.write().unwrap().remove(wg_pubkey);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::client_store::store_tests;
+
+    #[test]
+    fn test_memory_store_clients() {
+        store_tests::check_clients(&MemoryStore::default());
+    }
+
+    #[test]
+    fn test_memory_store_assigned_ips() {
+        store_tests::check_assigned_ips(&MemoryStore::default());
+    }
+
+    #[test]
+    fn test_memory_store_internal_ips() {
+        store_tests::check_internal_ips(&MemoryStore::default());
+    }
+
+    #[test]
+    fn test_memory_store_bans() {
+        store_tests::check_bans(&MemoryStore::default());
+    }
+}
@@ -0,0 +1,429 @@
+//! ClientStore is where the exit keeps its registered clients and the state of its ipv6 subnet
+//! allocation. The logic in database_tools only ever talks to the store through this trait so
+//! that the backend can be picked by the scheme of db_uri. Postgres is what production exits
+//! run, SQLite is enough for a small operator running a single exit and the in memory store is
+//! for tests and development where nothing needs to survive a restart. SQLite links against the
+//! system libsqlite3 so it is only built with the sqlite feature.
+
+pub mod diesel_store;
+pub mod memory;
+
+use crate::RitaExitError;
+use althea_types::ExitClientIdentity;
+use diesel_store::PostgresStore;
+#[cfg(feature = "sqlite")]
+use diesel_store::SqliteStore;
+use exit_db::models::{AssignedIps, BannedClient, Client, InternalIp};
+use memory::MemoryStore;
+use std::sync::Arc;
+
+/// Which clients a query applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientSelector {
+    /// The client matching all of mesh ip, wg key and eth address
+    Exact {
+        mesh_ip: String,
+        wg_pubkey: String,
+        eth_address: String,
+    },
+    MeshIp(String),
+    WgKey(String),
+    EthAddress(String),
+    Email(String),
+    All,
+}
+
+impl ClientSelector {
+    /// Selects the record of this exact client
+    pub fn identity(client: &ExitClientIdentity) -> ClientSelector {
+        ClientSelector::Exact {
+            mesh_ip: client.global.mesh_ip.to_string(),
+            wg_pubkey: client.global.wg_public_key.to_string(),
+            // TODO search for EIP-55 capitalized key string and add a fallback
+            // to upgrade entires that are using the old all lowercase format. This should
+            // simplify the code some and reduce calls to .to_lowercase and worst of all the chance
+            // that we might forget a lowercase call, which type-checking can't protect us from.
+            eth_address: client.global.eth_address.to_string().to_lowercase(),
+        }
+    }
+
+    /// Selects a record we already have by all of its identifying details
+    pub fn record(client: &Client) -> ClientSelector {
+        ClientSelector::Exact {
+            mesh_ip: client.mesh_ip.clone(),
+            wg_pubkey: client.wg_pubkey.clone(),
+            eth_address: client.eth_address.to_lowercase(),
+        }
+    }
+
+    pub fn matches(&self, client: &Client) -> bool {
+        match self {
+            ClientSelector::Exact {
+                mesh_ip,
+                wg_pubkey,
+                eth_address,
+            } => {
+                &client.mesh_ip == mesh_ip
+                    && &client.wg_pubkey == wg_pubkey
+                    && &client.eth_address == eth_address
+            }
+            ClientSelector::MeshIp(ip) => &client.mesh_ip == ip,
+            ClientSelector::WgKey(key) => &client.wg_pubkey == key,
+            ClientSelector::EthAddress(addr) => &client.eth_address == addr,
+            ClientSelector::Email(mail) => &client.email == mail,
+            ClientSelector::All => true,
+        }
+    }
+}
+
+/// A change to a single column of a client record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientUpdate {
+    Email(String),
+    Phone(String),
+    Verified(bool),
+    TextSent(i32),
+    EmailSentTime(i64),
+    LastSeen(i64),
+    /// The internal ipv4 address, which must have been reserved from the internal ip pool
+    InternalIp(String),
+    /// The comma separated list of subnets assigned to the client, one per exit subnet
+    InternetIpv6(String),
+}
+
+pub trait ClientStore: Send + Sync {
+    fn get_clients(&self, selector: ClientSelector) -> Result<Vec<Client>, Box<RitaExitError>>;
+    fn client_exists(&self, selector: ClientSelector) -> Result<bool, Box<RitaExitError>>;
+    /// Errors if a client with the same mesh ip already exists
+    fn insert_client(&self, client: &Client) -> Result<(), Box<RitaExitError>>;
+    fn update_clients(
+        &self,
+        selector: ClientSelector,
+        update: ClientUpdate,
+    ) -> Result<(), Box<RitaExitError>>;
+    fn delete_clients(&self, selector: ClientSelector) -> Result<(), Box<RitaExitError>>;
+
+    /// The allocation state of every exit subnet clients have been given ipv6 subnets from
+    fn get_assigned_ips(&self) -> Result<Vec<AssignedIps>, Box<RitaExitError>>;
+    fn insert_assigned_ips(&self, record: &AssignedIps) -> Result<(), Box<RitaExitError>>;
+    /// Replaces current with new in a single step only if the stored record is still current,
+    /// returns false without writing anything if someone else changed it first
+    fn swap_assigned_ips(
+        &self,
+        current: &AssignedIps,
+        new: &AssignedIps,
+    ) -> Result<bool, Box<RitaExitError>>;
+    fn clear_assigned_ips(&self) -> Result<(), Box<RitaExitError>>;
+
+    /// Every address of the named internal ip pool that has been handed out, including released
+    /// ones, ordered by offset
+    fn get_internal_ips(&self, pool: &str) -> Result<Vec<InternalIp>, Box<RitaExitError>>;
+    fn get_internal_ip(
+        &self,
+        pool: &str,
+        ip_offset: i64,
+    ) -> Result<Option<InternalIp>, Box<RitaExitError>>;
+    /// The released address of the named pool with the lowest offset, out of those released at or
+    /// before released_before
+    fn first_free_internal_ip(
+        &self,
+        pool: &str,
+        released_before: i64,
+    ) -> Result<Option<InternalIp>, Box<RitaExitError>>;
+    /// One past the highest offset of the named pool that has a row, 0 if it has none
+    fn next_internal_ip_offset(&self, pool: &str) -> Result<i64, Box<RitaExitError>>;
+    /// Adds all of ips in a single step, returns false without writing anything if any of the
+    /// addresses already has a row
+    fn insert_internal_ips(&self, ips: &[InternalIp]) -> Result<bool, Box<RitaExitError>>;
+    /// Replaces current with new in a single step only if the stored row is still current,
+    /// returns false without writing anything if someone else changed it first
+    fn swap_internal_ip(
+        &self,
+        current: &InternalIp,
+        new: &InternalIp,
+    ) -> Result<bool, Box<RitaExitError>>;
+
+    /// Every wg key the operator has banned
+    fn get_bans(&self) -> Result<Vec<BannedClient>, Box<RitaExitError>>;
+    fn is_banned(&self, wg_pubkey: &str) -> Result<bool, Box<RitaExitError>>;
+    /// Errors if the key is already banned
+    fn insert_ban(&self, ban: &BannedClient) -> Result<(), Box<RitaExitError>>;
+    fn delete_ban(&self, wg_pubkey: &str) -> Result<(), Box<RitaExitError>>;
+}
+
+/// The backends db_uri can select
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DbUri {
+    /// postgres://, postgresql:// or psql://, passed to the driver as is
+    Postgres(String),
+    /// sqlite:// followed by the path of the database file
+    Sqlite(String),
+    /// memory://
+    Memory,
+}
+
+pub fn parse_db_uri(db_uri: &str) -> Result<DbUri, Box<RitaExitError>> {
+    let db_uri = db_uri.trim();
+    if db_uri.starts_with("postgres://")
+        || db_uri.starts_with("postgresql://")
+        || db_uri.starts_with("psql://")
+    {
+        Ok(DbUri::Postgres(db_uri.to_string()))
+    } else if let Some(path) = db_uri.strip_prefix("sqlite://") {
+        if path.is_empty() {
+            return Err(Box::new(RitaExitError::MiscStringError(
+                "sqlite:// database uri is missing a path".to_string(),
+            )));
+        }
+        Ok(DbUri::Sqlite(path.to_string()))
+    } else if db_uri == "memory://" {
+        Ok(DbUri::Memory)
+    } else {
+        Err(Box::new(RitaExitError::MiscStringError(format!(
+            "Unsupported database uri {db_uri}, expected postgres://, sqlite:// or memory://"
+        ))))
+    }
+}
+
+/// Opens the store db_uri points to, pool_size is the number of connections kept open for
+/// backends that have them
+pub fn open_client_store(
+    db_uri: &str,
+    pool_size: u32,
+) -> Result<Arc<dyn ClientStore>, Box<RitaExitError>> {
+    Ok(match parse_db_uri(db_uri)? {
+        DbUri::Postgres(uri) => Arc::new(PostgresStore::new(&uri, pool_size)?),
+        #[cfg(feature = "sqlite")]
+        DbUri::Sqlite(path) => Arc::new(SqliteStore::new(&path, pool_size)?),
+        #[cfg(not(feature = "sqlite"))]
+        DbUri::Sqlite(_) => {
+            return Err(Box::new(RitaExitError::MiscStringError(
+                "This exit was built without SQLite support, rebuild with the sqlite feature"
+                    .to_string(),
+            )))
+        }
+        DbUri::Memory => Arc::new(MemoryStore::default()),
+    })
+}
+
+/// Checks every backend has to pass, run against each of them by their own tests
+#[cfg(test)]
+pub(crate) mod store_tests {
+    use super::*;
+
+    fn client(mesh_ip: &str, wg_pubkey: &str) -> Client {
+        Client {
+            mesh_ip: mesh_ip.to_string(),
+            wg_pubkey: wg_pubkey.to_string(),
+            eth_address: "0xabc".to_string(),
+            email: "a@example.com".to_string(),
+            ..Default::default()
+        }
+    }
+
+    pub fn check_clients(store: &dyn ClientStore) {
+        store.insert_client(&client("fd00::1", "one")).unwrap();
+        store.insert_client(&client("fd00::2", "two")).unwrap();
+        assert!(store.insert_client(&client("fd00::1", "three")).is_err());
+        assert_eq!(store.get_clients(ClientSelector::All).unwrap().len(), 2);
+        assert!(store
+            .client_exists(ClientSelector::WgKey("two".to_string()))
+            .unwrap());
+        assert!(!store
+            .client_exists(ClientSelector::WgKey("three".to_string()))
+            .unwrap());
+
+        store
+            .update_clients(
+                ClientSelector::MeshIp("fd00::1".to_string()),
+                ClientUpdate::Verified(true),
+            )
+            .unwrap();
+        store
+            .update_clients(
+                ClientSelector::Email("a@example.com".to_string()),
+                ClientUpdate::EmailSentTime(42),
+            )
+            .unwrap();
+        store
+            .update_clients(
+                ClientSelector::record(&client("fd00::2", "two")),
+                ClientUpdate::InternalIp("172.16.0.2".to_string()),
+            )
+            .unwrap();
+        let clients = store.get_clients(ClientSelector::All).unwrap();
+        assert!(clients[0].verified);
+        assert!(!clients[1].verified);
+        assert_eq!(clients[1].internal_ip, "172.16.0.2");
+        assert!(clients.iter().all(|c| c.email_sent_time == 42));
+
+        store
+            .delete_clients(ClientSelector::MeshIp("fd00::1".to_string()))
+            .unwrap();
+        let clients = store.get_clients(ClientSelector::All).unwrap();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].wg_pubkey, "two");
+        store.delete_clients(ClientSelector::All).unwrap();
+        assert!(!store.client_exists(ClientSelector::All).unwrap());
+    }
+
+    pub fn check_assigned_ips(store: &dyn ClientStore) {
+        let current = AssignedIps {
+            subnet: "fd00::/40".to_string(),
+            available_subnets: "".to_string(),
+            iterative_index: 0,
+        };
+        store.insert_assigned_ips(&current).unwrap();
+        assert!(store.insert_assigned_ips(&current).is_err());
+        let new = AssignedIps {
+            available_subnets: "1".to_string(),
+            iterative_index: 3,
+            ..current.clone()
+        };
+        assert!(store.swap_assigned_ips(&current, &new).unwrap());
+        // current is stale now, so a second swap from it must not go through
+        assert!(!store.swap_assigned_ips(&current, &current).unwrap());
+        assert_eq!(store.get_assigned_ips().unwrap(), vec![new]);
+        store.clear_assigned_ips().unwrap();
+        assert!(store.get_assigned_ips().unwrap().is_empty());
+    }
+
+    pub fn check_internal_ips(store: &dyn ClientStore) {
+        let row = |pool: &str, ip_offset: i64| InternalIp {
+            pool: pool.to_string(),
+            ip_offset,
+            reserved_at: 10,
+            released_at: 0,
+        };
+        assert!(store.get_internal_ips("172.16.0.0/12").unwrap().is_empty());
+        assert_eq!(store.next_internal_ip_offset("172.16.0.0/12").unwrap(), 0);
+        assert!(store
+            .insert_internal_ips(&[row("172.16.0.0/12", 4), row("172.16.0.0/12", 2)])
+            .unwrap());
+        assert!(store.insert_internal_ips(&[row("10.0.0.0/8", 4)]).unwrap());
+        // one taken address stops the whole insert
+        assert!(!store
+            .insert_internal_ips(&[row("172.16.0.0/12", 3), row("172.16.0.0/12", 4)])
+            .unwrap());
+        assert_eq!(
+            store.get_internal_ips("172.16.0.0/12").unwrap(),
+            vec![row("172.16.0.0/12", 2), row("172.16.0.0/12", 4)]
+        );
+        assert_eq!(
+            store.get_internal_ip("172.16.0.0/12", 2).unwrap(),
+            Some(row("172.16.0.0/12", 2))
+        );
+        assert_eq!(store.get_internal_ip("172.16.0.0/12", 3).unwrap(), None);
+        assert_eq!(store.next_internal_ip_offset("172.16.0.0/12").unwrap(), 5);
+        assert!(store
+            .first_free_internal_ip("172.16.0.0/12", i64::MAX)
+            .unwrap()
+            .is_none());
+
+        let current = row("172.16.0.0/12", 4);
+        let released = InternalIp {
+            released_at: 20,
+            ..current.clone()
+        };
+        assert!(store.swap_internal_ip(&current, &released).unwrap());
+        // current is stale now, so a second swap from it must not go through
+        let stale = InternalIp {
+            reserved_at: 30,
+            ..current.clone()
+        };
+        assert!(!store.swap_internal_ip(&current, &stale).unwrap());
+        assert_eq!(
+            store.get_internal_ips("172.16.0.0/12").unwrap(),
+            vec![row("172.16.0.0/12", 2), released]
+        );
+        assert_eq!(
+            store.get_internal_ips("10.0.0.0/8").unwrap(),
+            vec![row("10.0.0.0/8", 4)]
+        );
+
+        // only addresses released long enough ago are free, lowest offset first
+        let early = InternalIp {
+            released_at: 15,
+            ..row("172.16.0.0/12", 2)
+        };
+        assert!(store
+            .swap_internal_ip(&row("172.16.0.0/12", 2), &early)
+            .unwrap());
+        assert_eq!(
+            store.first_free_internal_ip("172.16.0.0/12", 14).unwrap(),
+            None
+        );
+        assert_eq!(
+            store.first_free_internal_ip("172.16.0.0/12", 20).unwrap(),
+            Some(early)
+        );
+        assert!(store
+            .first_free_internal_ip("10.0.0.0/8", i64::MAX)
+            .unwrap()
+            .is_none());
+    }
+
+    pub fn check_bans(store: &dyn ClientStore) {
+        let ban = BannedClient {
+            wg_pubkey: "one".to_string(),
+            reason: "abuse".to_string(),
+            banned_at: 42,
+        };
+        assert!(!store.is_banned("one").unwrap());
+        store.insert_ban(&ban).unwrap();
+        assert!(store.insert_ban(&ban).is_err());
+        assert!(store.is_banned("one").unwrap());
+        assert!(!store.is_banned("two").unwrap());
+        assert_eq!(store.get_bans().unwrap(), vec![ban]);
+        store.delete_ban("one").unwrap();
+        assert!(!store.is_banned("one").unwrap());
+        assert!(store.get_bans().unwrap().is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_db_uri() {
+        assert_eq!(
+            parse_db_uri("postgres://postgres@localhost/test").unwrap(),
+            DbUri::Postgres("postgres://postgres@localhost/test".to_string())
+        );
+        assert_eq!(
+            parse_db_uri("postgresql://postgres@10.0.0.1/test").unwrap(),
+            DbUri::Postgres("postgresql://postgres@10.0.0.1/test".to_string())
+        );
+        assert_eq!(
+            parse_db_uri("sqlite:///var/lib/rita/clients.db").unwrap(),
+            DbUri::Sqlite("/var/lib/rita/clients.db".to_string())
+        );
+        assert_eq!(parse_db_uri("memory://").unwrap(), DbUri::Memory);
+        assert!(parse_db_uri("sqlite://").is_err());
+        assert!(parse_db_uri("mysql://root@localhost/test").is_err());
+        assert!(parse_db_uri("").is_err());
+    }
+
+    #[test]
+    fn test_selector_matches() {
+        let client = Client {
+            mesh_ip: "fd00::1".to_string(),
+            wg_pubkey: "key".to_string(),
+            eth_address: "0xabc".to_string(),
+            email: "a@example.com".to_string(),
+            ..Default::default()
+        };
+        assert!(ClientSelector::record(&client).matches(&client));
+        assert!(ClientSelector::MeshIp("fd00::1".to_string()).matches(&client));
+        assert!(ClientSelector::Email("a@example.com".to_string()).matches(&client));
+        assert!(ClientSelector::All.matches(&client));
+        assert!(!ClientSelector::WgKey("other".to_string()).matches(&client));
+        let other = ClientSelector::Exact {
+            mesh_ip: "fd00::1".to_string(),
+            wg_pubkey: "other".to_string(),
+            eth_address: "0xabc".to_string(),
+        };
+        assert!(!other.matches(&client));
+    }
+}
@@ -1,104 +1,42 @@
+use crate::database::client_store::{ClientSelector, ClientStore, ClientUpdate};
+use crate::database::ip_allocator::{
+    claim_internal_ip, release_internal_ip, reserve_internal_ip, InternalIpRange,
+};
 use crate::database::secs_since_unix_epoch;
 use crate::database::struct_tools::client_to_new_db_client;
+use crate::database::struct_tools::to_exit_client;
 use crate::database::ONE_DAY;
-use exit_db::models::AssignedIps;
+use exit_db::models::{AssignedIps, BannedClient};
 use ipnetwork::{IpNetwork, Ipv6Network, NetworkSize};
-use rita_common::utils::ip_increment::increment;
 
-use crate::{get_db_pool, RitaExitError};
+use crate::{get_client_store, RitaExitError};
 use althea_kernel_interface::ExitClient;
 use althea_types::ExitClientIdentity;
-use diesel::dsl::{delete, exists};
-use diesel::prelude::{ExpressionMethods, PgConnection, QueryDsl, RunQueryDsl};
-use diesel::r2d2::ConnectionManager;
-use diesel::r2d2::PooledConnection;
-use diesel::select;
-use exit_db::{models, schema};
+use exit_db::models;
 use std::convert::TryInto;
-use std::net::Ipv4Addr;
-use std::net::{IpAddr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 // Default Subnet size assigned to each client
 const DEFAULT_CLIENT_SUBNET_SIZE: u8 = 56;
 
-/// Takes a list of clients and returns a sorted list of ip addresses spefically v4 since it
-/// can implement comparison operators
-fn get_internal_ips(clients: &[exit_db::models::Client]) -> Vec<Ipv4Addr> {
-    let mut list = Vec::with_capacity(clients.len());
-    for client in clients {
-        let client_internal_ip = client.internal_ip.parse();
-        match client_internal_ip {
-            Ok(address) => list.push(address),
-            Err(_e) => error!("Bad database entry! {:?}", client),
-        }
-    }
-    // this list should come sorted from the database, this just double checks
-    list.sort();
-    list
-}
-
-/// Gets the next available client ip, takes about O(n) time, we could make it faster by
-/// sorting on the database side but I've left that optimization on the vine for now
-pub fn get_next_client_ip(conn: &PgConnection) -> Result<IpAddr, Box<RitaExitError>> {
-    use self::schema::clients::dsl::clients;
-    let rita_exit = settings::get_rita_exit();
-    let exit_settings = rita_exit.exit_network;
-    let netmask = exit_settings.netmask;
-    let start_ip = exit_settings.exit_start_ip;
-    let gateway_ip = exit_settings.own_internal_ip;
-    // drop here to free up the settings lock, this codepath runs in parallel
-    drop(exit_settings);
-
-    let clients_list = match clients.load::<models::Client>(conn) {
-        Ok(a) => a,
-        Err(e) => return Err(Box::new(e.into())),
-    };
-    let ips_list = get_internal_ips(&clients_list);
-    let mut new_ip: IpAddr = start_ip.into();
-
-    // iterate until we find an open spot, yes converting to string and back is quite awkward
-    while ips_list.contains({
-        match &new_ip.to_string().parse() {
-            Ok(a) => a,
-            Err(e) => return Err(Box::new(e.clone().into())),
-        }
-    }) {
-        new_ip = match increment(new_ip, netmask) {
-            Ok(a) => a,
-            Err(e) => return Err(Box::new(e.into())),
-        };
-        if new_ip == gateway_ip {
-            new_ip = match increment(new_ip, netmask) {
-                Ok(a) => a,
-                Err(e) => return Err(Box::new(e.into())),
-            }
-        }
-    }
-    trace!(
-        "The new client's ip is {} selected using {:?}",
-        new_ip,
-        ips_list
-    );
+/// How many times an assigned_ips update is retried when other workers keep changing it
+const MAX_SUBNET_ATTEMPTS: usize = 16;
 
-    Ok(new_ip)
+/// Reserves the internal ip for a new client from the allocator, the caller is responsible for
+/// releasing it if the client never ends up in the database
+pub fn get_next_client_ip(store: &dyn ClientStore) -> Result<IpAddr, Box<RitaExitError>> {
+    let new_ip = reserve_internal_ip(store, &InternalIpRange::from_settings())?;
+    trace!("The new client's ip is {}", new_ip);
+    Ok(new_ip.into())
 }
 
 /// updates the last seen time
 pub fn update_client(
     client: &ExitClientIdentity,
     their_record: &models::Client,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<(), Box<RitaExitError>> {
-    use self::schema::clients::dsl::{
-        clients, email, eth_address, last_seen, mesh_ip, phone, wg_pubkey,
-    };
-    let ip = client.global.mesh_ip;
-    let wg = client.global.wg_public_key;
-    let key = client.global.eth_address;
-    let filtered_list = clients
-        .filter(mesh_ip.eq(ip.to_string()))
-        .filter(wg_pubkey.eq(wg.to_string()))
-        .filter(eth_address.eq(key.to_string().to_lowercase()));
+    let selector = ClientSelector::identity(client);
 
     if let Some(mail) = client.reg_details.email.clone() {
         if their_record.email != mail {
@@ -106,12 +44,7 @@ pub fn update_client(
                 "Client {} email has changed from {} to {} updating",
                 their_record.wg_pubkey, their_record.email, mail
             );
-            if let Err(e) = diesel::update(filtered_list.clone())
-                .set(email.eq(mail))
-                .execute(conn)
-            {
-                return Err(Box::new(e.into()));
-            }
+            store.update_clients(selector.clone(), ClientUpdate::Email(mail))?;
         }
     }
 
@@ -121,12 +54,7 @@ pub fn update_client(
                 "Client {} phonenumber has changed from {} to {} updating",
                 their_record.wg_pubkey, their_record.phone, number
             );
-            if let Err(e) = diesel::update(filtered_list.clone())
-                .set(phone.eq(number))
-                .execute(conn)
-            {
-                return Err(Box::new(e.into()));
-            }
+            store.update_clients(selector.clone(), ClientUpdate::Phone(number))?;
         }
     }
 
@@ -135,12 +63,7 @@ pub fn update_client(
     // update every 12 hours, no entry timeouts less than a day allowed
     if time_since_last_update > ONE_DAY / 2 {
         info!("Bumping client timestamp for {}", their_record.wg_pubkey);
-        if let Err(e) = diesel::update(filtered_list)
-            .set(last_seen.eq(secs_since_unix_epoch()))
-            .execute(conn)
-        {
-            return Err(Box::new(e.into()));
-        }
+        store.update_clients(selector, ClientUpdate::LastSeen(secs_since_unix_epoch()))?;
     }
 
     Ok(())
@@ -148,21 +71,12 @@ pub fn update_client(
 
 pub fn get_client(
     client: &ExitClientIdentity,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<Option<models::Client>, Box<RitaExitError>> {
-    use self::schema::clients::dsl::{clients, eth_address, mesh_ip, wg_pubkey};
     let ip = client.global.mesh_ip;
     let wg = client.global.wg_public_key;
     let key = client.global.eth_address;
-    let filtered_list = clients
-        .filter(mesh_ip.eq(ip.to_string()))
-        .filter(wg_pubkey.eq(wg.to_string()))
-        // TODO search for EIP-55 capitalized key string and add a fallback
-        // to upgrade entires that are using the old all lowercase format. This should
-        // simplify the code some and reduce calls to .to_lowercase and worst of all the chance
-        // that we might forget a lowercase call, which type-checking can't protect us from.
-        .filter(eth_address.eq(key.to_string().to_lowercase()));
-    match filtered_list.load::<models::Client>(conn) {
+    match store.get_clients(ClientSelector::identity(client)) {
         Ok(entry) => {
             if entry.len() > 1 {
                 let err_msg =
@@ -175,7 +89,7 @@ pub fn get_client(
             Ok(Some(entry[0].clone()))
         }
         Err(e) => {
-            error!("We failed to lookup the client {:?} with{:?}", mesh_ip, e);
+            error!("We failed to lookup the client {:?} with{:?}", ip, e);
             Err(Box::new(RitaExitError::MiscStringError(
                 "We failed to lookup the client!".to_string(),
             )))
@@ -187,128 +101,65 @@ pub fn get_client(
 pub fn verify_client(
     client: &ExitClientIdentity,
     client_verified: bool,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<(), Box<RitaExitError>> {
-    use self::schema::clients::dsl::*;
-    let ip = client.global.mesh_ip;
-    let wg = client.global.wg_public_key;
-    let key = client.global.eth_address;
-    let filtered_list = clients
-        .filter(mesh_ip.eq(ip.to_string()))
-        .filter(wg_pubkey.eq(wg.to_string()))
-        .filter(eth_address.eq(key.to_string().to_lowercase()));
-
-    if let Err(e) = diesel::update(filtered_list)
-        .set(verified.eq(client_verified))
-        .execute(conn)
-    {
-        return Err(Box::new(e.into()));
-    }
-
-    Ok(())
+    store.update_clients(
+        ClientSelector::identity(client),
+        ClientUpdate::Verified(client_verified),
+    )
 }
 
 /// Marks a client as verified in the database
 pub fn verify_db_client(
     client: &models::Client,
     client_verified: bool,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<(), Box<RitaExitError>> {
-    use self::schema::clients::dsl::*;
-    let ip = &client.mesh_ip;
-    let wg = &client.wg_pubkey;
-    let key = &client.eth_address;
-    let filtered_list = clients
-        .filter(mesh_ip.eq(ip.to_string()))
-        .filter(wg_pubkey.eq(wg.to_string()))
-        .filter(eth_address.eq(key.to_string().to_lowercase()));
-
-    if let Err(e) = diesel::update(filtered_list)
-        .set(verified.eq(client_verified))
-        .execute(conn)
-    {
-        return Err(Box::new(e.into()));
-    }
-
-    Ok(())
+    store.update_clients(
+        ClientSelector::record(client),
+        ClientUpdate::Verified(client_verified),
+    )
 }
 
 /// Increments the text message sent count in the database
 pub fn text_sent(
     client: &ExitClientIdentity,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
     val: i32,
 ) -> Result<(), Box<RitaExitError>> {
-    use self::schema::clients::dsl::*;
-    let ip = client.global.mesh_ip;
-    let wg = client.global.wg_public_key;
-    let key = client.global.eth_address;
-    let filtered_list = clients
-        .filter(mesh_ip.eq(ip.to_string()))
-        .filter(wg_pubkey.eq(wg.to_string()))
-        .filter(eth_address.eq(key.to_string().to_lowercase()));
-
-    if let Err(e) = diesel::update(filtered_list)
-        .set(text_sent.eq(val + 1))
-        .execute(conn)
-    {
-        return Err(Box::new(e.into()));
-    }
-
-    Ok(())
+    store.update_clients(
+        ClientSelector::identity(client),
+        ClientUpdate::TextSent(val + 1),
+    )
 }
 
 fn client_exists(
     client: &ExitClientIdentity,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<bool, Box<RitaExitError>> {
-    use self::schema::clients::dsl::*;
     trace!("Checking if client exists");
-    let ip = client.global.mesh_ip;
-    let wg = client.global.wg_public_key;
-    let key = client.global.eth_address;
-    let filtered_list = clients
-        .filter(mesh_ip.eq(ip.to_string()))
-        .filter(wg_pubkey.eq(wg.to_string()))
-        .filter(eth_address.eq(key.to_string().to_lowercase()));
-    Ok(match select(exists(filtered_list)).get_result(conn) {
-        Ok(a) => a,
-        Err(e) => return Err(Box::new(e.into())),
-    })
+    store.client_exists(ClientSelector::identity(client))
 }
 
 /// True if there is any client with the same eth address, wg key, or ip address already registered
 pub fn client_conflict(
     client: &ExitClientIdentity,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<bool, Box<RitaExitError>> {
-    use self::schema::clients::dsl::*;
     // we can't possibly have a conflict if we have exactly this client already
     // since client exists checks all major details this is safe and will return false
     // if it's not exactly the same client
-    if client_exists(client, conn)? {
+    if client_exists(client, store)? {
         return Ok(false);
     }
     trace!("Checking if client exists");
     let ip = client.global.mesh_ip;
     let wg = client.global.wg_public_key;
     let key = client.global.eth_address;
-    let ip_match = clients.filter(mesh_ip.eq(ip.to_string()));
-    let wg_key_match = clients.filter(wg_pubkey.eq(wg.to_string()));
-    let eth_address_match = clients.filter(eth_address.eq(key.to_string().to_lowercase()));
-
-    let ip_exists = match select(exists(ip_match)).get_result(conn) {
-        Ok(a) => a,
-        Err(e) => return Err(Box::new(e.into())),
-    };
-    let wg_exists = match select(exists(wg_key_match)).get_result(conn) {
-        Ok(a) => a,
-        Err(e) => return Err(Box::new(e.into())),
-    };
-    let eth_exists = match select(exists(eth_address_match)).get_result(conn) {
-        Ok(a) => a,
-        Err(e) => return Err(Box::new(e.into())),
-    };
+    let ip_exists = store.client_exists(ClientSelector::MeshIp(ip.to_string()))?;
+    let wg_exists = store.client_exists(ClientSelector::WgKey(wg.to_string()))?;
+    let eth_exists =
+        store.client_exists(ClientSelector::EthAddress(key.to_string().to_lowercase()))?;
 
     info!(
         "Signup conflict ip {} eth {} wg {}",
@@ -321,29 +172,22 @@ pub fn client_conflict(
 /// available_subnets in assigned_ips database
 pub fn delete_client(
     client: ExitClient,
-    connection: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<(), Box<RitaExitError>> {
-    use self::schema::assigned_ips::dsl::{assigned_ips, subnet};
-    use self::schema::clients::dsl::*;
     info!("Deleting clients {:?} in database", client);
 
-    let mesh_ip_string = client.mesh_ip.to_string();
-    let statement = clients.find(&mesh_ip_string);
+    let selector = ClientSelector::MeshIp(client.mesh_ip.to_string());
+
+    let records = store.get_clients(selector.clone())?;
 
     // Add the reclaimed subnet to available subnets
-    let filtered_list = clients
-        .select(internet_ipv6)
-        .filter(mesh_ip.eq(&mesh_ip_string));
-    let mut client_sub = match filtered_list.load::<String>(connection) {
-        Ok(a) => a,
-        Err(e) => return Err(Box::new(e.into())),
-    };
+    let mut client_sub: Vec<String> = records.iter().map(|c| c.internet_ipv6.clone()).collect();
 
-    let filtered_list = assigned_ips.select(subnet);
-    let exit_sub = match filtered_list.load::<String>(connection) {
-        Ok(a) => a,
-        Err(e) => return Err(Box::new(e.into())),
-    };
+    let exit_sub: Vec<String> = store
+        .get_assigned_ips()?
+        .into_iter()
+        .map(|a| a.subnet)
+        .collect();
 
     if let Some(client_sub) = client_sub.pop() {
         if !client_sub.is_empty() {
@@ -352,16 +196,127 @@ pub fn delete_client(
                 "For reclaiming subnets, exit subs are: {:?} and client subs are {:?}",
                 exit_sub, client_sub
             );
-            reclaim_all_ip_subnets(client_sub, exit_sub, connection)?;
+            reclaim_all_ip_subnets(client_sub, exit_sub, store)?;
         }
     }
 
-    if let Err(e) = delete(statement).execute(connection) {
-        return Err(Box::new(e.into()));
-    };
+    store.delete_clients(selector)?;
+
+    // only once the client is gone can its internal ip be handed to someone else
+    let range = InternalIpRange::from_settings();
+    for record in records {
+        match record.internal_ip.parse() {
+            Ok(ip) => release_internal_ip(store, &range, ip)?,
+            Err(_) => error!("Bad database entry! {:?}", record),
+        }
+    }
     Ok(())
 }
 
+/// Bans a wg key from the exit and deletes any client using it, the client's tunnel is torn down
+/// once the exit loop no longer finds it in the store and signing up again will be denied
+pub fn ban_client(
+    wg_pubkey: &str,
+    reason: String,
+    store: &dyn ClientStore,
+) -> Result<(), Box<RitaExitError>> {
+    if !store.is_banned(wg_pubkey)? {
+        info!("Banning {} because {}", wg_pubkey, reason);
+        store.insert_ban(&BannedClient {
+            wg_pubkey: wg_pubkey.to_string(),
+            reason,
+            banned_at: secs_since_unix_epoch(),
+        })?;
+    }
+    for record in store.get_clients(ClientSelector::WgKey(wg_pubkey.to_string()))? {
+        delete_client(to_exit_client(record)?, store)?;
+    }
+    Ok(())
+}
+
+/// Moves a client to a new internal ip, either the one given or the next free one. The old ip
+/// goes back in the pool but isn't handed out again for INTERNAL_IP_GRACE_PERIOD, so the client
+/// can keep using it until it picks up the change the next time it checks its status
+pub fn set_client_internal_ip(
+    record: &models::Client,
+    new_ip: Option<Ipv4Addr>,
+    store: &dyn ClientStore,
+) -> Result<Ipv4Addr, Box<RitaExitError>> {
+    let range = InternalIpRange::from_settings();
+    let ip = match new_ip {
+        Some(ip) => {
+            claim_internal_ip(store, &range, ip)?;
+            ip
+        }
+        None => reserve_internal_ip(store, &range)?,
+    };
+    if let Err(e) = store.update_clients(
+        ClientSelector::record(record),
+        ClientUpdate::InternalIp(ip.to_string()),
+    ) {
+        release_internal_ip(store, &range, ip)?;
+        return Err(e);
+    }
+    info!(
+        "Moved {} from internal ip {} to {}",
+        record.wg_pubkey, record.internal_ip, ip
+    );
+    match record.internal_ip.parse() {
+        Ok(old_ip) => release_internal_ip(store, &range, old_ip)?,
+        Err(_) => error!("Bad database entry! {:?}", record),
+    }
+    Ok(ip)
+}
+
+/// Gives a client a new ipv6 subnet from this exit's subnet, returning its old subnets to the
+/// exits they came from. Other exits in the cluster assign the client a new subnet the next time
+/// it checks in with them. None if this exit doesn't support ipv6
+pub fn reassign_client_ipv6(
+    record: &models::Client,
+    store: &dyn ClientStore,
+) -> Result<Option<IpNetwork>, Box<RitaExitError>> {
+    let exit_sub = match settings::get_rita_exit().exit_network.subnet {
+        Some(exit_sub) => exit_sub,
+        None => return Ok(None),
+    };
+    let exit_subs: Vec<String> = store
+        .get_assigned_ips()?
+        .into_iter()
+        .map(|a| a.subnet)
+        .collect();
+    // the old subnets are still in use until the client is moved off them, so the new subnet
+    // can't be one of them and the client is never left without one if something fails
+    let internet_ip = get_client_subnet(exit_sub, store)?;
+    if let Err(e) = store.update_clients(
+        ClientSelector::record(record),
+        ClientUpdate::InternetIpv6(internet_ip.to_string()),
+    ) {
+        let new_sub = internet_ip.to_string();
+        reclaim_all_ip_subnets(vec![&new_sub], exit_subs, store)?;
+        return Err(e);
+    }
+    if !record.internet_ipv6.is_empty() {
+        reclaim_all_ip_subnets(record.internet_ipv6.split(',').collect(), exit_subs, store)?;
+    }
+    info!(
+        "Moved {} from ipv6 {} to {}",
+        record.wg_pubkey, record.internet_ipv6, internet_ip
+    );
+    Ok(Some(internet_ip))
+}
+
+/// The allocation state of an exit subnet, there should only ever be one
+fn get_subnet_entries(
+    sub: &str,
+    store: &dyn ClientStore,
+) -> Result<Vec<models::AssignedIps>, Box<RitaExitError>> {
+    Ok(store
+        .get_assigned_ips()?
+        .into_iter()
+        .filter(|a| a.subnet == sub)
+        .collect())
+}
+
 /// Given a vector of client subnet and exit subnets, reclaim all client subnets into the given exit subnets
 /// This relies on the fact that there are no overlapping subnets
 /// For example, if client has Ip addrs : "fbad::1000/64,feee::1000/64"
@@ -371,10 +326,8 @@ pub fn delete_client(
 fn reclaim_all_ip_subnets(
     client_sub: Vec<&str>,
     exit_sub: Vec<String>,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<(), Box<RitaExitError>> {
-    use self::schema::assigned_ips::dsl::{assigned_ips, available_subnets, subnet};
-
     for client_ip in client_sub {
         for exit_ip in &exit_sub {
             let c_net: IpNetwork = client_ip.parse().expect("Unable to parse client subnet");
@@ -382,58 +335,7 @@ fn reclaim_all_ip_subnets(
             if e_net.contains(c_net.ip()) {
                 let index = generate_index_from_subnet(e_net, c_net)?;
                 info!("Reclaimed index is: {:?}", index);
-
-                let filtered_list = assigned_ips.filter(subnet.eq(exit_ip));
-                let res = filtered_list.load::<models::AssignedIps>(conn);
-
-                match res {
-                    Ok(mut a) => {
-                        if a.len() > 1 {
-                            error!("Received multiple assigned ip entries for a singular subnet! Error");
-                        }
-                        let a_ip = match a.pop() {
-                            Some(a) => a,
-                            None => {
-                                return Err(Box::new(RitaExitError::MiscStringError(
-                                    "Unable to retrive assigned ip database".to_string(),
-                                )))
-                            }
-                        };
-                        let mut avail_ips = a_ip.available_subnets;
-                        if avail_ips.is_empty() {
-                            avail_ips.push_str(&index.to_string())
-                        } else {
-                            // if our index is '10', we need to append ",10" to the end
-                            let mut new_str = ",".to_owned();
-                            new_str.push_str(&index.to_string());
-                            // If avail_ips does not contains '"," + "index"' and (avail ips has only one entry and does not contains 'index')
-                            if !(avail_ips.contains(&new_str)
-                                || (!avail_ips.contains(',')
-                                    && avail_ips.contains(&index.to_string())))
-                            {
-                                avail_ips.push_str(&new_str);
-                            } else {
-                                error!("IPV6 ERROR: We tried adding {:?} to string {:?}, how did we get in this position?", index, avail_ips);
-                            }
-                        }
-                        info!(
-                            "We are updating database with reclaim string: {:?}",
-                            avail_ips
-                        );
-                        if let Err(e) = diesel::update(assigned_ips.find(exit_ip))
-                            .set(available_subnets.eq(avail_ips))
-                            .execute(conn)
-                        {
-                            return Err(Box::new(e.into()));
-                        };
-                    }
-                    Err(e) => {
-                        error!(
-                            "unable to add a reclaimed ip to database with error: {:?}",
-                            e
-                        );
-                    }
-                }
+                reclaim_subnet_index(exit_ip, index, store)?;
 
                 // After we reclaim an index, we break from the loop. The prevents duplicate reclaiming when two instances have the same subnet
                 break;
@@ -444,31 +346,85 @@ fn reclaim_all_ip_subnets(
     Ok(())
 }
 
+/// Adds index to the available subnets of the exit subnet sub. The record is swapped rather
+/// than overwritten so a subnet handed out or reclaimed by another worker in the meantime isn't
+/// lost, if the record changed under us we read it again and retry
+fn reclaim_subnet_index(
+    sub: &str,
+    index: u64,
+    store: &dyn ClientStore,
+) -> Result<(), Box<RitaExitError>> {
+    for _ in 0..MAX_SUBNET_ATTEMPTS {
+        let mut a = get_subnet_entries(sub, store)?;
+        if a.len() > 1 {
+            error!("Received multiple assigned ip entries for a singular subnet! Error");
+        }
+        let current = match a.pop() {
+            Some(a) => a,
+            None => {
+                return Err(Box::new(RitaExitError::MiscStringError(
+                    "Unable to retrive assigned ip database".to_string(),
+                )))
+            }
+        };
+        let mut avail_ips = current.available_subnets.clone();
+        if avail_ips.is_empty() {
+            avail_ips.push_str(&index.to_string())
+        } else {
+            // if our index is '10', we need to append ",10" to the end
+            let mut new_str = ",".to_owned();
+            new_str.push_str(&index.to_string());
+            // If avail_ips does not contains '"," + "index"' and (avail ips has only one entry and does not contains 'index')
+            if !(avail_ips.contains(&new_str)
+                || (!avail_ips.contains(',') && avail_ips.contains(&index.to_string())))
+            {
+                avail_ips.push_str(&new_str);
+            } else {
+                error!("IPV6 ERROR: We tried adding {:?} to string {:?}, how did we get in this position?", index, avail_ips);
+                return Ok(());
+            }
+        }
+        info!(
+            "We are updating database with reclaim string: {:?}",
+            avail_ips
+        );
+        let next = AssignedIps {
+            available_subnets: avail_ips,
+            ..current.clone()
+        };
+        if store.swap_assigned_ips(&current, &next)? {
+            return Ok(());
+        }
+        trace!(
+            "Assigned ips for {} changed while reclaiming, retrying",
+            sub
+        );
+    }
+    Err(Box::new(RitaExitError::MiscStringError(format!(
+        "Unable to reclaim subnet index {index} of {sub}, the subnet is too busy"
+    ))))
+}
+
 // for backwards compatibility with entires that do not have a timestamp
 // new entires will be initialized and updated as part of the normal flow
 pub fn set_client_timestamp(
     client: ExitClient,
-    connection: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<(), Box<RitaExitError>> {
-    use self::schema::clients::dsl::*;
     info!("Setting timestamp for client {:?}", client);
 
-    if let Err(e) = diesel::update(clients.find(&client.mesh_ip.to_string()))
-        .set(last_seen.eq(secs_since_unix_epoch()))
-        .execute(connection)
-    {
-        return Err(Box::new(e.into()));
-    };
-    Ok(())
+    store.update_clients(
+        ClientSelector::MeshIp(client.mesh_ip.to_string()),
+        ClientUpdate::LastSeen(secs_since_unix_epoch()),
+    )
 }
 
 // we match on email not key? that has interesting implications for
 // shared emails
 pub fn update_mail_sent_time(
     client: &ExitClientIdentity,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<(), Box<RitaExitError>> {
-    use self::schema::clients::dsl::{clients, email, email_sent_time};
     let mail_addr = match client.clone().reg_details.email {
         Some(mail) => mail,
         None => {
@@ -478,56 +434,34 @@ pub fn update_mail_sent_time(
         }
     };
 
-    if let Err(e) = diesel::update(clients.filter(email.eq(mail_addr)))
-        .set(email_sent_time.eq(secs_since_unix_epoch()))
-        .execute(conn)
-    {
-        return Err(Box::new(e.into()));
-    };
-
-    Ok(())
-}
-
-/// Gets the Postgres database connection from the threadpool, since there are dedicated
-/// connections for each threadpool member error if non is available right away
-pub fn get_database_connection(
-) -> Result<PooledConnection<ConnectionManager<PgConnection>>, Box<RitaExitError>> {
-    match get_db_pool() {
-        Some(connection) => Ok(connection),
-        None => {
-            error!("No available db connection!");
-            Err(Box::new(RitaExitError::MiscStringError(
-                "No Database connection available!".to_string(),
-            )))
-        }
-    }
+    store.update_clients(
+        ClientSelector::Email(mail_addr),
+        ClientUpdate::EmailSentTime(secs_since_unix_epoch()),
+    )
 }
 
 pub fn create_or_update_user_record(
-    conn: &PgConnection,
+    store: &dyn ClientStore,
     client: &ExitClientIdentity,
     user_country: String,
 ) -> Result<models::Client, Box<RitaExitError>> {
-    use self::schema::clients::dsl::clients;
-
     // Retrieve exit subnet
     let rita_exit = settings::get_rita_exit();
     let exit_settings = rita_exit.exit_network;
     let subnet = exit_settings.subnet;
 
     // If subnet isnt already present in database, create it
-    let mut subnet_entry = None;
     if let Some(subnet) = subnet {
-        subnet_entry = Some(initialize_subnet_datastore(subnet, conn)?);
+        let subnet_entry = initialize_subnet_datastore(subnet, store)?;
         info!("Subnet Database entry: {:?}", subnet_entry);
     }
 
-    if let Some(val) = get_client(client, conn)? {
+    if let Some(val) = get_client(client, store)? {
         // Give ipv6 if not present
         if let Some(subnet) = subnet {
-            assign_ip_to_client(val.mesh_ip.clone(), subnet, conn)?;
+            assign_ip_to_client(val.mesh_ip.clone(), subnet, store)?;
         }
-        update_client(client, &val, conn)?;
+        update_client(client, &val, store)?;
         Ok(val)
     } else {
         info!(
@@ -535,33 +469,49 @@ pub fn create_or_update_user_record(
             client.global.wg_public_key
         );
 
-        let new_ip = get_next_client_ip(conn)?;
+        let new_ip = get_next_client_ip(store)?;
 
-        let internet_ip = if let (Some(subnet), Some(subnet_entry)) = (subnet, subnet_entry) {
-            Some(get_client_subnet(subnet, subnet_entry, conn)?)
-        } else {
-            None
-        };
+        let res = insert_new_client(store, client, user_country, new_ip, subnet);
+        if res.is_err() {
+            if let IpAddr::V4(new_ip) = new_ip {
+                if let Err(e) =
+                    release_internal_ip(store, &InternalIpRange::from_settings(), new_ip)
+                {
+                    error!("Unable to release internal ip {} with {:?}", new_ip, e);
+                }
+            }
+        }
+        res
+    }
+}
 
-        let c = client_to_new_db_client(client, new_ip, user_country, internet_ip);
+/// Writes a new client record with the internal ip reserved for it
+fn insert_new_client(
+    store: &dyn ClientStore,
+    client: &ExitClientIdentity,
+    user_country: String,
+    new_ip: IpAddr,
+    subnet: Option<IpNetwork>,
+) -> Result<models::Client, Box<RitaExitError>> {
+    let internet_ip = match subnet {
+        Some(subnet) => Some(get_client_subnet(subnet, store)?),
+        None => None,
+    };
 
-        info!("Inserting new client {}", client.global.wg_public_key);
-        if let Err(e) = diesel::insert_into(clients).values(&c).execute(conn) {
-            return Err(Box::new(e.into()));
-        }
+    let c = client_to_new_db_client(client, new_ip, user_country, internet_ip);
 
-        Ok(c)
-    }
+    info!("Inserting new client {}", client.global.wg_public_key);
+    store.insert_client(&c)?;
+
+    Ok(c)
 }
 
 /// This function creates an entry for the given subnet in the assgined_ips table if doesnt exist
 fn initialize_subnet_datastore(
     sub: IpNetwork,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<models::AssignedIps, Box<RitaExitError>> {
-    use self::schema::assigned_ips::dsl::{assigned_ips, subnet};
-    let filtered_list = assigned_ips.filter(subnet.eq(sub.to_string()));
-    match filtered_list.load::<models::AssignedIps>(conn) {
+    match get_subnet_entries(&sub.to_string(), store) {
         Err(_) => {
             // When there is no entry create an entry in the database
             let record = AssignedIps {
@@ -569,12 +519,7 @@ fn initialize_subnet_datastore(
                 available_subnets: "".to_string(),
                 iterative_index: 0,
             };
-            if let Err(e) = diesel::insert_into(assigned_ips)
-                .values(&record)
-                .execute(conn)
-            {
-                return Err(Box::new(e.into()));
-            };
+            store.insert_assigned_ips(&record)?;
             Ok(record)
         }
         Ok(mut a) => {
@@ -589,12 +534,7 @@ fn initialize_subnet_datastore(
                     iterative_index: 0,
                 };
                 info!("Received an empty vector, adding new subnet entry");
-                if let Err(e) = diesel::insert_into(assigned_ips)
-                    .values(&record)
-                    .execute(conn)
-                {
-                    return Err(Box::new(e.into()));
-                };
+                store.insert_assigned_ips(&record)?;
                 return Ok(record);
             }
             Ok(a.pop().unwrap())
@@ -608,42 +548,49 @@ fn initialize_subnet_datastore(
 /// 3.) Retrieve assigned_ips struct from database table for given exit subnet. Check for any available subnet index
 /// 4.) If not get the subnets next iterative index instead
 /// 5.) Use this index to retrieve the 'ith' iterative subnet in the larger subnet
+/// 6.) Swap the assigned_ips struct for one without that index, starting over if another worker changed it first
 /// Max Iterative index should theoretically never be reached because we choose subnets from deleted clients before generating
 /// an iterative subnet
 pub fn get_client_subnet(
     sub: IpNetwork,
-    ip_tracker: AssignedIps,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
+) -> Result<IpNetwork, Box<RitaExitError>> {
+    let subprefix = settings::get_rita_exit()
+        .get_client_subnet_size()
+        .unwrap_or(DEFAULT_CLIENT_SUBNET_SIZE);
+    take_client_subnet(sub, subprefix, store)
+}
+
+/// get_client_subnet with the client subnet size passed in rather than read from the settings
+fn take_client_subnet(
+    sub: IpNetwork,
+    subprefix: u8,
+    store: &dyn ClientStore,
 ) -> Result<IpNetwork, Box<RitaExitError>> {
-    use self::schema::assigned_ips::dsl::{assigned_ips, available_subnets, iterative_index};
-    use self::schema::clients::dsl::{clients, internet_ipv6};
     // Get our assigned subnet
     info!("Received Exit assigned ipv6 subnet: {}", sub);
 
-    // Make sql query to get list of all client subnets in use
-    // SELECT internet_ipv6 FROM <TABLE>
-    let filtered_list = clients.select(internet_ipv6);
-    let ip_list = match filtered_list.load::<String>(conn) {
-        Ok(a) => a,
-        Err(e) => return Err(Box::new(e.into())),
-    };
-
-    let mut index: Option<u64> = None;
-
-    // First check for any available subnets to reclaim
-    if !ip_tracker.available_subnets.is_empty() {
-        // available ips are stored in the form of "1,2,5" etc
-        if let Some((remaining, i)) = ip_tracker.available_subnets.rsplit_once(',') {
-            // set database available subnets to remainging
-            if let Err(e) = diesel::update(assigned_ips.find(sub.to_string()))
-                .set(available_subnets.eq(remaining))
-                .execute(conn)
-            {
-                return Err(Box::new(e.into()));
-            };
-
-            index = match i.parse() {
-                Ok(a) => Some(a),
+    // Get the list of all client subnets in use
+    let ip_list: Vec<String> = store
+        .get_clients(ClientSelector::All)?
+        .into_iter()
+        .map(|c| c.internet_ipv6)
+        .collect();
+
+    for _ in 0..MAX_SUBNET_ATTEMPTS {
+        let ip_tracker = initialize_subnet_datastore(sub, store)?;
+        let mut tracker = ip_tracker.clone();
+
+        let index: u64 = if !ip_tracker.available_subnets.is_empty() {
+            // First check for any available subnets to reclaim, available ips are stored in the
+            // form of "1,2,5" etc, a singular entry is just "2"
+            let (remaining, i) = ip_tracker
+                .available_subnets
+                .rsplit_once(',')
+                .unwrap_or(("", &ip_tracker.available_subnets));
+            tracker.available_subnets = remaining.to_string();
+            match i.parse() {
+                Ok(a) => a,
                 Err(e) => {
                     return Err(Box::new(RitaExitError::MiscStringError(format!(
                         "Unable to assign user ipv6 subnet when parsing latest index: {e}"
@@ -651,81 +598,53 @@ pub fn get_client_subnet(
                 }
             }
         } else {
-            // This is case of singular entry in, for exmaple "2"
-            // set database available subnets to ""
-            if let Err(e) = diesel::update(assigned_ips.find(sub.to_string()))
-                .set(available_subnets.eq(""))
-                .execute(conn)
-            {
-                return Err(Box::new(e.into()));
-            };
-
-            index = match ip_tracker.available_subnets.parse() {
-                Ok(a) => Some(a),
+            // Next get iterative index to generate next subnet
+            match ip_tracker.iterative_index.try_into() {
+                Ok(a) => {
+                    tracker.iterative_index = ip_tracker.iterative_index + 1;
+                    a
+                }
                 Err(e) => {
                     return Err(Box::new(RitaExitError::MiscStringError(format!(
-                        "Unable to assign user ipv6 subnet: {e}"
+                        "Unable to assign user ipv6 subnet when parsing iterative index: {e}"
                     ))));
                 }
             }
-        }
-    }
+        };
 
-    let mut used_iterative_index = false;
-    // Next get iterative index to generate next subnet. If index is not already set from available subnets, set it here from database
-    if index.is_none() {
-        used_iterative_index = true;
-        index = Some(match ip_tracker.iterative_index.try_into() {
-            Ok(a) => a,
+        // Once we get the index, generate the subnet
+        let addr = match generate_iterative_client_subnet(sub, index, subprefix) {
+            Ok(addr) => addr,
             Err(e) => {
-                return Err(Box::new(RitaExitError::MiscStringError(format!(
-                    "Unable to assign user ipv6 subnet when parsing iterative index: {e}"
-                ))));
-            }
-        });
-    }
-
-    // Once we get the index, generate the subnet
-    match generate_iterative_client_subnet(
-        sub,
-        index.unwrap(),
-        settings::get_rita_exit()
-            .get_client_subnet_size()
-            .unwrap_or(DEFAULT_CLIENT_SUBNET_SIZE),
-    ) {
-        Ok(addr) => {
-            // increment iterative index
-            if used_iterative_index {
-                let new_ind = (index.unwrap() + 1) as i64;
-                if let Err(e) = diesel::update(assigned_ips.find(sub.to_string()))
-                    .set(iterative_index.eq(new_ind))
-                    .execute(conn)
-                {
-                    return Err(Box::new(e.into()));
-                };
+                error!(
+                    "Unable to retrieve an available ipv6 subnet for client: {}",
+                    e
+                );
+                return Err(e);
             }
+        };
 
-            // ip_list is a vector of a list of ipaddrs, so we check each ipaddr to see if it is already used
-            if !ip_list
-                .iter()
-                .any(|ipv6_list| ipv6_list.contains(&addr.to_string()))
-            {
-                Ok(addr)
-            } else {
-                error!("Chosen subnet: {:?} is in use! Race condition hit", addr);
-                Err(Box::new(RitaExitError::MiscStringError(format!(
-                    "Unable to assign user ipv6 subnet. Chosen subnet {addr:?} is in use"
-                ))))
-            }
-        }
-        Err(e) => {
-            error!(
-                "Unable to retrieve an available ipv6 subnet for client: {}",
-                e
-            );
-            Err(e)
+        if !store.swap_assigned_ips(&ip_tracker, &tracker)? {
+            trace!("Assigned ips for {} changed while assigning, retrying", sub);
+            continue;
         }
+
+        // ip_list is a vector of a list of ipaddrs, so we check each ipaddr to see if it is already used
+        return if !ip_list
+            .iter()
+            .any(|ipv6_list| ipv6_list.contains(&addr.to_string()))
+        {
+            Ok(addr)
+        } else {
+            error!("Chosen subnet: {:?} is in use! Race condition hit", addr);
+            Err(Box::new(RitaExitError::MiscStringError(format!(
+                "Unable to assign user ipv6 subnet. Chosen subnet {addr:?} is in use"
+            ))))
+        };
     }
+    Err(Box::new(RitaExitError::MiscStringError(format!(
+        "Unable to assign user ipv6 subnet, {sub} is too busy"
+    ))))
 }
 
 /// Take an index i, a larger subnet and a smaller subnet length and generate the ith smaller subnet in the larger subnet
@@ -832,18 +751,15 @@ fn generate_index_from_subnet(
 fn assign_ip_to_client(
     client_mesh_ip: String,
     exit_sub: IpNetwork,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<IpNetwork, Box<RitaExitError>> {
     // check if ipv6 list already has an ip in its subnet
-    use self::schema::clients::dsl::{clients, internet_ipv6, mesh_ip};
-
-    let filtered_list = clients
-        .select(internet_ipv6)
-        .filter(mesh_ip.eq(&client_mesh_ip));
-    let mut sub = match filtered_list.load::<String>(conn) {
-        Ok(a) => a,
-        Err(e) => return Err(Box::new(e.into())),
-    };
+    let selector = ClientSelector::MeshIp(client_mesh_ip.clone());
+    let mut sub: Vec<String> = store
+        .get_clients(selector.clone())?
+        .into_iter()
+        .map(|c| c.internet_ipv6)
+        .collect();
 
     let client_ipv6_list = sub.pop();
 
@@ -859,43 +775,43 @@ fn assign_ip_to_client(
                 }
             }
             // If code hasnt returned yet, we need to add the ip to the list
-            let subnet_entry = initialize_subnet_datastore(exit_sub, conn)?;
-            let internet_ip = get_client_subnet(exit_sub, subnet_entry.clone(), conn)?;
+            let internet_ip = get_client_subnet(exit_sub, store)?;
             let mut new_str = ",".to_owned();
             new_str.push_str(&internet_ip.to_string());
             list_str.push_str(&new_str);
-            info!("Initializing ipv6 addrs for existing clients, IP: {}, is given ip {:?}, subnet entry is {:?}", client_mesh_ip, list_str.clone(), subnet_entry);
-            if let Err(e) = diesel::update(clients.find(client_mesh_ip))
-                .set(internet_ipv6.eq(list_str))
-                .execute(conn)
-            {
-                return Err(Box::new(e.into()));
-            };
+            info!(
+                "Initializing ipv6 addrs for existing clients, IP: {}, is given ip {:?}",
+                client_mesh_ip,
+                list_str.clone()
+            );
+            store.update_clients(selector, ClientUpdate::InternetIpv6(list_str))?;
             Ok(internet_ip)
         } else {
             // List is empty
-            let subnet_entry = initialize_subnet_datastore(exit_sub, conn)?;
-            let internet_ip = get_client_subnet(exit_sub, subnet_entry.clone(), conn)?;
-            info!("Initializing ipv6 addrs for existing clients, IP: {}, is given ip {:?}, subnet entry is {:?}", client_mesh_ip, internet_ip.clone(), subnet_entry);
-            if let Err(e) = diesel::update(clients.find(client_mesh_ip))
-                .set(internet_ipv6.eq(internet_ip.to_string()))
-                .execute(conn)
-            {
-                return Err(Box::new(e.into()));
-            };
+            let internet_ip = get_client_subnet(exit_sub, store)?;
+            info!(
+                "Initializing ipv6 addrs for existing clients, IP: {}, is given ip {:?}",
+                client_mesh_ip,
+                internet_ip.clone()
+            );
+            store.update_clients(
+                selector,
+                ClientUpdate::InternetIpv6(internet_ip.to_string()),
+            )?;
             Ok(internet_ip)
         }
     } else {
         // The client doesnt not have an appropriate ipv6 addr for our subnet, assign it one
-        let subnet_entry = initialize_subnet_datastore(exit_sub, conn)?;
-        let internet_ip = get_client_subnet(exit_sub, subnet_entry.clone(), conn)?;
-        info!("Initializing ipv6 addrs for existing clients, IP: {}, is given ip {:?}, subnet entry is {:?}", client_mesh_ip, internet_ip.clone(), subnet_entry);
-        if let Err(e) = diesel::update(clients.find(client_mesh_ip))
-            .set(internet_ipv6.eq(internet_ip.to_string()))
-            .execute(conn)
-        {
-            return Err(Box::new(e.into()));
-        };
+        let internet_ip = get_client_subnet(exit_sub, store)?;
+        info!(
+            "Initializing ipv6 addrs for existing clients, IP: {}, is given ip {:?}",
+            client_mesh_ip,
+            internet_ip.clone()
+        );
+        store.update_clients(
+            selector,
+            ClientUpdate::InternetIpv6(internet_ip.to_string()),
+        )?;
         Ok(internet_ip)
     }
 }
@@ -923,8 +839,8 @@ pub fn get_client_ipv6(
         }
 
         // If no ip has been returned, an ip has not been setup, so we assign an ip in the database
-        let conn = get_database_connection()?;
-        let ip_net = assign_ip_to_client(client_mesh_ip.to_string(), exit_sub, &conn)?;
+        let store = get_client_store();
+        let ip_net = assign_ip_to_client(client_mesh_ip.to_string(), exit_sub, store.as_ref())?;
         Ok(Some(ip_net))
     } else {
         // This exit doesnt support ipv6
@@ -935,6 +851,7 @@ pub fn get_client_ipv6(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::database::client_store::memory::MemoryStore;
 
     /// Test iterative subnet generation
     #[test]
@@ -1166,6 +1083,51 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_reclaim_into_store() {
+        let store = MemoryStore::default();
+        for sub in ["fbad::/40", "feee::/40"] {
+            initialize_subnet_datastore(sub.parse().unwrap(), &store).unwrap();
+        }
+        let exit_sub: Vec<String> = vec!["fbad::/40".to_string(), "feee::/40".to_string()];
+        reclaim_all_ip_subnets(vec!["fbad:0:2::/48"], exit_sub.clone(), &store).unwrap();
+        reclaim_all_ip_subnets(vec!["fbad:0:5::/48", "feee:0:1::/48"], exit_sub, &store).unwrap();
+
+        let entries = store.get_assigned_ips().unwrap();
+        assert_eq!(entries[0].subnet, "fbad::/40");
+        assert_eq!(entries[0].available_subnets, "2,5");
+        assert_eq!(entries[1].available_subnets, "1");
+        // an existing entry is left alone
+        let entry = initialize_subnet_datastore("fbad::/40".parse().unwrap(), &store).unwrap();
+        assert_eq!(entry.available_subnets, "2,5");
+    }
+
+    #[test]
+    fn test_take_and_reclaim_subnets() {
+        let store = MemoryStore::default();
+        let sub: IpNetwork = "fbad::/40".parse().unwrap();
+        let first = take_client_subnet(sub, 48, &store).unwrap();
+        let second = take_client_subnet(sub, 48, &store).unwrap();
+        assert_eq!(first, "fbad::/48".parse().unwrap());
+        assert_eq!(second, "fbad:0:1::/48".parse().unwrap());
+
+        let first_str = first.to_string();
+        reclaim_all_ip_subnets(vec![&first_str], vec![sub.to_string()], &store).unwrap();
+        let entry = store.get_assigned_ips().unwrap().pop().unwrap();
+        assert_eq!(entry.available_subnets, "0");
+        assert_eq!(entry.iterative_index, 2);
+
+        // reclaimed subnets are handed out before new ones
+        assert_eq!(take_client_subnet(sub, 48, &store).unwrap(), first);
+        assert_eq!(
+            take_client_subnet(sub, 48, &store).unwrap(),
+            "fbad:0:2::/48".parse().unwrap()
+        );
+        let entry = store.get_assigned_ips().unwrap().pop().unwrap();
+        assert_eq!(entry.available_subnets, "");
+        assert_eq!(entry.iterative_index, 3);
+    }
+
     #[test]
     fn test_playground() {
         let a = generate_index_from_subnet(
@@ -1,23 +1,64 @@
+use crate::database::client_store::ClientStore;
 use crate::database::database_tools::update_mail_sent_time;
 use crate::database::database_tools::verify_client;
 use crate::database::get_exit_info;
 use crate::database::secs_since_unix_epoch;
 use crate::database::struct_tools::verif_done;
+use crate::database::verification::VerificationProvider;
 use crate::get_client_ipv6;
 use crate::RitaExitError;
 
-use althea_types::{ExitClientDetails, ExitClientIdentity, ExitState};
-use diesel::prelude::PgConnection;
+use althea_types::{ExitClientDetails, ExitClientIdentity, ExitState, ExitVerifMode};
+use async_trait::async_trait;
 use exit_db::models;
 use handlebars::Handlebars;
 use lettre::transport::smtp::authentication::Credentials;
 use lettre::transport::smtp::authentication::Mechanism;
 use lettre::transport::smtp::extension::ClientId;
 use lettre::transport::smtp::PoolConfig;
+use lettre::transport::smtp::SmtpTransportBuilder;
 use lettre::FileTransport;
 use lettre::{Message, SmtpTransport, Transport};
 use serde_json::json;
-use settings::exit::ExitVerifSettings;
+use settings::exit::{EmailVerifSettings, ExitVerifSettings};
+
+/// Builds the transport for an smtp:// (plaintext) or smtps:// (implicit tls) connection url,
+/// the port is optional and defaults to the usual one for the scheme
+fn transport_from_url(url: &str) -> Result<SmtpTransportBuilder, Box<RitaExitError>> {
+    let (tls, rest) = if let Some(rest) = url.strip_prefix("smtps://") {
+        (true, rest)
+    } else if let Some(rest) = url.strip_prefix("smtp://") {
+        (false, rest)
+    } else {
+        return Err(Box::new(RitaExitError::MiscStringError(format!(
+            "Unsupported smtp connection url {url}, expected smtp:// or smtps://"
+        ))));
+    };
+    let authority = rest.split('/').next().unwrap_or_default();
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => match port.parse::<u16>() {
+            Ok(port) => (host, Some(port)),
+            Err(_) => {
+                return Err(Box::new(RitaExitError::MiscStringError(format!(
+                    "Invalid port in smtp connection url {url}"
+                ))))
+            }
+        },
+        None => (authority, None),
+    };
+    let builder = if tls {
+        match SmtpTransport::relay(host) {
+            Ok(a) => a,
+            Err(e) => return Err(Box::new(e.into())),
+        }
+    } else {
+        SmtpTransport::builder_dangerous(host)
+    };
+    Ok(match port {
+        Some(port) => builder.port(port),
+        None => builder,
+    })
+}
 
 pub fn send_mail(client: &models::Client) -> Result<(), Box<RitaExitError>> {
     let mailer = match settings::get_rita_exit().verif_settings {
@@ -61,6 +102,17 @@ pub fn send_mail(client: &models::Client) -> Result<(), Box<RitaExitError>> {
         if let Err(e) = mailer.send(&email) {
             return Err(Box::new(e.into()));
         };
+    } else if let Some(url) = &mailer.smtp_connection_url {
+        let mut transport =
+            transport_from_url(url)?.hello_name(ClientId::Domain(mailer.smtp_domain));
+        if !mailer.smtp_username.is_empty() {
+            transport = transport
+                .credentials(Credentials::new(mailer.smtp_username, mailer.smtp_password))
+                .authentication(vec![Mechanism::Plain]);
+        }
+        if let Err(e) = transport.build().send(&email) {
+            return Err(Box::new(e.into()));
+        };
     } else {
         let mailer = match SmtpTransport::relay(&mailer.smtp_url) {
             Ok(a) => a,
@@ -79,18 +131,50 @@ pub fn send_mail(client: &models::Client) -> Result<(), Box<RitaExitError>> {
     Ok(())
 }
 
+/// Verifies clients by emailing them a code over smtp
+pub struct EmailProvider {
+    settings: EmailVerifSettings,
+}
+
+impl EmailProvider {
+    pub fn new(settings: EmailVerifSettings) -> EmailProvider {
+        EmailProvider { settings }
+    }
+}
+
+#[async_trait(?Send)]
+impl VerificationProvider for EmailProvider {
+    fn verif_mode(&self) -> ExitVerifMode {
+        ExitVerifMode::Email
+    }
+
+    async fn handle_registration(
+        &self,
+        client: ExitClientIdentity,
+        their_record: models::Client,
+        store: &dyn ClientStore,
+    ) -> Result<ExitState, Box<RitaExitError>> {
+        handle_email_registration(
+            &client,
+            &their_record,
+            store,
+            self.settings.email_cooldown as i64,
+        )
+    }
+}
+
 /// handles the minutia of emails and cooldowns
 pub fn handle_email_registration(
     client: &ExitClientIdentity,
     their_record: &exit_db::models::Client,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
     cooldown: i64,
 ) -> Result<ExitState, Box<RitaExitError>> {
     let mut their_record = their_record.clone();
     if client.reg_details.email_code == Some(their_record.email_code.clone()) {
         info!("email verification complete for {:?}", client);
 
-        verify_client(client, true, conn)?;
+        verify_client(client, true, store)?;
         their_record.verified = true;
     }
 
@@ -122,7 +206,7 @@ pub fn handle_email_registration(
                 ),
             })
         } else {
-            update_mail_sent_time(client, conn)?;
+            update_mail_sent_time(client, store)?;
 
             send_mail(&their_record)?;
 
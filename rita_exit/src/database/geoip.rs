@@ -1,6 +1,5 @@
-use babel_monitor::open_babel_stream;
-use babel_monitor::parse_routes;
 use ipnetwork::IpNetwork;
+use rita_common::rita_loop::get_babel_monitor;
 use rita_common::utils::ip_increment::is_unicast_link_local;
 use rita_common::KI;
 use std::collections::HashMap;
@@ -17,41 +16,29 @@ lazy_static! {
 
 /// gets the gateway ip for a given mesh IP
 pub fn get_gateway_ip_single(mesh_ip: IpAddr) -> Result<IpAddr, Box<RitaExitError>> {
-    let babel_port = settings::get_rita_exit().network.babel_port;
-
-    match open_babel_stream(babel_port, Duration::from_secs(5)) {
-        Ok(mut stream) => {
-            match parse_routes(&mut stream) {
-                Ok(routes) => {
-                    let mut route_to_des = None;
-                    for route in routes.iter() {
-                        // Only ip6
-                        if let IpNetwork::V6(ref ip) = route.prefix {
-                            // Only host addresses and installed routes
-                            if ip.prefix() == 128
-                                && route.installed
-                                && IpAddr::V6(ip.ip()) == mesh_ip
-                            {
-                                route_to_des = Some(route.clone());
-                            }
-                        }
-                    }
-
-                    match route_to_des {
-                        Some(route) => Ok(match KI.get_wg_remote_ip(&route.iface) {
-                            Ok(a) => a,
-                            Err(e) => return Err(Box::new(e.into())),
-                        }),
-                        None => Err(Box::new(RitaExitError::IpAddrError(mesh_ip))),
+    match get_babel_monitor().get_routes() {
+        Ok(routes) => {
+            let mut route_to_des = None;
+            for route in routes.iter() {
+                // Only ip6
+                if let IpNetwork::V6(ref ip) = route.prefix {
+                    // Only host addresses and installed routes
+                    if ip.prefix() == 128 && route.installed && IpAddr::V6(ip.ip()) == mesh_ip {
+                        route_to_des = Some(route.clone());
                     }
                 }
-                Err(e) => Err(Box::new(RitaExitError::MiscStringError(format!(
-                    "Parse routes babel monitor error, {e:?}"
-                )))),
+            }
+
+            match route_to_des {
+                Some(route) => Ok(match KI.get_wg_remote_ip(&route.iface) {
+                    Ok(a) => a,
+                    Err(e) => return Err(Box::new(e.into())),
+                }),
+                None => Err(Box::new(RitaExitError::IpAddrError(mesh_ip))),
             }
         }
         Err(e) => Err(Box::new(RitaExitError::MiscStringError(format!(
-            "Error opening babel stream, {e:?}"
+            "Parse routes babel monitor error, {e:?}"
         )))),
     }
 }
@@ -64,60 +51,47 @@ pub struct IpPair {
 
 /// gets the gateway ip for a given set of mesh IPs, inactive addresses will simply
 /// not appear in the result vec
-pub fn get_gateway_ip_bulk(
-    mesh_ip_list: Vec<IpAddr>,
-    timeout: Duration,
-) -> Result<Vec<IpPair>, Box<RitaExitError>> {
-    let babel_port = settings::get_rita_exit().network.babel_port;
+pub fn get_gateway_ip_bulk(mesh_ip_list: Vec<IpAddr>) -> Result<Vec<IpPair>, Box<RitaExitError>> {
     trace!("getting gateway ip bulk");
 
-    match open_babel_stream(babel_port, timeout) {
-        Ok(mut stream) => {
-            match parse_routes(&mut stream) {
-                Ok(routes) => {
-                    trace!("done talking to babel for gateway ip bulk");
-                    let mut remote_ip_cache: HashMap<String, IpAddr> = HashMap::new();
-                    let mut results = Vec::new();
-                    for mesh_ip in mesh_ip_list {
-                        for route in routes.iter() {
-                            // Only ip6
-                            if let IpNetwork::V6(ref ip) = route.prefix {
-                                // Only host addresses and installed routes
-                                if ip.prefix() == 128
-                                    && route.installed
-                                    && IpAddr::V6(ip.ip()) == mesh_ip
-                                {
-                                    // check if we've already looked up this interface this round, since gateways
-                                    // have many clients this will often be the case
-                                    if let Some(remote_ip) = remote_ip_cache.get(&route.iface) {
+    match get_babel_monitor().get_routes() {
+        Ok(routes) => {
+            trace!("done talking to babel for gateway ip bulk");
+            let mut remote_ip_cache: HashMap<String, IpAddr> = HashMap::new();
+            let mut results = Vec::new();
+            for mesh_ip in mesh_ip_list {
+                for route in routes.iter() {
+                    // Only ip6
+                    if let IpNetwork::V6(ref ip) = route.prefix {
+                        // Only host addresses and installed routes
+                        if ip.prefix() == 128 && route.installed && IpAddr::V6(ip.ip()) == mesh_ip {
+                            // check if we've already looked up this interface this round, since gateways
+                            // have many clients this will often be the case
+                            if let Some(remote_ip) = remote_ip_cache.get(&route.iface) {
+                                results.push(IpPair {
+                                    mesh_ip,
+                                    gateway_ip: *remote_ip,
+                                });
+                            } else {
+                                match KI.get_wg_remote_ip(&route.iface) {
+                                    Ok(remote_ip) => {
+                                        remote_ip_cache.insert(route.iface.clone(), remote_ip);
                                         results.push(IpPair {
                                             mesh_ip,
-                                            gateway_ip: *remote_ip,
-                                        });
-                                    } else {
-                                        match KI.get_wg_remote_ip(&route.iface) {
-                                            Ok(remote_ip) => {
-                                                remote_ip_cache
-                                                    .insert(route.iface.clone(), remote_ip);
-                                                results.push(IpPair {
-                                                    mesh_ip,
-                                                    gateway_ip: remote_ip,
-                                                })
-                                            }
-                                            Err(e) => {
-                                                error!("Failure looking up remote ip {:?}", e)
-                                            }
-                                        }
+                                            gateway_ip: remote_ip,
+                                        })
+                                    }
+                                    Err(e) => {
+                                        error!("Failure looking up remote ip {:?}", e)
                                     }
                                 }
                             }
                         }
                     }
-
-                    Ok(results)
                 }
-                Err(e) => Err(Box::new(e.into())),
             }
+
+            Ok(results)
         }
         Err(e) => Err(Box::new(e.into())),
     }
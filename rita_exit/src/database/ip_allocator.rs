@@ -0,0 +1,520 @@
+//! Hands out the internal ipv4 addresses clients are reached at over the exit tunnel. Rather than
+//! scanning every client for a free address on each signup the allocator keeps a row in the client
+//! store for every address it has handed out, keyed by the pool and the address's offset from
+//! exit_start_ip. Taking a new address is inserting its row so parallel signups can never be handed
+//! the same one, the one that loses the race just tries again. A released address keeps its row for
+//! INTERNAL_IP_GRACE_PERIOD before it is handed out again, so a client that hasn't picked up its new
+//! address yet, or whose tunnel is still being torn down, never shares it with someone new.
+//!
+//! Every offset below the highest one with a row has a row of its own, apart from the exit's, so
+//! the released rows are the pool's free list and one past the highest row is where the addresses
+//! that have never been handed out start. Addresses skipped over on the way, by an operator claiming
+//! one further up, are given rows that have been free since the epoch. A reservation only ever reads
+//! the first free row or the cursor, never the whole pool.
+
+use crate::database::client_store::{ClientSelector, ClientStore};
+use crate::RitaExitError;
+use exit_db::models::InternalIp;
+use rita_common::utils::secs_since_unix_epoch;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::net::Ipv4Addr;
+
+/// How many times a reservation is retried when other workers keep changing the pool under it
+const MAX_RESERVE_ATTEMPTS: usize = 16;
+
+/// How long in seconds a released address is held before it is handed out again, and how long a
+/// reserved address can go without a client holding it before the reclaim sweep releases it
+pub const INTERNAL_IP_GRACE_PERIOD: i64 = 3600;
+
+/// The range of addresses clients are given, everything from the start ip to the end of its
+/// netmask apart from the exit's own address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InternalIpRange {
+    pub start: Ipv4Addr,
+    pub netmask: u8,
+    pub gateway: Ipv4Addr,
+}
+
+impl InternalIpRange {
+    pub fn from_settings() -> InternalIpRange {
+        let exit_settings = settings::get_rita_exit().exit_network;
+        InternalIpRange {
+            start: exit_settings.exit_start_ip,
+            netmask: exit_settings.netmask,
+            gateway: exit_settings.own_internal_ip,
+        }
+    }
+
+    /// The key of this range's record in the store, changing the range starts a new pool
+    pub fn pool_name(&self) -> String {
+        format!("{}/{}", self.start, self.netmask)
+    }
+
+    fn mask(&self) -> u32 {
+        match self.netmask {
+            0 => 0,
+            n => u32::MAX << (32 - u32::from(n.min(32))),
+        }
+    }
+
+    /// The address at offset, None once offset runs past the end of the netmask
+    pub fn ip_at(&self, offset: u64) -> Option<Ipv4Addr> {
+        let start = u32::from(self.start);
+        let ip: u32 = (u64::from(start) + offset).try_into().ok()?;
+        if ip & self.mask() == start & self.mask() {
+            Some(ip.into())
+        } else {
+            None
+        }
+    }
+
+    /// The offset of ip, None if it isn't in the range
+    pub fn offset_of(&self, ip: Ipv4Addr) -> Option<u64> {
+        let start = u32::from(self.start);
+        let ip = u32::from(ip);
+        if ip >= start && ip & self.mask() == start & self.mask() {
+            Some(u64::from(ip - start))
+        } else {
+            None
+        }
+    }
+}
+
+/// released_at of the rows for addresses that were skipped over rather than handed out
+const NEVER_HELD: i64 = 1;
+
+/// True if the address behind row can be handed out again
+fn is_free(row: &InternalIp, now: i64) -> bool {
+    row.released_at != 0 && now - row.released_at >= INTERNAL_IP_GRACE_PERIOD
+}
+
+/// The row for an address taken at now
+fn held(range: &InternalIpRange, offset: u64, now: i64) -> InternalIp {
+    InternalIp {
+        pool: range.pool_name(),
+        ip_offset: offset as i64,
+        reserved_at: now,
+        released_at: 0,
+    }
+}
+
+/// The rows for the addresses skipped over between the cursor and offset
+fn skipped(range: &InternalIpRange, cursor: u64, offset: u64) -> Vec<InternalIp> {
+    let gateway = range.offset_of(range.gateway);
+    (cursor..offset)
+        .filter(|o| Some(*o) != gateway)
+        .map(|o| InternalIp {
+            released_at: NEVER_HELD,
+            ..held(range, o, NEVER_HELD)
+        })
+        .collect()
+}
+
+/// The offsets of the addresses clients hold in range
+fn client_offsets(
+    store: &dyn ClientStore,
+    range: &InternalIpRange,
+) -> Result<HashSet<u64>, Box<RitaExitError>> {
+    let gateway = range.offset_of(range.gateway);
+    Ok(store
+        .get_clients(ClientSelector::All)?
+        .iter()
+        .filter_map(|c| c.internal_ip.parse().ok())
+        .filter_map(|ip| range.offset_of(ip))
+        .filter(|offset| Some(*offset) != gateway)
+        .collect())
+}
+
+/// The offset the never used addresses of the pool start at. A pool that has never been used first
+/// takes over whatever addresses clients registered before the allocator existed already hold
+fn get_cursor(store: &dyn ClientStore, range: &InternalIpRange) -> Result<u64, Box<RitaExitError>> {
+    let name = range.pool_name();
+    let cursor = store.next_internal_ip_offset(&name)? as u64;
+    if cursor == 0 {
+        let in_use = client_offsets(store, range)?;
+        if let Some(highest) = in_use.iter().max() {
+            info!("Setting up internal ip pool {}", name);
+            let now = secs_since_unix_epoch();
+            let mut adopted = skipped(range, 0, highest + 1);
+            for row in adopted.iter_mut() {
+                if in_use.contains(&(row.ip_offset as u64)) {
+                    *row = held(range, row.ip_offset as u64, now);
+                }
+            }
+            // fails if another worker set it up in the meantime, which is just as good
+            store.insert_internal_ips(&adopted)?;
+            return Ok(store.next_internal_ip_offset(&name)? as u64);
+        }
+    }
+    Ok(cursor)
+}
+
+/// Takes the address at offset, whose row if it has one is current. False if someone else
+/// took it first
+fn take(
+    store: &dyn ClientStore,
+    range: &InternalIpRange,
+    offset: u64,
+    current: Option<&InternalIp>,
+    now: i64,
+) -> Result<bool, Box<RitaExitError>> {
+    let new = held(range, offset, now);
+    match current {
+        Some(current) => store.swap_internal_ip(current, &new),
+        None => {
+            let mut rows = skipped(range, get_cursor(store, range)?, offset);
+            rows.push(new);
+            store.insert_internal_ips(&rows)
+        }
+    }
+}
+
+/// Reserves an unused address in range for a new client, the lowest one that was released more
+/// than a grace period ago or otherwise the first that has never been handed out
+pub fn reserve_internal_ip(
+    store: &dyn ClientStore,
+    range: &InternalIpRange,
+) -> Result<Ipv4Addr, Box<RitaExitError>> {
+    let gateway = range.offset_of(range.gateway);
+    let name = range.pool_name();
+    for _ in 0..MAX_RESERVE_ATTEMPTS {
+        let now = secs_since_unix_epoch();
+        let cursor = get_cursor(store, range)?;
+        let free = store.first_free_internal_ip(&name, now - INTERNAL_IP_GRACE_PERIOD)?;
+        let offset = match &free {
+            Some(row) => row.ip_offset as u64,
+            None if Some(cursor) == gateway => cursor + 1,
+            None => cursor,
+        };
+        let ip = match range.ip_at(offset) {
+            Some(ip) => ip,
+            None => {
+                return Err(Box::new(RitaExitError::MiscStringError(
+                    "Address space exhausted!".to_string(),
+                )))
+            }
+        };
+        if take(store, range, offset, free.as_ref(), now)? {
+            trace!("Reserved internal ip {}", ip);
+            return Ok(ip);
+        }
+        trace!("Internal ip pool changed while reserving, retrying");
+    }
+    Err(Box::new(RitaExitError::MiscStringError(
+        "Unable to reserve an internal ip, the pool is too busy".to_string(),
+    )))
+}
+
+/// Reserves a particular address in range, for when the operator moves a client to an address
+/// of their choosing. Errors if the address is outside the range, held or still in its grace period
+pub fn claim_internal_ip(
+    store: &dyn ClientStore,
+    range: &InternalIpRange,
+    ip: Ipv4Addr,
+) -> Result<(), Box<RitaExitError>> {
+    let offset = match range.offset_of(ip) {
+        Some(offset) if ip != range.gateway => offset,
+        _ => {
+            return Err(Box::new(RitaExitError::MiscStringError(format!(
+                "{ip} is not an address clients can be given"
+            ))))
+        }
+    };
+    let name = range.pool_name();
+    for _ in 0..MAX_RESERVE_ATTEMPTS {
+        let now = secs_since_unix_epoch();
+        get_cursor(store, range)?;
+        let row = store.get_internal_ip(&name, offset as i64)?;
+        if let Some(row) = &row {
+            if !is_free(row, now) {
+                return Err(Box::new(RitaExitError::MiscStringError(format!(
+                    "{ip} is already in use"
+                ))));
+            }
+        }
+        if take(store, range, offset, row.as_ref(), now)? {
+            trace!("Claimed internal ip {}", ip);
+            return Ok(());
+        }
+        trace!("Internal ip pool changed while claiming, retrying");
+    }
+    Err(Box::new(RitaExitError::MiscStringError(format!(
+        "Unable to claim internal ip {ip}, the pool is too busy"
+    ))))
+}
+
+/// Releases an address once no client holds it anymore, it is handed out again after the grace period
+pub fn release_internal_ip(
+    store: &dyn ClientStore,
+    range: &InternalIpRange,
+    ip: Ipv4Addr,
+) -> Result<(), Box<RitaExitError>> {
+    let offset = match range.offset_of(ip) {
+        Some(offset) => offset,
+        None => {
+            warn!(
+                "Not releasing {}, it is outside of the internal ip range",
+                ip
+            );
+            return Ok(());
+        }
+    };
+    let name = range.pool_name();
+    for _ in 0..MAX_RESERVE_ATTEMPTS {
+        let current = match store.get_internal_ip(&name, offset as i64)? {
+            Some(row) if row.released_at == 0 => row,
+            _ => return Ok(()),
+        };
+        let released = InternalIp {
+            released_at: secs_since_unix_epoch(),
+            ..current.clone()
+        };
+        if store.swap_internal_ip(&current, &released)? {
+            trace!("Released internal ip {}", ip);
+            return Ok(());
+        }
+    }
+    Err(Box::new(RitaExitError::MiscStringError(format!(
+        "Unable to release internal ip {ip}, the pool is too busy"
+    ))))
+}
+
+/// Brings the pool back in line with the addresses clients actually hold. Addresses reserved more
+/// than a grace period ago that no client holds, for example because the exit went down between
+/// removing a client and releasing its address, are released. Addresses clients hold that are
+/// released or were never handed out, for example because the database was edited by hand, are
+/// marked as held. Returns how many addresses were released
+pub fn reclaim_internal_ips(
+    store: &dyn ClientStore,
+    range: &InternalIpRange,
+) -> Result<usize, Box<RitaExitError>> {
+    // the rows have to be read before the clients, or a client could be written between the two
+    // with a row we don't know about. Rows newer than the grace period are left alone either way
+    get_cursor(store, range)?;
+    let rows: HashMap<u64, InternalIp> = store
+        .get_internal_ips(&range.pool_name())?
+        .into_iter()
+        .map(|row| (row.ip_offset as u64, row))
+        .collect();
+    let in_use = client_offsets(store, range)?;
+    let now = secs_since_unix_epoch();
+
+    let mut reclaimed = 0;
+    for (offset, row) in rows.iter() {
+        let fixed = match (row.released_at == 0, in_use.contains(offset)) {
+            (true, false) if now - row.reserved_at >= INTERNAL_IP_GRACE_PERIOD => InternalIp {
+                released_at: now,
+                ..row.clone()
+            },
+            (false, true) => {
+                error!(
+                    "Internal ip offset {} is held by a client but released",
+                    offset
+                );
+                held(range, *offset, now)
+            }
+            _ => continue,
+        };
+        // if the row changed under us whatever is still wrong will be caught next time
+        if store.swap_internal_ip(row, &fixed)? && fixed.released_at != 0 {
+            reclaimed += 1;
+        }
+    }
+
+    let mut missing: Vec<u64> = in_use
+        .into_iter()
+        .filter(|offset| !rows.contains_key(offset))
+        .collect();
+    missing.sort_unstable();
+    if !missing.is_empty() {
+        error!(
+            "{} internal ips are held by clients but were never handed out",
+            missing.len()
+        );
+        for offset in missing {
+            take(store, range, offset, None, now)?;
+        }
+    }
+
+    if reclaimed > 0 {
+        info!("Reclaimed {} internal ips", reclaimed);
+    }
+    Ok(reclaimed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::client_store::memory::MemoryStore;
+    use exit_db::models::Client;
+
+    fn range() -> InternalIpRange {
+        InternalIpRange {
+            start: "172.16.0.0".parse().unwrap(),
+            netmask: 29,
+            gateway: "172.16.0.6".parse().unwrap(),
+        }
+    }
+
+    fn ip(last: u8) -> Ipv4Addr {
+        Ipv4Addr::new(172, 16, 0, last)
+    }
+
+    fn client(mesh_ip: &str, internal_ip: Ipv4Addr) -> Client {
+        Client {
+            mesh_ip: mesh_ip.to_string(),
+            internal_ip: internal_ip.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn row(store: &MemoryStore, offset: i64) -> InternalIp {
+        store
+            .get_internal_ips(&range().pool_name())
+            .unwrap()
+            .into_iter()
+            .find(|r| r.ip_offset == offset)
+            .unwrap()
+    }
+
+    /// Moves the row at offset back by a grace period, as if it had been sitting there that long
+    fn age(store: &MemoryStore, offset: i64) {
+        let current = row(store, offset);
+        let older = InternalIp {
+            reserved_at: current.reserved_at - INTERNAL_IP_GRACE_PERIOD,
+            released_at: match current.released_at {
+                0 => 0,
+                t => t - INTERNAL_IP_GRACE_PERIOD,
+            },
+            ..current.clone()
+        };
+        assert!(store.swap_internal_ip(&current, &older).unwrap());
+    }
+
+    #[test]
+    fn test_range() {
+        let range = range();
+        assert_eq!(range.ip_at(0), Some("172.16.0.0".parse().unwrap()));
+        assert_eq!(range.ip_at(7), Some("172.16.0.7".parse().unwrap()));
+        assert_eq!(range.ip_at(8), None);
+        assert_eq!(range.offset_of("172.16.0.5".parse().unwrap()), Some(5));
+        assert_eq!(range.offset_of("172.16.0.8".parse().unwrap()), None);
+        assert_eq!(range.offset_of("172.15.255.255".parse().unwrap()), None);
+        assert_eq!(range.pool_name(), "172.16.0.0/29");
+    }
+
+    #[test]
+    fn test_reserve_until_exhausted() {
+        let store = MemoryStore::default();
+        let range = range();
+        let mut handed_out = Vec::new();
+        while let Ok(ip) = reserve_internal_ip(&store, &range) {
+            handed_out.push(ip.octets()[3]);
+        }
+        // everything but the gateway, in order
+        assert_eq!(handed_out, vec![0, 1, 2, 3, 4, 5, 7]);
+
+        release_internal_ip(&store, &range, ip(3)).unwrap();
+        release_internal_ip(&store, &range, ip(3)).unwrap();
+        // not until the grace period is up
+        assert!(reserve_internal_ip(&store, &range).is_err());
+        age(&store, 3);
+        assert_eq!(reserve_internal_ip(&store, &range).unwrap(), ip(3));
+        assert!(reserve_internal_ip(&store, &range).is_err());
+    }
+
+    #[test]
+    fn test_new_pool_adopts_existing_clients() {
+        let store = MemoryStore::default();
+        let range = range();
+        for (i, last) in [0, 1, 4].iter().enumerate() {
+            store
+                .insert_client(&client(&format!("fd00::{i}"), ip(*last)))
+                .unwrap();
+        }
+        let ips: Vec<u8> = (0..3)
+            .map(|_| reserve_internal_ip(&store, &range).unwrap().octets()[3])
+            .collect();
+        assert_eq!(ips, vec![2, 3, 5]);
+    }
+
+    #[test]
+    fn test_reclaim_sweep() {
+        let store = MemoryStore::default();
+        let range = range();
+        for i in 0..3 {
+            let ip = reserve_internal_ip(&store, &range).unwrap();
+            store
+                .insert_client(&client(&format!("fd00::{i}"), ip))
+                .unwrap();
+        }
+        // a client removed without its address being released
+        store
+            .delete_clients(ClientSelector::MeshIp("fd00::1".to_string()))
+            .unwrap();
+        // a signup that has reserved its address but not written the client yet
+        assert_eq!(reserve_internal_ip(&store, &range).unwrap(), ip(3));
+
+        // nothing is old enough yet
+        assert_eq!(reclaim_internal_ips(&store, &range).unwrap(), 0);
+        age(&store, 1);
+        assert_eq!(reclaim_internal_ips(&store, &range).unwrap(), 1);
+        assert_eq!(reclaim_internal_ips(&store, &range).unwrap(), 0);
+        assert_eq!(row(&store, 3).released_at, 0);
+
+        // the reclaimed address gets its grace period like any other
+        assert_eq!(reserve_internal_ip(&store, &range).unwrap(), ip(4));
+        age(&store, 1);
+        assert_eq!(reserve_internal_ip(&store, &range).unwrap(), ip(1));
+    }
+
+    #[test]
+    fn test_reclaim_fixes_held_addresses() {
+        let store = MemoryStore::default();
+        let range = range();
+        let first = reserve_internal_ip(&store, &range).unwrap();
+        store.insert_client(&client("fd00::1", first)).unwrap();
+        // released while the client still holds it, and a client given an address by hand
+        release_internal_ip(&store, &range, first).unwrap();
+        store.insert_client(&client("fd00::2", ip(5))).unwrap();
+
+        assert_eq!(reclaim_internal_ips(&store, &range).unwrap(), 0);
+        assert_eq!(row(&store, 0).released_at, 0);
+        assert_eq!(row(&store, 5).released_at, 0);
+        assert_eq!(row(&store, 4).released_at, NEVER_HELD);
+        age(&store, 0);
+        age(&store, 5);
+        assert!(claim_internal_ip(&store, &range, first).is_err());
+        assert!(claim_internal_ip(&store, &range, ip(5)).is_err());
+    }
+
+    #[test]
+    fn test_claim() {
+        let store = MemoryStore::default();
+        let range = range();
+        assert_eq!(reserve_internal_ip(&store, &range).unwrap(), ip(0));
+        claim_internal_ip(&store, &range, ip(3)).unwrap();
+        // the addresses skipped over go on the free list
+        assert_eq!(row(&store, 1).released_at, NEVER_HELD);
+        assert_eq!(row(&store, 2).released_at, NEVER_HELD);
+        assert!(claim_internal_ip(&store, &range, ip(3)).is_err());
+        assert!(claim_internal_ip(&store, &range, ip(0)).is_err());
+        assert!(claim_internal_ip(&store, &range, range.gateway).is_err());
+        assert!(claim_internal_ip(&store, &range, ip(8)).is_err());
+        // the addresses skipped over are still handed out
+        claim_internal_ip(&store, &range, ip(2)).unwrap();
+        let ips: Vec<u8> = (0..3)
+            .map(|_| reserve_internal_ip(&store, &range).unwrap().octets()[3])
+            .collect();
+        assert_eq!(ips, vec![1, 4, 5]);
+
+        // a released address can't be claimed during its grace period either
+        release_internal_ip(&store, &range, ip(2)).unwrap();
+        assert!(claim_internal_ip(&store, &range, ip(2)).is_err());
+        age(&store, 2);
+        claim_internal_ip(&store, &range, ip(2)).unwrap();
+        assert_eq!(reserve_internal_ip(&store, &range).unwrap(), ip(7));
+        assert!(reserve_internal_ip(&store, &range).is_err());
+    }
+}
@@ -3,54 +3,57 @@
 //! and therefore synchronous database requests are quite expensive (on the order of tens of milliseconds)
 
 use crate::create_or_update_user_record;
+use crate::database::client_store::ClientStore;
 use crate::database::database_tools::client_conflict;
 use crate::database::database_tools::delete_client;
 use crate::database::database_tools::get_client;
-use crate::database::database_tools::get_database_connection;
 use crate::database::database_tools::set_client_timestamp;
 use crate::database::database_tools::update_client;
 use crate::database::database_tools::verify_client;
 use crate::database::database_tools::verify_db_client;
-use crate::database::email::handle_email_registration;
 use crate::database::geoip::get_country;
 use crate::database::geoip::get_gateway_ip_bulk;
 use crate::database::geoip::get_gateway_ip_single;
 use crate::database::geoip::verify_ip;
-use crate::database::sms::handle_sms_registration;
 use crate::database::struct_tools::display_hashset;
 use crate::database::struct_tools::to_exit_client;
 use crate::database::struct_tools::to_identity;
 use crate::database::struct_tools::verif_done;
+use crate::database::verification::get_verification_provider;
 use crate::get_client_ipv6;
+use crate::get_client_store;
 use crate::rita_loop::EXIT_INTERFACE;
-use crate::rita_loop::EXIT_LOOP_TIMEOUT;
 use crate::rita_loop::LEGACY_INTERFACE;
 use crate::RitaExitError;
 use althea_kernel_interface::ExitClient;
 use althea_types::Identity;
 use althea_types::WgKey;
 use althea_types::{ExitClientDetails, ExitClientIdentity, ExitDetails, ExitState, ExitVerifMode};
-use diesel::prelude::PgConnection;
 use exit_db::models::Client;
 use rita_common::blockchain_oracle::calculate_close_thresh;
 use rita_common::debt_keeper::get_debts_list;
 use rita_common::debt_keeper::DebtAction;
 use rita_common::utils::secs_since_unix_epoch;
 use rita_common::KI;
-use settings::exit::ExitVerifSettings;
 use settings::get_rita_exit;
+use settings::payment::EnforcementPolicy;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::net::IpAddr;
+use std::net::Ipv4Addr;
+use std::net::SocketAddrV4;
 use std::time::Instant;
 use std::time::SystemTime;
 
+pub mod client_store;
 pub mod database_tools;
 pub mod db_client;
 pub mod email;
 pub mod geoip;
+pub mod ip_allocator;
 pub mod sms;
 pub mod struct_tools;
+pub mod verification;
 
 /// one day in seconds
 pub const ONE_DAY: i64 = 86400;
@@ -64,9 +67,8 @@ pub fn get_exit_info() -> ExitDetails {
         exit_currency: exit_settings.payment.system_chain,
         netmask: exit_settings.exit_network.netmask,
         description: exit_settings.description,
-        verif_mode: match exit_settings.verif_settings {
-            Some(ExitVerifSettings::Email(_mailer_settings)) => ExitVerifMode::Email,
-            Some(ExitVerifSettings::Phone(_phone_settings)) => ExitVerifMode::Phone,
+        verif_mode: match get_verification_provider(exit_settings.verif_settings) {
+            Some(provider) => provider.verif_mode(),
             None => ExitVerifMode::Off,
         },
     }
@@ -90,15 +92,25 @@ pub async fn signup_client(
     let user_country = get_country(gateway_ip)?;
     info!("got the country  {:?}", client);
 
-    let conn = get_database_connection()?;
+    let store = get_client_store();
 
     info!(
         "Doing database work for {:?} in country {} with verify_status {}",
         client, user_country, verify_status
     );
+    if store.is_banned(&client.global.wg_public_key.to_string())? {
+        info!(
+            "Refusing signup from banned key {}",
+            client.global.wg_public_key
+        );
+        return Ok(ExitState::Denied {
+            message: "This router has been banned from the exit".to_string(),
+        });
+    }
+
     // check if we have any users with conflicting details
 
-    match client_conflict(&client, &conn) {
+    match client_conflict(&client, store.as_ref()) {
         Ok(true) => {
             return Ok(ExitState::Denied {
                 message: format!(
@@ -111,12 +123,13 @@ pub async fn signup_client(
         Err(e) => return Err(e),
     }
 
-    let their_record = create_or_update_user_record(&conn, &client, user_country)?;
+    let their_record = create_or_update_user_record(store.as_ref(), &client, user_country)?;
 
     // either update and grab an existing entry or create one
-    match (verify_status, exit_settings.verif_settings, from_ops) {
+    let provider = get_verification_provider(exit_settings.verif_settings);
+    match (verify_status, provider, from_ops) {
         (true, _, true) => {
-            verify_client(&client, true, &conn)?;
+            verify_client(&client, true, store.as_ref())?;
             let client_internal_ip = match their_record.internal_ip.parse() {
                 Ok(ip) => ip,
                 Err(e) => return Err(Box::new(RitaExitError::AddrParseError(e))),
@@ -133,7 +146,7 @@ pub async fn signup_client(
         }
 
         (true, None, false) => {
-            verify_client(&client, true, &conn)?;
+            verify_client(&client, true, store.as_ref())?;
             let client_internal_ip = match their_record.internal_ip.parse() {
                 Ok(ip) => ip,
                 Err(e) => return Err(Box::new(RitaExitError::AddrParseError(e))),
@@ -148,11 +161,10 @@ pub async fn signup_client(
                 message: "Registration OK".to_string(),
             })
         }
-        (true, Some(ExitVerifSettings::Email(mailer)), false) => {
-            handle_email_registration(&client, &their_record, &conn, mailer.email_cooldown as i64)
-        }
-        (true, Some(ExitVerifSettings::Phone(phone)), false) => {
-            handle_sms_registration(client, their_record, phone.auth_api_key).await
+        (true, Some(provider), false) => {
+            provider
+                .handle_registration(client, their_record, store.as_ref())
+                .await
         }
 
         (false, _, _) => Ok(ExitState::Denied {
@@ -167,11 +179,11 @@ pub async fn signup_client(
 /// Gets the status of a client and updates it in the database
 pub fn client_status(
     client: ExitClientIdentity,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<ExitState, Box<RitaExitError>> {
     trace!("Checking if record exists for {:?}", client.global.mesh_ip);
 
-    if let Some(their_record) = get_client(&client, conn)? {
+    if let Some(their_record) = get_client(&client, store)? {
         trace!("record exists, updating");
 
         if !verif_done(&their_record) {
@@ -190,7 +202,7 @@ pub fn client_status(
 
         let current_internet_ipv6 = get_client_ipv6(&their_record)?;
 
-        update_client(&client, &their_record, conn)?;
+        update_client(&client, &their_record, store)?;
 
         Ok(ExitState::Registered {
             our_details: ExitClientDetails {
@@ -210,7 +222,7 @@ pub fn client_status(
 /// client that doesn't make status requests
 pub fn validate_clients_region(
     clients_list: Vec<exit_db::models::Client>,
-    conn: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<(), Box<RitaExitError>> {
     info!("Starting exit region validation");
     let start = Instant::now();
@@ -232,7 +244,7 @@ pub fn validate_clients_region(
             Err(_e) => error!("Database entry with invalid mesh ip! {:?}", item),
         }
     }
-    let list = get_gateway_ip_bulk(ip_vec, EXIT_LOOP_TIMEOUT)?;
+    let list = get_gateway_ip_bulk(ip_vec)?;
     for item in list.iter() {
         let res = verify_ip(item.gateway_ip);
         match res {
@@ -245,7 +257,7 @@ pub fn validate_clients_region(
                 // get_gateway_ip_bulk can't add new entires to the list
                 // therefore client_map is strictly a superset of ip_bulk results
                 let client_to_deauth = &client_map[&item.mesh_ip];
-                if verify_db_client(client_to_deauth, false, conn).is_err() {
+                if verify_db_client(client_to_deauth, false, store).is_err() {
                     error!("Failed to deauth client {:?}", client_to_deauth);
                 }
             }
@@ -266,7 +278,7 @@ pub fn validate_clients_region(
 /// the client timeout it is deleted
 pub fn cleanup_exit_clients(
     clients_list: &[exit_db::models::Client],
-    conn: &PgConnection,
+    store: &dyn ClientStore,
 ) -> Result<(), Box<RitaExitError>> {
     trace!("Running exit client cleanup");
     let start = Instant::now();
@@ -284,7 +296,7 @@ pub fn cleanup_exit_clients(
                         "{} does not have a last seen timestamp, adding one now ",
                         client.wg_pubkey
                     );
-                    let res = set_client_timestamp(client_id, conn);
+                    let res = set_client_timestamp(client_id, store);
                     if res.is_err() {
                         warn!(
                             "Unable to update the client timestamp for {} with {:?}",
@@ -298,7 +310,7 @@ pub fn cleanup_exit_clients(
                         "{} has been inactive for too long, deleting! ",
                         client.wg_pubkey
                     );
-                    let res = delete_client(client_id, conn);
+                    let res = delete_client(client_id, store);
                     if res.is_err() {
                         error!(
                             "Unable to remove inactive client {:?} with {:?}",
@@ -593,18 +605,55 @@ pub fn get_client_interface(
     }
 }
 
+/// Redirects the client's web traffic to the portal on both exit interfaces, false if either failed
+fn redirect_to_captive_portal(client: &Client, ip: Ipv4Addr, portal: SocketAddrV4) -> bool {
+    info!(
+        "Redirecting {} to captive portal {}",
+        client.wg_pubkey, portal
+    );
+    let mut ok = true;
+    for iface in [LEGACY_INTERFACE, EXIT_INTERFACE] {
+        if let Err(e) = KI.set_captive_portal(iface, ip, portal) {
+            error!("Unable to setup captive portal on {}: {:?}", iface, e);
+            ok = false;
+        }
+    }
+    ok
+}
+
+/// Removes the client's captive portal redirect from both exit interfaces, false if either failed
+fn remove_captive_portal_redirect(client: &Client, ip: Ipv4Addr) -> bool {
+    info!("Removing captive portal redirect for {}", client.wg_pubkey);
+    let mut ok = true;
+    for iface in [LEGACY_INTERFACE, EXIT_INTERFACE] {
+        if let Err(e) = KI.remove_captive_portal(iface, ip) {
+            error!("Unable to remove captive portal on {}: {:?}", iface, e);
+            ok = false;
+        }
+    }
+    ok
+}
+
 /// Performs enforcement actions on clients by requesting a list of clients from debt keeper
-/// if they are also a exit client they are limited to the free tier level of bandwidth by
-/// setting the htb class they are assigned to to a maximum speed of the free tier value.
-/// Unlike intermediary enforcement we do not need to subdivide the free tier to prevent
+/// if they are also a exit client they are limited to the free tier level of bandwidth, or
+/// the throttle step debt keeper picked, by setting the htb class they are assigned to to that
+/// maximum speed. Unlike intermediary enforcement we do not need to subdivide the free tier to prevent
 /// ourselves from exceeding the upstream free tier. As an exit we are the upstream.
+/// Under the captive portal policy web traffic from the client is also redirected to the portal.
+/// The returned actions are what was actually applied, a redirect that could not be set up is left
+/// out and one that could not be removed is kept, so that either is retried on the next run
 pub fn enforce_exit_clients(
     clients_list: Vec<exit_db::models::Client>,
     old_debt_actions: &HashSet<(Identity, DebtAction)>,
 ) -> Result<HashSet<(Identity, DebtAction)>, Box<RitaExitError>> {
     let start = Instant::now();
     let mut clients_by_id = HashMap::new();
-    let free_tier_limit = settings::get_rita_exit().payment.free_tier_throughput;
+    let payment = settings::get_rita_exit().payment;
+    let free_tier_limit = payment.free_tier_throughput;
+    let captive_portal = match payment.enforcement_policy {
+        EnforcementPolicy::CaptivePortal(portal) => Some(portal),
+        _ => None,
+    };
     let close_threshold = calculate_close_thresh();
     for client in clients_list.iter() {
         if let Ok(id) = to_identity(client) {
@@ -634,13 +683,41 @@ pub fn enforce_exit_clients(
         info!("No change in enforcement list found, skipping tc calls");
         return Ok(new_debt_actions);
     }
+    let mut applied_debt_actions = new_debt_actions.clone();
 
     for debt_entry in list.iter() {
         match clients_by_id.get(&debt_entry.identity) {
             Some(client) => {
                 match client.internal_ip.parse() {
                     Ok(IpAddr::V4(ip)) => {
-                        if debt_entry.payment_details.action == DebtAction::SuspendTunnel {
+                        let action = &debt_entry.payment_details.action;
+                        let limit = match action {
+                            DebtAction::SuspendTunnel | DebtAction::CaptivePortal => {
+                                Some(free_tier_limit)
+                            }
+                            DebtAction::ThrottleTunnel { throughput } => Some(*throughput),
+                            DebtAction::OpenTunnel | DebtAction::MakePayment { .. } => None,
+                        };
+                        let redirect = (debt_entry.identity, DebtAction::CaptivePortal);
+                        let was_redirected = old_debt_actions.contains(&redirect);
+                        match (action, captive_portal) {
+                            (DebtAction::CaptivePortal, Some(portal)) if !was_redirected => {
+                                let redirected = redirect_to_captive_portal(client, ip, portal);
+                                if !redirected {
+                                    applied_debt_actions.remove(&redirect);
+                                }
+                            }
+                            (DebtAction::CaptivePortal, _) => {}
+                            _ if was_redirected => {
+                                let removed = remove_captive_portal_redirect(client, ip);
+                                if !removed {
+                                    applied_debt_actions.insert(redirect);
+                                }
+                            }
+                            _ => {}
+                        }
+
+                        if let Some(limit) = limit {
                             info!("Exit is enforcing on {} because their debt of {} is greater than the limit of {}", client.wg_pubkey, debt_entry.payment_details.debt, close_threshold);
                             // setup flows this allows us to classify traffic we then limit the class, we delete the class as part of unenforcment but it's difficult to delete the flows
                             // so a user who has been enforced and unenforced while the exit has been online may already have them setup
@@ -686,20 +763,10 @@ pub fn enforce_exit_clients(
                                 )
                             }
 
-                            if let Err(e) = KI.set_class_limit(
-                                LEGACY_INTERFACE,
-                                free_tier_limit,
-                                free_tier_limit,
-                                ip,
-                            ) {
+                            if let Err(e) = KI.set_class_limit(LEGACY_INTERFACE, limit, limit, ip) {
                                 error!("Unable to setup enforcement class on wg_exit: {:?}", e);
                             }
-                            if let Err(e) = KI.set_class_limit(
-                                EXIT_INTERFACE,
-                                free_tier_limit,
-                                free_tier_limit,
-                                ip,
-                            ) {
+                            if let Err(e) = KI.set_class_limit(EXIT_INTERFACE, limit, limit, ip) {
                                 error!("Unable to setup enforcement class on wg_exit_v2: {:?}", e);
                             }
                         } else {
@@ -745,10 +812,31 @@ pub fn enforce_exit_clients(
         }
     }
 
+    // clients debt keeper no longer has an entry for, for example because they have gone quiet,
+    // are not enforced on anymore. Without a client record there is no address to remove the
+    // redirect for
+    for (id, action) in old_debt_actions.iter() {
+        if *action != DebtAction::CaptivePortal || list.iter().any(|d| d.identity == *id) {
+            continue;
+        }
+        let client = match clients_by_id.get(id) {
+            Some(client) => client,
+            None => continue,
+        };
+        match client.internal_ip.parse() {
+            Ok(ip) => {
+                if !remove_captive_portal_redirect(client, ip) {
+                    applied_debt_actions.insert((*id, DebtAction::CaptivePortal));
+                }
+            }
+            Err(_) => warn!("Can't parse Ipv4Addr to remove captive portal redirect!"),
+        }
+    }
+
     info!(
         "Exit enforcement completed in {}s {}ms",
         start.elapsed().as_secs(),
         start.elapsed().subsec_millis(),
     );
-    Ok(new_debt_actions)
+    Ok(applied_debt_actions)
 }
@@ -1,43 +1,60 @@
+use crate::database::client_store::ClientStore;
 use crate::database::database_tools::text_sent;
 use crate::database::database_tools::verify_client;
-use crate::database::get_database_connection;
 use crate::database::get_exit_info;
 use crate::database::struct_tools::texts_sent;
+use crate::database::verification::VerificationProvider;
 use crate::get_client_ipv6;
 use crate::RitaExitError;
 
-use althea_types::{ExitClientDetails, ExitClientIdentity, ExitState};
+use althea_types::{ExitClientDetails, ExitClientIdentity, ExitState, ExitVerifMode};
+use async_trait::async_trait;
 use phonenumber::PhoneNumber;
-use settings::exit::ExitVerifSettings;
+use settings::exit::{ExitVerifSettings, PhoneVerifSettings};
 use settings::get_rita_exit;
 use std::time::Duration;
 
 #[derive(Serialize)]
 pub struct SmsCheck {
-    api_key: String,
-    verification_code: String,
-    phone_number: String,
-    country_code: String,
+    #[serde(rename = "To")]
+    to: String,
+    #[serde(rename = "Code")]
+    code: String,
+}
+
+/// The part of a Twilio Verify verification check response we care about
+#[derive(Deserialize)]
+pub struct SmsCheckResponse {
+    status: String,
 }
 
-/// Posts to the validation endpoint with the code, will return success if the code
-/// is the same as the one sent to the user
-async fn check_text(number: String, code: String, api_key: String) -> Result<bool, RitaExitError> {
+/// Posts the code to Twilio Verify, will return success if the code is the same as the one
+/// sent to the user
+async fn check_text(
+    number: String,
+    code: String,
+    phone_settings: &PhoneVerifSettings,
+) -> Result<bool, RitaExitError> {
     trace!("About to check text message status for {}", number);
     let number: PhoneNumber = match number.parse() {
         Ok(number) => number,
         Err(e) => return Err(e.into()),
     };
-    let url = "https://api.authy.com/protected/json/phones/verification/check";
+    let url = format!(
+        "{}/v2/Services/{}/VerificationCheck",
+        phone_settings.verify_url, phone_settings.verify_service_id
+    );
 
     let client = awc::Client::default();
-    let response = match client
-        .get(url)
+    let mut response = match client
+        .post(url)
+        .basic_auth(
+            &phone_settings.twillio_account_id,
+            &phone_settings.twillio_auth_token,
+        )
         .send_form(&SmsCheck {
-            api_key,
-            verification_code: code,
-            phone_number: number.national().to_string(),
-            country_code: number.code().value().to_string(),
+            to: number.format().mode(phonenumber::Mode::E164).to_string(),
+            code,
         })
         .await
     {
@@ -50,21 +67,36 @@ async fn check_text(number: String, code: String, api_key: String) -> Result<boo
     };
 
     trace!("Got {} back from check text", response.status());
-    Ok(response.status().is_success())
+    // a check with no pending verification to check against is a 404, that is just a wrong code
+    if !response.status().is_success() {
+        return Ok(false);
+    }
+    match response.json::<SmsCheckResponse>().await {
+        Ok(check) => Ok(check.status == "approved"),
+        Err(e) => Err(RitaExitError::MiscStringError(format!(
+            "Bad verification check response: {e:?}"
+        ))),
+    }
 }
 
 #[derive(Serialize)]
 pub struct SmsRequest {
-    api_key: String,
-    via: String,
-    phone_number: String,
-    country_code: String,
+    #[serde(rename = "To")]
+    to: String,
+    #[serde(rename = "Channel")]
+    channel: String,
 }
 
-/// Sends the authy verification text by hitting the api endpoint
-async fn send_text(number: String, api_key: String) -> Result<(), RitaExitError> {
+/// Has Twilio Verify send the verification text
+async fn send_text(
+    number: String,
+    phone_settings: &PhoneVerifSettings,
+) -> Result<(), RitaExitError> {
     info!("Sending message for {}", number);
-    let url = "https://api.authy.com/protected/json/phones/verification/start";
+    let url = format!(
+        "{}/v2/Services/{}/Verifications",
+        phone_settings.verify_url, phone_settings.verify_service_id
+    );
     let number: PhoneNumber = match number.parse() {
         Ok(number) => number,
         Err(e) => return Err(e.into()),
@@ -73,26 +105,60 @@ async fn send_text(number: String, api_key: String) -> Result<(), RitaExitError>
     let client = awc::Client::default();
     match client
         .post(url)
+        .basic_auth(
+            &phone_settings.twillio_account_id,
+            &phone_settings.twillio_auth_token,
+        )
         .send_form(&SmsRequest {
-            api_key,
-            via: "sms".to_string(),
-            phone_number: number.national().to_string(),
-            country_code: number.code().value().to_string(),
+            to: number.format().mode(phonenumber::Mode::E164).to_string(),
+            channel: "sms".to_string(),
         })
         .await
     {
-        Ok(_a) => Ok(()),
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => Err(RitaExitError::MiscStringError(format!(
+            "Send text failed with {}",
+            response.status()
+        ))),
         Err(e) => Err(RitaExitError::MiscStringError(format!(
             "Send text error: {e:?}"
         ))),
     }
 }
 
+/// Verifies clients by texting them a code through Twilio
+pub struct TwilioProvider {
+    settings: PhoneVerifSettings,
+}
+
+impl TwilioProvider {
+    pub fn new(settings: PhoneVerifSettings) -> TwilioProvider {
+        TwilioProvider { settings }
+    }
+}
+
+#[async_trait(?Send)]
+impl VerificationProvider for TwilioProvider {
+    fn verif_mode(&self) -> ExitVerifMode {
+        ExitVerifMode::Phone
+    }
+
+    async fn handle_registration(
+        &self,
+        client: ExitClientIdentity,
+        their_record: exit_db::models::Client,
+        store: &dyn ClientStore,
+    ) -> Result<ExitState, Box<RitaExitError>> {
+        handle_sms_registration(client, their_record, &self.settings, store).await
+    }
+}
+
 /// Handles the minutia of phone registration states
 pub async fn handle_sms_registration(
     client: ExitClientIdentity,
     their_record: exit_db::models::Client,
-    api_key: String,
+    phone_settings: &PhoneVerifSettings,
+    store: &dyn ClientStore,
 ) -> Result<ExitState, Box<RitaExitError>> {
     info!(
         "Handling phone registration for {}",
@@ -119,10 +185,9 @@ pub async fn handle_sms_registration(
         (Some(number), Some(code), true) => {
             let result = (magic_phone_number.is_some()
                 && magic_phone_number.unwrap() == number.clone())
-                || check_text(number.clone(), code, api_key).await?;
-            let conn = get_database_connection()?;
+                || check_text(number.clone(), code, phone_settings).await?;
             if result {
-                verify_client(&client, true, &conn)?;
+                verify_client(&client, true, store)?;
                 info!(
                     "Phone registration complete for {}",
                     client.global.wg_public_key
@@ -156,9 +221,8 @@ pub async fn handle_sms_registration(
         }),
         // user has attempts remaining and is requesting the code be resent
         (Some(number), None, false) => {
-            send_text(number, api_key).await?;
-            let conn = get_database_connection()?;
-            text_sent(&client, &conn, text_num)?;
+            send_text(number, phone_settings).await?;
+            text_sent(&client, store, text_num)?;
             Ok(ExitState::Pending {
                 general_details: get_exit_info(),
                 message: "awaiting phone verification".to_string(),
@@ -170,12 +234,11 @@ pub async fn handle_sms_registration(
         (Some(number), Some(code), false) => {
             let result = (magic_phone_number.is_some()
                 && magic_phone_number.unwrap() == number.clone())
-                || check_text(number, code, api_key).await?;
-            let conn = get_database_connection()?;
+                || check_text(number, code, phone_settings).await?;
 
             trace!("Check text returned {}", result);
             if result {
-                verify_client(&client, true, &conn)?;
+                verify_client(&client, true, store)?;
                 info!(
                     "Phone registration complete for {}",
                     client.global.wg_public_key
@@ -226,8 +289,8 @@ pub fn send_admin_notification_sms(message: &str) {
         info!("Sending Admin notification message for");
 
         let url = format!(
-            "https://api.twilio.com/2010-04-01/Accounts/{}/Messages.json",
-            phone.twillio_account_id
+            "{}/2010-04-01/Accounts/{}/Messages.json",
+            phone.twilio_url, phone.twillio_account_id
         );
 
         for number in phone.operator_notification_number {
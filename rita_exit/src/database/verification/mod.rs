@@ -0,0 +1,188 @@
+//! Verification providers decide when a client that has signed up is allowed to use the exit.
+//! Signup only talks to the provider selected by verif_settings through the VerificationProvider
+//! trait, so onboarding users somewhere email or Twilio don't work is a matter of adding a provider
+//! rather than another branch in signup_client. EmailProvider and TwilioProvider live next to the
+//! email and sms code they wrap, WebhookProvider hands the decision to a service run by the
+//! operator and OperatorApprovalProvider leaves clients pending until the operator registers them.
+
+pub mod operator_approval;
+pub mod webhook;
+
+use crate::database::client_store::ClientStore;
+use crate::database::database_tools::verify_client;
+use crate::database::email::EmailProvider;
+use crate::database::get_exit_info;
+use crate::database::sms::TwilioProvider;
+use crate::get_client_ipv6;
+use crate::RitaExitError;
+use althea_types::{ExitClientDetails, ExitClientIdentity, ExitState, ExitVerifMode};
+use async_trait::async_trait;
+use exit_db::models::Client;
+use operator_approval::OperatorApprovalProvider;
+use settings::exit::ExitVerifSettings;
+use webhook::WebhookProvider;
+
+/// A way of verifying clients. Futures are not Send because the http clients providers use run
+/// on the actix runtime
+#[async_trait(?Send)]
+pub trait VerificationProvider {
+    /// What routers should ask their user for when signing up to this exit
+    fn verif_mode(&self) -> ExitVerifMode;
+
+    /// Handles a signup from a client in an allowed region, their_record is the client's entry
+    /// in the store which may already be verified
+    async fn handle_registration(
+        &self,
+        client: ExitClientIdentity,
+        their_record: Client,
+        store: &dyn ClientStore,
+    ) -> Result<ExitState, Box<RitaExitError>>;
+}
+
+/// Gets the provider for the configured verification settings, None if this exit doesn't verify
+/// clients at all
+pub fn get_verification_provider(
+    verif_settings: Option<ExitVerifSettings>,
+) -> Option<Box<dyn VerificationProvider>> {
+    match verif_settings? {
+        ExitVerifSettings::Email(mailer) => Some(Box::new(EmailProvider::new(mailer))),
+        ExitVerifSettings::Phone(phone) => Some(Box::new(TwilioProvider::new(phone))),
+        ExitVerifSettings::Webhook(webhook) => Some(Box::new(WebhookProvider::new(webhook))),
+        ExitVerifSettings::OperatorApproval => Some(Box::new(OperatorApprovalProvider)),
+    }
+}
+
+/// Marks the client verified and builds the registered state handed back to it
+pub fn complete_registration(
+    client: &ExitClientIdentity,
+    their_record: &Client,
+    store: &dyn ClientStore,
+) -> Result<ExitState, Box<RitaExitError>> {
+    verify_client(client, true, store)?;
+    info!("Registration complete for {}", client.global.wg_public_key);
+    let client_internal_ip = match their_record.internal_ip.parse() {
+        Ok(ip) => ip,
+        Err(e) => return Err(Box::new(RitaExitError::AddrParseError(e))),
+    };
+    Ok(ExitState::Registered {
+        our_details: ExitClientDetails {
+            client_internal_ip,
+            internet_ipv6_subnet: get_client_ipv6(their_record)?,
+        },
+        general_details: get_exit_info(),
+        message: "Registration OK".to_string(),
+    })
+}
+
+/// Sets up the exit and builds the clients the provider flow tests sign up with
+#[cfg(test)]
+pub(crate) mod flow_tests {
+    use super::VerificationProvider;
+    use crate::database::client_store::memory::MemoryStore;
+    use crate::database::client_store::{ClientSelector, ClientStore};
+    use crate::RitaExitError;
+    use crate::EXIT_GLOBALS_TEST_LOCK;
+    use althea_types::{ExitClientIdentity, ExitRegistrationDetails, ExitState, Identity};
+    use exit_db::models::Client;
+    use settings::exit::{ExitVerifSettings, RitaExitSettingsStruct};
+    use std::sync::MutexGuard;
+
+    /// Configures the exit to verify through verif_settings, the returned guard keeps other
+    /// tests off the settings until the test is done
+    pub fn setup(verif_settings: ExitVerifSettings) -> MutexGuard<'static, ()> {
+        let lock = EXIT_GLOBALS_TEST_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let mut exit = RitaExitSettingsStruct::test_default();
+        exit.verif_settings = Some(verif_settings);
+        // clients get no ipv6 subnet, so registering doesn't need the global client store
+        exit.exit_network.subnet = None;
+        settings::set_rita_exit(exit);
+        lock
+    }
+
+    pub fn identity(
+        mesh_ip: &str,
+        wg_key: &str,
+        details: ExitRegistrationDetails,
+    ) -> ExitClientIdentity {
+        ExitClientIdentity {
+            wg_port: 60000,
+            global: Identity {
+                mesh_ip: mesh_ip.parse().unwrap(),
+                eth_address: "0x0000000000000000000000000000000000000001"
+                    .parse()
+                    .unwrap(),
+                wg_public_key: wg_key.parse().unwrap(),
+                nickname: None,
+            },
+            reg_details: details,
+        }
+    }
+
+    /// Adds the client to the store the way signup does before asking the provider
+    pub fn insert_record(store: &MemoryStore, client: &ExitClientIdentity) -> Client {
+        let record = Client {
+            mesh_ip: client.global.mesh_ip.to_string(),
+            wg_pubkey: client.global.wg_public_key.to_string(),
+            wg_port: client.wg_port.into(),
+            eth_address: client.global.eth_address.to_string().to_lowercase(),
+            internal_ip: "172.16.0.2".to_string(),
+            country: "US".to_string(),
+            ..Default::default()
+        };
+        store.insert_client(&record).unwrap();
+        record
+    }
+
+    /// Hands the signup to the provider along with the client's current record
+    pub async fn register(
+        provider: &dyn VerificationProvider,
+        client: &ExitClientIdentity,
+        store: &MemoryStore,
+    ) -> Result<ExitState, Box<RitaExitError>> {
+        let record = store
+            .get_clients(ClientSelector::identity(client))
+            .unwrap()
+            .remove(0);
+        provider
+            .handle_registration(client.clone(), record, store)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use settings::exit::{EmailVerifSettings, PhoneVerifSettings, WebhookVerifSettings};
+
+    #[test]
+    fn test_provider_verif_modes() {
+        let mode = |settings| get_verification_provider(settings).map(|p| p.verif_mode());
+        assert_eq!(mode(None), None);
+        assert_eq!(
+            mode(Some(
+                ExitVerifSettings::Email(EmailVerifSettings::default())
+            )),
+            Some(ExitVerifMode::Email)
+        );
+        assert_eq!(
+            mode(Some(
+                ExitVerifSettings::Phone(PhoneVerifSettings::default())
+            )),
+            Some(ExitVerifMode::Phone)
+        );
+        assert_eq!(
+            mode(Some(ExitVerifSettings::Webhook(WebhookVerifSettings {
+                url: "http://localhost:8000".to_string(),
+                auth_token: None,
+                verif_mode: ExitVerifMode::Phone,
+            }))),
+            Some(ExitVerifMode::Phone)
+        );
+        assert_eq!(
+            mode(Some(ExitVerifSettings::OperatorApproval)),
+            Some(ExitVerifMode::Off)
+        );
+    }
+}
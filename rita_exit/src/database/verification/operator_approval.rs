@@ -0,0 +1,92 @@
+//! A provider for exits where the operator vets every client themselves. Signups are left pending
+//! until the key shows up in to_register on an operator checkin, at which point register_op_clients
+//! signs the client up on the operator's behalf and verifies it.
+
+use super::{complete_registration, VerificationProvider};
+use crate::database::client_store::ClientStore;
+use crate::database::get_exit_info;
+use crate::RitaExitError;
+use althea_types::{ExitClientIdentity, ExitState, ExitVerifMode};
+use async_trait::async_trait;
+use exit_db::models::Client;
+
+pub struct OperatorApprovalProvider;
+
+#[async_trait(?Send)]
+impl VerificationProvider for OperatorApprovalProvider {
+    fn verif_mode(&self) -> ExitVerifMode {
+        ExitVerifMode::Off
+    }
+
+    async fn handle_registration(
+        &self,
+        client: ExitClientIdentity,
+        their_record: Client,
+        store: &dyn ClientStore,
+    ) -> Result<ExitState, Box<RitaExitError>> {
+        if their_record.verified {
+            return complete_registration(&client, &their_record, store);
+        }
+        info!(
+            "{} is waiting on operator approval",
+            client.global.wg_public_key
+        );
+        Ok(ExitState::Pending {
+            general_details: get_exit_info(),
+            message: "awaiting operator approval".to_string(),
+            email_code: None,
+            phone_code: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::client_store::memory::MemoryStore;
+    use crate::database::database_tools::verify_client;
+    use crate::database::verification::flow_tests::{identity, insert_record, register, setup};
+    use actix_async::System;
+    use althea_types::ExitRegistrationDetails;
+    use settings::exit::ExitVerifSettings;
+
+    #[test]
+    fn test_operator_approval_registration() {
+        let _lock = setup(ExitVerifSettings::OperatorApproval);
+        let store = MemoryStore::default();
+        let client = identity(
+            "fd00::1",
+            "AwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8gISI=",
+            ExitRegistrationDetails::default(),
+        );
+        insert_record(&store, &client);
+
+        System::new().block_on(async {
+            for _ in 0..2 {
+                match register(&OperatorApprovalProvider, &client, &store)
+                    .await
+                    .unwrap()
+                {
+                    ExitState::Pending { message, .. } => {
+                        assert_eq!(message, "awaiting operator approval")
+                    }
+                    state => panic!("Unexpected state {:?}", state),
+                }
+            }
+
+            // the operator listing the key in to_register signs the client up through
+            // signup_client, which verifies it
+            verify_client(&client, true, &store).unwrap();
+            match register(&OperatorApprovalProvider, &client, &store)
+                .await
+                .unwrap()
+            {
+                ExitState::Registered { our_details, .. } => assert_eq!(
+                    our_details.client_internal_ip,
+                    "172.16.0.2".parse::<std::net::IpAddr>().unwrap()
+                ),
+                state => panic!("Unexpected state {:?}", state),
+            }
+        });
+    }
+}
@@ -0,0 +1,369 @@
+//! A provider that asks a service run by the operator whether a client is verified, for operators
+//! with their own onboarding process or an sms gateway that covers places Twilio doesn't. Every
+//! signup attempt from a client that isn't verified yet is posted to the configured url as a
+//! WebhookRequest and the service answers with a WebhookResponse. Routers retry signup every few
+//! seconds while pending, so an answer is reused for retries with the same details until it is
+//! WEBHOOK_ANSWER_TTL old rather than posting the same request over and over.
+
+use super::{complete_registration, VerificationProvider};
+use crate::database::client_store::ClientStore;
+use crate::database::get_exit_info;
+use crate::RitaExitError;
+use althea_types::{ExitClientIdentity, ExitState, ExitVerifMode};
+use async_trait::async_trait;
+use exit_db::models::Client;
+use settings::exit::WebhookVerifSettings;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
+
+/// How long we wait for the service to answer
+pub const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long an answer is reused for signup retries that don't change the request
+pub const WEBHOOK_ANSWER_TTL: Duration = Duration::from_secs(600);
+
+lazy_static! {
+    /// The last answer the webhook gave for each client, keyed by wg key
+    static ref WEBHOOK_ANSWERS: Arc<RwLock<HashMap<String, WebhookAnswer>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+struct WebhookAnswer {
+    request: WebhookRequest,
+    response: WebhookResponse,
+    at: Instant,
+}
+
+/// Gets the answer to an identical request made less than WEBHOOK_ANSWER_TTL ago
+fn previous_answer(request: &WebhookRequest) -> Option<WebhookResponse> {
+    let answers = WEBHOOK_ANSWERS.read().unwrap();
+    match answers.get(&request.wg_public_key) {
+        Some(answer) if answer.request == *request && answer.at.elapsed() < WEBHOOK_ANSWER_TTL => {
+            Some(answer.response.clone())
+        }
+        _ => None,
+    }
+}
+
+fn save_answer(request: WebhookRequest, response: WebhookResponse) {
+    let mut answers = WEBHOOK_ANSWERS.write().unwrap();
+    answers.retain(|_, answer| answer.at.elapsed() < WEBHOOK_ANSWER_TTL);
+    answers.insert(
+        request.wg_public_key.clone(),
+        WebhookAnswer {
+            request,
+            response,
+            at: Instant::now(),
+        },
+    );
+}
+
+/// What the exit posts to the webhook
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WebhookRequest {
+    pub wg_public_key: String,
+    pub mesh_ip: String,
+    pub eth_address: String,
+    /// The country the client's gateway is in
+    pub country: String,
+    pub email: Option<String>,
+    pub phone: Option<String>,
+    /// The code the user entered, if any
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum WebhookStatus {
+    Verified,
+    Pending,
+    Denied,
+}
+
+/// What the webhook answers with, message is shown to the user
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct WebhookResponse {
+    pub status: WebhookStatus,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
+pub struct WebhookProvider {
+    settings: WebhookVerifSettings,
+}
+
+impl WebhookProvider {
+    pub fn new(settings: WebhookVerifSettings) -> WebhookProvider {
+        WebhookProvider { settings }
+    }
+
+    fn request(&self, client: &ExitClientIdentity, their_record: &Client) -> WebhookRequest {
+        let details = &client.reg_details;
+        WebhookRequest {
+            wg_public_key: client.global.wg_public_key.to_string(),
+            mesh_ip: client.global.mesh_ip.to_string(),
+            eth_address: client.global.eth_address.to_string(),
+            country: their_record.country.clone(),
+            email: details.email.clone(),
+            phone: details.phone.clone(),
+            code: match self.settings.verif_mode {
+                ExitVerifMode::Email => details.email_code.clone(),
+                ExitVerifMode::Phone => details.phone_code.clone(),
+                ExitVerifMode::Off => None,
+            },
+        }
+    }
+
+    async fn ask(&self, request: &WebhookRequest) -> Result<WebhookResponse, RitaExitError> {
+        trace!("Posting signup of {} to the webhook", request.wg_public_key);
+        let client = awc::Client::default();
+        let mut post = client.post(&self.settings.url).timeout(WEBHOOK_TIMEOUT);
+        if let Some(token) = &self.settings.auth_token {
+            post = post.insert_header(("Authorization", format!("Bearer {token}")));
+        }
+        let mut response = match post.send_json(request).await {
+            Ok(a) => a,
+            Err(e) => {
+                return Err(RitaExitError::MiscStringError(format!(
+                    "Verification webhook request error: {e:?}"
+                )))
+            }
+        };
+        if !response.status().is_success() {
+            return Err(RitaExitError::MiscStringError(format!(
+                "Verification webhook responded with {}",
+                response.status()
+            )));
+        }
+        match response.json().await {
+            Ok(a) => Ok(a),
+            Err(e) => Err(RitaExitError::MiscStringError(format!(
+                "Invalid verification webhook response: {e:?}"
+            ))),
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl VerificationProvider for WebhookProvider {
+    fn verif_mode(&self) -> ExitVerifMode {
+        self.settings.verif_mode
+    }
+
+    async fn handle_registration(
+        &self,
+        client: ExitClientIdentity,
+        their_record: Client,
+        store: &dyn ClientStore,
+    ) -> Result<ExitState, Box<RitaExitError>> {
+        if their_record.verified {
+            return complete_registration(&client, &their_record, store);
+        }
+        let request = self.request(&client, &their_record);
+        let response = match previous_answer(&request) {
+            Some(response) => response,
+            None => {
+                let response = self.ask(&request).await?;
+                info!(
+                    "Verification webhook says {:?} for {}",
+                    response.status, client.global.wg_public_key
+                );
+                save_answer(request, response.clone());
+                response
+            }
+        };
+        match response.status {
+            WebhookStatus::Verified => complete_registration(&client, &their_record, store),
+            WebhookStatus::Pending => Ok(ExitState::Pending {
+                general_details: get_exit_info(),
+                message: response
+                    .message
+                    .unwrap_or_else(|| "awaiting verification".to_string()),
+                email_code: None,
+                phone_code: None,
+            }),
+            WebhookStatus::Denied => Ok(ExitState::Denied {
+                message: response
+                    .message
+                    .unwrap_or_else(|| "Verification denied".to_string()),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::client_store::memory::MemoryStore;
+    use crate::database::client_store::{ClientSelector, ClientStore};
+    use crate::database::verification::flow_tests::{identity, insert_record, register, setup};
+    use actix_async::System;
+    use althea_types::ExitRegistrationDetails;
+    use settings::exit::ExitVerifSettings;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use std::thread;
+
+    const CODE: &str = "123456";
+    /// A code the webhook fails on
+    const ERROR_CODE: &str = "000000";
+    const PHONE: &str = "+15555555555";
+    /// A number the webhook denies
+    const DENIED_PHONE: &str = "+15555550000";
+
+    /// A request the webhook got and the Authorization header it came with
+    type Received = Arc<Mutex<Vec<(Option<String>, WebhookRequest)>>>;
+
+    /// Serves the webhook over plain http, it answers Verified for CODE, Denied for DENIED_PHONE,
+    /// fails for ERROR_CODE and answers Pending to everything else
+    fn start_webhook() -> (u16, Received) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Received::default();
+        let log = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                let mut auth = None;
+                let mut len = 0;
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(": ") {
+                        match name.to_lowercase().as_str() {
+                            "content-length" => len = value.parse().unwrap(),
+                            "authorization" => auth = Some(value.to_string()),
+                            _ => {}
+                        }
+                    }
+                }
+                let mut body = vec![0; len];
+                stream.read_exact(&mut body).unwrap();
+                let request: WebhookRequest = serde_json::from_slice(&body).unwrap();
+                let (status, body) = match (request.phone.as_deref(), request.code.as_deref()) {
+                    (Some(DENIED_PHONE), _) => (
+                        "200 OK",
+                        r#"{"status": "Denied", "message": "Not in service area"}"#,
+                    ),
+                    (_, Some(ERROR_CODE)) => ("500 Internal Server Error", ""),
+                    (_, Some(CODE)) => ("200 OK", r#"{"status": "Verified"}"#),
+                    _ => ("200 OK", r#"{"status": "Pending"}"#),
+                };
+                log.lock().unwrap().push((auth, request));
+                write!(
+                    stream.get_mut(),
+                    "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .unwrap();
+            }
+        });
+        (port, received)
+    }
+
+    fn phone_details(phone: &str, code: Option<&str>) -> ExitRegistrationDetails {
+        ExitRegistrationDetails {
+            phone: Some(phone.to_string()),
+            phone_code: code.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_webhook_registration() {
+        let (port, received) = start_webhook();
+        let settings = WebhookVerifSettings {
+            url: format!("http://127.0.0.1:{port}/verify"),
+            auth_token: Some("hunter2".to_string()),
+            verif_mode: ExitVerifMode::Phone,
+        };
+        let _lock = setup(ExitVerifSettings::Webhook(settings.clone()));
+        let provider = WebhookProvider::new(settings);
+        let store = MemoryStore::default();
+        let calls = || received.lock().unwrap().len();
+
+        System::new().block_on(async {
+            let key = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+            insert_record(
+                &store,
+                &identity("fd00::1", key, phone_details(PHONE, None)),
+            );
+
+            // asking for a code leaves the client pending until the webhook verifies it
+            let client = identity("fd00::1", key, phone_details(PHONE, None));
+            let state = register(&provider, &client, &store).await.unwrap();
+            assert!(matches!(state, ExitState::Pending { .. }));
+            assert_eq!(calls(), 1);
+            let (auth, request) = received.lock().unwrap()[0].clone();
+            assert_eq!(auth, Some("Bearer hunter2".to_string()));
+            assert_eq!(request.wg_public_key, key);
+            assert_eq!(request.country, "US");
+            assert_eq!(request.phone, Some(PHONE.to_string()));
+            assert_eq!(request.code, None);
+
+            // the router retrying with the same details doesn't post again
+            let state = register(&provider, &client, &store).await.unwrap();
+            assert!(matches!(state, ExitState::Pending { .. }));
+            assert_eq!(calls(), 1);
+
+            // entering the code is a new request
+            let client = identity("fd00::1", key, phone_details(PHONE, Some(CODE)));
+            let state = register(&provider, &client, &store).await.unwrap();
+            assert!(matches!(state, ExitState::Registered { .. }));
+            assert_eq!(calls(), 2);
+            assert_eq!(received.lock().unwrap()[1].1.code, Some(CODE.to_string()));
+            let record = store
+                .get_clients(ClientSelector::WgKey(key.to_string()))
+                .unwrap();
+            assert!(record[0].verified);
+
+            // once verified the webhook isn't needed
+            let state = register(&provider, &client, &store).await.unwrap();
+            assert!(matches!(state, ExitState::Registered { .. }));
+            assert_eq!(calls(), 2);
+
+            let key = "AQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHyA=";
+            let client = identity("fd00::2", key, phone_details(DENIED_PHONE, None));
+            insert_record(&store, &client);
+            for _ in 0..2 {
+                match register(&provider, &client, &store).await.unwrap() {
+                    ExitState::Denied { message } => assert_eq!(message, "Not in service area"),
+                    state => panic!("Unexpected state {:?}", state),
+                }
+            }
+            assert_eq!(calls(), 3);
+
+            // failures aren't remembered, the next retry asks again
+            let key = "AgMEBQYHCAkKCwwNDg8QERITFBUWFxgZGhscHR4fICE=";
+            let client = identity("fd00::3", key, phone_details(PHONE, Some(ERROR_CODE)));
+            insert_record(&store, &client);
+            assert!(register(&provider, &client, &store).await.is_err());
+            assert!(register(&provider, &client, &store).await.is_err());
+            assert_eq!(calls(), 5);
+        });
+    }
+
+    #[test]
+    fn test_webhook_response() {
+        let response: WebhookResponse = serde_json::from_str(r#"{"status": "Verified"}"#).unwrap();
+        assert_eq!(
+            response,
+            WebhookResponse {
+                status: WebhookStatus::Verified,
+                message: None,
+            }
+        );
+        let response: WebhookResponse =
+            serde_json::from_str(r#"{"status": "Denied", "message": "Not in service area"}"#)
+                .unwrap();
+        assert_eq!(response.status, WebhookStatus::Denied);
+        assert_eq!(response.message, Some("Not in service area".to_string()));
+        assert!(serde_json::from_str::<WebhookResponse>(r#"{"status": "Maybe"}"#).is_err());
+    }
+}
@@ -7,6 +7,7 @@ extern crate lazy_static;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod admin;
 pub mod database;
 pub mod network_endpoints;
 pub mod operator_update;
@@ -20,8 +21,9 @@ use actix_web_async::App;
 use actix_web_async::HttpServer;
 use althea_kernel_interface::KI;
 pub use error::RitaExitError;
-use r2d2::PooledConnection;
 
+use crate::admin::configure_admin;
+use crate::database::client_store::{open_client_store, ClientStore};
 pub use crate::database::database_tools::*;
 pub use crate::database::database_tools::*;
 pub use crate::database::db_client::*;
@@ -29,15 +31,13 @@ pub use crate::database::email::*;
 pub use crate::database::geoip::*;
 pub use crate::database::sms::*;
 use crate::network_endpoints::nuke_db;
-use diesel::r2d2::ConnectionManager;
-use diesel::r2d2::Pool;
-use diesel::PgConnection;
 use rita_common::dashboard::babel::*;
 use rita_common::dashboard::debts::*;
 use rita_common::dashboard::development::*;
 use rita_common::dashboard::nickname::*;
 use rita_common::dashboard::own_info::READABLE_VERSION;
 use rita_common::dashboard::own_info::*;
+use rita_common::dashboard::peer_policy::*;
 use rita_common::dashboard::settings::*;
 use rita_common::dashboard::token_bridge::*;
 use rita_common::dashboard::usage::*;
@@ -51,38 +51,37 @@ use std::sync::RwLock;
 use std::thread;
 
 lazy_static! {
-    pub static ref DB_POOL: Arc<RwLock<HashMap<u32, Pool<ConnectionManager<PgConnection>>>>> =
+    pub static ref CLIENT_STORE: Arc<RwLock<HashMap<u32, Arc<dyn ClientStore>>>> =
         Arc::new(RwLock::new(HashMap::new()));
 }
 
-pub fn initialize_db_pool() {
-    let db_uri = settings::get_rita_exit().db_uri;
-    if !(db_uri.contains("postgres://")
-        || db_uri.contains("postgresql://")
-        || db_uri.contains("psql://"))
-    {
-        panic!("You must provide a valid postgressql database uri!");
-    }
-    let manager = ConnectionManager::new(settings::get_rita_exit().db_uri);
-    let db_pool = &mut *DB_POOL.write().unwrap();
-    let netns = KI.check_integration_test_netns();
+#[cfg(test)]
+lazy_static! {
+    /// Held by tests that change the exit settings or the client store, which are shared by
+    /// every test in the crate
+    pub static ref EXIT_GLOBALS_TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+}
 
-    db_pool.insert(
-        netns,
-        r2d2::Pool::builder()
-            .max_size(settings::get_rita_exit().workers + 1)
-            .build(manager)
-            .expect("Failed to create pool. Check exit IP is trusted to access postgresql"),
-    );
+/// Opens the client store selected by db_uri, the pool size only matters for the database
+/// backends which keep a connection open for each worker
+pub fn initialize_client_store() {
+    let rita_exit = settings::get_rita_exit();
+    let store = match open_client_store(&rita_exit.db_uri, rita_exit.workers + 1) {
+        Ok(store) => store,
+        Err(e) => panic!("Failed to open the client store: {}", e),
+    };
+    let netns = KI.check_integration_test_netns();
+    CLIENT_STORE.write().unwrap().insert(netns, store);
 }
 
-pub fn get_db_pool() -> Option<PooledConnection<ConnectionManager<PgConnection>>> {
+pub fn get_client_store() -> Arc<dyn ClientStore> {
     let netns = KI.check_integration_test_netns();
-    let db_pool = DB_POOL.read().unwrap();
-    db_pool
+    CLIENT_STORE
+        .read()
+        .unwrap()
         .get(&netns)
         .expect("This should be initialized at startup")
-        .try_get()
+        .clone()
 }
 
 #[derive(Debug, Deserialize, Default)]
@@ -124,12 +123,17 @@ pub fn start_rita_exit_dashboard() {
                     .route("/database", web::delete().to(nuke_db))
                     .route("/debts", web::get().to(get_debts))
                     .route("/debts/reset", web::post().to(reset_debt))
+                    .route("/debts/ledger", web::post().to(get_debts_ledger))
                     .route("/withdraw/{address}/{amount}", web::post().to(withdraw))
                     .route("/withdraw_all/{address}", web::post().to(withdraw_all))
                     .route("/nickname/get/", web::get().to(get_nickname))
                     .route("/nickname/set/", web::post().to(set_nickname))
+                    .route("/peer_policy", web::get().to(get_peer_policy))
+                    .route("/peer_policy", web::post().to(set_peer_policy))
+                    .route("/peer_policy/rejected", web::get().to(get_rejected_peers))
                     .route("/usage/payments", web::get().to(get_payments))
                     .route("/token_bridge/status", web::get().to(get_bridge_status))
+                    .configure(configure_admin)
             })
             .bind(format!(
                 "[::0]:{}",
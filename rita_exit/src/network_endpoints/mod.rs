@@ -1,8 +1,8 @@
 //! Network endpoints for rita-exit that are not dashboard or local infromational endpoints
 //! these are called by rita instances to operate the mesh
 
-use crate::database::database_tools::get_database_connection;
 use crate::database::{client_status, get_exit_info, signup_client};
+use crate::get_client_store;
 #[cfg(feature = "development")]
 use crate::rita_exit::database::db_client::DbClient;
 #[cfg(feature = "development")]
@@ -199,14 +199,7 @@ pub async fn secure_status_request(request: Json<EncryptedExitClientIdentity>) -
     };
     trace!("got status request from {}", their_wg_pubkey);
 
-    let conn = match get_database_connection() {
-        Ok(conn) => conn,
-        Err(e) => {
-            return HttpResponse::build(StatusCode::INTERNAL_SERVER_ERROR)
-                .json(format!("Error getting database connection: {e:?}"))
-        }
-    };
-    let state = match client_status(*decrypted_id, &conn) {
+    let state = match client_status(*decrypted_id, get_client_store().as_ref()) {
         Ok(state) => state,
         Err(e) => match *e {
             RitaExitError::NoClientError => {
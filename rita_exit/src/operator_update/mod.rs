@@ -5,15 +5,12 @@ use althea_types::ExitClientIdentity;
 use althea_types::OperatorExitCheckinMessage;
 use althea_types::OperatorExitUpdateMessage;
 use althea_types::WgKey;
-use diesel::QueryDsl;
-use diesel::RunQueryDsl;
-use exit_db::schema::clients::dsl::clients as db_client;
-use exit_db::schema::clients::wg_pubkey;
 use rita_common::KI;
 use std::time::{Duration, Instant};
 
+use crate::database::client_store::ClientSelector;
 use crate::database::signup_client;
-use crate::get_database_connection;
+use crate::get_client_store;
 use crate::rita_loop::EXIT_INTERFACE;
 
 pub struct UptimeStruct {
@@ -102,35 +99,23 @@ async fn register_op_clients(clients: Vec<ExitClientIdentity>) {
 }
 
 pub fn get_registered_list() -> Option<Vec<WgKey>> {
-    match get_database_connection() {
-        Ok(conn) => {
-            let registered_routers = db_client.select(wg_pubkey);
-            let registered_routers = match registered_routers.load::<String>(&conn) {
-                Ok(a) => a,
-                Err(e) => {
-                    error!("Unable to retrive wg keys {}", e);
-                    return None;
-                }
-            };
-            Some(
-                registered_routers
-                    .iter()
-                    .filter_map(|r| match r.parse() {
-                        Ok(a) => Some(a),
-                        Err(_) => {
-                            error!("Invalid wg key in database! {}", r);
-                            None
-                        }
-                    })
-                    .collect::<Vec<WgKey>>(),
-            )
-        }
+    let registered_routers = match get_client_store().get_clients(ClientSelector::All) {
+        Ok(a) => a,
         Err(e) => {
-            error!(
-                "Unable to get a database connection to retrieve registered exits: {}",
-                e
-            );
-            None
+            error!("Unable to retrive wg keys {}", e);
+            return None;
         }
-    }
+    };
+    Some(
+        registered_routers
+            .iter()
+            .filter_map(|r| match r.wg_pubkey.parse() {
+                Ok(a) => Some(a),
+                Err(_) => {
+                    error!("Invalid wg key in database! {}", r.wg_pubkey);
+                    None
+                }
+            })
+            .collect::<Vec<WgKey>>(),
+    )
 }
@@ -10,8 +10,10 @@
 //! Two threads are generated by this, one actual worker thread and a watchdog restarting thread that only
 //! wakes up to restart the inner thread if anything goes wrong.
 
-use crate::{get_database_connection, network_endpoints::*, RitaExitError};
+use crate::database::client_store::{ClientSelector, ClientStore, ClientUpdate};
+use crate::{get_client_store, network_endpoints::*, RitaExitError};
 
+use crate::database::ip_allocator::{reclaim_internal_ips, InternalIpRange};
 use crate::database::struct_tools::clients_to_ids;
 use crate::database::{
     cleanup_exit_clients, enforce_exit_clients, setup_clients, validate_clients_region,
@@ -23,12 +25,10 @@ use actix_web_async::{web, App, HttpServer};
 use althea_kernel_interface::wg_iface_counter::WgUsage;
 use althea_kernel_interface::ExitClient;
 use althea_types::{Identity, WgKey};
-use babel_monitor::{open_babel_stream, parse_routes};
 
-use diesel::{query_dsl::RunQueryDsl, PgConnection};
 use exit_db::models;
-use exit_db::schema::clients::internet_ipv6;
 use rita_common::debt_keeper::DebtAction;
+use rita_common::rita_loop::get_babel_monitor;
 use settings::{get_rita_exit, set_rita_exit, write_config};
 
 use std::collections::{HashMap, HashSet};
@@ -43,6 +43,9 @@ use rita_common::KI;
 pub const EXIT_LOOP_SPEED: u64 = 5;
 pub const EXIT_LOOP_SPEED_DURATION: Duration = Duration::from_secs(EXIT_LOOP_SPEED);
 pub const EXIT_LOOP_TIMEOUT: Duration = Duration::from_secs(4);
+/// How often the internal ip pool is swept for addresses no client holds, these only turn up when
+/// the exit goes down part way through removing a client so there's no need to look every tick
+pub const INTERNAL_IP_SWEEP_INTERVAL: Duration = Duration::from_secs(600);
 
 /// Name of the legacy exit interface
 pub const LEGACY_INTERFACE: &str = "wg_exit";
@@ -64,10 +67,27 @@ pub struct RitaExitCache {
     wg_exit_clients: HashSet<WgKey>,
     // cache of b20 routers we have successful rules and routes for
     wg_exit_v2_clients: HashSet<WgKey>,
+    // when we last swept the internal ip pool for addresses no client holds
+    #[serde(skip)]
+    last_internal_ip_sweep: Option<Instant>,
 }
 
 pub type ExitLock = Arc<RwLock<HashMap<WgKey, WgUsage>>>;
 
+lazy_static! {
+    /// The usage history of the exit loop running in each netns, so the dashboard can report it
+    static ref USAGE_HISTORY: Arc<RwLock<HashMap<u32, ExitLock>>> =
+        Arc::new(RwLock::new(HashMap::new()));
+}
+
+/// The tunnel counters of a client as of the last time it was billed, None if it hasn't been
+pub fn get_client_usage(key: &WgKey) -> Option<WgUsage> {
+    let netns = KI.check_integration_test_netns();
+    let usage_history = USAGE_HISTORY.read().unwrap().get(&netns)?.clone();
+    let usage = usage_history.read().unwrap().get(key).copied();
+    usage
+}
+
 /// Starts the rita exit billing thread, this thread deals with blocking db
 /// calls and performs various tasks required for billing. The tasks interacting
 /// with actix are the most troublesome because the actix system may restart
@@ -79,7 +99,11 @@ pub fn start_rita_exit_loop() {
 
     // the last usage of the wg tunnels, if an innner thread restarts this must be preserved to prevent
     // overbilling users
-    let usage_history = Arc::new(RwLock::new(HashMap::new()));
+    let usage_history: ExitLock = Arc::new(RwLock::new(HashMap::new()));
+    USAGE_HISTORY
+        .write()
+        .unwrap()
+        .insert(KI.check_integration_test_netns(), usage_history.clone());
 
     // outer thread is a watchdog, inner thread is the runner
     thread::spawn(move || {
@@ -112,110 +136,114 @@ pub fn start_rita_exit_loop() {
 fn rita_exit_loop(rita_exit_cache: RitaExitCache, usage_history: ExitLock) -> RitaExitCache {
     let mut rita_exit_cache = rita_exit_cache;
     let start = Instant::now();
-    // opening a database connection takes at least several milliseconds, as the database server
-    // may be across the country, so to save on back and forth we open on and reuse it as much
-    // as possible
-    match get_database_connection() {
-        Ok(conn) => {
-            use exit_db::schema::clients::dsl::clients;
-            let babel_port = settings::get_rita_exit().network.babel_port;
+    let store = get_client_store();
+
+    // Resets all ipv6 data in database
+    if let Err(e) = recompute_ipv6_if_needed(store.as_ref()) {
+        error!("IPV6 Error: Unable to reset databases: {:?}", e);
+    };
+
+    // getting the clients list from a remote database takes at least several milliseconds, as
+    // the database server may be across the country, so we grab it once and reuse it for the tick
+    let get_clients = Instant::now();
+    match store.get_clients(ClientSelector::All) {
+        Ok(clients_list) => {
             info!(
-                "Exit tick! got DB connection after {}ms",
-                start.elapsed().as_millis(),
+                "Finished Rita get clients, got {:?} clients in {}ms",
+                clients_list.len(),
+                get_clients.elapsed().as_millis()
             );
+            let ids = clients_to_ids(clients_list.clone());
 
-            // Resets all ipv6 data in database
-            if let Err(e) = recompute_ipv6_if_needed(&conn) {
-                error!("IPV6 Error: Unable to reset databases: {:?}", e);
-            };
-
-            let get_clients = Instant::now();
-            if let Ok(clients_list) = clients.load::<models::Client>(&conn) {
-                info!(
-                    "Finished Rita get clients, got {:?} clients in {}ms",
-                    clients_list.len(),
-                    get_clients.elapsed().as_millis()
-                );
-                let ids = clients_to_ids(clients_list.clone());
-
-                let start_bill = Instant::now();
-                // watch and bill for traffic
-                bill(babel_port, start, ids, usage_history);
-                info!(
-                    "Finished Rita billing in {}ms",
-                    start_bill.elapsed().as_millis()
-                );
+            let start_bill = Instant::now();
+            // watch and bill for traffic
+            bill(start, ids, usage_history);
+            info!(
+                "Finished Rita billing in {}ms",
+                start_bill.elapsed().as_millis()
+            );
 
-                info!("about to setup clients");
-                let start_setup = Instant::now();
-                // Create and update client tunnels
-                match setup_clients(
-                    &clients_list,
-                    ExitClientSetupStates {
-                        old_clients: rita_exit_cache.wg_clients.clone(),
-                        wg_exit_clients: rita_exit_cache.wg_exit_clients.clone(),
-                        wg_exit_v2_clients: rita_exit_cache.wg_exit_v2_clients.clone(),
-                    },
-                ) {
-                    Ok(client_states) => {
-                        rita_exit_cache.successful_setup = true;
-                        rita_exit_cache.wg_clients = client_states.old_clients;
-                        rita_exit_cache.wg_exit_clients = client_states.wg_exit_clients;
-                        rita_exit_cache.wg_exit_v2_clients = client_states.wg_exit_v2_clients;
-                    }
-                    Err(e) => error!("Setup clients failed with {:?}", e),
+            info!("about to setup clients");
+            let start_setup = Instant::now();
+            // Create and update client tunnels
+            match setup_clients(
+                &clients_list,
+                ExitClientSetupStates {
+                    old_clients: rita_exit_cache.wg_clients.clone(),
+                    wg_exit_clients: rita_exit_cache.wg_exit_clients.clone(),
+                    wg_exit_v2_clients: rita_exit_cache.wg_exit_v2_clients.clone(),
+                },
+            ) {
+                Ok(client_states) => {
+                    rita_exit_cache.successful_setup = true;
+                    rita_exit_cache.wg_clients = client_states.old_clients;
+                    rita_exit_cache.wg_exit_clients = client_states.wg_exit_clients;
+                    rita_exit_cache.wg_exit_v2_clients = client_states.wg_exit_v2_clients;
                 }
-                info!(
-                    "Finished Rita setting up clients in {}ms",
-                    start_setup.elapsed().as_millis()
-                );
+                Err(e) => error!("Setup clients failed with {:?}", e),
+            }
+            info!(
+                "Finished Rita setting up clients in {}ms",
+                start_setup.elapsed().as_millis()
+            );
 
-                let start_cleanup = Instant::now();
-                info!("about to cleanup clients");
-                // find users that have not been active within the configured time period
-                // and remove them from the db
-                if let Err(e) = cleanup_exit_clients(&clients_list, &conn) {
-                    error!("Exit client cleanup failed with {:?}", e);
+            let start_cleanup = Instant::now();
+            info!("about to cleanup clients");
+            // find users that have not been active within the configured time period
+            // and remove them from the db
+            if let Err(e) = cleanup_exit_clients(&clients_list, store.as_ref()) {
+                error!("Exit client cleanup failed with {:?}", e);
+            }
+            // release the internal ips of clients that were removed without releasing them
+            if rita_exit_cache
+                .last_internal_ip_sweep
+                .is_none_or(|last| last.elapsed() >= INTERNAL_IP_SWEEP_INTERVAL)
+            {
+                if let Err(e) =
+                    reclaim_internal_ips(store.as_ref(), &InternalIpRange::from_settings())
+                {
+                    error!("Internal ip reclaim failed with {:?}", e);
                 }
-                info!(
-                    "Finished Rita cleaning clients in {}ms",
-                    start_cleanup.elapsed().as_millis()
-                );
-
-                // Make sure no one we are setting up is geoip unauthorized
-                let start_region = Instant::now();
-                info!("about to check regions");
-                check_regions(start, clients_list.clone(), &conn);
-                info!(
-                    "Finished Rita checking region in {}ms",
-                    start_region.elapsed().as_millis()
-                );
+                rita_exit_cache.last_internal_ip_sweep = Some(Instant::now());
+            }
+            info!(
+                "Finished Rita cleaning clients in {}ms",
+                start_cleanup.elapsed().as_millis()
+            );
 
-                info!("About to enforce exit clients");
-                // handle enforcement on client tunnels by querying debt keeper
-                // this consumes client list
-                let start_enforce = Instant::now();
-                match enforce_exit_clients(clients_list, &rita_exit_cache.debt_actions) {
-                    Ok(new_debt_actions) => rita_exit_cache.debt_actions = new_debt_actions,
-                    Err(e) => warn!("Failed to enforce exit clients with {:?}", e,),
-                }
-                info!(
-                    "Finished Rita enforcement in {}ms ",
-                    start_enforce.elapsed().as_millis()
-                );
+            // Make sure no one we are setting up is geoip unauthorized
+            let start_region = Instant::now();
+            info!("about to check regions");
+            check_regions(start, clients_list.clone(), store.as_ref());
+            info!(
+                "Finished Rita checking region in {}ms",
+                start_region.elapsed().as_millis()
+            );
 
-                info!(
-                    "Finished Rita exit loop in {}ms, all vars should be dropped",
-                    start.elapsed().as_millis(),
-                );
+            info!("About to enforce exit clients");
+            // handle enforcement on client tunnels by querying debt keeper
+            // this consumes client list
+            let start_enforce = Instant::now();
+            match enforce_exit_clients(clients_list, &rita_exit_cache.debt_actions) {
+                Ok(new_debt_actions) => rita_exit_cache.debt_actions = new_debt_actions,
+                Err(e) => warn!("Failed to enforce exit clients with {:?}", e,),
             }
+            info!(
+                "Finished Rita enforcement in {}ms ",
+                start_enforce.elapsed().as_millis()
+            );
+
+            info!(
+                "Finished Rita exit loop in {}ms, all vars should be dropped",
+                start.elapsed().as_millis(),
+            );
         }
         Err(e) => {
-            error!("Failed to get database connection with {}", e);
+            error!("Failed to get the clients list with {}", e);
             if !rita_exit_cache.successful_setup {
                 let db_uri = settings::get_rita_exit().db_uri;
                 let message = format!(
-                    "Failed to get database connection to {db_uri} on first setup loop, the exit can not operate without the ability to get the clients list from the database exiting"
+                    "Failed to get the clients list from {db_uri} on first setup loop, the exit can not operate without the ability to get the clients list from the database exiting"
                 );
                 error!("{}", message);
                 let sys = AsyncSystem::current();
@@ -228,34 +256,23 @@ fn rita_exit_loop(rita_exit_cache: RitaExitCache, usage_history: ExitLock) -> Ri
     rita_exit_cache
 }
 
-fn bill(babel_port: u16, start: Instant, ids: Vec<Identity>, usage_history: ExitLock) {
-    trace!("about to try opening babel stream");
-
-    match open_babel_stream(babel_port, EXIT_LOOP_TIMEOUT) {
-        Ok(mut stream) => match parse_routes(&mut stream) {
-            Ok(routes) => {
-                trace!("Sending traffic watcher message?");
-                if let Err(e) = watch_exit_traffic(usage_history, &routes, &ids) {
-                    error!(
-                        "Watch exit traffic failed with {}, in {} millis",
-                        e,
-                        start.elapsed().as_millis()
-                    );
-                } else {
-                    info!(
-                        "Watch exit traffic completed successfully in {} millis",
-                        start.elapsed().as_millis()
-                    );
-                }
-            }
-            Err(e) => {
+fn bill(start: Instant, ids: Vec<Identity>, usage_history: ExitLock) {
+    match get_babel_monitor().get_routes() {
+        Ok(routes) => {
+            trace!("Sending traffic watcher message?");
+            if let Err(e) = watch_exit_traffic(usage_history, &routes, &ids) {
                 error!(
-                    "Watch exit traffic failed with: {} in {} millis",
+                    "Watch exit traffic failed with {}, in {} millis",
                     e,
                     start.elapsed().as_millis()
                 );
+            } else {
+                info!(
+                    "Watch exit traffic completed successfully in {} millis",
+                    start.elapsed().as_millis()
+                );
             }
-        },
+        }
         Err(e) => {
             error!(
                 "Watch exit traffic failed with: {} in {} millis",
@@ -266,10 +283,10 @@ fn bill(babel_port: u16, start: Instant, ids: Vec<Identity>, usage_history: Exit
     }
 }
 
-fn check_regions(start: Instant, clients_list: Vec<models::Client>, conn: &PgConnection) {
+fn check_regions(start: Instant, clients_list: Vec<models::Client>, store: &dyn ClientStore) {
     let val = settings::get_rita_exit().allowed_countries.is_empty();
     if !val {
-        let res = validate_clients_region(clients_list, conn);
+        let res = validate_clients_region(clients_list, store);
         match res {
             Err(e) => warn!(
                 "Failed to validate client region with {:?} {}ms since start",
@@ -286,10 +303,7 @@ fn check_regions(start: Instant, clients_list: Vec<models::Client>, conn: &PgCon
 
 /// When the ipv6 database gets into an invalid state, we can have unexpected behaviors if rita exit doesnt
 /// crash. This function checks if a config variable is set; if it is, clear out ipv6 database and let it recompute
-fn recompute_ipv6_if_needed(conn: &PgConnection) -> Result<(), Box<RitaExitError>> {
-    use diesel::ExpressionMethods;
-    use exit_db::schema::assigned_ips::dsl::assigned_ips;
-    use exit_db::schema::clients::dsl::clients;
+fn recompute_ipv6_if_needed(store: &dyn ClientStore) -> Result<(), Box<RitaExitError>> {
     let mut rita_exit = get_rita_exit();
     let recompute = rita_exit.exit_network.recompute_ipv6;
     info!("About to call recompute with : {:?}", recompute);
@@ -297,18 +311,13 @@ fn recompute_ipv6_if_needed(conn: &PgConnection) -> Result<(), Box<RitaExitError
         info!("Reseting IPV6 databases");
 
         // Reseting client ipv6 column
-        let empty_str = "";
-        if let Err(e) = diesel::update(clients)
-            .set(internet_ipv6.eq(empty_str))
-            .execute(conn)
-        {
-            return Err(Box::new(e.into()));
-        };
+        store.update_clients(
+            ClientSelector::All,
+            ClientUpdate::InternetIpv6("".to_string()),
+        )?;
 
         // Reseting assigned_ips
-        if let Err(e) = diesel::delete(assigned_ips).execute(conn) {
-            return Err(Box::new(e.into()));
-        };
+        store.clear_assigned_ips()?;
 
         // Set recompute ipv6 to false
         rita_exit.exit_network.recompute_ipv6 = false;
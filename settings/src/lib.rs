@@ -398,4 +398,66 @@ mod tests {
     fn test_exit_settings_example() {
         RitaExitSettingsStruct::new("example_exit.toml").unwrap();
     }
+
+    #[test]
+    fn test_exit_verif_settings() {
+        use crate::exit::{ExitVerifSettings, WebhookVerifSettings};
+        use althea_types::ExitVerifMode;
+
+        #[derive(Deserialize)]
+        struct Verif {
+            verif_settings: ExitVerifSettings,
+        }
+
+        let webhook: Verif = toml::from_str(
+            "
+            [verif_settings]
+            type = \"Webhook\"
+
+            [verif_settings.contents]
+            url = \"https://verify.example.com/signup\"
+            verif_mode = \"Phone\"
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            webhook.verif_settings,
+            ExitVerifSettings::Webhook(WebhookVerifSettings {
+                url: "https://verify.example.com/signup".to_string(),
+                auth_token: None,
+                verif_mode: ExitVerifMode::Phone,
+            })
+        );
+
+        let approval: Verif = toml::from_str(
+            "
+            [verif_settings]
+            type = \"OperatorApproval\"
+            ",
+        )
+        .unwrap();
+        assert_eq!(approval.verif_settings, ExitVerifSettings::OperatorApproval);
+
+        let phone: Verif = toml::from_str(
+            "
+            [verif_settings]
+            type = \"Phone\"
+
+            [verif_settings.contents]
+            verify_service_id = \"VA00000000000000000000000000000000\"
+            notification_number = \"+15555555555\"
+            twillio_account_id = \"account\"
+            twillio_auth_token = \"token\"
+            ",
+        )
+        .unwrap();
+        match phone.verif_settings {
+            ExitVerifSettings::Phone(phone) => {
+                assert_eq!(phone.verify_url, "https://verify.twilio.com");
+                assert_eq!(phone.auth_api_key, "");
+                assert_eq!(phone.twilio_url, "https://api.twilio.com");
+            }
+            other => panic!("Expected phone settings, got {:?}", other),
+        }
+    }
 }
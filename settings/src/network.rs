@@ -1,4 +1,6 @@
 use althea_kernel_interface::DefaultRoute;
+use althea_types::PeerPolicy;
+use althea_types::ShaperAlgorithmKind;
 use althea_types::ShaperSettings;
 use std::collections::HashSet;
 use std::net::{IpAddr, Ipv6Addr};
@@ -19,11 +21,20 @@ fn default_usage_tracker_file() -> String {
     "/etc/rita-usage-tracker.bincode".to_string()
 }
 
+fn default_link_probe_port() -> u16 {
+    4873
+}
+
+fn default_link_probing() -> bool {
+    true
+}
+
 fn default_shaper_settings() -> ShaperSettings {
     ShaperSettings {
         enabled: true,
         max_speed: 10000,
         min_speed: 50,
+        algorithm: ShaperAlgorithmKind::default(),
     }
 }
 
@@ -104,6 +115,22 @@ pub struct NetworkSettings {
     /// in a symmetrical limit of the users choice. Specified in mbit/s
     #[serde(default)]
     pub user_bandwidth_limit: Option<usize>,
+    /// When true hellos that are not signed by the eth key of the identity they carry are
+    /// ignored. Leave this off until every node on the mesh signs its hellos, otherwise older
+    /// neighbors will never get tunnels
+    #[serde(default)]
+    pub require_signed_hellos: bool,
+    /// Which neighbors we will open tunnels with, see PeerPolicy
+    #[serde(default)]
+    pub peer_policy: PeerPolicy,
+    /// Port on which link quality probes are sent and received over each tunnel (needs to be
+    /// constant across an entire althea deployment)
+    #[serde(default = "default_link_probe_port")]
+    pub link_probe_port: u16,
+    /// Determines if we actively probe each tunnel for jitter, loss and bandwidth, the results
+    /// are used by the shaper alongside babel's rtt
+    #[serde(default = "default_link_probing")]
+    pub link_probing: bool,
 }
 
 impl Default for NetworkSettings {
@@ -133,6 +160,10 @@ impl Default for NetworkSettings {
             nickname: None,
             usage_tracker_file: default_usage_tracker_file(),
             user_bandwidth_limit: None,
+            require_signed_hellos: false,
+            peer_policy: PeerPolicy::default(),
+            link_probe_port: default_link_probe_port(),
+            link_probing: default_link_probing(),
         }
     }
 }
@@ -1,11 +1,13 @@
 use althea_types::Denom;
 use althea_types::SystemChain;
+use althea_types::WgKey;
 use auto_bridge::default_bridge_addresses;
 use auto_bridge::TokenBridgeAddresses;
 use clarity::{Address, PrivateKey};
 use num256::Int256;
 use num256::Uint256;
 use std::collections::HashMap;
+use std::net::SocketAddrV4;
 
 fn default_local_fee() -> u32 {
     0u32 // updated by oracle, denominated in wei/byte
@@ -85,6 +87,99 @@ fn default_node_grpc() -> Vec<String> {
     vec!["http://althea.zone:9090".to_string()]
 }
 
+fn default_batch_payments() -> bool {
+    false
+}
+
+fn default_payment_channels_enabled() -> bool {
+    false
+}
+
+fn default_channel_payment_threshold() -> Int256 {
+    // 3 cents, a tenth of the on chain payment threshold
+    30_000_000_000_000_000i64.into()
+}
+
+fn default_channel_max_outstanding() -> Uint256 {
+    // 1 dollar
+    1_000_000_000_000_000_000u128.into()
+}
+
+fn default_channel_settlement_interval() -> u64 {
+    // one day
+    86400
+}
+
+fn default_reorg_watch_window() -> u64 {
+    // one hour, 720 blocks on xDai
+    3600
+}
+
+fn default_debt_dispute_threshold() -> Uint256 {
+    // 10 cents
+    100_000_000_000_000_000u128.into()
+}
+
+fn default_debt_reconcile_threshold() -> Uint256 {
+    0u8.into()
+}
+
+fn default_debt_reconcile_limit() -> Uint256 {
+    // one dollar
+    1_000_000_000_000_000_000u128.into()
+}
+
+fn default_enforcement_policy() -> EnforcementPolicy {
+    EnforcementPolicy::FreeTier
+}
+
+fn default_enforcement_grace_period() -> u64 {
+    0
+}
+
+fn default_settlement_denom_policy() -> SettlementDenomPolicy {
+    SettlementDenomPolicy::Fixed("usdc".to_string())
+}
+
+/// Debt keeper tracks debts in a single denom, but on chains where we accept more than one
+/// denom we have to pick which one to actually settle a debt in. Values are keys into
+/// `accepted_denoms`
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub enum SettlementDenomPolicy {
+    /// Always pay using this denom
+    Fixed(String),
+    /// Pay each neighbor in the denom they have paid us the most in, so that the amounts held in
+    /// each asset stay balanced between us, if they have never paid us use the provided denom
+    MatchNeighbor(String),
+}
+
+/// One step of progressive throttling
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+pub struct ThrottleStep {
+    /// This step applies once a neighbor's debt is this many times the close threshold
+    pub close_threshold_multiple: u32,
+    /// The throughput the neighbor is limited to in kbit/s
+    pub throughput: u32,
+}
+
+/// What we do to a neighbor whose debt is past the close threshold, applied in the same way by
+/// tunnel manager to neighbors and by exits to their clients
+#[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
+pub enum EnforcementPolicy {
+    /// Log a warning but never limit the neighbor
+    WarnOnly,
+    /// Limit the neighbor to free_tier_throughput
+    FreeTier,
+    /// Limit the neighbor further as their debt grows, the step with the largest multiple their
+    /// debt has passed applies. A debt past the close threshold but short of every step gets the
+    /// step with the smallest multiple
+    Progressive(Vec<ThrottleStep>),
+    /// Limit the neighbor to free_tier_throughput and, on exits, redirect web traffic from the
+    /// client's LAN to a captive portal at this address. Relays only see encrypted exit traffic
+    /// so they just apply the free tier
+    CaptivePortal(SocketAddrV4),
+}
+
 /// This struct is used by both rita and rita_exit to configure the dummy payment controller and
 /// debt keeper
 #[derive(Debug, Serialize, Deserialize, Clone, Eq, PartialEq)]
@@ -168,6 +263,54 @@ pub struct PaymentSettings {
     /// post-eip1599 networks that do not respect min-fee
     #[serde(default = "default_min_gas")]
     pub min_gas: Uint256,
+    /// How we pick which of the accepted denoms to make payments in
+    #[serde(default = "default_settlement_denom_policy")]
+    pub settlement_denom_policy: SettlementDenomPolicy,
+    /// When enabled all payments queued in a single payment controller tick are sent as one
+    /// multi message transaction, saving on fees. Only supported on Althea chain
+    #[serde(default = "default_batch_payments")]
+    pub batch_payments: bool,
+    /// When enabled we pay neighbors that support it with signed off chain balance updates
+    /// rather than on chain transactions, settling the total on chain periodically
+    #[serde(default = "default_payment_channels_enabled")]
+    pub payment_channels_enabled: bool,
+    /// The payment threshold used for neighbors we have an open payment channel with, since
+    /// channel payments cost nothing to send this is much lower than payment_threshold
+    #[serde(default = "default_channel_payment_threshold")]
+    pub channel_payment_threshold: Int256,
+    /// The most we will owe a neighbor in unsettled channel payments, once this is exceeded the
+    /// outstanding balance is settled on chain along with the next payment
+    #[serde(default = "default_channel_max_outstanding")]
+    pub channel_max_outstanding: Uint256,
+    /// How often in seconds any outstanding channel balance is settled on chain
+    #[serde(default = "default_channel_settlement_interval")]
+    pub channel_settlement_interval: u64,
+    /// How long in seconds a validated payment is watched for after it is credited, if it
+    /// disappears from the chain during this window (a reorg) the credit is reversed
+    #[serde(default = "default_reorg_watch_window")]
+    pub reorg_watch_window: u64,
+    /// Drift between our view of the debt with a neighbor and theirs larger than this, for two
+    /// exchanges in a row, is logged as a debt dispute
+    #[serde(default = "default_debt_dispute_threshold")]
+    pub debt_dispute_threshold: Uint256,
+    /// Drift in the debt with a neighbor smaller than this is reconciled automatically, zero
+    /// disables reconciliation
+    #[serde(default = "default_debt_reconcile_threshold")]
+    pub debt_reconcile_threshold: Uint256,
+    /// The most auto reconcile may move the debt with any one neighbor in total, in either
+    /// direction, since Rita started
+    #[serde(default = "default_debt_reconcile_limit")]
+    pub debt_reconcile_limit: Uint256,
+    /// What we do to neighbors that don't pay, only applies if enable_enforcement is true
+    #[serde(default = "default_enforcement_policy")]
+    pub enforcement_policy: EnforcementPolicy,
+    /// How long in seconds a neighbor's debt may be past the close threshold before the
+    /// enforcement policy is applied
+    #[serde(default = "default_enforcement_grace_period")]
+    pub enforcement_grace_period: u64,
+    /// Grace periods in seconds for specific neighbors, overriding enforcement_grace_period
+    #[serde(default)]
+    pub enforcement_grace_periods: HashMap<WgKey, u64>,
 }
 
 impl Default for PaymentSettings {
@@ -196,6 +339,19 @@ impl Default for PaymentSettings {
             simulated_transaction_fee: default_simulated_transaction_fee(),
             forgive_on_reboot: default_forgive_on_reboot(),
             min_gas: default_min_gas(),
+            settlement_denom_policy: default_settlement_denom_policy(),
+            batch_payments: default_batch_payments(),
+            payment_channels_enabled: default_payment_channels_enabled(),
+            channel_payment_threshold: default_channel_payment_threshold(),
+            channel_max_outstanding: default_channel_max_outstanding(),
+            channel_settlement_interval: default_channel_settlement_interval(),
+            reorg_watch_window: default_reorg_watch_window(),
+            debt_dispute_threshold: default_debt_dispute_threshold(),
+            debt_reconcile_threshold: default_debt_reconcile_threshold(),
+            debt_reconcile_limit: default_debt_reconcile_limit(),
+            enforcement_policy: default_enforcement_policy(),
+            enforcement_grace_period: default_enforcement_grace_period(),
+            enforcement_grace_periods: HashMap::new(),
         }
     }
 }
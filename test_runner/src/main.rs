@@ -1,3 +1,4 @@
+use althea_types::ExitVerifMode;
 use integration_tests::config::{
     generate_exit_config_file, generate_rita_config_file, CONFIG_FILE_PATH, EXIT_CONFIG_PATH,
 };
@@ -5,6 +6,7 @@ use integration_tests::debts::run_debts_test;
 /// Binary crate for actually running the integration tests
 use integration_tests::five_nodes::run_five_node_test_scenario;
 use integration_tests::mutli_exit::run_multi_exit_test;
+use integration_tests::verification::run_verification_test;
 use integration_tests::{
     payments_althea::run_althea_payments_test_scenario,
     payments_eth::run_eth_payments_test_scenario, utils::set_sigterm,
@@ -47,6 +49,10 @@ async fn main() {
             run_althea_payments_test_scenario().await
         } else if test_type == "MULTI_EXIT" {
             run_multi_exit_test().await
+        } else if test_type == "VERIFICATION_PHONE" {
+            run_verification_test(ExitVerifMode::Phone).await
+        } else if test_type == "VERIFICATION_EMAIL" {
+            run_verification_test(ExitVerifMode::Email).await
         } else {
             panic!("Error unknown test type {}!", test_type);
         }